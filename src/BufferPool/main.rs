@@ -1,17 +1,65 @@
+use std::cell::{Cell, RefCell};
 use std::collections::HashMap;
+use std::ops::{Index, IndexMut};
+use std::rc::Rc;
+use std::sync::Arc;
+
+use crate::disk_manager::{DiskManager, PageId, PAGE_SIZE};
+use crate::page_trace::{Event, Tracer};
+// ストレージ層のエラーはcrate::errorにまとめている
+pub use crate::error::Error;
+use crate::wal::{Lsn, LogManager};
+
 // 型エイリアス
 // 配列の型：[要素の型; 配列の長さ]
 pub type Page = [u8; PAGE_SIZE];
+
+// 各ページの先頭8byteには、そのページを最後に更新したログレコードのLSN(page LSN)を書く
+// ページの中身として使えるのはこの後ろから
+pub const PAGE_LSN_SIZE: usize = 8;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct BufferId(pub u64);
 
+
 pub struct Buffer {
     pub page_id: PageId,
     // RefCell<T>：複雑なデータ構造のデータ競合をコンパイル時ではなく実行時に検査する
     pub page: RefCell<Page>,
     // Cell<T>：読み取り専用の値の中に書き込み可能な値を作る
     pub is_dirty: Cell<bool>,
-} 
+}
 
+impl Default for Buffer {
+    fn default() -> Self {
+        Self {
+            page_id: Default::default(),
+            page: RefCell::new([0u8; PAGE_SIZE]),
+            is_dirty: Cell::new(false),
+        }
+    }
+}
+
+impl Buffer {
+    // ページの先頭に書かれているpage LSNを読み出す
+    pub fn page_lsn(&self) -> Lsn {
+        page_lsn(&self.page.borrow())
+    }
+
+    // page LSNを書き換える
+    // WALに書いたレコードでページを更新したら、そのレコードのLSNを書き込む
+    pub fn set_page_lsn(&self, lsn: Lsn) {
+        self.page.borrow_mut()[..PAGE_LSN_SIZE].copy_from_slice(&lsn.0.to_le_bytes());
+    }
+}
+
+pub fn page_lsn(page: &Page) -> Lsn {
+    let mut bytes = [0u8; PAGE_LSN_SIZE];
+    bytes.copy_from_slice(&page[..PAGE_LSN_SIZE]);
+    Lsn(u64::from_le_bytes(bytes))
+}
+
+#[derive(Default)]
 pub struct Frame {
     usage_count: u64,
     // Rc<T>：対象データへの参照の数をカウントする
@@ -38,11 +86,32 @@ pub struct BufferPoolManager {
     pool:BufferPool,
     // HashMapはKey/Valueで、KeyはEq又はHashトレイトを保持する値ならなんでもOK
     page_table: HashMap<PageId, BufferId>,
+    // WALを使う場合のログマネージャー
+    // ダーティページを書き戻す前に、そのページのpage LSNまでログを永続化する(WALルール)
+    wal: Option<Arc<LogManager>>,
+    // ページアクセスを記録する場合のトレーサー
+    // 記録しない時にバッファプールマネージャーが大きくならないようBoxに入れる
+    tracer: Option<Box<Tracer>>,
 }
 
 impl BufferPool {
+    pub fn new(pool_size: usize) -> Self {
+        let mut buffers = vec![];
+        buffers.resize_with(pool_size, Default::default);
+        let next_victim_id = BufferId::default();
+        Self {
+            buffers,
+            next_victim_id,
+        }
+    }
+
+    fn size(&self) -> usize {
+        self.buffers.len()
+    }
+
     // &mut selfが引数にあるのでレシーバ
     // 破棄するバッファを決めてBufferIdを返す
+    // Clock-sweepアルゴリズム：バッファを順番に見ていき、利用回数を減らしながら利用回数が0のバッファを探す
     fn evict(&mut self) -> Option<BufferId> {
         let pool_size = self.size();
         let mut consecutive_pinned = 0;
@@ -55,6 +124,7 @@ impl BufferPool {
                 break self.next_victim_id;
             }
 
+            // Rc::get_mutは参照カウントが1(誰にも貸し出していない)の時だけSomeを返す
             if Rc::get_mut(&mut frame.buffer).is_some() {
                 frame.usage_count -= 1;
                 consecutive_pinned = 0;
@@ -64,6 +134,261 @@ impl BufferPool {
                     return None;
                 }
             }
+            self.next_victim_id = self.increment_id(self.next_victim_id);
+        };
+        Some(victim_id)
+    }
+
+    fn increment_id(&self, buffer_id: BufferId) -> BufferId {
+        BufferId((buffer_id.0 + 1) % self.size() as u64)
+    }
+}
+
+// Indexトレイトを実装すると、self[buffer_id]のように添字でアクセス出来る
+impl Index<BufferId> for BufferPool {
+    type Output = Frame;
+    fn index(&self, index: BufferId) -> &Self::Output {
+        &self.buffers[index.0 as usize]
+    }
+}
+
+impl IndexMut<BufferId> for BufferPool {
+    fn index_mut(&mut self, index: BufferId) -> &mut Self::Output {
+        &mut self.buffers[index.0 as usize]
+    }
+}
+
+impl BufferPoolManager {
+    pub fn new(disk: DiskManager, pool: BufferPool) -> Self {
+        let page_table = HashMap::new();
+        Self {
+            disk,
+            pool,
+            page_table,
+            wal: None,
+            tracer: None,
+        }
+    }
+
+    // WALを使うバッファプールマネージャーを生成する
+    // クラッシュリカバリはwal::recoverで行う
+    pub fn with_wal(disk: DiskManager, pool: BufferPool, wal: Arc<LogManager>) -> Self {
+        let mut bufmgr = Self::new(disk, pool);
+        bufmgr.wal = Some(wal);
+        bufmgr
+    }
+
+    pub fn wal(&self) -> Option<&Arc<LogManager>> {
+        self.wal.as_ref()
+    }
+
+    pub fn disk_mut(&mut self) -> &mut DiskManager {
+        &mut self.disk
+    }
+
+    // これ以降のページの取得・作成・追い出し・書き出しをtracerに記録する
+    // 記録したファイルはpage_trace::replayでバッファプールの大きさや置き換えアルゴリズムを変えて再生できる
+    pub fn start_trace(&mut self, tracer: Tracer) {
+        self.tracer = Some(Box::new(tracer));
+    }
+
+    // 記録をやめて、残っているレコードをファイルに書き出す
+    pub fn stop_trace(&mut self) -> Result<(), Error> {
+        if let Some(mut tracer) = self.tracer.take() {
+            tracer.flush()?;
+        }
+        Ok(())
+    }
+
+    fn trace(&mut self, event: Event, page_id: PageId) -> Result<(), Error> {
+        if let Some(tracer) = &mut self.tracer {
+            tracer.record(event, page_id)?;
+        }
+        Ok(())
+    }
+
+    // ページを取得する
+    // バッファプールに無ければ、バッファを1つ追い出してディスクから読み込む
+    pub fn fetch_page(&mut self, page_id: PageId) -> Result<Rc<Buffer>, Error> {
+        if let Some(&buffer_id) = self.page_table.get(&page_id) {
+            let frame = &mut self.pool[buffer_id];
+            frame.usage_count += 1;
+            let page = Rc::clone(&frame.buffer);
+            self.trace(Event::Fetch { hit: true }, page_id)?;
+            return Ok(page);
+        }
+        let buffer_id = self.pool.evict().ok_or(Error::NoFreeBuffer)?;
+        let frame = &mut self.pool[buffer_id];
+        let evict_page_id = frame.buffer.page_id;
+        let evict_dirty = frame.buffer.is_dirty.get();
+        {
+            // evictで選ばれたバッファは誰にも貸し出されていないのでunwrapしても良い
+            let buffer = Rc::get_mut(&mut frame.buffer).unwrap();
+            if buffer.is_dirty.get() {
+                write_back(&mut self.disk, self.wal.as_deref(), evict_page_id, buffer.page.get_mut())?;
+            }
+            buffer.page_id = page_id;
+            buffer.is_dirty.set(false);
+            if let Err(e) = self.disk.read_page_data(page_id, buffer.page.get_mut()) {
+                // 読めなかったバッファは空にして、追い出したページの対応も外しておく
+                // (残しておくと、追い出したページを取り出した時に別のページのバッファが返ってしまう)
+                *buffer = Buffer::default();
+                frame.usage_count = 0;
+                self.page_table.remove(&evict_page_id);
+                self.trace_evict(evict_page_id, evict_dirty)?;
+                return Err(e.into());
+            }
+            frame.usage_count = 1;
+        }
+        let page = Rc::clone(&frame.buffer);
+        // ページを読めてから、ページテーブルを書き換える
+        self.page_table.remove(&evict_page_id);
+        self.page_table.insert(page_id, buffer_id);
+        self.trace_evict(evict_page_id, evict_dirty)?;
+        self.trace(Event::Fetch { hit: false }, page_id)?;
+        Ok(page)
+    }
+
+    // 新しいページを採番して、中身が0のバッファを返す
+    pub fn create_page(&mut self) -> Result<Rc<Buffer>, Error> {
+        let page_id = self.disk.allocate_page();
+        // 解放されたページを再利用する場合、まだバッファプールに残っていればそのバッファを使う
+        if let Some(&buffer_id) = self.page_table.get(&page_id) {
+            let frame = &mut self.pool[buffer_id];
+            // 解放されたページは誰も借りていない
+            let buffer = Rc::get_mut(&mut frame.buffer).unwrap();
+            *buffer = Buffer::default();
+            buffer.page_id = page_id;
+            buffer.is_dirty.set(true);
+            frame.usage_count = 1;
+            let page = Rc::clone(&frame.buffer);
+            self.trace(Event::Create, page_id)?;
+            return Ok(page);
+        }
+        let buffer_id = match self.pool.evict() {
+            Some(buffer_id) => buffer_id,
+            None => {
+                // 採番したページは次に使えるよう戻しておく
+                self.disk.deallocate_page(page_id);
+                return Err(Error::NoFreeBuffer);
+            }
+        };
+        let frame = &mut self.pool[buffer_id];
+        let evict_page_id = frame.buffer.page_id;
+        let evict_dirty = frame.buffer.is_dirty.get();
+        {
+            let buffer = Rc::get_mut(&mut frame.buffer).unwrap();
+            if buffer.is_dirty.get() {
+                write_back(&mut self.disk, self.wal.as_deref(), evict_page_id, buffer.page.get_mut())?;
+            }
+            *buffer = Buffer::default();
+            buffer.page_id = page_id;
+            // 新しいページはまだディスクに無いので、ダーティにして必ず書き出されるようにする
+            buffer.is_dirty.set(true);
+            frame.usage_count = 1;
+        }
+        let page = Rc::clone(&frame.buffer);
+        self.page_table.remove(&evict_page_id);
+        self.page_table.insert(page_id, buffer_id);
+        self.trace_evict(evict_page_id, evict_dirty)?;
+        self.trace(Event::Create, page_id)?;
+        Ok(page)
+    }
+
+    // 空いていたバッファ(ページが入っていない)を使った場合は追い出しとして記録しない
+    fn trace_evict(&mut self, page_id: PageId, dirty: bool) -> Result<(), Error> {
+        match page_id.valid() {
+            Some(page_id) => self.trace(Event::Evict { dirty }, page_id),
+            None => Ok(()),
+        }
+    }
+
+    // ページを解放する。次のcreate_pageで再利用される
+    // WALを使う場合、再利用した後のREDOが前の中身の上に重ならないよう、中身を0にしたことをログに書いてから呼ぶ
+    pub fn free_page(&mut self, page_id: PageId) {
+        self.disk.deallocate_page(page_id);
+    }
+
+    // ファイルをnum_pagesページに切り詰める
+    // それより後ろのページのバッファは書き戻さずに捨てる(解放する前に中身を0にしたことはWALに残っている)
+    pub fn shrink(&mut self, num_pages: u64) -> Result<(), Error> {
+        let removed: Vec<PageId> = self.page_table.keys().copied().filter(|page_id| page_id.to_u64() >= num_pages).collect();
+        for page_id in removed {
+            let buffer_id = self.page_table.remove(&page_id).unwrap();
+            let frame = &mut self.pool[buffer_id];
+            // 解放されたページは誰も借りていない
+            let buffer = Rc::get_mut(&mut frame.buffer).unwrap();
+            *buffer = Buffer::default();
+            frame.usage_count = 0;
+        }
+        self.disk.shrink(num_pages)?;
+        Ok(())
+    }
+
+    // 全てのダーティページをディスクに書き出す
+    pub fn flush(&mut self) -> Result<(), Error> {
+        for (&page_id, &buffer_id) in self.page_table.iter() {
+            let frame = &self.pool[buffer_id];
+            if frame.buffer.is_dirty.get() {
+                write_back(&mut self.disk, self.wal.as_deref(), page_id, &frame.buffer.page.borrow())?;
+                frame.buffer.is_dirty.set(false);
+                // self.traceはpage_tableを借りている間は呼べないので直接記録する
+                if let Some(tracer) = &mut self.tracer {
+                    tracer.record(Event::Flush, page_id)?;
+                }
+            }
         }
+        self.disk.sync()?;
+        Ok(())
     }
-}
\ No newline at end of file
+
+    // 指定したページがダーティならディスクに書き出す
+    pub fn flush_page(&mut self, page_id: PageId) -> Result<(), Error> {
+        if let Some(&buffer_id) = self.page_table.get(&page_id) {
+            let frame = &self.pool[buffer_id];
+            if frame.buffer.is_dirty.get() {
+                write_back(&mut self.disk, self.wal.as_deref(), page_id, &frame.buffer.page.borrow())?;
+                frame.buffer.is_dirty.set(false);
+                self.trace(Event::Flush, page_id)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// ページをディスクに書き戻す
+// WALを使っている場合は、先にpage LSNまでのログを永続化しておく
+// そうしないと、ログに残っていない変更がディスクに書かれ、クラッシュ後にUNDO出来なくなる
+fn write_back(disk: &mut DiskManager, wal: Option<&LogManager>, page_id: PageId, page: &Page) -> Result<(), Error> {
+    if let Some(wal) = wal {
+        wal.flush_to(page_lsn(page))?;
+    }
+    disk.write_page_data(page_id, page)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fetch_page_read_error() {
+        let path = std::env::temp_dir().join(format!("buffer_pool_test_{}_read_error", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let disk = DiskManager::open(&path).unwrap();
+        let mut bufmgr = BufferPoolManager::new(disk, BufferPool::new(1));
+        let page_id = {
+            let buffer = bufmgr.create_page().unwrap();
+            buffer.page.borrow_mut()[0] = 42;
+            buffer.is_dirty.set(true);
+            buffer.page_id
+        };
+        // ファイルに無いページは読めない。1つしか無いバッファからは前のページが追い出される
+        assert!(bufmgr.fetch_page(PageId(100)).is_err());
+        let buffer = bufmgr.fetch_page(page_id).unwrap();
+        assert_eq!(buffer.page_id, page_id);
+        assert_eq!(buffer.page.borrow()[0], 42);
+        drop(buffer);
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -1,30 +1,100 @@
 use std::fs::OpenOptions;
 use std::fs::File;
-use std::io::SeekFrom;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+
+// 1ページのサイズ(byte)
+pub const PAGE_SIZE: usize = 4096;
+
+// ヒープファイルやログファイルの読み書きに使う操作
+// 普段はFileを使い、シミュレーションテスト(sim.rs)ではメモリ上の仮想ディスクに差し替える
+pub trait StorageFile: Send {
+    // offsetバイト目からbufの長さだけ読む。ファイルの末尾を越えたらエラー
+    fn read_exact_at(&mut self, buf: &mut [u8], offset: u64) -> io::Result<()>;
+    // offsetバイト目からdataを書く。ファイルの末尾より後ろなら、間は0で埋められる
+    fn write_all_at(&mut self, data: &[u8], offset: u64) -> io::Result<()>;
+    fn file_size(&self) -> io::Result<u64>;
+    fn set_len(&mut self, len: u64) -> io::Result<()>;
+    // 書いた内容をディスクまで永続化する
+    fn sync(&mut self) -> io::Result<()>;
+}
+
+impl StorageFile for File {
+    fn read_exact_at(&mut self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        FileExt::read_exact_at(self, buf, offset)
+    }
+
+    fn write_all_at(&mut self, data: &[u8], offset: u64) -> io::Result<()> {
+        FileExt::write_all_at(self, data, offset)
+    }
+
+    fn file_size(&self) -> io::Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        File::set_len(self, len)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.sync_all()
+    }
+}
 
 pub struct DiskManager {
-    // ヒープファイルのファイルディスクリプタ
-    heap_file: File,
+    // ヒープファイル
+    heap_file: Box<dyn StorageFile>,
     // 採番するページIDを決めるカウンタ
     next_page_id: u64, // 符号なし64bit整数型
+    // 解放されたページID。次に採番する時に再利用する
+    // ヒープファイルには記録せず、WALを使う場合はチェックポイントとログからリカバリで復元する
+    free_pages: Vec<PageId>,
 }
 
 // page_idはほぼ整数値だが、page_id同士の演算など無意味な処理を静的型チェックで検出するためあえて独自定義型を使う
 // Rustではnew typeパターンと言う※以下はnewtypeイデオム
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub struct PageId(pub u64);
 
+impl PageId {
+    // どのページも指していないことを表す値
+    pub const INVALID_PAGE_ID: PageId = PageId(u64::MAX);
+
+    pub fn to_u64(self) -> u64 {
+        self.0
+    }
+
+    pub fn valid(self) -> Option<PageId> {
+        if self == Self::INVALID_PAGE_ID {
+            None
+        } else {
+            Some(self)
+        }
+    }
+}
+
+impl Default for PageId {
+    fn default() -> Self {
+        Self::INVALID_PAGE_ID
+    }
+}
+
 // 引数の&mut selfはレシーバ = this
 // レシーバが存在するメソッドはインスタンスメソッド
 // レシーバが存在しないメソッドはスタティックメソッド
 impl DiskManager {
-    const PAGE_SIZE:u64 = 4096;
-
     // コンストラクタ
     // io::ResultはI/O関連の操作の結果を表す型
     // io::Result<Self>は自分自身=DiskMagagerを返す
     pub fn new(heap_file:File) -> io::Result<Self> {
+        Self::with_storage(Box::new(heap_file))
+    }
+
+    // File以外(仮想ディスクなど)をヒープファイルとして使う
+    pub fn with_storage(heap_file: Box<dyn StorageFile>) -> io::Result<Self> {
         // ファイルサイズ取得
-        let heap_file_size = heap_file.metadata()?.len();
+        let heap_file_size = heap_file.file_size()?;
         let next_page_id = heap_file_size / PAGE_SIZE as u64;
         // Self{heap_file, next_page_id}はDiskManagerのインスタンス
         // 関数の最後に評価した式が戻り値 (return をあえて書かない)
@@ -32,6 +102,7 @@ impl DiskManager {
         Ok(Self {
             heap_file,
             next_page_id,
+            free_pages: vec![],
         })
     }
 
@@ -44,13 +115,18 @@ impl DiskManager {
             .read(true) // 読み込み可能
             .write(true) // 書き込み可能
             .create(true) // ファイルが無い場合は生成
+            .truncate(false) // 既存のファイルの中身は消さない
             // openメソッドはio::Resutl<File>を返す
             .open(heap_file_path)?; // ?はエラーが返ってきたらそこで早期returnする、という意味
-        self::new(heap_file)
+        Self::new(heap_file)
     }
 
     // 新しいページIDを採番する
+    // 解放されたページがあればそれを返す
     pub fn allocate_page(&mut self) -> PageId {
+        if let Some(page_id) = self.free_pages.pop() {
+            return page_id;
+        }
         // 「self」はthis的な意味合い
         let page_id = self.next_page_id;
         self.next_page_id += 1;
@@ -58,12 +134,53 @@ impl DiskManager {
         PageId(page_id)
     }
 
+    // ページを解放して、次の採番で再利用できるようにする
+    // 中身はそのまま残るので、呼び出し側で消しておく
+    pub fn deallocate_page(&mut self, page_id:PageId) {
+        debug_assert!(!self.free_pages.contains(&page_id));
+        self.free_pages.push(page_id);
+    }
+
+    // 解放済みのページID
+    pub fn free_pages(&self) -> &[PageId] {
+        &self.free_pages
+    }
+
+    // 解放済みのページIDを置き換える(リカバリで復元する時に使う)
+    pub fn set_free_pages(&mut self, free_pages: Vec<PageId>) {
+        self.free_pages = free_pages;
+    }
+
+    // ファイルの末尾にある解放済みのページを取り除いた後のページ数
+    pub fn truncated_num_pages(&self) -> u64 {
+        let mut num_pages = self.next_page_id;
+        while num_pages > 0 && self.free_pages.contains(&PageId(num_pages - 1)) {
+            num_pages -= 1;
+        }
+        num_pages
+    }
+
+    // ファイルをnum_pagesページに切り詰め、それより後ろのページを解放済みのページからも取り除く
+    // 次の採番ではまたファイルの末尾から伸ばしていく
+    pub fn shrink(&mut self, num_pages: u64) -> io::Result<()> {
+        if num_pages >= self.next_page_id {
+            return Ok(());
+        }
+        self.next_page_id = num_pages;
+        self.free_pages.retain(|page_id| page_id.to_u64() < num_pages);
+        self.heap_file.set_len(PAGE_SIZE as u64 * num_pages)
+    }
+
+    // 採番済みのページ数
+    pub fn num_pages(&self) -> u64 {
+        self.next_page_id
+    }
+
     // ページのデータを読み出す
     pub fn read_page_data(&mut self, page_id:PageId, data:&mut [u8]) -> io::Result<()> { // 戻り値型はvoid
         let offset = PAGE_SIZE as u64 * page_id.to_u64();
-        self.heap_file.seek(SeekFrom::Start(offset))?;
         // 読み出したデータをdata引数に書き込む
-        self.heap_file.read_exact(data)
+        self.heap_file.read_exact_at(data, offset)
     }
 
     // データをページに書き出す
@@ -71,10 +188,23 @@ impl DiskManager {
         // オブセットを計算
         // 現在のpage_idにページサイズをかけることでファイル内のオフセットが分かる
         let offset = PAGE_SIZE as u64 * page_id.to_u64();
-        // ページ先頭へシーク
-        // SeekFrom::Start(offset)は「ファイルの先頭から数えてoffsetバイト目」と言う意味
-        self.heap_file.seek(SeekFrom::Start(offset))?;
-        // データを書き込む
-        self.heap_file.write_all(data);
+        // ページ先頭(ファイルの先頭から数えてoffsetバイト目)からデータを書き込む
+        self.heap_file.write_all_at(data, offset)
     }
-}
\ No newline at end of file
+
+    // OSのページキャッシュに残っている書き込みをディスクまで永続化する
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.heap_file.sync()
+    }
+
+    // 採番済みだがファイルに一度も書き出されていないページをファイルに確保する
+    // クラッシュリカバリでREDOする際、ページがファイルの末尾より後ろにある場合に使う
+    pub fn ensure_page(&mut self, page_id:PageId) -> io::Result<()> {
+        if page_id.to_u64() < self.next_page_id {
+            return Ok(());
+        }
+        self.next_page_id = page_id.to_u64() + 1;
+        // 伸ばした部分は0で埋められる
+        self.heap_file.set_len(PAGE_SIZE as u64 * self.next_page_id)
+    }
+}
@@ -0,0 +1,126 @@
+// ブルームフィルター
+// 「キーが入っているかもしれない / 絶対に入っていない」をビット列だけで判定する
+// SSTableごとに持たせ、キーが入っていないファイルのブロックを読まずに済ませる
+//
+// キーのハッシュ値からk個のビットの位置を決め、追加する時は全て立て、調べる時は全て立っているかを見る
+// k個の位置は2つのハッシュ値h1, h2からh1 + i * h2で作る(Kirsch-Mitzenmacherの方法)
+// キー1つあたりbビット使う時、誤判定の確率はk = b * ln2で最小になる(10ビットで約1%)
+//
+// エンコードした形式: | k(u8) | ビット列 |
+
+/// キーのハッシュ値(FNV-1aにsplitmix64の仕上げを掛けて、ビットを散らす)
+pub fn hash(key: &[u8]) -> u64 {
+    let mut h: u64 = 0xcbf2_9ce4_8422_2325;
+    for &b in key {
+        h ^= b as u64;
+        h = h.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    h ^= h >> 30;
+    h = h.wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    h ^= h >> 27;
+    h = h.wrapping_mul(0x94d0_49bb_1331_11eb);
+    h ^ (h >> 31)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BloomFilter {
+    bits: Vec<u8>,
+    hashes: u32,
+}
+
+impl BloomFilter {
+    /// keys個のキーを、1キーあたりbits_per_keyビットで入れるフィルター
+    pub fn new(keys: usize, bits_per_key: usize) -> Self {
+        // キーが少ないと誤判定が増えるので、最低でも64ビットにする
+        let bits = std::cmp::max(keys * bits_per_key, 64);
+        let hashes = ((bits_per_key as f64 * std::f64::consts::LN_2).round() as u32).clamp(1, 30);
+        Self {
+            bits: vec![0; bits.div_ceil(8)],
+            hashes,
+        }
+    }
+
+    // ハッシュ値から、立てる(調べる)ビットの位置を順に返す
+    fn positions(&self, hash: u64) -> impl Iterator<Item = usize> {
+        let len = self.bits.len() as u64 * 8;
+        let h1 = hash & 0xffff_ffff;
+        let h2 = (hash >> 32) | 1;
+        (0..self.hashes as u64).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % len) as usize)
+    }
+
+    /// hash()で求めたハッシュ値のキーを追加する
+    pub fn insert_hash(&mut self, hash: u64) {
+        for pos in self.positions(hash) {
+            self.bits[pos / 8] |= 1 << (pos % 8);
+        }
+    }
+
+    pub fn insert(&mut self, key: &[u8]) {
+        self.insert_hash(hash(key));
+    }
+
+    /// キーが入っているかもしれなければtrue。falseなら絶対に入っていない
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        self.positions(hash(key)).all(|pos| self.bits[pos / 8] & (1 << (pos % 8)) != 0)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = vec![self.hashes as u8];
+        bytes.extend_from_slice(&self.bits);
+        bytes
+    }
+
+    /// 形式が正しくなければNone
+    pub fn decode(bytes: &[u8]) -> Option<Self> {
+        let (&hashes, bits) = bytes.split_first()?;
+        if hashes == 0 || bits.is_empty() {
+            return None;
+        }
+        Some(Self {
+            bits: bits.to_vec(),
+            hashes: hashes as u32,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bloom_filter() {
+        let mut filter = BloomFilter::new(1000, 10);
+        for i in 0..1000u32 {
+            filter.insert(format!("key{}", i).as_bytes());
+        }
+        let filter = BloomFilter::decode(&filter.encode()).unwrap();
+        // 入れたキーは必ず見つかる
+        assert!((0..1000u32).all(|i| filter.may_contain(format!("key{}", i).as_bytes())));
+        // 入れていないキーの誤判定は1キー10ビットなら1%前後
+        let false_positives = (0..10000u32).filter(|i| filter.may_contain(format!("other{}", i).as_bytes())).count();
+        assert!(false_positives < 300, "false positives: {}", false_positives);
+        assert_eq!(BloomFilter::decode(&[]), None);
+    }
+
+    #[test]
+    fn test_false_positive_rate() {
+        let keys = 10_000;
+        let probes = 100_000;
+        let mut last_rate = 1.0;
+        for bits_per_key in [4, 8, 12, 16] {
+            let mut filter = BloomFilter::new(keys, bits_per_key);
+            for i in 0..keys {
+                filter.insert(format!("key{}", i).as_bytes());
+            }
+            let false_positives = (0..probes).filter(|i| filter.may_contain(format!("other{}", i).as_bytes())).count();
+            let rate = false_positives as f64 / probes as f64;
+            // k個のハッシュ、mビット、n個のキーの時の誤判定の確率は(1 - e^(-kn/m))^k
+            let k = filter.hashes as f64;
+            let expected = (1.0 - (-k / bits_per_key as f64).exp()).powf(k);
+            assert!(rate < expected * 1.5 + 0.0005, "bits_per_key {}: rate {} expected {}", bits_per_key, rate, expected);
+            // ビットを増やすほど誤判定は減る
+            assert!(rate < last_rate, "bits_per_key {}: rate {} last {}", bits_per_key, rate, last_rate);
+            last_rate = rate;
+        }
+    }
+}
@@ -0,0 +1,731 @@
+// B+tree
+// キーの順に並んだ(キー, 値)の組を保存するインデックス
+// キーはバイト列のまま比較するので、型付きの値はmemcmpableでエンコードしてから渡す
+//
+// - メタページ：ルートのページID
+// - リーフノード：(キー, 値)の組と、前後のリーフのページID(範囲検索で横に辿る)
+// - ブランチノード：(キー, 子のページID)の組と、一番右の子のページID
+//   i番目の子にはi番目のキーより小さいキーが入り、一番右の子には最後のキー以上のキーが入る
+//
+// ノードの中身: | 種類(u8) | a(u64) | b(u64) | 組の数(u16) | (キーの長さ(u16), 値の長さ(u16), キー, 値) ... |
+//   リーフ   : a = 前のリーフ, b = 次のリーフ
+//   ブランチ : a = 一番右の子
+//
+// ノードの操作はページの中身をNodeに読み込み、書き換えてからページに書き戻す
+// 削除してもノードの併合はしない
+//
+// リーフの値の先頭1byteは置き場所を表す
+// 大きな値はオーバーフローページに置き、リーフにはポインタだけを入れる
+//
+// 空のB+treeには、キーの順に並んだ組からBuilderで下から組み立てることもできる
+
+use std::rc::Rc;
+
+use crate::buffer_pool::{Buffer, BufferPoolManager, Error, PAGE_LSN_SIZE};
+use crate::disk_manager::{PageId, PAGE_SIZE};
+use crate::overflow::{self, Pointer};
+use crate::transaction::{Transaction, UNDO_BTREE};
+use crate::wal::TxnLog;
+
+const NODE_LEAF: u8 = 1;
+const NODE_BRANCH: u8 = 2;
+const NODE_HEADER_SIZE: usize = 1 + 8 + 8 + 2;
+const PAIR_HEADER_SIZE: usize = 4;
+// ページの中身のサイズ
+const BODY_SIZE: usize = PAGE_SIZE - PAGE_LSN_SIZE;
+
+// 取り消し方法の種類
+const UNDO_INSERT: u8 = 1;
+const UNDO_DELETE: u8 = 2;
+// 取り消さない(ルートを付け替えるまで辿れないページへの書き込み)
+const UNDO_NONE: u8 = 3;
+// ルートを元のページに戻す
+const UNDO_ROOT: u8 = 4;
+
+// まとめて組み立てる時にノードに詰めるサイズ
+// 後から追加した時にすぐ分割しないよう、少し空けておく
+const BUILD_FILL_SIZE: usize = BODY_SIZE * 9 / 10;
+
+// リーフの値の置き場所
+const VALUE_INLINE: u8 = 0;
+const VALUE_OVERFLOW: u8 = 1;
+
+/// リーフに入れるキーと値(置き場所の1byteを含む)を合わせた最大サイズ
+/// 1ノードに少なくとも4組入るようにして、分割すれば必ず収まるようにする
+/// 値が入りきらなければオーバーフローページに置くので、この制限を受けるのはキーだけ
+pub const MAX_PAIR_SIZE: usize = (BODY_SIZE - NODE_HEADER_SIZE) / 4 - PAIR_HEADER_SIZE;
+
+/// イテレータの開始位置
+pub enum SearchMode {
+    // 先頭から
+    Start,
+    // このキー以上の最初の組から
+    Key(Vec<u8>),
+}
+
+/// (キー, 値)の組
+pub type Pair = (Vec<u8>, Vec<u8>);
+
+// ルートからリーフまでに通ったブランチ(ページと子の位置)
+type Path = Vec<(Rc<Buffer>, usize)>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BTree {
+    pub meta_page_id: PageId,
+}
+
+struct Node {
+    kind: u8,
+    a: PageId,
+    b: PageId,
+    pairs: Vec<Pair>,
+}
+
+impl Node {
+    fn new_leaf(prev: PageId, next: PageId) -> Self {
+        Self { kind: NODE_LEAF, a: prev, b: next, pairs: vec![] }
+    }
+
+    fn read(body: &[u8]) -> Self {
+        let kind = body[0];
+        let a = PageId(read_u64(&body[1..9]));
+        let b = PageId(read_u64(&body[9..17]));
+        let count = u16::from_le_bytes([body[17], body[18]]) as usize;
+        let mut pairs = Vec::with_capacity(count);
+        let mut offset = NODE_HEADER_SIZE;
+        for _ in 0..count {
+            let key_len = u16::from_le_bytes([body[offset], body[offset + 1]]) as usize;
+            let value_len = u16::from_le_bytes([body[offset + 2], body[offset + 3]]) as usize;
+            offset += PAIR_HEADER_SIZE;
+            let key = body[offset..offset + key_len].to_vec();
+            offset += key_len;
+            let value = body[offset..offset + value_len].to_vec();
+            offset += value_len;
+            pairs.push((key, value));
+        }
+        Self { kind, a, b, pairs }
+    }
+
+    fn write(&self, body: &mut [u8]) {
+        body[0] = self.kind;
+        body[1..9].copy_from_slice(&self.a.0.to_le_bytes());
+        body[9..17].copy_from_slice(&self.b.0.to_le_bytes());
+        body[17..19].copy_from_slice(&(self.pairs.len() as u16).to_le_bytes());
+        let mut offset = NODE_HEADER_SIZE;
+        for (key, value) in &self.pairs {
+            body[offset..offset + 2].copy_from_slice(&(key.len() as u16).to_le_bytes());
+            body[offset + 2..offset + 4].copy_from_slice(&(value.len() as u16).to_le_bytes());
+            offset += PAIR_HEADER_SIZE;
+            body[offset..offset + key.len()].copy_from_slice(key);
+            offset += key.len();
+            body[offset..offset + value.len()].copy_from_slice(value);
+            offset += value.len();
+        }
+        // 残りは0で埋めて、前の中身が残らないようにする
+        for b in body[offset..].iter_mut() {
+            *b = 0;
+        }
+    }
+
+    fn size(pairs: &[Pair]) -> usize {
+        NODE_HEADER_SIZE + pairs.iter().map(|(k, v)| PAIR_HEADER_SIZE + k.len() + v.len()).sum::<usize>()
+    }
+
+    fn fits(&self) -> bool {
+        Self::size(&self.pairs) <= BODY_SIZE
+    }
+
+    // ブランチノードで、keyを含む子の位置と子のページID
+    fn child(&self, key: &[u8]) -> (usize, PageId) {
+        let pos = self.pairs.partition_point(|(k, _)| k.as_slice() <= key);
+        let child = match self.pairs.get(pos) {
+            Some((_, child)) => PageId(read_u64(child)),
+            None => self.a,
+        };
+        (pos, child)
+    }
+
+    // 組をおおよそ半分のサイズになる位置で分ける
+    fn split_point(&self) -> usize {
+        let total = Self::size(&self.pairs);
+        let mut size = NODE_HEADER_SIZE;
+        for (i, (k, v)) in self.pairs.iter().enumerate() {
+            size += PAIR_HEADER_SIZE + k.len() + v.len();
+            if size * 2 >= total {
+                return std::cmp::max(1, i);
+            }
+        }
+        self.pairs.len() / 2
+    }
+}
+
+fn read_u64(bytes: &[u8]) -> u64 {
+    let mut buf = [0u8; 8];
+    buf.copy_from_slice(&bytes[..8]);
+    u64::from_le_bytes(buf)
+}
+
+fn read_node(buffer: &Buffer) -> Node {
+    Node::read(&buffer.page.borrow()[PAGE_LSN_SIZE..])
+}
+
+fn write_node(log: &TxnLog, buffer: &Buffer, node: &Node) -> Result<(), Error> {
+    log.modify_page(buffer, |body| node.write(body))?;
+    Ok(())
+}
+
+// 値をリーフに入れる形にする。大きな値はオーバーフローページに書く
+fn store_value(bufmgr: &mut BufferPoolManager, log: &TxnLog, key: &[u8], value: &[u8]) -> Result<Vec<u8>, Error> {
+    if value.len() <= overflow::THRESHOLD && key.len() + 1 + value.len() <= MAX_PAIR_SIZE {
+        let mut stored = vec![VALUE_INLINE];
+        stored.extend_from_slice(value);
+        return Ok(stored);
+    }
+    let mut stored = vec![VALUE_OVERFLOW];
+    stored.extend_from_slice(&overflow::write(bufmgr, log, value)?.encode());
+    Ok(stored)
+}
+
+// リーフに入っている値から、元の値を読む
+fn load_value(bufmgr: &mut BufferPoolManager, stored: &[u8]) -> Result<Vec<u8>, Error> {
+    match stored.split_first() {
+        Some((&VALUE_OVERFLOW, pointer)) => overflow::read(bufmgr, Pointer::decode(pointer)),
+        Some((_, value)) => Ok(value.to_vec()),
+        None => Ok(vec![]),
+    }
+}
+
+// 値がオーバーフローページにあれば解放する
+fn free_value(bufmgr: &mut BufferPoolManager, log: &TxnLog, stored: &[u8]) -> Result<(), Error> {
+    if let Some((&VALUE_OVERFLOW, pointer)) = stored.split_first() {
+        overflow::free(bufmgr, log, Pointer::decode(pointer))?;
+    }
+    Ok(())
+}
+
+fn root_page_id(bufmgr: &mut BufferPoolManager, meta_page_id: PageId) -> Result<PageId, Error> {
+    let buffer = bufmgr.fetch_page(meta_page_id)?;
+    let page = buffer.page.borrow();
+    Ok(PageId(read_u64(&page[PAGE_LSN_SIZE..])))
+}
+
+impl BTree {
+    /// 空のB+treeを作る
+    pub fn create(bufmgr: &mut BufferPoolManager, txn: &Transaction) -> Result<Self, Error> {
+        let log = txn.log();
+        let meta = bufmgr.create_page()?;
+        let root = bufmgr.create_page()?;
+        let leaf = Node::new_leaf(PageId::INVALID_PAGE_ID, PageId::INVALID_PAGE_ID);
+        write_node(log, &root, &leaf)?;
+        log.modify_page(&meta, |body| body[..8].copy_from_slice(&root.page_id.0.to_le_bytes()))?;
+        Ok(Self { meta_page_id: meta.page_id })
+    }
+
+    pub fn open(meta_page_id: PageId) -> Self {
+        Self { meta_page_id }
+    }
+
+    /// キーに対応する値を返す
+    pub fn search(&self, bufmgr: &mut BufferPoolManager, _txn: &Transaction, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        let (buffer, _) = self.find_leaf(bufmgr, key)?;
+        let node = read_node(&buffer);
+        match node.pairs.binary_search_by(|(k, _)| k.as_slice().cmp(key)) {
+            Ok(i) => Ok(Some(load_value(bufmgr, &node.pairs[i].1)?)),
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// modeで指定した位置から、キーの順に組を返すイテレータ
+    pub fn iter(&self, bufmgr: &mut BufferPoolManager, _txn: &Transaction, mode: SearchMode) -> Result<Iter, Error> {
+        let key = match &mode {
+            SearchMode::Start => vec![],
+            SearchMode::Key(key) => key.clone(),
+        };
+        let (buffer, _) = self.find_leaf(bufmgr, &key)?;
+        let node = read_node(&buffer);
+        let index = node.pairs.partition_point(|(k, _)| k.as_slice() < key.as_slice());
+        Ok(Iter { pairs: node.pairs, index, next_page_id: node.b })
+    }
+
+    /// 組を追加する。同じキーが既にあればError::DuplicateKey
+    pub fn insert(&self, bufmgr: &mut BufferPoolManager, txn: &Transaction, key: &[u8], value: &[u8]) -> Result<(), Error> {
+        let log = txn.log();
+        let start = log.last_lsn();
+        self.insert_raw(bufmgr, log, key, value)?;
+        log.log_operation(start, undo_record(UNDO_INSERT, self.meta_page_id, key, &[]))?;
+        Ok(())
+    }
+
+    /// キーを削除する。キーが無ければError::NotFound
+    /// オーバーフローページはすぐに解放し、取り消す時は値を書き直す
+    pub fn delete(&self, bufmgr: &mut BufferPoolManager, txn: &Transaction, key: &[u8]) -> Result<(), Error> {
+        let log = txn.log();
+        let start = log.last_lsn();
+        let stored = self.delete_raw(bufmgr, log, key)?.ok_or(Error::NotFound)?;
+        let value = load_value(bufmgr, &stored)?;
+        free_value(bufmgr, log, &stored)?;
+        log.log_operation(start, undo_record(UNDO_DELETE, self.meta_page_id, key, &value))?;
+        Ok(())
+    }
+
+    /// 組が1つも無いかどうか(削除して空になったリーフが残っていれば、空とはみなさない)
+    pub fn is_empty(&self, bufmgr: &mut BufferPoolManager) -> Result<bool, Error> {
+        let root = root_page_id(bufmgr, self.meta_page_id)?;
+        let root = bufmgr.fetch_page(root)?;
+        let node = read_node(&root);
+        Ok(node.kind == NODE_LEAF && node.pairs.is_empty())
+    }
+
+    /// キーの昇順に並んだ組から、このB+treeを下から組み立てるビルダーを作る
+    /// B+treeが空でなければエラー
+    pub fn builder(&self, bufmgr: &mut BufferPoolManager) -> Result<Builder, Error> {
+        if !self.is_empty(bufmgr)? {
+            return Err(invalid_input("bulk load requires an empty B+tree"));
+        }
+        Ok(Builder {
+            btree: *self,
+            levels: vec![],
+            last_key: None,
+        })
+    }
+
+    // keyが入るべきリーフと、そこまでに通ったブランチを返す
+    fn find_leaf(&self, bufmgr: &mut BufferPoolManager, key: &[u8]) -> Result<(Rc<Buffer>, Path), Error> {
+        let mut page_id = root_page_id(bufmgr, self.meta_page_id)?;
+        let mut path = vec![];
+        loop {
+            let buffer = bufmgr.fetch_page(page_id)?;
+            let node = read_node(&buffer);
+            if node.kind == NODE_LEAF {
+                return Ok((buffer, path));
+            }
+            let (pos, child) = node.child(key);
+            path.push((buffer, pos));
+            page_id = child;
+        }
+    }
+
+    fn insert_raw(&self, bufmgr: &mut BufferPoolManager, log: &TxnLog, key: &[u8], value: &[u8]) -> Result<(), Error> {
+        if key.len() + 1 + overflow::POINTER_SIZE > MAX_PAIR_SIZE {
+            return Err(Error::TooLarge);
+        }
+        let (leaf, mut path) = self.find_leaf(bufmgr, key)?;
+        let mut node = read_node(&leaf);
+        let pos = match node.pairs.binary_search_by(|(k, _)| k.as_slice().cmp(key)) {
+            Ok(_) => return Err(Error::DuplicateKey),
+            Err(pos) => pos,
+        };
+        // 重複を確かめてから書くので、オーバーフローページが無駄にならない
+        let stored = store_value(bufmgr, log, key, value)?;
+        node.pairs.insert(pos, (key.to_vec(), stored));
+        let mut split = self.write_or_split(bufmgr, log, &leaf, node)?;
+
+        // 分割したら、親に(区切りのキー, 左半分のページID)を追加する
+        while let Some((sep, left)) = split {
+            match path.pop() {
+                Some((parent, pos)) => {
+                    let mut node = read_node(&parent);
+                    node.pairs.insert(pos, (sep, left.0.to_le_bytes().to_vec()));
+                    split = self.write_or_split(bufmgr, log, &parent, node)?;
+                }
+                None => {
+                    // ルートが分割されたので、新しいルートを作る
+                    let old_root = root_page_id(bufmgr, self.meta_page_id)?;
+                    let root = bufmgr.create_page()?;
+                    let node = Node {
+                        kind: NODE_BRANCH,
+                        a: old_root,
+                        b: PageId::INVALID_PAGE_ID,
+                        pairs: vec![(sep, left.0.to_le_bytes().to_vec())],
+                    };
+                    write_node(log, &root, &node)?;
+                    let meta = bufmgr.fetch_page(self.meta_page_id)?;
+                    log.modify_page(&meta, |body| body[..8].copy_from_slice(&root.page_id.0.to_le_bytes()))?;
+                    split = None;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // ノードがページに収まれば書き込む
+    // 収まらなければ小さい方の半分を新しいページに移し、(区切りのキー, 新しいページ)を返す
+    fn write_or_split(&self, bufmgr: &mut BufferPoolManager, log: &TxnLog, buffer: &Rc<Buffer>, mut node: Node) -> Result<Option<(Vec<u8>, PageId)>, Error> {
+        if node.fits() {
+            write_node(log, buffer, &node)?;
+            return Ok(None);
+        }
+        let new_buffer = bufmgr.create_page()?;
+        let mid = node.split_point();
+        let mut right_pairs = node.pairs.split_off(mid);
+        let (sep, left) = if node.kind == NODE_LEAF {
+            let sep = right_pairs[0].0.clone();
+            let left = Node { kind: NODE_LEAF, a: node.a, b: buffer.page_id, pairs: node.pairs };
+            // 前のリーフの「次」を新しいリーフに付け替える
+            if let Some(prev) = node.a.valid() {
+                let prev = bufmgr.fetch_page(prev)?;
+                let mut prev_node = read_node(&prev);
+                prev_node.b = new_buffer.page_id;
+                write_node(log, &prev, &prev_node)?;
+            }
+            node.a = new_buffer.page_id;
+            (sep, left)
+        } else {
+            // ブランチは真ん中のキーを親に上げ、その子を左半分の一番右の子にする
+            let (sep, child) = right_pairs.remove(0);
+            let left = Node { kind: NODE_BRANCH, a: PageId(read_u64(&child)), b: PageId::INVALID_PAGE_ID, pairs: node.pairs };
+            (sep, left)
+        };
+        node.pairs = right_pairs;
+        write_node(log, &new_buffer, &left)?;
+        write_node(log, buffer, &node)?;
+        Ok(Some((sep, new_buffer.page_id)))
+    }
+
+    // 削除して、リーフに入っていた形の値を返す
+    fn delete_raw(&self, bufmgr: &mut BufferPoolManager, log: &TxnLog, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        let (leaf, _) = self.find_leaf(bufmgr, key)?;
+        let mut node = read_node(&leaf);
+        match node.pairs.binary_search_by(|(k, _)| k.as_slice().cmp(key)) {
+            Ok(pos) => {
+                let (_, value) = node.pairs.remove(pos);
+                write_node(log, &leaf, &node)?;
+                Ok(Some(value))
+            }
+            Err(_) => Ok(None),
+        }
+    }
+}
+
+fn invalid_input(msg: &str) -> Error {
+    Error::Io(std::io::Error::new(std::io::ErrorKind::InvalidInput, msg.to_string()))
+}
+
+/// キーの昇順に並んだ組から、空のB+treeを下から組み立てるビルダー
+/// 1件ずつinsertすると毎回ルートから辿ってノードを分割するが、ここではリーフを左から順に詰めて書く
+/// 各段で書きかけのノードだけをメモリに持ち、いっぱいになったら書き出して親の段に(先頭のキー, ページID)を渡す
+///
+/// 書いたページはfinish()でルートを付け替えるまでどこからも辿れないので、WALには取り消さない操作として書く
+/// ロールバックするとルートだけを元の空のリーフに戻す
+/// そのため、コミットするまで他のトランザクションはこのB+treeに書き込まないこと
+pub struct Builder {
+    btree: BTree,
+    // 段ごとの書きかけのノード(0がリーフ)
+    levels: Vec<Level>,
+    last_key: Option<Vec<u8>>,
+}
+
+struct Level {
+    page_id: PageId,
+    // ノードの先頭のキー(親で区切りに使う)
+    first_key: Vec<u8>,
+    // ブランチの段では(子の先頭のキー, 子のページID)を並べておき、書き出す時にブランチの形にする
+    node: Node,
+}
+
+impl Builder {
+    /// 組を追加する。キーは前に追加したキーより大きくなければならない
+    pub fn push(&mut self, bufmgr: &mut BufferPoolManager, txn: &Transaction, key: &[u8], value: &[u8]) -> Result<(), Error> {
+        if key.len() + 1 + overflow::POINTER_SIZE > MAX_PAIR_SIZE {
+            return Err(Error::TooLarge);
+        }
+        match &self.last_key {
+            Some(last) if last.as_slice() == key => return Err(Error::DuplicateKey),
+            Some(last) if last.as_slice() > key => return Err(invalid_input("keys must be pushed in ascending order")),
+            _ => {}
+        }
+        self.last_key = Some(key.to_vec());
+        let log = txn.log();
+        let start = log.last_lsn();
+        let stored = store_value(bufmgr, log, key, value)?;
+        self.add(bufmgr, log, 0, key.to_vec(), stored)?;
+        if log.last_lsn() != start {
+            log.log_operation(start, undo_record(UNDO_NONE, self.btree.meta_page_id, &[], &[]))?;
+        }
+        Ok(())
+    }
+
+    /// 書きかけのノードを全て書き出し、一番上のノードをルートにする
+    pub fn finish(mut self, bufmgr: &mut BufferPoolManager, txn: &Transaction) -> Result<(), Error> {
+        if self.levels.is_empty() {
+            return Ok(());
+        }
+        let log = txn.log();
+        let start = log.last_lsn();
+        // 書き出すと親の段が増えることがあるので、段の数は毎回確かめる
+        let mut level = 0;
+        while level + 1 < self.levels.len() {
+            let page_id = self.levels[level].page_id;
+            let first_key = self.write_level(bufmgr, log, level, PageId::INVALID_PAGE_ID)?;
+            self.add(bufmgr, log, level + 1, first_key, page_id.0.to_le_bytes().to_vec())?;
+            level += 1;
+        }
+        let root = self.levels[level].page_id;
+        self.write_level(bufmgr, log, level, PageId::INVALID_PAGE_ID)?;
+        let old_root = root_page_id(bufmgr, self.btree.meta_page_id)?;
+        let meta = bufmgr.fetch_page(self.btree.meta_page_id)?;
+        log.modify_page(&meta, |body| body[..8].copy_from_slice(&root.0.to_le_bytes()))?;
+        log.log_operation(start, undo_record(UNDO_ROOT, self.btree.meta_page_id, &[], &old_root.0.to_le_bytes()))?;
+        Ok(())
+    }
+
+    // levelの段に組を追加する。ノードがいっぱいなら書き出して、次のノードに入れる
+    fn add(&mut self, bufmgr: &mut BufferPoolManager, log: &TxnLog, level: usize, key: Vec<u8>, value: Vec<u8>) -> Result<(), Error> {
+        let kind = if level == 0 { NODE_LEAF } else { NODE_BRANCH };
+        if self.levels.len() == level {
+            self.levels.push(Level {
+                page_id: bufmgr.create_page()?.page_id,
+                first_key: key.clone(),
+                node: Node { kind, a: PageId::INVALID_PAGE_ID, b: PageId::INVALID_PAGE_ID, pairs: vec![] },
+            });
+        }
+        let pairs = &self.levels[level].node.pairs;
+        if !pairs.is_empty() && Node::size(pairs) + PAIR_HEADER_SIZE + key.len() + value.len() > BUILD_FILL_SIZE {
+            let page_id = self.levels[level].page_id;
+            let next_page_id = bufmgr.create_page()?.page_id;
+            let first_key = self.write_level(bufmgr, log, level, next_page_id)?;
+            self.levels[level] = Level {
+                page_id: next_page_id,
+                first_key: key.clone(),
+                // リーフは前のリーフと繋ぐ
+                node: Node { kind, a: if level == 0 { page_id } else { PageId::INVALID_PAGE_ID }, b: PageId::INVALID_PAGE_ID, pairs: vec![] },
+            };
+            self.add(bufmgr, log, level + 1, first_key, page_id.0.to_le_bytes().to_vec())?;
+        }
+        self.levels[level].node.pairs.push((key, value));
+        Ok(())
+    }
+
+    // levelの段の書きかけのノードをページに書き、ノードの先頭のキーを返す
+    // nextはリーフの次のリーフ
+    fn write_level(&mut self, bufmgr: &mut BufferPoolManager, log: &TxnLog, level: usize, next: PageId) -> Result<Vec<u8>, Error> {
+        let current = &mut self.levels[level];
+        let pairs = std::mem::take(&mut current.node.pairs);
+        let node = if current.node.kind == NODE_LEAF {
+            Node { kind: NODE_LEAF, a: current.node.a, b: next, pairs }
+        } else {
+            // i番目の子には(i+1)番目の子の先頭のキーより小さいキーが入り、最後の子が一番右の子になる
+            let rightmost = PageId(read_u64(&pairs.last().unwrap().1));
+            let pairs = pairs.windows(2).map(|w| (w[1].0.clone(), w[0].1.clone())).collect();
+            Node { kind: NODE_BRANCH, a: rightmost, b: PageId::INVALID_PAGE_ID, pairs }
+        };
+        let buffer = bufmgr.fetch_page(self.levels[level].page_id)?;
+        write_node(log, &buffer, &node)?;
+        Ok(self.levels[level].first_key.clone())
+    }
+}
+
+/// B+treeの組をキーの順に返すイテレータ
+/// リーフ1ページ分の組を読み込んでおき、読み終わったら次のリーフに進む
+pub struct Iter {
+    pairs: Vec<Pair>,
+    index: usize,
+    next_page_id: PageId,
+}
+
+impl Iter {
+    pub fn next(&mut self, bufmgr: &mut BufferPoolManager) -> Result<Option<Pair>, Error> {
+        loop {
+            if self.index < self.pairs.len() {
+                let (key, stored) = std::mem::take(&mut self.pairs[self.index]);
+                self.index += 1;
+                return Ok(Some((key, load_value(bufmgr, &stored)?)));
+            }
+            match self.next_page_id.valid() {
+                Some(page_id) => {
+                    let buffer = bufmgr.fetch_page(page_id)?;
+                    let node = read_node(&buffer);
+                    self.pairs = node.pairs;
+                    self.index = 0;
+                    self.next_page_id = node.b;
+                }
+                None => return Ok(None),
+            }
+        }
+    }
+}
+
+// 取り消し方法: | UNDO_BTREE | 種類 | メタページID(u64) | キーの長さ(u16) | キー | 値 |
+// UNDO_ROOTでは値に元のルートのページIDを入れる
+fn undo_record(kind: u8, meta_page_id: PageId, key: &[u8], value: &[u8]) -> Vec<u8> {
+    let mut undo = vec![UNDO_BTREE, kind];
+    undo.extend_from_slice(&meta_page_id.0.to_le_bytes());
+    undo.extend_from_slice(&(key.len() as u16).to_le_bytes());
+    undo.extend_from_slice(key);
+    undo.extend_from_slice(value);
+    undo
+}
+
+/// B+treeへの操作を取り消す(UndoDispatcherから呼ばれる)
+/// キーで探し直すので、他のトランザクションの操作でノードが分割されていても取り消せる
+pub(crate) fn undo(bufmgr: &mut BufferPoolManager, log: &TxnLog, undo: &[u8]) -> Result<(), Error> {
+    let kind = undo[0];
+    let btree = BTree::open(PageId(read_u64(&undo[1..9])));
+    let key_len = u16::from_le_bytes([undo[9], undo[10]]) as usize;
+    let key = &undo[11..11 + key_len];
+    let value = &undo[11 + key_len..];
+    match kind {
+        UNDO_INSERT => {
+            if let Some(stored) = btree.delete_raw(bufmgr, log, key)? {
+                free_value(bufmgr, log, &stored)?;
+            }
+        }
+        UNDO_DELETE => btree.insert_raw(bufmgr, log, key, value)?,
+        UNDO_NONE => {}
+        UNDO_ROOT => {
+            let meta = bufmgr.fetch_page(btree.meta_page_id)?;
+            log.modify_page(&meta, |body| body[..8].copy_from_slice(&value[..8]))?;
+        }
+        _ => unreachable!(),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction;
+
+    #[test]
+    fn test_insert_search_iter() {
+        let path = std::env::temp_dir().join(format!("btree_test_{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(crate::wal::log_path(&path));
+        let (mut bufmgr, txn_mgr, _) = transaction::open(&path, 32).unwrap();
+        let txn = txn_mgr.begin().unwrap();
+        let btree = BTree::create(&mut bufmgr, &txn).unwrap();
+        // 逆順に入れて、分割が何段も起きるようにする
+        for i in (0..2000u32).rev() {
+            btree.insert(&mut bufmgr, &txn, &i.to_be_bytes(), &[i as u8; 40]).unwrap();
+        }
+        assert!(matches!(btree.insert(&mut bufmgr, &txn, &7u32.to_be_bytes(), b""), Err(Error::DuplicateKey)));
+        assert_eq!(btree.search(&mut bufmgr, &txn, &1234u32.to_be_bytes()).unwrap(), Some(vec![1234u32 as u8; 40]));
+        assert_eq!(btree.search(&mut bufmgr, &txn, &5000u32.to_be_bytes()).unwrap(), None);
+
+        btree.delete(&mut bufmgr, &txn, &1000u32.to_be_bytes()).unwrap();
+        let mut iter = btree.iter(&mut bufmgr, &txn, SearchMode::Key(999u32.to_be_bytes().to_vec())).unwrap();
+        assert_eq!(iter.next(&mut bufmgr).unwrap().unwrap().0, 999u32.to_be_bytes());
+        assert_eq!(iter.next(&mut bufmgr).unwrap().unwrap().0, 1001u32.to_be_bytes());
+        let mut count = 2;
+        while iter.next(&mut bufmgr).unwrap().is_some() {
+            count += 1;
+        }
+        assert_eq!(count, 1000);
+    }
+
+    #[test]
+    fn test_builder() {
+        let path = std::env::temp_dir().join(format!("btree_test_builder_{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(crate::wal::log_path(&path));
+        let (mut bufmgr, txn_mgr, _) = transaction::open(&path, 32).unwrap();
+        let txn = txn_mgr.begin().unwrap();
+        let btree = BTree::create(&mut bufmgr, &txn).unwrap();
+        txn_mgr.commit(txn).unwrap();
+
+        // ロールバックすると空のB+treeに戻る
+        let txn = txn_mgr.begin().unwrap();
+        let mut builder = btree.builder(&mut bufmgr).unwrap();
+        for i in 0..500u32 {
+            builder.push(&mut bufmgr, &txn, &i.to_be_bytes(), b"x").unwrap();
+        }
+        builder.finish(&mut bufmgr, &txn).unwrap();
+        assert!(btree.search(&mut bufmgr, &txn, &7u32.to_be_bytes()).unwrap().is_some());
+        txn_mgr.rollback(&mut bufmgr, txn).unwrap();
+        let txn = txn_mgr.begin().unwrap();
+        assert!(btree.iter(&mut bufmgr, &txn, SearchMode::Start).unwrap().next(&mut bufmgr).unwrap().is_none());
+
+        // ブランチが何段にもなる数を入れる
+        let mut builder = btree.builder(&mut bufmgr).unwrap();
+        for i in (0..20000u32).map(|i| i * 2) {
+            builder.push(&mut bufmgr, &txn, &i.to_be_bytes(), &[i as u8; 40]).unwrap();
+        }
+        assert!(matches!(builder.push(&mut bufmgr, &txn, &0u32.to_be_bytes(), b""), Err(Error::Io(_))));
+        assert!(matches!(builder.push(&mut bufmgr, &txn, &39998u32.to_be_bytes(), b""), Err(Error::DuplicateKey)));
+        builder.finish(&mut bufmgr, &txn).unwrap();
+        assert!(btree.builder(&mut bufmgr).is_err());
+        txn_mgr.commit(txn).unwrap();
+
+        let txn = txn_mgr.begin().unwrap();
+        for i in [0u32, 2, 1234, 39998] {
+            assert_eq!(btree.search(&mut bufmgr, &txn, &i.to_be_bytes()).unwrap(), Some(vec![i as u8; 40]));
+        }
+        assert_eq!(btree.search(&mut bufmgr, &txn, &1235u32.to_be_bytes()).unwrap(), None);
+        // 組み立てた後も普通に追加できる
+        for i in (0..2000u32).map(|i| i * 2 + 1) {
+            btree.insert(&mut bufmgr, &txn, &i.to_be_bytes(), b"odd").unwrap();
+        }
+        let mut iter = btree.iter(&mut bufmgr, &txn, SearchMode::Start).unwrap();
+        let mut keys = vec![];
+        while let Some((key, _)) = iter.next(&mut bufmgr).unwrap() {
+            keys.push(u32::from_be_bytes([key[0], key[1], key[2], key[3]]));
+        }
+        assert_eq!(keys.len(), 22000);
+        assert!(keys.windows(2).all(|w| w[0] < w[1]));
+        txn_mgr.commit(txn).unwrap();
+    }
+
+    #[test]
+    fn test_overflow_values() {
+        let path = std::env::temp_dir().join(format!("btree_test_overflow_{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(crate::wal::log_path(&path));
+        let (mut bufmgr, txn_mgr, _) = transaction::open(&path, 32).unwrap();
+        let txn = txn_mgr.begin().unwrap();
+        let btree = BTree::create(&mut bufmgr, &txn).unwrap();
+        let value = |i: u32| vec![i as u8; 300 * i as usize];
+        // 1ページに入らない値も入れられる
+        for i in 0..30u32 {
+            btree.insert(&mut bufmgr, &txn, &i.to_be_bytes(), &value(i)).unwrap();
+        }
+        txn_mgr.commit(txn).unwrap();
+        let txn = txn_mgr.begin().unwrap();
+        assert_eq!(btree.search(&mut bufmgr, &txn, &29u32.to_be_bytes()).unwrap(), Some(value(29)));
+        let mut iter = btree.iter(&mut bufmgr, &txn, SearchMode::Start).unwrap();
+        for i in 0..30u32 {
+            assert_eq!(iter.next(&mut bufmgr).unwrap(), Some((i.to_be_bytes().to_vec(), value(i))));
+        }
+        // 削除を取り消すと、大きな値も元に戻る
+        btree.delete(&mut bufmgr, &txn, &20u32.to_be_bytes()).unwrap();
+        btree.insert(&mut bufmgr, &txn, &100u32.to_be_bytes(), &value(25)).unwrap();
+        txn_mgr.rollback(&mut bufmgr, txn).unwrap();
+        let txn = txn_mgr.begin().unwrap();
+        assert_eq!(btree.search(&mut bufmgr, &txn, &20u32.to_be_bytes()).unwrap(), Some(value(20)));
+        assert_eq!(btree.search(&mut bufmgr, &txn, &100u32.to_be_bytes()).unwrap(), None);
+        // 大きすぎるキーは入れられない
+        assert!(matches!(btree.insert(&mut bufmgr, &txn, &[1u8; MAX_PAIR_SIZE], b""), Err(Error::TooLarge)));
+        txn_mgr.commit(txn).unwrap();
+    }
+
+    #[test]
+    fn test_overflow_values_freed() {
+        let path = std::env::temp_dir().join(format!("btree_test_overflow_freed_{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(crate::wal::log_path(&path));
+        let (mut bufmgr, txn_mgr, _) = transaction::open(&path, 32).unwrap();
+        let txn = txn_mgr.begin().unwrap();
+        let btree = BTree::create(&mut bufmgr, &txn).unwrap();
+        // どれも3ページに跨る値
+        let value = |i: u32| vec![i as u8; PAGE_SIZE * 3];
+        for i in 0..10u32 {
+            btree.insert(&mut bufmgr, &txn, &i.to_be_bytes(), &value(i)).unwrap();
+        }
+        txn_mgr.commit(txn).unwrap();
+
+        // 削除した値のページは、次に入れる値で再利用される
+        let pages = bufmgr.disk_mut().num_pages();
+        let txn = txn_mgr.begin().unwrap();
+        for i in 0..10u32 {
+            btree.delete(&mut bufmgr, &txn, &i.to_be_bytes()).unwrap();
+        }
+        txn_mgr.commit(txn).unwrap();
+        let txn = txn_mgr.begin().unwrap();
+        for i in 10..20u32 {
+            btree.insert(&mut bufmgr, &txn, &i.to_be_bytes(), &value(i)).unwrap();
+        }
+        assert_eq!(bufmgr.disk_mut().num_pages(), pages);
+        assert_eq!(btree.search(&mut bufmgr, &txn, &0u32.to_be_bytes()).unwrap(), None);
+        assert_eq!(btree.search(&mut bufmgr, &txn, &15u32.to_be_bytes()).unwrap(), Some(value(15)));
+        txn_mgr.commit(txn).unwrap();
+    }
+}
@@ -0,0 +1,678 @@
+// システムカタログ
+// ヒープファイルの中にあるテーブルとインデックスの一覧
+// プロセスを再起動してもテーブルやインデックスを見つけられるように、ファイルの中に保存する
+//
+// - ファイルヘッダー(ページ0)：| マジックナンバー(8byte) | フォーマットのバージョン(u32) | カタログの先頭ページID(u64) | カタログの空き領域マップのページID(u64) |
+// - カタログ：テーブル・インデックスの定義と、テーブルの統計情報を1件1レコードで保存するヒープテーブル
+//   レコードはmemcmpableでエンコードした値の並び
+//
+// カタログもただのヒープテーブルなので、テーブルの作成や削除はトランザクションの一部になる
+// ロールバックすれば作成も削除も無かったことになり、他のトランザクションからはコミットするまで見えない
+
+use std::fmt;
+use std::io;
+use std::path::Path;
+
+use crate::btree::BTree;
+use crate::buffer_pool::{BufferPoolManager, Error, PAGE_LSN_SIZE};
+use crate::disk_manager::PageId;
+use crate::hash_index::HashIndex;
+use crate::heap::{HeapTable, RecordId};
+use crate::memcmpable::{self, Value};
+use crate::transaction::{self, Transaction, TransactionManager};
+
+/// ファイルヘッダーを置くページ
+pub const HEADER_PAGE_ID: PageId = PageId(0);
+
+const MAGIC: &[u8; 8] = b"PRACTDB\0";
+// カタログのレコードやファイルヘッダーの形式を変えたら上げて、FORMAT_HISTORYに何を変えたかを書く
+const FORMAT_VERSION: u32 = 5;
+
+// 各バージョンで変えたこと(開けないファイルのエラーメッセージに使う)
+const FORMAT_HISTORY: &[(u32, &str)] = &[
+    (1, "tables and indexes"),
+    (2, "hash indexes"),
+    (3, "free space map page in the file header"),
+    (4, "table statistics collected by ANALYZE"),
+    (5, "hash index directory split across several pages"),
+];
+
+// カタログのレコードの種類
+const ENTRY_TABLE: u64 = 1;
+const ENTRY_INDEX: u64 = 2;
+const ENTRY_STATS: u64 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColumnType {
+    Int,
+    UInt,
+    Float,
+    Bytes,
+    Str,
+}
+
+impl ColumnType {
+    fn tag(self) -> u64 {
+        match self {
+            ColumnType::Int => 1,
+            ColumnType::UInt => 2,
+            ColumnType::Float => 3,
+            ColumnType::Bytes => 4,
+            ColumnType::Str => 5,
+        }
+    }
+
+    fn from_tag(tag: u64) -> Option<Self> {
+        match tag {
+            1 => Some(ColumnType::Int),
+            2 => Some(ColumnType::UInt),
+            3 => Some(ColumnType::Float),
+            4 => Some(ColumnType::Bytes),
+            5 => Some(ColumnType::Str),
+            _ => None,
+        }
+    }
+}
+
+// CREATE TABLEで書く型の名前
+impl fmt::Display for ColumnType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            ColumnType::Int => "INT",
+            ColumnType::UInt => "UINT",
+            ColumnType::Float => "FLOAT",
+            ColumnType::Bytes => "BLOB",
+            ColumnType::Str => "TEXT",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Column {
+    pub name: String,
+    pub ty: ColumnType,
+}
+
+impl Column {
+    pub fn new(name: &str, ty: ColumnType) -> Self {
+        Self { name: name.to_string(), ty }
+    }
+}
+
+/// テーブルの列の並び
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Schema {
+    pub columns: Vec<Column>,
+}
+
+impl Schema {
+    pub fn new(columns: Vec<Column>) -> Self {
+        Self { columns }
+    }
+
+    /// 列の番号
+    pub fn position(&self, name: &str) -> Option<usize> {
+        self.columns.iter().position(|column| column.name == name)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TableDef {
+    pub name: String,
+    pub schema: Schema,
+    pub heap: HeapTable,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexDef {
+    pub name: String,
+    pub table: String,
+    /// キーにする列(この順に並べてエンコードする)
+    pub columns: Vec<String>,
+    pub unique: bool,
+    pub storage: IndexStorage,
+}
+
+/// インデックスのデータ構造の種類
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IndexKind {
+    /// 範囲検索にも使える
+    BTree,
+    /// 全ての列の等号での検索にだけ使える
+    Hash,
+}
+
+impl IndexKind {
+    fn tag(self) -> u64 {
+        match self {
+            IndexKind::BTree => 1,
+            IndexKind::Hash => 2,
+        }
+    }
+}
+
+/// インデックスのデータ構造
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IndexStorage {
+    BTree(BTree),
+    Hash(HashIndex),
+}
+
+impl IndexStorage {
+    fn create(bufmgr: &mut BufferPoolManager, txn: &Transaction, kind: IndexKind) -> Result<Self, Error> {
+        Ok(match kind {
+            IndexKind::BTree => IndexStorage::BTree(BTree::create(bufmgr, txn)?),
+            IndexKind::Hash => IndexStorage::Hash(HashIndex::create(bufmgr, txn)?),
+        })
+    }
+
+    fn open(tag: u64, page_id: PageId) -> Option<Self> {
+        match tag {
+            1 => Some(IndexStorage::BTree(BTree::open(page_id))),
+            2 => Some(IndexStorage::Hash(HashIndex::open(page_id))),
+            _ => None,
+        }
+    }
+
+    pub fn kind(&self) -> IndexKind {
+        match self {
+            IndexStorage::BTree(_) => IndexKind::BTree,
+            IndexStorage::Hash(_) => IndexKind::Hash,
+        }
+    }
+
+    fn page_id(&self) -> PageId {
+        match self {
+            IndexStorage::BTree(btree) => btree.meta_page_id,
+            IndexStorage::Hash(hash) => hash.directory_page_id,
+        }
+    }
+}
+
+/// 列の統計情報
+#[derive(Debug, Clone, PartialEq)]
+pub struct ColumnStats {
+    pub name: String,
+    /// NULL以外の異なる値の数
+    pub distinct: u64,
+    pub nulls: u64,
+    /// 等深ヒストグラムのバケットの境界(NULL以外の値を並べて等間隔に選んだもの)
+    pub histogram: Vec<Value>,
+}
+
+/// ANALYZEで集めたテーブルの統計情報
+#[derive(Debug, Clone, PartialEq)]
+pub struct TableStats {
+    pub table: String,
+    pub rows: u64,
+    pub pages: u64,
+    pub columns: Vec<ColumnStats>,
+}
+
+impl TableStats {
+    pub fn column(&self, name: &str) -> Option<&ColumnStats> {
+        self.columns.iter().find(|column| column.name == name)
+    }
+}
+
+enum Entry {
+    Table(TableDef),
+    Index(IndexDef),
+    Stats(TableStats),
+}
+
+impl Entry {
+    fn encode(&self) -> Vec<u8> {
+        let values = match self {
+            Entry::Table(table) => {
+                let columns = table
+                    .schema
+                    .columns
+                    .iter()
+                    .map(|column| Value::Tuple(vec![Value::Str(column.name.clone()), Value::UInt(column.ty.tag())]))
+                    .collect();
+                vec![
+                    Value::UInt(ENTRY_TABLE),
+                    Value::Str(table.name.clone()),
+                    Value::UInt(table.heap.first_page_id.0),
+                    Value::UInt(table.heap.fsm.first_page_id.0),
+                    Value::Tuple(columns),
+                ]
+            }
+            Entry::Index(index) => vec![
+                Value::UInt(ENTRY_INDEX),
+                Value::Str(index.name.clone()),
+                Value::Str(index.table.clone()),
+                Value::UInt(index.storage.page_id().0),
+                Value::Tuple(index.columns.iter().cloned().map(Value::Str).collect()),
+                Value::UInt(index.unique as u64),
+                Value::UInt(index.storage.kind().tag()),
+            ],
+            Entry::Stats(stats) => {
+                let columns = stats
+                    .columns
+                    .iter()
+                    .map(|column| {
+                        Value::Tuple(vec![
+                            Value::Str(column.name.clone()),
+                            Value::UInt(column.distinct),
+                            Value::UInt(column.nulls),
+                            Value::Tuple(column.histogram.clone()),
+                        ])
+                    })
+                    .collect();
+                vec![
+                    Value::UInt(ENTRY_STATS),
+                    Value::Str(stats.table.clone()),
+                    Value::UInt(stats.rows),
+                    Value::UInt(stats.pages),
+                    Value::Tuple(columns),
+                ]
+            }
+        };
+        let mut bytes = vec![];
+        memcmpable::encode(&values, &mut bytes);
+        bytes
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        let values = memcmpable::decode(bytes).ok()?;
+        match values.as_slice() {
+            [Value::UInt(ENTRY_TABLE), Value::Str(name), Value::UInt(page_id), Value::UInt(fsm_page_id), Value::Tuple(columns)] => {
+                let columns = columns
+                    .iter()
+                    .map(|column| match column {
+                        Value::Tuple(column) => match column.as_slice() {
+                            [Value::Str(name), Value::UInt(tag)] => Some(Column::new(name, ColumnType::from_tag(*tag)?)),
+                            _ => None,
+                        },
+                        _ => None,
+                    })
+                    .collect::<Option<Vec<_>>>()?;
+                Some(Entry::Table(TableDef {
+                    name: name.clone(),
+                    schema: Schema::new(columns),
+                    heap: HeapTable::open(PageId(*page_id), PageId(*fsm_page_id)),
+                }))
+            }
+            [Value::UInt(ENTRY_INDEX), Value::Str(name), Value::Str(table), Value::UInt(page_id), Value::Tuple(columns), Value::UInt(unique), Value::UInt(kind)] => {
+                let columns = columns
+                    .iter()
+                    .map(|column| match column {
+                        Value::Str(column) => Some(column.clone()),
+                        _ => None,
+                    })
+                    .collect::<Option<Vec<_>>>()?;
+                Some(Entry::Index(IndexDef {
+                    name: name.clone(),
+                    table: table.clone(),
+                    columns,
+                    unique: *unique != 0,
+                    storage: IndexStorage::open(*kind, PageId(*page_id))?,
+                }))
+            }
+            [Value::UInt(ENTRY_STATS), Value::Str(table), Value::UInt(rows), Value::UInt(pages), Value::Tuple(columns)] => {
+                let columns = columns
+                    .iter()
+                    .map(|column| match column {
+                        Value::Tuple(column) => match column.as_slice() {
+                            [Value::Str(name), Value::UInt(distinct), Value::UInt(nulls), Value::Tuple(histogram)] => Some(ColumnStats {
+                                name: name.clone(),
+                                distinct: *distinct,
+                                nulls: *nulls,
+                                histogram: histogram.clone(),
+                            }),
+                            _ => None,
+                        },
+                        _ => None,
+                    })
+                    .collect::<Option<Vec<_>>>()?;
+                Some(Entry::Stats(TableStats {
+                    table: table.clone(),
+                    rows: *rows,
+                    pages: *pages,
+                    columns,
+                }))
+            }
+            _ => None,
+        }
+    }
+}
+
+fn invalid_data(msg: &str) -> Error {
+    Error::Io(io::Error::new(io::ErrorKind::InvalidData, msg.to_string()))
+}
+
+// ファイルのバージョンがこのプログラムと違う時に、何が違うのかを説明する
+// 古いファイルを読み替える仕組みは無いので、作り直してもらう
+fn version_mismatch(version: u32) -> String {
+    if version > FORMAT_VERSION {
+        return format!(
+            "database file format version {} is newer than the supported version {}; open it with a newer build",
+            version, FORMAT_VERSION
+        );
+    }
+    let missing: Vec<&str> = FORMAT_HISTORY
+        .iter()
+        .filter(|(added, _)| *added > version)
+        .map(|(_, change)| *change)
+        .collect();
+    format!(
+        "database file format version {} is older than the supported version {} (added since: {}); \
+         export the tables with COPY TO using the build that created the file and load them into a new file",
+        version,
+        FORMAT_VERSION,
+        missing.join(", ")
+    )
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Catalog {
+    heap: HeapTable,
+}
+
+impl Catalog {
+    /// ファイルヘッダーからカタログを探す
+    /// 空のファイルならファイルヘッダーと空のカタログを作る
+    pub fn bootstrap(bufmgr: &mut BufferPoolManager, txn: &Transaction) -> Result<Self, Error> {
+        if bufmgr.disk_mut().num_pages() == 0 {
+            let buffer = bufmgr.create_page()?;
+            assert_eq!(buffer.page_id, HEADER_PAGE_ID);
+        }
+        let (magic, version, first_page_id, fsm_page_id) = {
+            let buffer = bufmgr.fetch_page(HEADER_PAGE_ID)?;
+            let page = buffer.page.borrow();
+            let body = &page[PAGE_LSN_SIZE..];
+            let mut magic = [0u8; 8];
+            magic.copy_from_slice(&body[..8]);
+            let mut version = [0u8; 4];
+            version.copy_from_slice(&body[8..12]);
+            let mut page_id = [0u8; 8];
+            page_id.copy_from_slice(&body[12..20]);
+            let mut fsm_page_id = [0u8; 8];
+            fsm_page_id.copy_from_slice(&body[20..28]);
+            (
+                magic,
+                u32::from_le_bytes(version),
+                PageId(u64::from_le_bytes(page_id)),
+                PageId(u64::from_le_bytes(fsm_page_id)),
+            )
+        };
+        // ヘッダーを書く前にクラッシュした場合も、ページ0は0で埋まっている
+        if magic == [0u8; 8] {
+            let heap = HeapTable::create(bufmgr, txn)?;
+            let buffer = bufmgr.fetch_page(HEADER_PAGE_ID)?;
+            txn.log().modify_page(&buffer, |body| {
+                body[..8].copy_from_slice(MAGIC);
+                body[8..12].copy_from_slice(&FORMAT_VERSION.to_le_bytes());
+                body[12..20].copy_from_slice(&heap.first_page_id.0.to_le_bytes());
+                body[20..28].copy_from_slice(&heap.fsm.first_page_id.0.to_le_bytes());
+            })?;
+            return Ok(Self { heap });
+        }
+        if &magic != MAGIC {
+            return Err(invalid_data("not a database file"));
+        }
+        if version != FORMAT_VERSION {
+            return Err(invalid_data(&version_mismatch(version)));
+        }
+        Ok(Self {
+            heap: HeapTable::open(first_page_id, fsm_page_id),
+        })
+    }
+
+    // txnから見えるカタログのレコードを全て読む
+    fn entries(&self, bufmgr: &mut BufferPoolManager, txn: &Transaction) -> Result<Vec<(RecordId, Entry)>, Error> {
+        let mut iter = self.heap.scan();
+        let mut entries = vec![];
+        while let Some((rid, bytes)) = iter.next(bufmgr, txn)? {
+            let entry = Entry::decode(&bytes).ok_or_else(|| invalid_data("broken catalog entry"))?;
+            entries.push((rid, entry));
+        }
+        Ok(entries)
+    }
+
+    fn find_table(&self, bufmgr: &mut BufferPoolManager, txn: &Transaction, name: &str) -> Result<Option<(RecordId, TableDef)>, Error> {
+        Ok(self.entries(bufmgr, txn)?.into_iter().find_map(|(rid, entry)| match entry {
+            Entry::Table(table) if table.name == name => Some((rid, table)),
+            _ => None,
+        }))
+    }
+
+    fn find_indexes(
+        &self,
+        bufmgr: &mut BufferPoolManager,
+        txn: &Transaction,
+        f: impl Fn(&IndexDef) -> bool,
+    ) -> Result<Vec<(RecordId, IndexDef)>, Error> {
+        Ok(self
+            .entries(bufmgr, txn)?
+            .into_iter()
+            .filter_map(|(rid, entry)| match entry {
+                Entry::Index(index) if f(&index) => Some((rid, index)),
+                _ => None,
+            })
+            .collect())
+    }
+
+    /// テーブルを作る。同じ名前のテーブルがあればError::DuplicateKey
+    pub fn create_table(&self, bufmgr: &mut BufferPoolManager, txn: &Transaction, name: &str, schema: Schema) -> Result<TableDef, Error> {
+        if self.find_table(bufmgr, txn, name)?.is_some() {
+            return Err(Error::DuplicateKey);
+        }
+        let table = TableDef {
+            name: name.to_string(),
+            schema,
+            heap: HeapTable::create(bufmgr, txn)?,
+        };
+        let entry = Entry::Table(table);
+        self.heap.insert(bufmgr, txn, &entry.encode())?;
+        match entry {
+            Entry::Table(table) => Ok(table),
+            _ => unreachable!(),
+        }
+    }
+
+    fn find_stats(&self, bufmgr: &mut BufferPoolManager, txn: &Transaction, table: &str) -> Result<Option<(RecordId, TableStats)>, Error> {
+        Ok(self.entries(bufmgr, txn)?.into_iter().find_map(|(rid, entry)| match entry {
+            Entry::Stats(stats) if stats.table == table => Some((rid, stats)),
+            _ => None,
+        }))
+    }
+
+    /// テーブルと、そのテーブルのインデックス・統計情報をカタログから削除する
+    /// ページはまだ回収しない
+    pub fn drop_table(&self, bufmgr: &mut BufferPoolManager, txn: &Transaction, name: &str) -> Result<TableDef, Error> {
+        let (rid, table) = self.find_table(bufmgr, txn, name)?.ok_or(Error::NotFound)?;
+        for (rid, _) in self.find_indexes(bufmgr, txn, |index| index.table == name)? {
+            self.heap.delete(bufmgr, txn, rid)?;
+        }
+        if let Some((rid, _)) = self.find_stats(bufmgr, txn, name)? {
+            self.heap.delete(bufmgr, txn, rid)?;
+        }
+        self.heap.delete(bufmgr, txn, rid)?;
+        Ok(table)
+    }
+
+    pub fn table(&self, bufmgr: &mut BufferPoolManager, txn: &Transaction, name: &str) -> Result<Option<TableDef>, Error> {
+        Ok(self.find_table(bufmgr, txn, name)?.map(|(_, table)| table))
+    }
+
+    pub fn tables(&self, bufmgr: &mut BufferPoolManager, txn: &Transaction) -> Result<Vec<TableDef>, Error> {
+        Ok(self
+            .entries(bufmgr, txn)?
+            .into_iter()
+            .filter_map(|(_, entry)| match entry {
+                Entry::Table(table) => Some(table),
+                _ => None,
+            })
+            .collect())
+    }
+
+    /// インデックスを作る
+    /// テーブルや列が無ければError::NotFound、同じ名前のインデックスがあればError::DuplicateKey
+    /// カタログに登録するだけなので、既にある行からキーを作るのは呼び出し側で行う
+    #[allow(clippy::too_many_arguments)]
+    pub fn create_index(
+        &self,
+        bufmgr: &mut BufferPoolManager,
+        txn: &Transaction,
+        name: &str,
+        table: &str,
+        columns: &[&str],
+        unique: bool,
+        kind: IndexKind,
+    ) -> Result<IndexDef, Error> {
+        let (_, table_def) = self.find_table(bufmgr, txn, table)?.ok_or(Error::NotFound)?;
+        if columns.is_empty() || columns.iter().any(|column| table_def.schema.position(column).is_none()) {
+            return Err(Error::NotFound);
+        }
+        if !self.find_indexes(bufmgr, txn, |index| index.name == name)?.is_empty() {
+            return Err(Error::DuplicateKey);
+        }
+        let index = IndexDef {
+            name: name.to_string(),
+            table: table.to_string(),
+            columns: columns.iter().map(|column| column.to_string()).collect(),
+            unique,
+            storage: IndexStorage::create(bufmgr, txn, kind)?,
+        };
+        let entry = Entry::Index(index);
+        self.heap.insert(bufmgr, txn, &entry.encode())?;
+        match entry {
+            Entry::Index(index) => Ok(index),
+            _ => unreachable!(),
+        }
+    }
+
+    /// インデックスをカタログから削除する。ページはまだ回収しない
+    pub fn drop_index(&self, bufmgr: &mut BufferPoolManager, txn: &Transaction, name: &str) -> Result<IndexDef, Error> {
+        let (rid, index) = self.find_indexes(bufmgr, txn, |index| index.name == name)?.pop().ok_or(Error::NotFound)?;
+        self.heap.delete(bufmgr, txn, rid)?;
+        Ok(index)
+    }
+
+    pub fn index(&self, bufmgr: &mut BufferPoolManager, txn: &Transaction, name: &str) -> Result<Option<IndexDef>, Error> {
+        Ok(self.find_indexes(bufmgr, txn, |index| index.name == name)?.pop().map(|(_, index)| index))
+    }
+
+    /// テーブルのインデックスを全て返す
+    pub fn indexes(&self, bufmgr: &mut BufferPoolManager, txn: &Transaction, table: &str) -> Result<Vec<IndexDef>, Error> {
+        Ok(self.find_indexes(bufmgr, txn, |index| index.table == table)?.into_iter().map(|(_, index)| index).collect())
+    }
+
+    /// テーブルの統計情報を保存する。前の統計情報は置き換える
+    /// テーブルが無ければError::NotFound
+    pub fn set_stats(&self, bufmgr: &mut BufferPoolManager, txn: &Transaction, stats: TableStats) -> Result<(), Error> {
+        if self.find_table(bufmgr, txn, &stats.table)?.is_none() {
+            return Err(Error::NotFound);
+        }
+        let old = self.find_stats(bufmgr, txn, &stats.table)?;
+        let bytes = Entry::Stats(stats).encode();
+        match old {
+            Some((rid, _)) => self.heap.update(bufmgr, txn, rid, &bytes),
+            None => self.heap.insert(bufmgr, txn, &bytes).map(|_| ()),
+        }
+    }
+
+    /// テーブルの統計情報。まだANALYZEしていなければNone
+    pub fn stats(&self, bufmgr: &mut BufferPoolManager, txn: &Transaction, table: &str) -> Result<Option<TableStats>, Error> {
+        Ok(self.find_stats(bufmgr, txn, table)?.map(|(_, stats)| stats))
+    }
+}
+
+/// ヒープファイルを開いてクラッシュリカバリを行い、カタログを読み込む
+pub fn open(heap_file_path: impl AsRef<Path>, pool_size: usize) -> Result<(BufferPoolManager, TransactionManager, Catalog), Error> {
+    let (mut bufmgr, txn_mgr, _) = transaction::open(heap_file_path, pool_size)?;
+    let txn = txn_mgr.begin()?;
+    let catalog = Catalog::bootstrap(&mut bufmgr, &txn)?;
+    txn_mgr.commit(txn)?;
+    Ok((bufmgr, txn_mgr, catalog))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wal;
+    use std::path::PathBuf;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("catalog_test_{}_{}", std::process::id(), name));
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(wal::log_path(&path));
+        path
+    }
+
+    fn users() -> Schema {
+        Schema::new(vec![Column::new("id", ColumnType::Int), Column::new("name", ColumnType::Str)])
+    }
+
+    #[test]
+    fn test_persist_catalog() {
+        let path = temp_path("persist");
+        {
+            let (mut bufmgr, txn_mgr, catalog) = open(&path, 16).unwrap();
+            let txn = txn_mgr.begin().unwrap();
+            catalog.create_table(&mut bufmgr, &txn, "users", users()).unwrap();
+            catalog.create_table(&mut bufmgr, &txn, "logs", Schema::new(vec![Column::new("body", ColumnType::Bytes)])).unwrap();
+            catalog.create_index(&mut bufmgr, &txn, "users_name", "users", &["name"], false, IndexKind::BTree).unwrap();
+            catalog.create_index(&mut bufmgr, &txn, "users_id", "users", &["id"], true, IndexKind::Hash).unwrap();
+            assert!(matches!(catalog.create_table(&mut bufmgr, &txn, "users", users()), Err(Error::DuplicateKey)));
+            assert!(matches!(catalog.create_index(&mut bufmgr, &txn, "bad", "users", &["age"], false, IndexKind::BTree), Err(Error::NotFound)));
+            txn_mgr.commit(txn).unwrap();
+
+            // ロールバックしたテーブルの作成は残らない
+            let txn = txn_mgr.begin().unwrap();
+            catalog.create_table(&mut bufmgr, &txn, "tmp", users()).unwrap();
+            txn_mgr.rollback(&mut bufmgr, txn).unwrap();
+            bufmgr.flush().unwrap();
+        }
+
+        let (mut bufmgr, txn_mgr, catalog) = open(&path, 16).unwrap();
+        let txn = txn_mgr.begin().unwrap();
+        let names: Vec<String> = catalog.tables(&mut bufmgr, &txn).unwrap().into_iter().map(|table| table.name).collect();
+        assert_eq!(names, vec!["users", "logs"]);
+        let table = catalog.table(&mut bufmgr, &txn, "users").unwrap().unwrap();
+        assert_eq!(table.schema, users());
+        let index = catalog.index(&mut bufmgr, &txn, "users_name").unwrap().unwrap();
+        assert_eq!(index.columns, vec!["name"]);
+        assert_eq!(index.storage.kind(), IndexKind::BTree);
+        let by_id = catalog.index(&mut bufmgr, &txn, "users_id").unwrap().unwrap();
+        assert_eq!(by_id.storage.kind(), IndexKind::Hash);
+        assert_eq!(catalog.indexes(&mut bufmgr, &txn, "users").unwrap(), vec![index, by_id]);
+
+        // テーブルを削除するとインデックスも消える
+        catalog.drop_table(&mut bufmgr, &txn, "users").unwrap();
+        assert_eq!(catalog.table(&mut bufmgr, &txn, "users").unwrap(), None);
+        assert_eq!(catalog.index(&mut bufmgr, &txn, "users_name").unwrap(), None);
+        assert!(matches!(catalog.drop_index(&mut bufmgr, &txn, "users_name"), Err(Error::NotFound)));
+        txn_mgr.commit(txn).unwrap();
+    }
+
+    #[test]
+    fn test_reject_unknown_file() {
+        let path = temp_path("unknown");
+        std::fs::write(&path, vec![1u8; 4096]).unwrap();
+        assert!(open(&path, 16).is_err());
+    }
+
+    #[test]
+    fn test_reject_old_format_version() {
+        use std::os::unix::fs::FileExt;
+
+        let path = temp_path("old_version");
+        let (mut bufmgr, _, _) = open(&path, 16).unwrap();
+        bufmgr.flush().unwrap();
+        drop(bufmgr);
+        // フリースペースマップを持たない古い形式(バージョン2)のファイルにする
+        let file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+        file.write_all_at(&2u32.to_le_bytes(), PAGE_LSN_SIZE as u64 + 8).unwrap();
+        drop(file);
+        let _ = std::fs::remove_file(wal::log_path(&path));
+        let err = open(&path, 16).err().unwrap().to_string();
+        assert!(err.contains("version 2 is older"), "{}", err);
+        assert!(err.contains("free space map page in the file header, table statistics"), "{}", err);
+        assert!(version_mismatch(FORMAT_VERSION + 1).contains("newer"));
+    }
+}
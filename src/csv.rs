@@ -0,0 +1,211 @@
+// CSVの読み書き(RFC 4180)
+// - フィールドはカンマで区切り、レコードは改行(LFかCRLF)で区切る
+// - カンマ・ダブルクォート・改行を含むフィールドはダブルクォートで囲み、中のダブルクォートは2つ重ねる
+// - 囲んでいない空のフィールドはNULL、囲んだ空のフィールド("")は空文字列として扱う(PostgreSQLのCOPYと同じ)
+//
+// 列の値との変換
+// - INT, UINT, FLOAT：数値の文字列
+// - TEXT：そのまま
+// - BLOB：\xに続けて16進数(PostgreSQLのbyteaと同じ)
+
+use std::io::{self, BufRead, Write};
+
+use crate::catalog::ColumnType;
+use crate::memcmpable::Value;
+
+/// フィールドの値。NoneはNULL
+pub type Field = Option<String>;
+
+fn invalid_data(line: usize, msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("line {}: {}", line, msg))
+}
+
+/// CSVのレコードを1つずつ読むリーダー
+pub struct Reader<R> {
+    inner: R,
+    // 読み終わった行の数
+    line: usize,
+}
+
+impl<R: BufRead> Reader<R> {
+    pub fn new(inner: R) -> Self {
+        Self { inner, line: 0 }
+    }
+
+    // 1行読んで、末尾の改行を取り除く。終わりに達したらNone
+    fn read_line(&mut self) -> io::Result<Option<String>> {
+        let mut line = String::new();
+        if self.inner.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        self.line += 1;
+        if line.ends_with('\n') {
+            line.pop();
+            if line.ends_with('\r') {
+                line.pop();
+            }
+        }
+        Ok(Some(line))
+    }
+
+    /// 次のレコードと、レコードが始まる行の番号(1から数える)を返す。終わりに達したらNone
+    /// 囲んだフィールドの中の改行はフィールドの一部なので、1つのレコードが複数行にまたがることがある
+    pub fn next_record(&mut self) -> io::Result<Option<(usize, Vec<Field>)>> {
+        let mut line = match self.read_line()? {
+            Some(line) => line,
+            None => return Ok(None),
+        };
+        let start = self.line;
+        let mut fields = vec![];
+        let mut pos = 0;
+        loop {
+            let rest = &line[pos..];
+            if !rest.starts_with('"') {
+                // 囲んでいないフィールドは次のカンマまで
+                let end = rest.find(',').unwrap_or(rest.len());
+                let field = &rest[..end];
+                if field.contains('"') {
+                    return Err(invalid_data(self.line, "unexpected quote in unquoted field"));
+                }
+                fields.push(if field.is_empty() { None } else { Some(field.to_string()) });
+                if end == rest.len() {
+                    return Ok(Some((start, fields)));
+                }
+                pos += end + 1;
+                continue;
+            }
+            // 囲んだフィールドは、重ねていないダブルクォートまで(行をまたぐこともある)
+            let mut field = String::new();
+            pos += 1;
+            loop {
+                match line[pos..].find('"') {
+                    Some(i) if line[pos + i + 1..].starts_with('"') => {
+                        field.push_str(&line[pos..pos + i + 1]);
+                        pos += i + 2;
+                    }
+                    Some(i) => {
+                        field.push_str(&line[pos..pos + i]);
+                        pos += i + 1;
+                        break;
+                    }
+                    None => {
+                        field.push_str(&line[pos..]);
+                        field.push('\n');
+                        line = self.read_line()?.ok_or_else(|| invalid_data(start, "unterminated quoted field"))?;
+                        pos = 0;
+                    }
+                }
+            }
+            fields.push(Some(field));
+            let rest = &line[pos..];
+            if rest.is_empty() {
+                return Ok(Some((start, fields)));
+            }
+            if !rest.starts_with(',') {
+                return Err(invalid_data(self.line, "unexpected character after closing quote"));
+            }
+            pos += 1;
+        }
+    }
+}
+
+/// レコードを1つ書く
+pub fn write_record(writer: &mut impl Write, fields: &[Field]) -> io::Result<()> {
+    for (i, field) in fields.iter().enumerate() {
+        if i > 0 {
+            writer.write_all(b",")?;
+        }
+        match field {
+            None => {}
+            Some(field) if field.is_empty() || field.contains([',', '"', '\n', '\r']) => {
+                write!(writer, "\"{}\"", field.replace('"', "\"\""))?;
+            }
+            Some(field) => writer.write_all(field.as_bytes())?,
+        }
+    }
+    writer.write_all(b"\n")
+}
+
+/// フィールドを列の型の値にする。変換できなければエラーの説明を返す
+pub fn parse_field(field: &Field, ty: ColumnType) -> Result<Value, String> {
+    let text = match field {
+        Some(text) => text,
+        None => return Ok(Value::Null),
+    };
+    let name = match ty {
+        ColumnType::Int => "INT",
+        ColumnType::UInt => "UINT",
+        ColumnType::Float => "FLOAT",
+        ColumnType::Str => "TEXT",
+        ColumnType::Bytes => "BLOB",
+    };
+    let invalid = || format!("invalid {} value '{}'", name, text);
+    Ok(match ty {
+        ColumnType::Int => Value::Int(text.trim().parse().map_err(|_| invalid())?),
+        ColumnType::UInt => Value::UInt(text.trim().parse().map_err(|_| invalid())?),
+        ColumnType::Float => Value::Float(text.trim().parse().map_err(|_| invalid())?),
+        ColumnType::Str => Value::Str(text.clone()),
+        ColumnType::Bytes => {
+            let hex = text.strip_prefix("\\x").filter(|hex| hex.len() % 2 == 0 && hex.is_ascii()).ok_or_else(invalid)?;
+            let bytes = (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(&hex[i..i + 2], 16)).collect::<Result<Vec<_>, _>>();
+            Value::Bytes(bytes.map_err(|_| invalid())?)
+        }
+    })
+}
+
+/// 値をフィールドにする
+pub fn format_field(value: &Value) -> Field {
+    match value {
+        Value::Null => None,
+        Value::Int(n) => Some(n.to_string()),
+        Value::UInt(n) => Some(n.to_string()),
+        Value::Float(f) => Some(f.to_string()),
+        Value::Str(s) => Some(s.clone()),
+        Value::Bytes(bytes) => Some(format!("\\x{}", bytes.iter().map(|b| format!("{:02x}", b)).collect::<String>())),
+        Value::Tuple(_) => Some(crate::sql::format_value(value)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_write() {
+        let records: Vec<Vec<Field>> = vec![
+            vec![Some("id".to_string()), Some("name".to_string())],
+            vec![Some("1".to_string()), Some("a, \"quoted\"\nline".to_string())],
+            vec![None, Some("".to_string())],
+        ];
+        let mut bytes = vec![];
+        for record in &records {
+            write_record(&mut bytes, record).unwrap();
+        }
+        assert_eq!(String::from_utf8(bytes.clone()).unwrap(), "id,name\n1,\"a, \"\"quoted\"\"\nline\"\n,\"\"\n");
+        let mut reader = Reader::new(&bytes[..]);
+        assert_eq!(reader.next_record().unwrap(), Some((1, records[0].clone())));
+        // 改行を含むフィールドのレコードは2行にまたがるので、次のレコードは4行目から
+        assert_eq!(reader.next_record().unwrap(), Some((2, records[1].clone())));
+        assert_eq!(reader.next_record().unwrap(), Some((4, records[2].clone())));
+        assert_eq!(reader.next_record().unwrap(), None);
+
+        let mut reader = Reader::new(&b"a,b\r\n1,\"x\"y\n"[..]);
+        assert_eq!(reader.next_record().unwrap(), Some((1, vec![Some("a".to_string()), Some("b".to_string())])));
+        assert!(reader.next_record().unwrap_err().to_string().starts_with("line 2:"));
+        let mut reader = Reader::new(&b"1,\"open\n"[..]);
+        assert!(reader.next_record().is_err());
+    }
+
+    #[test]
+    fn test_convert() {
+        assert_eq!(parse_field(&Some("-3".to_string()), ColumnType::Int), Ok(Value::Int(-3)));
+        assert_eq!(parse_field(&None, ColumnType::Int), Ok(Value::Null));
+        assert!(parse_field(&Some("-3".to_string()), ColumnType::UInt).is_err());
+        assert!(parse_field(&Some("abc".to_string()), ColumnType::Float).is_err());
+        let bytes = Value::Bytes(vec![0, 0xab, 0xff]);
+        assert_eq!(format_field(&bytes), Some("\\x00abff".to_string()));
+        assert_eq!(parse_field(&format_field(&bytes), ColumnType::Bytes), Ok(bytes));
+        assert!(parse_field(&Some("\\x0".to_string()), ColumnType::Bytes).is_err());
+        assert_eq!(parse_field(&format_field(&Value::Float(0.1)), ColumnType::Float), Ok(Value::Float(0.1)));
+    }
+}
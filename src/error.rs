@@ -0,0 +1,45 @@
+// ストレージ層(バッファプール・WAL・テーブル・インデックスなど)で共通に使うエラー
+use std::fmt;
+use std::io;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    // 全てのバッファが使用中(pin止めされている)で追い出せるバッファがない
+    NoFreeBuffer,
+    // ユニークなキーが既に存在する
+    DuplicateKey,
+    // レコードやキーが大きすぎてページに収まらない
+    TooLarge,
+    // 指定したレコードが存在しない
+    NotFound,
+    // 他のトランザクションが先に同じレコードを書き換えていた(書き込み同士の衝突)
+    // トランザクションをロールバックしてやり直す必要がある
+    Conflict,
+    // デッドロックの犠牲に選ばれた
+    // トランザクションをロールバックしてやり直す必要がある
+    Deadlock,
+}
+
+// ?演算子でio::ErrorをErrorに変換できるようにする
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(err) => write!(f, "I/O error: {}", err),
+            Error::NoFreeBuffer => write!(f, "no free buffer available in buffer pool"),
+            Error::DuplicateKey => write!(f, "duplicate key"),
+            Error::TooLarge => write!(f, "record is too large"),
+            Error::NotFound => write!(f, "record not found"),
+            Error::Deadlock => write!(f, "deadlock detected"),
+            Error::Conflict => write!(f, "could not serialize access due to concurrent update"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
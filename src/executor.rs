@@ -0,0 +1,1289 @@
+// クエリの実行器(Volcanoモデル)
+// 演算子を木の形に組み立て、根のnext()を呼ぶと子のnext()を呼びながら1行ずつ結果を返す
+//
+// - PlanNode：実行計画の1つの演算子。start()で実行を開始してExecutorを返す
+// - Executor：実行中の状態。next()を呼ぶたびに1行返し、終わればNone
+//
+// 行はmemcmpableの値の並び(Tuple)で、ヒープテーブルにはエンコードしたバイト列を保存する
+// インデックスのキーは「インデックスの列の値 + RecordId」をエンコードしたもの
+// RecordIdを付けるのは、MVCCで古い版を指すエントリが残っていても、キーが重複しないようにするため
+// ハッシュインデックスは同じキーに複数の値を持てるので、キーは列の値だけにしてRecordIdは値の方に入れる
+// どちらも、インデックスから読んだ行は今の値でキーを作り直して、エントリと一致するか確かめる
+
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ops::Bound;
+
+use crate::btree::{self, SearchMode};
+use crate::buffer_pool::{BufferPoolManager, Error};
+use crate::catalog::{ColumnType, IndexDef, IndexStorage, TableDef};
+use crate::disk_manager::PageId;
+use crate::expr::{self, Expr};
+use crate::external_sort::{ExternalSorter, SortedRows, WORK_MEMORY};
+use crate::heap::{HeapIter, RecordId};
+use crate::memcmpable::{self, Value};
+use crate::transaction::Transaction;
+use crate::wal::TxnId;
+
+/// ストレージ層のエラーも式の評価のエラーも、まとめて扱えるようにする
+pub type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+pub type Tuple = Vec<Value>;
+
+pub trait Executor {
+    fn next(&mut self, bufmgr: &mut BufferPoolManager, txn: &Transaction) -> Result<Option<Tuple>>;
+}
+
+pub type BoxExecutor<'a> = Box<dyn Executor + 'a>;
+
+pub trait PlanNode {
+    fn start(&self, bufmgr: &mut BufferPoolManager, txn: &Transaction) -> Result<BoxExecutor<'_>>;
+}
+
+/// 実行して全ての行を集める
+pub fn collect(plan: &dyn PlanNode, bufmgr: &mut BufferPoolManager, txn: &Transaction) -> Result<Vec<Tuple>> {
+    let mut exec = plan.start(bufmgr, txn)?;
+    let mut rows = vec![];
+    while let Some(row) = exec.next(bufmgr, txn)? {
+        rows.push(row);
+    }
+    Ok(rows)
+}
+
+pub fn encode_row(row: &[Value]) -> Vec<u8> {
+    let mut bytes = vec![];
+    memcmpable::encode(row, &mut bytes);
+    bytes
+}
+
+pub fn decode_row(bytes: &[u8]) -> Result<Tuple> {
+    Ok(memcmpable::decode(bytes)?)
+}
+
+// インデックスの列の値を取り出す
+fn index_values(table: &TableDef, index: &IndexDef, row: &[Value]) -> Result<Vec<Value>> {
+    index
+        .columns
+        .iter()
+        .map(|column| {
+            let pos = table.schema.position(column).ok_or_else(|| format!("column {} does not exist", column))?;
+            Ok(row[pos].clone())
+        })
+        .collect()
+}
+
+fn index_key(values: &[Value], rid: RecordId) -> Vec<u8> {
+    let mut key = encode_row(values);
+    memcmpable::encode(&[Value::UInt(rid.page_id.0), Value::UInt(rid.slot as u64)], &mut key);
+    key
+}
+
+fn rid_bytes(rid: RecordId) -> Vec<u8> {
+    let mut bytes = rid.page_id.0.to_le_bytes().to_vec();
+    bytes.extend_from_slice(&rid.slot.to_le_bytes());
+    bytes
+}
+
+fn rid_from_bytes(bytes: &[u8]) -> RecordId {
+    let mut page_id = [0u8; 8];
+    page_id.copy_from_slice(&bytes[..8]);
+    RecordId {
+        page_id: PageId(u64::from_le_bytes(page_id)),
+        slot: u16::from_le_bytes([bytes[8], bytes[9]]),
+    }
+}
+
+// 行の値がスキーマの型と合っているか確かめる。NULLはどの列にも入れられる
+fn check_row(table: &TableDef, row: &[Value]) -> Result<()> {
+    if row.len() != table.schema.columns.len() {
+        return Err(format!("table {} has {} columns but {} values were given", table.name, table.schema.columns.len(), row.len()).into());
+    }
+    for (column, value) in table.schema.columns.iter().zip(row) {
+        let ok = matches!(
+            (column.ty, value),
+            (_, Value::Null)
+                | (ColumnType::Int, Value::Int(_))
+                | (ColumnType::UInt, Value::UInt(_))
+                | (ColumnType::Float, Value::Float(_))
+                | (ColumnType::Bytes, Value::Bytes(_))
+                | (ColumnType::Str, Value::Str(_))
+        );
+        if !ok {
+            return Err(format!("column {} expects {} but got {}", column.name, column.ty, expr::sql_literal(value)).into());
+        }
+    }
+    Ok(())
+}
+
+// インデックスに列の値とRecordIdのエントリを追加する
+fn insert_entry(bufmgr: &mut BufferPoolManager, txn: &Transaction, index: &IndexDef, values: &[Value], rid: RecordId) -> std::result::Result<(), Error> {
+    match &index.storage {
+        IndexStorage::BTree(btree) => btree.insert(bufmgr, txn, &index_key(values, rid), &rid_bytes(rid)),
+        IndexStorage::Hash(hash) => hash.insert(bufmgr, txn, &encode_row(values), &rid_bytes(rid)),
+    }
+}
+
+// 列の値がvaluesのエントリが指す行(古い版を指すものも含む)
+fn lookup_entries(bufmgr: &mut BufferPoolManager, txn: &Transaction, index: &IndexDef, values: &[Value]) -> Result<Vec<RecordId>> {
+    let prefix = encode_row(values);
+    let mut rids = vec![];
+    match &index.storage {
+        IndexStorage::BTree(btree) => {
+            let mut iter = btree.iter(bufmgr, txn, SearchMode::Key(prefix.clone()))?;
+            while let Some((key, value)) = iter.next(bufmgr)? {
+                if !key.starts_with(&prefix) {
+                    break;
+                }
+                rids.push(rid_from_bytes(&value));
+            }
+        }
+        IndexStorage::Hash(hash) => rids.extend(hash.search(bufmgr, &prefix)?.iter().map(|value| rid_from_bytes(value))),
+    }
+    Ok(rids)
+}
+
+// ユニークインデックスに同じ値の行が見えていればエラー
+// NULLを含むキーは重複とみなさない
+fn check_unique(bufmgr: &mut BufferPoolManager, txn: &Transaction, table: &TableDef, index: &IndexDef, values: &[Value], except: Option<RecordId>) -> Result<()> {
+    if !index.unique || values.contains(&Value::Null) {
+        return Ok(());
+    }
+    for rid in lookup_entries(bufmgr, txn, index, values)? {
+        if Some(rid) == except {
+            continue;
+        }
+        if let Some(bytes) = table.heap.get(bufmgr, txn, rid)? {
+            if index_values(table, index, &decode_row(&bytes)?)? == values {
+                return Err(Error::DuplicateKey.into());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// 行を追加し、インデックスにもエントリを追加する
+pub fn insert_row(bufmgr: &mut BufferPoolManager, txn: &Transaction, table: &TableDef, indexes: &[IndexDef], row: &[Value]) -> Result<RecordId> {
+    check_row(table, row)?;
+    for index in indexes {
+        check_unique(bufmgr, txn, table, index, &index_values(table, index, row)?, None)?;
+    }
+    let rid = table.heap.insert(bufmgr, txn, &encode_row(row))?;
+    for index in indexes {
+        insert_entry(bufmgr, txn, index, &index_values(table, index, row)?, rid)?;
+    }
+    Ok(rid)
+}
+
+/// 作ったばかりのインデックスに、テーブルに今ある行のエントリを追加する
+pub fn build_index(bufmgr: &mut BufferPoolManager, txn: &Transaction, table: &TableDef, index: &IndexDef) -> Result<()> {
+    let mut entries = entry_sorter();
+    let mut iter = table.heap.scan();
+    while let Some((rid, bytes)) = iter.next(bufmgr, txn)? {
+        entries.push(index_entry(index_values(table, index, &decode_row(&bytes)?)?, rid))?;
+    }
+    fill_index(bufmgr, txn, table, index, entries)
+}
+
+/// 行をまとめてテーブルに追加し、追加した行数を返す(COPYなど)
+/// ヒープにはHeapLoaderで新しいページに詰め、インデックスのエントリはキーの順に並び替えてから入れる
+/// 空のB+treeは下から組み立てるので、コミットするまで他のトランザクションはテーブルに書き込まないこと
+pub fn load_rows(
+    bufmgr: &mut BufferPoolManager,
+    txn: &Transaction,
+    table: &TableDef,
+    indexes: &[IndexDef],
+    rows: impl IntoIterator<Item = Result<Tuple>>,
+) -> Result<u64> {
+    let mut loader = table.heap.loader();
+    let mut entries: Vec<ExternalSorter> = indexes.iter().map(|_| entry_sorter()).collect();
+    let mut count = 0;
+    for row in rows {
+        let row = row?;
+        check_row(table, &row)?;
+        let rid = loader.push(bufmgr, txn, &encode_row(&row))?;
+        for (index, entries) in indexes.iter().zip(&mut entries) {
+            entries.push(index_entry(index_values(table, index, &row)?, rid))?;
+        }
+        count += 1;
+    }
+    loader.finish(bufmgr, txn)?;
+    for (index, entries) in indexes.iter().zip(entries) {
+        fill_index(bufmgr, txn, table, index, entries)?;
+    }
+    Ok(count)
+}
+
+// インデックスのエントリをB+treeのキーの順に並び替える
+// 行は| B+treeのキー | RecordId | 列の値 |
+fn entry_sorter() -> ExternalSorter {
+    ExternalSorter::new(vec![SortKey { expr: Expr::column(0), desc: false }], WORK_MEMORY)
+}
+
+fn index_entry(values: Vec<Value>, rid: RecordId) -> Tuple {
+    vec![Value::Bytes(index_key(&values, rid)), Value::Bytes(rid_bytes(rid)), Value::Tuple(values)]
+}
+
+// 並び替えたエントリをインデックスに入れる
+// 空のB+treeなら下から組み立て、そうでなければ1件ずつ追加する(キーの順なので、同じページを続けて使える)
+// 同じ値は隣り合うので、追加する行同士のユニーク制約は直前のエントリと比べるだけで確かめられる
+fn fill_index(bufmgr: &mut BufferPoolManager, txn: &Transaction, table: &TableDef, index: &IndexDef, entries: ExternalSorter) -> Result<()> {
+    let mut builder = match &index.storage {
+        IndexStorage::BTree(btree) if btree.is_empty(bufmgr)? => Some(btree.builder(bufmgr)?),
+        _ => None,
+    };
+    let mut prev: Option<Vec<Value>> = None;
+    for entry in entries.finish()? {
+        let (key, rid, values) = match entry?.as_slice() {
+            [Value::Bytes(key), Value::Bytes(rid), Value::Tuple(values)] => (key.clone(), rid_from_bytes(rid), values.clone()),
+            _ => unreachable!(),
+        };
+        if index.unique && !values.contains(&Value::Null) {
+            if prev.as_ref() == Some(&values) {
+                return Err(Error::DuplicateKey.into());
+            }
+            // 空のインデックスでなければ、前からある行とも比べる
+            if builder.is_none() {
+                check_unique(bufmgr, txn, table, index, &values, None)?;
+            }
+        }
+        match &mut builder {
+            Some(builder) => builder.push(bufmgr, txn, &key, &rid_bytes(rid))?,
+            None => insert_entry(bufmgr, txn, index, &values, rid)?,
+        }
+        prev = Some(values);
+    }
+    if let Some(builder) = builder {
+        builder.finish(bufmgr, txn)?;
+    }
+    Ok(())
+}
+
+/// 行を書き換える
+/// キーが変わったインデックスには新しいエントリを追加する(古いエントリは古い版のために残す)
+pub fn update_row(
+    bufmgr: &mut BufferPoolManager,
+    txn: &Transaction,
+    table: &TableDef,
+    indexes: &[IndexDef],
+    rid: RecordId,
+    row: &[Value],
+) -> Result<()> {
+    check_row(table, row)?;
+    let old = table.heap.get(bufmgr, txn, rid)?.ok_or(Error::NotFound)?;
+    let old = decode_row(&old)?;
+    for index in indexes {
+        check_unique(bufmgr, txn, table, index, &index_values(table, index, row)?, Some(rid))?;
+    }
+    table.heap.update(bufmgr, txn, rid, &encode_row(row))?;
+    for index in indexes {
+        let values = index_values(table, index, row)?;
+        if values != index_values(table, index, &old)? {
+            match insert_entry(bufmgr, txn, index, &values, rid) {
+                // 以前の値に戻した場合は、その時のエントリが残っている
+                Err(Error::DuplicateKey) => {}
+                result => result?,
+            }
+        }
+    }
+    Ok(())
+}
+
+/// 行を削除する
+/// インデックスのエントリは古い版のために残し、VACUUMで回収した行の分を取り除く
+pub fn delete_row(bufmgr: &mut BufferPoolManager, txn: &Transaction, table: &TableDef, rid: RecordId) -> Result<()> {
+    table.heap.delete(bufmgr, txn, rid)?;
+    Ok(())
+}
+
+/// VACUUMの結果
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct VacuumReport {
+    /// 回収した行(削除が全員から見えるようになったもの)
+    pub removed: usize,
+    /// 回収した古い版
+    pub versions: usize,
+    /// 取り除いたインデックスのエントリ
+    pub index_entries: usize,
+    /// 解放したヒープのページ
+    pub freed_pages: u64,
+}
+
+/// テーブルのVACUUM
+/// horizonより前に消された版を回収し、どの版も指さなくなったインデックスのエントリを取り除いてから、
+/// ヒープのページを詰め直して空いたページを解放する
+/// 取り除いたエントリはロールバックすると戻ってしまうので、終わったらすぐにコミットすること
+pub fn vacuum(bufmgr: &mut BufferPoolManager, txn: &Transaction, table: &TableDef, indexes: &[IndexDef], horizon: TxnId) -> Result<VacuumReport> {
+    let gc = table.heap.gc(bufmgr, txn, horizon)?;
+    let removed: HashSet<RecordId> = gc.removed.iter().copied().collect();
+    let mut index_entries = 0;
+    for index in indexes {
+        index_entries += vacuum_index(bufmgr, txn, table, index, &removed)?;
+    }
+    let freed_pages = table.heap.compact(bufmgr, txn)?;
+    Ok(VacuumReport {
+        removed: gc.removed.len(),
+        versions: gc.versions,
+        index_entries,
+        freed_pages,
+    })
+}
+
+// 回収した行や、残っているどの版とも値が合わないエントリを取り除き、取り除いた数を返す
+fn vacuum_index(bufmgr: &mut BufferPoolManager, txn: &Transaction, table: &TableDef, index: &IndexDef, removed: &HashSet<RecordId>) -> Result<usize> {
+    let entries = match &index.storage {
+        IndexStorage::BTree(btree) => {
+            let mut iter = btree.iter(bufmgr, txn, SearchMode::Start)?;
+            let mut entries = vec![];
+            while let Some(entry) = iter.next(bufmgr)? {
+                entries.push(entry);
+            }
+            entries
+        }
+        IndexStorage::Hash(hash) => hash.entries(bufmgr)?,
+    };
+    let mut garbage = vec![];
+    for (key, value) in entries {
+        let rid = rid_from_bytes(&value);
+        let mut live = false;
+        if !removed.contains(&rid) {
+            for version in table.heap.versions(bufmgr, rid)? {
+                let values = index_values(table, index, &decode_row(&version)?)?;
+                let version_key = match &index.storage {
+                    IndexStorage::BTree(_) => index_key(&values, rid),
+                    IndexStorage::Hash(_) => encode_row(&values),
+                };
+                if version_key == key {
+                    live = true;
+                    break;
+                }
+            }
+        }
+        if !live {
+            garbage.push((key, value));
+        }
+    }
+    for (key, value) in &garbage {
+        match &index.storage {
+            IndexStorage::BTree(btree) => btree.delete(bufmgr, txn, key)?,
+            IndexStorage::Hash(hash) => hash.delete(bufmgr, txn, key, value)?,
+        }
+    }
+    Ok(garbage.len())
+}
+
+/// テーブルを先頭から全て読む
+pub struct SeqScan {
+    pub table: TableDef,
+}
+
+impl PlanNode for SeqScan {
+    fn start(&self, _bufmgr: &mut BufferPoolManager, _txn: &Transaction) -> Result<BoxExecutor<'_>> {
+        Ok(Box::new(ExecSeqScan { iter: self.table.heap.scan() }))
+    }
+}
+
+pub struct ExecSeqScan {
+    iter: HeapIter,
+}
+
+impl Executor for ExecSeqScan {
+    fn next(&mut self, bufmgr: &mut BufferPoolManager, txn: &Transaction) -> Result<Option<Tuple>> {
+        match self.iter.next(bufmgr, txn)? {
+            Some((_, bytes)) => Ok(Some(decode_row(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+/// インデックスの列の値が範囲に入る行を、インデックスの順に読む
+/// 範囲はインデックスの先頭の列から順に指定した値の並びで、途中の列までしか指定しなくても良い
+pub struct IndexScan {
+    pub table: TableDef,
+    pub index: IndexDef,
+    pub lower: Bound<Vec<Value>>,
+    pub upper: Bound<Vec<Value>>,
+}
+
+impl PlanNode for IndexScan {
+    fn start(&self, bufmgr: &mut BufferPoolManager, txn: &Transaction) -> Result<BoxExecutor<'_>> {
+        let mode = match &self.lower {
+            Bound::Included(values) | Bound::Excluded(values) => SearchMode::Key(encode_row(values)),
+            Bound::Unbounded => SearchMode::Start,
+        };
+        let encode_bound = |bound: &Bound<Vec<Value>>| match bound {
+            Bound::Included(values) => Bound::Included(encode_row(values)),
+            Bound::Excluded(values) => Bound::Excluded(encode_row(values)),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        let btree = match &self.index.storage {
+            IndexStorage::BTree(btree) => btree,
+            IndexStorage::Hash(_) => return Err(format!("index {} does not support range scans", self.index.name).into()),
+        };
+        Ok(Box::new(ExecIndexScan {
+            plan: self,
+            iter: btree.iter(bufmgr, txn, mode)?,
+            lower: encode_bound(&self.lower),
+            upper: encode_bound(&self.upper),
+        }))
+    }
+}
+
+pub struct ExecIndexScan<'a> {
+    plan: &'a IndexScan,
+    iter: btree::Iter,
+    lower: Bound<Vec<u8>>,
+    upper: Bound<Vec<u8>>,
+}
+
+impl<'a> Executor for ExecIndexScan<'a> {
+    fn next(&mut self, bufmgr: &mut BufferPoolManager, txn: &Transaction) -> Result<Option<Tuple>> {
+        let num_columns = self.plan.index.columns.len();
+        while let Some((key, value)) = self.iter.next(bufmgr)? {
+            let values = memcmpable::decode(&key)?;
+            let columns = encode_row(&values[..num_columns]);
+            // 範囲の値で始まるキーは、その値と等しいものとして扱う
+            if let Bound::Excluded(lower) = &self.lower {
+                if columns.starts_with(lower) {
+                    continue;
+                }
+            }
+            let in_range = match &self.upper {
+                Bound::Included(upper) => columns < *upper || columns.starts_with(upper),
+                Bound::Excluded(upper) => columns < *upper,
+                Bound::Unbounded => true,
+            };
+            if !in_range {
+                return Ok(None);
+            }
+            let rid = rid_from_bytes(&value);
+            let row = match self.plan.table.heap.get(bufmgr, txn, rid)? {
+                Some(bytes) => decode_row(&bytes)?,
+                None => continue,
+            };
+            // 古い版を指すエントリは読み飛ばす
+            if encode_row(&index_values(&self.plan.table, &self.plan.index, &row)?) == columns {
+                return Ok(Some(row));
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// インデックスの全ての列の値がkeyと等しい行を読む
+/// ハッシュインデックスにも使える
+pub struct IndexLookup {
+    pub table: TableDef,
+    pub index: IndexDef,
+    pub key: Vec<Value>,
+}
+
+impl PlanNode for IndexLookup {
+    fn start(&self, bufmgr: &mut BufferPoolManager, txn: &Transaction) -> Result<BoxExecutor<'_>> {
+        let mut rids = lookup_entries(bufmgr, txn, &self.index, &self.key)?;
+        rids.reverse();
+        Ok(Box::new(ExecIndexLookup { plan: self, rids }))
+    }
+}
+
+pub struct ExecIndexLookup<'a> {
+    plan: &'a IndexLookup,
+    // 後ろから取り出す
+    rids: Vec<RecordId>,
+}
+
+impl<'a> Executor for ExecIndexLookup<'a> {
+    fn next(&mut self, bufmgr: &mut BufferPoolManager, txn: &Transaction) -> Result<Option<Tuple>> {
+        while let Some(rid) = self.rids.pop() {
+            let row = match self.plan.table.heap.get(bufmgr, txn, rid)? {
+                Some(bytes) => decode_row(&bytes)?,
+                None => continue,
+            };
+            // 古い版を指すエントリは読み飛ばす
+            if index_values(&self.plan.table, &self.plan.index, &row)? == self.plan.key {
+                return Ok(Some(row));
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// 条件を満たす行だけを返す
+pub struct Filter {
+    pub inner: Box<dyn PlanNode>,
+    pub cond: Expr,
+}
+
+impl PlanNode for Filter {
+    fn start(&self, bufmgr: &mut BufferPoolManager, txn: &Transaction) -> Result<BoxExecutor<'_>> {
+        Ok(Box::new(ExecFilter {
+            inner: self.inner.start(bufmgr, txn)?,
+            cond: &self.cond,
+        }))
+    }
+}
+
+pub struct ExecFilter<'a> {
+    inner: BoxExecutor<'a>,
+    cond: &'a Expr,
+}
+
+impl<'a> Executor for ExecFilter<'a> {
+    fn next(&mut self, bufmgr: &mut BufferPoolManager, txn: &Transaction) -> Result<Option<Tuple>> {
+        while let Some(row) = self.inner.next(bufmgr, txn)? {
+            if self.cond.matches(&row)? {
+                return Ok(Some(row));
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// 行ごとに式を評価して、新しい行を作る
+pub struct Project {
+    pub inner: Box<dyn PlanNode>,
+    pub exprs: Vec<Expr>,
+}
+
+impl PlanNode for Project {
+    fn start(&self, bufmgr: &mut BufferPoolManager, txn: &Transaction) -> Result<BoxExecutor<'_>> {
+        Ok(Box::new(ExecProject {
+            inner: self.inner.start(bufmgr, txn)?,
+            exprs: &self.exprs,
+        }))
+    }
+}
+
+pub struct ExecProject<'a> {
+    inner: BoxExecutor<'a>,
+    exprs: &'a [Expr],
+}
+
+impl<'a> Executor for ExecProject<'a> {
+    fn next(&mut self, bufmgr: &mut BufferPoolManager, txn: &Transaction) -> Result<Option<Tuple>> {
+        match self.inner.next(bufmgr, txn)? {
+            Some(row) => Ok(Some(self.exprs.iter().map(|expr| expr.eval(&row)).collect::<Result<_>>()?)),
+            None => Ok(None),
+        }
+    }
+}
+
+/// 左の行ごとに右の行を全て組み合わせ、条件を満たすものを返す
+/// 結果の行は左の行の後ろに右の行を繋げたもの。右の行は最初に全て読んでおく
+pub struct NestedLoopJoin {
+    pub left: Box<dyn PlanNode>,
+    pub right: Box<dyn PlanNode>,
+    pub cond: Option<Expr>,
+}
+
+impl PlanNode for NestedLoopJoin {
+    fn start(&self, bufmgr: &mut BufferPoolManager, txn: &Transaction) -> Result<BoxExecutor<'_>> {
+        let right = collect(self.right.as_ref(), bufmgr, txn)?;
+        Ok(Box::new(ExecNestedLoopJoin {
+            left: self.left.start(bufmgr, txn)?,
+            right,
+            cond: self.cond.as_ref(),
+            current: None,
+            index: 0,
+        }))
+    }
+}
+
+pub struct ExecNestedLoopJoin<'a> {
+    left: BoxExecutor<'a>,
+    right: Vec<Tuple>,
+    cond: Option<&'a Expr>,
+    current: Option<Tuple>,
+    index: usize,
+}
+
+impl<'a> Executor for ExecNestedLoopJoin<'a> {
+    fn next(&mut self, bufmgr: &mut BufferPoolManager, txn: &Transaction) -> Result<Option<Tuple>> {
+        loop {
+            if let Some(left) = &self.current {
+                while self.index < self.right.len() {
+                    let mut row = left.clone();
+                    row.extend_from_slice(&self.right[self.index]);
+                    self.index += 1;
+                    if self.cond.map_or(Ok(true), |cond| cond.matches(&row))? {
+                        return Ok(Some(row));
+                    }
+                }
+            }
+            self.current = self.left.next(bufmgr, txn)?;
+            self.index = 0;
+            if self.current.is_none() {
+                return Ok(None);
+            }
+        }
+    }
+}
+
+/// 等しいキーを持つ左右の行を組み合わせる(等結合)
+/// 右の行でハッシュ表を作り、左の行で引く。NULLを含むキーはどの行とも一致しない
+pub struct HashJoin {
+    pub left: Box<dyn PlanNode>,
+    pub right: Box<dyn PlanNode>,
+    pub left_keys: Vec<Expr>,
+    pub right_keys: Vec<Expr>,
+}
+
+// 式の値をエンコードしてハッシュ表のキーにする。NULLを含めばNone
+fn hash_key(exprs: &[Expr], row: &[Value]) -> Result<Option<Vec<u8>>> {
+    let values = exprs.iter().map(|expr| expr.eval(row)).collect::<Result<Vec<_>>>()?;
+    if values.contains(&Value::Null) {
+        return Ok(None);
+    }
+    Ok(Some(encode_row(&values)))
+}
+
+impl PlanNode for HashJoin {
+    fn start(&self, bufmgr: &mut BufferPoolManager, txn: &Transaction) -> Result<BoxExecutor<'_>> {
+        let mut table: HashMap<Vec<u8>, Vec<Tuple>> = HashMap::new();
+        let mut right = self.right.start(bufmgr, txn)?;
+        while let Some(row) = right.next(bufmgr, txn)? {
+            if let Some(key) = hash_key(&self.right_keys, &row)? {
+                table.entry(key).or_default().push(row);
+            }
+        }
+        Ok(Box::new(ExecHashJoin {
+            left: self.left.start(bufmgr, txn)?,
+            left_keys: &self.left_keys,
+            table,
+            pending: vec![],
+        }))
+    }
+}
+
+pub struct ExecHashJoin<'a> {
+    left: BoxExecutor<'a>,
+    left_keys: &'a [Expr],
+    table: HashMap<Vec<u8>, Vec<Tuple>>,
+    // 左の1行に一致した結果のうち、まだ返していないもの(逆順)
+    pending: Vec<Tuple>,
+}
+
+impl<'a> Executor for ExecHashJoin<'a> {
+    fn next(&mut self, bufmgr: &mut BufferPoolManager, txn: &Transaction) -> Result<Option<Tuple>> {
+        loop {
+            if let Some(row) = self.pending.pop() {
+                return Ok(Some(row));
+            }
+            let left = match self.left.next(bufmgr, txn)? {
+                Some(left) => left,
+                None => return Ok(None),
+            };
+            if let Some(key) = hash_key(self.left_keys, &left)? {
+                for right in self.table.get(&key).into_iter().flatten().rev() {
+                    let mut row = left.clone();
+                    row.extend_from_slice(right);
+                    self.pending.push(row);
+                }
+            }
+        }
+    }
+}
+
+/// 等しいキーを持つ左右の行を組み合わせる(等結合)
+/// 左右とも結合キーの昇順に並んでいることを前提に、両方を先頭から1度ずつ読み進める
+/// 右の行は同じキーを持つ間だけ覚えておく。NULLを含むキーはどの行とも一致しない
+pub struct MergeJoin {
+    pub left: Box<dyn PlanNode>,
+    pub right: Box<dyn PlanNode>,
+    pub left_keys: Vec<Expr>,
+    pub right_keys: Vec<Expr>,
+}
+
+// 結合キーの値を比べる
+fn compare_join_keys(a: &[Value], b: &[Value]) -> Ordering {
+    a.iter().zip(b).map(|(a, b)| expr::sort_cmp(a, b)).find(|ord| ord.is_ne()).unwrap_or(Ordering::Equal)
+}
+
+// キーがNULLを含まない次の行を、キーと一緒に返す
+fn next_keyed(exec: &mut BoxExecutor<'_>, keys: &[Expr], bufmgr: &mut BufferPoolManager, txn: &Transaction) -> Result<Option<(Vec<Value>, Tuple)>> {
+    while let Some(row) = exec.next(bufmgr, txn)? {
+        let values = keys.iter().map(|expr| expr.eval(&row)).collect::<Result<Vec<_>>>()?;
+        if !values.contains(&Value::Null) {
+            return Ok(Some((values, row)));
+        }
+    }
+    Ok(None)
+}
+
+impl PlanNode for MergeJoin {
+    fn start(&self, bufmgr: &mut BufferPoolManager, txn: &Transaction) -> Result<BoxExecutor<'_>> {
+        let left = self.left.start(bufmgr, txn)?;
+        let mut right = self.right.start(bufmgr, txn)?;
+        let right_head = next_keyed(&mut right, &self.right_keys, bufmgr, txn)?;
+        Ok(Box::new(ExecMergeJoin {
+            left,
+            right,
+            left_keys: &self.left_keys,
+            right_keys: &self.right_keys,
+            right_head,
+            group_key: None,
+            group: vec![],
+            pending: vec![],
+        }))
+    }
+}
+
+pub struct ExecMergeJoin<'a> {
+    left: BoxExecutor<'a>,
+    right: BoxExecutor<'a>,
+    left_keys: &'a [Expr],
+    right_keys: &'a [Expr],
+    // 右でまだグループに入れていない先頭の行
+    right_head: Option<(Vec<Value>, Tuple)>,
+    // 直前に読んだ右の行のグループと、そのキー
+    group_key: Option<Vec<Value>>,
+    group: Vec<Tuple>,
+    // 左の1行に一致した結果のうち、まだ返していないもの(逆順)
+    pending: Vec<Tuple>,
+}
+
+impl<'a> Executor for ExecMergeJoin<'a> {
+    fn next(&mut self, bufmgr: &mut BufferPoolManager, txn: &Transaction) -> Result<Option<Tuple>> {
+        loop {
+            if let Some(row) = self.pending.pop() {
+                return Ok(Some(row));
+            }
+            let (keys, left) = match next_keyed(&mut self.left, self.left_keys, bufmgr, txn)? {
+                Some(left) => left,
+                None => return Ok(None),
+            };
+            // 左のキーが変わったら、右をそのキー以上まで読み進めてグループを作り直す
+            let same = matches!(&self.group_key, Some(group_key) if compare_join_keys(group_key, &keys).is_eq());
+            if !same {
+                self.group_key = None;
+                self.group.clear();
+                while let Some((right_keys, _)) = &self.right_head {
+                    match compare_join_keys(right_keys, &keys) {
+                        Ordering::Less => {}
+                        Ordering::Equal => {
+                            let (_, row) = self.right_head.take().unwrap();
+                            self.group.push(row);
+                        }
+                        Ordering::Greater => break,
+                    }
+                    self.right_head = next_keyed(&mut self.right, self.right_keys, bufmgr, txn)?;
+                }
+                self.group_key = Some(keys);
+            }
+            for right in self.group.iter().rev() {
+                let mut row = left.clone();
+                row.extend_from_slice(right);
+                self.pending.push(row);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SortKey {
+    pub expr: Expr,
+    pub desc: bool,
+}
+
+/// 全ての行を読んでから並び替える。NULLは昇順なら先頭に並ぶ
+/// memoryバイトを超える分は一時ファイルに書き出して、外部マージソートで並び替える
+pub struct Sort {
+    pub inner: Box<dyn PlanNode>,
+    pub keys: Vec<SortKey>,
+    pub memory: usize,
+}
+
+impl PlanNode for Sort {
+    fn start(&self, bufmgr: &mut BufferPoolManager, txn: &Transaction) -> Result<BoxExecutor<'_>> {
+        let mut sorter = ExternalSorter::new(self.keys.clone(), self.memory);
+        let mut inner = self.inner.start(bufmgr, txn)?;
+        while let Some(row) = inner.next(bufmgr, txn)? {
+            sorter.push(row)?;
+        }
+        Ok(Box::new(ExecSort { rows: sorter.finish()? }))
+    }
+}
+
+pub struct ExecSort {
+    rows: SortedRows,
+}
+
+impl Executor for ExecSort {
+    fn next(&mut self, _bufmgr: &mut BufferPoolManager, _txn: &Transaction) -> Result<Option<Tuple>> {
+        self.rows.next().transpose()
+    }
+}
+
+/// 読み込み済みの行を順番に返す
+pub struct ExecRows {
+    rows: std::vec::IntoIter<Tuple>,
+}
+
+impl Executor for ExecRows {
+    fn next(&mut self, _bufmgr: &mut BufferPoolManager, _txn: &Transaction) -> Result<Option<Tuple>> {
+        Ok(self.rows.next())
+    }
+}
+
+/// offset行を読み飛ばしてから、最大limit行を返す
+pub struct Limit {
+    pub inner: Box<dyn PlanNode>,
+    pub limit: Option<usize>,
+    pub offset: usize,
+}
+
+impl PlanNode for Limit {
+    fn start(&self, bufmgr: &mut BufferPoolManager, txn: &Transaction) -> Result<BoxExecutor<'_>> {
+        Ok(Box::new(ExecLimit {
+            inner: self.inner.start(bufmgr, txn)?,
+            skip: self.offset,
+            remaining: self.limit,
+        }))
+    }
+}
+
+pub struct ExecLimit<'a> {
+    inner: BoxExecutor<'a>,
+    skip: usize,
+    remaining: Option<usize>,
+}
+
+impl<'a> Executor for ExecLimit<'a> {
+    fn next(&mut self, bufmgr: &mut BufferPoolManager, txn: &Transaction) -> Result<Option<Tuple>> {
+        while self.skip > 0 {
+            if self.inner.next(bufmgr, txn)?.is_none() {
+                return Ok(None);
+            }
+            self.skip -= 1;
+        }
+        match &mut self.remaining {
+            Some(0) => Ok(None),
+            Some(remaining) => {
+                *remaining -= 1;
+                self.inner.next(bufmgr, txn)
+            }
+            None => self.inner.next(bufmgr, txn),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AggregateFunc {
+    // COUNT(*)：NULLも数える
+    CountStar,
+    Count,
+    Sum,
+    Min,
+    Max,
+    Avg,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AggregateExpr {
+    pub func: AggregateFunc,
+    pub arg: Expr,
+}
+
+/// group_byの値ごとに行をまとめて集約する
+/// 結果の行はgroup_byの値の後ろに集約した値を並べたもので、group_byの値の順に返す
+/// group_byが空なら、行が無くても1行返す
+pub struct Aggregate {
+    pub inner: Box<dyn PlanNode>,
+    pub group_by: Vec<Expr>,
+    pub aggregates: Vec<AggregateExpr>,
+}
+
+// 集約の途中経過
+#[derive(Debug, Clone)]
+enum Accumulator {
+    Count(u64),
+    // NULL以外の値が無ければNULL
+    Value(Value),
+    Avg(f64, u64),
+}
+
+impl Accumulator {
+    fn new(func: AggregateFunc) -> Self {
+        match func {
+            AggregateFunc::CountStar | AggregateFunc::Count => Accumulator::Count(0),
+            AggregateFunc::Avg => Accumulator::Avg(0.0, 0),
+            _ => Accumulator::Value(Value::Null),
+        }
+    }
+
+    fn add(&mut self, func: AggregateFunc, value: Value) -> Result<()> {
+        if value == Value::Null && func != AggregateFunc::CountStar {
+            return Ok(());
+        }
+        match self {
+            Accumulator::Count(count) => *count += 1,
+            Accumulator::Avg(sum, count) => {
+                *sum += match value {
+                    Value::Int(n) => n as f64,
+                    Value::UInt(n) => n as f64,
+                    Value::Float(f) => f,
+                    _ => return Err("AVG expects numbers".into()),
+                };
+                *count += 1;
+            }
+            Accumulator::Value(current) => {
+                let replace = match (func, &*current) {
+                    (_, Value::Null) => true,
+                    (AggregateFunc::Min, current) => expr::sort_cmp(&value, current).is_lt(),
+                    (AggregateFunc::Max, current) => expr::sort_cmp(&value, current).is_gt(),
+                    _ => false,
+                };
+                if replace {
+                    *current = value;
+                } else if func == AggregateFunc::Sum {
+                    *current = Expr::binary(expr::BinaryOp::Add, Expr::literal(current.clone()), Expr::literal(value)).eval(&[])?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> Value {
+        match self {
+            Accumulator::Count(count) => Value::UInt(count),
+            Accumulator::Value(value) => value,
+            Accumulator::Avg(_, 0) => Value::Null,
+            Accumulator::Avg(sum, count) => Value::Float(sum / count as f64),
+        }
+    }
+}
+
+impl PlanNode for Aggregate {
+    fn start(&self, bufmgr: &mut BufferPoolManager, txn: &Transaction) -> Result<BoxExecutor<'_>> {
+        let new_group = || self.aggregates.iter().map(|agg| Accumulator::new(agg.func)).collect::<Vec<_>>();
+        // エンコードしたgroup_byの値 → (group_byの値, 途中経過)
+        let mut groups: BTreeMap<Vec<u8>, (Vec<Value>, Vec<Accumulator>)> = BTreeMap::new();
+        if self.group_by.is_empty() {
+            groups.insert(vec![], (vec![], new_group()));
+        }
+        let mut inner = self.inner.start(bufmgr, txn)?;
+        while let Some(row) = inner.next(bufmgr, txn)? {
+            let keys = self.group_by.iter().map(|expr| expr.eval(&row)).collect::<Result<Vec<_>>>()?;
+            let (_, accs) = groups.entry(encode_row(&keys)).or_insert_with(|| (keys, new_group()));
+            for (acc, agg) in accs.iter_mut().zip(&self.aggregates) {
+                let value = match agg.func {
+                    AggregateFunc::CountStar => Value::Null,
+                    _ => agg.arg.eval(&row)?,
+                };
+                acc.add(agg.func, value)?;
+            }
+        }
+        let rows: Vec<Tuple> = groups
+            .into_iter()
+            .map(|(_, (mut keys, accs))| {
+                keys.extend(accs.into_iter().map(Accumulator::finish));
+                keys
+            })
+            .collect();
+        Ok(Box::new(ExecRows { rows: rows.into_iter() }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::catalog::{self, Catalog, Column, IndexKind, Schema};
+    use crate::expr::BinaryOp;
+    use crate::transaction::TransactionManager;
+    use crate::wal;
+
+    fn int(n: i64) -> Value {
+        Value::Int(n)
+    }
+
+    fn s(s: &str) -> Value {
+        Value::Str(s.to_string())
+    }
+
+    fn col(i: usize) -> Expr {
+        Expr::column(i)
+    }
+
+    // users(id, name, dept) と depts(id, name)
+    fn setup(name: &str) -> (BufferPoolManager, TransactionManager, TableDef, IndexDef, TableDef) {
+        let path = std::env::temp_dir().join(format!("executor_test_{}_{}", std::process::id(), name));
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(wal::log_path(&path));
+        let (mut bufmgr, txn_mgr, catalog): (_, _, Catalog) = catalog::open(&path, 32).unwrap();
+        let txn = txn_mgr.begin().unwrap();
+        let users = catalog
+            .create_table(
+                &mut bufmgr,
+                &txn,
+                "users",
+                Schema::new(vec![
+                    Column::new("id", ColumnType::Int),
+                    Column::new("name", ColumnType::Str),
+                    Column::new("dept", ColumnType::Int),
+                ]),
+            )
+            .unwrap();
+        let by_dept = catalog.create_index(&mut bufmgr, &txn, "users_dept", "users", &["dept"], false, IndexKind::BTree).unwrap();
+        let depts = catalog
+            .create_table(&mut bufmgr, &txn, "depts", Schema::new(vec![Column::new("id", ColumnType::Int), Column::new("name", ColumnType::Str)]))
+            .unwrap();
+        let indexes = vec![by_dept.clone()];
+        for i in 0..100 {
+            let dept = if i % 10 == 9 { Value::Null } else { int(i % 3) };
+            insert_row(&mut bufmgr, &txn, &users, &indexes, &[int(i), s(&format!("user{:02}", i)), dept]).unwrap();
+        }
+        for (i, name) in ["eng", "sales", "ops"].iter().enumerate() {
+            insert_row(&mut bufmgr, &txn, &depts, &[], &[int(i as i64), s(name)]).unwrap();
+        }
+        txn_mgr.commit(txn).unwrap();
+        (bufmgr, txn_mgr, users, by_dept, depts)
+    }
+
+    #[test]
+    fn test_scan_filter_project_sort_limit() {
+        let (mut bufmgr, txn_mgr, users, _, _) = setup("basic");
+        let txn = txn_mgr.begin().unwrap();
+        // SELECT name, id * 2 FROM users WHERE id >= 10 AND dept = 1 ORDER BY id DESC LIMIT 3 OFFSET 1
+        let plan = Limit {
+            inner: Box::new(Sort {
+                inner: Box::new(Project {
+                    inner: Box::new(Filter {
+                        inner: Box::new(SeqScan { table: users }),
+                        cond: Expr::binary(
+                            BinaryOp::And,
+                            Expr::binary(BinaryOp::Ge, col(0), Expr::literal(int(10))),
+                            Expr::binary(BinaryOp::Eq, col(2), Expr::literal(int(1))),
+                        ),
+                    }),
+                    exprs: vec![col(1), Expr::binary(BinaryOp::Mul, col(0), Expr::literal(int(2)))],
+                }),
+                keys: vec![SortKey { expr: col(1), desc: true }],
+                memory: crate::external_sort::WORK_MEMORY,
+            }),
+            limit: Some(3),
+            offset: 1,
+        };
+        let rows = collect(&plan, &mut bufmgr, &txn).unwrap();
+        assert_eq!(rows, vec![vec![s("user94"), int(188)], vec![s("user91"), int(182)], vec![s("user88"), int(176)]]);
+    }
+
+    #[test]
+    fn test_index_scan() {
+        let (mut bufmgr, txn_mgr, users, by_dept, _) = setup("index");
+        let txn = txn_mgr.begin().unwrap();
+        let scan = |lower, upper| IndexScan {
+            table: users.clone(),
+            index: by_dept.clone(),
+            lower,
+            upper,
+        };
+        let eq = scan(Bound::Included(vec![int(2)]), Bound::Included(vec![int(2)]));
+        let rows = collect(&eq, &mut bufmgr, &txn).unwrap();
+        assert_eq!(rows.len(), 30);
+        assert!(rows.iter().all(|row| row[2] == int(2)));
+        let range = scan(Bound::Excluded(vec![int(0)]), Bound::Unbounded);
+        assert_eq!(collect(&range, &mut bufmgr, &txn).unwrap().len(), 60);
+        txn_mgr.commit(txn).unwrap();
+
+        // 書き換えた行は新しいキーでだけ見つかる
+        let txn = txn_mgr.begin().unwrap();
+        let mut iter = users.heap.scan();
+        let (rid, _) = iter.next(&mut bufmgr, &txn).unwrap().unwrap();
+        update_row(&mut bufmgr, &txn, &users, std::slice::from_ref(&by_dept), rid, &[int(0), s("moved"), int(2)]).unwrap();
+        txn_mgr.commit(txn).unwrap();
+        let txn = txn_mgr.begin().unwrap();
+        assert_eq!(collect(&eq, &mut bufmgr, &txn).unwrap().len(), 31);
+        let zero = scan(Bound::Included(vec![int(0)]), Bound::Included(vec![int(0)]));
+        assert_eq!(collect(&zero, &mut bufmgr, &txn).unwrap().len(), 29);
+    }
+
+    #[test]
+    fn test_hash_index_lookup() {
+        let path = std::env::temp_dir().join(format!("executor_test_{}_hash", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(wal::log_path(&path));
+        let (mut bufmgr, txn_mgr, catalog): (_, _, Catalog) = catalog::open(&path, 32).unwrap();
+        let txn = txn_mgr.begin().unwrap();
+        let schema = Schema::new(vec![Column::new("id", ColumnType::Int), Column::new("dept", ColumnType::Int)]);
+        let users = catalog.create_table(&mut bufmgr, &txn, "users", schema).unwrap();
+        for i in 0..300 {
+            insert_row(&mut bufmgr, &txn, &users, &[], &[int(i), int(i % 3)]).unwrap();
+        }
+        // 既にある行からエントリを作る
+        let by_dept = catalog.create_index(&mut bufmgr, &txn, "users_dept", "users", &["dept"], false, IndexKind::Hash).unwrap();
+        build_index(&mut bufmgr, &txn, &users, &by_dept).unwrap();
+        let by_id = catalog.create_index(&mut bufmgr, &txn, "users_id", "users", &["id"], true, IndexKind::Hash).unwrap();
+        build_index(&mut bufmgr, &txn, &users, &by_id).unwrap();
+        let indexes = vec![by_dept.clone(), by_id.clone()];
+        let err = insert_row(&mut bufmgr, &txn, &users, &indexes, &[int(7), int(0)]).unwrap_err();
+        assert!(matches!(err.downcast_ref::<Error>(), Some(Error::DuplicateKey)));
+        txn_mgr.commit(txn).unwrap();
+
+        let lookup = |index: &IndexDef, key| IndexLookup {
+            table: users.clone(),
+            index: index.clone(),
+            key,
+        };
+        let txn = txn_mgr.begin().unwrap();
+        assert_eq!(collect(&lookup(&by_id, vec![int(123)]), &mut bufmgr, &txn).unwrap(), vec![vec![int(123), int(0)]]);
+        assert_eq!(collect(&lookup(&by_dept, vec![int(2)]), &mut bufmgr, &txn).unwrap().len(), 100);
+        // 範囲での検索はできない
+        let range = IndexScan {
+            table: users.clone(),
+            index: by_dept.clone(),
+            lower: Bound::Unbounded,
+            upper: Bound::Unbounded,
+        };
+        assert!(range.start(&mut bufmgr, &txn).is_err());
+
+        // 書き換えた行は新しいキーでだけ見つかる
+        let (rid, _) = users.heap.scan().next(&mut bufmgr, &txn).unwrap().unwrap();
+        update_row(&mut bufmgr, &txn, &users, &indexes, rid, &[int(0), int(2)]).unwrap();
+        assert_eq!(collect(&lookup(&by_dept, vec![int(2)]), &mut bufmgr, &txn).unwrap().len(), 101);
+        assert_eq!(collect(&lookup(&by_dept, vec![int(0)]), &mut bufmgr, &txn).unwrap().len(), 99);
+        txn_mgr.commit(txn).unwrap();
+    }
+
+    #[test]
+    fn test_vacuum() {
+        let path = std::env::temp_dir().join(format!("executor_test_{}_vacuum", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(wal::log_path(&path));
+        let (mut bufmgr, txn_mgr, catalog): (_, _, Catalog) = catalog::open(&path, 32).unwrap();
+        let txn = txn_mgr.begin().unwrap();
+        let schema = Schema::new(vec![Column::new("id", ColumnType::Int), Column::new("dept", ColumnType::Int)]);
+        let users = catalog.create_table(&mut bufmgr, &txn, "users", schema).unwrap();
+        let by_dept = catalog.create_index(&mut bufmgr, &txn, "users_dept", "users", &["dept"], false, IndexKind::BTree).unwrap();
+        let by_id = catalog.create_index(&mut bufmgr, &txn, "users_id", "users", &["id"], true, IndexKind::Hash).unwrap();
+        let indexes = vec![by_dept.clone(), by_id.clone()];
+        let rids: Vec<RecordId> = (0..300).map(|i| insert_row(&mut bufmgr, &txn, &users, &indexes, &[int(i), int(i % 3)]).unwrap()).collect();
+        txn_mgr.commit(txn).unwrap();
+
+        // 書き換えるとdeptのインデックスには古いエントリが残り、削除した行のエントリはどちらにも残る
+        let reader = txn_mgr.begin().unwrap();
+        let txn = txn_mgr.begin().unwrap();
+        for (i, &rid) in rids.iter().enumerate().take(100) {
+            update_row(&mut bufmgr, &txn, &users, &indexes, rid, &[int(i as i64), int(9)]).unwrap();
+        }
+        for &rid in &rids[250..] {
+            delete_row(&mut bufmgr, &txn, &users, rid).unwrap();
+        }
+        txn_mgr.commit(txn).unwrap();
+
+        // 古いスナップショットが見ている間は何も回収しない
+        let txn = txn_mgr.begin().unwrap();
+        let report = vacuum(&mut bufmgr, &txn, &users, &indexes, txn_mgr.horizon()).unwrap();
+        txn_mgr.commit(txn).unwrap();
+        assert_eq!(report, VacuumReport::default());
+        assert_eq!(collect(&SeqScan { table: users.clone() }, &mut bufmgr, &reader).unwrap().len(), 300);
+        txn_mgr.commit(reader).unwrap();
+
+        let txn = txn_mgr.begin().unwrap();
+        let report = vacuum(&mut bufmgr, &txn, &users, &indexes, txn_mgr.horizon()).unwrap();
+        txn_mgr.commit(txn).unwrap();
+        assert_eq!(report.removed, 50);
+        assert_eq!(report.versions, 100);
+        assert_eq!(report.index_entries, 100 + 50 * 2);
+
+        let txn = txn_mgr.begin().unwrap();
+        let lookup = |index: &IndexDef, key| IndexLookup {
+            table: users.clone(),
+            index: index.clone(),
+            key,
+        };
+        let scan = |key: Value| IndexScan {
+            table: users.clone(),
+            index: by_dept.clone(),
+            lower: Bound::Included(vec![key.clone()]),
+            upper: Bound::Included(vec![key]),
+        };
+        assert_eq!(collect(&scan(int(9)), &mut bufmgr, &txn).unwrap().len(), 100);
+        assert_eq!(collect(&scan(int(0)), &mut bufmgr, &txn).unwrap().len(), 50);
+        assert!(collect(&lookup(&by_id, vec![int(260)]), &mut bufmgr, &txn).unwrap().is_empty());
+        // 回収した行のキーはまた使える
+        insert_row(&mut bufmgr, &txn, &users, &indexes, &[int(260), int(0)]).unwrap();
+        assert_eq!(collect(&lookup(&by_id, vec![int(260)]), &mut bufmgr, &txn).unwrap(), vec![vec![int(260), int(0)]]);
+        txn_mgr.commit(txn).unwrap();
+    }
+
+    #[test]
+    fn test_joins() {
+        let (mut bufmgr, txn_mgr, users, _, depts) = setup("join");
+        let txn = txn_mgr.begin().unwrap();
+        let nested = NestedLoopJoin {
+            left: Box::new(SeqScan { table: users.clone() }),
+            right: Box::new(SeqScan { table: depts.clone() }),
+            cond: Some(Expr::binary(BinaryOp::Eq, col(2), col(3))),
+        };
+        let hash = HashJoin {
+            left: Box::new(SeqScan { table: users.clone() }),
+            right: Box::new(SeqScan { table: depts.clone() }),
+            left_keys: vec![col(2)],
+            right_keys: vec![col(0)],
+        };
+        // 左は一時ファイルに書き出すほど小さなメモリで並び替える
+        let merge = MergeJoin {
+            left: Box::new(Sort {
+                inner: Box::new(SeqScan { table: users }),
+                keys: vec![SortKey { expr: col(2), desc: false }],
+                memory: 256,
+            }),
+            right: Box::new(Sort {
+                inner: Box::new(SeqScan { table: depts }),
+                keys: vec![SortKey { expr: col(0), desc: false }],
+                memory: crate::external_sort::WORK_MEMORY,
+            }),
+            left_keys: vec![col(2)],
+            right_keys: vec![col(0)],
+        };
+        let nested = collect(&nested, &mut bufmgr, &txn).unwrap();
+        let hash = collect(&hash, &mut bufmgr, &txn).unwrap();
+        let merge = collect(&merge, &mut bufmgr, &txn).unwrap();
+        // deptがNULLの10人はどの結合でも消える
+        assert_eq!(nested.len(), 90);
+        assert_eq!(nested, hash);
+        assert_eq!(hash[1], vec![int(1), s("user01"), int(1), int(1), s("sales")]);
+        // マージ結合の結果は結合キーの順に並ぶ。同じキーの中では元の順序が保たれる
+        let mut expected = hash;
+        expected.sort_by_key(|row| match row[2] {
+            Value::Int(dept) => dept,
+            _ => unreachable!(),
+        });
+        assert_eq!(merge, expected);
+    }
+
+    #[test]
+    fn test_aggregate() {
+        let (mut bufmgr, txn_mgr, users, _, _) = setup("aggregate");
+        let txn = txn_mgr.begin().unwrap();
+        let agg = |func, arg| AggregateExpr { func, arg };
+        // SELECT dept, COUNT(*), COUNT(dept), SUM(id), MIN(name), MAX(id), AVG(id) FROM users GROUP BY dept
+        let plan = Aggregate {
+            inner: Box::new(SeqScan { table: users.clone() }),
+            group_by: vec![col(2)],
+            aggregates: vec![
+                agg(AggregateFunc::CountStar, Expr::literal(Value::Null)),
+                agg(AggregateFunc::Count, col(2)),
+                agg(AggregateFunc::Sum, col(0)),
+                agg(AggregateFunc::Min, col(1)),
+                agg(AggregateFunc::Max, col(0)),
+                agg(AggregateFunc::Avg, col(0)),
+            ],
+        };
+        let rows = collect(&plan, &mut bufmgr, &txn).unwrap();
+        assert_eq!(rows.len(), 4);
+        // NULLのグループが先頭に来る
+        assert_eq!(rows[0], vec![Value::Null, Value::UInt(10), Value::UInt(0), int(540), s("user09"), int(99), Value::Float(54.0)]);
+        assert_eq!(rows[1][1], Value::UInt(30));
+
+        // 行が無くても、GROUP BYが無ければ1行返す
+        let empty = Aggregate {
+            inner: Box::new(Filter {
+                inner: Box::new(SeqScan { table: users }),
+                cond: Expr::literal(expr::bool_value(false)),
+            }),
+            group_by: vec![],
+            aggregates: vec![agg(AggregateFunc::CountStar, Expr::literal(Value::Null)), agg(AggregateFunc::Sum, col(0))],
+        };
+        assert_eq!(collect(&empty, &mut bufmgr, &txn).unwrap(), vec![vec![Value::UInt(0), Value::Null]]);
+    }
+}
@@ -0,0 +1,260 @@
+// 式
+// フィルターや射影などで、行の値から新しい値を計算する
+//
+// 真偽値の型は用意せず、Int(1)を真、Int(0)を偽として扱う
+// NULLを含む比較や論理演算の結果はSQLと同じ3値論理になる(NULLは真でも偽でもない)
+
+use std::cmp::Ordering;
+use std::convert::TryFrom;
+
+use crate::executor::Result;
+use crate::memcmpable::{self, Value};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    And,
+    Or,
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    // 行のi番目の値
+    Column(usize),
+    Literal(Value),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    IsNull(Box<Expr>),
+}
+
+impl Expr {
+    pub fn column(index: usize) -> Self {
+        Expr::Column(index)
+    }
+
+    pub fn literal(value: Value) -> Self {
+        Expr::Literal(value)
+    }
+
+    pub fn binary(op: BinaryOp, left: Expr, right: Expr) -> Self {
+        Expr::Binary(op, Box::new(left), Box::new(right))
+    }
+
+    /// 行に対して式を評価する
+    pub fn eval(&self, row: &[Value]) -> Result<Value> {
+        match self {
+            Expr::Column(index) => row.get(*index).cloned().ok_or_else(|| format!("column #{} does not exist", index).into()),
+            Expr::Literal(value) => Ok(value.clone()),
+            Expr::Not(expr) => Ok(match to_bool(&expr.eval(row)?) {
+                Some(b) => bool_value(!b),
+                None => Value::Null,
+            }),
+            Expr::IsNull(expr) => Ok(bool_value(expr.eval(row)? == Value::Null)),
+            Expr::Binary(op, left, right) => {
+                let left = left.eval(row)?;
+                let right = right.eval(row)?;
+                eval_binary(*op, &left, &right)
+            }
+        }
+    }
+
+    /// 行が条件を満たすか(NULLは満たさない)
+    pub fn matches(&self, row: &[Value]) -> Result<bool> {
+        Ok(to_bool(&self.eval(row)?) == Some(true))
+    }
+}
+
+pub fn bool_value(b: bool) -> Value {
+    Value::Int(b as i64)
+}
+
+/// 値をSQLのリテラルとして書いた形にする(エラーメッセージに使う)
+/// 文字列は'で囲み、中の'は''にする。バイト列はx'16進数'にする
+pub fn sql_literal(value: &Value) -> String {
+    match value {
+        Value::Null => "NULL".to_string(),
+        Value::Int(n) => n.to_string(),
+        Value::UInt(n) => n.to_string(),
+        // 整数と区別できるよう、1.0のように小数点を付ける
+        Value::Float(f) => format!("{:?}", f),
+        Value::Str(s) => format!("'{}'", s.replace('\'', "''")),
+        Value::Bytes(bytes) => format!("x'{}'", bytes.iter().map(|b| format!("{:02x}", b)).collect::<String>()),
+        Value::Tuple(values) => format!("({})", values.iter().map(sql_literal).collect::<Vec<_>>().join(", ")),
+    }
+}
+
+// 真偽値として解釈する。NULLならNone
+fn to_bool(value: &Value) -> Option<bool> {
+    match value {
+        Value::Null => None,
+        Value::Int(n) => Some(*n != 0),
+        Value::UInt(n) => Some(*n != 0),
+        Value::Float(f) => Some(*f != 0.0),
+        Value::Bytes(bytes) => Some(!bytes.is_empty()),
+        Value::Str(s) => Some(!s.is_empty()),
+        Value::Tuple(values) => Some(!values.is_empty()),
+    }
+}
+
+fn eval_binary(op: BinaryOp, left: &Value, right: &Value) -> Result<Value> {
+    use BinaryOp::*;
+    match op {
+        And => Ok(match (to_bool(left), to_bool(right)) {
+            (Some(false), _) | (_, Some(false)) => bool_value(false),
+            (Some(true), Some(true)) => bool_value(true),
+            _ => Value::Null,
+        }),
+        Or => Ok(match (to_bool(left), to_bool(right)) {
+            (Some(true), _) | (_, Some(true)) => bool_value(true),
+            (Some(false), Some(false)) => bool_value(false),
+            _ => Value::Null,
+        }),
+        Eq | Ne | Lt | Le | Gt | Ge => Ok(match compare(left, right)? {
+            Some(ord) => bool_value(match op {
+                Eq => ord == Ordering::Equal,
+                Ne => ord != Ordering::Equal,
+                Lt => ord == Ordering::Less,
+                Le => ord != Ordering::Greater,
+                Gt => ord == Ordering::Greater,
+                _ => ord != Ordering::Less,
+            }),
+            None => Value::Null,
+        }),
+        Add | Sub | Mul | Div => arithmetic(op, left, right),
+    }
+}
+
+// 整数同士は整数のまま(あふれたらエラー)、浮動小数点数が混ざれば浮動小数点数で計算する
+fn arithmetic(op: BinaryOp, left: &Value, right: &Value) -> Result<Value> {
+    use BinaryOp::*;
+    let overflow = || -> Box<dyn std::error::Error> { "integer overflow".into() };
+    match (left, right) {
+        (Value::Null, _) | (_, Value::Null) => Ok(Value::Null),
+        (Value::UInt(a), Value::UInt(b)) => {
+            let (a, b) = (*a, *b);
+            let n = match op {
+                Add => a.checked_add(b),
+                Sub => a.checked_sub(b),
+                Mul => a.checked_mul(b),
+                _ if b == 0 => return Err("division by zero".into()),
+                _ => a.checked_div(b),
+            };
+            n.map(Value::UInt).ok_or_else(overflow)
+        }
+        (Value::Int(_), Value::Int(_) | Value::UInt(_)) | (Value::UInt(_), Value::Int(_)) => {
+            let a = as_i64(left).ok_or_else(overflow)?;
+            let b = as_i64(right).ok_or_else(overflow)?;
+            let n = match op {
+                Add => a.checked_add(b),
+                Sub => a.checked_sub(b),
+                Mul => a.checked_mul(b),
+                _ if b == 0 => return Err("division by zero".into()),
+                _ => a.checked_div(b),
+            };
+            n.map(Value::Int).ok_or_else(overflow)
+        }
+        _ => match (as_f64(left), as_f64(right)) {
+            (Some(a), Some(b)) => Ok(Value::Float(match op {
+                Add => a + b,
+                Sub => a - b,
+                Mul => a * b,
+                _ => a / b,
+            })),
+            _ => Err(format!("cannot apply {:?} to {} and {}", op, sql_literal(left), sql_literal(right)).into()),
+        },
+    }
+}
+
+fn as_i64(value: &Value) -> Option<i64> {
+    match value {
+        Value::Int(n) => Some(*n),
+        Value::UInt(n) => i64::try_from(*n).ok(),
+        _ => None,
+    }
+}
+
+fn as_f64(value: &Value) -> Option<f64> {
+    match value {
+        Value::Int(n) => Some(*n as f64),
+        Value::UInt(n) => Some(*n as f64),
+        Value::Float(f) => Some(*f),
+        _ => None,
+    }
+}
+
+/// 値を比較する。どちらかがNULLならNone、比較できない型同士ならエラー
+/// 数値は型が違っても値で比べる
+pub fn compare(left: &Value, right: &Value) -> Result<Option<Ordering>> {
+    Ok(match (left, right) {
+        (Value::Null, _) | (_, Value::Null) => None,
+        (Value::Int(a), Value::Int(b)) => Some(a.cmp(b)),
+        (Value::UInt(a), Value::UInt(b)) => Some(a.cmp(b)),
+        (Value::Int(a), Value::UInt(b)) => Some(i128::from(*a).cmp(&i128::from(*b))),
+        (Value::UInt(a), Value::Int(b)) => Some(i128::from(*a).cmp(&i128::from(*b))),
+        (Value::Bytes(a), Value::Bytes(b)) => Some(a.cmp(b)),
+        (Value::Str(a), Value::Str(b)) => Some(a.cmp(b)),
+        _ => match (as_f64(left), as_f64(right)) {
+            (Some(a), Some(b)) => a.partial_cmp(&b),
+            _ => return Err(format!("cannot compare {} with {}", sql_literal(left), sql_literal(right)).into()),
+        },
+    })
+}
+
+/// 並び替え用の全順序
+/// NULLは一番小さく、比較できない値同士はmemcomparable formatの順に並べる
+pub fn sort_cmp(left: &Value, right: &Value) -> Ordering {
+    match compare(left, right) {
+        Ok(Some(ord)) => ord,
+        _ => {
+            let mut a = vec![];
+            let mut b = vec![];
+            memcmpable::encode_value(left, &mut a);
+            memcmpable::encode_value(right, &mut b);
+            a.cmp(&b)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_eval() {
+        let row = vec![Value::Int(3), Value::Float(1.5), Value::Null, Value::Str("a".to_string())];
+        let add = Expr::binary(BinaryOp::Add, Expr::column(0), Expr::column(1));
+        assert_eq!(add.eval(&row).unwrap(), Value::Float(4.5));
+        let lt = Expr::binary(BinaryOp::Lt, Expr::column(0), Expr::literal(Value::UInt(4)));
+        assert!(lt.matches(&row).unwrap());
+        // NULLとの比較はNULLで、ANDの相手が偽なら偽
+        let null = Expr::binary(BinaryOp::Eq, Expr::column(2), Expr::literal(Value::Int(1)));
+        assert_eq!(null.eval(&row).unwrap(), Value::Null);
+        assert!(!null.matches(&row).unwrap());
+        let and = Expr::binary(BinaryOp::And, null.clone(), Expr::literal(bool_value(false)));
+        assert_eq!(and.eval(&row).unwrap(), bool_value(false));
+        assert_eq!(Expr::Not(Box::new(null)).eval(&row).unwrap(), Value::Null);
+        assert!(Expr::binary(BinaryOp::Eq, Expr::column(3), Expr::column(0)).eval(&row).is_err());
+        assert!(Expr::binary(BinaryOp::Div, Expr::column(0), Expr::literal(Value::Int(0))).eval(&row).is_err());
+        assert_eq!(sort_cmp(&Value::Null, &Value::Int(-1)), Ordering::Less);
+    }
+
+    #[test]
+    fn test_sql_literal() {
+        assert_eq!(sql_literal(&Value::Str("it's".to_string())), "'it''s'");
+        assert_eq!(sql_literal(&Value::Float(1.0)), "1.0");
+        assert_eq!(sql_literal(&Value::Bytes(vec![0, 255])), "x'00ff'");
+        assert_eq!(sql_literal(&Value::Tuple(vec![Value::Null, Value::Int(-1)])), "(NULL, -1)");
+        let row = vec![Value::Int(3), Value::Str("a".to_string())];
+        let err = Expr::binary(BinaryOp::Eq, Expr::column(1), Expr::column(0)).eval(&row).unwrap_err();
+        assert_eq!(err.to_string(), "cannot compare 'a' with 3");
+    }
+}
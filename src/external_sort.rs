@@ -0,0 +1,461 @@
+// 外部マージソート
+// メモリに入りきらない行を並び替える
+//
+// 1. 行をメモリの上限まで読んで並び替え、一時ファイルのページに書き出す(ラン)
+// 2. ランを一度にfan_in個ずつマージして、ランが1つ(fan_in個以下)になるまで繰り返す
+// 3. 最後のマージは結果を書き出さず、1行ずつ返す
+//
+// 一時ファイルはデータベースのファイルとは別に作り、専用のBufferPoolManagerで読み書きする
+// ランの読み書きにはバッファプールのフレーム(= メモリの上限 / ページサイズ)しか使わない
+// 一時ファイルはクラッシュしても要らないので、WALには書かない。使い終われば削除する
+//
+// ランはページを連続して確保したバイト列で、| 長さ(u32) | レコード | を並べる
+// レコードは| ソートキーの値 | 行の値 |をmemcmpableでエンコードしたもの
+
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+
+use crate::buffer_pool::{Buffer, BufferPool, BufferPoolManager};
+use crate::disk_manager::{DiskManager, PageId, PAGE_SIZE};
+use crate::executor::{Result, SortKey, Tuple};
+use crate::expr;
+use crate::memcmpable::{self, Value};
+
+/// 並び替えやハッシュ表に使うメモリの既定の上限(バイト)
+pub const WORK_MEMORY: usize = 4 << 20;
+
+const LENGTH_SIZE: usize = 4;
+
+// 一時ファイルの名前が被らないようにする番号
+static TEMP_FILE_ID: AtomicUsize = AtomicUsize::new(0);
+
+// ランを書き出す一時ファイル
+struct Spill {
+    bufmgr: BufferPoolManager,
+    path: PathBuf,
+}
+
+impl Spill {
+    fn create(pool_size: usize) -> Result<Self> {
+        let id = TEMP_FILE_ID.fetch_add(1, AtomicOrdering::Relaxed);
+        let path = std::env::temp_dir().join(format!("practice_sort_{}_{}", std::process::id(), id));
+        let disk = DiskManager::open(&path)?;
+        Ok(Self {
+            bufmgr: BufferPoolManager::new(disk, BufferPool::new(pool_size)),
+            path,
+        })
+    }
+}
+
+impl Drop for Spill {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+// 一時ファイルに書いたラン
+#[derive(Debug, Clone, Copy)]
+struct Run {
+    first_page_id: PageId,
+    len: u64,
+}
+
+// ランを末尾に書き足していく
+struct RunWriter {
+    run: Run,
+    page: Option<Rc<Buffer>>,
+}
+
+impl RunWriter {
+    fn new() -> Self {
+        Self {
+            run: Run {
+                first_page_id: PageId::INVALID_PAGE_ID,
+                len: 0,
+            },
+            page: None,
+        }
+    }
+
+    fn write(&mut self, bufmgr: &mut BufferPoolManager, mut data: &[u8]) -> Result<()> {
+        while !data.is_empty() {
+            let offset = (self.run.len % PAGE_SIZE as u64) as usize;
+            if offset == 0 {
+                // 書き終わったページは手放して、次のページを確保する
+                self.page = None;
+                let buffer = bufmgr.create_page()?;
+                if self.run.len == 0 {
+                    self.run.first_page_id = buffer.page_id;
+                }
+                // ラン1つを書き終えるまで他のページは確保しないので、ページIDは連続する
+                debug_assert_eq!(buffer.page_id.0, self.run.first_page_id.0 + self.run.len / PAGE_SIZE as u64);
+                self.page = Some(buffer);
+            }
+            let buffer = self.page.as_ref().unwrap();
+            let n = data.len().min(PAGE_SIZE - offset);
+            buffer.page.borrow_mut()[offset..offset + n].copy_from_slice(&data[..n]);
+            buffer.is_dirty.set(true);
+            self.run.len += n as u64;
+            data = &data[n..];
+        }
+        Ok(())
+    }
+
+    fn write_record(&mut self, bufmgr: &mut BufferPoolManager, record: &[u8]) -> Result<()> {
+        self.write(bufmgr, &(record.len() as u32).to_le_bytes())?;
+        self.write(bufmgr, record)
+    }
+
+    fn finish(self) -> Run {
+        self.run
+    }
+}
+
+// ランを先頭から読む
+struct RunReader {
+    run: Run,
+    pos: u64,
+}
+
+impl RunReader {
+    fn read(&mut self, bufmgr: &mut BufferPoolManager, len: usize) -> Result<Vec<u8>> {
+        let mut data = Vec::with_capacity(len);
+        while data.len() < len {
+            let page_id = PageId(self.run.first_page_id.0 + self.pos / PAGE_SIZE as u64);
+            let offset = (self.pos % PAGE_SIZE as u64) as usize;
+            let n = (len - data.len()).min(PAGE_SIZE - offset);
+            let buffer = bufmgr.fetch_page(page_id)?;
+            data.extend_from_slice(&buffer.page.borrow()[offset..offset + n]);
+            self.pos += n as u64;
+        }
+        Ok(data)
+    }
+
+    fn next(&mut self, bufmgr: &mut BufferPoolManager) -> Result<Option<(Vec<Value>, Tuple)>> {
+        if self.pos >= self.run.len {
+            return Ok(None);
+        }
+        let mut len = [0u8; LENGTH_SIZE];
+        len.copy_from_slice(&self.read(bufmgr, LENGTH_SIZE)?);
+        let record = self.read(bufmgr, u32::from_le_bytes(len) as usize)?;
+        Ok(Some(decode_record(&record)?))
+    }
+}
+
+fn encode_record(keys: &[Value], row: &[Value]) -> Vec<u8> {
+    let mut bytes = vec![];
+    memcmpable::encode(&[Value::Tuple(keys.to_vec()), Value::Tuple(row.to_vec())], &mut bytes);
+    bytes
+}
+
+fn decode_record(bytes: &[u8]) -> Result<(Vec<Value>, Tuple)> {
+    let mut values = memcmpable::decode(bytes)?.into_iter();
+    match (values.next(), values.next()) {
+        (Some(Value::Tuple(keys)), Some(Value::Tuple(row))) => Ok((keys, row)),
+        _ => Err("broken sort run".into()),
+    }
+}
+
+// ソートキーの値を比べる
+fn compare_keys(desc: &[bool], a: &[Value], b: &[Value]) -> Ordering {
+    for (desc, (a, b)) in desc.iter().zip(a.iter().zip(b)) {
+        let ord = expr::sort_cmp(a, b);
+        let ord = if *desc { ord.reverse() } else { ord };
+        if ord.is_ne() {
+            return ord;
+        }
+    }
+    Ordering::Equal
+}
+
+// マージ中の各ランの先頭の行
+struct Head {
+    keys: Vec<Value>,
+    row: Tuple,
+    run: usize,
+    desc: Rc<[bool]>,
+}
+
+impl PartialEq for Head {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other).is_eq()
+    }
+}
+
+impl Eq for Head {}
+
+impl PartialOrd for Head {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// BinaryHeapは最大のものから取り出すので、逆順にする
+// キーが同じなら前のランの行を先に返す(前のランほど先に読んだ行なので、安定ソートになる)
+impl Ord for Head {
+    fn cmp(&self, other: &Self) -> Ordering {
+        compare_keys(&self.desc, &self.keys, &other.keys).then(self.run.cmp(&other.run)).reverse()
+    }
+}
+
+// 複数のランを1つにマージする
+struct Merger {
+    readers: Vec<RunReader>,
+    heap: BinaryHeap<Head>,
+    desc: Rc<[bool]>,
+}
+
+impl Merger {
+    fn new(bufmgr: &mut BufferPoolManager, runs: &[Run], desc: Rc<[bool]>) -> Result<Self> {
+        let mut merger = Self {
+            readers: runs.iter().map(|&run| RunReader { run, pos: 0 }).collect(),
+            heap: BinaryHeap::new(),
+            desc,
+        };
+        for run in 0..runs.len() {
+            merger.refill(bufmgr, run)?;
+        }
+        Ok(merger)
+    }
+
+    fn refill(&mut self, bufmgr: &mut BufferPoolManager, run: usize) -> Result<()> {
+        if let Some((keys, row)) = self.readers[run].next(bufmgr)? {
+            self.heap.push(Head {
+                keys,
+                row,
+                run,
+                desc: self.desc.clone(),
+            });
+        }
+        Ok(())
+    }
+
+    fn next(&mut self, bufmgr: &mut BufferPoolManager) -> Result<Option<(Vec<Value>, Tuple)>> {
+        match self.heap.pop() {
+            Some(head) => {
+                self.refill(bufmgr, head.run)?;
+                Ok(Some((head.keys, head.row)))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+/// 行を受け取って並び替える
+/// メモリの上限を超えたら、並び替えた分をランとして一時ファイルに書き出す
+pub struct ExternalSorter {
+    keys: Vec<SortKey>,
+    desc: Rc<[bool]>,
+    memory: usize,
+    rows: Vec<(Vec<Value>, Tuple)>,
+    // メモリに持っている行のバイト数(エンコードした大きさで数える)
+    bytes: usize,
+    spill: Option<Spill>,
+    runs: Vec<Run>,
+}
+
+impl ExternalSorter {
+    pub fn new(keys: Vec<SortKey>, memory: usize) -> Self {
+        let desc = keys.iter().map(|key| key.desc).collect();
+        Self {
+            keys,
+            desc,
+            memory,
+            rows: vec![],
+            bytes: 0,
+            spill: None,
+            runs: vec![],
+        }
+    }
+
+    // マージに使うバッファプールのフレーム数。入力ごとに1つ、出力に1つ使う
+    fn pool_size(&self) -> usize {
+        (self.memory / PAGE_SIZE).max(3)
+    }
+
+    fn fan_in(&self) -> usize {
+        self.pool_size() - 1
+    }
+
+    pub fn push(&mut self, row: Tuple) -> Result<()> {
+        let keys = self.keys.iter().map(|key| key.expr.eval(&row)).collect::<Result<Vec<_>>>()?;
+        self.bytes += encode_record(&keys, &row).len() + LENGTH_SIZE;
+        self.rows.push((keys, row));
+        if self.bytes > self.memory {
+            self.spill_rows()?;
+        }
+        Ok(())
+    }
+
+    fn sort_rows(&mut self) {
+        let desc = self.desc.clone();
+        // 安定ソートなので、キーが同じ行は読んだ順のまま
+        self.rows.sort_by(|(a, _), (b, _)| compare_keys(&desc, a, b));
+    }
+
+    // メモリにある行を並び替えて、ランとして書き出す
+    fn spill_rows(&mut self) -> Result<()> {
+        if self.rows.is_empty() {
+            return Ok(());
+        }
+        self.sort_rows();
+        if self.spill.is_none() {
+            self.spill = Some(Spill::create(self.pool_size())?);
+        }
+        let bufmgr = &mut self.spill.as_mut().unwrap().bufmgr;
+        let mut writer = RunWriter::new();
+        for (keys, row) in self.rows.drain(..) {
+            writer.write_record(bufmgr, &encode_record(&keys, &row))?;
+        }
+        self.runs.push(writer.finish());
+        self.bytes = 0;
+        Ok(())
+    }
+
+    /// 一時ファイルに書き出したランの数
+    pub fn num_runs(&self) -> usize {
+        self.runs.len()
+    }
+
+    /// 全ての行を受け取ったので、並び替えた結果を返す
+    pub fn finish(mut self) -> Result<SortedRows> {
+        if self.spill.is_none() {
+            self.sort_rows();
+            return Ok(SortedRows {
+                inner: Sorted::Memory(std::mem::take(&mut self.rows).into_iter()),
+            });
+        }
+        self.spill_rows()?;
+        let fan_in = self.fan_in();
+        let mut spill = self.spill.take().unwrap();
+        let mut runs = std::mem::take(&mut self.runs);
+        // 隣り合うfan_in個ずつマージして、順番を保ったままランを減らす
+        while runs.len() > fan_in {
+            let mut merged = vec![];
+            for group in runs.chunks(fan_in) {
+                let mut merger = Merger::new(&mut spill.bufmgr, group, self.desc.clone())?;
+                let mut writer = RunWriter::new();
+                while let Some((keys, row)) = merger.next(&mut spill.bufmgr)? {
+                    writer.write_record(&mut spill.bufmgr, &encode_record(&keys, &row))?;
+                }
+                merged.push(writer.finish());
+            }
+            runs = merged;
+        }
+        let merger = Merger::new(&mut spill.bufmgr, &runs, self.desc.clone())?;
+        Ok(SortedRows {
+            inner: Sorted::Merge { spill, merger },
+        })
+    }
+}
+
+/// 並び替えた行を順番に返す
+pub struct SortedRows {
+    inner: Sorted,
+}
+
+enum Sorted {
+    // 一時ファイルに書き出さずに済んだ場合
+    Memory(std::vec::IntoIter<(Vec<Value>, Tuple)>),
+    Merge { spill: Spill, merger: Merger },
+}
+
+impl Iterator for SortedRows {
+    type Item = Result<Tuple>;
+
+    fn next(&mut self) -> Option<Result<Tuple>> {
+        match &mut self.inner {
+            Sorted::Memory(rows) => rows.next().map(|(_, row)| Ok(row)),
+            Sorted::Merge { spill, merger } => merger.next(&mut spill.bufmgr).transpose().map(|item| item.map(|(_, row)| row)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::expr::Expr;
+
+    fn sort(rows: &[Tuple], keys: Vec<SortKey>, memory: usize) -> (Vec<Tuple>, usize) {
+        let mut sorter = ExternalSorter::new(keys, memory);
+        for row in rows {
+            sorter.push(row.clone()).unwrap();
+        }
+        let runs = sorter.num_runs();
+        let result = sorter.finish().unwrap().collect::<Result<Vec<_>>>().unwrap();
+        (result, runs)
+    }
+
+    #[test]
+    fn test_external_sort() {
+        // 1ページより大きな行も混ぜる
+        let rows: Vec<Tuple> = (0..3000u64)
+            .map(|i| {
+                let key = (i * 7919) % 1000;
+                let len = if i % 500 == 0 { PAGE_SIZE + 100 } else { 20 };
+                vec![Value::UInt(key), Value::UInt(i), Value::Bytes(vec![i as u8; len])]
+            })
+            .collect();
+        let keys = vec![SortKey {
+            expr: Expr::column(0),
+            desc: true,
+        }];
+        let mut expected = rows.clone();
+        expected.sort_by(|a, b| expr::sort_cmp(&b[0], &a[0]));
+
+        // メモリに収まる場合
+        let (sorted, runs) = sort(&rows, keys.clone(), WORK_MEMORY);
+        assert_eq!(runs, 0);
+        assert_eq!(sorted, expected);
+
+        // 3ページ分のメモリしか無いので、何段もマージする(キーが同じ行の順番も保つ)
+        let (sorted, runs) = sort(&rows, keys, 3 * PAGE_SIZE);
+        assert!(runs > 2, "{}", runs);
+        assert_eq!(sorted, expected);
+    }
+
+    #[test]
+    fn test_empty_input() {
+        let keys = vec![SortKey {
+            expr: Expr::column(0),
+            desc: false,
+        }];
+        // メモリに収まる場合も、一時ファイルを使う大きさのメモリしか無い場合も、空のまま返す
+        assert_eq!(sort(&[], keys.clone(), WORK_MEMORY), (vec![], 0));
+        assert_eq!(sort(&[], keys, 0), (vec![], 0));
+    }
+
+    #[test]
+    fn test_duplicate_keys_across_runs() {
+        // キーが3種類しか無い行を、NULLも混ぜて並べる
+        let rows: Vec<Tuple> = (0..200i64)
+            .map(|i| {
+                let key = if i % 7 == 0 { Value::Null } else { Value::Int(i % 3) };
+                vec![key, Value::Int(i % 5), Value::Int(i)]
+            })
+            .collect();
+        // 1列目は昇順、2列目は降順
+        let keys = vec![
+            SortKey {
+                expr: Expr::column(0),
+                desc: false,
+            },
+            SortKey {
+                expr: Expr::column(1),
+                desc: true,
+            },
+        ];
+        let mut expected = rows.clone();
+        expected.sort_by(|a, b| expr::sort_cmp(&a[0], &b[0]).then(expr::sort_cmp(&b[1], &a[1])));
+
+        // メモリが無いので1行ずつランになり、2つずつ何段もマージする
+        // 同じキーの行は、別のランに分かれても読んだ順のまま並ぶ
+        let (sorted, runs) = sort(&rows, keys, 0);
+        assert_eq!(runs, rows.len());
+        assert_eq!(sorted, expected);
+        assert_eq!(sorted[0][0], Value::Null);
+    }
+}
@@ -0,0 +1,236 @@
+// 空き領域マップ(free space map)
+// ヒープテーブルのページごとに、詰め直せば使える空き領域のバイト数を記録する
+// 追加するレコードが入るページを、ヒープのページを1つずつ読まずに探せる
+//
+// ページの中身: | 次のページID(u64) | エントリ数(u16) | (ヒープのページID(u64), 空き(u16)) ... |
+//
+// エントリはヒープのページを繋げた順に並べるので、最後のエントリがヒープの末尾のページになる
+// 記録はあくまで目安で、ロールバックで空いた領域などは反映しない
+// 追加する側はページに入らなければ記録を直して次を探し、VACUUMでヒープのページから作り直す
+
+use crate::buffer_pool::{BufferPoolManager, Error, PAGE_LSN_SIZE};
+use crate::disk_manager::{PageId, PAGE_SIZE};
+use crate::wal::TxnLog;
+
+const HEADER_SIZE: usize = 8 + 2;
+const ENTRY_SIZE: usize = 8 + 2;
+// 1ページに入るエントリの数
+const ENTRIES_PER_PAGE: usize = (PAGE_SIZE - PAGE_LSN_SIZE - HEADER_SIZE) / ENTRY_SIZE;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FreeSpaceMap {
+    pub first_page_id: PageId,
+}
+
+struct MapPage {
+    next: PageId,
+    entries: Vec<(PageId, u16)>,
+}
+
+impl MapPage {
+    fn read(body: &[u8]) -> Self {
+        let next = PageId(read_u64(&body[..8]));
+        let count = u16::from_le_bytes([body[8], body[9]]) as usize;
+        let entries = (0..count)
+            .map(|i| {
+                let offset = HEADER_SIZE + i * ENTRY_SIZE;
+                (PageId(read_u64(&body[offset..])), u16::from_le_bytes([body[offset + 8], body[offset + 9]]))
+            })
+            .collect();
+        Self { next, entries }
+    }
+
+    fn write(&self, body: &mut [u8]) {
+        body[..8].copy_from_slice(&self.next.0.to_le_bytes());
+        body[8..10].copy_from_slice(&(self.entries.len() as u16).to_le_bytes());
+        let mut offset = HEADER_SIZE;
+        for (page_id, free) in &self.entries {
+            body[offset..offset + 8].copy_from_slice(&page_id.0.to_le_bytes());
+            body[offset + 8..offset + 10].copy_from_slice(&free.to_le_bytes());
+            offset += ENTRY_SIZE;
+        }
+        body[offset..].fill(0);
+    }
+}
+
+fn read_u64(bytes: &[u8]) -> u64 {
+    let mut buf = [0u8; 8];
+    buf.copy_from_slice(&bytes[..8]);
+    u64::from_le_bytes(buf)
+}
+
+fn read_page(bufmgr: &mut BufferPoolManager, page_id: PageId) -> Result<MapPage, Error> {
+    let buffer = bufmgr.fetch_page(page_id)?;
+    let page = buffer.page.borrow();
+    Ok(MapPage::read(&page[PAGE_LSN_SIZE..]))
+}
+
+fn write_page(bufmgr: &mut BufferPoolManager, log: &TxnLog, page_id: PageId, map_page: &MapPage) -> Result<(), Error> {
+    let buffer = bufmgr.fetch_page(page_id)?;
+    log.modify_page(&buffer, |body| map_page.write(body))?;
+    Ok(())
+}
+
+fn create_page(bufmgr: &mut BufferPoolManager, log: &TxnLog) -> Result<PageId, Error> {
+    let buffer = bufmgr.create_page()?;
+    let empty = MapPage {
+        next: PageId::INVALID_PAGE_ID,
+        entries: vec![],
+    };
+    log.modify_page(&buffer, |body| empty.write(body))?;
+    Ok(buffer.page_id)
+}
+
+// 中身を0にしてからページを解放する(再利用した時に前の中身がREDOで混ざらないようにする)
+fn free_page(bufmgr: &mut BufferPoolManager, log: &TxnLog, page_id: PageId) -> Result<(), Error> {
+    let buffer = bufmgr.fetch_page(page_id)?;
+    log.modify_page(&buffer, |body| body.fill(0))?;
+    drop(buffer);
+    log.free_page(bufmgr, page_id)?;
+    Ok(())
+}
+
+impl FreeSpaceMap {
+    /// 空のマップを作る
+    pub fn create(bufmgr: &mut BufferPoolManager, log: &TxnLog) -> Result<Self, Error> {
+        Ok(Self {
+            first_page_id: create_page(bufmgr, log)?,
+        })
+    }
+
+    pub fn open(first_page_id: PageId) -> Self {
+        Self { first_page_id }
+    }
+
+    // マップのページを全て読む
+    fn read_chain(&self, bufmgr: &mut BufferPoolManager) -> Result<Vec<(PageId, MapPage)>, Error> {
+        let mut chain = vec![];
+        let mut page_id = self.first_page_id;
+        while let Some(current) = page_id.valid() {
+            let map_page = read_page(bufmgr, current)?;
+            page_id = map_page.next;
+            chain.push((current, map_page));
+        }
+        Ok(chain)
+    }
+
+    /// 空きがsizeバイト以上あると記録されている最初のページ
+    pub fn find(&self, bufmgr: &mut BufferPoolManager, size: usize) -> Result<Option<PageId>, Error> {
+        let mut page_id = self.first_page_id;
+        while let Some(current) = page_id.valid() {
+            let map_page = read_page(bufmgr, current)?;
+            if let Some(&(found, _)) = map_page.entries.iter().find(|&&(_, free)| free as usize >= size) {
+                return Ok(Some(found));
+            }
+            page_id = map_page.next;
+        }
+        Ok(None)
+    }
+
+    /// ヒープのページの空きを記録する。まだ載っていないページなら末尾に追加する
+    pub fn set(&self, bufmgr: &mut BufferPoolManager, log: &TxnLog, heap_page_id: PageId, free: usize) -> Result<(), Error> {
+        let free = free as u16;
+        let mut chain = self.read_chain(bufmgr)?;
+        for (page_id, map_page) in chain.iter_mut() {
+            if let Some(entry) = map_page.entries.iter_mut().find(|(id, _)| *id == heap_page_id) {
+                if entry.1 != free {
+                    entry.1 = free;
+                    write_page(bufmgr, log, *page_id, map_page)?;
+                }
+                return Ok(());
+            }
+        }
+        let (last_page_id, mut last) = chain.pop().unwrap();
+        if last.entries.len() < ENTRIES_PER_PAGE {
+            last.entries.push((heap_page_id, free));
+            return write_page(bufmgr, log, last_page_id, &last);
+        }
+        // 最後のページがいっぱいなので、新しいページを繋げる
+        let new_page_id = create_page(bufmgr, log)?;
+        let new_page = MapPage {
+            next: PageId::INVALID_PAGE_ID,
+            entries: vec![(heap_page_id, free)],
+        };
+        write_page(bufmgr, log, new_page_id, &new_page)?;
+        write_page(bufmgr, log, last_page_id, &MapPage { next: new_page_id, ..last })
+    }
+
+    /// 最後に載せたヒープのページ
+    pub fn last(&self, bufmgr: &mut BufferPoolManager) -> Result<Option<PageId>, Error> {
+        Ok(self.entries(bufmgr)?.last().map(|&(page_id, _)| page_id))
+    }
+
+    /// 全ての(ヒープのページID, 空き)を載せた順に返す
+    pub fn entries(&self, bufmgr: &mut BufferPoolManager) -> Result<Vec<(PageId, usize)>, Error> {
+        Ok(self
+            .read_chain(bufmgr)?
+            .into_iter()
+            .flat_map(|(_, map_page)| map_page.entries)
+            .map(|(page_id, free)| (page_id, free as usize))
+            .collect())
+    }
+
+    /// マップをentriesで作り直す。余ったページは解放する
+    pub fn rebuild(&self, bufmgr: &mut BufferPoolManager, log: &TxnLog, entries: &[(PageId, usize)]) -> Result<(), Error> {
+        let mut pages: Vec<PageId> = self.read_chain(bufmgr)?.into_iter().map(|(page_id, _)| page_id).collect();
+        let groups: Vec<&[(PageId, usize)]> = if entries.is_empty() { vec![&[]] } else { entries.chunks(ENTRIES_PER_PAGE).collect() };
+        for &page_id in pages.iter().skip(groups.len()) {
+            free_page(bufmgr, log, page_id)?;
+        }
+        pages.truncate(groups.len());
+        while pages.len() < groups.len() {
+            pages.push(create_page(bufmgr, log)?);
+        }
+        for (i, group) in groups.into_iter().enumerate() {
+            let map_page = MapPage {
+                next: pages.get(i + 1).copied().unwrap_or(PageId::INVALID_PAGE_ID),
+                entries: group.iter().map(|&(page_id, free)| (page_id, free as u16)).collect(),
+            };
+            write_page(bufmgr, log, pages[i], &map_page)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction;
+    use crate::wal;
+
+    #[test]
+    fn test_free_space_map() {
+        let path = std::env::temp_dir().join(format!("fsm_test_{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(wal::log_path(&path));
+        let (mut bufmgr, txn_mgr, _) = transaction::open(&path, 16).unwrap();
+        let txn = txn_mgr.begin().unwrap();
+        let log = txn.log();
+        let fsm = FreeSpaceMap::create(&mut bufmgr, log).unwrap();
+        assert_eq!(fsm.find(&mut bufmgr, 1).unwrap(), None);
+        assert_eq!(fsm.last(&mut bufmgr).unwrap(), None);
+
+        // 1ページに入りきらない数を載せる
+        let count = ENTRIES_PER_PAGE as u64 * 2 + 10;
+        for i in 0..count {
+            fsm.set(&mut bufmgr, log, PageId(1000 + i), (i % 100) as usize).unwrap();
+        }
+        assert_eq!(fsm.entries(&mut bufmgr).unwrap().len(), count as usize);
+        assert_eq!(fsm.last(&mut bufmgr).unwrap(), Some(PageId(1000 + count - 1)));
+        assert_eq!(fsm.find(&mut bufmgr, 99).unwrap(), Some(PageId(1099)));
+        fsm.set(&mut bufmgr, log, PageId(1099), 0).unwrap();
+        assert_eq!(fsm.find(&mut bufmgr, 99).unwrap(), Some(PageId(1199)));
+        fsm.set(&mut bufmgr, log, PageId(1000 + count - 1), 500).unwrap();
+        assert_eq!(fsm.find(&mut bufmgr, 500).unwrap(), Some(PageId(1000 + count - 1)));
+
+        // 作り直すと、余ったページは解放されて次に再利用される
+        let entries: Vec<(PageId, usize)> = (0..5).map(|i| (PageId(2000 + i), 10)).collect();
+        fsm.rebuild(&mut bufmgr, log, &entries).unwrap();
+        assert_eq!(fsm.entries(&mut bufmgr).unwrap(), entries);
+        let pages = bufmgr.disk_mut().num_pages();
+        FreeSpaceMap::create(&mut bufmgr, log).unwrap();
+        FreeSpaceMap::create(&mut bufmgr, log).unwrap();
+        assert_eq!(bufmgr.disk_mut().num_pages(), pages);
+        txn_mgr.commit(txn).unwrap();
+    }
+}
@@ -1,12 +1,96 @@
+// grepコマンド(grep・grep-benchのbinから使う)
+// 正規表現(regex)で行を検索し、ディレクトリはwalkで再帰的に辿る
+
+use std::collections::{BTreeMap, VecDeque};
 use std::env;
-use std::env::Args;
 use std::process;
 use std::io;
 use std::io::prelude::*;
+use std::io::IsTerminal;
 use std::fs::File;
 use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
 use std::result::Result;
 use std::error::Error;
+use std::sync::{mpsc, Mutex};
+use std::thread;
+
+use crate::regex::{self, Regex};
+use crate::walk::{Glob, Walk, WalkOptions};
+
+// ファイルの先頭からこのバイト数までにNULがあれば、バイナリファイルとして扱う
+const BINARY_CHECK_SIZE: usize = 8 * 1024;
+
+// 一致した部分の色(赤)と、色を戻すエスケープシーケンス
+const MATCH_COLOR: &str = "\x1b[31m";
+const RESET_COLOR: &str = "\x1b[0m";
+
+const USAGE: &str = "\
+usage: grep [OPTION]... PATTERN [PATH]...
+       grep [OPTION]... -e PATTERN [-e PATTERN]... [PATH]...
+Search for lines matching PATTERN (a regular expression) in each PATH.
+Directories are searched recursively. With no PATH, search the current directory.
+
+  -e, --regexp=PATTERN       use PATTERN for matching (can be given more than once)
+  -F, --fixed-strings        treat PATTERN as a plain string, not a regular expression
+  -i, --ignore-case          ignore case distinctions (also enabled by IS_INSENSITIVE)
+  -S, --smart-case           ignore case distinctions unless PATTERN has an uppercase letter
+  -w, --word-regexp          match only whole words
+  -x, --line-regexp          match only whole lines
+  -v, --invert-match         select non-matching lines
+  -c, --count                print only a count of selected lines per file
+  -l, --files-with-matches   print only names of files with selected lines
+  -L, --files-without-match  print only names of files without selected lines
+  -n, --line-number          print line numbers
+  -o, --only-matching        print only the matched parts of lines
+  -m, --max-count=NUM        stop reading a file after NUM selected lines
+  -A, --after-context=NUM    print NUM lines of context after selected lines
+  -B, --before-context=NUM   print NUM lines of context before selected lines
+  -C, --context=NUM          print NUM lines of context before and after selected lines
+      --color[=WHEN]         highlight matches. WHEN is auto (default), always or never
+      --include=GLOB         search only files matching GLOB
+      --exclude=GLOB         skip files and directories matching GLOB
+      --hidden               search hidden files and directories
+      --no-ignore            don't skip files listed in .gitignore and .ignore
+  -j, --threads=NUM          number of threads to search with (default: number of CPUs)
+      --sort                 print results in a deterministic order even with threads
+      --help                 display this help and exit
+      --version              display version information and exit
+
+Exit status is 0 if a line is selected, 1 if no lines were selected, and 2 if an error occurred.";
+
+// 短いオプションと、同じ意味の長いオプション
+const SHORT_OPTIONS: [(char, &str); 17] = [
+    ('e', "regexp"),
+    ('F', "fixed-strings"),
+    ('i', "ignore-case"),
+    ('S', "smart-case"),
+    ('w', "word-regexp"),
+    ('x', "line-regexp"),
+    ('v', "invert-match"),
+    ('c', "count"),
+    ('l', "files-with-matches"),
+    ('L', "files-without-match"),
+    ('n', "line-number"),
+    ('o', "only-matching"),
+    ('m', "max-count"),
+    ('A', "after-context"),
+    ('B', "before-context"),
+    ('C', "context"),
+    ('j', "threads"),
+];
+
+// 値を取るオプション(=の後ろか、次の引数を値にする)
+const VALUE_OPTIONS: [&str; 8] = [
+    "regexp",
+    "max-count",
+    "after-context",
+    "before-context",
+    "context",
+    "threads",
+    "include",
+    "exclude",
+];
 
 pub fn grep_fast() {
     // env::args()で、コマンドラインから入力された引数を取得
@@ -15,29 +99,67 @@ pub fn grep_fast() {
     // .collectの戻り値はFromIteratorトレイトを実装している必要がある(Vec<T>はFromIteratorを実装している=iteratorから変換可能)
     // args()は不正なUnicodeを含んでいた場合panicを起こす
     // 不正なUnicodeを受け入れる必要がある場合は,args_os()を使う
-    let mut args = env::args();
+    let args = env::args();
     // Resultのunwrap_or_else()でpanic以外の独自エラーが発生した際の処理をクロージャで定義できる
-    let config: GrepConfg = GrepConfg::new(&mut args).unwrap_or_else(|err| {
+    let command = parse_args(args).unwrap_or_else(|err| {
         eprintln!("引数解析時に問題発生： {}", err);
+        eprintln!("詳しくは grep --help を見てください");
         // non zero codeを指定することでエラーであることを通知
-        process::exit(1);
+        process::exit(2);
     });
-
-    // env::var()はResultを返すが、.is_err()でbool値に変換している
-    // 環境変数が設定されていなければ.is_err()はtrueを、設定されていればfalseを返す
-    let is_sensitive: bool = env::var("IS_INSENSITIVE").is_err();
-
-    if is_sensitive == true {
-        // unwrapしたい値を返さないのでunwrap_or_elseではなく、if let構文を使う
-        if let Err(e) = run_read_buf(config) {
-            eprintln!("検索時エラー: {}", e);
-            process::exit(1);
+    let config = match command {
+        Command::Search(config) => config,
+        Command::Help => {
+            println!("{}", USAGE);
+            return;
+        }
+        Command::Version => {
+            println!("grep (practice) {}", option_env!("CARGO_PKG_VERSION").unwrap_or("unknown"));
+            return;
         }
+    };
+
+    // stdout.lock()・・stdoutのロックを１度だけ取ることで速度向上
+    // BufWriter::new()・・標準出力への書き込みをメモリ内にバッファリングしてI/Oの頻度を抑える
+    let stdout = io::stdout();
+    let mut stdout_writer = BufWriter::new(stdout.lock());
+    let result = if config.threads > 1 {
+        // 複数のスレッドで検索する
+        run_parallel(&config, &mut stdout_writer)
     } else {
-        // unwrapしたい値を返さないのでunwrap_or_elseではなく、if let構文を使う
-        if let Err(e) = run_read_all(config) {
+        run_read_buf(&config, &mut stdout_writer)
+    };
+    // unwrapしたい値を返さないのでunwrap_or_elseではなく、match式を使う
+    match result {
+        // 読めないファイルがあれば2、選んだ行があれば0、無ければ1で終了する
+        Ok(summary) if summary.errors => process::exit(2),
+        Ok(summary) if summary.matched => {}
+        Ok(_) => process::exit(1),
+        Err(e) => {
             eprintln!("検索時エラー: {}", e);
-            process::exit(1);
+            process::exit(2);
+        }
+    }
+}
+
+/// 全てのファイルを検索した結果(終了ステータスを決めるのに使う)
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Summary {
+    /// 行を選んだ(-Lなら名前を表示した)ファイルがあった
+    pub matched: bool,
+    /// 読めないファイルがあった
+    pub errors: bool,
+}
+
+impl Summary {
+    fn add(&mut self, result: &io::Result<bool>) {
+        match result {
+            Ok(matched) => self.matched |= matched,
+            // 読めないファイルがあっても、エラーを表示して残りのファイルを検索する
+            Err(e) => {
+                eprintln!("{}", e);
+                self.errors = true;
+            }
         }
     }
 }
@@ -45,115 +167,900 @@ pub fn grep_fast() {
 // Box<dyn Error>はトレイトオブジェクト
 // Errorトレイトを実装しているオブジェクトであればなんでも返せるため、
 // エラー時の戻り値を柔軟に出来る
-#[allow(dead_code)]
-fn run_read_buf(config: GrepConfg)-> Result<(), Box<dyn Error>> {
-    let mut reader = create_file_reader(&config.filename);
-    // stdout.lock()・・stdoutのロックをloopの前に１度だけ取ることで速度向上
-    // BufWriter::new()・・標準出力への書き込みをメモリ内にバッファリングしてI/Oの頻度を抑える
-    let stdout = io::stdout();
-    let mut stdout_writer = BufWriter::new(stdout.lock());
-    let mut search_target_text: String = String::new();
-    loop {
-        let num: usize = reader.read_line(&mut search_target_text)?;
-        let res = search_target_text.find(&config.query);
-        match res {
-            Some(_) => {
-                let display_text: String = search_target_text.replace(&config.query, &format!("\x1b[31m{}\x1b[37m", config.query)).replace("\n", "");
-                writeln!(stdout_writer, "{}", display_text)?;
-                // println!は毎回stdoutのロックを取っているため遅い
-                // println!("{}", replaced_buf);
-            },
-            None => ()
+// 1つのスレッドでファイルを順に、1行ずつ読みながら検索して、選んだ行をwriterに書く
+pub fn run_read_buf(config: &GrepConfg, writer: &mut impl Write)-> Result<Summary, Box<dyn Error>> {
+    // 検索文字列は正規表現としてコンパイルしておく(不正なパターンはここでエラーになる)
+    let regex = config.regex()?;
+    let mut summary = Summary::default();
+    // 前のファイルで行を書いていれば、次のファイルの最初のまとまりの前にも区切りを書く
+    let mut separate = false;
+    for path in config.files() {
+        let result = path.and_then(|path| {
+            let reader = create_file_reader(&path)?;
+            let mut search = FileSearch::new(config, &regex, &path, writer, separate);
+            search_reader(&mut search, reader).map_err(|e| with_path(e, &path))?;
+            separate |= search.last_line.is_some();
+            search.finish()
+        });
+        summary.add(&result);
+    }
+    writer.flush()?;
+    Ok(summary)
+}
+
+// 検索したファイルの結果(書く内容と、行を選んだかどうか)
+type FileResult = io::Result<(Vec<u8>, bool)>;
+
+// config.threads個のスレッドでファイルを並行して検索して、選んだ行をwriterに書く
+// ディレクトリを辿るのはこのスレッドで行い、見つけたファイルをチャネルでワーカースレッドに渡す
+// ワーカーはファイルごとに書く内容をまとめて返すので、別のファイルの行が混ざることはない
+// config.sortedなら、1つのスレッドで検索した時と同じ順(辿った順)に書く。そうでなければ検索し終わった順に書く
+pub fn run_parallel(config: &GrepConfg, writer: &mut impl Write) -> Result<Summary, Box<dyn Error>> {
+    let regex = config.regex()?;
+    let (job_sender, job_receiver) = mpsc::channel::<(usize, PathBuf)>();
+    // 複数のワーカーで1つの受信側を共有するためにMutexに入れる
+    let job_receiver = Mutex::new(job_receiver);
+    let (result_sender, result_receiver) = mpsc::channel::<(usize, FileResult)>();
+    let mut output = Output {
+        writer,
+        sorted: config.sorted,
+        pending: BTreeMap::new(),
+        next: 0,
+        context: config.context(),
+        separate: false,
+        summary: Summary::default(),
+    };
+    // thread::scopeの中で作ったスレッドは、スコープを抜ける前に必ずjoinされるので、
+    // regexやconfigをArcに入れずに参照で渡せる
+    thread::scope(|scope| -> io::Result<()> {
+        for _ in 0..config.threads {
+            let result_sender = result_sender.clone();
+            let regex = &regex;
+            let job_receiver = &job_receiver;
+            scope.spawn(move || loop {
+                // ロックは次のファイルを受け取る間だけ持つ
+                let job = job_receiver.lock().unwrap().recv();
+                // 送信側が閉じられたら(全てのファイルを渡し終えたら)終わる
+                let (index, path) = match job {
+                    Ok(job) => job,
+                    Err(_) => break,
+                };
+                let mut lines = vec![];
+                let result = create_file_reader(&path).and_then(|reader| {
+                    let mut search = FileSearch::new(config, regex, &path, &mut lines, false);
+                    search_reader(&mut search, reader).map_err(|e| with_path(e, &path))?;
+                    search.finish()
+                });
+                if result_sender.send((index, result.map(|matched| (lines, matched)))).is_err() {
+                    break;
+                }
+            });
+        }
+        // ワーカーが持つ送信側だけが残るようにする(全てのワーカーが終わると受信が終わる)
+        drop(result_sender);
+
+        for (index, path) in config.files().enumerate() {
+            match path {
+                Ok(path) => job_sender.send((index, path)).unwrap(),
+                // ディレクトリを辿る時のエラーも、順番を保つため結果として扱う
+                Err(e) => output.push(index, Err(e))?,
+            }
+            // 辿っている間も、検索し終わった結果を書いていく
+            while let Ok((index, result)) = result_receiver.try_recv() {
+                output.push(index, result)?;
+            }
+        }
+        drop(job_sender);
+        for (index, result) in result_receiver {
+            output.push(index, result)?;
+        }
+        Ok(())
+    })?;
+    output.writer.flush()?;
+    Ok(output.summary)
+}
+
+// 並行して検索した結果を書く
+struct Output<'a, W: Write> {
+    writer: &'a mut W,
+    sorted: bool,
+    // 順番を保つ場合に、前のファイルの結果を待っている結果
+    pending: BTreeMap<usize, FileResult>,
+    // 順番を保つ場合に、次に書くファイルの番号
+    next: usize,
+    // 前後の行も書くか(-A・-B・-C)
+    context: bool,
+    // 次のファイルの行の前に区切りを書くか
+    separate: bool,
+    summary: Summary,
+}
+
+impl<W: Write> Output<'_, W> {
+    fn push(&mut self, index: usize, result: FileResult) -> io::Result<()> {
+        if !self.sorted {
+            return self.write(result);
+        }
+        self.pending.insert(index, result);
+        while let Some(result) = self.pending.remove(&self.next) {
+            self.write(result)?;
+            self.next += 1;
         }
+        Ok(())
+    }
+
+    fn write(&mut self, result: FileResult) -> io::Result<()> {
+        let result = match result {
+            Ok((lines, matched)) => {
+                // ワーカーはファイルごとに検索するので、ファイルの間の区切りはここで書く
+                if self.context && !lines.is_empty() {
+                    if self.separate {
+                        writeln!(self.writer, "--")?;
+                    }
+                    self.separate = true;
+                }
+                self.writer.write_all(&lines)?;
+                Ok(matched)
+            }
+            Err(e) => Err(e),
+        };
+        self.summary.add(&result);
+        Ok(())
+    }
+}
+
+// 1行ずつ読みながら検索する
+// UTF-8として正しくないファイルも検索できるよう、バイト列として読む
+fn search_reader<W: Write>(search: &mut FileSearch<W>, mut reader: impl BufRead) -> io::Result<()> {
+    // 最初に読み込んだブロックで、バイナリファイルかどうかを判定する
+    let block = reader.fill_buf()?;
+    search.binary = is_binary(&block[..block.len().min(BINARY_CHECK_SIZE)]);
+    let mut search_target_text: Vec<u8> = vec![];
+    let mut number = 0;
+    while search.wants_more() {
         search_target_text.clear();
+        let num: usize = reader.read_until(b'\n', &mut search_target_text)?;
         if num == 0 {
             break;
         }
+        number += 1;
+        // UTF-8として正しくないバイトはU+FFFDに置き換えて検索する
+        search.line(number, &String::from_utf8_lossy(trim_line_end(&search_target_text)))?;
     }
     Ok(())
 }
 
-fn run_read_all(config: GrepConfg)-> Result<(), Box<dyn Error>> {
-    let mut f = File::open(config.filename)?;
+// 行末の改行("\r\n"か"\n")は検索対象に含めない($が改行の前で一致するように)
+fn trim_line_end(line: &[u8]) -> &[u8] {
+    line.strip_suffix(b"\r\n")
+        .or_else(|| line.strip_suffix(b"\n"))
+        .unwrap_or(line)
+}
 
-    let mut contents = String::new();
-    f.read_to_string(&mut contents)?;
+// NULを含むファイルはテキストではないとみなす
+fn is_binary(block: &[u8]) -> bool {
+    block.contains(&0)
+}
 
-    for line in search(&config.query, &contents) {
-        println!("{}", line);
+// 1つのファイルを検索して、選んだ行(-vなら一致しなかった行)や、-c・-l・-Lの結果を書く
+// -A・-B・-Cなら選んだ行の前後の行も書き、続いていない行のまとまりの間に「--」を書く
+struct FileSearch<'a, W: Write> {
+    config: &'a GrepConfg,
+    regex: &'a Regex,
+    path: &'a Path,
+    writer: &'a mut W,
+    // 選んだ行の数
+    count: u64,
+    // まだ書いていない、選んだ行の前に書くかもしれない直前の行(最大でconfig.before()行)
+    before: VecDeque<(u64, String)>,
+    // 選んだ行の後に、あと何行書くか
+    after: usize,
+    // 最後に書いた行の番号
+    last_line: Option<u64>,
+    // 前のファイルで行を書いたので、最初のまとまりの前にも区切りを書く
+    separate: bool,
+    // バイナリファイルなら、行の代わりに「Binary file ... matches」とだけ書く
+    binary: bool,
+}
+
+impl<'a, W: Write> FileSearch<'a, W> {
+    fn new(config: &'a GrepConfg, regex: &'a Regex, path: &'a Path, writer: &'a mut W, separate: bool) -> Self {
+        Self {
+            config,
+            regex,
+            path,
+            writer,
+            count: 0,
+            before: VecDeque::new(),
+            after: 0,
+            last_line: None,
+            separate,
+            binary: false,
+        }
     }
 
-    Ok(())
+    // -mで指定した数の行を選び終わったか
+    fn max_count_reached(&self) -> bool {
+        self.config.max_count.is_some_and(|max| self.count >= max)
+    }
+
+    // まだ続きの行を読む必要があるか
+    fn wants_more(&self) -> bool {
+        match self.config.output {
+            // ファイル名を表示するだけなら、1行見つかれば十分
+            OutputMode::FilesWithMatches | OutputMode::FilesWithoutMatch => self.count == 0,
+            // バイナリファイルは1行見つかれば十分
+            OutputMode::Lines if self.binary => self.count == 0 && !self.max_count_reached(),
+            // -mで止める時も、最後に選んだ行の後の行は書く
+            OutputMode::Lines => !self.max_count_reached() || self.after > 0,
+            OutputMode::Count => !self.max_count_reached(),
+        }
+    }
+
+    // number行目を検索する
+    fn line(&mut self, number: u64, line: &str) -> io::Result<()> {
+        // -mの数に達した後は、一致する行も後ろの行として書くだけで選ばない
+        if self.max_count_reached() || self.regex.is_match(line) == self.config.invert {
+            return self.context_line(number, line);
+        }
+        self.count += 1;
+        if self.config.output != OutputMode::Lines {
+            return Ok(());
+        }
+        if self.binary {
+            // 前後の行を書く時は、普通の行と同じく前に書いた行との間に区切りを書く
+            self.write_separator(number)?;
+            return writeln!(self.writer, "Binary file {} matches", self.path.display());
+        }
+        // 溜めておいた前の行を先に書く
+        while let Some((number, line)) = self.before.pop_front() {
+            self.write_prefix(number, '-')?;
+            writeln!(self.writer, "{}", line)?;
+        }
+        if self.config.context() {
+            self.after = self.config.after();
+        }
+        if !self.config.only_matching {
+            self.write_prefix(number, ':')?;
+            // 一致しなかった行を表示する時は、色を付ける部分が無い
+            if self.config.color && !self.config.invert {
+                writeln!(self.writer, "{}", highlight(self.regex, line))?;
+            } else {
+                writeln!(self.writer, "{}", line)?;
+            }
+            return Ok(());
+        }
+        // -oは一致した部分を1つずつ別の行に書く(-vと一緒に使うと何も書かない)
+        if self.config.invert {
+            return Ok(());
+        }
+        for (start, end) in self.regex.find_iter(line) {
+            if start == end {
+                continue;
+            }
+            self.write_prefix(number, ':')?;
+            if self.config.color {
+                writeln!(self.writer, "{}{}{}", MATCH_COLOR, &line[start..end], RESET_COLOR)?;
+            } else {
+                writeln!(self.writer, "{}", &line[start..end])?;
+            }
+        }
+        Ok(())
+    }
+
+    // 選ばなかった行を、選んだ行の後の行なら書き、そうでなければ次に選ぶ行の前の行として溜めておく
+    // 溜めた行は書いた行より必ず後ろなので、前後の範囲が重なっても同じ行を2回書くことはない
+    fn context_line(&mut self, number: u64, line: &str) -> io::Result<()> {
+        if !self.config.context() || self.binary {
+            return Ok(());
+        }
+        if self.after > 0 {
+            self.after -= 1;
+            self.write_prefix(number, '-')?;
+            return writeln!(self.writer, "{}", line);
+        }
+        let before = self.config.before();
+        if before > 0 {
+            if self.before.len() == before {
+                // 溜めた行の文字列の領域を使い回す
+                let (_, mut buf) = self.before.pop_front().unwrap();
+                buf.clear();
+                buf.push_str(line);
+                self.before.push_back((number, buf));
+            } else {
+                self.before.push_back((number, line.to_string()));
+            }
+        }
+        Ok(())
+    }
+
+    // 複数のファイルを検索する時は、どのファイルの行か分かるように「パス:」を行の前に付ける
+    // -nなら「行番号:」も付ける
+    // 選んだ行は「:」、前後の行は「-」で区切る
+    fn write_prefix(&mut self, number: u64, separator: char) -> io::Result<()> {
+        self.write_separator(number)?;
+        if self.config.with_filename {
+            write!(self.writer, "{}{}", self.path.display(), separator)?;
+        }
+        if self.config.line_number {
+            write!(self.writer, "{}{}", number, separator)?;
+        }
+        Ok(())
+    }
+
+    // number行目を書く前に、前に書いた行と続いていなければ、まとまりの間に区切り(-A・-B・-Cの時)を書く
+    fn write_separator(&mut self, number: u64) -> io::Result<()> {
+        if self.config.context() {
+            let separate = match self.last_line {
+                Some(last) => number > last + 1,
+                None => self.separate,
+            };
+            if separate {
+                writeln!(self.writer, "--")?;
+            }
+        }
+        self.last_line = Some(number);
+        Ok(())
+    }
+
+    // ファイルを検索し終わった後に、-c・-l・-Lの結果を書く
+    // 終了ステータスを成功にするファイルか(行を選んだか、-Lなら名前を書いたか)を返す
+    fn finish(self) -> io::Result<bool> {
+        match self.config.output {
+            OutputMode::Lines => {}
+            OutputMode::Count => {
+                if self.config.with_filename {
+                    write!(self.writer, "{}:", self.path.display())?;
+                }
+                writeln!(self.writer, "{}", self.count)?;
+            }
+            OutputMode::FilesWithMatches => {
+                if self.count > 0 {
+                    writeln!(self.writer, "{}", self.path.display())?;
+                }
+            }
+            OutputMode::FilesWithoutMatch => {
+                if self.count == 0 {
+                    writeln!(self.writer, "{}", self.path.display())?;
+                }
+                return Ok(self.count == 0);
+            }
+        }
+        Ok(self.count > 0)
+    }
 }
 
-/// 与えられた検索文字列が含まれる行を返す
-/// 検索文字列は色付けする
-/// 大文字・小文字は区別しない
-///
-/// # Examples
-///
-/// ```
-/// let query = "hello";
-/// let contents = "hello every one.\n My name is Yori.\n It's over.\n Hello!";
-/// let result = grep::search(query, contents);
-/// assert_eq!(2, result.len());
-/// ```
-fn search(query: &str, contents: &str) -> Vec<String> {
-    // 検索文字列と検索対象を共に小文字に統一してから検索
-    let query = query.to_lowercase();
-    // lines()が返すLinesはIteratorトレイトを実装しているため、
-    // filterやmapなどIteratorのメソッドが使える
-    contents.lines()
-        // queryを含む行にフィルタ
-        .filter(|line| line.to_lowercase().contains(&query))
-        // 各行でqueryに該当する文字列の色を変更
-        .map(|line| line.replace(&query, &format!("\x1b[31m{}\x1b[37m", query)).replace("\n", ""))
-        // ベクタに変換してreturn
-        .collect()
+/// 行の中で正規表現に一致した部分を色付けする
+/// 一致した位置(バイト単位の範囲)で元の行を切り分けるので、元の文字がそのまま残る
+fn highlight(regex: &Regex, line: &str) -> String {
+    let mut highlighted = String::with_capacity(line.len());
+    let mut last = 0;
+    for (start, end) in regex.find_iter(line) {
+        // 空の一致(x*など)は色付けする文字が無い
+        if start == end {
+            continue;
+        }
+        highlighted.push_str(&line[last..start]);
+        highlighted.push_str(MATCH_COLOR);
+        highlighted.push_str(&line[start..end]);
+        highlighted.push_str(RESET_COLOR);
+        last = end;
+    }
+    highlighted.push_str(&line[last..]);
+    highlighted
 }
 
 /// ファイルリーダーを生成する関数
-/// 
-/// # Panics
-/// 指定したファイル名に該当するファイルが存在しない場合パニックを起こします
-#[allow(dead_code)]
-fn create_file_reader(filename: &str) -> BufReader<File> {
-    let file = File::open(filename).expect("file not found");
-    BufReader::new(file)
+///
+/// # Errors
+/// 指定したファイル名に該当するファイルが存在しない場合、ファイル名を付けたエラーを返します
+fn create_file_reader(filename: &Path) -> io::Result<BufReader<File>> {
+    let file = File::open(filename).map_err(|e| with_path(e, filename))?;
+    Ok(BufReader::new(file))
 }
 
-struct GrepConfg {
-    query: String,
-    filename: String
+// どのファイルのエラーか分かるように、メッセージにファイル名を付ける
+fn with_path(err: io::Error, path: &Path) -> io::Error {
+    io::Error::new(err.kind(), format!("{}: {}", path.display(), err))
+}
+
+// 何を書くか
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum OutputMode {
+    // 選んだ行
+    Lines,
+    // 選んだ行の数(-c)
+    Count,
+    // 行を選んだファイルの名前(-l)
+    FilesWithMatches,
+    // 行を選ばなかったファイルの名前(-L)
+    FilesWithoutMatch,
+}
+
+pub struct GrepConfg {
+    // 検索するパターン(どれかに一致する行を選ぶ)
+    patterns: Vec<String>,
+    regex: regex::Options,
+    // 検索するファイル・ディレクトリ(ディレクトリは再帰的に辿る)
+    paths: Vec<String>,
+    walk: WalkOptions,
+    // 検索するスレッドの数
+    threads: usize,
+    // 複数のスレッドで検索する時も、ファイルを辿った順に出力する
+    sorted: bool,
+    // 一致しなかった行を選ぶ(-v)
+    invert: bool,
+    output: OutputMode,
+    // 一致した部分だけを書く(-o)
+    only_matching: bool,
+    // 行番号を書く(-n)
+    line_number: bool,
+    // 1つのファイルで選ぶ行の数の上限(-m)
+    max_count: Option<u64>,
+    // 選んだ行の後・前に書く行の数(-A・-B)。指定しなければ-Cの数になる
+    after_context: Option<usize>,
+    before_context: Option<usize>,
+    // 選んだ行の前後に書く行の数(-C)
+    context: usize,
+    // 一致した部分に色を付ける
+    color: bool,
+    // 行の前にファイル名を付ける(複数のファイルを検索する時)
+    with_filename: bool,
+}
+
+/// コマンドライン引数で指定された動作
+pub enum Command {
+    Search(GrepConfg),
+    Help,
+    Version,
+}
+
+/// コマンドライン引数を解析する
+///
+/// grep [オプション]... <検索文字列> [ファイル・ディレクトリ...]
+/// grep [オプション]... -e <検索文字列> [-e <検索文字列>]... [ファイル・ディレクトリ...]
+///
+/// 短いオプションは-inのようにまとめたり、-m5のように値を続けて書いたりできる
+/// 長いオプションの値は--max-count=5か--max-count 5のように書く
+/// --より後ろの引数はオプションとして扱わない
+/// ファイル・ディレクトリを指定しなければカレントディレクトリを検索する
+/// 環境変数IS_INSENSITIVEが設定されていれば、-iと同じく大文字・小文字を区別しない
+pub fn parse_args(args: impl Iterator<Item = String>) -> Result<Command, String> {
+    // イテレータに対して.next()を使う場合、内部的に状態を保持して変更するため、
+    // mutableにする必要がある
+    let mut args = args.skip(1);
+    let mut config = GrepConfg {
+        patterns: vec![],
+        regex: regex::Options::default(),
+        paths: vec![],
+        walk: WalkOptions::default(),
+        threads: thread::available_parallelism().map_or(1, |n| n.get()),
+        sorted: false,
+        invert: false,
+        output: OutputMode::Lines,
+        only_matching: false,
+        line_number: false,
+        max_count: None,
+        after_context: None,
+        before_context: None,
+        context: 0,
+        color: io::stdout().is_terminal(),
+        with_filename: false,
+    };
+    let mut positional = vec![];
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            positional.extend(args.by_ref());
+            break;
+        }
+        if let Some(long) = arg.strip_prefix("--") {
+            let (name, value) = match long.split_once('=') {
+                Some((name, value)) => (name, Some(value.to_string())),
+                None => (long, None),
+            };
+            let value = match value {
+                None if VALUE_OPTIONS.contains(&name) => Some(args.next().ok_or_else(|| format!("--{}には値が必要です", name))?),
+                Some(_) if !VALUE_OPTIONS.contains(&name) && name != "color" && name != "colour" => {
+                    return Err(format!("--{}は値を取りません", name));
+                }
+                value => value,
+            };
+            if let Some(command) = config.apply(name, value)? {
+                return Ok(command);
+            }
+        } else if arg.len() > 1 && arg.starts_with('-') {
+            // -inのようにまとめて書いた短いオプションを1文字ずつ見る
+            for (i, c) in arg.char_indices().skip(1) {
+                let name = match SHORT_OPTIONS.iter().find(|(short, _)| *short == c) {
+                    Some((_, name)) => *name,
+                    None => return Err(format!("不明なオプションです: -{}", c)),
+                };
+                if !VALUE_OPTIONS.contains(&name) {
+                    config.apply(name, None)?;
+                    continue;
+                }
+                // 値を取るオプションは、残りの文字か次の引数を値にする
+                let rest = &arg[i + c.len_utf8()..];
+                let value = if rest.is_empty() {
+                    args.next().ok_or_else(|| format!("-{}には値が必要です", c))?
+                } else {
+                    rest.to_string()
+                };
+                config.apply(name, Some(value))?;
+                break;
+            }
+        } else {
+            positional.push(arg);
+        }
+    }
+
+    // -eで指定しなければ、最初の引数が検索文字列
+    let mut positional = positional.into_iter();
+    if config.patterns.is_empty() {
+        match positional.next() {
+            Some(pattern) => config.patterns.push(pattern),
+            None => return Err("検索文字列を指定してください".to_string()),
+        }
+    }
+    config.paths = positional.collect();
+    if config.paths.is_empty() {
+        config.paths.push(".".to_string());
+    }
+    config.with_filename = config.paths.len() > 1 || Path::new(&config.paths[0]).is_dir();
+    // env::var()はResultを返すが、.is_ok()でbool値に変換している
+    // 環境変数が設定されていれば.is_ok()はtrueを、設定されていなければfalseを返す
+    if env::var("IS_INSENSITIVE").is_ok() {
+        config.regex.case_insensitive = true;
+    }
+    Ok(Command::Search(config))
 }
 
 impl GrepConfg {
-    // この関数内で生成された文字列の参照である&strをreturnする
-    // ライフタイムを指定しないと、スコープを抜けた際にダングリング参照になる可能性がある
-    // 'staticが無いと「この関数の戻り値の型には、ライフタイムが省略された借用値が含まれていますが、ライフタイムは引数から導出できません」というエラーが出る
-    pub fn new(args: &mut Args) -> Result<GrepConfg, &'static str> {
-        if args.len() < 3 {
-            return Err("引数が足りません")
-        }
-        args.next();
-        // イテレータArgsに対して.next()を使う場合、内部的に状態を保持して変更するため、
-        // mutableにする必要がある
-        let query = match args.next() {
-            Some(arg) => arg.to_string(),
-            None => return Err("Didn't get a query string"),
-        };
-        let filename = match args.next() {
-            Some(arg) => arg.to_string(),
-            None => return Err("Didn't get a query string"),
-        };
+    // 長いオプションの名前で、オプションを1つ設定する
+    // --helpと--versionは検索せずに終わるのでCommandを返す
+    fn apply(&mut self, name: &str, value: Option<String>) -> Result<Option<Command>, String> {
+        // VALUE_OPTIONSのオプションには必ず値がある
+        let required = || value.clone().unwrap();
+        match name {
+            "regexp" => self.patterns.push(required()),
+            "fixed-strings" => self.regex.literal = true,
+            "ignore-case" => self.regex.case_insensitive = true,
+            "smart-case" => self.regex.smart_case = true,
+            "word-regexp" => self.regex.whole_word = true,
+            "line-regexp" => self.regex.whole_line = true,
+            "invert-match" => self.invert = true,
+            "count" => self.output = OutputMode::Count,
+            "files-with-matches" => self.output = OutputMode::FilesWithMatches,
+            "files-without-match" => self.output = OutputMode::FilesWithoutMatch,
+            "line-number" => self.line_number = true,
+            "only-matching" => self.only_matching = true,
+            "max-count" => {
+                let max = required().parse().map_err(|_| format!("不正な行数です: {}", required()))?;
+                self.max_count = Some(max);
+            }
+            "after-context" => self.after_context = Some(parse_lines(&required())?),
+            "before-context" => self.before_context = Some(parse_lines(&required())?),
+            "context" => self.context = parse_lines(&required())?,
+            "color" | "colour" => {
+                self.color = match value.as_deref() {
+                    None | Some("auto") => io::stdout().is_terminal(),
+                    Some("always") => true,
+                    Some("never") => false,
+                    Some(when) => return Err(format!("--colorにはauto・always・neverのどれかを指定してください: {}", when)),
+                }
+            }
+            "include" => self.walk.include.push(Glob::new(&required())),
+            "exclude" => self.walk.exclude.push(Glob::new(&required())),
+            "hidden" => self.walk.hidden = true,
+            "no-ignore" => self.walk.ignore_files = false,
+            "threads" => {
+                self.threads = match required().parse() {
+                    Ok(n) if n > 0 => n,
+                    _ => return Err("スレッドの数は1以上の整数で指定してください".to_string()),
+                }
+            }
+            "sort" => self.sorted = true,
+            "help" => return Ok(Some(Command::Help)),
+            "version" => return Ok(Some(Command::Version)),
+            _ => return Err(format!("不明なオプションです: --{}", name)),
+        }
+        Ok(None)
+    }
+
+    // パターンを正規表現としてコンパイルする(不正なパターンはここでエラーになる)
+    fn regex(&self) -> Result<Regex, regex::Error> {
+        Regex::with_options(&self.patterns, &self.regex)
+    }
+
+    // 検索するファイルを順に返す
+    fn files(&self) -> Walk {
+        Walk::new(&self.paths, self.walk.clone())
+    }
+
+    // 選んだ行の後に書く行の数(-Aを-Cより優先する)
+    fn after(&self) -> usize {
+        self.after_context.unwrap_or(self.context)
+    }
+
+    // 選んだ行の前に書く行の数(-Bを-Cより優先する)
+    fn before(&self) -> usize {
+        self.before_context.unwrap_or(self.context)
+    }
+
+    // 選んだ行の前後の行も書くか(-c・-l・-L・-oの時は書かない)
+    fn context(&self) -> bool {
+        (self.after() > 0 || self.before() > 0) && self.output == OutputMode::Lines && !self.only_matching
+    }
+}
+
+// -A・-B・-Cの行数を読む
+fn parse_lines(value: &str) -> Result<usize, String> {
+    value.parse().map_err(|_| format!("不正な行数です: {}", value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("grep_test_{}_{}", process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn config(args: &[&str]) -> GrepConfg {
+        match parse_args(["grep", "--color=never"].iter().chain(args).map(|arg| arg.to_string())) {
+            Ok(Command::Search(config)) => config,
+            _ => panic!("invalid arguments: {:?}", args),
+        }
+    }
+
+    type Run = fn(&GrepConfg, &mut Vec<u8>) -> Result<Summary, Box<dyn Error>>;
+
+    // 1つのスレッドで読む・複数のスレッドで順番を保って読む、の2通りで検索して、
+    // 同じ結果になることを確かめてから返す
+    fn grep(args: &[&str]) -> (String, Summary) {
+        let mut config = config(args);
+        config.threads = 4;
+        config.sorted = true;
+        let runs: [Run; 2] = [run_read_buf, run_parallel];
+        let mut results = vec![];
+        for run in runs {
+            let mut output = vec![];
+            let summary = run(&config, &mut output).unwrap();
+            results.push((String::from_utf8(output).unwrap(), summary));
+        }
+        assert_eq!(results[0], results[1], "run_parallel differs from run_read_buf");
+        results.pop().unwrap()
+    }
+
+    // 解析に失敗した時のメッセージ
+    fn parse_error(args: &[&str]) -> String {
+        match parse_args(["grep"].iter().chain(args).map(|arg| arg.to_string())) {
+            Ok(_) => panic!("parsed invalid arguments: {:?}", args),
+            Err(err) => err,
+        }
+    }
+
+    #[test]
+    fn test_parse_args() {
+        // 短いオプションはまとめて書ける
+        let parsed = config(&["-inv", "foo", "a", "b"]);
+        assert!(parsed.regex.case_insensitive && parsed.line_number && parsed.invert);
+        assert_eq!(parsed.patterns, vec!["foo"]);
+        assert_eq!(parsed.paths, vec!["a", "b"]);
+        assert!(parsed.with_filename);
+
+        // 値は続けて書いても、次の引数にしても、=で書いても良い
+        let parsed = config(&["-m5", "-A2", "-B", "3", "--context=4", "-j", "2", "foo"]);
+        assert_eq!(parsed.max_count, Some(5));
+        assert_eq!((parsed.after(), parsed.before()), (2, 3));
+        assert_eq!(parsed.threads, 2);
+        assert_eq!(parsed.paths, vec!["."]);
+        let parsed = config(&["-C1", "-cn", "foo"]);
+        assert_eq!((parsed.after(), parsed.before()), (1, 1));
+        assert_eq!(parsed.output, OutputMode::Count);
+
+        // -eは何度でも指定でき、その時は最初の引数もパスになる
+        let parsed = config(&["-e", "foo", "-ebar", "--regexp=baz", "path"]);
+        assert_eq!(parsed.patterns, vec!["foo", "bar", "baz"]);
+        assert_eq!(parsed.paths, vec!["path"]);
+        let regex = parsed.regex().unwrap();
+        assert!(regex.is_match("xbaz") && !regex.is_match("qux"));
+
+        // -Fなら正規表現の記号もただの文字
+        let regex = config(&["-F", "a.b(", "."]).regex().unwrap();
+        assert!(regex.is_match("xa.b(y"));
+        assert!(!regex.is_match("axb("));
+        assert!(config(&["a.b(", "."]).regex().is_err());
+
+        // 後に書いた--colorが優先される。autoは標準出力が端末なら色を付ける
+        assert!(config(&["--color=always", "foo"]).color);
+        assert!(!config(&["--color=always", "--color=never", "foo"]).color);
+        assert_eq!(config(&["--color=always", "--color", "foo"]).color, io::stdout().is_terminal());
+        assert_eq!(config(&["--colour=auto", "foo"]).color, io::stdout().is_terminal());
+
+        // --より後ろはオプションとして扱わない
+        let parsed = config(&["-n", "--", "-v", "--help"]);
+        assert_eq!(parsed.patterns, vec!["-v"]);
+        assert_eq!(parsed.paths, vec!["--help"]);
+        assert!(parsed.line_number && !parsed.invert);
+
+        assert!(matches!(parse_args(["grep", "-n", "--help"].iter().map(|arg| arg.to_string())), Ok(Command::Help)));
+        assert!(matches!(parse_args(["grep", "--version"].iter().map(|arg| arg.to_string())), Ok(Command::Version)));
+    }
+
+    #[test]
+    fn test_parse_args_errors() {
+        assert_eq!(parse_error(&["-z", "foo"]), "不明なオプションです: -z");
+        assert_eq!(parse_error(&["-iz", "foo"]), "不明なオプションです: -z");
+        assert_eq!(parse_error(&["--bogus", "foo"]), "不明なオプションです: --bogus");
+        assert_eq!(parse_error(&["foo", "-m"]), "-mには値が必要です");
+        assert_eq!(parse_error(&["foo", "--after-context"]), "--after-contextには値が必要です");
+        assert_eq!(parse_error(&["--count=1", "foo"]), "--countは値を取りません");
+        assert_eq!(parse_error(&["-m", "x", "foo"]), "不正な行数です: x");
+        assert_eq!(parse_error(&["-j0", "foo"]), "スレッドの数は1以上の整数で指定してください");
+        assert!(parse_error(&["--color=sometimes", "foo"]).contains("sometimes"));
+        assert_eq!(parse_error(&["-n"]), "検索文字列を指定してください");
+    }
+
+    // outputを書いたファイルごとに分ける。同じファイルの行が離れた場所に出てきたらpanicする
+    fn group_by_file(output: &str) -> Vec<(&str, Vec<&str>)> {
+        let mut groups: Vec<(&str, Vec<&str>)> = vec![];
+        for line in output.lines() {
+            let (path, _) = line.split_once(':').unwrap();
+            match groups.last_mut() {
+                Some((last, lines)) if *last == path => lines.push(line),
+                _ => {
+                    assert!(groups.iter().all(|(seen, _)| *seen != path), "lines of {} are interleaved", path);
+                    groups.push((path, vec![line]));
+                }
+            }
+        }
+        groups
+    }
+
+    #[test]
+    fn test_highlight() {
+        let regex = config(&["été"]).regex().unwrap();
+        assert_eq!(highlight(&regex, "l'été"), "l'\x1b[31mété\x1b[0m");
+        // 大文字・小文字を区別しない時は、元の行の文字のまま色を付ける(ÉとéはUTF-8で2バイト)
+        let regex = config(&["-i", "été"]).regex().unwrap();
+        assert_eq!(
+            highlight(&regex, "L'ÉTÉ et l'Été"),
+            "L'\x1b[31mÉTÉ\x1b[0m et l'\x1b[31mÉté\x1b[0m"
+        );
+        // 空の一致には色を付けない
+        let regex = config(&["x*"]).regex().unwrap();
+        assert_eq!(highlight(&regex, "aéb"), "aéb");
+        assert_eq!(highlight(&regex, "axxb"), "a\x1b[31mxx\x1b[0mb");
+    }
+
+    #[test]
+    fn test_color_output() {
+        let dir = temp_dir("color");
+        let path = dir.join("a.txt");
+        fs::write(&path, "Grüße aus Köln\nno match\nköln, KÖLN\n").unwrap();
+        let path = path.to_str().unwrap();
+        let (output, _) = grep(&["--color=always", "-n", "-i", "köln", path]);
+        assert_eq!(
+            output,
+            "1:Grüße aus \x1b[31mKöln\x1b[0m\n3:\x1b[31mköln\x1b[0m, \x1b[31mKÖLN\x1b[0m\n"
+        );
+        // -oは一致した部分を1つずつ色付けして書く
+        let (output, _) = grep(&["--color=always", "-o", "-i", "ö", path]);
+        assert_eq!(output, "\x1b[31mö\x1b[0m\n\x1b[31mö\x1b[0m\n\x1b[31mÖ\x1b[0m\n");
+        // -vで選んだ行には一致した部分が無いので、色を付けない
+        let (output, _) = grep(&["--color=always", "-v", "-i", "köln", path]);
+        assert_eq!(output, "no match\n");
+    }
+
+    #[test]
+    fn test_parallel_output() {
+        let dir = temp_dir("parallel");
+        for i in 0..64 {
+            let sub = dir.join(format!("{:02}", i / 8));
+            fs::create_dir_all(&sub).unwrap();
+            let contents: String = (0..200).map(|j| format!("file {} line {} {}\n", i, j, if j % 7 == 0 { "match" } else { "skip" })).collect();
+            fs::write(sub.join(format!("{}.txt", i)), contents).unwrap();
+        }
+        let dir = dir.to_str().unwrap();
+        let mut expected = vec![];
+        run_read_buf(&config(&["-n", "match", dir]), &mut expected).unwrap();
+        let expected = String::from_utf8(expected).unwrap();
+        let expected_groups = group_by_file(&expected);
+        assert_eq!(expected_groups.len(), 64);
+        for threads in [2, 4, 8] {
+            let threads = format!("-j{}", threads);
+            // --sortなら1つのスレッドで検索した時と全く同じ出力になる
+            let mut output = vec![];
+            run_parallel(&config(&[&threads, "--sort", "-n", "match", dir]), &mut output).unwrap();
+            assert_eq!(String::from_utf8(output).unwrap(), expected);
+            // --sortが無ければファイルの順番は変わるが、1つのファイルの行はまとまっていて順番も変わらない
+            let mut output = vec![];
+            run_parallel(&config(&[&threads, "-n", "match", dir]), &mut output).unwrap();
+            let output = String::from_utf8(output).unwrap();
+            let mut groups = group_by_file(&output);
+            groups.sort();
+            let mut expected_groups = expected_groups.clone();
+            expected_groups.sort();
+            assert_eq!(groups, expected_groups);
+        }
+    }
+
+    #[test]
+    fn test_context() {
+        let dir = temp_dir("context");
+        let lines: String = (1..=20).map(|n| format!("line {}\n", n)).collect();
+        fs::write(dir.join("a.txt"), &lines).unwrap();
+        let path = dir.join("a.txt");
+        let path = path.to_str().unwrap();
+
+        // 3行目と5行目の前後の範囲は重なるので、1つのまとまりとして書く
+        // 12行目のまとまりとは続いていないので、間に--を書く
+        let (output, _) = grep(&["-n", "-B2", "-A1", "-e", "line 3$", "-e", "line 5$", "-e", "line 12$", path]);
+        assert_eq!(output, "1-line 1\n2-line 2\n3:line 3\n4-line 4\n5:line 5\n6-line 6\n--\n10-line 10\n11-line 11\n12:line 12\n13-line 13\n");
+        // 後ろの範囲と次の前の範囲がちょうど接していれば、--は書かない
+        let (output, _) = grep(&["-n", "-C1", "-e", "line 2$", "-e", "line 5$", path]);
+        assert_eq!(output, "1-line 1\n2:line 2\n3-line 3\n4-line 4\n5:line 5\n6-line 6\n");
+
+        // -mで止めても、最後に選んだ行の後ろの行は書く(一致する行も前後の行として書く)
+        let (output, summary) = grep(&["-n", "-m2", "-A2", "line 1", path]);
+        assert_eq!(output, "1:line 1\n2-line 2\n3-line 3\n--\n10:line 10\n11-line 11\n12-line 12\n");
+        assert!(summary.matched);
+    }
+
+    #[test]
+    fn test_context_between_files() {
+        let dir = temp_dir("context_files");
+        fs::write(dir.join("a.txt"), "x\nmatch\ny\n").unwrap();
+        fs::write(dir.join("b.txt"), "none\n").unwrap();
+        fs::write(dir.join("c.txt"), "match\nz\n").unwrap();
+        let dir = dir.to_str().unwrap();
+        // ファイルの間には--を1つだけ書き、何も書かないファイルの分は書かない
+        // ファイルの最初と最後には書かない
+        let (output, _) = grep(&["-C1", "match", dir]);
+        assert_eq!(output, format!("{0}/a.txt-x\n{0}/a.txt:match\n{0}/a.txt-y\n--\n{0}/c.txt:match\n{0}/c.txt-z\n", dir));
+    }
+
+    #[test]
+    fn test_crlf() {
+        let dir = temp_dir("crlf");
+        fs::write(dir.join("a.txt"), "foo\r\nbar\r\nbaz\r").unwrap();
+        let path = dir.join("a.txt");
+        let path = path.to_str().unwrap();
+        // 行末の\r\nは行に含めないので、$はその前で一致する
+        let (output, _) = grep(&["-n", "(foo|bar)$", path]);
+        assert_eq!(output, "1:foo\n2:bar\n");
+        // 改行の無い最後の行の\rは行の一部
+        let (output, _) = grep(&["-c", "baz$", path]);
+        assert_eq!(output, "0\n");
+    }
 
-        Ok(GrepConfg {
-            // .cloneは新しいメモリ領域にコピーを生成するため、
-            // 参照を保持するよりもメモリと時間を食う
-            // ただ、参照を保持する場合ライフタイムの設定が必要なので、
-            // それが無い分コードの見通しは良くなる
-            query,
-            filename
-        })
+    #[test]
+    fn test_binary_and_invalid_utf8_files() {
+        let dir = temp_dir("binary");
+        fs::write(dir.join("a.txt"), "hello\nworld\n").unwrap();
+        fs::write(dir.join("b.bin"), b"\x7fELF\0\0hello\xff\xfe\n").unwrap();
+        fs::write(dir.join("c.txt"), b"caf\xe9 hello\n").unwrap();
+        let dir = dir.to_str().unwrap();
+        let (output, summary) = grep(&["hello", dir]);
+        let expected = format!(
+            "{0}/a.txt:hello\nBinary file {0}/b.bin matches\n{0}/c.txt:caf\u{fffd} hello\n",
+            dir
+        );
+        assert_eq!(output, expected);
+        assert_eq!(summary, Summary { matched: true, errors: false });
+        // 前後の行を書く時も、バイナリファイルの行と他のファイルの行の間に区切りを書く
+        let (output, _) = grep(&["-C1", "hello", dir]);
+        let expected = format!(
+            "{0}/a.txt:hello\n{0}/a.txt-world\n--\nBinary file {0}/b.bin matches\n--\n{0}/c.txt:caf\u{fffd} hello\n",
+            dir
+        );
+        assert_eq!(output, expected);
+        // バイナリファイルも-cや-lでは普通に数える
+        let (output, _) = grep(&["-l", "hello", dir]);
+        assert_eq!(output, format!("{0}/a.txt\n{0}/b.bin\n{0}/c.txt\n", dir));
     }
 }
@@ -0,0 +1,565 @@
+// 拡張ハッシュ(extendible hashing)によるインデックス
+// キーのハッシュ値でバケットを決めて(キー, 値)の組を保存する。等号での検索しかできないが、B+treeより辿るページが少ない
+//
+// - ディレクトリ：ハッシュ値の下位global depthビット → バケットのページID
+// - バケット：ハッシュ値の下位local depthビットが同じ組を入れるページ
+//   local depth < global depthのバケットは、ディレクトリの複数の要素から指される
+//
+// バケットがいっぱいになったら2つに分割し、次のビットで組を分け直す
+// 分割するバケットのlocal depthがglobal depthと同じなら、先にディレクトリを2倍にする
+// 同じキーが多いなど、下位MAX_DEPTHビットまで見ても分けられない組は、バケットの後ろにページを繋げて入れる
+//
+// ディレクトリは1ページに収まらないので、SEGMENT_ENTRIES個ずつのセグメントに分けて別のページに置く
+// ディレクトリのページ: | global depth(u8) | セグメントのページID(u64) ... |
+// セグメント: | バケットのページID(u64) ... |
+// バケット: | local depth(u8) | 次のページID(u64) | 組の数(u16) | (キーの長さ(u16), 値の長さ(u16), キー, 値) ... |
+//
+// 同じキーに複数の値を入れられる(キーと値の両方が同じ組は入れられない)
+// 削除してもバケットの併合はしない
+
+use crate::buffer_pool::{BufferPoolManager, Error, PAGE_LSN_SIZE};
+use crate::disk_manager::{PageId, PAGE_SIZE};
+use crate::transaction::{Transaction, UNDO_HASH};
+use crate::wal::TxnLog;
+
+const BODY_SIZE: usize = PAGE_SIZE - PAGE_LSN_SIZE;
+const BUCKET_HEADER_SIZE: usize = 1 + 8 + 2;
+const PAIR_HEADER_SIZE: usize = 4;
+// 1つのセグメントに入るディレクトリの要素の数
+const SEGMENT_ENTRIES: usize = 256;
+// ディレクトリの最大のglobal depth(2^16 / 256 = 256個のセグメントのページIDがディレクトリのページに収まる)
+const MAX_DEPTH: u8 = 16;
+
+// 取り消し方法の種類
+const UNDO_INSERT: u8 = 1;
+const UNDO_DELETE: u8 = 2;
+
+/// キーと値を合わせた最大サイズ
+/// 1バケットに少なくとも4組入るようにする
+pub const MAX_PAIR_SIZE: usize = (BODY_SIZE - BUCKET_HEADER_SIZE) / 4 - PAIR_HEADER_SIZE;
+
+/// (キー, 値)の組
+pub type Pair = (Vec<u8>, Vec<u8>);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HashIndex {
+    pub directory_page_id: PageId,
+}
+
+#[derive(Clone)]
+struct Directory {
+    depth: u8,
+    // ディレクトリの要素をSEGMENT_ENTRIES個ずつ置いたページ
+    segments: Vec<PageId>,
+    buckets: Vec<PageId>,
+}
+
+// global depthがdepthの時のセグメントの数
+fn segment_count(depth: u8) -> usize {
+    (1usize << depth).div_ceil(SEGMENT_ENTRIES)
+}
+
+#[derive(Clone)]
+struct Bucket {
+    depth: u8,
+    next: PageId,
+    pairs: Vec<Pair>,
+}
+
+impl Bucket {
+    fn read(body: &[u8]) -> Self {
+        let depth = body[0];
+        let next = PageId(read_u64(&body[1..9]));
+        let count = u16::from_le_bytes([body[9], body[10]]) as usize;
+        let mut pairs = Vec::with_capacity(count);
+        let mut offset = BUCKET_HEADER_SIZE;
+        for _ in 0..count {
+            let key_len = u16::from_le_bytes([body[offset], body[offset + 1]]) as usize;
+            let value_len = u16::from_le_bytes([body[offset + 2], body[offset + 3]]) as usize;
+            offset += PAIR_HEADER_SIZE;
+            let key = body[offset..offset + key_len].to_vec();
+            offset += key_len;
+            let value = body[offset..offset + value_len].to_vec();
+            offset += value_len;
+            pairs.push((key, value));
+        }
+        Self { depth, next, pairs }
+    }
+
+    fn write(&self, body: &mut [u8]) {
+        body[0] = self.depth;
+        body[1..9].copy_from_slice(&self.next.0.to_le_bytes());
+        body[9..11].copy_from_slice(&(self.pairs.len() as u16).to_le_bytes());
+        let mut offset = BUCKET_HEADER_SIZE;
+        for (key, value) in &self.pairs {
+            body[offset..offset + 2].copy_from_slice(&(key.len() as u16).to_le_bytes());
+            body[offset + 2..offset + 4].copy_from_slice(&(value.len() as u16).to_le_bytes());
+            offset += PAIR_HEADER_SIZE;
+            body[offset..offset + key.len()].copy_from_slice(key);
+            offset += key.len();
+            body[offset..offset + value.len()].copy_from_slice(value);
+            offset += value.len();
+        }
+        // 残りは0で埋めて、前の中身が残らないようにする
+        body[offset..].fill(0);
+    }
+
+    fn size(pairs: &[Pair]) -> usize {
+        BUCKET_HEADER_SIZE + pairs.iter().map(|(k, v)| PAIR_HEADER_SIZE + k.len() + v.len()).sum::<usize>()
+    }
+}
+
+fn read_u64(bytes: &[u8]) -> u64 {
+    let mut buf = [0u8; 8];
+    buf.copy_from_slice(&bytes[..8]);
+    u64::from_le_bytes(buf)
+}
+
+// FNV-1a
+// ファイルに保存したディレクトリと合わせるため、プロセスごとに変わらないハッシュ関数を使う
+fn hash(key: &[u8]) -> u64 {
+    let mut hash = 0xcbf2_9ce4_8422_2325u64;
+    for &b in key {
+        hash ^= b as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
+
+// 下位depthビット
+fn low_bits(hash: u64, depth: u8) -> usize {
+    (hash & ((1u64 << depth) - 1)) as usize
+}
+
+fn read_bucket(bufmgr: &mut BufferPoolManager, page_id: PageId) -> Result<Bucket, Error> {
+    let buffer = bufmgr.fetch_page(page_id)?;
+    let page = buffer.page.borrow();
+    Ok(Bucket::read(&page[PAGE_LSN_SIZE..]))
+}
+
+fn write_bucket(bufmgr: &mut BufferPoolManager, log: &TxnLog, page_id: PageId, bucket: &Bucket) -> Result<(), Error> {
+    let buffer = bufmgr.fetch_page(page_id)?;
+    log.modify_page(&buffer, |body| bucket.write(body))?;
+    Ok(())
+}
+
+// バケットと後ろに繋がったページを全て読む
+// 大きなチェーンでバッファを使い切らないよう、ページは借りたままにしない
+fn read_chain(bufmgr: &mut BufferPoolManager, first_page_id: PageId) -> Result<Vec<(PageId, Bucket)>, Error> {
+    let mut chain = vec![];
+    let mut page_id = first_page_id;
+    while let Some(current) = page_id.valid() {
+        let bucket = read_bucket(bufmgr, current)?;
+        page_id = bucket.next;
+        chain.push((current, bucket));
+    }
+    Ok(chain)
+}
+
+// 組をpagesから順に詰めて書く。足りなければページを繋げ、余ったページは解放する
+fn write_chain(bufmgr: &mut BufferPoolManager, log: &TxnLog, mut pages: Vec<PageId>, depth: u8, pairs: Vec<Pair>) -> Result<(), Error> {
+    let mut groups: Vec<Vec<Pair>> = vec![vec![]];
+    for pair in pairs {
+        let last = groups.last_mut().unwrap();
+        if Bucket::size(last) + PAIR_HEADER_SIZE + pair.0.len() + pair.1.len() > BODY_SIZE {
+            groups.push(vec![]);
+        }
+        groups.last_mut().unwrap().push(pair);
+    }
+    for &page_id in pages.iter().skip(groups.len()) {
+        free_page(bufmgr, log, page_id)?;
+    }
+    pages.truncate(groups.len());
+    while pages.len() < groups.len() {
+        pages.push(bufmgr.create_page()?.page_id);
+    }
+    for (i, pairs) in groups.into_iter().enumerate() {
+        let next = pages.get(i + 1).copied().unwrap_or(PageId::INVALID_PAGE_ID);
+        write_bucket(bufmgr, log, pages[i], &Bucket { depth, next, pairs })?;
+    }
+    Ok(())
+}
+
+// 中身を0にしてからページを解放する(再利用した時に前の中身がREDOで混ざらないようにする)
+fn free_page(bufmgr: &mut BufferPoolManager, log: &TxnLog, page_id: PageId) -> Result<(), Error> {
+    let buffer = bufmgr.fetch_page(page_id)?;
+    log.modify_page(&buffer, |body| body.fill(0))?;
+    drop(buffer);
+    log.free_page(bufmgr, page_id)?;
+    Ok(())
+}
+
+impl HashIndex {
+    /// 空のハッシュインデックスを作る(global depthが0で、バケットは1つ)
+    pub fn create(bufmgr: &mut BufferPoolManager, txn: &Transaction) -> Result<Self, Error> {
+        let log = txn.log();
+        let index = Self {
+            directory_page_id: bufmgr.create_page()?.page_id,
+        };
+        let bucket = bufmgr.create_page()?;
+        let empty = Bucket {
+            depth: 0,
+            next: PageId::INVALID_PAGE_ID,
+            pairs: vec![],
+        };
+        log.modify_page(&bucket, |body| empty.write(body))?;
+        // 何も書いていない状態から、セグメントを1つ作ってディレクトリを書く
+        let nothing = Directory {
+            depth: 0,
+            segments: vec![],
+            buckets: vec![],
+        };
+        let mut dir = Directory {
+            depth: 0,
+            segments: vec![],
+            buckets: vec![bucket.page_id],
+        };
+        index.write_directory(bufmgr, log, &nothing, &mut dir)?;
+        Ok(index)
+    }
+
+    pub fn open(directory_page_id: PageId) -> Self {
+        Self { directory_page_id }
+    }
+
+    /// キーに対応する値を全て返す
+    pub fn search(&self, bufmgr: &mut BufferPoolManager, key: &[u8]) -> Result<Vec<Vec<u8>>, Error> {
+        let first = self.bucket_of(bufmgr, key)?;
+        let mut values = vec![];
+        for (_, bucket) in read_chain(bufmgr, first)? {
+            values.extend(bucket.pairs.into_iter().filter(|(k, _)| k.as_slice() == key).map(|(_, v)| v));
+        }
+        Ok(values)
+    }
+
+    /// 組を追加する。キーと値の両方が同じ組が既にあればError::DuplicateKey
+    pub fn insert(&self, bufmgr: &mut BufferPoolManager, txn: &Transaction, key: &[u8], value: &[u8]) -> Result<(), Error> {
+        let log = txn.log();
+        let start = log.last_lsn();
+        self.insert_raw(bufmgr, log, key, value)?;
+        log.log_operation(start, undo_record(UNDO_INSERT, self.directory_page_id, key, value))?;
+        Ok(())
+    }
+
+    /// 組を削除する。無ければError::NotFound
+    pub fn delete(&self, bufmgr: &mut BufferPoolManager, txn: &Transaction, key: &[u8], value: &[u8]) -> Result<(), Error> {
+        let log = txn.log();
+        let start = log.last_lsn();
+        if !self.delete_raw(bufmgr, log, key, value)? {
+            return Err(Error::NotFound);
+        }
+        log.log_operation(start, undo_record(UNDO_DELETE, self.directory_page_id, key, value))?;
+        Ok(())
+    }
+
+    /// 全ての組を返す(順番はバケットの順で、キーの順ではない)
+    pub fn entries(&self, bufmgr: &mut BufferPoolManager) -> Result<Vec<Pair>, Error> {
+        let mut buckets = self.read_directory(bufmgr)?.buckets;
+        // local depthが小さいバケットはディレクトリの複数の要素から指されている
+        buckets.sort();
+        buckets.dedup();
+        let mut pairs = vec![];
+        for first in buckets {
+            for (_, bucket) in read_chain(bufmgr, first)? {
+                pairs.extend(bucket.pairs);
+            }
+        }
+        Ok(pairs)
+    }
+
+    /// ディレクトリのglobal depth
+    pub fn global_depth(&self, bufmgr: &mut BufferPoolManager) -> Result<u8, Error> {
+        Ok(self.read_directory(bufmgr)?.depth)
+    }
+
+    // ディレクトリのページからglobal depthとセグメントのページIDを読む
+    fn read_header(&self, bufmgr: &mut BufferPoolManager) -> Result<(u8, Vec<PageId>), Error> {
+        let buffer = bufmgr.fetch_page(self.directory_page_id)?;
+        let page = buffer.page.borrow();
+        let body = &page[PAGE_LSN_SIZE..];
+        let depth = body[0];
+        let segments = (0..segment_count(depth)).map(|i| PageId(read_u64(&body[1 + i * 8..]))).collect();
+        Ok((depth, segments))
+    }
+
+    // ディレクトリの全ての要素を読む
+    fn read_directory(&self, bufmgr: &mut BufferPoolManager) -> Result<Directory, Error> {
+        let (depth, segments) = self.read_header(bufmgr)?;
+        let entries = 1usize << depth;
+        let mut buckets = Vec::with_capacity(entries);
+        for &segment in &segments {
+            let buffer = bufmgr.fetch_page(segment)?;
+            let page = buffer.page.borrow();
+            let body = &page[PAGE_LSN_SIZE..];
+            let count = (entries - buckets.len()).min(SEGMENT_ENTRIES);
+            buckets.extend((0..count).map(|i| PageId(read_u64(&body[i * 8..]))));
+        }
+        Ok(Directory { depth, segments, buckets })
+    }
+
+    // ディレクトリを書く。セグメントが足りなければ作り、oldから変わったページだけを書き直す
+    fn write_directory(&self, bufmgr: &mut BufferPoolManager, log: &TxnLog, old: &Directory, dir: &mut Directory) -> Result<(), Error> {
+        while dir.segments.len() < segment_count(dir.depth) {
+            dir.segments.push(bufmgr.create_page()?.page_id);
+        }
+        for (i, entries) in dir.buckets.chunks(SEGMENT_ENTRIES).enumerate() {
+            let start = i * SEGMENT_ENTRIES;
+            if old.buckets.get(start..start + entries.len()) == Some(entries) {
+                continue;
+            }
+            let buffer = bufmgr.fetch_page(dir.segments[i])?;
+            log.modify_page(&buffer, |body| {
+                for (j, page_id) in entries.iter().enumerate() {
+                    body[j * 8..j * 8 + 8].copy_from_slice(&page_id.0.to_le_bytes());
+                }
+            })?;
+        }
+        if dir.depth != old.depth || dir.segments != old.segments {
+            let buffer = bufmgr.fetch_page(self.directory_page_id)?;
+            log.modify_page(&buffer, |body| {
+                body[0] = dir.depth;
+                for (i, page_id) in dir.segments.iter().enumerate() {
+                    body[1 + i * 8..9 + i * 8].copy_from_slice(&page_id.0.to_le_bytes());
+                }
+            })?;
+        }
+        Ok(())
+    }
+
+    // キーが入るバケットのページID(ディレクトリのページと、要素のあるセグメントだけを読む)
+    fn bucket_of(&self, bufmgr: &mut BufferPoolManager, key: &[u8]) -> Result<PageId, Error> {
+        let (depth, segments) = self.read_header(bufmgr)?;
+        let index = low_bits(hash(key), depth);
+        let buffer = bufmgr.fetch_page(segments[index / SEGMENT_ENTRIES])?;
+        let page = buffer.page.borrow();
+        Ok(PageId(read_u64(&page[PAGE_LSN_SIZE + index % SEGMENT_ENTRIES * 8..])))
+    }
+
+    fn insert_raw(&self, bufmgr: &mut BufferPoolManager, log: &TxnLog, key: &[u8], value: &[u8]) -> Result<(), Error> {
+        if key.len() + value.len() > MAX_PAIR_SIZE {
+            return Err(Error::TooLarge);
+        }
+        let key_hash = hash(key);
+        // 分割したら、入れるバケットを探し直す
+        loop {
+            let first = self.bucket_of(bufmgr, key)?;
+            let chain = read_chain(bufmgr, first)?;
+            if chain.iter().any(|(_, bucket)| bucket.pairs.iter().any(|(k, v)| k.as_slice() == key && v.as_slice() == value)) {
+                return Err(Error::DuplicateKey);
+            }
+            // 空きのあるページがあればそこに入れる
+            let pair_size = PAIR_HEADER_SIZE + key.len() + value.len();
+            for (page_id, mut bucket) in chain.iter().map(|(page_id, bucket)| (*page_id, bucket.clone())) {
+                if Bucket::size(&bucket.pairs) + pair_size <= BODY_SIZE {
+                    bucket.pairs.push((key.to_vec(), value.to_vec()));
+                    return write_bucket(bufmgr, log, page_id, &bucket);
+                }
+            }
+            let depth = chain[0].1.depth;
+            let splittable = depth < MAX_DEPTH
+                && chain
+                    .iter()
+                    .flat_map(|(_, bucket)| &bucket.pairs)
+                    .any(|(k, _)| low_bits(hash(k), MAX_DEPTH) != low_bits(key_hash, MAX_DEPTH));
+            if !splittable {
+                // ビットで分けられないので、後ろにページを繋げる
+                let (last_page_id, last) = chain.last().unwrap();
+                let page_id = bufmgr.create_page()?.page_id;
+                let bucket = Bucket {
+                    depth,
+                    next: PageId::INVALID_PAGE_ID,
+                    pairs: vec![(key.to_vec(), value.to_vec())],
+                };
+                write_bucket(bufmgr, log, page_id, &bucket)?;
+                let last = Bucket {
+                    next: page_id,
+                    ..last.clone()
+                };
+                return write_bucket(bufmgr, log, *last_page_id, &last);
+            }
+            self.split(bufmgr, log, first, chain)?;
+        }
+    }
+
+    // バケットを2つに分け、下位depthビット目が1の組を新しいバケットに移す
+    fn split(&self, bufmgr: &mut BufferPoolManager, log: &TxnLog, first: PageId, chain: Vec<(PageId, Bucket)>) -> Result<(), Error> {
+        let depth = chain[0].1.depth;
+        let old = self.read_directory(bufmgr)?;
+        let mut dir = old.clone();
+        if depth == dir.depth {
+            // ディレクトリを2倍にする。増えた要素は下位ビットが同じ要素と同じバケットを指す
+            dir.buckets.extend_from_within(..);
+            dir.depth += 1;
+        }
+        let pages: Vec<PageId> = chain.iter().map(|(page_id, _)| *page_id).collect();
+        let (moved, stay): (Vec<Pair>, Vec<Pair>) =
+            chain.into_iter().flat_map(|(_, bucket)| bucket.pairs).partition(|(k, _)| hash(k) >> depth & 1 == 1);
+        write_chain(bufmgr, log, pages, depth + 1, stay)?;
+        let new_page_id = bufmgr.create_page()?.page_id;
+        write_chain(bufmgr, log, vec![new_page_id], depth + 1, moved)?;
+        for (i, bucket) in dir.buckets.iter_mut().enumerate() {
+            if *bucket == first && i >> depth & 1 == 1 {
+                *bucket = new_page_id;
+            }
+        }
+        self.write_directory(bufmgr, log, &old, &mut dir)
+    }
+
+    fn delete_raw(&self, bufmgr: &mut BufferPoolManager, log: &TxnLog, key: &[u8], value: &[u8]) -> Result<bool, Error> {
+        let first = self.bucket_of(bufmgr, key)?;
+        for (page_id, mut bucket) in read_chain(bufmgr, first)? {
+            if let Some(pos) = bucket.pairs.iter().position(|(k, v)| k.as_slice() == key && v.as_slice() == value) {
+                bucket.pairs.remove(pos);
+                write_bucket(bufmgr, log, page_id, &bucket)?;
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+}
+
+// 取り消し方法: | UNDO_HASH | 種類 | ディレクトリのページID(u64) | キーの長さ(u16) | キー | 値 |
+fn undo_record(kind: u8, directory_page_id: PageId, key: &[u8], value: &[u8]) -> Vec<u8> {
+    let mut undo = vec![UNDO_HASH, kind];
+    undo.extend_from_slice(&directory_page_id.0.to_le_bytes());
+    undo.extend_from_slice(&(key.len() as u16).to_le_bytes());
+    undo.extend_from_slice(key);
+    undo.extend_from_slice(value);
+    undo
+}
+
+/// ハッシュインデックスへの操作を取り消す(UndoDispatcherから呼ばれる)
+/// キーで探し直すので、他のトランザクションの操作でバケットが分割されていても取り消せる
+pub(crate) fn undo(bufmgr: &mut BufferPoolManager, log: &TxnLog, undo: &[u8]) -> Result<(), Error> {
+    let kind = undo[0];
+    let index = HashIndex::open(PageId(read_u64(&undo[1..9])));
+    let key_len = u16::from_le_bytes([undo[9], undo[10]]) as usize;
+    let key = &undo[11..11 + key_len];
+    let value = &undo[11 + key_len..];
+    match kind {
+        UNDO_INSERT => {
+            index.delete_raw(bufmgr, log, key, value)?;
+        }
+        UNDO_DELETE => index.insert_raw(bufmgr, log, key, value)?,
+        _ => unreachable!(),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction::{self, TransactionManager};
+
+    fn open(name: &str) -> (BufferPoolManager, TransactionManager) {
+        let path = std::env::temp_dir().join(format!("hash_index_test_{}_{}", std::process::id(), name));
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(crate::wal::log_path(&path));
+        let (bufmgr, txn_mgr, _) = transaction::open(&path, 16).unwrap();
+        (bufmgr, txn_mgr)
+    }
+
+    #[test]
+    fn test_split_and_doubling() {
+        let (mut bufmgr, txn_mgr) = open("split");
+        let txn = txn_mgr.begin().unwrap();
+        let index = HashIndex::create(&mut bufmgr, &txn).unwrap();
+        for i in 0..2000u32 {
+            index.insert(&mut bufmgr, &txn, &i.to_be_bytes(), &[i as u8; 20]).unwrap();
+        }
+        // 1バケットに入りきらないので、分割してディレクトリが大きくなる
+        assert!(index.global_depth(&mut bufmgr).unwrap() >= 4);
+        assert_eq!(index.search(&mut bufmgr, &1234u32.to_be_bytes()).unwrap(), vec![vec![1234u32 as u8; 20]]);
+        assert!(index.search(&mut bufmgr, &5000u32.to_be_bytes()).unwrap().is_empty());
+        assert!(matches!(index.insert(&mut bufmgr, &txn, &7u32.to_be_bytes(), &[7u8; 20]), Err(Error::DuplicateKey)));
+
+        // 同じキーの値はビットで分けられないので、ページを繋げて入れる
+        for i in 0..500u32 {
+            index.insert(&mut bufmgr, &txn, b"same", &i.to_be_bytes()).unwrap();
+        }
+        assert_eq!(index.search(&mut bufmgr, b"same").unwrap().len(), 500);
+        // 複数の要素から指されるバケットも1回だけ数える
+        assert_eq!(index.entries(&mut bufmgr).unwrap().len(), 2500);
+        txn_mgr.commit(txn).unwrap();
+
+        // ロールバックすると追加も削除も取り消される
+        let txn = txn_mgr.begin().unwrap();
+        index.delete(&mut bufmgr, &txn, &1234u32.to_be_bytes(), &[1234u32 as u8; 20]).unwrap();
+        assert!(matches!(index.delete(&mut bufmgr, &txn, b"same", b"none"), Err(Error::NotFound)));
+        for i in 2000..2500u32 {
+            index.insert(&mut bufmgr, &txn, &i.to_be_bytes(), &[0u8; 20]).unwrap();
+        }
+        assert!(index.search(&mut bufmgr, &1234u32.to_be_bytes()).unwrap().is_empty());
+        txn_mgr.rollback(&mut bufmgr, txn).unwrap();
+        let txn = txn_mgr.begin().unwrap();
+        assert_eq!(index.search(&mut bufmgr, &1234u32.to_be_bytes()).unwrap().len(), 1);
+        assert!(index.search(&mut bufmgr, &2100u32.to_be_bytes()).unwrap().is_empty());
+        for i in 0..2000u32 {
+            assert_eq!(index.search(&mut bufmgr, &i.to_be_bytes()).unwrap().len(), 1, "{}", i);
+        }
+        txn_mgr.commit(txn).unwrap();
+    }
+
+    #[test]
+    fn test_multi_page_directory() {
+        let (mut bufmgr, txn_mgr) = open("directory");
+        let txn = txn_mgr.begin().unwrap();
+        let index = HashIndex::create(&mut bufmgr, &txn).unwrap();
+        // 1バケットに4組しか入らない大きな値で、ディレクトリを1つのセグメントより大きくする
+        let value = |i: u32| vec![i as u8; MAX_PAIR_SIZE - 4];
+        for i in 0..3000u32 {
+            index.insert(&mut bufmgr, &txn, &i.to_be_bytes(), &value(i)).unwrap();
+        }
+        let depth = index.global_depth(&mut bufmgr).unwrap();
+        assert!(1usize << depth > SEGMENT_ENTRIES, "depth {}", depth);
+        let dir = index.read_directory(&mut bufmgr).unwrap();
+        assert_eq!(dir.segments.len(), segment_count(depth));
+        assert_eq!(dir.buckets.len(), 1 << depth);
+        // 違うキーはビットで分けられるので、ページを繋げたバケットは無い
+        for &bucket in &dir.buckets {
+            assert_eq!(read_chain(&mut bufmgr, bucket).unwrap().len(), 1);
+        }
+        for i in 0..3000u32 {
+            assert_eq!(index.search(&mut bufmgr, &i.to_be_bytes()).unwrap(), vec![value(i)], "{}", i);
+        }
+        assert_eq!(index.entries(&mut bufmgr).unwrap().len(), 3000);
+        txn_mgr.commit(txn).unwrap();
+    }
+
+    #[test]
+    fn test_delete() {
+        let (mut bufmgr, txn_mgr) = open("delete");
+        let txn = txn_mgr.begin().unwrap();
+        let index = HashIndex::create(&mut bufmgr, &txn).unwrap();
+        for i in 0..1000u32 {
+            index.insert(&mut bufmgr, &txn, &i.to_be_bytes(), b"v").unwrap();
+        }
+        // ページを繋げたバケットの途中の組も消せる
+        for i in 0..300u32 {
+            index.insert(&mut bufmgr, &txn, b"same", &i.to_be_bytes()).unwrap();
+        }
+        for i in (0..1000u32).step_by(2) {
+            index.delete(&mut bufmgr, &txn, &i.to_be_bytes(), b"v").unwrap();
+        }
+        for i in 100..200u32 {
+            index.delete(&mut bufmgr, &txn, b"same", &i.to_be_bytes()).unwrap();
+        }
+        assert!(matches!(index.delete(&mut bufmgr, &txn, &0u32.to_be_bytes(), b"v"), Err(Error::NotFound)));
+        txn_mgr.commit(txn).unwrap();
+
+        let txn = txn_mgr.begin().unwrap();
+        for i in 0..1000u32 {
+            let expected = if i % 2 == 0 { vec![] } else { vec![b"v".to_vec()] };
+            assert_eq!(index.search(&mut bufmgr, &i.to_be_bytes()).unwrap(), expected, "{}", i);
+        }
+        let mut same = index.search(&mut bufmgr, b"same").unwrap();
+        same.sort();
+        let expected: Vec<Vec<u8>> = (0..100u32).chain(200..300).map(|i| i.to_be_bytes().to_vec()).collect();
+        assert_eq!(same, expected);
+        assert_eq!(index.entries(&mut bufmgr).unwrap().len(), 700);
+        // 消した組はもう一度入れられる
+        index.insert(&mut bufmgr, &txn, &0u32.to_be_bytes(), b"v").unwrap();
+        assert_eq!(index.search(&mut bufmgr, &0u32.to_be_bytes()).unwrap(), vec![b"v".to_vec()]);
+        txn_mgr.commit(txn).unwrap();
+    }
+}
@@ -0,0 +1,1178 @@
+// ヒープテーブル
+// レコードを順番を気にせずにページへ詰め込むテーブル
+// ページは単方向リストで繋がっていて、各ページの中はスロット付きページになっている
+//
+// ページの中身: | 次のページID(u64) | スロット付きページ |
+//
+// レコードはRecordId(ページID, スロット番号)で指す
+//
+// MVCC(多版型同時実行制御)
+// スロットには| タプルヘッダー | データ |を入れる
+// ヘッダーにはその版を書いたトランザクション(xmin)と、消した・上書きしたトランザクション(xmax)を持つ
+// RecordIdの場所には常に最新版を置き、古い版は別のスロットにコピーして新しい順に繋げる(linkで1つ前の版を指す)
+// こうするとRecordIdは書き換えても変わらないので、インデックスを書き換えなくて済む
+// 読む側はスナップショットから見える版まで遡るだけなので、書き込み中のトランザクションを待たない
+//
+// 書き換えた最新版がページに入らない場合は、別のページに移してRecordIdの場所には移動先だけを残す
+//
+// overflow::THRESHOLDより大きなデータはオーバーフローページに置き、タプルにはポインタだけを入れる
+// オーバーフローページはそれを指すタプルと一緒に、ガベージコレクションやINSERTの取り消しで解放する
+// 古い版のコピーは最新版と同じページを指すことがあるので、コピーの取り消しでは解放しない
+//
+// 追加するページは空き領域マップ(fsm)で探す。どのページにも入らなければ、マップの最後のページの後ろに繋げる
+// VACUUMではgcで回収した後にcompactでページを詰め直し、空になったページをリストから外して解放する
+
+use std::rc::Rc;
+
+use crate::buffer_pool::{Buffer, BufferPoolManager, Error, PAGE_LSN_SIZE};
+use crate::disk_manager::{PageId, PAGE_SIZE};
+use crate::fsm::FreeSpaceMap;
+use crate::lock::LockMode;
+use crate::overflow::{self, Pointer};
+use crate::slotted::{self, Slotted};
+use crate::transaction::{Transaction, UNDO_HEAP};
+use crate::wal::{TxnId, TxnLog};
+
+const NEXT_PAGE_ID_SIZE: usize = 8;
+
+// 取り消し方法の種類
+const UNDO_INSERT: u8 = 1;
+const UNDO_UPDATE: u8 = 2;
+// 取り消さない(ガベージコレクションで回収した版は元に戻す必要が無い)
+const UNDO_NONE: u8 = 3;
+// ページの先頭から続くスロットに、まとめて追加したレコードを取り消す
+const UNDO_BULK: u8 = 4;
+
+// タプルの種類
+// RecordIdの場所にある最新版
+const TUPLE_HOME: u8 = 0;
+// 最新版を別の場所に移した跡(linkが移動先)
+const TUPLE_FORWARD: u8 = 1;
+// 別の場所に移した最新版
+const TUPLE_MOVED: u8 = 2;
+// 古い版
+const TUPLE_VERSION: u8 = 3;
+// もう使わない移動先。xmaxのトランザクションが全員から見えるようになれば回収できる
+const TUPLE_DEAD: u8 = 4;
+// 種類に足して、データがオーバーフローページへのポインタであることを表す
+const TUPLE_OVERFLOW: u8 = 0x80;
+
+const TUPLE_HEADER_SIZE: usize = 27;
+
+/// レコードの場所
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct RecordId {
+    pub page_id: PageId,
+    pub slot: u16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeapTable {
+    pub first_page_id: PageId,
+    pub fsm: FreeSpaceMap,
+}
+
+/// ガベージコレクションの結果
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct GcReport {
+    /// 削除が全員から見えるようになったので回収したレコード
+    /// インデックスに残っているエントリは呼び出し側で取り除く
+    pub removed: Vec<RecordId>,
+    /// 回収した古い版の数
+    pub versions: usize,
+}
+
+// タプルヘッダー: | 種類(u8) | xmin(u64) | xmax(u64) | linkのページID(u64) | linkのスロット(u16) |
+// トランザクションIDは1から振るので、xmaxの0は「消されていない」を表す
+#[derive(Debug, Clone, Copy)]
+struct TupleHeader {
+    kind: u8,
+    xmin: TxnId,
+    xmax: Option<TxnId>,
+    link: Option<RecordId>,
+    // データがオーバーフローページへのポインタかどうか
+    overflow: bool,
+}
+
+impl TupleHeader {
+    fn new(kind: u8, xmin: TxnId, link: Option<RecordId>) -> Self {
+        Self { kind, xmin, xmax: None, link, overflow: false }
+    }
+
+    fn encode(&self, data: &[u8]) -> Vec<u8> {
+        let link = self.link.unwrap_or(RecordId {
+            page_id: PageId::INVALID_PAGE_ID,
+            slot: 0,
+        });
+        let mut bytes = Vec::with_capacity(TUPLE_HEADER_SIZE + data.len());
+        bytes.push(if self.overflow { self.kind | TUPLE_OVERFLOW } else { self.kind });
+        bytes.extend_from_slice(&self.xmin.0.to_le_bytes());
+        bytes.extend_from_slice(&self.xmax.map_or(0, |xmax| xmax.0).to_le_bytes());
+        bytes.extend_from_slice(&link.page_id.0.to_le_bytes());
+        bytes.extend_from_slice(&link.slot.to_le_bytes());
+        bytes.extend_from_slice(data);
+        bytes
+    }
+
+    fn decode(bytes: &[u8]) -> (Self, &[u8]) {
+        let read_u64 = |offset: usize| {
+            let mut n = [0u8; 8];
+            n.copy_from_slice(&bytes[offset..offset + 8]);
+            u64::from_le_bytes(n)
+        };
+        let xmax = read_u64(9);
+        let link = PageId(read_u64(17)).valid().map(|page_id| RecordId {
+            page_id,
+            slot: u16::from_le_bytes([bytes[25], bytes[26]]),
+        });
+        let header = Self {
+            kind: bytes[0] & !TUPLE_OVERFLOW,
+            xmin: TxnId(read_u64(1)),
+            xmax: if xmax == 0 { None } else { Some(TxnId(xmax)) },
+            link,
+            overflow: bytes[0] & TUPLE_OVERFLOW != 0,
+        };
+        (header, &bytes[TUPLE_HEADER_SIZE..])
+    }
+}
+
+// ページの中身から次のページIDを読む
+fn next_page_id(body: &[u8]) -> PageId {
+    let mut bytes = [0u8; NEXT_PAGE_ID_SIZE];
+    bytes.copy_from_slice(&body[..NEXT_PAGE_ID_SIZE]);
+    PageId(u64::from_le_bytes(bytes))
+}
+
+fn set_next_page_id(body: &mut [u8], page_id: PageId) {
+    body[..NEXT_PAGE_ID_SIZE].copy_from_slice(&page_id.0.to_le_bytes());
+}
+
+// ページの中身から、詰め直せば使える空き領域のサイズを求める
+fn free_space(body: &[u8]) -> usize {
+    Slotted::new(&body[NEXT_PAGE_ID_SIZE..]).reclaimable_space()
+}
+
+// 空のヒープページを作る
+fn create_page(bufmgr: &mut BufferPoolManager, log: &TxnLog) -> Result<Rc<Buffer>, Error> {
+    let buffer = bufmgr.create_page()?;
+    log.modify_page(&buffer, |body| set_next_page_id(body, PageId::INVALID_PAGE_ID))?;
+    Ok(buffer)
+}
+
+// ページの次のページIDと空き領域のサイズを読む
+fn read_page_info(bufmgr: &mut BufferPoolManager, page_id: PageId) -> Result<(PageId, usize), Error> {
+    let buffer = bufmgr.fetch_page(page_id)?;
+    let page = buffer.page.borrow();
+    let body = &page[PAGE_LSN_SIZE..];
+    Ok((next_page_id(body), free_space(body)))
+}
+
+impl HeapTable {
+    /// 空のテーブルを作る
+    pub fn create(bufmgr: &mut BufferPoolManager, txn: &Transaction) -> Result<Self, Error> {
+        let log = txn.log();
+        let first_page_id = create_page(bufmgr, log)?.page_id;
+        let fsm = FreeSpaceMap::create(bufmgr, log)?;
+        let (_, free) = read_page_info(bufmgr, first_page_id)?;
+        fsm.set(bufmgr, log, first_page_id, free)?;
+        Ok(Self { first_page_id, fsm })
+    }
+
+    pub fn open(first_page_id: PageId, fsm_page_id: PageId) -> Self {
+        Self {
+            first_page_id,
+            fsm: FreeSpaceMap::open(fsm_page_id),
+        }
+    }
+
+    /// レコードを追加する
+    /// 空き領域マップで空きのあるページを探し、無ければ末尾にページを追加する
+    pub fn insert(&self, bufmgr: &mut BufferPoolManager, txn: &Transaction, data: &[u8]) -> Result<RecordId, Error> {
+        let mut header = TupleHeader::new(TUPLE_HOME, txn.id(), None);
+        let data = store(bufmgr, txn.log(), &mut header, data)?;
+        let rid = insert_tuple(bufmgr, txn.log(), self, &header.encode(&data))?;
+        txn.lock_record(self.first_page_id, rid, LockMode::Exclusive)?;
+        Ok(rid)
+    }
+
+    /// readerから読んだデータをレコードとして追加する
+    /// データは大きさに関わらずオーバーフローページに置くので、全体をメモリに載せずに済む
+    pub fn insert_from(&self, bufmgr: &mut BufferPoolManager, txn: &Transaction, reader: &mut impl std::io::Read) -> Result<RecordId, Error> {
+        let pointer = write_overflow(bufmgr, txn.log(), reader)?;
+        let header = TupleHeader {
+            overflow: true,
+            ..TupleHeader::new(TUPLE_HOME, txn.id(), None)
+        };
+        let rid = insert_tuple(bufmgr, txn.log(), self, &header.encode(&pointer.encode()))?;
+        txn.lock_record(self.first_page_id, rid, LockMode::Exclusive)?;
+        Ok(rid)
+    }
+
+    /// レコードをまとめて追加するローダーを作る
+    pub fn loader(&self) -> HeapLoader {
+        HeapLoader {
+            table: self.first_page_id,
+            last_page_id: self.first_page_id,
+            fsm: self.fsm,
+            page: None,
+        }
+    }
+
+    /// txnのスナップショットから見える版を取得する。見えなければ(削除済みも含む)None
+    pub fn get(&self, bufmgr: &mut BufferPoolManager, txn: &Transaction, rid: RecordId) -> Result<Option<Vec<u8>>, Error> {
+        match self.get_reader(bufmgr, txn, rid)? {
+            Some(reader) => Ok(Some(reader.read_to_end(bufmgr)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// getと同じ版を、ページ1つ分ずつ読むリーダーを返す
+    /// とても大きなレコードを全てメモリに載せずに読むのに使う
+    pub fn get_reader(&self, bufmgr: &mut BufferPoolManager, txn: &Transaction, rid: RecordId) -> Result<Option<overflow::Reader>, Error> {
+        txn.lock_record(self.first_page_id, rid, LockMode::Shared)?;
+        match locate(bufmgr, rid)? {
+            Some((_, header, data)) => visible_version(bufmgr, txn, header, data),
+            None => Ok(None),
+        }
+    }
+
+    /// レコードを削除する
+    /// 最新版にxmaxを付けるだけで、領域はガベージコレクションで回収する
+    pub fn delete(&self, bufmgr: &mut BufferPoolManager, txn: &Transaction, rid: RecordId) -> Result<(), Error> {
+        txn.lock_record(self.first_page_id, rid, LockMode::Exclusive)?;
+        let (loc, mut header, data) = locate(bufmgr, rid)?.ok_or(Error::NotFound)?;
+        check_writable(bufmgr, txn, &header, &data)?;
+        header.xmax = Some(txn.id());
+        // 長さは変わらないので、必ずその場で書き換えられる
+        assert!(write_tuple(bufmgr, txn.log(), loc, &header.encode(&data), true)?);
+        Ok(())
+    }
+
+    /// レコードを書き換える
+    /// 元の版は古い版としてコピーして残すので、RecordIdは変わらない
+    pub fn update(&self, bufmgr: &mut BufferPoolManager, txn: &Transaction, rid: RecordId, data: &[u8]) -> Result<(), Error> {
+        txn.lock_record(self.first_page_id, rid, LockMode::Exclusive)?;
+        let (loc, header, old) = locate(bufmgr, rid)?.ok_or(Error::NotFound)?;
+        check_writable(bufmgr, txn, &header, &old)?;
+        let log = txn.log();
+        // 元の版のオーバーフローページはコピーした古い版が引き継ぐ
+        let version = TupleHeader {
+            kind: TUPLE_VERSION,
+            xmax: Some(txn.id()),
+            ..header
+        };
+        let prev = insert_tuple(bufmgr, log, self, &version.encode(&old))?;
+        let mut latest = TupleHeader::new(header.kind, txn.id(), Some(prev));
+        let data = &store(bufmgr, log, &mut latest, data)?;
+        if write_tuple(bufmgr, log, loc, &latest.encode(data), true)? {
+            return Ok(());
+        }
+        // 元のページに入らないので、最新版を別の場所に移す
+        let latest = TupleHeader { kind: TUPLE_MOVED, ..latest };
+        let moved = insert_tuple(bufmgr, log, self, &latest.encode(data))?;
+        // 移動先の跡はヘッダーだけなので、必ずその場で書き換えられる
+        let forward = TupleHeader::new(TUPLE_FORWARD, txn.id(), Some(moved));
+        assert!(write_tuple(bufmgr, log, rid, &forward.encode(&[]), true)?);
+        if loc != rid {
+            // 前の移動先はロールバックに備えて、コミットが全員から見えるようになるまで残しておく
+            let dead = TupleHeader {
+                xmax: Some(txn.id()),
+                ..TupleHeader::new(TUPLE_DEAD, txn.id(), None)
+            };
+            assert!(write_tuple(bufmgr, log, loc, &dead.encode(&[]), true)?);
+        }
+        Ok(())
+    }
+
+    /// 先頭から全てのレコードを読むイテレータ
+    /// ロックマネージャーを使う場合は、テーブル全体にSロックを掛けて読む
+    pub fn scan(&self) -> HeapIter {
+        HeapIter {
+            table: self.first_page_id,
+            page_id: self.first_page_id,
+            slot: 0,
+        }
+    }
+
+    /// テーブルのページ数(ページの連結リストをたどって数える)
+    pub fn num_pages(&self, bufmgr: &mut BufferPoolManager) -> Result<u64, Error> {
+        let mut count = 0;
+        let mut page_id = self.first_page_id;
+        while page_id.valid().is_some() {
+            let buffer = bufmgr.fetch_page(page_id)?;
+            let page = buffer.page.borrow();
+            page_id = next_page_id(&page[PAGE_LSN_SIZE..]);
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// どのスナップショットからも見えなくなった版を回収し、空き領域マップを作り直す
+    /// horizonより小さいIDのトランザクションの変更は全員から見えるものとして扱う(TransactionManager::horizon())
+    /// 回収は取り消さないので、txnがロールバックしても回収した版は戻らない
+    pub fn gc(&self, bufmgr: &mut BufferPoolManager, txn: &Transaction, horizon: TxnId) -> Result<GcReport, Error> {
+        txn.lock_table(self.first_page_id, LockMode::Exclusive)?;
+        let log = txn.log();
+        let mut report = GcReport::default();
+        let mut pages = vec![];
+        let mut page_id = self.first_page_id;
+        while page_id.valid().is_some() {
+            pages.push(page_id);
+            // ページを借用したまま他のページは読めないので、先にヘッダーを集めておく
+            let (tuples, next) = {
+                let buffer = bufmgr.fetch_page(page_id)?;
+                let page = buffer.page.borrow();
+                let slotted = Slotted::new(body(&page[..]));
+                let tuples: Vec<(u16, TupleHeader)> = slotted.iter().map(|(slot, bytes)| (slot, TupleHeader::decode(bytes).0)).collect();
+                (tuples, next_page_id(&page[PAGE_LSN_SIZE..]))
+            };
+            for (slot, header) in tuples {
+                let rid = RecordId { page_id, slot };
+                match header.kind {
+                    TUPLE_DEAD if matches!(header.xmax, Some(xmax) if xmax < horizon) => purge_tuple(bufmgr, log, rid)?,
+                    TUPLE_HOME | TUPLE_FORWARD => {
+                        let (loc, header, data) = locate(bufmgr, rid)?.ok_or(Error::NotFound)?;
+                        if matches!(header.xmax, Some(xmax) if xmax < horizon) {
+                            // 削除が全員から見えるので、レコードごと回収する
+                            report.versions += purge_chain(bufmgr, log, header.link)?;
+                            if loc != rid {
+                                purge_tuple(bufmgr, log, loc)?;
+                            }
+                            purge_tuple(bufmgr, log, rid)?;
+                            report.removed.push(rid);
+                        } else {
+                            report.versions += prune(bufmgr, log, horizon, loc, header, data)?;
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            page_id = next;
+        }
+        // 回収して空いた領域を空き領域マップに記録する
+        let start = log.last_lsn();
+        let entries = pages
+            .into_iter()
+            .map(|page_id| Ok((page_id, read_page_info(bufmgr, page_id)?.1)))
+            .collect::<Result<Vec<_>, Error>>()?;
+        self.fsm.rebuild(bufmgr, log, &entries)?;
+        log.log_operation(start, undo_record(UNDO_NONE, RecordId { page_id: self.first_page_id, slot: 0 }, &[]))?;
+        Ok(report)
+    }
+
+    /// RecordIdの全ての版(最新版と、まだ回収していない古い版)のデータを新しい順に返す
+    /// 回収済みなら空。インデックスのエントリがまだ要るかどうかを確かめるのに使う
+    pub fn versions(&self, bufmgr: &mut BufferPoolManager, rid: RecordId) -> Result<Vec<Vec<u8>>, Error> {
+        let mut versions = vec![];
+        let (mut header, mut data) = match locate(bufmgr, rid)? {
+            Some((_, header, data)) => (header, data),
+            None => return Ok(versions),
+        };
+        loop {
+            let link = header.link;
+            versions.push(reader(&header, data).read_to_end(bufmgr)?);
+            match link {
+                Some(prev) => {
+                    let (prev_header, prev_data) = read_tuple(bufmgr, prev)?.ok_or(Error::NotFound)?;
+                    header = prev_header;
+                    data = prev_data;
+                }
+                None => return Ok(versions),
+            }
+        }
+    }
+
+    /// ページを詰め直して空き領域マップを作り直し、空になったページ(先頭のページを除く)を解放する
+    /// 解放したページがファイルの末尾にあれば、ファイルを切り詰める。解放したページ数を返す
+    ///
+    /// 不要な版はgcで回収しておき、解放するページのRecordIdを指すインデックスのエントリも先に取り除いておく
+    /// スロット番号は変わらないので、実行中のトランザクションの取り消しには影響しない
+    /// ただし解放したページを読んでいる途中のスキャンがあってはいけない
+    /// (ロックマネージャーを使う場合は、テーブル全体のXロックでスキャンが終わるのを待つ)
+    pub fn compact(&self, bufmgr: &mut BufferPoolManager, txn: &Transaction) -> Result<u64, Error> {
+        txn.lock_table(self.first_page_id, LockMode::Exclusive)?;
+        let log = txn.log();
+        let none = |page_id| undo_record(UNDO_NONE, RecordId { page_id, slot: 0 }, &[]);
+        // (ページID, 空になったか, 空き領域のサイズ)
+        let mut pages = vec![];
+        let mut page_id = self.first_page_id;
+        while let Some(current) = page_id.valid() {
+            let start = log.last_lsn();
+            let buffer = bufmgr.fetch_page(current)?;
+            let (next, empty, free) = log.modify_page(&buffer, |body| {
+                let next = next_page_id(body);
+                let mut slotted = Slotted::new(&mut body[NEXT_PAGE_ID_SIZE..]);
+                slotted.truncate_slots();
+                slotted.compact();
+                (next, slotted.num_slots() == 0, slotted.reclaimable_space())
+            })?;
+            drop(buffer);
+            log.log_operation(start, none(current))?;
+            pages.push((current, empty && current != self.first_page_id, free));
+            page_id = next;
+        }
+
+        // 先にマップから外しておけば、途中でクラッシュしても解放したページにレコードを追加することはない
+        let start = log.last_lsn();
+        let entries: Vec<(PageId, usize)> = pages.iter().filter(|(_, empty, _)| !empty).map(|&(page_id, _, free)| (page_id, free)).collect();
+        self.fsm.rebuild(bufmgr, log, &entries)?;
+        log.log_operation(start, none(self.first_page_id))?;
+
+        let mut freed = 0;
+        let mut prev = self.first_page_id;
+        for &(page_id, empty, _) in &pages[1..] {
+            if !empty {
+                prev = page_id;
+                continue;
+            }
+            let start = log.last_lsn();
+            let buffer = bufmgr.fetch_page(page_id)?;
+            let next = log.modify_page(&buffer, |body| {
+                let next = next_page_id(body);
+                body.fill(0);
+                next
+            })?;
+            drop(buffer);
+            let prev_buffer = bufmgr.fetch_page(prev)?;
+            log.modify_page(&prev_buffer, |body| set_next_page_id(body, next))?;
+            drop(prev_buffer);
+            log.log_operation(start, none(page_id))?;
+            log.free_page(bufmgr, page_id)?;
+            freed += 1;
+        }
+        log.truncate_file(bufmgr)?;
+        Ok(freed)
+    }
+}
+
+// ページ全体からヒープページのスロット付きページ部分を取り出す
+fn body(page: &[u8]) -> &[u8] {
+    &page[PAGE_LSN_SIZE + NEXT_PAGE_ID_SIZE..]
+}
+
+// スロットのタプルを読む
+fn read_tuple(bufmgr: &mut BufferPoolManager, rid: RecordId) -> Result<Option<(TupleHeader, Vec<u8>)>, Error> {
+    let buffer = bufmgr.fetch_page(rid.page_id)?;
+    let page = buffer.page.borrow();
+    let slotted = Slotted::new(body(&page[..]));
+    Ok(slotted.get(rid.slot).map(|bytes| {
+        let (header, data) = TupleHeader::decode(bytes);
+        (header, data.to_vec())
+    }))
+}
+
+// RecordIdの最新版の場所とタプルを返す。別の場所に移っていれば移動先を読む
+fn locate(bufmgr: &mut BufferPoolManager, rid: RecordId) -> Result<Option<(RecordId, TupleHeader, Vec<u8>)>, Error> {
+    match read_tuple(bufmgr, rid)? {
+        Some((header, data)) if header.kind == TUPLE_HOME => Ok(Some((rid, header, data))),
+        Some((header, _)) if header.kind == TUPLE_FORWARD => {
+            let loc = header.link.ok_or(Error::NotFound)?;
+            let (header, data) = read_tuple(bufmgr, loc)?.ok_or(Error::NotFound)?;
+            Ok(Some((loc, header, data)))
+        }
+        // 古い版や移動先はRecordIdとしては指せない
+        _ => Ok(None),
+    }
+}
+
+// データが大きければオーバーフローページに書き、タプルに入れるデータ(ポインタ)を返す
+fn store(bufmgr: &mut BufferPoolManager, log: &TxnLog, header: &mut TupleHeader, data: &[u8]) -> Result<Vec<u8>, Error> {
+    if data.len() <= overflow::THRESHOLD {
+        return Ok(data.to_vec());
+    }
+    header.overflow = true;
+    Ok(write_overflow(bufmgr, log, &mut &data[..])?.encode())
+}
+
+// オーバーフローページへの書き込みを、取り消さない操作としてWALに書く
+// ページはタプルの操作を取り消す時に解放するので、ページの変更そのものは戻さなくて良い
+// (戻すと、解放した後のページに前の中身を書いてしまう)
+fn write_overflow(bufmgr: &mut BufferPoolManager, log: &TxnLog, reader: &mut impl std::io::Read) -> Result<Pointer, Error> {
+    let start = log.last_lsn();
+    let pointer = overflow::write_from(bufmgr, log, reader)?;
+    log.log_operation(start, vec![UNDO_HEAP, UNDO_NONE])?;
+    Ok(pointer)
+}
+
+// タプルのデータを読むリーダー
+fn reader(header: &TupleHeader, data: Vec<u8>) -> overflow::Reader {
+    if header.overflow {
+        overflow::Reader::new(Pointer::decode(&data))
+    } else {
+        overflow::Reader::inline(data)
+    }
+}
+
+// タプルが指しているオーバーフローページ
+fn pointer(bytes: &[u8]) -> Option<Pointer> {
+    let (header, data) = TupleHeader::decode(bytes);
+    if header.overflow {
+        Some(Pointer::decode(data))
+    } else {
+        None
+    }
+}
+
+// 最新版から古い版へ遡り、txnのスナップショットから見える版のデータを読むリーダーを返す
+fn visible_version(
+    bufmgr: &mut BufferPoolManager,
+    txn: &Transaction,
+    mut header: TupleHeader,
+    mut data: Vec<u8>,
+) -> Result<Option<overflow::Reader>, Error> {
+    let snapshot = txn.snapshot();
+    loop {
+        if snapshot.sees(header.xmin) {
+            // 消したトランザクションが見えなければ、まだ残っている
+            return Ok(match header.xmax {
+                Some(xmax) if snapshot.sees(xmax) => None,
+                _ => Some(reader(&header, data)),
+            });
+        }
+        match header.link {
+            Some(prev) => {
+                let (prev_header, prev_data) = read_tuple(bufmgr, prev)?.ok_or(Error::NotFound)?;
+                header = prev_header;
+                data = prev_data;
+            }
+            // 自分のスナップショットより後に挿入された
+            None => return Ok(None),
+        }
+    }
+}
+
+// 最新版を書き換えて良いか確認する
+// 最新版を書いた・消したトランザクションが見えなければ、他のトランザクションに先を越されているので衝突
+// (実行中でも、自分より後にコミットしていても同じ。待たずに先に書いた方を勝ちとする)
+fn check_writable(bufmgr: &mut BufferPoolManager, txn: &Transaction, header: &TupleHeader, data: &[u8]) -> Result<(), Error> {
+    let snapshot = txn.snapshot();
+    if !snapshot.sees(header.xmin) {
+        // 自分からはまだ存在しないレコードなら衝突ではない
+        return match visible_version(bufmgr, txn, *header, data.to_vec())? {
+            Some(_) => Err(Error::Conflict),
+            None => Err(Error::NotFound),
+        };
+    }
+    match header.xmax {
+        None => Ok(()),
+        Some(xmax) if snapshot.sees(xmax) => Err(Error::NotFound),
+        Some(_) => Err(Error::Conflict),
+    }
+}
+
+// 空き領域マップで入りそうなページを探して追加する。どのページにも入らなければ末尾にページを繋げる
+fn insert_raw(bufmgr: &mut BufferPoolManager, log: &TxnLog, heap: &HeapTable, data: &[u8]) -> Result<RecordId, Error> {
+    // オーバーフローページに置かないデータは必ず入る
+    debug_assert!(data.len() <= slotted::max_record_size(PAGE_SIZE - PAGE_LSN_SIZE - NEXT_PAGE_ID_SIZE));
+    let required = slotted::required_space(data.len());
+    loop {
+        let page_id = match heap.fsm.find(bufmgr, required)? {
+            Some(page_id) => page_id,
+            None => append_page(bufmgr, log, heap)?,
+        };
+        let buffer = bufmgr.fetch_page(page_id)?;
+        let (slot, free) = log.modify_page(&buffer, |body| {
+            let mut slotted = Slotted::new(&mut body[NEXT_PAGE_ID_SIZE..]);
+            (slotted.insert(data), slotted.reclaimable_space())
+        })?;
+        drop(buffer);
+        heap.fsm.set(bufmgr, log, page_id, free)?;
+        if let Some(slot) = slot {
+            return Ok(RecordId { page_id, slot });
+        }
+        // マップに記録していたより空きが少なかった
+        // 記録を直したので、次は別のページが選ばれる
+    }
+}
+
+// 末尾のページの後ろに空のページを繋げる
+fn append_page(bufmgr: &mut BufferPoolManager, log: &TxnLog, heap: &HeapTable) -> Result<PageId, Error> {
+    let mut last_page_id = heap.fsm.last(bufmgr)?.unwrap_or(heap.first_page_id);
+    // マップに載せる前にクラッシュしたページが後ろに繋がっていれば、辿って載せる
+    loop {
+        let (next, _) = read_page_info(bufmgr, last_page_id)?;
+        match next.valid() {
+            Some(next) => {
+                let (_, free) = read_page_info(bufmgr, next)?;
+                heap.fsm.set(bufmgr, log, next, free)?;
+                last_page_id = next;
+            }
+            None => break,
+        }
+    }
+    let new_page_id = create_page(bufmgr, log)?.page_id;
+    let buffer = bufmgr.fetch_page(last_page_id)?;
+    log.modify_page(&buffer, |body| set_next_page_id(body, new_page_id))?;
+    Ok(new_page_id)
+}
+
+// 以下の関数はスロット1つへの変更を、それぞれ1つの論理的な操作としてWALに書く
+// 途中でロールバックやクラッシュが起きても、完了した操作だけが新しい順に取り消される
+
+fn insert_tuple(bufmgr: &mut BufferPoolManager, log: &TxnLog, heap: &HeapTable, bytes: &[u8]) -> Result<RecordId, Error> {
+    let start = log.last_lsn();
+    let rid = insert_raw(bufmgr, log, heap, bytes)?;
+    log.log_operation(start, undo_record(UNDO_INSERT, rid, &[]))?;
+    Ok(rid)
+}
+
+// スロットを書き換える。ページに収まらなければ何もせずfalse
+// undoがfalseならロールバックしても元に戻さない
+fn write_tuple(bufmgr: &mut BufferPoolManager, log: &TxnLog, rid: RecordId, bytes: &[u8], undo: bool) -> Result<bool, Error> {
+    let start = log.last_lsn();
+    let buffer = bufmgr.fetch_page(rid.page_id)?;
+    let old = log.modify_page(&buffer, |body| {
+        let mut slotted = Slotted::new(&mut body[NEXT_PAGE_ID_SIZE..]);
+        let old = slotted.get(rid.slot)?.to_vec();
+        if slotted.update(rid.slot, bytes) {
+            Some(old)
+        } else {
+            None
+        }
+    })?;
+    let old = match old {
+        Some(old) => old,
+        None => return Ok(false),
+    };
+    let undo = if undo { undo_record(UNDO_UPDATE, rid, &old) } else { undo_record(UNDO_NONE, rid, &[]) };
+    log.log_operation(start, undo)?;
+    Ok(true)
+}
+
+// スロットを解放する(ガベージコレクション用なので元に戻さない)
+// タプルが指しているオーバーフローページも解放する
+fn purge_tuple(bufmgr: &mut BufferPoolManager, log: &TxnLog, rid: RecordId) -> Result<(), Error> {
+    let start = log.last_lsn();
+    let buffer = bufmgr.fetch_page(rid.page_id)?;
+    let pointer = log.modify_page(&buffer, |body| {
+        let mut slotted = Slotted::new(&mut body[NEXT_PAGE_ID_SIZE..]);
+        let pointer = slotted.get(rid.slot).and_then(pointer);
+        slotted.delete(rid.slot);
+        slotted.purge(rid.slot);
+        pointer
+    })?;
+    drop(buffer);
+    if let Some(pointer) = pointer {
+        overflow::free(bufmgr, log, pointer)?;
+    }
+    log.log_operation(start, undo_record(UNDO_NONE, rid, &[]))?;
+    Ok(())
+}
+
+// linkから古い版を辿って全て回収し、回収した数を返す
+fn purge_chain(bufmgr: &mut BufferPoolManager, log: &TxnLog, mut link: Option<RecordId>) -> Result<usize, Error> {
+    let mut count = 0;
+    while let Some(rid) = link {
+        let (header, _) = read_tuple(bufmgr, rid)?.ok_or(Error::NotFound)?;
+        purge_tuple(bufmgr, log, rid)?;
+        link = header.link;
+        count += 1;
+    }
+    Ok(count)
+}
+
+// 全員から見える最も新しい版より古い版を回収し、回収した数を返す
+// それより古い版まで遡るスナップショットは無い
+fn prune(
+    bufmgr: &mut BufferPoolManager,
+    log: &TxnLog,
+    horizon: TxnId,
+    mut loc: RecordId,
+    mut header: TupleHeader,
+    mut data: Vec<u8>,
+) -> Result<usize, Error> {
+    while let Some(prev) = header.link {
+        if header.xmin < horizon {
+            // 先に繋がりを切ってから回収する(途中でクラッシュしても、辿れない版が残るだけで済む)
+            let cut = TupleHeader { link: None, ..header };
+            assert!(write_tuple(bufmgr, log, loc, &cut.encode(&data), false)?);
+            return purge_chain(bufmgr, log, Some(prev));
+        }
+        let (prev_header, prev_data) = read_tuple(bufmgr, prev)?.ok_or(Error::NotFound)?;
+        loc = prev;
+        header = prev_header;
+        data = prev_data;
+    }
+    Ok(0)
+}
+
+/// レコードをまとめて追加するローダー
+/// insertは空きのあるページを先頭から探すが、ローダーは新しいページだけに隙間なく詰めて末尾に繋げる
+/// ページの中身はメモリ上で組み立て、いっぱいになったらページ1つ分の追加を1つの操作としてWALに書く
+///
+/// 組み立て中のページはテーブルに繋ぐまで誰からも辿れないので、他のトランザクションのinsertと混ざらない
+/// finish()を呼ぶまで、最後のページのレコードはテーブルに入らない
+pub struct HeapLoader {
+    table: PageId,
+    // テーブルの末尾のページ(他のトランザクションが後ろに繋げているかもしれない)
+    last_page_id: PageId,
+    fsm: FreeSpaceMap,
+    // 組み立て中のページとその中身
+    page: Option<(PageId, Vec<u8>)>,
+}
+
+impl HeapLoader {
+    /// レコードを追加する
+    pub fn push(&mut self, bufmgr: &mut BufferPoolManager, txn: &Transaction, data: &[u8]) -> Result<RecordId, Error> {
+        let log = txn.log();
+        let mut header = TupleHeader::new(TUPLE_HOME, txn.id(), None);
+        let data = store(bufmgr, log, &mut header, data)?;
+        let bytes = header.encode(&data);
+        loop {
+            if self.page.is_none() {
+                // ページを作ったこと自体は取り消さない(繋ぐ前にロールバックしても、どこからも辿れないだけ)
+                let start = log.last_lsn();
+                let buffer = create_page(bufmgr, log)?;
+                log.log_operation(start, undo_record(UNDO_NONE, RecordId { page_id: buffer.page_id, slot: 0 }, &[]))?;
+                let body = buffer.page.borrow()[PAGE_LSN_SIZE..].to_vec();
+                self.page = Some((buffer.page_id, body));
+            }
+            let (page_id, body) = self.page.as_mut().unwrap();
+            if let Some(slot) = Slotted::new(&mut body[NEXT_PAGE_ID_SIZE..]).insert(&bytes) {
+                let rid = RecordId { page_id: *page_id, slot };
+                txn.lock_record(self.table, rid, LockMode::Exclusive)?;
+                return Ok(rid);
+            }
+            self.flush(bufmgr, log)?;
+        }
+    }
+
+    /// 組み立て中のページを書いてテーブルに繋ぐ
+    pub fn finish(mut self, bufmgr: &mut BufferPoolManager, txn: &Transaction) -> Result<(), Error> {
+        self.flush(bufmgr, txn.log())
+    }
+
+    fn flush(&mut self, bufmgr: &mut BufferPoolManager, log: &TxnLog) -> Result<(), Error> {
+        let (page_id, body) = match self.page.take() {
+            Some(page) => page,
+            None => return Ok(()),
+        };
+        let start = log.last_lsn();
+        let buffer = bufmgr.fetch_page(page_id)?;
+        log.modify_page(&buffer, |page| page.copy_from_slice(&body))?;
+        drop(buffer);
+        let mut last = bufmgr.fetch_page(self.last_page_id)?;
+        loop {
+            let next = next_page_id(&last.page.borrow()[PAGE_LSN_SIZE..]);
+            match next.valid() {
+                Some(next) => last = bufmgr.fetch_page(next)?,
+                None => break,
+            }
+        }
+        log.modify_page(&last, |page| set_next_page_id(page, page_id))?;
+        drop(last);
+        self.fsm.set(bufmgr, log, page_id, free_space(&body))?;
+        self.last_page_id = page_id;
+        let count = Slotted::new(&body[NEXT_PAGE_ID_SIZE..]).num_slots();
+        log.log_operation(start, undo_record(UNDO_BULK, RecordId { page_id, slot: 0 }, &count.to_le_bytes()))?;
+        Ok(())
+    }
+}
+
+/// txnのスナップショットから見えるレコードを順番に読むイテレータ
+/// バッファプールを借用し続けないよう、next()のたびにbufmgrを受け取る
+pub struct HeapIter {
+    table: PageId,
+    page_id: PageId,
+    slot: u16,
+}
+
+impl HeapIter {
+    pub fn next(&mut self, bufmgr: &mut BufferPoolManager, txn: &Transaction) -> Result<Option<(RecordId, Vec<u8>)>, Error> {
+        txn.lock_table(self.table, LockMode::Shared)?;
+        while let Some(rid) = self.next_rid(bufmgr)? {
+            if let Some((_, header, data)) = locate(bufmgr, rid)? {
+                if let Some(reader) = visible_version(bufmgr, txn, header, data)? {
+                    return Ok(Some((rid, reader.read_to_end(bufmgr)?)));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    // 次のRecordId(最新版か移動先の跡があるスロット)を返す
+    fn next_rid(&mut self, bufmgr: &mut BufferPoolManager) -> Result<Option<RecordId>, Error> {
+        while self.page_id.valid().is_some() {
+            let buffer = bufmgr.fetch_page(self.page_id)?;
+            let page = buffer.page.borrow();
+            let slotted = Slotted::new(body(&page[..]));
+            while self.slot < slotted.num_slots() {
+                let slot = self.slot;
+                self.slot += 1;
+                if let Some(bytes) = slotted.get(slot) {
+                    if matches!(bytes[0] & !TUPLE_OVERFLOW, TUPLE_HOME | TUPLE_FORWARD) {
+                        return Ok(Some(RecordId { page_id: self.page_id, slot }));
+                    }
+                }
+            }
+            self.page_id = next_page_id(&page[PAGE_LSN_SIZE..]);
+            self.slot = 0;
+        }
+        Ok(None)
+    }
+}
+
+// 取り消し方法: | UNDO_HEAP | 種類 | ページID(u64) | スロット(u16) | 元のレコード |
+// UNDO_BULKでは元のレコードの代わりに、スロットからいくつ追加したか(u16)を入れる
+fn undo_record(kind: u8, rid: RecordId, data: &[u8]) -> Vec<u8> {
+    let mut undo = vec![UNDO_HEAP, kind];
+    undo.extend_from_slice(&rid.page_id.0.to_le_bytes());
+    undo.extend_from_slice(&rid.slot.to_le_bytes());
+    undo.extend_from_slice(data);
+    undo
+}
+
+/// ヒープテーブルへの操作を取り消す(UndoDispatcherから呼ばれる)
+/// 取り消しはオーバーフローページの解放を除いて、同じページの中で完結する
+pub(crate) fn undo(bufmgr: &mut BufferPoolManager, log: &TxnLog, undo: &[u8]) -> Result<(), Error> {
+    let kind = undo[0];
+    if kind == UNDO_NONE {
+        return Ok(());
+    }
+    let mut page_id = [0u8; 8];
+    page_id.copy_from_slice(&undo[1..9]);
+    let page_id = PageId(u64::from_le_bytes(page_id));
+    let slot = u16::from_le_bytes([undo[9], undo[10]]);
+    let data = &undo[11..];
+    let buffer = bufmgr.fetch_page(page_id)?;
+    // 取り消すと指す先が無くなるオーバーフローページ
+    let garbage: Vec<Pointer> = log.modify_page(&buffer, |body| {
+        let mut slotted = Slotted::new(&mut body[NEXT_PAGE_ID_SIZE..]);
+        match kind {
+            UNDO_INSERT => undo_insert(&mut slotted, slot).into_iter().collect(),
+            UNDO_BULK => {
+                let count = u16::from_le_bytes([data[0], data[1]]);
+                (slot..slot + count).filter_map(|slot| undo_insert(&mut slotted, slot)).collect()
+            }
+            UNDO_UPDATE => {
+                let current = slotted.get(slot).unwrap();
+                let garbage = pointer(current).filter(|&current| pointer(data) != Some(current));
+                // 確保したサイズは縮まないので、元のレコードは必ず収まる
+                assert!(slotted.update(slot, data));
+                garbage.into_iter().collect()
+            }
+            _ => unreachable!(),
+        }
+    })?;
+    drop(buffer);
+    for garbage in garbage {
+        overflow::free(bufmgr, log, garbage)?;
+    }
+    Ok(())
+}
+
+// 追加したレコードを消し、指す先が無くなるオーバーフローページを返す
+// まだ誰も見ていないレコードなので、領域もすぐに解放する
+fn undo_insert(slotted: &mut Slotted<&mut [u8]>, slot: u16) -> Option<Pointer> {
+    let current = slotted.get(slot).unwrap();
+    // 古い版のコピーは、コピー元と同じページを指している
+    let garbage = match TupleHeader::decode(current).0.kind {
+        TUPLE_VERSION => None,
+        _ => pointer(current),
+    };
+    slotted.delete(slot);
+    slotted.purge(slot);
+    garbage
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction::{self, TransactionManager};
+    use crate::wal;
+
+    fn open(name: &str) -> (BufferPoolManager, TransactionManager, HeapTable) {
+        let path = std::env::temp_dir().join(format!("heap_test_{}_{}", std::process::id(), name));
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(wal::log_path(&path));
+        let (mut bufmgr, txn_mgr, _) = transaction::open(&path, 16).unwrap();
+        let txn = txn_mgr.begin().unwrap();
+        let table = HeapTable::create(&mut bufmgr, &txn).unwrap();
+        txn_mgr.commit(txn).unwrap();
+        (bufmgr, txn_mgr, table)
+    }
+
+    fn scan_all(bufmgr: &mut BufferPoolManager, txn: &Transaction, table: &HeapTable) -> Vec<Vec<u8>> {
+        let mut iter = table.scan();
+        let mut records = vec![];
+        while let Some((_, data)) = iter.next(bufmgr, txn).unwrap() {
+            records.push(data);
+        }
+        records
+    }
+
+    #[test]
+    fn test_snapshot_isolation() {
+        let (mut bufmgr, txn_mgr, table) = open("snapshot");
+        let txn = txn_mgr.begin().unwrap();
+        let a = table.insert(&mut bufmgr, &txn, b"a1").unwrap();
+        let b = table.insert(&mut bufmgr, &txn, b"b1").unwrap();
+        txn_mgr.commit(txn).unwrap();
+
+        let reader = txn_mgr.begin().unwrap();
+        let writer = txn_mgr.begin().unwrap();
+        table.update(&mut bufmgr, &writer, a, b"a2").unwrap();
+        table.delete(&mut bufmgr, &writer, b).unwrap();
+        let c = table.insert(&mut bufmgr, &writer, b"c2").unwrap();
+        // 書いている本人には自分の変更が見える
+        assert_eq!(table.get(&mut bufmgr, &writer, a).unwrap(), Some(b"a2".to_vec()));
+        assert_eq!(table.get(&mut bufmgr, &writer, b).unwrap(), None);
+        // 読む側は書き込みを待たずに、beginした時点の版を読む
+        assert_eq!(table.get(&mut bufmgr, &reader, a).unwrap(), Some(b"a1".to_vec()));
+        assert_eq!(table.get(&mut bufmgr, &reader, c).unwrap(), None);
+        txn_mgr.commit(writer).unwrap();
+        // コミットされた後も、スナップショットは変わらない
+        assert_eq!(scan_all(&mut bufmgr, &reader, &table), vec![b"a1".to_vec(), b"b1".to_vec()]);
+        txn_mgr.commit(reader).unwrap();
+
+        let txn = txn_mgr.begin().unwrap();
+        assert_eq!(scan_all(&mut bufmgr, &txn, &table), vec![b"a2".to_vec(), b"c2".to_vec()]);
+    }
+
+    #[test]
+    fn test_write_conflict() {
+        let (mut bufmgr, txn_mgr, table) = open("conflict");
+        let txn = txn_mgr.begin().unwrap();
+        let rid = table.insert(&mut bufmgr, &txn, b"v1").unwrap();
+        txn_mgr.commit(txn).unwrap();
+
+        let txn1 = txn_mgr.begin().unwrap();
+        let txn2 = txn_mgr.begin().unwrap();
+        table.update(&mut bufmgr, &txn1, rid, b"v2").unwrap();
+        // 実行中のトランザクションが書き換えたレコードは書き換えられない
+        assert!(matches!(table.update(&mut bufmgr, &txn2, rid, b"x"), Err(Error::Conflict)));
+        txn_mgr.commit(txn1).unwrap();
+        // 自分より後にコミットされた変更も上書きできない(更新の消失を防ぐ)
+        assert!(matches!(table.delete(&mut bufmgr, &txn2, rid), Err(Error::Conflict)));
+        txn_mgr.rollback(&mut bufmgr, txn2).unwrap();
+
+        // ロールバックした変更は衝突にならない
+        let txn3 = txn_mgr.begin().unwrap();
+        table.delete(&mut bufmgr, &txn3, rid).unwrap();
+        assert!(matches!(table.delete(&mut bufmgr, &txn3, rid), Err(Error::NotFound)));
+        txn_mgr.rollback(&mut bufmgr, txn3).unwrap();
+        let txn4 = txn_mgr.begin().unwrap();
+        table.update(&mut bufmgr, &txn4, rid, b"v3").unwrap();
+        txn_mgr.commit(txn4).unwrap();
+
+        let txn = txn_mgr.begin().unwrap();
+        assert_eq!(table.get(&mut bufmgr, &txn, rid).unwrap(), Some(b"v3".to_vec()));
+    }
+
+    #[test]
+    fn test_move_and_gc() {
+        let (mut bufmgr, txn_mgr, table) = open("gc");
+        let txn = txn_mgr.begin().unwrap();
+        let rids: Vec<RecordId> = (0..20u8).map(|i| table.insert(&mut bufmgr, &txn, &[i; 150]).unwrap()).collect();
+        txn_mgr.commit(txn).unwrap();
+
+        // 古いスナップショットを残したまま何度も書き換える
+        let reader = txn_mgr.begin().unwrap();
+        for round in 1..=3usize {
+            let txn = txn_mgr.begin().unwrap();
+            for (i, &rid) in rids.iter().enumerate() {
+                // 長くしていくのでページに入らなくなり、別のページに移るレコードもある
+                table.update(&mut bufmgr, &txn, rid, &vec![i as u8; 150 + round * 100]).unwrap();
+            }
+            txn_mgr.commit(txn).unwrap();
+        }
+        let txn = txn_mgr.begin().unwrap();
+        table.delete(&mut bufmgr, &txn, rids[0]).unwrap();
+        // 書き換えをロールバックしても、移動する前の版に戻る
+        let aborted = txn_mgr.begin().unwrap();
+        table.update(&mut bufmgr, &aborted, rids[1], &[9u8; 1500]).unwrap();
+        txn_mgr.rollback(&mut bufmgr, aborted).unwrap();
+        txn_mgr.commit(txn).unwrap();
+
+        // readerが見ている版は回収されない
+        let horizon = txn_mgr.horizon();
+        let txn = txn_mgr.begin().unwrap();
+        let report = table.gc(&mut bufmgr, &txn, horizon).unwrap();
+        txn_mgr.commit(txn).unwrap();
+        assert!(report.removed.is_empty());
+        for (i, &rid) in rids.iter().enumerate() {
+            assert_eq!(table.get(&mut bufmgr, &reader, rid).unwrap(), Some(vec![i as u8; 150]));
+        }
+        txn_mgr.commit(reader).unwrap();
+
+        let horizon = txn_mgr.horizon();
+        let txn = txn_mgr.begin().unwrap();
+        let report = table.gc(&mut bufmgr, &txn, horizon).unwrap();
+        txn_mgr.commit(txn).unwrap();
+        assert_eq!(report.removed, vec![rids[0]]);
+        assert_eq!(report.versions, 60);
+        let txn = txn_mgr.begin().unwrap();
+        let records = scan_all(&mut bufmgr, &txn, &table);
+        assert_eq!(records.len(), 19);
+        assert_eq!(records[0], vec![1u8; 450]);
+        // 回収した領域は再利用される
+        let pages = table.num_pages(&mut bufmgr).unwrap();
+        for _ in 0..10 {
+            table.insert(&mut bufmgr, &txn, &[7u8; 400]).unwrap();
+        }
+        assert_eq!(table.num_pages(&mut bufmgr).unwrap(), pages);
+    }
+
+    #[test]
+    fn test_vacuum() {
+        let (mut bufmgr, txn_mgr, table) = open("vacuum");
+        let txn = txn_mgr.begin().unwrap();
+        let rids: Vec<RecordId> = (0..200u8).map(|i| table.insert(&mut bufmgr, &txn, &[i; 300]).unwrap()).collect();
+        txn_mgr.commit(txn).unwrap();
+        let pages = table.num_pages(&mut bufmgr).unwrap();
+        let file_pages = bufmgr.disk_mut().num_pages();
+        assert!(pages > 10);
+
+        // 最初と真ん中のレコードだけ残して消す
+        let txn = txn_mgr.begin().unwrap();
+        for (i, &rid) in rids.iter().enumerate() {
+            if i != 0 && i != 100 {
+                table.delete(&mut bufmgr, &txn, rid).unwrap();
+            }
+        }
+        txn_mgr.commit(txn).unwrap();
+        let horizon = txn_mgr.horizon();
+        let txn = txn_mgr.begin().unwrap();
+        assert_eq!(table.gc(&mut bufmgr, &txn, horizon).unwrap().removed.len(), 198);
+        // 空になったページはリストから外して解放し、ファイルの末尾にあるものは切り詰める
+        let freed = table.compact(&mut bufmgr, &txn).unwrap();
+        txn_mgr.commit(txn).unwrap();
+        assert_eq!(freed, pages - 2);
+        assert_eq!(table.num_pages(&mut bufmgr).unwrap(), 2);
+        assert!(bufmgr.disk_mut().num_pages() < file_pages);
+        assert_eq!(table.fsm.entries(&mut bufmgr).unwrap().len(), 2);
+
+        let txn = txn_mgr.begin().unwrap();
+        assert_eq!(scan_all(&mut bufmgr, &txn, &table), vec![vec![0u8; 300], vec![100u8; 300]]);
+        assert_eq!(table.get(&mut bufmgr, &txn, rids[100]).unwrap(), Some(vec![100u8; 300]));
+        // 残ったページの空きを使ってから、末尾にページを繋げる
+        for i in 0..40u8 {
+            table.insert(&mut bufmgr, &txn, &[i; 300]).unwrap();
+        }
+        assert_eq!(scan_all(&mut bufmgr, &txn, &table).len(), 42);
+        assert_eq!(table.num_pages(&mut bufmgr).unwrap(), 4);
+        txn_mgr.commit(txn).unwrap();
+    }
+
+    #[test]
+    fn test_loader() {
+        let (mut bufmgr, txn_mgr, table) = open("loader");
+        let txn = txn_mgr.begin().unwrap();
+        table.insert(&mut bufmgr, &txn, b"first").unwrap();
+        txn_mgr.commit(txn).unwrap();
+
+        // ロールバックすると、まとめて追加したレコードも消える
+        let txn = txn_mgr.begin().unwrap();
+        let mut loader = table.loader();
+        for i in 0..1000u32 {
+            loader.push(&mut bufmgr, &txn, &i.to_be_bytes()).unwrap();
+        }
+        loader.finish(&mut bufmgr, &txn).unwrap();
+        txn_mgr.rollback(&mut bufmgr, txn).unwrap();
+        let txn = txn_mgr.begin().unwrap();
+        assert_eq!(scan_all(&mut bufmgr, &txn, &table), vec![b"first".to_vec()]);
+
+        let pages = table.num_pages(&mut bufmgr).unwrap();
+        let mut loader = table.loader();
+        let mut rids = vec![];
+        let mut records: Vec<Vec<u8>> = (0..1000u32).map(|i| vec![i as u8; 100]).collect();
+        records.push(vec![7u8; PAGE_SIZE * 2]);
+        for record in &records {
+            rids.push(loader.push(&mut bufmgr, &txn, record).unwrap());
+        }
+        loader.finish(&mut bufmgr, &txn).unwrap();
+        txn_mgr.commit(txn).unwrap();
+        let txn = txn_mgr.begin().unwrap();
+        assert_eq!(table.get(&mut bufmgr, &txn, rids[500]).unwrap(), Some(records[500].clone()));
+        assert_eq!(table.get(&mut bufmgr, &txn, rids[1000]).unwrap(), Some(records[1000].clone()));
+        let all = scan_all(&mut bufmgr, &txn, &table);
+        assert_eq!(all[0], b"first".to_vec());
+        assert_eq!(&all[1..], &records[..]);
+        // ページに隙間なく詰める(スロット4byteとレコードの長さ2byteを足したサイズで割ったページ数に収まる)
+        let per_page = (PAGE_SIZE - PAGE_LSN_SIZE - NEXT_PAGE_ID_SIZE - 4) / (100 + TUPLE_HEADER_SIZE + 6);
+        assert!(table.num_pages(&mut bufmgr).unwrap() - pages <= (1000 / per_page + 1) as u64);
+        txn_mgr.commit(txn).unwrap();
+    }
+
+    #[test]
+    fn test_overflow() {
+        let (mut bufmgr, txn_mgr, table) = open("overflow");
+        let big = |seed: u8, len: usize| (0..len).map(|i| (i as u8).wrapping_mul(seed)).collect::<Vec<u8>>();
+        let txn = txn_mgr.begin().unwrap();
+        let small = table.insert(&mut bufmgr, &txn, b"small").unwrap();
+        let rid = table.insert(&mut bufmgr, &txn, &big(3, PAGE_SIZE * 3)).unwrap();
+        txn_mgr.commit(txn).unwrap();
+
+        let reader = txn_mgr.begin().unwrap();
+        let txn = txn_mgr.begin().unwrap();
+        table.update(&mut bufmgr, &txn, rid, &big(5, PAGE_SIZE * 2)).unwrap();
+        txn_mgr.commit(txn).unwrap();
+        // 古い版も新しい版も、ページを跨いだまま読める
+        assert_eq!(table.get(&mut bufmgr, &reader, rid).unwrap(), Some(big(3, PAGE_SIZE * 3)));
+        let txn = txn_mgr.begin().unwrap();
+        assert_eq!(table.get(&mut bufmgr, &txn, rid).unwrap(), Some(big(5, PAGE_SIZE * 2)));
+        assert_eq!(scan_all(&mut bufmgr, &txn, &table), vec![b"small".to_vec(), big(5, PAGE_SIZE * 2)]);
+
+        // ロールバックした書き換えのページは解放され、次の書き込みで再利用される
+        table.update(&mut bufmgr, &txn, small, &big(7, PAGE_SIZE * 2)).unwrap();
+        txn_mgr.rollback(&mut bufmgr, txn).unwrap();
+        let pages = bufmgr.disk_mut().num_pages();
+        let txn = txn_mgr.begin().unwrap();
+        assert_eq!(table.get(&mut bufmgr, &txn, small).unwrap(), Some(b"small".to_vec()));
+        table.update(&mut bufmgr, &txn, small, &big(9, PAGE_SIZE * 2)).unwrap();
+        assert_eq!(bufmgr.disk_mut().num_pages(), pages);
+
+        // 大きなレコードは少しずつ書いて、少しずつ読める
+        let blob = big(11, PAGE_SIZE * 10);
+        let streamed = table.insert_from(&mut bufmgr, &txn, &mut &blob[..]).unwrap();
+        let mut reader_stream = table.get_reader(&mut bufmgr, &txn, streamed).unwrap().unwrap();
+        assert_eq!(reader_stream.remaining(), blob.len() as u64);
+        let mut chunks = 0;
+        let mut read = vec![];
+        while let Some(chunk) = reader_stream.next_chunk(&mut bufmgr).unwrap() {
+            assert!(chunk.len() < PAGE_SIZE);
+            read.extend(chunk);
+            chunks += 1;
+        }
+        assert!(chunks > 10);
+        assert_eq!(read, blob);
+        table.delete(&mut bufmgr, &txn, rid).unwrap();
+        txn_mgr.commit(txn).unwrap();
+        txn_mgr.commit(reader).unwrap();
+
+        // 削除したレコードと古い版のページは、ガベージコレクションで解放される
+        let horizon = txn_mgr.horizon();
+        let txn = txn_mgr.begin().unwrap();
+        let report = table.gc(&mut bufmgr, &txn, horizon).unwrap();
+        assert_eq!(report.removed, vec![rid]);
+        let pages = bufmgr.disk_mut().num_pages();
+        table.insert(&mut bufmgr, &txn, &big(13, PAGE_SIZE * 5)).unwrap();
+        assert_eq!(bufmgr.disk_mut().num_pages(), pages);
+        txn_mgr.commit(txn).unwrap();
+    }
+
+    #[test]
+    fn test_overflow_update_gc() {
+        let (mut bufmgr, txn_mgr, table) = open("overflow_update_gc");
+        let big = |seed: u8| (0..PAGE_SIZE * 4).map(|i| (i as u8).wrapping_mul(seed)).collect::<Vec<u8>>();
+        let txn = txn_mgr.begin().unwrap();
+        let rid = table.insert(&mut bufmgr, &txn, &big(3)).unwrap();
+        txn_mgr.commit(txn).unwrap();
+
+        // 大きな値を小さな値に書き換える。古い版のページは、見える人がいなくなるまで残る
+        let txn = txn_mgr.begin().unwrap();
+        table.update(&mut bufmgr, &txn, rid, b"small").unwrap();
+        txn_mgr.commit(txn).unwrap();
+        let pages = bufmgr.disk_mut().num_pages();
+        let horizon = txn_mgr.horizon();
+        let txn = txn_mgr.begin().unwrap();
+        let report = table.gc(&mut bufmgr, &txn, horizon).unwrap();
+        assert_eq!(report.versions, 1);
+        // 回収した古い版のページに、同じ大きさの値が入る
+        let other = table.insert(&mut bufmgr, &txn, &big(5)).unwrap();
+        assert_eq!(bufmgr.disk_mut().num_pages(), pages);
+        assert_eq!(table.get(&mut bufmgr, &txn, rid).unwrap(), Some(b"small".to_vec()));
+        assert_eq!(table.get(&mut bufmgr, &txn, other).unwrap(), Some(big(5)));
+        txn_mgr.commit(txn).unwrap();
+    }
+}
@@ -0,0 +1,739 @@
+// 組み込みのキーバリューストア
+// SQLを使わずに、バイト列のキーと値をキーの順に保存する
+//
+// let db = Db::open("kv.db")?;
+// db.put(b"key", b"value")?;
+// assert_eq!(db.get(b"key")?, Some(b"value".to_vec()));
+// for pair in db.scan_prefix(b"k") { let (key, value) = pair?; ... }
+//
+// 1つのB+treeに全てのキーの版を保存する(多版型同時実行制御)
+// - B+treeのキー：| memcmpableでエンコードしたキー | !書いたトランザクションのID(u64, ビッグエンディアン) |
+//   IDを反転して入れるので、同じキーの版は新しい順に並ぶ
+// - B+treeの値：| 種類(u8) | 値 |   種類はVERSION_PUTかVERSION_DELETE(削除した印で値は無い)
+// 読む時はスナップショットから見える一番新しい版を返すので、スナップショットを取った後に書き込んでも読む結果は変わらない
+// 書く時に、どのスナップショットからも見えなくなった古い版を回収する
+//
+// 書き込みは1回ごと(バッチなら1つにまとめて)トランザクションとしてコミットするので、WALでクラッシュから復旧できる
+// ファイルヘッダー(ページ0)：| マジックナンバー(8byte) | フォーマットのバージョン(u32) | B+treeのメタページID(u64) |
+//
+// BufferPoolManagerと同じく、1つのスレッドから使う(Dbを複製すると同じストアを共有する)
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io;
+use std::ops::{Bound, RangeBounds};
+use std::path::Path;
+use std::rc::Rc;
+
+use crate::btree::{BTree, SearchMode};
+use crate::buffer_pool::{BufferPoolManager, Error, PAGE_LSN_SIZE};
+use crate::disk_manager::{DiskManager, PageId};
+use crate::memcmpable;
+use crate::transaction::{self, Transaction, TransactionManager};
+use crate::wal::{self, LogManager, TxnId};
+
+const HEADER_PAGE_ID: PageId = PageId(0);
+const MAGIC: &[u8; 8] = b"PRACTKV\0";
+const FORMAT_VERSION: u32 = 1;
+
+const VERSION_PUT: u8 = 1;
+const VERSION_DELETE: u8 = 2;
+
+// イテレータが1回に読み進めるキーの数
+const ITER_BATCH_SIZE: usize = 64;
+
+// 開く時のバッファプールのページ数
+const DEFAULT_POOL_SIZE: usize = 64;
+
+fn invalid_data(msg: &str) -> Error {
+    Error::Io(io::Error::new(io::ErrorKind::InvalidData, msg.to_string()))
+}
+
+fn version_key(key: &[u8], txn_id: TxnId) -> Vec<u8> {
+    let mut bytes = vec![];
+    memcmpable::encode_bytes(key, &mut bytes);
+    bytes.extend_from_slice(&(!txn_id.0).to_be_bytes());
+    bytes
+}
+
+// B+treeのキーを(キー, 書いたトランザクションのID)に戻す
+fn decode_version_key(mut bytes: &[u8]) -> Result<(Vec<u8>, TxnId), Error> {
+    let mut key = vec![];
+    memcmpable::decode_bytes(&mut bytes, &mut key).map_err(invalid_data)?;
+    if bytes.len() != 8 {
+        return Err(invalid_data("invalid version key"));
+    }
+    let mut id = [0u8; 8];
+    id.copy_from_slice(bytes);
+    Ok((key, TxnId(!u64::from_be_bytes(id))))
+}
+
+fn encode_version(value: Option<&[u8]>) -> Vec<u8> {
+    match value {
+        Some(value) => [&[VERSION_PUT], value].concat(),
+        None => vec![VERSION_DELETE],
+    }
+}
+
+fn decode_version(mut bytes: Vec<u8>) -> Result<Option<Vec<u8>>, Error> {
+    match bytes.first() {
+        Some(&VERSION_PUT) => {
+            bytes.remove(0);
+            Ok(Some(bytes))
+        }
+        Some(&VERSION_DELETE) => Ok(None),
+        _ => Err(invalid_data("invalid version")),
+    }
+}
+
+// prefixで始まる全てのキーより大きい最小のキー。無ければ(prefixが0xffだけなら)None
+pub(crate) fn prefix_end(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < 0xff {
+            end.push(last + 1);
+            return Some(end);
+        }
+    }
+    None
+}
+
+pub(crate) fn to_owned_bound<K: AsRef<[u8]>>(bound: Bound<&K>) -> Bound<Vec<u8>> {
+    match bound {
+        Bound::Included(key) => Bound::Included(key.as_ref().to_vec()),
+        Bound::Excluded(key) => Bound::Excluded(key.as_ref().to_vec()),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
+// 1つのキーの版(新しい順)
+struct Version {
+    tree_key: Vec<u8>,
+    txn_id: TxnId,
+    value: Option<Vec<u8>>,
+}
+
+struct Inner {
+    bufmgr: BufferPoolManager,
+    txn_mgr: TransactionManager,
+    tree: BTree,
+}
+
+impl Inner {
+    // ファイルヘッダーを読み、無ければB+treeを作って書く
+    fn bootstrap(bufmgr: &mut BufferPoolManager, txn: &Transaction) -> Result<BTree, Error> {
+        if bufmgr.disk_mut().num_pages() == 0 {
+            let buffer = bufmgr.create_page()?;
+            assert_eq!(buffer.page_id, HEADER_PAGE_ID);
+        }
+        let (magic, version, meta_page_id) = {
+            let buffer = bufmgr.fetch_page(HEADER_PAGE_ID)?;
+            let page = buffer.page.borrow();
+            let body = &page[PAGE_LSN_SIZE..];
+            let mut magic = [0u8; 8];
+            magic.copy_from_slice(&body[..8]);
+            let mut version = [0u8; 4];
+            version.copy_from_slice(&body[8..12]);
+            let mut page_id = [0u8; 8];
+            page_id.copy_from_slice(&body[12..20]);
+            (magic, u32::from_le_bytes(version), PageId(u64::from_le_bytes(page_id)))
+        };
+        // ヘッダーを書く前にクラッシュした場合も、ページ0は0で埋まっている
+        if magic == [0u8; 8] {
+            let tree = BTree::create(bufmgr, txn)?;
+            let buffer = bufmgr.fetch_page(HEADER_PAGE_ID)?;
+            txn.log().modify_page(&buffer, |body| {
+                body[..8].copy_from_slice(MAGIC);
+                body[8..12].copy_from_slice(&FORMAT_VERSION.to_le_bytes());
+                body[12..20].copy_from_slice(&tree.meta_page_id.0.to_le_bytes());
+            })?;
+            return Ok(tree);
+        }
+        if &magic != MAGIC {
+            return Err(invalid_data("not a key-value store file"));
+        }
+        if version != FORMAT_VERSION {
+            return Err(invalid_data("unsupported key-value store format version"));
+        }
+        Ok(BTree::open(meta_page_id))
+    }
+
+    // keyの版を新しい順に全て返す
+    fn versions(&mut self, txn: &Transaction, key: &[u8]) -> Result<Vec<Version>, Error> {
+        let mut start = vec![];
+        memcmpable::encode_bytes(key, &mut start);
+        let mut iter = self.tree.iter(&mut self.bufmgr, txn, SearchMode::Key(start))?;
+        let mut versions = vec![];
+        while let Some((tree_key, value)) = iter.next(&mut self.bufmgr)? {
+            let (found, txn_id) = decode_version_key(&tree_key)?;
+            if found != key {
+                break;
+            }
+            versions.push(Version {
+                tree_key,
+                txn_id,
+                value: decode_version(value)?,
+            });
+        }
+        Ok(versions)
+    }
+
+    // txnのスナップショットから見えるkeyの値
+    fn get(&mut self, txn: &Transaction, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        let mut start = vec![];
+        memcmpable::encode_bytes(key, &mut start);
+        let mut iter = self.tree.iter(&mut self.bufmgr, txn, SearchMode::Key(start))?;
+        while let Some((tree_key, value)) = iter.next(&mut self.bufmgr)? {
+            let (found, txn_id) = decode_version_key(&tree_key)?;
+            if found != key {
+                break;
+            }
+            if txn.snapshot().sees(txn_id) {
+                return decode_version(value);
+            }
+        }
+        Ok(None)
+    }
+
+    // keyにvalue(Noneなら削除)を書き、書く前の値を返す
+    // 書き込むトランザクションは同時に1つしか無いので、一番新しい版が今の値になる
+    fn write(&mut self, txn: &Transaction, key: &[u8], value: Option<&[u8]>) -> Result<Option<Vec<u8>>, Error> {
+        let versions = self.versions(txn, key)?;
+        let previous = versions.first().and_then(|version| version.value.clone());
+        if value.is_none() && previous.is_none() {
+            return Ok(None);
+        }
+        // 古い版のうち、新しい版が見えない他のスナップショットから読まれるものだけを残す
+        // 書いたトランザクションはすぐにコミットするので、自分より前に始まった版は全てコミット済みで、
+        // 他のスナップショットからは、それより前に書かれた一番新しい版が見える
+        let mut keep = vec![false; versions.len()];
+        for reader in self.txn_mgr.active_txn_ids() {
+            if reader == txn.id() {
+                continue;
+            }
+            if let Some(pos) = versions.iter().position(|version| version.txn_id < reader) {
+                keep[pos] = true;
+            }
+        }
+        // 削除した印は、それより古い版が残っている時だけ要る(古い版が無ければ、印が無くても見えないのと同じ)
+        // 古い版が残っているのに印を消すと、印が見えていたスナップショットから古い版が見えてしまう
+        let mut older_kept = false;
+        for (version, keep) in versions.iter().zip(keep.iter_mut()).rev() {
+            if version.value.is_none() && !older_kept {
+                *keep = false;
+            }
+            older_kept |= *keep;
+        }
+        let mut kept = 0;
+        for (version, keep) in versions.iter().zip(keep) {
+            // 同じトランザクション(バッチ)の中で書いた版は上書きする
+            if keep && version.txn_id != txn.id() {
+                kept += 1;
+            } else {
+                self.tree.delete(&mut self.bufmgr, txn, &version.tree_key)?;
+            }
+        }
+        // 削除した印は、古い版が残っている時だけ書く
+        if value.is_some() || kept > 0 {
+            self.tree.insert(&mut self.bufmgr, txn, &version_key(key, txn.id()), &encode_version(value))?;
+        }
+        Ok(previous)
+    }
+
+    // fromから順に、txnのスナップショットから見えるキーと値を最大でlimit個読む
+    // skipは読み飛ばすキー(前回最後に返したキー、またはBound::Excludedの開始位置)
+    // 読み終わったらtrueを返す
+    fn read_batch(
+        &mut self,
+        txn: &Transaction,
+        from: SearchMode,
+        skip: Option<&[u8]>,
+        end: &Bound<Vec<u8>>,
+        limit: usize,
+        out: &mut VecDeque<(Vec<u8>, Vec<u8>)>,
+    ) -> Result<bool, Error> {
+        let mut iter = self.tree.iter(&mut self.bufmgr, txn, from)?;
+        // 見える版を探し終わったキー
+        let mut resolved: Option<Vec<u8>> = None;
+        let mut count = 0;
+        while let Some((tree_key, value)) = iter.next(&mut self.bufmgr)? {
+            let (key, txn_id) = decode_version_key(&tree_key)?;
+            if Some(key.as_slice()) == skip {
+                continue;
+            }
+            let past_end = match end {
+                Bound::Included(end) => key > *end,
+                Bound::Excluded(end) => key >= *end,
+                Bound::Unbounded => false,
+            };
+            if past_end {
+                return Ok(true);
+            }
+            if Some(&key) == resolved.as_ref() || !txn.snapshot().sees(txn_id) {
+                continue;
+            }
+            if let Some(value) = decode_version(value)? {
+                out.push_back((key.clone(), value));
+                count += 1;
+            }
+            resolved = Some(key);
+            if count == limit {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        // コミットした内容はWALに残っているので、書き出せなくてもリカバリで戻る
+        let _ = wal::checkpoint(&mut self.bufmgr);
+    }
+}
+
+/// キーバリューストア
+/// 複製すると同じストアを指す
+#[derive(Clone)]
+pub struct Db {
+    inner: Rc<RefCell<Inner>>,
+}
+
+impl Db {
+    /// ファイルを開く(無ければ作る)。前回クラッシュしていればリカバリする
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, Error> {
+        Self::open_with_pool_size(path, DEFAULT_POOL_SIZE)
+    }
+
+    /// バッファプールのページ数を指定して開く
+    pub fn open_with_pool_size(path: impl AsRef<Path>, pool_size: usize) -> Result<Self, Error> {
+        let (bufmgr, txn_mgr, _) = transaction::open(path, pool_size)?;
+        Self::start(bufmgr, txn_mgr)
+    }
+
+    /// 開いたヒープファイルとログファイル(仮想ディスクなど)を使う
+    pub fn open_with(disk: DiskManager, wal: LogManager, pool_size: usize) -> Result<Self, Error> {
+        let (bufmgr, txn_mgr, _) = transaction::open_with(disk, wal, pool_size)?;
+        Self::start(bufmgr, txn_mgr)
+    }
+
+    fn start(mut bufmgr: BufferPoolManager, txn_mgr: TransactionManager) -> Result<Self, Error> {
+        let txn = txn_mgr.begin()?;
+        let tree = Inner::bootstrap(&mut bufmgr, &txn)?;
+        txn_mgr.commit(txn)?;
+        Ok(Self {
+            inner: Rc::new(RefCell::new(Inner { bufmgr, txn_mgr, tree })),
+        })
+    }
+
+    /// keyの値を返す
+    pub fn get(&self, key: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>, Error> {
+        let inner = &mut *self.inner.borrow_mut();
+        let txn = inner.txn_mgr.begin_read_only();
+        let result = inner.get(&txn, key.as_ref());
+        inner.txn_mgr.finish_read_only(txn);
+        result
+    }
+
+    pub fn contains_key(&self, key: impl AsRef<[u8]>) -> Result<bool, Error> {
+        Ok(self.get(key)?.is_some())
+    }
+
+    /// keyにvalueを書き、前の値を返す
+    pub fn put(&self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>, Error> {
+        self.transact(|inner, txn| inner.write(txn, key.as_ref(), Some(value.as_ref())))
+    }
+
+    /// keyを削除し、前の値を返す
+    pub fn delete(&self, key: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>, Error> {
+        self.transact(|inner, txn| inner.write(txn, key.as_ref(), None))
+    }
+
+    /// バッチの書き込みを全て行う。途中でエラーになれば1つも行わない
+    pub fn apply_batch(&self, batch: Batch) -> Result<(), Error> {
+        self.transact(|inner, txn| {
+            for (key, value) in &batch.writes {
+                inner.write(txn, key, value.as_deref())?;
+            }
+            Ok(())
+        })
+    }
+
+    // 1つのトランザクションでfを実行し、成功すればコミット、失敗すればロールバックする
+    fn transact<T>(&self, f: impl FnOnce(&mut Inner, &Transaction) -> Result<T, Error>) -> Result<T, Error> {
+        let inner = &mut *self.inner.borrow_mut();
+        let txn = inner.txn_mgr.begin()?;
+        let result = match f(inner, &txn) {
+            Ok(result) => {
+                inner.txn_mgr.commit(txn)?;
+                Ok(result)
+            }
+            Err(err) => {
+                inner.txn_mgr.rollback(&mut inner.bufmgr, txn)?;
+                Err(err)
+            }
+        };
+        inner.txn_mgr.checkpoint_if_needed(&mut inner.bufmgr)?;
+        result
+    }
+
+    /// 今の内容のスナップショットを取る
+    /// スナップショットを持っている間は、それより古い版を回収できない
+    pub fn snapshot(&self) -> Snapshot {
+        let txn = self.inner.borrow().txn_mgr.begin_read_only();
+        Snapshot(Rc::new(SnapshotState {
+            inner: Rc::clone(&self.inner),
+            txn: Some(txn),
+        }))
+    }
+
+    /// rangeに入るキーと値をキーの順に返すイテレータ
+    /// イテレータを作った時点のスナップショットを読むので、途中で書き込んでも結果は変わらない
+    pub fn range<K: AsRef<[u8]>, R: RangeBounds<K>>(&self, range: R) -> Iter {
+        self.snapshot().range(range)
+    }
+
+    /// prefixで始まるキーと値をキーの順に返すイテレータ
+    pub fn scan_prefix(&self, prefix: impl AsRef<[u8]>) -> Iter {
+        self.snapshot().scan_prefix(prefix)
+    }
+
+    /// 全てのキーと値をキーの順に返すイテレータ
+    pub fn iter(&self) -> Iter {
+        self.snapshot().iter()
+    }
+
+    /// バッファプールのページを全てファイルに書き出し、チェックポイントを取ってWALを切り詰める
+    /// コミットした書き込みはWALに残っているので、呼ばなくてもクラッシュから復旧できる
+    pub fn flush(&self) -> Result<(), Error> {
+        wal::checkpoint(&mut self.inner.borrow_mut().bufmgr)?;
+        Ok(())
+    }
+}
+
+/// まとめて書き込む操作
+/// apply_batchで、全て行うか1つも行わないかのどちらかになる
+#[derive(Debug, Default, Clone)]
+pub struct Batch {
+    pub(crate) writes: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+}
+
+impl Batch {
+    pub fn put(&mut self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) {
+        self.writes.push((key.as_ref().to_vec(), Some(value.as_ref().to_vec())));
+    }
+
+    pub fn delete(&mut self, key: impl AsRef<[u8]>) {
+        self.writes.push((key.as_ref().to_vec(), None));
+    }
+}
+
+struct SnapshotState {
+    inner: Rc<RefCell<Inner>>,
+    txn: Option<Transaction>,
+}
+
+impl SnapshotState {
+    fn txn(&self) -> &Transaction {
+        self.txn.as_ref().unwrap()
+    }
+}
+
+impl Drop for SnapshotState {
+    fn drop(&mut self) {
+        if let Some(txn) = self.txn.take() {
+            self.inner.borrow().txn_mgr.finish_read_only(txn);
+        }
+    }
+}
+
+/// ある時点の内容を読むスナップショット
+/// 複製したスナップショットや、スナップショットから作ったイテレータも同じ時点を読む
+#[derive(Clone)]
+pub struct Snapshot(Rc<SnapshotState>);
+
+impl Snapshot {
+    pub fn get(&self, key: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>, Error> {
+        let inner = &mut *self.0.inner.borrow_mut();
+        inner.get(self.0.txn(), key.as_ref())
+    }
+
+    pub fn range<K: AsRef<[u8]>, R: RangeBounds<K>>(&self, range: R) -> Iter {
+        let (from, skip) = match range.start_bound() {
+            Bound::Included(key) => (Bound::Included(key.as_ref().to_vec()), None),
+            Bound::Excluded(key) => (Bound::Included(key.as_ref().to_vec()), Some(key.as_ref().to_vec())),
+            Bound::Unbounded => (Bound::Unbounded, None),
+        };
+        Iter {
+            snapshot: Rc::clone(&self.0),
+            from,
+            skip,
+            end: to_owned_bound(range.end_bound()),
+            buffer: VecDeque::new(),
+            done: false,
+        }
+    }
+
+    pub fn scan_prefix(&self, prefix: impl AsRef<[u8]>) -> Iter {
+        let prefix = prefix.as_ref();
+        match prefix_end(prefix) {
+            Some(end) => self.range(prefix.to_vec()..end),
+            None => self.range(prefix.to_vec()..),
+        }
+    }
+
+    pub fn iter(&self) -> Iter {
+        self.range::<&[u8], _>(..)
+    }
+}
+
+/// キーと値をキーの順に返すイテレータ
+/// 読み進めるたびにB+treeを探し直すので、途中で書き込んでページが分割されても読み飛ばさない
+pub struct Iter {
+    snapshot: Rc<SnapshotState>,
+    // 次に探し始めるキー
+    from: Bound<Vec<u8>>,
+    skip: Option<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+    buffer: VecDeque<(Vec<u8>, Vec<u8>)>,
+    done: bool,
+}
+
+impl Iter {
+    fn fill(&mut self) -> Result<(), Error> {
+        let mode = match &self.from {
+            Bound::Included(key) => {
+                let mut start = vec![];
+                memcmpable::encode_bytes(key, &mut start);
+                SearchMode::Key(start)
+            }
+            _ => SearchMode::Start,
+        };
+        let inner = &mut *self.snapshot.inner.borrow_mut();
+        let before = self.buffer.len();
+        self.done = inner.read_batch(self.snapshot.txn(), mode, self.skip.as_deref(), &self.end, ITER_BATCH_SIZE, &mut self.buffer)?;
+        // 次は最後に読んだキーから探し直し、そのキー自体は読み飛ばす
+        if self.buffer.len() > before {
+            let key = self.buffer.back().unwrap().0.clone();
+            self.from = Bound::Included(key.clone());
+            self.skip = Some(key);
+        }
+        Ok(())
+    }
+}
+
+impl Iterator for Iter {
+    type Item = Result<(Vec<u8>, Vec<u8>), Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.buffer.is_empty() && !self.done {
+            if let Err(err) = self.fill() {
+                self.done = true;
+                return Some(Err(err));
+            }
+        }
+        self.buffer.pop_front().map(Ok)
+    }
+}
+
+/// キーバリューストアの共通の操作
+/// ページを使うDbと、LSM-treeのlsm::Dbを同じコードで使い比べられるようにする
+pub trait KvStore {
+    type Iter: Iterator<Item = Result<(Vec<u8>, Vec<u8>), Error>>;
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Error>;
+    fn put(&self, key: &[u8], value: &[u8]) -> Result<(), Error>;
+    fn delete(&self, key: &[u8]) -> Result<(), Error>;
+    fn apply_batch(&self, batch: Batch) -> Result<(), Error>;
+    fn range(&self, start: Bound<&[u8]>, end: Bound<&[u8]>) -> Self::Iter;
+    fn scan_prefix(&self, prefix: &[u8]) -> Self::Iter;
+    fn flush(&self) -> Result<(), Error>;
+}
+
+impl KvStore for Db {
+    type Iter = Iter;
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        Db::get(self, key)
+    }
+
+    fn put(&self, key: &[u8], value: &[u8]) -> Result<(), Error> {
+        Db::put(self, key, value).map(|_| ())
+    }
+
+    fn delete(&self, key: &[u8]) -> Result<(), Error> {
+        Db::delete(self, key).map(|_| ())
+    }
+
+    fn apply_batch(&self, batch: Batch) -> Result<(), Error> {
+        Db::apply_batch(self, batch)
+    }
+
+    fn range(&self, start: Bound<&[u8]>, end: Bound<&[u8]>) -> Iter {
+        Db::range::<&[u8], _>(self, (start, end))
+    }
+
+    fn scan_prefix(&self, prefix: &[u8]) -> Iter {
+        Db::scan_prefix(self, prefix)
+    }
+
+    fn flush(&self) -> Result<(), Error> {
+        Db::flush(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("kv_test_{}_{}", std::process::id(), name));
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(wal::log_path(&path));
+        path
+    }
+
+    fn keys(iter: Iter) -> Vec<Vec<u8>> {
+        iter.map(|pair| pair.unwrap().0).collect()
+    }
+
+    #[test]
+    fn test_get_put_delete() {
+        let path = temp_path("basic");
+        let db = Db::open_with_pool_size(&path, 16).unwrap();
+        assert_eq!(db.get(b"a").unwrap(), None);
+        assert_eq!(db.put(b"a", b"1").unwrap(), None);
+        assert_eq!(db.put(b"a", b"2").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(db.get(b"a").unwrap(), Some(b"2".to_vec()));
+        assert_eq!(db.delete(b"a").unwrap(), Some(b"2".to_vec()));
+        assert_eq!(db.delete(b"a").unwrap(), None);
+        assert!(!db.contains_key(b"a").unwrap());
+        // 空のキーや大きな値も置ける
+        db.put(b"", b"empty").unwrap();
+        db.put(b"big", vec![7u8; 20000]).unwrap();
+        assert_eq!(db.get(b"").unwrap(), Some(b"empty".to_vec()));
+        assert_eq!(db.get(b"big").unwrap(), Some(vec![7u8; 20000]));
+        assert!(matches!(db.put(vec![0u8; 2000], b"x"), Err(Error::TooLarge)));
+
+        // スナップショットが無ければ古い版はすぐに回収され、削除したキーは何も残らない
+        for i in 0..100u32 {
+            db.put(b"counter", i.to_be_bytes()).unwrap();
+        }
+        let versions = |db: &Db, key: &[u8]| {
+            let inner = &mut *db.inner.borrow_mut();
+            let txn = inner.txn_mgr.begin_read_only();
+            let count = inner.versions(&txn, key).unwrap().len();
+            inner.txn_mgr.finish_read_only(txn);
+            count
+        };
+        assert_eq!(versions(&db, b"counter"), 1);
+        assert_eq!(versions(&db, b"a"), 0);
+
+        // スナップショットが読む版だけが残る
+        let snapshot = db.snapshot();
+        db.put(b"counter", b"x").unwrap();
+        db.put(b"counter", b"y").unwrap();
+        db.delete(b"counter").unwrap();
+        assert_eq!(versions(&db, b"counter"), 2);
+        assert_eq!(snapshot.get(b"counter").unwrap(), Some(99u32.to_be_bytes().to_vec()));
+        drop(snapshot);
+        db.put(b"counter", b"z").unwrap();
+        assert_eq!(versions(&db, b"counter"), 1);
+    }
+
+    #[test]
+    fn test_range_and_prefix() {
+        let path = temp_path("range");
+        let db = Db::open_with_pool_size(&path, 16).unwrap();
+        for i in 0..500u32 {
+            db.put(format!("key{:04}", i), i.to_string()).unwrap();
+        }
+        db.put(b"other", b"x").unwrap();
+        db.put(b"key\xff", b"y").unwrap();
+        db.delete(b"key0003").unwrap();
+
+        let all = keys(db.iter());
+        assert_eq!(all.len(), 501);
+        assert!(all.windows(2).all(|w| w[0] < w[1]));
+        assert_eq!(keys(db.scan_prefix(b"key000")), (0..10).filter(|&i| i != 3).map(|i| format!("key{:04}", i).into_bytes()).collect::<Vec<_>>());
+        assert_eq!(keys(db.scan_prefix(b"key")).len(), 500);
+        assert_eq!(keys(db.range(b"key0100".to_vec()..b"key0200".to_vec())).len(), 100);
+        assert_eq!(keys(db.range(b"key0100".to_vec()..=b"key0200".to_vec())).len(), 101);
+        let after: Vec<_> = keys(db.range((Bound::Excluded(b"key0497".to_vec()), Bound::Unbounded)));
+        assert_eq!(after, vec![b"key0498".to_vec(), b"key0499".to_vec(), b"key\xff".to_vec(), b"other".to_vec()]);
+        assert_eq!(prefix_end(b"a\xff"), Some(b"b".to_vec()));
+        assert_eq!(prefix_end(b"\xff\xff"), None);
+    }
+
+    #[test]
+    fn test_snapshot_and_batch() {
+        let path = temp_path("snapshot");
+        let db = Db::open_with_pool_size(&path, 16).unwrap();
+        db.put(b"a", b"1").unwrap();
+        db.put(b"b", b"1").unwrap();
+        let snapshot = db.snapshot();
+        let mut iter = db.iter();
+        assert_eq!(iter.next().unwrap().unwrap(), (b"a".to_vec(), b"1".to_vec()));
+
+        let mut batch = Batch::default();
+        batch.put(b"a", b"2");
+        batch.delete(b"b");
+        batch.put(b"c", b"2");
+        batch.put(b"c", b"3");
+        db.apply_batch(batch).unwrap();
+        assert_eq!(db.get(b"a").unwrap(), Some(b"2".to_vec()));
+        assert_eq!(db.get(b"b").unwrap(), None);
+        assert_eq!(db.get(b"c").unwrap(), Some(b"3".to_vec()));
+
+        // スナップショットとイテレータは書き込む前の内容を読む
+        assert_eq!(snapshot.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(snapshot.get(b"c").unwrap(), None);
+        assert_eq!(keys(snapshot.iter()), vec![b"a".to_vec(), b"b".to_vec()]);
+        assert_eq!(iter.next().unwrap().unwrap(), (b"b".to_vec(), b"1".to_vec()));
+        assert!(iter.next().is_none());
+        // スナップショットを持っている間は古い版が残る
+        db.put(b"a", b"3").unwrap();
+        assert_eq!(snapshot.get(b"a").unwrap(), Some(b"1".to_vec()));
+        // 削除した印が見えるスナップショットには、古い版を残していても無いままに見える
+        let deleted = db.snapshot();
+        db.put(b"b", b"2").unwrap();
+        assert_eq!(deleted.get(b"b").unwrap(), None);
+        assert_eq!(snapshot.get(b"b").unwrap(), Some(b"1".to_vec()));
+        drop(deleted);
+        drop(snapshot);
+        drop(iter);
+
+        // 途中で失敗したバッチは1つも書き込まない
+        let mut batch = Batch::default();
+        batch.put(b"d", b"1");
+        batch.put(vec![0u8; 2000], b"too large key");
+        assert!(db.apply_batch(batch).is_err());
+        assert_eq!(db.get(b"d").unwrap(), None);
+        assert_eq!(keys(db.iter()), vec![b"a".to_vec(), b"b".to_vec(), b"c".to_vec()]);
+    }
+
+    #[test]
+    fn test_recover() {
+        let path = temp_path("recover");
+        {
+            let db = Db::open_with_pool_size(&path, 16).unwrap();
+            for i in 0..300u32 {
+                db.put(i.to_be_bytes(), vec![i as u8; 100]).unwrap();
+            }
+            db.delete(7u32.to_be_bytes()).unwrap();
+            // ページを書き出さずにクラッシュする
+            std::mem::forget(db);
+        }
+        let db = Db::open_with_pool_size(&path, 16).unwrap();
+        assert_eq!(db.iter().count(), 299);
+        assert_eq!(db.get(8u32.to_be_bytes()).unwrap(), Some(vec![8u8; 100]));
+        assert_eq!(db.get(7u32.to_be_bytes()).unwrap(), None);
+        drop(db);
+
+        // KVストアでないファイルは開けない
+        let sql = temp_path("not_kv");
+        drop(crate::catalog::open(&sql, 16).unwrap());
+        assert!(Db::open(&sql).is_err());
+    }
+}
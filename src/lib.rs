@@ -1,2 +1,65 @@
+// ライブラリクレートのルート
+// 各binから`practice::モジュール名`で使えるようにモジュールを公開する
 
+// 順序を保ったまま値をバイト列に変換する(B+treeのキーなどに使う)
+pub mod memcmpable;
 
+// ストレージエンジン
+// DiskManager/BufferPoolはディレクトリごとに分けているので、#[path]でファイルの場所を指定する
+#[path = "DiskManager/main.rs"]
+pub mod disk_manager;
+#[path = "BufferPool/main.rs"]
+pub mod buffer_pool;
+// バッファプールのページアクセスの記録と再生
+pub mod page_trace;
+// Write-Ahead Logとクラッシュリカバリ
+pub mod wal;
+// ストレージ層で共通のエラー
+pub mod error;
+// スロット付きページ
+pub mod slotted;
+// 大きな値を置くオーバーフローページ
+pub mod overflow;
+// ヒープテーブルの空き領域マップ
+pub mod fsm;
+// ヒープテーブル
+pub mod heap;
+// B+treeインデックス
+pub mod btree;
+// 拡張ハッシュインデックス
+pub mod hash_index;
+// トランザクション
+pub mod transaction;
+// ロックマネージャー
+pub mod lock;
+// システムカタログ
+pub mod catalog;
+// 式とクエリの実行器
+pub mod expr;
+pub mod executor;
+// メモリに入りきらない行の並び替え
+pub mod external_sort;
+// 統計情報とコストベースのプランナー
+pub mod planner;
+// CSVの読み書き
+pub mod csv;
+// 組み込みのキーバリューストア
+pub mod kv;
+// ブルームフィルター
+pub mod bloom;
+// LSM-treeのソート済みファイル(SSTable)
+pub mod sstable;
+// LSM-treeのキーバリューストア
+pub mod lsm;
+// SQLの構文解析と実行
+pub mod sql;
+// TCP・Unixドメインソケットで複数のプロセスからSQLを受け付けるサーバーとクライアント
+pub mod server;
+// 正規表現エンジン(grepで使う)
+pub mod regex;
+// ディレクトリを再帰的に辿って検索するファイルを列挙する(.gitignore対応)
+pub mod walk;
+// grepコマンドの引数の解析と検索
+pub mod grep;
+// 決定的シミュレーションテスト(シード付きスケジューラー・仮想ディスク・仮想時計)
+pub mod sim;
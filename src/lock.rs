@@ -0,0 +1,466 @@
+// ロックマネージャー
+// シリアライザブルなトランザクションのために、テーブルとレコードにロックを掛ける(厳密な2相ロック)
+// ロックはコミットかロールバックが終わるまで持ち続け、最後にまとめて解放する
+//
+// ロックの種類
+// - S(共有)とX(排他)：読む・書くためのロック
+// - IS・IX・SIX(インテンションロック)：テーブルの中のレコードにS・Xを掛けるつもりであることを示す
+//   レコードにロックを掛ける前に、テーブルにインテンションロックを掛ける
+//   こうするとテーブル全体へのS・Xと、個々のレコードへのロックが衝突するかをテーブルだけで判断できる
+//
+// 待ち行列
+// - ロックは申し込んだ順(FIFO)に与える。前に待っている人がいれば、掛けられるロックでも追い越さない
+// - 既に持っているロックを強くする(アップグレード)場合は、待っている人より先に与える
+//
+// デッドロック検出
+// 待つ前にwaits-forグラフ(誰が誰を待っているか)を作って閉路を探す
+// 閉路があれば、その中で一番新しいトランザクションを犠牲にしてError::Deadlockを返す
+// 犠牲になったトランザクションはロールバックしてunlock_allを呼ぶ
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::{Condvar, Mutex};
+
+use crate::buffer_pool::Error;
+use crate::disk_manager::PageId;
+use crate::heap::RecordId;
+use crate::wal::TxnId;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockMode {
+    IntentionShared,
+    IntentionExclusive,
+    Shared,
+    SharedIntentionExclusive,
+    Exclusive,
+}
+
+use LockMode::*;
+
+impl LockMode {
+    /// 別のトランザクションがotherを持っていても、selfを掛けられるか
+    ///
+    /// |     | IS | IX | S  | SIX | X  |
+    /// |-----|----|----|----|-----|----|
+    /// | IS  | o  | o  | o  | o   |    |
+    /// | IX  | o  | o  |    |     |    |
+    /// | S   | o  |    | o  |     |    |
+    /// | SIX | o  |    |    |     |    |
+    /// | X   |    |    |    |     |    |
+    pub fn compatible(self, other: LockMode) -> bool {
+        match (self, other) {
+            (Exclusive, _) | (_, Exclusive) => false,
+            (IntentionShared, _) | (_, IntentionShared) => true,
+            (IntentionExclusive, IntentionExclusive) | (Shared, Shared) => true,
+            _ => false,
+        }
+    }
+
+    /// selfを持っていれば、otherを持っているのと同じかそれ以上か
+    pub fn covers(self, other: LockMode) -> bool {
+        match (self, other) {
+            (Exclusive, _) => true,
+            (SharedIntentionExclusive, mode) => mode != Exclusive,
+            (Shared, Shared) | (Shared, IntentionShared) => true,
+            (IntentionExclusive, IntentionExclusive) | (IntentionExclusive, IntentionShared) => true,
+            (IntentionShared, IntentionShared) => true,
+            _ => false,
+        }
+    }
+
+    /// selfとotherの両方を含む一番弱いロック(アップグレード先)
+    pub fn supremum(self, other: LockMode) -> LockMode {
+        if self.covers(other) {
+            self
+        } else if other.covers(self) {
+            other
+        } else if matches!((self, other), (Shared, IntentionExclusive) | (IntentionExclusive, Shared)) {
+            SharedIntentionExclusive
+        } else {
+            Exclusive
+        }
+    }
+}
+
+/// ロックを掛ける対象
+/// テーブルは先頭ページのIDで区別する
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Resource {
+    Table(PageId),
+    Record(RecordId),
+}
+
+#[derive(Debug)]
+struct Request {
+    txn_id: TxnId,
+    mode: LockMode,
+    granted: bool,
+    // 持っているロックを強くしようとして待っている
+    upgrade: Option<LockMode>,
+}
+
+impl Request {
+    fn waiting(&self) -> bool {
+        !self.granted || self.upgrade.is_some()
+    }
+
+    // 待っている場合に欲しいロック
+    fn wanted(&self) -> LockMode {
+        self.upgrade.unwrap_or(self.mode)
+    }
+}
+
+#[derive(Debug, Default)]
+struct LockState {
+    // 対象ごとの待ち行列。与えたロックが先頭に並び、その後ろに待っている申し込みが並ぶ
+    queues: HashMap<Resource, VecDeque<Request>>,
+    // トランザクションごとに、ロックを持っている対象
+    held: HashMap<TxnId, HashSet<Resource>>,
+    // デッドロックの犠牲に選ばれたが、まだ起きていないトランザクション
+    victims: HashSet<TxnId>,
+}
+
+impl LockState {
+    // txn_idの申し込みにロックを与えられるなら与える
+    fn try_grant(&mut self, resource: Resource, txn_id: TxnId) -> bool {
+        let queue = self.queues.get_mut(&resource).unwrap();
+        let pos = queue.iter().position(|req| req.txn_id == txn_id).unwrap();
+        let wanted = queue[pos].wanted();
+        let compatible = queue
+            .iter()
+            .enumerate()
+            .all(|(i, req)| i == pos || !req.granted || wanted.compatible(req.mode));
+        let grantable = if queue[pos].upgrade.is_some() {
+            compatible
+        } else {
+            // 前に待っている人やアップグレード待ちがいれば追い越さない
+            compatible && queue.iter().take(pos).all(|req| !req.waiting()) && queue.iter().all(|req| req.upgrade.is_none())
+        };
+        if grantable {
+            let req = &mut queue[pos];
+            req.mode = wanted;
+            req.granted = true;
+            req.upgrade = None;
+            self.held.entry(txn_id).or_default().insert(resource);
+        }
+        grantable
+    }
+
+    // 待つのをやめる。アップグレードなら元のロックは持ったまま
+    fn cancel(&mut self, resource: Resource, txn_id: TxnId) {
+        let queue = self.queues.get_mut(&resource).unwrap();
+        let pos = queue.iter().position(|req| req.txn_id == txn_id).unwrap();
+        if queue[pos].granted {
+            queue[pos].upgrade = None;
+        } else {
+            queue.remove(pos);
+            if queue.is_empty() {
+                self.queues.remove(&resource);
+            }
+        }
+    }
+
+    // waits-forグラフ: 待っているトランザクション → 待たされている相手
+    fn waits_for(&self) -> HashMap<TxnId, Vec<TxnId>> {
+        let mut graph: HashMap<TxnId, Vec<TxnId>> = HashMap::new();
+        for queue in self.queues.values() {
+            for (pos, waiter) in queue.iter().enumerate() {
+                if !waiter.waiting() || self.victims.contains(&waiter.txn_id) {
+                    continue;
+                }
+                let wanted = waiter.wanted();
+                for (i, other) in queue.iter().enumerate() {
+                    if i == pos || self.victims.contains(&other.txn_id) {
+                        continue;
+                    }
+                    let blocks = if other.granted && !wanted.compatible(other.mode) {
+                        true
+                    } else if waiter.granted {
+                        // アップグレードは、持っているロックとぶつかる相手だけを待つ
+                        false
+                    } else {
+                        // 新しい申し込みは、前に並んでいる人とアップグレード待ちも待つ
+                        (i < pos && other.waiting()) || other.upgrade.is_some()
+                    };
+                    if blocks {
+                        graph.entry(waiter.txn_id).or_default().push(other.txn_id);
+                    }
+                }
+            }
+        }
+        graph
+    }
+
+    // startを含む閉路を探し、見つかればその中で一番新しいトランザクションを返す
+    fn find_victim(&self, start: TxnId) -> Option<TxnId> {
+        let graph = self.waits_for();
+        let mut path = vec![start];
+        let mut visited = HashSet::new();
+        if find_cycle(&graph, start, &mut path, &mut visited) {
+            path.into_iter().max()
+        } else {
+            None
+        }
+    }
+}
+
+// 深さ優先探索でpathの先頭に戻る閉路を探す
+fn find_cycle(graph: &HashMap<TxnId, Vec<TxnId>>, node: TxnId, path: &mut Vec<TxnId>, visited: &mut HashSet<TxnId>) -> bool {
+    visited.insert(node);
+    for &next in graph.get(&node).into_iter().flatten() {
+        if next == path[0] {
+            return true;
+        }
+        if !visited.contains(&next) {
+            path.push(next);
+            if find_cycle(graph, next, path, visited) {
+                return true;
+            }
+            path.pop();
+        }
+    }
+    false
+}
+
+/// 複数スレッドから共有できるよう、内部の状態はMutexで守る
+#[derive(Debug, Default)]
+pub struct LockManager {
+    state: Mutex<LockState>,
+    // ロックが解放されたり、デッドロックの犠牲が選ばれたりしたら待っているスレッドを起こす
+    changed: Condvar,
+}
+
+impl LockManager {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// ロックを掛ける。掛けられるまで待つ
+    /// 既に持っているロックより強いロックを申し込むとアップグレードになる
+    /// デッドロックの犠牲に選ばれたらError::Deadlockを返すので、ロールバックしてunlock_allを呼ぶ
+    pub fn lock(&self, txn_id: TxnId, resource: Resource, mode: LockMode) -> Result<(), Error> {
+        let mut state = self.state.lock().unwrap();
+        let queue = state.queues.entry(resource).or_default();
+        match queue.iter_mut().find(|req| req.txn_id == txn_id) {
+            Some(req) if req.mode.covers(mode) => return Ok(()),
+            Some(req) => req.upgrade = Some(req.mode.supremum(mode)),
+            None => queue.push_back(Request {
+                txn_id,
+                mode,
+                granted: false,
+                upgrade: None,
+            }),
+        }
+        loop {
+            if state.victims.remove(&txn_id) {
+                state.cancel(resource, txn_id);
+                self.changed.notify_all();
+                return Err(Error::Deadlock);
+            }
+            if state.try_grant(resource, txn_id) {
+                // 後ろに並んでいる申し込み(共有ロックなど)も、自分が取れたことで取れるようになるかもしれない
+                self.changed.notify_all();
+                return Ok(());
+            }
+            if let Some(victim) = state.find_victim(txn_id) {
+                state.victims.insert(victim);
+                // 自分が犠牲なら次のループで諦める。他人なら起こして諦めさせる
+                if victim == txn_id {
+                    continue;
+                }
+                self.changed.notify_all();
+            }
+            state = self.changed.wait(state).unwrap();
+        }
+    }
+
+    /// レコードにロックを掛ける
+    /// 先にテーブルへインテンションロック(SならIS、それ以外はIX)を掛ける
+    pub fn lock_record(&self, txn_id: TxnId, table: PageId, rid: RecordId, mode: LockMode) -> Result<(), Error> {
+        let intention = if mode == Shared { IntentionShared } else { IntentionExclusive };
+        self.lock(txn_id, Resource::Table(table), intention)?;
+        self.lock(txn_id, Resource::Record(rid), mode)
+    }
+
+    /// txn_idが持っているロックを全て解放する(コミット・ロールバックの最後に呼ぶ)
+    pub fn unlock_all(&self, txn_id: TxnId) {
+        let mut state = self.state.lock().unwrap();
+        for resource in state.held.remove(&txn_id).unwrap_or_default() {
+            let queue = state.queues.get_mut(&resource).unwrap();
+            queue.retain(|req| req.txn_id != txn_id);
+            if queue.is_empty() {
+                state.queues.remove(&resource);
+            }
+        }
+        self.changed.notify_all();
+    }
+
+    /// txn_idがresourceに持っているロック
+    pub fn held(&self, txn_id: TxnId, resource: Resource) -> Option<LockMode> {
+        let state = self.state.lock().unwrap();
+        let queue = state.queues.get(&resource)?;
+        queue.iter().find(|req| req.txn_id == txn_id && req.granted).map(|req| req.mode)
+    }
+
+    /// resourceのロックを待っているトランザクション(申し込んだ順)
+    pub fn waiting(&self, resource: Resource) -> Vec<TxnId> {
+        let state = self.state.lock().unwrap();
+        let queue = state.queues.get(&resource);
+        queue.into_iter().flatten().filter(|req| req.waiting()).map(|req| req.txn_id).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::sync::{Arc, Barrier};
+    use std::thread;
+
+    fn table(n: u64) -> Resource {
+        Resource::Table(PageId(n))
+    }
+
+    // 別のスレッドの申し込みがresourceの待ち行列にcount件並ぶまで待つ
+    fn wait_queued(locks: &LockManager, resource: Resource, count: usize) {
+        while locks.waiting(resource).len() < count {
+            thread::yield_now();
+        }
+    }
+
+    #[test]
+    fn test_compatibility_and_upgrade() {
+        assert!(IntentionShared.compatible(SharedIntentionExclusive));
+        assert!(!IntentionExclusive.compatible(Shared));
+        assert_eq!(Shared.supremum(IntentionExclusive), SharedIntentionExclusive);
+        assert_eq!(IntentionShared.supremum(Exclusive), Exclusive);
+
+        let locks = LockManager::new();
+        locks.lock(TxnId(1), table(0), Shared).unwrap();
+        locks.lock(TxnId(2), table(0), Shared).unwrap();
+        // 弱いロックを申し込んでも持っているロックはそのまま
+        locks.lock(TxnId(1), table(0), IntentionShared).unwrap();
+        assert_eq!(locks.held(TxnId(1), table(0)), Some(Shared));
+        locks.unlock_all(TxnId(2));
+        locks.lock(TxnId(1), table(0), Exclusive).unwrap();
+        assert_eq!(locks.held(TxnId(1), table(0)), Some(Exclusive));
+    }
+
+    #[test]
+    fn test_fifo() {
+        let locks = Arc::new(LockManager::new());
+        let (granted_tx, granted) = mpsc::channel();
+        locks.lock(TxnId(1), table(0), Exclusive).unwrap();
+        let mut handles = vec![];
+        for (i, (txn_id, mode)) in [(2, Exclusive), (3, Shared), (4, Shared)].iter().copied().enumerate() {
+            let waiter = Arc::clone(&locks);
+            let granted_tx = granted_tx.clone();
+            // ロックを取れたら知らせ、releaseを受け取るまで持ち続ける
+            let (release_tx, release) = mpsc::channel::<()>();
+            handles.push((
+                release_tx,
+                thread::spawn(move || {
+                    waiter.lock(TxnId(txn_id), table(0), mode).unwrap();
+                    granted_tx.send(txn_id).unwrap();
+                    release.recv().unwrap();
+                    waiter.unlock_all(TxnId(txn_id));
+                }),
+            ));
+            // 申し込む順番を決めるため、並ぶまで待つ
+            wait_queued(&locks, table(0), i + 1);
+        }
+        assert!(granted.try_recv().is_err());
+
+        // Sを申し込んだ3と4は、先に並んだXの2を追い越さない
+        locks.unlock_all(TxnId(1));
+        assert_eq!(granted.recv().unwrap(), 2);
+        assert!(granted.try_recv().is_err());
+        handles[0].0.send(()).unwrap();
+        let mut shared = vec![granted.recv().unwrap(), granted.recv().unwrap()];
+        shared.sort();
+        assert_eq!(shared, vec![3, 4]);
+        for (release, handle) in handles {
+            let _ = release.send(());
+            handle.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_intention_locks() {
+        let locks = LockManager::new();
+        let rid = |slot| RecordId { page_id: PageId(1), slot };
+        // 別々のレコードを書くトランザクションは、テーブルではぶつからない
+        locks.lock_record(TxnId(1), PageId(0), rid(0), Exclusive).unwrap();
+        locks.lock_record(TxnId(2), PageId(0), rid(1), Exclusive).unwrap();
+        locks.lock_record(TxnId(3), PageId(0), rid(2), Shared).unwrap();
+        assert_eq!(locks.held(TxnId(3), table(0)), Some(IntentionShared));
+        // テーブル全体を読むには、書いている全員が終わるのを待つ
+        thread::scope(|s| {
+            let reader = s.spawn(|| locks.lock(TxnId(4), table(0), Shared));
+            wait_queued(&locks, table(0), 1);
+            assert_eq!(locks.held(TxnId(4), table(0)), None);
+            locks.unlock_all(TxnId(1));
+            locks.unlock_all(TxnId(2));
+            reader.join().unwrap().unwrap();
+        });
+        assert_eq!(locks.held(TxnId(4), table(0)), Some(Shared));
+    }
+
+    // 2つのトランザクションが互いに相手の持つロックを待つ
+    fn run_deadlock(first: [(Resource, LockMode); 2], second: [(Resource, LockMode); 2]) {
+        let locks = Arc::new(LockManager::new());
+        let barrier = Arc::new(Barrier::new(2));
+        let handles: Vec<_> = vec![(1, first), (2, second)]
+            .into_iter()
+            .map(|(txn_id, steps)| {
+                let locks = Arc::clone(&locks);
+                let barrier = Arc::clone(&barrier);
+                thread::spawn(move || {
+                    let txn_id = TxnId(txn_id);
+                    locks.lock(txn_id, steps[0].0, steps[0].1).unwrap();
+                    barrier.wait();
+                    let result = locks.lock(txn_id, steps[1].0, steps[1].1);
+                    // ロールバックしたことにしてロックを解放する
+                    locks.unlock_all(txn_id);
+                    result.is_ok()
+                })
+            })
+            .collect();
+        let results: Vec<bool> = handles.into_iter().map(|handle| handle.join().unwrap()).collect();
+        // 新しい方(2)が犠牲になり、古い方(1)は最後までロックを取れる
+        assert_eq!(results, vec![true, false]);
+    }
+
+    #[test]
+    fn test_deadlock_two_resources() {
+        run_deadlock([(table(0), Exclusive), (table(1), Exclusive)], [(table(1), Exclusive), (table(0), Shared)]);
+    }
+
+    #[test]
+    fn test_deadlock_upgrade() {
+        // 2人が同じ対象のSを持ったまま、両方がXにアップグレードしようとする
+        run_deadlock([(table(0), Shared), (table(0), Exclusive)], [(table(0), Shared), (table(0), Exclusive)]);
+    }
+
+    #[test]
+    fn test_deadlock_cycle_of_three() {
+        let locks = Arc::new(LockManager::new());
+        let barrier = Arc::new(Barrier::new(3));
+        let handles: Vec<_> = (0..3u64)
+            .map(|i| {
+                let locks = Arc::clone(&locks);
+                let barrier = Arc::clone(&barrier);
+                thread::spawn(move || {
+                    let txn_id = TxnId(i + 1);
+                    locks.lock(txn_id, table(i), Exclusive).unwrap();
+                    barrier.wait();
+                    // 1→2→3→1の順に相手の持っているロックを待つ
+                    let result = locks.lock(txn_id, table((i + 1) % 3), Exclusive);
+                    locks.unlock_all(txn_id);
+                    result.is_ok()
+                })
+            })
+            .collect();
+        let results: Vec<bool> = handles.into_iter().map(|handle| handle.join().unwrap()).collect();
+        assert_eq!(results, vec![true, true, false]);
+    }
+}
@@ -0,0 +1,1034 @@
+// LSM-tree(Log-Structured Merge-tree)のキーバリューストア
+// 書き込みの多い用途のために、ページを書き換えるkv::Dbの代わりに使える。操作はkv::KvStoreで共通にしている
+//
+// - 書き込みはWAL(ログファイル)に追記してから、メモリ上の表(memtable)に入れる
+//   ページを書き換えないので、1回の書き込みはログへの追記1回で済む
+// - memtableがmemtable_sizeを超えたら、SSTableに書き出してレベル0に置き、ログを新しくする
+// - 読む時はmemtable → レベル0(新しい順) → レベル1 → ... の順に探し、最初に見つかった版を返す
+//   SSTableごとのブルームフィルターで、キーが無いファイルのブロックは読まない
+//
+// 版とスナップショット
+// 書き込みには1つずつ増えるシーケンス番号を振り、キーの版として(キー, シーケンス番号)を保存する
+// スナップショットはその時点のシーケンス番号で、それ以下の版のうち一番新しいものを読む
+//
+// レベル別のコンパクション(leveled compaction)
+// - レベル0のSSTableはキーの範囲が重なってよい。level0_compaction_trigger個になったら、全てをレベル1にマージする
+// - レベル1以降はキーの範囲が重ならないように並べる。レベルの合計サイズが上限
+//   (level1_size * level_size_multiplier^(レベル-1))を超えたら、1つのSSTableを次のレベルの重なるSSTableとマージする
+// - マージする時に、どのスナップショットからも読まれない古い版を捨てる
+//   より深いレベルにキーが無ければ、削除した印も捨てる
+// 同じキーの版は1つのSSTableにまとめて置くので、浅いレベルにある版ほど新しい
+//
+// ディレクトリの中のファイル
+// - MANIFEST：| マジックナンバー(8byte) | 次のファイル番号(u64) | 最後のシーケンス番号(u64) | ログのファイル番号(u64) |
+//             | レベルの数(u32) | (SSTableの数(u32), ファイル番号(u64) ...) ... | CRC-32(u32) |
+//   MANIFEST.tmpに書いてからrenameで置き換えるので、クラッシュしても古いか新しいかのどちらかが残る
+// - 番号.log：WAL。| 長さ(u32) | CRC-32(u32) | エントリ ... | を書き込み(バッチ)ごとに追記する
+// - 番号.sst：SSTable(sstable.rs)
+// 開く時はMANIFESTにあるSSTableを開き、ログのファイル番号以降のログを読み直してmemtableを戻す
+// CRCが合わないレコード(書きかけのまま落ちた)から後ろは捨てる
+//
+// kv::Dbと同じく、1つのスレッドから使う(Dbを複製すると同じストアを共有する)
+
+use std::cell::RefCell;
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BTreeMap, BinaryHeap, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::ops::{Bound, RangeBounds};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use crate::error::Error;
+use crate::kv::{self, Batch, KvStore};
+use crate::sstable::{Entry, Table, TableBuilder};
+use crate::wal::crc32;
+
+const MANIFEST: &str = "MANIFEST";
+const MANIFEST_TMP: &str = "MANIFEST.tmp";
+const MANIFEST_MAGIC: &[u8; 8] = b"PRACTLSM";
+const MAX_LEVELS: usize = 7;
+
+// イテレータが1回に読み進めるキーの数
+const ITER_BATCH_SIZE: usize = 64;
+
+/// LSM-treeの設定
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// memtableをSSTableに書き出す大きさ(バイト)
+    pub memtable_size: usize,
+    /// SSTableのデータブロックの大きさ(バイト)
+    pub block_size: usize,
+    /// コンパクションで書き出すSSTable1つの大きさ(バイト)
+    pub table_size: u64,
+    /// ブルームフィルターの1キーあたりのビット数
+    pub bloom_bits_per_key: usize,
+    /// レベル0のSSTableがこの数になったらコンパクションする
+    pub level0_compaction_trigger: usize,
+    /// レベル1の合計サイズの上限(バイト)
+    pub level1_size: u64,
+    /// 1つ深いレベルの上限は何倍になるか
+    pub level_size_multiplier: u64,
+    /// 書き込みごとにログをfsyncするか。falseならOSが落ちた時に最後の書き込みを失うことがある
+    pub sync: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            memtable_size: 4 << 20,
+            block_size: 4 << 10,
+            table_size: 2 << 20,
+            bloom_bits_per_key: 10,
+            level0_compaction_trigger: 4,
+            level1_size: 10 << 20,
+            level_size_multiplier: 10,
+            sync: true,
+        }
+    }
+}
+
+fn invalid_data(msg: &str) -> Error {
+    Error::Io(io::Error::new(io::ErrorKind::InvalidData, msg.to_string()))
+}
+
+fn table_path(dir: &Path, number: u64) -> PathBuf {
+    dir.join(format!("{:06}.sst", number))
+}
+
+fn log_path(dir: &Path, number: u64) -> PathBuf {
+    dir.join(format!("{:06}.log", number))
+}
+
+// "番号.拡張子"のファイル名を(番号, 拡張子)にする
+fn parse_file_name(name: &str) -> Option<(u64, &str)> {
+    let (number, ext) = name.split_once('.')?;
+    Some((number.parse().ok()?, ext))
+}
+
+// ディレクトリのエントリ(ファイルの作成・削除・rename)を永続化する
+fn sync_dir(dir: &Path) -> Result<(), Error> {
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+fn read_u64(bytes: &[u8]) -> u64 {
+    let mut buf = [0u8; 8];
+    buf.copy_from_slice(&bytes[..8]);
+    u64::from_le_bytes(buf)
+}
+
+// (キー, シーケンス番号の逆順)の順に並べるので、同じキーの版は新しい順になる
+type VersionMap = BTreeMap<(Vec<u8>, Reverse<u64>), Option<Vec<u8>>>;
+
+#[derive(Default)]
+struct Memtable {
+    map: VersionMap,
+    size: usize,
+}
+
+impl Memtable {
+    fn insert(&mut self, entry: Entry) {
+        self.size += entry.size();
+        self.map.insert((entry.key, Reverse(entry.seq)), entry.value);
+    }
+
+    // keyのシーケンス番号がseq以下の版のうち一番新しいもの
+    fn get(&self, key: &[u8], seq: u64) -> Option<Option<Vec<u8>>> {
+        let ((found, _), value) = self.map.range((key.to_vec(), Reverse(seq))..).next()?;
+        if found.as_slice() == key {
+            Some(value.clone())
+        } else {
+            None
+        }
+    }
+
+    // keyの最初の版(Noneなら先頭)から順に返す
+    fn iter_from(&self, key: Option<&[u8]>) -> impl Iterator<Item = Entry> + '_ {
+        let start = match key {
+            Some(key) => Bound::Included((key.to_vec(), Reverse(u64::MAX))),
+            None => Bound::Unbounded,
+        };
+        self.map.range((start, Bound::Unbounded)).map(|((key, Reverse(seq)), value)| Entry {
+            key: key.clone(),
+            seq: *seq,
+            value: value.clone(),
+        })
+    }
+}
+
+type EntryIter<'a> = Box<dyn Iterator<Item = Result<Entry, Error>> + 'a>;
+
+// ヒープの中ではEntry::orderの逆順にして、一番小さいエントリが先頭に来るようにする
+struct HeapItem {
+    entry: Entry,
+    source: usize,
+}
+
+impl PartialEq for HeapItem {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for HeapItem {}
+
+impl PartialOrd for HeapItem {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for HeapItem {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.entry.order(&self.entry)
+    }
+}
+
+// 並んだエントリの列をマージして、Entry::orderの順に返す
+struct MergeIter<'a> {
+    sources: Vec<EntryIter<'a>>,
+    heap: BinaryHeap<HeapItem>,
+}
+
+impl<'a> MergeIter<'a> {
+    fn new(mut sources: Vec<EntryIter<'a>>) -> Result<Self, Error> {
+        let mut heap = BinaryHeap::new();
+        for (source, iter) in sources.iter_mut().enumerate() {
+            if let Some(entry) = iter.next() {
+                heap.push(HeapItem { entry: entry?, source });
+            }
+        }
+        Ok(Self { sources, heap })
+    }
+}
+
+impl<'a> Iterator for MergeIter<'a> {
+    type Item = Result<Entry, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let HeapItem { entry, source } = self.heap.pop()?;
+        match self.sources[source].next() {
+            Some(Ok(next)) => self.heap.push(HeapItem { entry: next, source }),
+            Some(Err(err)) => return Some(Err(err)),
+            None => {}
+        }
+        Some(Ok(entry))
+    }
+}
+
+// 書き出す時に、どのスナップショットからも読まれない版を捨てる
+// oldest_snapshotより新しい版は全て残し、それ以下の版はキーごとに一番新しいものだけを残す
+// その版が削除した印で、is_bottomが(より深いレベルにキーが無いと)言えば、それも捨てる
+struct DropObsolete<I, F> {
+    entries: I,
+    oldest_snapshot: u64,
+    is_bottom: F,
+    last_key: Option<Vec<u8>>,
+    // last_keyのoldest_snapshot以下の版を既に出したか
+    emitted_old: bool,
+}
+
+impl<I: Iterator<Item = Result<Entry, Error>>, F: Fn(&[u8]) -> bool> Iterator for DropObsolete<I, F> {
+    type Item = Result<Entry, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let entry = match self.entries.next()? {
+                Ok(entry) => entry,
+                Err(err) => return Some(Err(err)),
+            };
+            if self.last_key.as_ref() != Some(&entry.key) {
+                self.last_key = Some(entry.key.clone());
+                self.emitted_old = false;
+            }
+            if entry.seq > self.oldest_snapshot {
+                return Some(Ok(entry));
+            }
+            if self.emitted_old {
+                continue;
+            }
+            self.emitted_old = true;
+            if entry.value.is_none() && (self.is_bottom)(&entry.key) {
+                continue;
+            }
+            return Some(Ok(entry));
+        }
+    }
+}
+
+// エントリをSSTableに書き出す。max_sizeを超えたら、キーが変わる所で次のファイルに分ける
+fn write_tables(dir: &Path, options: &Options, next_file_number: &mut u64, entries: impl Iterator<Item = Result<Entry, Error>>, max_size: u64) -> Result<Vec<Table>, Error> {
+    let mut tables = vec![];
+    let mut current: Option<(u64, TableBuilder)> = None;
+    let mut last_key: Option<Vec<u8>> = None;
+    for entry in entries {
+        let entry = entry?;
+        let full = current.as_ref().is_some_and(|(_, builder)| builder.estimated_size() >= max_size);
+        if full && last_key.as_ref() != Some(&entry.key) {
+            let (number, builder) = current.take().unwrap();
+            builder.finish()?;
+            tables.push(Table::open(table_path(dir, number), number)?);
+        }
+        if current.is_none() {
+            let number = *next_file_number;
+            *next_file_number += 1;
+            current = Some((number, TableBuilder::create(table_path(dir, number), options.block_size, options.bloom_bits_per_key)?));
+        }
+        current.as_mut().unwrap().1.add(&entry)?;
+        last_key = Some(entry.key);
+    }
+    if let Some((number, builder)) = current {
+        builder.finish()?;
+        tables.push(Table::open(table_path(dir, number), number)?);
+    }
+    Ok(tables)
+}
+
+struct Inner {
+    dir: PathBuf,
+    options: Options,
+    memtable: Memtable,
+    log: Option<File>,
+    log_number: u64,
+    // levels[0]は新しい順、それ以外は最小のキーの順
+    levels: Vec<Vec<Table>>,
+    next_file_number: u64,
+    last_seq: u64,
+    // 実行中のスナップショットのシーケンス番号と、その数
+    snapshots: BTreeMap<u64, usize>,
+    // レベルごとに、最後にコンパクションしたSSTableの最大のキー(次はその続きから選ぶ)
+    compact_pointers: Vec<Vec<u8>>,
+}
+
+impl Inner {
+    fn open(dir: &Path, options: Options) -> Result<Self, Error> {
+        fs::create_dir_all(dir)?;
+        let mut inner = Self {
+            dir: dir.to_path_buf(),
+            options,
+            memtable: Memtable::default(),
+            log: None,
+            log_number: 0,
+            levels: (0..MAX_LEVELS).map(|_| vec![]).collect(),
+            next_file_number: 1,
+            last_seq: 0,
+            snapshots: BTreeMap::new(),
+            compact_pointers: vec![vec![]; MAX_LEVELS],
+        };
+        inner.read_manifest()?;
+        // クラッシュで残ったファイルと番号が重ならないように、どのファイルよりも大きい番号から使う
+        let mut logs = vec![];
+        for dir_entry in fs::read_dir(dir)? {
+            let name = dir_entry?.file_name();
+            if let Some((number, ext)) = name.to_str().and_then(parse_file_name) {
+                inner.next_file_number = std::cmp::max(inner.next_file_number, number + 1);
+                if ext == "log" && number >= inner.log_number {
+                    logs.push(number);
+                }
+            }
+        }
+        logs.sort_unstable();
+        for number in logs {
+            inner.replay(number)?;
+        }
+        // 読み直した分をSSTableに書き出し、新しいログで始める
+        inner.rotate()?;
+        inner.compact()?;
+        Ok(inner)
+    }
+
+    fn read_manifest(&mut self) -> Result<(), Error> {
+        let bytes = match fs::read(self.dir.join(MANIFEST)) {
+            Ok(bytes) => bytes,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err.into()),
+        };
+        let header = 8 + 8 + 8 + 8 + 4;
+        if bytes.len() < header + 4 || &bytes[..8] != MANIFEST_MAGIC {
+            return Err(invalid_data("not a LSM-tree manifest"));
+        }
+        let (body, crc) = bytes.split_at(bytes.len() - 4);
+        if crc32(body).to_le_bytes() != crc {
+            return Err(invalid_data("manifest checksum mismatch"));
+        }
+        self.next_file_number = read_u64(&body[8..]);
+        self.last_seq = read_u64(&body[16..]);
+        self.log_number = read_u64(&body[24..]);
+        let level_count = u32::from_le_bytes([body[32], body[33], body[34], body[35]]) as usize;
+        let mut src = &body[header..];
+        for level in 0..level_count {
+            if src.len() < 4 {
+                return Err(invalid_data("truncated manifest"));
+            }
+            let count = u32::from_le_bytes([src[0], src[1], src[2], src[3]]) as usize;
+            src = &src[4..];
+            if src.len() < count * 8 || level >= MAX_LEVELS {
+                return Err(invalid_data("invalid manifest"));
+            }
+            for i in 0..count {
+                let number = read_u64(&src[i * 8..]);
+                self.levels[level].push(Table::open(table_path(&self.dir, number), number)?);
+            }
+            src = &src[count * 8..];
+        }
+        Ok(())
+    }
+
+    fn write_manifest(&self) -> Result<(), Error> {
+        let mut bytes = MANIFEST_MAGIC.to_vec();
+        bytes.extend_from_slice(&self.next_file_number.to_le_bytes());
+        bytes.extend_from_slice(&self.last_seq.to_le_bytes());
+        bytes.extend_from_slice(&self.log_number.to_le_bytes());
+        bytes.extend_from_slice(&(self.levels.len() as u32).to_le_bytes());
+        for level in &self.levels {
+            bytes.extend_from_slice(&(level.len() as u32).to_le_bytes());
+            for table in level {
+                bytes.extend_from_slice(&table.number.to_le_bytes());
+            }
+        }
+        bytes.extend_from_slice(&crc32(&bytes).to_le_bytes());
+        let tmp = self.dir.join(MANIFEST_TMP);
+        let mut file = File::create(&tmp)?;
+        file.write_all(&bytes)?;
+        file.sync_all()?;
+        fs::rename(&tmp, self.dir.join(MANIFEST))?;
+        sync_dir(&self.dir)
+    }
+
+    // ログを読み直してmemtableに入れる
+    fn replay(&mut self, number: u64) -> Result<(), Error> {
+        let bytes = fs::read(log_path(&self.dir, number))?;
+        let mut src = &bytes[..];
+        while src.len() >= 8 {
+            let len = u32::from_le_bytes([src[0], src[1], src[2], src[3]]) as usize;
+            let crc = u32::from_le_bytes([src[4], src[5], src[6], src[7]]);
+            let payload = match src.get(8..8 + len) {
+                Some(payload) if crc32(payload) == crc => payload,
+                // 書きかけのレコード
+                _ => break,
+            };
+            let mut entries = payload;
+            while !entries.is_empty() {
+                let entry = Entry::decode(&mut entries)?;
+                self.last_seq = std::cmp::max(self.last_seq, entry.seq);
+                self.memtable.insert(entry);
+            }
+            src = &src[8 + len..];
+        }
+        Ok(())
+    }
+
+    fn new_file_number(&mut self) -> u64 {
+        let number = self.next_file_number;
+        self.next_file_number += 1;
+        number
+    }
+
+    // 実行中で一番古いスナップショット。無ければ最新
+    fn oldest_snapshot(&self) -> u64 {
+        self.snapshots.keys().next().copied().unwrap_or(self.last_seq)
+    }
+
+    // memtableをレベル0のSSTableに書き出し、新しいログに切り替える
+    // SSTableを書いてからMANIFESTを置き換え、最後に古いログを消すので、どこで落ちても書き込みは失われない
+    fn rotate(&mut self) -> Result<(), Error> {
+        let memtable = std::mem::take(&mut self.memtable);
+        let entries = DropObsolete {
+            entries: memtable.iter_from(None).map(Ok),
+            oldest_snapshot: self.oldest_snapshot(),
+            // 古い版は他のSSTableにあるかもしれないので、削除した印は捨てない
+            is_bottom: |_: &[u8]| false,
+            last_key: None,
+            emitted_old: false,
+        };
+        let tables = write_tables(&self.dir, &self.options, &mut self.next_file_number, entries, u64::MAX);
+        let tables = match tables {
+            Ok(tables) => tables,
+            Err(err) => {
+                self.memtable = memtable;
+                return Err(err);
+            }
+        };
+        for table in tables {
+            self.levels[0].insert(0, table);
+        }
+        let log_number = self.new_file_number();
+        let log = OpenOptions::new().create(true).write(true).truncate(true).open(log_path(&self.dir, log_number))?;
+        self.log = Some(log);
+        self.log_number = log_number;
+        self.write_manifest()?;
+        self.remove_obsolete_files()
+    }
+
+    // MANIFESTに無いSSTableと、今のログより古いログを消す
+    fn remove_obsolete_files(&self) -> Result<(), Error> {
+        let live: Vec<u64> = self.levels.iter().flatten().map(|table| table.number).collect();
+        for dir_entry in fs::read_dir(&self.dir)? {
+            let dir_entry = dir_entry?;
+            let name = dir_entry.file_name();
+            let obsolete = match name.to_str().and_then(parse_file_name) {
+                Some((number, "sst")) => !live.contains(&number),
+                Some((number, "log")) => number != self.log_number,
+                _ => name == MANIFEST_TMP,
+            };
+            if obsolete {
+                fs::remove_file(dir_entry.path())?;
+            }
+        }
+        Ok(())
+    }
+
+    // 書き込み(バッチ)をログに追記してからmemtableに入れる
+    fn write(&mut self, writes: &[(Vec<u8>, Option<Vec<u8>>)]) -> Result<(), Error> {
+        if writes.is_empty() {
+            return Ok(());
+        }
+        let entries: Vec<Entry> = writes
+            .iter()
+            .enumerate()
+            .map(|(i, (key, value))| Entry {
+                key: key.clone(),
+                seq: self.last_seq + 1 + i as u64,
+                value: value.clone(),
+            })
+            .collect();
+        let mut payload = vec![];
+        for entry in &entries {
+            entry.encode(&mut payload);
+        }
+        let mut record = Vec::with_capacity(8 + payload.len());
+        record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        record.extend_from_slice(&crc32(&payload).to_le_bytes());
+        record.extend_from_slice(&payload);
+        let log = self.log.as_mut().unwrap();
+        log.write_all(&record)?;
+        if self.options.sync {
+            log.sync_data()?;
+        }
+        self.last_seq += entries.len() as u64;
+        for entry in entries {
+            self.memtable.insert(entry);
+        }
+        if self.memtable.size >= self.options.memtable_size {
+            self.rotate()?;
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    // keyのシーケンス番号がseq以下の版のうち、一番新しいものの値
+    fn get(&self, key: &[u8], seq: u64) -> Result<Option<Vec<u8>>, Error> {
+        if let Some(value) = self.memtable.get(key, seq) {
+            return Ok(value);
+        }
+        for table in &self.levels[0] {
+            if let Some(entry) = table.get(key, seq)? {
+                return Ok(entry.value);
+            }
+        }
+        // レベル1以降はキーの範囲が重ならないので、キーが入りうるSSTableは1つだけ
+        for level in &self.levels[1..] {
+            let i = level.partition_point(|table| table.largest.as_slice() < key);
+            if let Some(entry) = level.get(i).map(|table| table.get(key, seq)).transpose()?.flatten() {
+                return Ok(entry.value);
+            }
+        }
+        Ok(None)
+    }
+
+    fn level_limit(&self, level: usize) -> u64 {
+        self.options.level1_size.saturating_mul(self.options.level_size_multiplier.saturating_pow(level as u32 - 1))
+    }
+
+    // コンパクションするレベルと、そのレベルから選んだSSTableの番号
+    fn pick_compaction(&self) -> Option<(usize, Vec<u64>)> {
+        if self.levels[0].len() >= self.options.level0_compaction_trigger {
+            return Some((0, self.levels[0].iter().map(|table| table.number).collect()));
+        }
+        // 一番深いレベルはそれ以上下に移せない
+        for level in 1..MAX_LEVELS - 1 {
+            let size: u64 = self.levels[level].iter().map(|table| table.size).sum();
+            if size > self.level_limit(level) {
+                let pointer = &self.compact_pointers[level];
+                let tables = &self.levels[level];
+                let table = tables.iter().find(|table| table.smallest > *pointer).unwrap_or(&tables[0]);
+                return Some((level, vec![table.number]));
+            }
+        }
+        None
+    }
+
+    fn compact(&mut self) -> Result<(), Error> {
+        while let Some((level, inputs)) = self.pick_compaction() {
+            self.compact_level(level, &inputs)?;
+        }
+        Ok(())
+    }
+
+    // levelのinputsと、次のレベルでキーの範囲が重なるSSTableをマージして次のレベルに置く
+    fn compact_level(&mut self, level: usize, inputs: &[u64]) -> Result<(), Error> {
+        let output = level + 1;
+        let input_tables: Vec<&Table> = self.levels[level].iter().filter(|table| inputs.contains(&table.number)).collect();
+        let smallest = input_tables.iter().map(|table| table.smallest.clone()).min().unwrap();
+        let largest = input_tables.iter().map(|table| table.largest.clone()).max().unwrap();
+        let overlapping: Vec<u64> = self.levels[output]
+            .iter()
+            .filter(|table| table.overlaps(&smallest, &largest))
+            .map(|table| table.number)
+            .collect();
+        let oldest_snapshot = self.oldest_snapshot();
+        let outputs = {
+            let sources: Vec<EntryIter> = input_tables
+                .into_iter()
+                .chain(self.levels[output].iter().filter(|table| overlapping.contains(&table.number)))
+                .map(|table| Box::new(table.iter_from(None)) as EntryIter)
+                .collect();
+            let deeper = &self.levels[output + 1..];
+            let entries = DropObsolete {
+                entries: MergeIter::new(sources)?,
+                oldest_snapshot,
+                is_bottom: |key: &[u8]| !deeper.iter().flatten().any(|table| table.overlaps(key, key)),
+                last_key: None,
+                emitted_old: false,
+            };
+            write_tables(&self.dir, &self.options, &mut self.next_file_number, entries, self.options.table_size)?
+        };
+        self.levels[level].retain(|table| !inputs.contains(&table.number));
+        self.levels[output].retain(|table| !overlapping.contains(&table.number));
+        self.levels[output].extend(outputs);
+        self.levels[output].sort_by(|a, b| a.smallest.cmp(&b.smallest));
+        self.compact_pointers[level] = largest;
+        self.write_manifest()?;
+        self.remove_obsolete_files()
+    }
+
+    // fromから順に、シーケンス番号seqのスナップショットから見えるキーと値を最大でlimit個読む(kv::Innerと同じ)
+    // 読み終わったらtrueを返す
+    fn read_batch(
+        &self,
+        seq: u64,
+        from: Option<&[u8]>,
+        skip: Option<&[u8]>,
+        end: &Bound<Vec<u8>>,
+        limit: usize,
+        out: &mut VecDeque<(Vec<u8>, Vec<u8>)>,
+    ) -> Result<bool, Error> {
+        let mut sources: Vec<EntryIter> = vec![Box::new(self.memtable.iter_from(from).map(Ok))];
+        for table in self.levels.iter().flatten() {
+            if from.is_none_or(|from| table.largest.as_slice() >= from) {
+                sources.push(Box::new(table.iter_from(from)));
+            }
+        }
+        let mut resolved: Option<Vec<u8>> = None;
+        let mut count = 0;
+        for entry in MergeIter::new(sources)? {
+            let entry = entry?;
+            if Some(entry.key.as_slice()) == skip {
+                continue;
+            }
+            let past_end = match end {
+                Bound::Included(end) => entry.key > *end,
+                Bound::Excluded(end) => entry.key >= *end,
+                Bound::Unbounded => false,
+            };
+            if past_end {
+                return Ok(true);
+            }
+            if Some(&entry.key) == resolved.as_ref() || entry.seq > seq {
+                continue;
+            }
+            if let Some(value) = entry.value {
+                out.push_back((entry.key.clone(), value));
+                count += 1;
+            }
+            resolved = Some(entry.key);
+            if count == limit {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+}
+
+/// LSM-treeのキーバリューストア
+/// 複製すると同じストアを指す
+#[derive(Clone)]
+pub struct Db {
+    inner: Rc<RefCell<Inner>>,
+}
+
+impl Db {
+    /// ディレクトリを開く(無ければ作る)。前回クラッシュしていればログから戻す
+    pub fn open(dir: impl AsRef<Path>) -> Result<Self, Error> {
+        Self::open_with_options(dir, Options::default())
+    }
+
+    pub fn open_with_options(dir: impl AsRef<Path>, options: Options) -> Result<Self, Error> {
+        Ok(Self {
+            inner: Rc::new(RefCell::new(Inner::open(dir.as_ref(), options)?)),
+        })
+    }
+
+    pub fn get(&self, key: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>, Error> {
+        let inner = self.inner.borrow();
+        inner.get(key.as_ref(), inner.last_seq)
+    }
+
+    pub fn put(&self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> Result<(), Error> {
+        self.inner.borrow_mut().write(&[(key.as_ref().to_vec(), Some(value.as_ref().to_vec()))])
+    }
+
+    pub fn delete(&self, key: impl AsRef<[u8]>) -> Result<(), Error> {
+        self.inner.borrow_mut().write(&[(key.as_ref().to_vec(), None)])
+    }
+
+    /// バッチの書き込みを1つのログレコードにまとめて書くので、全て行うか1つも行わないかのどちらかになる
+    pub fn apply_batch(&self, batch: Batch) -> Result<(), Error> {
+        self.inner.borrow_mut().write(&batch.writes)
+    }
+
+    /// 今の内容のスナップショットを取る
+    /// スナップショットを持っている間は、コンパクションでそれが読む版を捨てない
+    pub fn snapshot(&self) -> Snapshot {
+        let seq = {
+            let mut inner = self.inner.borrow_mut();
+            let seq = inner.last_seq;
+            *inner.snapshots.entry(seq).or_insert(0) += 1;
+            seq
+        };
+        Snapshot(Rc::new(SnapshotState {
+            inner: Rc::clone(&self.inner),
+            seq,
+        }))
+    }
+
+    pub fn range<K: AsRef<[u8]>, R: RangeBounds<K>>(&self, range: R) -> Iter {
+        self.snapshot().range(range)
+    }
+
+    pub fn scan_prefix(&self, prefix: impl AsRef<[u8]>) -> Iter {
+        self.snapshot().scan_prefix(prefix)
+    }
+
+    pub fn iter(&self) -> Iter {
+        self.snapshot().iter()
+    }
+
+    /// memtableをSSTableに書き出す
+    pub fn flush(&self) -> Result<(), Error> {
+        let mut inner = self.inner.borrow_mut();
+        inner.rotate()?;
+        inner.compact()
+    }
+
+    /// レベルごとのSSTableの数
+    pub fn table_counts(&self) -> Vec<usize> {
+        self.inner.borrow().levels.iter().map(|level| level.len()).collect()
+    }
+}
+
+impl KvStore for Db {
+    type Iter = Iter;
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        Db::get(self, key)
+    }
+
+    fn put(&self, key: &[u8], value: &[u8]) -> Result<(), Error> {
+        Db::put(self, key, value)
+    }
+
+    fn delete(&self, key: &[u8]) -> Result<(), Error> {
+        Db::delete(self, key)
+    }
+
+    fn apply_batch(&self, batch: Batch) -> Result<(), Error> {
+        Db::apply_batch(self, batch)
+    }
+
+    fn range(&self, start: Bound<&[u8]>, end: Bound<&[u8]>) -> Iter {
+        Db::range::<&[u8], _>(self, (start, end))
+    }
+
+    fn scan_prefix(&self, prefix: &[u8]) -> Iter {
+        Db::scan_prefix(self, prefix)
+    }
+
+    fn flush(&self) -> Result<(), Error> {
+        Db::flush(self)
+    }
+}
+
+struct SnapshotState {
+    inner: Rc<RefCell<Inner>>,
+    seq: u64,
+}
+
+impl Drop for SnapshotState {
+    fn drop(&mut self) {
+        let mut inner = self.inner.borrow_mut();
+        if let Some(count) = inner.snapshots.get_mut(&self.seq) {
+            *count -= 1;
+            if *count == 0 {
+                inner.snapshots.remove(&self.seq);
+            }
+        }
+    }
+}
+
+/// ある時点の内容を読むスナップショット
+#[derive(Clone)]
+pub struct Snapshot(Rc<SnapshotState>);
+
+impl Snapshot {
+    pub fn get(&self, key: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>, Error> {
+        self.0.inner.borrow().get(key.as_ref(), self.0.seq)
+    }
+
+    pub fn range<K: AsRef<[u8]>, R: RangeBounds<K>>(&self, range: R) -> Iter {
+        let (from, skip) = match range.start_bound() {
+            Bound::Included(key) => (Some(key.as_ref().to_vec()), None),
+            Bound::Excluded(key) => (Some(key.as_ref().to_vec()), Some(key.as_ref().to_vec())),
+            Bound::Unbounded => (None, None),
+        };
+        Iter {
+            snapshot: Rc::clone(&self.0),
+            from,
+            skip,
+            end: kv::to_owned_bound(range.end_bound()),
+            buffer: VecDeque::new(),
+            done: false,
+        }
+    }
+
+    pub fn scan_prefix(&self, prefix: impl AsRef<[u8]>) -> Iter {
+        let prefix = prefix.as_ref();
+        match kv::prefix_end(prefix) {
+            Some(end) => self.range(prefix.to_vec()..end),
+            None => self.range(prefix.to_vec()..),
+        }
+    }
+
+    pub fn iter(&self) -> Iter {
+        self.range::<&[u8], _>(..)
+    }
+}
+
+/// キーと値をキーの順に返すイテレータ
+/// 読み進めるたびにmemtableとSSTableを探し直すので、途中で書き出しやコンパクションがあっても読み飛ばさない
+pub struct Iter {
+    snapshot: Rc<SnapshotState>,
+    from: Option<Vec<u8>>,
+    skip: Option<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+    buffer: VecDeque<(Vec<u8>, Vec<u8>)>,
+    done: bool,
+}
+
+impl Iter {
+    fn fill(&mut self) -> Result<(), Error> {
+        let inner = self.snapshot.inner.borrow();
+        let before = self.buffer.len();
+        self.done = inner.read_batch(self.snapshot.seq, self.from.as_deref(), self.skip.as_deref(), &self.end, ITER_BATCH_SIZE, &mut self.buffer)?;
+        if self.buffer.len() > before {
+            let key = self.buffer.back().unwrap().0.clone();
+            self.from = Some(key.clone());
+            self.skip = Some(key);
+        }
+        Ok(())
+    }
+}
+
+impl Iterator for Iter {
+    type Item = Result<(Vec<u8>, Vec<u8>), Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.buffer.is_empty() && !self.done {
+            if let Err(err) = self.fill() {
+                self.done = true;
+                return Some(Err(err));
+            }
+        }
+        self.buffer.pop_front().map(Ok)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("lsm_test_{}_{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    // 小さな設定にして、書き出しやコンパクションを何度も起こす
+    fn small_options() -> Options {
+        Options {
+            memtable_size: 8 << 10,
+            block_size: 512,
+            table_size: 8 << 10,
+            level0_compaction_trigger: 3,
+            level1_size: 32 << 10,
+            level_size_multiplier: 4,
+            sync: false,
+            ..Options::default()
+        }
+    }
+
+    #[test]
+    fn test_get_put_delete() {
+        let dir = temp_dir("basic");
+        let db = Db::open_with_options(&dir, small_options()).unwrap();
+        db.put(b"a", b"1").unwrap();
+        db.put(b"a", b"2").unwrap();
+        db.put(b"b", b"3").unwrap();
+        db.delete(b"b").unwrap();
+        assert_eq!(db.get(b"a").unwrap(), Some(b"2".to_vec()));
+        assert_eq!(db.get(b"b").unwrap(), None);
+        assert_eq!(db.get(b"c").unwrap(), None);
+        // SSTableに書き出した後も読める
+        db.flush().unwrap();
+        assert_eq!(db.table_counts()[0], 1);
+        assert_eq!(db.get(b"a").unwrap(), Some(b"2".to_vec()));
+        assert_eq!(db.get(b"b").unwrap(), None);
+        db.put(b"b", b"4").unwrap();
+        assert_eq!(db.get(b"b").unwrap(), Some(b"4".to_vec()));
+        let pairs: Vec<_> = db.iter().map(|pair| pair.unwrap()).collect();
+        assert_eq!(pairs, vec![(b"a".to_vec(), b"2".to_vec()), (b"b".to_vec(), b"4".to_vec())]);
+    }
+
+    #[test]
+    fn test_compaction() {
+        let dir = temp_dir("compaction");
+        let db = Db::open_with_options(&dir, small_options()).unwrap();
+        // 同じキーを何度も書き換え、一部を消す
+        for round in 0..5u32 {
+            for i in 0..1000u32 {
+                db.put(format!("key{:05}", i), format!("value{}-{}", i, round)).unwrap();
+            }
+        }
+        for i in (0..1000u32).step_by(3) {
+            db.delete(format!("key{:05}", i)).unwrap();
+        }
+        db.flush().unwrap();
+        let counts = db.table_counts();
+        assert!(counts[0] < 3, "{:?}", counts);
+        assert!(counts[2..].iter().sum::<usize>() > 0, "{:?}", counts);
+
+        // レベル1以降のSSTableはキーの範囲が重ならない
+        {
+            let inner = db.inner.borrow();
+            for level in &inner.levels[1..] {
+                assert!(level.windows(2).all(|w| w[0].largest < w[1].smallest));
+            }
+        }
+        for i in 0..1000u32 {
+            let expected = if i % 3 == 0 { None } else { Some(format!("value{}-4", i).into_bytes()) };
+            assert_eq!(db.get(format!("key{:05}", i)).unwrap(), expected);
+        }
+        assert_eq!(db.iter().count(), 666);
+        assert_eq!(db.scan_prefix(b"key001").count(), 67);
+        assert_eq!(db.range(b"key00100".to_vec()..b"key00200".to_vec()).count(), 67);
+
+        // 古い版はマージする時に捨てられるので、残っている版は書いた数(5334)よりずっと少ない
+        let entries: usize = db.inner.borrow().levels.iter().flatten().map(|table| table.iter_from(None).count()).sum();
+        assert!(entries < 5334 / 2, "{}", entries);
+    }
+
+    #[test]
+    fn test_snapshot_survives_compaction() {
+        let dir = temp_dir("snapshot");
+        let db = Db::open_with_options(&dir, small_options()).unwrap();
+        for i in 0..500u32 {
+            db.put(format!("key{:05}", i), b"old").unwrap();
+        }
+        let snapshot = db.snapshot();
+        let mut iter = db.iter();
+        assert_eq!(iter.next().unwrap().unwrap().0, b"key00000".to_vec());
+        for round in 0..5 {
+            for i in 0..500u32 {
+                if i % 2 == 0 {
+                    db.delete(format!("key{:05}", i)).unwrap();
+                } else {
+                    db.put(format!("key{:05}", i), format!("new{}", round)).unwrap();
+                }
+            }
+        }
+        db.flush().unwrap();
+        assert_eq!(snapshot.get(b"key00002").unwrap(), Some(b"old".to_vec()));
+        assert_eq!(db.get(b"key00002").unwrap(), None);
+        assert_eq!(db.get(b"key00003").unwrap(), Some(b"new4".to_vec()));
+        assert!(snapshot.iter().all(|pair| pair.unwrap().1 == b"old"));
+        assert_eq!(iter.count(), 499);
+        assert_eq!(db.iter().count(), 250);
+    }
+
+    #[test]
+    fn test_recover() {
+        let dir = temp_dir("recover");
+        {
+            let db = Db::open_with_options(&dir, small_options()).unwrap();
+            for i in 0..2000u32 {
+                db.put(i.to_be_bytes(), vec![i as u8; 20]).unwrap();
+            }
+            let mut batch = Batch::default();
+            batch.delete(5u32.to_be_bytes());
+            batch.put(b"batch", b"x");
+            db.apply_batch(batch).unwrap();
+        }
+        // 最後のレコードが書きかけのまま落ちたことにする
+        let log = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .find(|path| path.extension().is_some_and(|ext| ext == "log"))
+            .unwrap();
+        let mut file = OpenOptions::new().append(true).open(&log).unwrap();
+        file.write_all(&[100, 0, 0, 0, 1, 2, 3, 4, 5]).unwrap();
+        drop(file);
+
+        let db = Db::open_with_options(&dir, small_options()).unwrap();
+        assert_eq!(db.iter().count(), 2000);
+        assert_eq!(db.get(7u32.to_be_bytes()).unwrap(), Some(vec![7u8; 20]));
+        assert_eq!(db.get(5u32.to_be_bytes()).unwrap(), None);
+        assert_eq!(db.get(b"batch").unwrap(), Some(b"x".to_vec()));
+        // 書き込みはシーケンス番号の続きから振る
+        db.put(b"batch", b"y").unwrap();
+        drop(db);
+        let db = Db::open_with_options(&dir, small_options()).unwrap();
+        assert_eq!(db.get(b"batch").unwrap(), Some(b"y".to_vec()));
+        // 要らなくなったログは消えている
+        let logs = fs::read_dir(&dir).unwrap().filter(|entry| entry.as_ref().unwrap().path().extension().is_some_and(|ext| ext == "log")).count();
+        assert_eq!(logs, 1);
+    }
+
+    #[test]
+    fn test_same_interface() {
+        // 2つのエンジンに同じ操作をして、同じ結果になる
+        fn run(store: &impl KvStore) -> Vec<(Vec<u8>, Vec<u8>)> {
+            for i in 0..300u32 {
+                store.put(format!("k{:03}", i).as_bytes(), &i.to_le_bytes()).unwrap();
+            }
+            let mut batch = Batch::default();
+            for i in (0..300u32).step_by(2) {
+                batch.delete(format!("k{:03}", i));
+            }
+            store.apply_batch(batch).unwrap();
+            store.flush().unwrap();
+            assert_eq!(store.get(b"k001").unwrap(), Some(1u32.to_le_bytes().to_vec()));
+            assert_eq!(store.scan_prefix(b"k1").count(), 50);
+            store.range(Bound::Excluded(b"k100"), Bound::Included(b"k200")).map(|pair| pair.unwrap()).collect()
+        }
+        let kv_path = temp_dir("same_kv");
+        let kv = kv::Db::open_with_pool_size(&kv_path, 32).unwrap();
+        let lsm = Db::open_with_options(temp_dir("same_lsm"), small_options()).unwrap();
+        let expected = run(&kv);
+        assert_eq!(expected.len(), 50);
+        assert_eq!(run(&lsm), expected);
+        drop(kv);
+        let _ = fs::remove_file(&kv_path);
+        let _ = fs::remove_file(crate::wal::log_path(&kv_path));
+    }
+}
@@ -1,4 +1,4 @@
-mod grep;
+use practice::grep;
 
 fn main() {
     grep::grep_fast();
@@ -0,0 +1,119 @@
+// grepの1つのスレッドでの検索(run_read_buf)と、複数のスレッドでの検索(run_parallel)の速さを比べる
+// cargo run --release --bin grep-bench -- [ファイルの数] [1ファイルの行数] [作業用ディレクトリ]
+// 作業用ディレクトリに、擬似乱数で作った単語を並べたファイルを作ってから検索する
+use std::env;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use practice::grep::{self, Command, GrepConfg};
+
+const WORDS: [&str; 16] = [
+    "error", "warning", "info", "debug", "request", "response", "timeout", "connection", "page", "buffer", "index", "table",
+    "commit", "rollback", "retry", "user",
+];
+// 行の最初の単語がerrorかwarningで、最後の単語がtimeoutかretryの行に一致する
+const PATTERN: &str = r"^(error|warning): .*\b(timeout|retry) [0-9]+$";
+
+// 実行ごとに同じ内容になるように、種を固定した擬似乱数(xorshift64)を使う
+struct XorShift(u64);
+
+impl XorShift {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+}
+
+fn generate(dir: &Path, files: usize, lines: usize) -> io::Result<u64> {
+    let _ = fs::remove_dir_all(dir);
+    let mut rng = XorShift(1);
+    let mut bytes = 0;
+    for i in 0..files {
+        // 1つのディレクトリに多くなりすぎないよう、16個ずつサブディレクトリに分ける
+        let sub = dir.join(format!("{:03}", i / 16));
+        fs::create_dir_all(&sub)?;
+        let mut contents = String::new();
+        for _ in 0..lines {
+            let len = 4 + rng.next() % 8;
+            for j in 0..len {
+                let word = WORDS[(rng.next() % WORDS.len() as u64) as usize];
+                contents.push_str(word);
+                contents.push_str(if j == 0 { ": " } else { " " });
+            }
+            contents.push_str(&(rng.next() % 10000).to_string());
+            contents.push('\n');
+        }
+        bytes += contents.len() as u64;
+        fs::write(sub.join(format!("{}.log", i)), contents)?;
+    }
+    Ok(bytes)
+}
+
+fn config(dir: &Path, options: &[&str]) -> GrepConfg {
+    // 端末で実行しても色を付けず、出力を比べられるようにする
+    let args = ["grep", "--color=never", PATTERN, dir.to_str().unwrap()];
+    match grep::parse_args(args.iter().chain(options).map(|arg| arg.to_string())) {
+        Ok(Command::Search(config)) => config,
+        _ => panic!("invalid grep arguments: {:?}", options),
+    }
+}
+
+// 3回測って一番速い時間を返す
+fn measure(mut f: impl FnMut() -> Vec<u8>) -> (Duration, Vec<u8>) {
+    let mut best = Duration::MAX;
+    let mut output = vec![];
+    for _ in 0..3 {
+        let start = Instant::now();
+        output = f();
+        best = best.min(start.elapsed());
+    }
+    (best, output)
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let files: usize = args.first().map_or(256, |arg| arg.parse().expect("number of files"));
+    let lines: usize = args.get(1).map_or(5000, |arg| arg.parse().expect("lines per file"));
+    let dir = env::temp_dir().join(args.get(2).map_or("grep_bench", String::as_str));
+    let bytes = generate(&dir, files, lines).unwrap();
+    println!("{} files, {} lines each, {:.1} MiB, pattern {}", files, lines, bytes as f64 / (1 << 20) as f64, PATTERN);
+
+    let (single, expected) = measure(|| {
+        let mut output = vec![];
+        grep::run_read_buf(&config(&dir, &["-j", "1"]), &mut output).unwrap();
+        output
+    });
+    let matched = expected.iter().filter(|&&b| b == b'\n').count();
+    println!("  {:<24} {:>10.3?} ({} lines matched)", "run_read_buf", single, matched);
+
+    let cpus = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let mut thread_counts = vec![2, 4, 8];
+    if cpus > 1 {
+        thread_counts.push(cpus);
+    }
+    thread_counts.sort_unstable();
+    thread_counts.dedup();
+    for threads in thread_counts {
+        for sorted in [false, true].iter() {
+            let threads_arg = format!("--threads={}", threads);
+            let mut options = vec![threads_arg.as_str()];
+            if *sorted {
+                options.push("--sort");
+            }
+            // 出力が1つのスレッドで検索した時と同じになることは、grep.rsのテストで確かめている
+            let (elapsed, _) = measure(|| {
+                let mut output = vec![];
+                grep::run_parallel(&config(&dir, &options), &mut output).unwrap();
+                output
+            });
+            let name = format!("run_parallel -j{}{}", threads, if *sorted { " --sort" } else { "" });
+            println!("  {:<24} {:>10.3?} ({:.2}x)", name, elapsed, single.as_secs_f64() / elapsed.as_secs_f64());
+        }
+    }
+    io::stdout().flush().unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+}
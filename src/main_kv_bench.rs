@@ -0,0 +1,95 @@
+// B+treeのキーバリューストア(kv)とLSM-tree(lsm)の性能を比べる
+// cargo run --release --bin kv-bench -- [キーの数] [作業用ディレクトリ]
+// 同じ操作(バッチでの書き込み、ランダムな書き込み、ランダムな読み込み、全体の走査)をkv::KvStore越しに行い、かかった時間を表示する
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::time::Instant;
+
+use practice::error::Error;
+use practice::kv::{self, Batch, KvStore};
+use practice::lsm;
+
+const VALUE_SIZE: usize = 100;
+const BATCH_SIZE: usize = 100;
+
+// 実行ごとに同じ順になるように、種を固定した擬似乱数(xorshift64)を使う
+struct XorShift(u64);
+
+impl XorShift {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+}
+
+fn key(i: u64) -> [u8; 8] {
+    i.to_be_bytes()
+}
+
+fn measure(name: &str, count: u64, f: impl FnOnce() -> Result<(), Error>) -> Result<(), Error> {
+    let start = Instant::now();
+    f()?;
+    let elapsed = start.elapsed();
+    println!("  {:<14} {:>10.3?} {:>12.0} ops/s", name, elapsed, count as f64 / elapsed.as_secs_f64());
+    Ok(())
+}
+
+fn run(store: &impl KvStore, n: u64) -> Result<(), Error> {
+    let value = vec![0xabu8; VALUE_SIZE];
+    measure("batch put", n, || {
+        let mut rng = XorShift(1);
+        for _ in 0..n / BATCH_SIZE as u64 {
+            let mut batch = Batch::default();
+            for _ in 0..BATCH_SIZE {
+                batch.put(key(rng.next() % n), &value);
+            }
+            store.apply_batch(batch)?;
+        }
+        store.flush()
+    })?;
+    measure("random put", n / 10, || {
+        let mut rng = XorShift(2);
+        for _ in 0..n / 10 {
+            store.put(&key(rng.next() % n), &value)?;
+        }
+        store.flush()
+    })?;
+    measure("random get", n, || {
+        let mut rng = XorShift(3);
+        for _ in 0..n {
+            store.get(&key(rng.next() % n))?;
+        }
+        Ok(())
+    })?;
+    measure("scan", n, || {
+        for pair in store.scan_prefix(&[]) {
+            pair?;
+        }
+        Ok(())
+    })
+}
+
+fn main() {
+    let mut args = env::args().skip(1);
+    let n: u64 = args.next().and_then(|arg| arg.parse().ok()).unwrap_or(100_000);
+    let dir = args.next().unwrap_or_else(|| "kv-bench".to_string());
+    let dir = Path::new(&dir);
+    let _ = fs::remove_dir_all(dir);
+    fs::create_dir_all(dir).unwrap();
+
+    println!("keys: {}, value size: {} bytes", n, VALUE_SIZE);
+    println!("kv (B+tree)");
+    let result = kv::Db::open_with_pool_size(dir.join("kv.db"), 1024).and_then(|db| run(&db, n));
+    if let Err(err) = result {
+        eprintln!("ERROR: {}", err);
+    }
+    println!("lsm (LSM-tree)");
+    let result = lsm::Db::open(dir.join("lsm")).and_then(|db| run(&db, n));
+    if let Err(err) = result {
+        eprintln!("ERROR: {}", err);
+    }
+    let _ = fs::remove_dir_all(dir);
+}
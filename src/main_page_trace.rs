@@ -0,0 +1,90 @@
+// 記録したページアクセスを、バッファプールの大きさと置き換えアルゴリズムを変えて再生する
+// cargo run --release --bin page-trace -- <記録したファイル> [バッファプールの大きさ,...] [clock|lru|fifo|opt,...]
+// 記録は sql <ヒープファイルのパス> --trace-pages <記録するファイル> などで取る
+use std::collections::HashSet;
+use std::env;
+use std::process;
+
+use practice::page_trace::{self, Event, Policy};
+
+fn usage() -> ! {
+    eprintln!("usage: page-trace <trace> [pool sizes, e.g. 16,64,256] [policies, e.g. clock,lru,fifo,opt]");
+    process::exit(2);
+}
+
+fn parse_list<T: std::str::FromStr>(arg: &str) -> Vec<T> {
+    arg.split(',').map(|s| s.trim().parse().unwrap_or_else(|_| usage())).collect()
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let path = args.first().unwrap_or_else(|| usage());
+    let pool_sizes: Vec<usize> = match args.get(1) {
+        Some(arg) => parse_list(arg),
+        None => vec![16, 64, 256, 1024],
+    };
+    let policies: Vec<Policy> = match args.get(2) {
+        Some(arg) => parse_list(arg),
+        None => Policy::ALL.to_vec(),
+    };
+    if pool_sizes.contains(&0) {
+        usage();
+    }
+
+    let records = page_trace::read_trace(path).unwrap_or_else(|err| {
+        eprintln!("failed to read {}: {}", path, err);
+        process::exit(1);
+    });
+
+    // 記録した時のバッファプールの様子
+    let mut hits = 0;
+    let mut misses = 0;
+    let mut creates = 0;
+    let mut evictions = 0;
+    let mut dirty_evictions = 0;
+    let mut flushes = 0;
+    let mut pages = HashSet::new();
+    for record in &records {
+        match record.event {
+            Event::Fetch { hit: true } => hits += 1,
+            Event::Fetch { hit: false } => misses += 1,
+            Event::Create => creates += 1,
+            Event::Evict { dirty } => {
+                evictions += 1;
+                if dirty {
+                    dirty_evictions += 1;
+                }
+            }
+            Event::Flush => flushes += 1,
+        }
+        pages.insert(record.page_id);
+    }
+    let duration = records.last().map_or(0, |record| record.time);
+    println!("{}: {} records over {:.3}s, {} distinct pages", path, records.len(), duration as f64 / 1e6, pages.len());
+    println!(
+        "recorded: {} fetches ({} hits, {:.1}%), {} creates, {} evictions ({} dirty), {} flushes",
+        hits + misses,
+        hits,
+        if hits + misses == 0 { 0.0 } else { hits as f64 * 100.0 / (hits + misses) as f64 },
+        creates,
+        evictions,
+        dirty_evictions,
+        flushes
+    );
+    println!();
+
+    print!("{:>10}", "pool size");
+    for policy in &policies {
+        print!(" {:>16}", policy.to_string());
+    }
+    println!();
+    for &pool_size in &pool_sizes {
+        print!("{:>10}", pool_size);
+        for &policy in &policies {
+            let stats = page_trace::replay(&records, pool_size, policy);
+            print!(" {:>6.1}% {:>8}", stats.hit_ratio() * 100.0, stats.reads);
+        }
+        println!();
+    }
+    println!("(hit ratio and number of page reads per policy)");
+}
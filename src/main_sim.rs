@@ -0,0 +1,57 @@
+// 決定的シミュレーションテストを実行する
+// cargo run --release --bin sim -- <kv|buffer-pool> [最初のシード] [シードの数] [操作の回数]
+// 失敗したシードは、そのシードから1つだけ実行すれば同じ操作の順番で再現でき、トレースを全て表示する
+use std::env;
+use std::process;
+
+use practice::sim::{self, BufferPoolWorkload, KvWorkload, Options};
+
+fn usage() -> ! {
+    eprintln!("usage: sim <kv|buffer-pool> [first seed] [number of seeds] [steps]");
+    process::exit(2);
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let workload = args.first().cloned().unwrap_or_else(|| usage());
+    let number = |i: usize, default: u64| match args.get(i) {
+        Some(arg) => arg.parse().unwrap_or_else(|_| usage()),
+        None => default,
+    };
+    let first = number(1, 0);
+    let count = number(2, 100);
+    let options = Options {
+        steps: number(3, Options::default().steps as u64) as usize,
+        ..Options::default()
+    };
+
+    for seed in first..first + count {
+        let result = match workload.as_str() {
+            "kv" => sim::run(seed, &options, KvWorkload::new(16, 64)),
+            "buffer-pool" => sim::run(seed, &options, BufferPoolWorkload::new(6)),
+            _ => usage(),
+        };
+        match result {
+            Ok(report) if count == 1 => {
+                for line in &report.trace {
+                    println!("{}", line);
+                }
+                println!("seed {} passed: {} steps, time {}, {:?}", seed, report.steps, report.time, report.disk);
+            }
+            Ok(_) => {}
+            Err(failure) => {
+                if count == 1 {
+                    for line in &failure.trace {
+                        println!("{}", line);
+                    }
+                }
+                eprintln!("{}", failure);
+                eprintln!("replay: sim {} {} 1 {}", workload, seed, options.steps);
+                process::exit(1);
+            }
+        }
+    }
+    if count > 1 {
+        println!("{} seeds passed ({}..{})", count, first, first + count);
+    }
+}
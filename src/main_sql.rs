@@ -0,0 +1,92 @@
+// SQLの対話シェル
+// cargo run --bin sql -- <ヒープファイルのパス>
+// cargo run --bin sql -- --connect <アドレス>  (sql-serverに接続する)
+// cargo run --bin sql -- <ヒープファイルのパス> --trace-pages <記録するファイル>  (ページアクセスを記録する。page-traceで再生できる)
+// ;までを1つの文として実行する。\q で終了
+use std::env;
+use std::io::{self, BufRead, Write};
+
+use practice::executor::Result;
+use practice::server::{Address, Client};
+use practice::sql::{self, Output, Session};
+
+// ファイルを直接開くか、サーバーに接続するか
+enum Backend {
+    Local(Session),
+    Remote(Client),
+}
+
+impl Backend {
+    fn open(args: &[String]) -> Result<Self> {
+        match args {
+            [flag, address] if flag == "--connect" => Ok(Backend::Remote(Client::connect(&address.parse::<Address>()?)?)),
+            [path, flag, trace] if flag == "--trace-pages" => {
+                let mut session = Session::open(path, 64)?;
+                session.trace_pages(trace)?;
+                Ok(Backend::Local(session))
+            }
+            [path] => Ok(Backend::Local(Session::open(path, 64)?)),
+            [] => Ok(Backend::Local(Session::open("sql.db", 64)?)),
+            _ => Err("usage: sql [<path> [--trace-pages <trace>] | --connect <address>]".into()),
+        }
+    }
+
+    fn execute(&mut self, sql: &str) -> Result<Output> {
+        match self {
+            Backend::Local(session) => session.execute(sql),
+            Backend::Remote(client) => client.execute(sql),
+        }
+    }
+
+    fn close(self) -> Result<()> {
+        match self {
+            Backend::Local(session) => session.close(),
+            // 接続を閉じればサーバーがトランザクションをロールバックする
+            Backend::Remote(_) => Ok(()),
+        }
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let mut session = match Backend::open(&args) {
+        Ok(session) => session,
+        Err(err) => {
+            eprintln!("ERROR: {}", err);
+            std::process::exit(1);
+        }
+    };
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+    let mut buf = String::new();
+    loop {
+        print!("{}", if buf.is_empty() { "sql> " } else { "  -> " });
+        io::stdout().flush().unwrap();
+        let line = match lines.next() {
+            Some(Ok(line)) => line,
+            _ => break,
+        };
+        if buf.is_empty() && matches!(line.trim(), "\\q" | "exit" | "quit") {
+            break;
+        }
+        buf.push_str(&line);
+        buf.push('\n');
+        // 1行に複数の文があれば順に実行する
+        while let Some(end) = sql::statement_end(&buf) {
+            let statement: String = buf.drain(..=end).collect();
+            if !statement.trim().trim_end_matches(';').trim().is_empty() {
+                match session.execute(&statement) {
+                    Ok(output) => println!("{}", output),
+                    Err(err) => eprintln!("ERROR: {}", err),
+                }
+            }
+        }
+        if buf.trim().is_empty() {
+            buf.clear();
+        }
+    }
+    println!();
+    if let Err(err) = session.close() {
+        eprintln!("ERROR: {}", err);
+    }
+}
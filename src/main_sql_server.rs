@@ -0,0 +1,45 @@
+// SQLサーバー
+// cargo run --bin sql-server -- <ヒープファイルのパス> [アドレス]
+// アドレスは tcp:127.0.0.1:5433(省略時) か unix:<ソケットのパス>
+// 接続するには cargo run --bin sql -- --connect <アドレス>
+use std::env;
+
+use practice::server::{Address, Listener, Server};
+
+fn main() {
+    let mut args = env::args().skip(1);
+    let path = args.next().unwrap_or_else(|| "sql.db".to_string());
+    let address: Address = match args.next().unwrap_or_else(|| "tcp:127.0.0.1:5433".to_string()).parse() {
+        Ok(address) => address,
+        Err(err) => {
+            eprintln!("ERROR: {}", err);
+            std::process::exit(1);
+        }
+    };
+    let server = match Server::start(&path, 64) {
+        Ok(server) => server,
+        Err(err) => {
+            eprintln!("ERROR: {}", err);
+            std::process::exit(1);
+        }
+    };
+    let listener = match Listener::bind(&address) {
+        Ok(listener) => listener,
+        Err(err) => {
+            eprintln!("ERROR: {}", err);
+            std::process::exit(1);
+        }
+    };
+    println!("listening on {:?}", listener.local_address().unwrap_or(address));
+    if let Err(err) = server.listen(listener) {
+        eprintln!("ERROR: {}", err);
+        if let Err(err) = server.shutdown() {
+            eprintln!("ERROR: {}", err);
+        }
+        std::process::exit(1);
+    }
+    // 受付スレッドは受け付けに失敗しても止まらないので、プロセスを止めるまで待つ
+    loop {
+        std::thread::park();
+    }
+}
@@ -0,0 +1,322 @@
+// memcomparable format
+// 型付きの値をバイト列に変換する際に、バイト列をmemcmp(先頭から1byteずつ比較)した順序と
+// 元の値の論理的な順序が一致するようにエンコードする
+// B+treeのキーはバイト列のまま比較したいので、Pageの上に作るインデックスのキーはこの形式で保存する
+
+// バイト列は8byteずつのブロックに区切り、各ブロックの後ろに1byteの「続きがあるか」を表すマーカーを置く
+// 8byte + 1byte = 9byteを1単位とする
+const ESCAPE_LENGTH: usize = 9;
+
+// 型ごとのタグ
+// 異なる型同士を比較した場合はタグの値の順序になる
+// NULLは全ての値より小さく並ぶ
+// TAG_ENDは複合タプルの終端で、どのタグよりも小さいので短いタプルが先に並ぶ
+const TAG_END: u8 = 0x00;
+const TAG_NULL: u8 = 0x01;
+const TAG_INT: u8 = 0x02;
+const TAG_UINT: u8 = 0x03;
+const TAG_FLOAT: u8 = 0x04;
+const TAG_BYTES: u8 = 0x05;
+const TAG_STR: u8 = 0x06;
+const TAG_TUPLE: u8 = 0x07;
+
+// i64の符号ビット
+const SIGN_BIT: u64 = 1 << 63;
+
+/// エンコード・デコードの対象になる型付きの値
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Null,
+    // 符号付き整数
+    Int(i64),
+    // 符号なし整数
+    UInt(u64),
+    Float(f64),
+    Bytes(Vec<u8>),
+    Str(String),
+    // 複合タプル(タプルの中にタプルを入れることも出来る)
+    Tuple(Vec<Value>),
+}
+
+/// バイト列をmemcomparable formatにエンコードしてdstの末尾に追加する
+///
+/// # Examples
+///
+/// ```
+/// use practice::memcmpable;
+/// let mut a = vec![];
+/// let mut b = vec![];
+/// memcmpable::encode_bytes(b"abc", &mut a);
+/// memcmpable::encode_bytes(b"abcd", &mut b);
+/// assert!(a < b);
+/// ```
+pub fn encode_bytes(mut src: &[u8], dst: &mut Vec<u8>) {
+    loop {
+        let copy_len = std::cmp::min(ESCAPE_LENGTH - 1, src.len());
+        dst.extend_from_slice(&src[..copy_len]);
+        src = &src[copy_len..];
+        if src.is_empty() {
+            // 最後のブロックは8byteに満たない分を0で埋め、マーカーには実際に書いたバイト数を入れる
+            let pad_size = ESCAPE_LENGTH - 1 - copy_len;
+            dst.resize(dst.len() + pad_size, 0);
+            dst.push(copy_len as u8);
+            break;
+        }
+        // 続きがある場合のマーカーはどの「実際のバイト数」より大きい9にする
+        // これにより長い方が後ろに並ぶ
+        dst.push(ESCAPE_LENGTH as u8);
+    }
+}
+
+/// encode_bytesでエンコードしたバイト列をデコードしてdstの末尾に追加する
+/// srcは読み取った分だけ先に進む
+pub fn decode_bytes(src: &mut &[u8], dst: &mut Vec<u8>) -> Result<(), &'static str> {
+    loop {
+        if src.len() < ESCAPE_LENGTH {
+            return Err("バイト列の途中で入力が終わりました");
+        }
+        let extra = src[ESCAPE_LENGTH - 1];
+        let len = std::cmp::min(ESCAPE_LENGTH - 1, extra as usize);
+        dst.extend_from_slice(&src[..len]);
+        *src = &src[ESCAPE_LENGTH..];
+        if extra < ESCAPE_LENGTH as u8 {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// 値の並びをエンコードしてbytesの末尾に追加する
+/// 要素を前から順に連結するだけなので、(1)は(1, 2)のprefixになり、(1) < (1, 2)の順で並ぶ
+///
+/// # Examples
+///
+/// ```
+/// use practice::memcmpable::{self, Value};
+/// let mut a = vec![];
+/// let mut b = vec![];
+/// memcmpable::encode(&[Value::Int(-1), Value::Str("b".to_string())], &mut a);
+/// memcmpable::encode(&[Value::Int(3), Value::Str("a".to_string())], &mut b);
+/// assert!(a < b);
+/// ```
+pub fn encode(values: &[Value], bytes: &mut Vec<u8>) {
+    for value in values {
+        encode_value(value, bytes);
+    }
+}
+
+/// 値を1つエンコードしてbytesの末尾に追加する
+pub fn encode_value(value: &Value, bytes: &mut Vec<u8>) {
+    match value {
+        Value::Null => bytes.push(TAG_NULL),
+        Value::Int(n) => {
+            bytes.push(TAG_INT);
+            // 符号ビットを反転させると、負の数が正の数より小さいバイト列になる
+            bytes.extend_from_slice(&((*n as u64) ^ SIGN_BIT).to_be_bytes());
+        }
+        Value::UInt(n) => {
+            bytes.push(TAG_UINT);
+            // ビッグエンディアンにすれば上位バイトから比較される
+            bytes.extend_from_slice(&n.to_be_bytes());
+        }
+        Value::Float(f) => {
+            bytes.push(TAG_FLOAT);
+            bytes.extend_from_slice(&encode_float(*f).to_be_bytes());
+        }
+        Value::Bytes(b) => {
+            bytes.push(TAG_BYTES);
+            encode_bytes(b, bytes);
+        }
+        Value::Str(s) => {
+            bytes.push(TAG_STR);
+            // UTF-8のバイト列の順序はコードポイントの順序と一致する
+            encode_bytes(s.as_bytes(), bytes);
+        }
+        Value::Tuple(elems) => {
+            bytes.push(TAG_TUPLE);
+            encode(elems, bytes);
+            bytes.push(TAG_END);
+        }
+    }
+}
+
+// f64を大小関係を保ったままu64に変換する
+fn encode_float(f: f64) -> u64 {
+    // -0.0と0.0は同じ値として扱う
+    // NaNは全て同じビット列に揃え、+∞より後ろに並べる
+    let bits = if f == 0.0 {
+        0
+    } else if f.is_nan() {
+        f64::NAN.to_bits() & !SIGN_BIT
+    } else {
+        f.to_bits()
+    };
+    if bits & SIGN_BIT != 0 {
+        // 負の数は絶対値が大きいほど小さいので全ビットを反転
+        !bits
+    } else {
+        // 正の数は符号ビットを立てて負の数より後ろに並べる
+        bits | SIGN_BIT
+    }
+}
+
+fn decode_float(bits: u64) -> f64 {
+    if bits & SIGN_BIT != 0 {
+        f64::from_bits(bits & !SIGN_BIT)
+    } else {
+        f64::from_bits(!bits)
+    }
+}
+
+/// encodeでエンコードしたバイト列を値の並びにデコードする
+pub fn decode(mut bytes: &[u8]) -> Result<Vec<Value>, &'static str> {
+    let mut values = vec![];
+    while !bytes.is_empty() {
+        values.push(decode_value(&mut bytes)?);
+    }
+    Ok(values)
+}
+
+/// 値を1つデコードする
+/// bytesは読み取った分だけ先に進む
+pub fn decode_value(bytes: &mut &[u8]) -> Result<Value, &'static str> {
+    let (&tag, rest) = bytes.split_first().ok_or("入力が空です")?;
+    *bytes = rest;
+    let value = match tag {
+        TAG_NULL => Value::Null,
+        TAG_INT => Value::Int((read_u64(bytes)? ^ SIGN_BIT) as i64),
+        TAG_UINT => Value::UInt(read_u64(bytes)?),
+        TAG_FLOAT => Value::Float(decode_float(read_u64(bytes)?)),
+        TAG_BYTES => {
+            let mut b = vec![];
+            decode_bytes(bytes, &mut b)?;
+            Value::Bytes(b)
+        }
+        TAG_STR => {
+            let mut b = vec![];
+            decode_bytes(bytes, &mut b)?;
+            Value::Str(String::from_utf8(b).map_err(|_| "不正なUTF-8です")?)
+        }
+        TAG_TUPLE => {
+            let mut elems = vec![];
+            loop {
+                match bytes.first() {
+                    Some(&TAG_END) => {
+                        *bytes = &bytes[1..];
+                        break;
+                    }
+                    Some(_) => elems.push(decode_value(bytes)?),
+                    None => return Err("タプルの終端がありません"),
+                }
+            }
+            Value::Tuple(elems)
+        }
+        _ => return Err("不明なタグです"),
+    };
+    Ok(value)
+}
+
+fn read_u64(bytes: &mut &[u8]) -> Result<u64, &'static str> {
+    if bytes.len() < 8 {
+        return Err("数値の途中で入力が終わりました");
+    }
+    let mut buf = [0u8; 8];
+    buf.copy_from_slice(&bytes[..8]);
+    *bytes = &bytes[8..];
+    Ok(u64::from_be_bytes(buf))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn enc(values: &[Value]) -> Vec<u8> {
+        let mut bytes = vec![];
+        encode(values, &mut bytes);
+        bytes
+    }
+
+    // 昇順に並べた値をエンコードしても昇順のままであることを確認する
+    fn assert_sorted(values: Vec<Value>) {
+        let encoded: Vec<Vec<u8>> = values.iter().map(|v| enc(std::slice::from_ref(v))).collect();
+        for pair in encoded.windows(2) {
+            assert!(pair[0] < pair[1], "{:?} < {:?}", pair[0], pair[1]);
+        }
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let values = vec![
+            Value::Null,
+            Value::Int(-42),
+            Value::UInt(42),
+            Value::Float(-1.5),
+            Value::Bytes(b"hello world!".to_vec()),
+            Value::Str("こんにちは".to_string()),
+            Value::Tuple(vec![Value::Int(1), Value::Tuple(vec![]), Value::Str("".to_string())]),
+        ];
+        assert_eq!(decode(&enc(&values)).unwrap(), values);
+    }
+
+    #[test]
+    fn test_int_order() {
+        assert_sorted(vec![
+            Value::Int(i64::MIN), Value::Int(-1), Value::Int(0), Value::Int(1), Value::Int(i64::MAX),
+        ]);
+        assert_sorted(vec![Value::UInt(0), Value::UInt(255), Value::UInt(256), Value::UInt(u64::MAX)]);
+    }
+
+    #[test]
+    fn test_float_order() {
+        assert_sorted(vec![
+            Value::Float(f64::NEG_INFINITY),
+            Value::Float(-2.5),
+            Value::Float(-f64::MIN_POSITIVE),
+            Value::Float(0.0),
+            Value::Float(f64::MIN_POSITIVE),
+            Value::Float(3.0),
+            Value::Float(f64::INFINITY),
+            Value::Float(f64::NAN),
+        ]);
+        assert_eq!(enc(&[Value::Float(-0.0)]), enc(&[Value::Float(0.0)]));
+    }
+
+    #[test]
+    fn test_bytes_order() {
+        assert_sorted(vec![
+            Value::Bytes(vec![]),
+            Value::Bytes(vec![0]),
+            Value::Bytes(vec![0, 0]),
+            Value::Bytes(b"abcdefgh".to_vec()),
+            Value::Bytes(b"abcdefgh\0".to_vec()),
+            Value::Bytes(b"abcdefghi".to_vec()),
+            Value::Bytes(b"b".to_vec()),
+        ]);
+        assert_sorted(vec![
+            Value::Str("a".to_string()),
+            Value::Str("ab".to_string()),
+            Value::Str("あ".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn test_null_and_tuple_order() {
+        // NULLは全ての値より前に並ぶ
+        assert!(enc(&[Value::Null]) < enc(&[Value::Int(i64::MIN)]));
+        // タプルは前の要素から順に比較され、短い方が先に並ぶ
+        assert!(enc(&[Value::Int(1)]) < enc(&[Value::Int(1), Value::Int(0)]));
+        assert!(enc(&[Value::Int(1), Value::Int(9)]) < enc(&[Value::Int(2), Value::Int(0)]));
+        assert_sorted(vec![
+            Value::Tuple(vec![Value::Str("a".to_string())]),
+            Value::Tuple(vec![Value::Str("a".to_string()), Value::Null]),
+            Value::Tuple(vec![Value::Str("b".to_string())]),
+        ]);
+    }
+
+    #[test]
+    fn test_decode_error() {
+        assert!(decode(&[TAG_INT, 0, 0]).is_err());
+        assert!(decode(&[TAG_TUPLE, TAG_NULL]).is_err());
+        assert!(decode(&[0xff]).is_err());
+    }
+}
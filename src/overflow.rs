@@ -0,0 +1,266 @@
+// オーバーフローページ
+// 1ページに入りきらない大きな値を、連結したページに分けて置く(PostgreSQLのTOASTと同じ考え方)
+// ヒープテーブルやB+treeには値の代わりに、先頭のページIDと長さ(ポインタ)だけを入れる
+//
+// ページの中身: | 次のページID(u64) | このページのデータの長さ(u16) | データ |
+// ポインタ: | 先頭のページID(u64) | 値の長さ(u64) |
+//
+// ページの書き込みはTxnLogでWALに書くので、クラッシュしても呼び出し側の操作と一緒にREDOされる
+// 解放したページは中身を0にしてからDiskManagerに返す
+// 再利用したページは0の状態から書き直すので、REDOの時に前の中身が混ざらない
+
+use std::io::{self, Read};
+
+use crate::buffer_pool::{BufferPoolManager, Error, PAGE_LSN_SIZE};
+use crate::disk_manager::{PageId, PAGE_SIZE};
+use crate::wal::TxnLog;
+
+/// これより大きな値はオーバーフローページに置く
+pub const THRESHOLD: usize = PAGE_SIZE / 4;
+
+/// エンコードしたポインタのサイズ
+pub const POINTER_SIZE: usize = 16;
+
+const NEXT_PAGE_ID_SIZE: usize = 8;
+const LENGTH_SIZE: usize = 2;
+// 1ページに入るデータの長さ
+const CHUNK_SIZE: usize = PAGE_SIZE - PAGE_LSN_SIZE - NEXT_PAGE_ID_SIZE - LENGTH_SIZE;
+
+/// オーバーフローページに置いた値の場所
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pointer {
+    pub first_page_id: PageId,
+    pub len: u64,
+}
+
+impl Pointer {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = self.first_page_id.0.to_le_bytes().to_vec();
+        bytes.extend_from_slice(&self.len.to_le_bytes());
+        bytes
+    }
+
+    pub fn decode(bytes: &[u8]) -> Self {
+        Self {
+            first_page_id: PageId(read_u64(&bytes[..8])),
+            len: read_u64(&bytes[8..16]),
+        }
+    }
+}
+
+fn read_u64(bytes: &[u8]) -> u64 {
+    let mut buf = [0u8; 8];
+    buf.copy_from_slice(&bytes[..8]);
+    u64::from_le_bytes(buf)
+}
+
+// ページの中身から(次のページID, データ)を読む
+fn read_chunk(body: &[u8]) -> (PageId, &[u8]) {
+    let next = PageId(read_u64(&body[..NEXT_PAGE_ID_SIZE]));
+    let len = u16::from_le_bytes([body[NEXT_PAGE_ID_SIZE], body[NEXT_PAGE_ID_SIZE + 1]]) as usize;
+    let start = NEXT_PAGE_ID_SIZE + LENGTH_SIZE;
+    (next, &body[start..start + len])
+}
+
+/// 値をオーバーフローページに書く
+pub fn write(bufmgr: &mut BufferPoolManager, log: &TxnLog, data: &[u8]) -> Result<Pointer, Error> {
+    write_from(bufmgr, log, &mut &data[..])
+}
+
+/// readerから読んだ値を、ページ1つ分ずつオーバーフローページに書く
+/// 値全体をメモリに載せずに済むので、とても大きな値に使う
+pub fn write_from(bufmgr: &mut BufferPoolManager, log: &TxnLog, reader: &mut impl Read) -> Result<Pointer, Error> {
+    let mut pointer = Pointer {
+        first_page_id: PageId::INVALID_PAGE_ID,
+        len: 0,
+    };
+    let mut prev = None;
+    let mut chunk = vec![0u8; CHUNK_SIZE];
+    loop {
+        let len = fill(reader, &mut chunk)?;
+        if len == 0 {
+            return Ok(pointer);
+        }
+        let buffer = bufmgr.create_page()?;
+        log.modify_page(&buffer, |body| {
+            body[..NEXT_PAGE_ID_SIZE].copy_from_slice(&PageId::INVALID_PAGE_ID.0.to_le_bytes());
+            body[NEXT_PAGE_ID_SIZE..NEXT_PAGE_ID_SIZE + LENGTH_SIZE].copy_from_slice(&(len as u16).to_le_bytes());
+            body[NEXT_PAGE_ID_SIZE + LENGTH_SIZE..][..len].copy_from_slice(&chunk[..len]);
+        })?;
+        // 前のページから新しいページに繋ぐ
+        match prev {
+            Some(prev) => {
+                let prev = bufmgr.fetch_page(prev)?;
+                log.modify_page(&prev, |body| body[..NEXT_PAGE_ID_SIZE].copy_from_slice(&buffer.page_id.0.to_le_bytes()))?;
+            }
+            None => pointer.first_page_id = buffer.page_id,
+        }
+        pointer.len += len as u64;
+        prev = Some(buffer.page_id);
+        if len < CHUNK_SIZE {
+            return Ok(pointer);
+        }
+    }
+}
+
+// bufがいっぱいになるか、終わりに達するまで読む
+fn fill(reader: &mut impl Read, buf: &mut [u8]) -> Result<usize, Error> {
+    let mut len = 0;
+    while len < buf.len() {
+        match reader.read(&mut buf[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err.into()),
+        }
+    }
+    Ok(len)
+}
+
+/// オーバーフローページの値を全て読む
+pub fn read(bufmgr: &mut BufferPoolManager, pointer: Pointer) -> Result<Vec<u8>, Error> {
+    Reader::new(pointer).read_to_end(bufmgr)
+}
+
+/// オーバーフローページを解放する
+/// 中身を0にしてからDiskManagerに返すので、呼び出し側の操作が取り消されても元には戻らない
+pub fn free(bufmgr: &mut BufferPoolManager, log: &TxnLog, pointer: Pointer) -> Result<(), Error> {
+    let mut page_id = pointer.first_page_id;
+    while let Some(current) = page_id.valid() {
+        let buffer = bufmgr.fetch_page(current)?;
+        page_id = log.modify_page(&buffer, |body| {
+            let (next, _) = read_chunk(body);
+            body.fill(0);
+            next
+        })?;
+        drop(buffer);
+        log.free_page(bufmgr, current)?;
+    }
+    Ok(())
+}
+
+/// 値をページ1つ分ずつ読むリーダー
+/// バッファプールを借用し続けないよう、next_chunk()のたびにbufmgrを受け取る
+pub struct Reader {
+    // ページに直接入っていた値
+    inline: Option<Vec<u8>>,
+    next_page_id: PageId,
+    remaining: u64,
+}
+
+impl Reader {
+    pub fn new(pointer: Pointer) -> Self {
+        Self {
+            inline: None,
+            next_page_id: pointer.first_page_id,
+            remaining: pointer.len,
+        }
+    }
+
+    /// オーバーフローページに置かなかった値も同じように読めるようにする
+    pub fn inline(data: Vec<u8>) -> Self {
+        Self {
+            remaining: data.len() as u64,
+            inline: Some(data),
+            next_page_id: PageId::INVALID_PAGE_ID,
+        }
+    }
+
+    /// まだ読んでいないバイト数
+    pub fn remaining(&self) -> u64 {
+        self.remaining
+    }
+
+    /// 次の断片を返す。読み終われば None
+    pub fn next_chunk(&mut self, bufmgr: &mut BufferPoolManager) -> Result<Option<Vec<u8>>, Error> {
+        if let Some(data) = self.inline.take() {
+            self.remaining = 0;
+            return Ok(Some(data));
+        }
+        let page_id = match self.next_page_id.valid() {
+            Some(page_id) if self.remaining > 0 => page_id,
+            _ => return Ok(None),
+        };
+        let buffer = bufmgr.fetch_page(page_id)?;
+        let page = buffer.page.borrow();
+        let (next, chunk) = read_chunk(&page[PAGE_LSN_SIZE..]);
+        self.next_page_id = next;
+        self.remaining = self.remaining.saturating_sub(chunk.len() as u64);
+        Ok(Some(chunk.to_vec()))
+    }
+
+    /// 残りを全て読む
+    pub fn read_to_end(mut self, bufmgr: &mut BufferPoolManager) -> Result<Vec<u8>, Error> {
+        let mut data = Vec::with_capacity(self.remaining as usize);
+        while let Some(chunk) = self.next_chunk(bufmgr)? {
+            data.extend_from_slice(&chunk);
+        }
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction;
+    use crate::wal;
+
+    #[test]
+    fn test_write_read_free() {
+        let path = std::env::temp_dir().join(format!("overflow_test_{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(wal::log_path(&path));
+        let (mut bufmgr, txn_mgr, _) = transaction::open(&path, 8).unwrap();
+        let txn = txn_mgr.begin().unwrap();
+        // ちょうどページの境目で終わる値と、半端な値
+        let exact: Vec<u8> = (0..CHUNK_SIZE * 3).map(|i| (i % 251) as u8).collect();
+        let odd: Vec<u8> = (0..CHUNK_SIZE * 2 + 7).map(|i| (i % 241) as u8).collect();
+        let a = write(&mut bufmgr, txn.log(), &exact).unwrap();
+        let b = write(&mut bufmgr, txn.log(), &odd).unwrap();
+        assert_eq!(read(&mut bufmgr, a).unwrap(), exact);
+        assert_eq!(read(&mut bufmgr, b).unwrap(), odd);
+        assert_eq!(Pointer::decode(&b.encode()), b);
+
+        let mut reader = Reader::new(b);
+        let mut sizes = vec![];
+        while let Some(chunk) = reader.next_chunk(&mut bufmgr).unwrap() {
+            sizes.push(chunk.len());
+        }
+        assert_eq!(sizes, vec![CHUNK_SIZE, CHUNK_SIZE, 7]);
+        assert_eq!(reader.remaining(), 0);
+
+        // 解放したページは次の書き込みで再利用される
+        let pages = bufmgr.disk_mut().num_pages();
+        free(&mut bufmgr, txn.log(), a).unwrap();
+        let c = write(&mut bufmgr, txn.log(), &odd).unwrap();
+        assert_eq!(bufmgr.disk_mut().num_pages(), pages);
+        assert_eq!(read(&mut bufmgr, c).unwrap(), odd);
+        assert_eq!(read(&mut bufmgr, b).unwrap(), odd);
+        txn_mgr.commit(txn).unwrap();
+    }
+
+    #[test]
+    fn test_long_chain() {
+        let path = std::env::temp_dir().join(format!("overflow_test_chain_{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(wal::log_path(&path));
+        // バッファプールより多くのページに跨る値
+        let (mut bufmgr, txn_mgr, _) = transaction::open(&path, 4).unwrap();
+        let txn = txn_mgr.begin().unwrap();
+        let long: Vec<u8> = (0..CHUNK_SIZE * 20 + 1).map(|i| (i % 239) as u8).collect();
+        let pages = bufmgr.disk_mut().num_pages();
+        let pointer = write(&mut bufmgr, txn.log(), &long).unwrap();
+        assert_eq!(bufmgr.disk_mut().num_pages(), pages + 21);
+        assert_eq!(read(&mut bufmgr, pointer).unwrap(), long);
+        assert_eq!(Reader::new(pointer).read_to_end(&mut bufmgr).unwrap(), long);
+
+        // 解放すると連結した全てのページが再利用される
+        free(&mut bufmgr, txn.log(), pointer).unwrap();
+        let a = write(&mut bufmgr, txn.log(), &long[..CHUNK_SIZE * 10]).unwrap();
+        let b = write(&mut bufmgr, txn.log(), &long[CHUNK_SIZE * 10..]).unwrap();
+        assert_eq!(bufmgr.disk_mut().num_pages(), pages + 21);
+        assert_eq!(read(&mut bufmgr, a).unwrap(), &long[..CHUNK_SIZE * 10]);
+        assert_eq!(read(&mut bufmgr, b).unwrap(), &long[CHUNK_SIZE * 10..]);
+        txn_mgr.commit(txn).unwrap();
+    }
+}
@@ -0,0 +1,525 @@
+// バッファプールのページアクセスの記録と再生
+// 本番のワークロードでBufferPoolManagerが行ったページの取得・作成・追い出し・書き出しをファイルに記録し、
+// 後からバッファプールの大きさや置き換えアルゴリズムを変えて再生して、ヒット率を比べる
+//
+// 記録するファイルの形式
+// | マジックナンバー(8byte) | バージョン(u8) | レコード ... |
+// レコード：| 種類(u8) | ページID(可変長整数) | 前のレコードからの経過時間(マイクロ秒, 可変長整数) |
+// 可変長整数はLEB128(7bitずつ、続きがあれば最上位bitを立てる)なので、1レコードは多くの場合4byte前後になる
+//
+// 再生ではFetchとCreateを「ページへのアクセス」として順に置き換えアルゴリズムに与える
+// 記録にはpin止めしていた期間が無いので、再生ではアクセスしたページはすぐにpin止めを外したものとして扱う
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Instant;
+
+use crate::disk_manager::PageId;
+
+const MAGIC: &[u8; 8] = b"PRACTPGT";
+const FORMAT_VERSION: u8 = 1;
+
+const KIND_FETCH_HIT: u8 = 1;
+const KIND_FETCH_MISS: u8 = 2;
+const KIND_CREATE: u8 = 3;
+const KIND_EVICT: u8 = 4;
+const KIND_EVICT_DIRTY: u8 = 5;
+const KIND_FLUSH: u8 = 6;
+
+/// 記録する出来事
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    /// fetch_page。hitはバッファプールにあったかどうか
+    Fetch { hit: bool },
+    /// create_page
+    Create,
+    /// ページを追い出した。dirtyなら書き戻した
+    Evict { dirty: bool },
+    /// flush・flush_pageでページを書き出した
+    Flush,
+}
+
+impl Event {
+    fn kind(self) -> u8 {
+        match self {
+            Event::Fetch { hit: true } => KIND_FETCH_HIT,
+            Event::Fetch { hit: false } => KIND_FETCH_MISS,
+            Event::Create => KIND_CREATE,
+            Event::Evict { dirty: false } => KIND_EVICT,
+            Event::Evict { dirty: true } => KIND_EVICT_DIRTY,
+            Event::Flush => KIND_FLUSH,
+        }
+    }
+
+    fn from_kind(kind: u8) -> Option<Self> {
+        Some(match kind {
+            KIND_FETCH_HIT => Event::Fetch { hit: true },
+            KIND_FETCH_MISS => Event::Fetch { hit: false },
+            KIND_CREATE => Event::Create,
+            KIND_EVICT => Event::Evict { dirty: false },
+            KIND_EVICT_DIRTY => Event::Evict { dirty: true },
+            KIND_FLUSH => Event::Flush,
+            _ => return None,
+        })
+    }
+}
+
+/// 記録の1レコード
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Record {
+    /// 記録を始めてからの時間(マイクロ秒)
+    pub time: u64,
+    pub event: Event,
+    pub page_id: PageId,
+}
+
+fn write_varint(buf: &mut Vec<u8>, mut n: u64) {
+    while n >= 0x80 {
+        buf.push(n as u8 | 0x80);
+        n >>= 7;
+    }
+    buf.push(n as u8);
+}
+
+// 読み終わっていればNone
+fn read_varint(reader: &mut impl Read) -> io::Result<Option<u64>> {
+    let mut n = 0u64;
+    for shift in (0..64).step_by(7) {
+        let mut byte = [0u8];
+        if reader.read(&mut byte)? == 0 {
+            if shift == 0 {
+                return Ok(None);
+            }
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated varint"));
+        }
+        n |= ((byte[0] & 0x7f) as u64) << shift;
+        if byte[0] & 0x80 == 0 {
+            return Ok(Some(n));
+        }
+    }
+    Err(io::Error::new(io::ErrorKind::InvalidData, "varint is too long"))
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+/// ページアクセスをファイルに記録する
+/// BufferPoolManager::start_traceで渡すと、バッファプールの操作のたびに記録される
+pub struct Tracer {
+    writer: BufWriter<File>,
+    start: Instant,
+    last_time: u64,
+    buf: Vec<u8>,
+}
+
+impl Tracer {
+    /// 記録するファイルを作る(あれば上書きする)
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(MAGIC)?;
+        writer.write_all(&[FORMAT_VERSION])?;
+        Ok(Self {
+            writer,
+            start: Instant::now(),
+            last_time: 0,
+            buf: Vec::with_capacity(32),
+        })
+    }
+
+    pub fn record(&mut self, event: Event, page_id: PageId) -> io::Result<()> {
+        let time = self.start.elapsed().as_micros() as u64;
+        self.buf.clear();
+        self.buf.push(event.kind());
+        write_varint(&mut self.buf, page_id.to_u64());
+        write_varint(&mut self.buf, time - self.last_time);
+        self.last_time = time;
+        self.writer.write_all(&self.buf)
+    }
+
+    /// バッファに溜まっているレコードをファイルに書き出す
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// 記録したファイルを読む
+pub fn read_trace(path: impl AsRef<Path>) -> io::Result<Vec<Record>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut header = [0u8; 9];
+    reader.read_exact(&mut header)?;
+    if &header[..8] != MAGIC {
+        return Err(invalid_data("not a page trace file"));
+    }
+    if header[8] != FORMAT_VERSION {
+        return Err(invalid_data("unsupported page trace version"));
+    }
+    let mut records = vec![];
+    let mut time = 0;
+    loop {
+        let mut kind = [0u8];
+        if reader.read(&mut kind)? == 0 {
+            break;
+        }
+        let event = Event::from_kind(kind[0]).ok_or_else(|| invalid_data("invalid event kind"))?;
+        let page_id = read_varint(&mut reader)?.ok_or_else(|| invalid_data("truncated record"))?;
+        time += read_varint(&mut reader)?.ok_or_else(|| invalid_data("truncated record"))?;
+        records.push(Record {
+            time,
+            event,
+            page_id: PageId(page_id),
+        });
+    }
+    Ok(records)
+}
+
+/// 再生に使うページの置き換えアルゴリズム
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Policy {
+    /// BufferPoolと同じClock-sweep
+    Clock,
+    /// 一番長く使われていないページを追い出す
+    Lru,
+    /// 一番先に読み込んだページを追い出す
+    Fifo,
+    /// 次に使われるのが一番遠いページを追い出す(Béládyの最適アルゴリズム)
+    /// 未来のアクセスが分かっている再生でしか使えないが、ヒット率の上限が分かる
+    Optimal,
+}
+
+impl Policy {
+    pub const ALL: [Policy; 4] = [Policy::Clock, Policy::Lru, Policy::Fifo, Policy::Optimal];
+}
+
+impl fmt::Display for Policy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Policy::Clock => "clock",
+            Policy::Lru => "lru",
+            Policy::Fifo => "fifo",
+            Policy::Optimal => "opt",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for Policy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "clock" => Ok(Policy::Clock),
+            "lru" => Ok(Policy::Lru),
+            "fifo" => Ok(Policy::Fifo),
+            "opt" => Ok(Policy::Optimal),
+            _ => Err(format!("unknown replacement policy: {}", s)),
+        }
+    }
+}
+
+/// 再生した結果
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ReplayStats {
+    /// ページへのアクセス(FetchとCreate)の数
+    pub accesses: u64,
+    /// バッファプールにあったアクセスの数
+    pub hits: u64,
+    /// ディスクから読んだ数(バッファプールに無かったFetch)
+    pub reads: u64,
+    /// 追い出した数
+    pub evictions: u64,
+}
+
+impl ReplayStats {
+    pub fn hit_ratio(&self) -> f64 {
+        if self.accesses == 0 {
+            return 0.0;
+        }
+        self.hits as f64 / self.accesses as f64
+    }
+}
+
+// 置き換えアルゴリズムの共通の操作
+trait Replacer {
+    // pageがバッファプールにあればアクセスしたことにしてtrueを返す
+    fn hit(&mut self, page_id: PageId, index: usize) -> bool;
+    // pageを読み込む。満杯なら1つ追い出してtrueを返す
+    fn insert(&mut self, page_id: PageId, index: usize) -> bool;
+}
+
+// BufferPool::evictと同じClock-sweep
+struct ClockReplacer {
+    frames: Vec<(Option<PageId>, u64)>,
+    page_table: HashMap<PageId, usize>,
+    next_victim: usize,
+}
+
+impl Replacer for ClockReplacer {
+    fn hit(&mut self, page_id: PageId, _index: usize) -> bool {
+        match self.page_table.get(&page_id) {
+            Some(&frame) => {
+                self.frames[frame].1 += 1;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn insert(&mut self, page_id: PageId, _index: usize) -> bool {
+        // 再生ではpin止めしたままのページは無いので、利用回数を減らしていけば必ず見つかる
+        while self.frames[self.next_victim].1 > 0 {
+            self.frames[self.next_victim].1 -= 1;
+            self.next_victim = (self.next_victim + 1) % self.frames.len();
+        }
+        let frame = &mut self.frames[self.next_victim];
+        let evicted = frame.0.replace(page_id);
+        frame.1 = 1;
+        if let Some(evicted) = evicted {
+            self.page_table.remove(&evicted);
+        }
+        self.page_table.insert(page_id, self.next_victim);
+        evicted.is_some()
+    }
+}
+
+struct LruReplacer {
+    capacity: usize,
+    // 最後に使った時刻 → ページ
+    by_time: BTreeMap<usize, PageId>,
+    last_used: HashMap<PageId, usize>,
+}
+
+impl Replacer for LruReplacer {
+    fn hit(&mut self, page_id: PageId, index: usize) -> bool {
+        match self.last_used.get_mut(&page_id) {
+            Some(last) => {
+                self.by_time.remove(last);
+                *last = index;
+                self.by_time.insert(index, page_id);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn insert(&mut self, page_id: PageId, index: usize) -> bool {
+        let evict = self.last_used.len() >= self.capacity;
+        if evict {
+            let (_, victim) = self.by_time.pop_first().unwrap();
+            self.last_used.remove(&victim);
+        }
+        self.by_time.insert(index, page_id);
+        self.last_used.insert(page_id, index);
+        evict
+    }
+}
+
+struct FifoReplacer {
+    capacity: usize,
+    queue: VecDeque<PageId>,
+    resident: HashSet<PageId>,
+}
+
+impl Replacer for FifoReplacer {
+    fn hit(&mut self, page_id: PageId, _index: usize) -> bool {
+        self.resident.contains(&page_id)
+    }
+
+    fn insert(&mut self, page_id: PageId, _index: usize) -> bool {
+        let evict = self.queue.len() >= self.capacity;
+        if evict {
+            let victim = self.queue.pop_front().unwrap();
+            self.resident.remove(&victim);
+        }
+        self.queue.push_back(page_id);
+        self.resident.insert(page_id);
+        evict
+    }
+}
+
+struct OptimalReplacer {
+    capacity: usize,
+    // アクセスごとに、同じページが次にアクセスされる位置(無ければusize::MAX)
+    next_use: Vec<usize>,
+    // (次に使う位置, ページ)
+    by_next_use: BTreeSet<(usize, PageId)>,
+    resident: HashMap<PageId, usize>,
+}
+
+impl OptimalReplacer {
+    fn new(capacity: usize, accesses: &[PageId]) -> Self {
+        let mut next_use = vec![usize::MAX; accesses.len()];
+        let mut seen: HashMap<PageId, usize> = HashMap::new();
+        for (index, page_id) in accesses.iter().enumerate().rev() {
+            if let Some(next) = seen.insert(*page_id, index) {
+                next_use[index] = next;
+            }
+        }
+        Self {
+            capacity,
+            next_use,
+            by_next_use: BTreeSet::new(),
+            resident: HashMap::new(),
+        }
+    }
+
+    fn touch(&mut self, page_id: PageId, index: usize) {
+        let next = self.next_use[index];
+        if let Some(old) = self.resident.insert(page_id, next) {
+            self.by_next_use.remove(&(old, page_id));
+        }
+        self.by_next_use.insert((next, page_id));
+    }
+}
+
+impl Replacer for OptimalReplacer {
+    fn hit(&mut self, page_id: PageId, index: usize) -> bool {
+        if !self.resident.contains_key(&page_id) {
+            return false;
+        }
+        self.touch(page_id, index);
+        true
+    }
+
+    fn insert(&mut self, page_id: PageId, index: usize) -> bool {
+        let evict = self.resident.len() >= self.capacity;
+        if evict {
+            let (_, victim) = self.by_next_use.pop_last().unwrap();
+            self.resident.remove(&victim);
+        }
+        self.touch(page_id, index);
+        evict
+    }
+}
+
+/// 記録をpool_sizeページのバッファプールとpolicyで再生する
+pub fn replay(records: &[Record], pool_size: usize, policy: Policy) -> ReplayStats {
+    assert!(pool_size > 0, "pool size must be positive");
+    let accesses: Vec<(PageId, bool)> = records
+        .iter()
+        .filter_map(|record| match record.event {
+            Event::Fetch { .. } => Some((record.page_id, false)),
+            Event::Create => Some((record.page_id, true)),
+            _ => None,
+        })
+        .collect();
+    let mut replacer: Box<dyn Replacer> = match policy {
+        Policy::Clock => Box::new(ClockReplacer {
+            frames: vec![(None, 0); pool_size],
+            page_table: HashMap::new(),
+            next_victim: 0,
+        }),
+        Policy::Lru => Box::new(LruReplacer {
+            capacity: pool_size,
+            by_time: BTreeMap::new(),
+            last_used: HashMap::new(),
+        }),
+        Policy::Fifo => Box::new(FifoReplacer {
+            capacity: pool_size,
+            queue: VecDeque::new(),
+            resident: HashSet::new(),
+        }),
+        Policy::Optimal => {
+            let page_ids: Vec<PageId> = accesses.iter().map(|&(page_id, _)| page_id).collect();
+            Box::new(OptimalReplacer::new(pool_size, &page_ids))
+        }
+    };
+    let mut stats = ReplayStats::default();
+    for (index, &(page_id, create)) in accesses.iter().enumerate() {
+        stats.accesses += 1;
+        if replacer.hit(page_id, index) {
+            stats.hits += 1;
+            continue;
+        }
+        // 新しいページはディスクから読まない
+        if !create {
+            stats.reads += 1;
+        }
+        if replacer.insert(page_id, index) {
+            stats.evictions += 1;
+        }
+    }
+    stats
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buffer_pool::{BufferPool, BufferPoolManager};
+    use crate::disk_manager::DiskManager;
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("page_trace_test_{}_{}", std::process::id(), name));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn test_trace_buffer_pool() {
+        let trace_path = temp_path("trace");
+        let disk = DiskManager::open(temp_path("heap")).unwrap();
+        let mut bufmgr = BufferPoolManager::new(disk, BufferPool::new(3));
+        bufmgr.start_trace(Tracer::create(&trace_path).unwrap());
+        let mut page_ids = vec![];
+        for _ in 0..4 {
+            let buffer = bufmgr.create_page().unwrap();
+            page_ids.push(buffer.page_id);
+        }
+        // 4つ目を作った時に1つ目を追い出している
+        bufmgr.fetch_page(page_ids[3]).unwrap();
+        bufmgr.fetch_page(page_ids[0]).unwrap();
+        bufmgr.flush().unwrap();
+        bufmgr.stop_trace().unwrap();
+        bufmgr.fetch_page(page_ids[1]).unwrap();
+
+        let records = read_trace(&trace_path).unwrap();
+        let events: Vec<(Event, u64)> = records.iter().map(|record| (record.event, record.page_id.to_u64())).collect();
+        assert_eq!(
+            &events[..8],
+            &[
+                (Event::Create, 0),
+                (Event::Create, 1),
+                (Event::Create, 2),
+                (Event::Evict { dirty: true }, 0),
+                (Event::Create, 3),
+                (Event::Fetch { hit: true }, 3),
+                (Event::Evict { dirty: true }, 1),
+                (Event::Fetch { hit: false }, 0),
+            ]
+        );
+        // flushはダーティな2ページ(作ったページ)を書き出す。読み込んだページはダーティではない
+        assert_eq!(events.len(), 10);
+        assert!(events[8..].iter().all(|&(event, _)| event == Event::Flush));
+        assert!(records.windows(2).all(|w| w[0].time <= w[1].time));
+
+        // 同じ大きさのClock-sweepで再生すると、元のバッファプールと同じ結果になる
+        let stats = replay(&records, 3, Policy::Clock);
+        assert_eq!(stats, ReplayStats { accesses: 6, hits: 1, reads: 1, evictions: 2 });
+    }
+
+    #[test]
+    fn test_replay_policies() {
+        // 5ページを順に繰り返し読むと、4ページのLRU・FIFOは1度もヒットしないが、最適なら多くがヒットする
+        let records: Vec<Record> = (0..100u64)
+            .map(|i| Record {
+                time: i,
+                event: Event::Fetch { hit: false },
+                page_id: PageId(i % 5),
+            })
+            .collect();
+        assert_eq!(replay(&records, 4, Policy::Lru).hits, 0);
+        assert_eq!(replay(&records, 4, Policy::Fifo).hits, 0);
+        let optimal = replay(&records, 4, Policy::Optimal);
+        assert!(optimal.hits > 60, "{:?}", optimal);
+        // 全て入るなら最初の読み込み以外は全てヒットする
+        for policy in Policy::ALL.iter() {
+            let stats = replay(&records, 5, *policy);
+            assert_eq!((stats.hits, stats.reads, stats.evictions), (95, 5, 0), "{}", policy);
+        }
+        assert_eq!("opt".parse::<Policy>(), Ok(Policy::Optimal));
+    }
+}
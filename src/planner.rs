@@ -0,0 +1,1124 @@
+// コストベースのプランナー
+// ANALYZEで集めた統計情報から、条件を満たす行の割合(選択率)と行数を見積もり、
+// 読むページ数と処理する行数から計算したコストが一番小さい実行計画を選ぶ
+//
+// - アクセスパス：テーブルを先頭から全て読む(Seq Scan)か、インデックスで範囲を絞って読む(Index Scan)か
+// - 結合順序：結合するテーブルの集合ごとに一番安い計画を覚えておく動的計画法(Selingerの方法)
+//   片方が必ず1つのテーブルになる形の木だけを考える
+//
+// 条件は全てのテーブルの列をFROMの順に並べた行(グローバルな列番号)に対して書いておき、
+// 実行計画を組み立てる時に、実際に並ぶ列の番号へ付け替える
+
+use std::fmt;
+use std::ops::Bound;
+
+use crate::buffer_pool::BufferPoolManager;
+use crate::catalog::{ColumnStats, ColumnType, IndexDef, IndexKind, TableDef, TableStats};
+use crate::disk_manager::PAGE_SIZE;
+use crate::executor::{self, AggregateExpr, AggregateFunc, PlanNode, Result, SortKey};
+use crate::expr::{self, BinaryOp, Expr};
+use crate::external_sort::WORK_MEMORY;
+use crate::memcmpable::Value;
+use crate::transaction::Transaction;
+
+// コストの単位は、ページを1つ順番に読む時間
+const SEQ_PAGE_COST: f64 = 1.0;
+const RANDOM_PAGE_COST: f64 = 4.0;
+const CPU_TUPLE_COST: f64 = 0.01;
+const CPU_OPERATOR_COST: f64 = 0.0025;
+
+// 統計情報が無い時の仮定
+const DEFAULT_ROWS: f64 = 1000.0;
+const DEFAULT_PAGES: f64 = 10.0;
+const DEFAULT_DISTINCT: f64 = 200.0;
+const DEFAULT_RANGE_SELECTIVITY: f64 = 1.0 / 3.0;
+const DEFAULT_SELECTIVITY: f64 = 0.5;
+// 1行のバイト数
+const DEFAULT_WIDTH: f64 = 100.0;
+
+const HISTOGRAM_BUCKETS: usize = 10;
+// 統計情報はカタログの1レコードに入れるので、ヒストグラムに入れる文字列やバイト列は切り詰める
+const HISTOGRAM_VALUE_SIZE: usize = 32;
+// 動的計画法で扱えるテーブルの数
+const MAX_JOIN_RELATIONS: usize = 12;
+
+/// テーブルを全て読んで統計情報を集める
+pub fn analyze(bufmgr: &mut BufferPoolManager, txn: &Transaction, table: &TableDef) -> Result<TableStats> {
+    let num_columns = table.schema.columns.len();
+    let mut values = vec![vec![]; num_columns];
+    let mut nulls = vec![0; num_columns];
+    let mut rows = 0;
+    let mut iter = table.heap.scan();
+    while let Some((_, bytes)) = iter.next(bufmgr, txn)? {
+        rows += 1;
+        for (i, value) in executor::decode_row(&bytes)?.into_iter().enumerate() {
+            if value == Value::Null {
+                nulls[i] += 1;
+            } else {
+                values[i].push(value);
+            }
+        }
+    }
+    let columns = table
+        .schema
+        .columns
+        .iter()
+        .zip(values)
+        .zip(nulls)
+        .map(|((column, mut values), nulls)| {
+            values.sort_by(expr::sort_cmp);
+            let distinct = (0..values.len()).filter(|&i| i == 0 || expr::sort_cmp(&values[i - 1], &values[i]).is_ne()).count();
+            let histogram = if values.is_empty() {
+                vec![]
+            } else {
+                (0..=HISTOGRAM_BUCKETS)
+                    .map(|i| truncate(&values[i * (values.len() - 1) / HISTOGRAM_BUCKETS]))
+                    .collect()
+            };
+            ColumnStats {
+                name: column.name.clone(),
+                distinct: distinct as u64,
+                nulls,
+                histogram,
+            }
+        })
+        .collect();
+    Ok(TableStats {
+        table: table.name.clone(),
+        rows,
+        pages: table.heap.num_pages(bufmgr)?,
+        columns,
+    })
+}
+
+fn truncate(value: &Value) -> Value {
+    match value {
+        Value::Str(s) if s.len() > HISTOGRAM_VALUE_SIZE => {
+            let mut end = HISTOGRAM_VALUE_SIZE;
+            while !s.is_char_boundary(end) {
+                end -= 1;
+            }
+            Value::Str(s[..end].to_string())
+        }
+        Value::Bytes(bytes) if bytes.len() > HISTOGRAM_VALUE_SIZE => Value::Bytes(bytes[..HISTOGRAM_VALUE_SIZE].to_vec()),
+        value => value.clone(),
+    }
+}
+
+/// 結合するテーブル1つ分の情報
+pub struct Relation {
+    pub table: TableDef,
+    /// FROMに書いた別名(無ければテーブル名)
+    pub alias: String,
+    pub indexes: Vec<IndexDef>,
+    /// まだANALYZEしていなければNone
+    pub stats: Option<TableStats>,
+}
+
+impl Relation {
+    fn rows(&self) -> f64 {
+        self.stats.as_ref().map_or(DEFAULT_ROWS, |stats| stats.rows as f64)
+    }
+
+    fn pages(&self) -> f64 {
+        self.stats.as_ref().map_or(DEFAULT_PAGES, |stats| stats.pages as f64)
+    }
+
+    // 1行のバイト数。ページの空きも含めた大まかな値
+    fn width(&self) -> f64 {
+        match &self.stats {
+            Some(stats) if stats.rows > 0 => stats.pages as f64 * PAGE_SIZE as f64 / stats.rows as f64,
+            _ => DEFAULT_WIDTH,
+        }
+    }
+
+    fn column_stats(&self, column: usize) -> Option<&ColumnStats> {
+        self.stats.as_ref()?.column(&self.table.schema.columns[column].name)
+    }
+
+    fn label(&self) -> String {
+        if self.alias == self.table.name {
+            self.table.name.clone()
+        } else {
+            format!("{} {}", self.table.name, self.alias)
+        }
+    }
+}
+
+/// EXPLAINで表示する実行計画の木
+#[derive(Debug, Clone, PartialEq)]
+pub struct Explain {
+    pub label: String,
+    /// 条件など、演算子の下に表示する行
+    pub details: Vec<String>,
+    pub rows: f64,
+    pub cost: f64,
+    pub children: Vec<Explain>,
+}
+
+impl Explain {
+    fn write(&self, f: &mut fmt::Formatter, indent: usize, child: bool) -> fmt::Result {
+        let arrow = if child { "->  " } else { "" };
+        writeln!(f, "{}{}{}  (cost={:.2} rows={:.0})", " ".repeat(indent), arrow, self.label, self.cost, self.rows)?;
+        let indent = indent + arrow.len() + 2;
+        for detail in &self.details {
+            writeln!(f, "{}{}", " ".repeat(indent), detail)?;
+        }
+        for child in &self.children {
+            child.write(f, indent, true)?;
+        }
+        Ok(())
+    }
+}
+
+// Hash Join  (cost=35.50 rows=100)
+//   Hash Cond: (u.dept = d.id)
+//   ->  Seq Scan on users u  (cost=20.00 rows=1000)
+//   ->  Seq Scan on depts d  (cost=1.10 rows=10)
+impl fmt::Display for Explain {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.write(f, 0, false)
+    }
+}
+
+/// 実行計画と、その見積もり
+pub struct Plan {
+    pub node: Box<dyn PlanNode>,
+    pub explain: Explain,
+    // 出力の列の名前(条件の表示に使う)と、列ごとの異なる値の数の見積もり
+    columns: Vec<String>,
+    distinct: Vec<f64>,
+}
+
+impl Plan {
+    pub fn rows(&self) -> f64 {
+        self.explain.rows
+    }
+
+    pub fn cost(&self) -> f64 {
+        self.explain.cost
+    }
+
+    /// 集約する
+    pub fn aggregate(self, group_by: Vec<Expr>, aggregates: Vec<AggregateExpr>) -> Plan {
+        let rows = if group_by.is_empty() {
+            1.0
+        } else {
+            let groups: f64 = group_by
+                .iter()
+                .map(|expr| match expr {
+                    Expr::Column(i) => self.distinct[*i],
+                    _ => self.rows(),
+                })
+                .product();
+            groups.min(self.rows()).max(1.0)
+        };
+        let cost = self.cost() + self.rows() * (CPU_TUPLE_COST + CPU_OPERATOR_COST * (group_by.len() + aggregates.len()) as f64);
+        let mut columns: Vec<String> = group_by.iter().map(|expr| describe(expr, &self.columns)).collect();
+        columns.extend(aggregates.iter().map(|agg| aggregate_name(agg, &self.columns)));
+        let mut details = vec![];
+        if !group_by.is_empty() {
+            details.push(format!("Group Key: {}", columns[..group_by.len()].join(", ")));
+        }
+        let distinct = vec![rows; columns.len()];
+        let node = Box::new(executor::Aggregate {
+            inner: self.node,
+            group_by,
+            aggregates,
+        });
+        Plan {
+            node,
+            explain: Explain {
+                label: "Aggregate".to_string(),
+                details,
+                rows,
+                cost,
+                children: vec![self.explain],
+            },
+            columns,
+            distinct,
+        }
+    }
+
+    /// 並び替える
+    pub fn sort(self, keys: Vec<SortKey>) -> Plan {
+        let rows = self.rows();
+        // 出力の行の幅は追っていないので、既定値で見積もる
+        let cost = self.cost() + sort_cost(rows, DEFAULT_WIDTH, keys.len());
+        let details = vec![format!(
+            "Sort Key: {}",
+            keys.iter()
+                .map(|key| format!("{}{}", describe(&key.expr, &self.columns), if key.desc { " DESC" } else { "" }))
+                .collect::<Vec<_>>()
+                .join(", ")
+        )];
+        let node = Box::new(executor::Sort { inner: self.node, keys, memory: WORK_MEMORY });
+        Plan {
+            node,
+            explain: Explain {
+                label: "Sort".to_string(),
+                details,
+                rows,
+                cost,
+                children: vec![self.explain],
+            },
+            columns: self.columns,
+            distinct: self.distinct,
+        }
+    }
+
+    /// 先頭からoffset行を読み飛ばし、limit行まで返す
+    pub fn limit(self, limit: Option<usize>, offset: usize) -> Plan {
+        let rows = (self.rows() - offset as f64).max(0.0);
+        let rows = limit.map_or(rows, |limit| rows.min(limit as f64));
+        let cost = self.cost();
+        let node = Box::new(executor::Limit {
+            inner: self.node,
+            limit,
+            offset,
+        });
+        Plan {
+            node,
+            explain: Explain {
+                label: "Limit".to_string(),
+                details: vec![],
+                rows,
+                cost,
+                children: vec![self.explain],
+            },
+            columns: self.columns,
+            distinct: self.distinct,
+        }
+    }
+
+    /// 式を評価して結果の列を作る
+    pub fn project(self, exprs: Vec<Expr>, names: Vec<String>) -> Plan {
+        let rows = self.rows();
+        let cost = self.cost() + rows * CPU_OPERATOR_COST * exprs.len() as f64;
+        let distinct = exprs
+            .iter()
+            .map(|expr| match expr {
+                Expr::Column(i) => self.distinct[*i],
+                _ => rows,
+            })
+            .collect();
+        let details = vec![format!(
+            "Output: {}",
+            exprs.iter().map(|expr| describe(expr, &self.columns)).collect::<Vec<_>>().join(", ")
+        )];
+        let node = Box::new(executor::Project { inner: self.node, exprs });
+        Plan {
+            node,
+            explain: Explain {
+                label: "Project".to_string(),
+                details,
+                rows,
+                cost,
+                children: vec![self.explain],
+            },
+            columns: names,
+            distinct,
+        }
+    }
+}
+
+fn aggregate_name(agg: &AggregateExpr, columns: &[String]) -> String {
+    let name = match agg.func {
+        AggregateFunc::CountStar => return "count(*)".to_string(),
+        AggregateFunc::Count => "count",
+        AggregateFunc::Sum => "sum",
+        AggregateFunc::Min => "min",
+        AggregateFunc::Max => "max",
+        AggregateFunc::Avg => "avg",
+    };
+    format!("{}({})", name, describe(&agg.arg, columns))
+}
+
+/// 式を表示用の文字列にする。列は名前で表示する
+pub fn describe(expr: &Expr, columns: &[String]) -> String {
+    match expr {
+        Expr::Column(i) => columns.get(*i).cloned().unwrap_or_else(|| format!("#{}", i)),
+        Expr::Literal(Value::Str(s)) => format!("'{}'", s.replace('\'', "''")),
+        Expr::Literal(Value::Null) => "NULL".to_string(),
+        Expr::Literal(Value::Int(n)) => n.to_string(),
+        Expr::Literal(Value::UInt(n)) => n.to_string(),
+        Expr::Literal(Value::Float(f)) => f.to_string(),
+        Expr::Literal(value) => format!("{:?}", value),
+        Expr::Binary(op, left, right) => {
+            let op = match op {
+                BinaryOp::Eq => "=",
+                BinaryOp::Ne => "<>",
+                BinaryOp::Lt => "<",
+                BinaryOp::Le => "<=",
+                BinaryOp::Gt => ">",
+                BinaryOp::Ge => ">=",
+                BinaryOp::And => "AND",
+                BinaryOp::Or => "OR",
+                BinaryOp::Add => "+",
+                BinaryOp::Sub => "-",
+                BinaryOp::Mul => "*",
+                BinaryOp::Div => "/",
+            };
+            format!("({} {} {})", describe(left, columns), op, describe(right, columns))
+        }
+        Expr::Not(expr) => format!("NOT {}", describe(expr, columns)),
+        Expr::IsNull(expr) => format!("({} IS NULL)", describe(expr, columns)),
+    }
+}
+
+// 列の番号を付け替える
+fn remap(expr: &Expr, layout: &[Option<usize>]) -> Expr {
+    match expr {
+        Expr::Column(i) => Expr::Column(layout[*i].expect("column is not in the input")),
+        Expr::Literal(value) => Expr::Literal(value.clone()),
+        Expr::Binary(op, left, right) => Expr::binary(*op, remap(left, layout), remap(right, layout)),
+        Expr::Not(expr) => Expr::Not(Box::new(remap(expr, layout))),
+        Expr::IsNull(expr) => Expr::IsNull(Box::new(remap(expr, layout))),
+    }
+}
+
+fn and_all(exprs: Vec<Expr>) -> Option<Expr> {
+    exprs.into_iter().reduce(|left, right| Expr::binary(BinaryOp::And, left, right))
+}
+
+// 条件と、条件が参照するテーブルの集合(ビットマスク)
+struct Predicate {
+    expr: Expr,
+    mask: u32,
+    selectivity: f64,
+}
+
+// 計画の候補。実行計画を組み立てる前に、見積もりだけで比べる
+#[derive(Debug, Clone)]
+struct Path {
+    rows: f64,
+    cost: f64,
+    // 1行のバイト数
+    width: f64,
+    // 出力の行にテーブルの列が並ぶ順
+    relations: Vec<usize>,
+    kind: PathKind,
+}
+
+#[derive(Debug, Clone)]
+enum PathKind {
+    SeqScan {
+        relation: usize,
+        filter: Vec<usize>,
+    },
+    IndexScan {
+        relation: usize,
+        index: usize,
+        lower: Bound<Vec<Value>>,
+        upper: Bound<Vec<Value>>,
+        // インデックスの範囲で絞り込んだ条件と、残りの条件(Predicateの番号)
+        index_cond: Vec<usize>,
+        filter: Vec<usize>,
+    },
+    HashJoin {
+        left: Box<Path>,
+        right: Box<Path>,
+        // 左の式と右の式が等しい(グローバルな列番号)
+        keys: Vec<(Expr, Expr)>,
+        filter: Vec<usize>,
+    },
+    // 左右をキーで並び替えてからマージする
+    MergeJoin {
+        left: Box<Path>,
+        right: Box<Path>,
+        keys: Vec<(Expr, Expr)>,
+        filter: Vec<usize>,
+    },
+    NestedLoop {
+        left: Box<Path>,
+        right: Box<Path>,
+        filter: Vec<usize>,
+    },
+}
+
+struct Planner<'a> {
+    relations: &'a [Relation],
+    // グローバルな列番号 → (テーブル, テーブルの中での列番号)
+    columns: Vec<(usize, usize)>,
+    names: Vec<String>,
+    predicates: Vec<Predicate>,
+}
+
+/// 結合するテーブルと条件(グローバルな列番号で書いたもの)から、一番安い実行計画を選ぶ
+/// 結果の行に並ぶテーブルの順も返す
+pub fn plan_joins(relations: &[Relation], predicates: Vec<Expr>) -> Result<(Plan, Vec<usize>)> {
+    if relations.is_empty() || relations.len() > MAX_JOIN_RELATIONS {
+        return Err(format!("cannot join {} tables", relations.len()).into());
+    }
+    let mut planner = Planner {
+        relations,
+        columns: vec![],
+        names: vec![],
+        predicates: vec![],
+    };
+    for (i, relation) in relations.iter().enumerate() {
+        for (j, column) in relation.table.schema.columns.iter().enumerate() {
+            planner.columns.push((i, j));
+            planner.names.push(format!("{}.{}", relation.alias, column.name));
+        }
+    }
+    planner.predicates = predicates
+        .into_iter()
+        .map(|expr| Predicate {
+            mask: planner.mask(&expr),
+            selectivity: planner.selectivity(&expr),
+            expr,
+        })
+        .collect();
+
+    // best[mask]：maskのテーブルを全て結合する一番安い計画
+    let n = relations.len();
+    let mut best: Vec<Option<Path>> = vec![None; 1 << n];
+    for relation in 0..n {
+        best[1 << relation] = Some(planner.access_path(relation));
+    }
+    for mask in 1..(1usize << n) {
+        if mask.count_ones() < 2 {
+            continue;
+        }
+        for relation in (0..n).filter(|relation| mask & (1 << relation) != 0) {
+            let rest = match &best[mask ^ (1 << relation)] {
+                Some(rest) => rest.clone(),
+                None => continue,
+            };
+            let single = best[1 << relation].clone().unwrap();
+            // ハッシュ表を作るのは右なので、左右を入れ替えたものも考える
+            for (left, right) in [(rest.clone(), single.clone()), (single, rest)] {
+                let path = planner.join(left, right);
+                if best[mask].as_ref().is_none_or(|best| path.cost < best.cost) {
+                    best[mask] = Some(path);
+                }
+            }
+        }
+    }
+    let path = best.pop().unwrap().unwrap();
+    let order = path.relations.clone();
+    let mut plan = planner.build(path);
+    // どのテーブルも参照しない条件は最後に確かめる
+    let constants: Vec<usize> = (0..planner.predicates.len()).filter(|&i| planner.predicates[i].mask == 0).collect();
+    if !constants.is_empty() {
+        let selectivity: f64 = constants.iter().map(|&i| planner.predicates[i].selectivity).product();
+        let cond = and_all(constants.iter().map(|&i| planner.predicates[i].expr.clone()).collect()).unwrap();
+        let rows = plan.rows() * selectivity;
+        let cost = plan.cost() + plan.rows() * CPU_OPERATOR_COST;
+        let details = vec![format!("Filter: {}", describe(&cond, &planner.names))];
+        plan = Plan {
+            node: Box::new(executor::Filter { inner: plan.node, cond }),
+            explain: Explain {
+                label: "Result".to_string(),
+                details,
+                rows,
+                cost,
+                children: vec![plan.explain],
+            },
+            columns: plan.columns,
+            distinct: plan.distinct,
+        };
+    }
+    Ok((plan, order))
+}
+
+impl<'a> Planner<'a> {
+    fn mask(&self, expr: &Expr) -> u32 {
+        match expr {
+            Expr::Column(i) => 1 << self.columns[*i].0,
+            Expr::Literal(_) => 0,
+            Expr::Binary(_, left, right) => self.mask(left) | self.mask(right),
+            Expr::Not(expr) | Expr::IsNull(expr) => self.mask(expr),
+        }
+    }
+
+    fn column_stats(&self, column: usize) -> Option<&ColumnStats> {
+        let (relation, column) = self.columns[column];
+        self.relations[relation].column_stats(column)
+    }
+
+    fn distinct(&self, column: usize) -> f64 {
+        let (relation, _) = self.columns[column];
+        match self.column_stats(column) {
+            Some(stats) => (stats.distinct as f64).max(1.0),
+            None => DEFAULT_DISTINCT.min(self.relations[relation].rows()).max(1.0),
+        }
+    }
+
+    // NULLでない行の割合
+    fn not_null_fraction(&self, column: usize) -> f64 {
+        let (relation, _) = self.columns[column];
+        match self.column_stats(column) {
+            Some(stats) => 1.0 - stats.nulls as f64 / self.relations[relation].rows().max(1.0),
+            None => 1.0,
+        }
+    }
+
+    // 列の値がvalueより小さい行の割合(ヒストグラムのバケットの中は一様に分布しているとする)
+    fn less_than_fraction(&self, column: usize, value: &Value) -> f64 {
+        let histogram = match self.column_stats(column) {
+            Some(stats) if stats.histogram.len() >= 2 => &stats.histogram,
+            _ => return DEFAULT_RANGE_SELECTIVITY,
+        };
+        let buckets = (histogram.len() - 1) as f64;
+        if expr::sort_cmp(value, &histogram[0]).is_le() {
+            return 0.0;
+        }
+        if expr::sort_cmp(value, &histogram[histogram.len() - 1]).is_gt() {
+            return self.not_null_fraction(column);
+        }
+        let bucket = histogram.iter().rposition(|bound| expr::sort_cmp(bound, value).is_lt()).unwrap_or(0);
+        let within = match (number(&histogram[bucket]), number(value), histogram.get(bucket + 1).and_then(number)) {
+            (Some(low), Some(value), Some(high)) if high > low => ((value - low) / (high - low)).clamp(0.0, 1.0),
+            _ => 0.5,
+        };
+        (bucket as f64 + within) / buckets * self.not_null_fraction(column)
+    }
+
+    /// 条件を満たす行の割合
+    fn selectivity(&self, expr: &Expr) -> f64 {
+        use BinaryOp::*;
+        if self.mask(expr) == 0 {
+            // 定数の条件はその場で評価する
+            return match expr.matches(&[]) {
+                Ok(true) => 1.0,
+                _ => 0.0,
+            };
+        }
+        let selectivity = match expr {
+            Expr::Binary(And, left, right) => self.selectivity(left) * self.selectivity(right),
+            Expr::Binary(Or, left, right) => {
+                let (left, right) = (self.selectivity(left), self.selectivity(right));
+                left + right - left * right
+            }
+            Expr::Not(expr) => 1.0 - self.selectivity(expr),
+            Expr::IsNull(expr) => match expr.as_ref() {
+                Expr::Column(i) => 1.0 - self.not_null_fraction(*i),
+                _ => 1.0 / DEFAULT_DISTINCT,
+            },
+            Expr::Binary(op, left, right) => match (op, left.as_ref(), right.as_ref()) {
+                (Eq, Expr::Column(a), Expr::Column(b)) => 1.0 / self.distinct(*a).max(self.distinct(*b)),
+                (Ne, Expr::Column(a), Expr::Column(b)) => 1.0 - 1.0 / self.distinct(*a).max(self.distinct(*b)),
+                (_, Expr::Column(i), Expr::Literal(value)) => self.compare_selectivity(*op, *i, value),
+                (_, Expr::Literal(value), Expr::Column(i)) => match flip(*op) {
+                    Some(op) => self.compare_selectivity(op, *i, value),
+                    None => DEFAULT_SELECTIVITY,
+                },
+                (Lt, _, _) | (Le, _, _) | (Gt, _, _) | (Ge, _, _) => DEFAULT_RANGE_SELECTIVITY,
+                (Eq, _, _) => 1.0 / DEFAULT_DISTINCT,
+                _ => DEFAULT_SELECTIVITY,
+            },
+            _ => DEFAULT_SELECTIVITY,
+        };
+        selectivity.clamp(0.0, 1.0)
+    }
+
+    // 列 op 値 の選択率
+    fn compare_selectivity(&self, op: BinaryOp, column: usize, value: &Value) -> f64 {
+        use BinaryOp::*;
+        if *value == Value::Null {
+            return 0.0;
+        }
+        let not_null = self.not_null_fraction(column);
+        match op {
+            Eq => not_null / self.distinct(column),
+            Ne => not_null * (1.0 - 1.0 / self.distinct(column)),
+            Lt | Le => self.less_than_fraction(column, value),
+            Gt | Ge => match self.column_stats(column) {
+                Some(_) => not_null - self.less_than_fraction(column, value),
+                None => DEFAULT_RANGE_SELECTIVITY,
+            },
+            _ => DEFAULT_SELECTIVITY,
+        }
+    }
+
+    // テーブル1つを読む一番安い方法
+    fn access_path(&self, relation: usize) -> Path {
+        let rel = &self.relations[relation];
+        let filter: Vec<usize> = (0..self.predicates.len()).filter(|&i| self.predicates[i].mask == 1 << relation).collect();
+        let selectivity: f64 = filter.iter().map(|&i| self.predicates[i].selectivity).product();
+        let rows = rel.rows();
+        let mut best = Path {
+            rows: rows * selectivity,
+            cost: rel.pages() * SEQ_PAGE_COST + rows * (CPU_TUPLE_COST + CPU_OPERATOR_COST * filter.len() as f64),
+            width: rel.width(),
+            relations: vec![relation],
+            kind: PathKind::SeqScan {
+                relation,
+                filter: filter.clone(),
+            },
+        };
+        for (index, index_def) in rel.indexes.iter().enumerate() {
+            if let Some(path) = self.index_path(relation, index, index_def, &filter, rows * selectivity) {
+                if path.cost < best.cost {
+                    best = path;
+                }
+            }
+        }
+        best
+    }
+
+    // インデックスの先頭の列から、等号で値が決まる列を並べ、その次の列に範囲の条件があれば使う
+    // ハッシュインデックスは全ての列が等号で決まる時だけ使える
+    fn index_path(&self, relation: usize, index: usize, index_def: &IndexDef, filter: &[usize], rows_out: f64) -> Option<Path> {
+        let rel = &self.relations[relation];
+        let offset = self.columns.iter().position(|&(r, _)| r == relation).unwrap();
+        let mut prefix = vec![];
+        let mut used = vec![];
+        let mut lower = None;
+        let mut upper = None;
+        for name in &index_def.columns {
+            let column = rel.table.schema.position(name)?;
+            let ty = rel.table.schema.columns[column].ty;
+            let conds: Vec<(usize, BinaryOp, Value)> = filter
+                .iter()
+                .filter_map(|&i| {
+                    let (op, value) = column_condition(&self.predicates[i].expr, offset + column)?;
+                    Some((i, op, coerce(value, ty)?))
+                })
+                .collect();
+            if let Some((i, _, value)) = conds.iter().find(|(_, op, _)| *op == BinaryOp::Eq) {
+                prefix.push(value.clone());
+                used.push(*i);
+                continue;
+            }
+            for (i, op, value) in conds {
+                let mut bound = prefix.clone();
+                bound.push(value);
+                match op {
+                    BinaryOp::Gt if lower.is_none() => lower = Some(Bound::Excluded(bound)),
+                    BinaryOp::Ge if lower.is_none() => lower = Some(Bound::Included(bound)),
+                    BinaryOp::Lt if upper.is_none() => upper = Some(Bound::Excluded(bound)),
+                    BinaryOp::Le if upper.is_none() => upper = Some(Bound::Included(bound)),
+                    _ => continue,
+                }
+                used.push(i);
+            }
+            break;
+        }
+        let hash = index_def.storage.kind() == IndexKind::Hash;
+        if used.is_empty() || (hash && prefix.len() < index_def.columns.len()) {
+            return None;
+        }
+        let (lower, upper) = match (lower, upper) {
+            (None, None) => (Bound::Included(prefix.clone()), Bound::Included(prefix)),
+            (lower, upper) => {
+                // NULLは一番小さい値として並んでいるので、下限が無ければNULLの後から読む
+                let lower = lower.unwrap_or_else(|| {
+                    let mut bound = prefix.clone();
+                    bound.push(Value::Null);
+                    Bound::Excluded(bound)
+                });
+                let upper = upper.unwrap_or(if prefix.is_empty() { Bound::Unbounded } else { Bound::Included(prefix) });
+                (lower, upper)
+            }
+        };
+        let rest: Vec<usize> = filter.iter().copied().filter(|i| !used.contains(i)).collect();
+        let rows = rel.rows();
+        let matched = rows * used.iter().map(|&i| self.predicates[i].selectivity).product::<f64>();
+        // 木をたどるページ(ハッシュインデックスならバケットのページ)と、行ごとのヒープのページ(テーブルのページ数より多くは読まない)
+        let index_pages = if hash { 1.0 } else { 2.0 };
+        let cost = RANDOM_PAGE_COST * (index_pages + matched.min(rel.pages()))
+            + matched * (2.0 * CPU_TUPLE_COST + CPU_OPERATOR_COST * rest.len() as f64);
+        Some(Path {
+            rows: rows_out,
+            cost,
+            width: rel.width(),
+            relations: vec![relation],
+            kind: PathKind::IndexScan {
+                relation,
+                index,
+                lower,
+                upper,
+                index_cond: used,
+                filter: rest,
+            },
+        })
+    }
+
+    fn join(&self, left: Path, right: Path) -> Path {
+        let left_mask = mask_of(&left.relations);
+        let right_mask = mask_of(&right.relations);
+        let mask = left_mask | right_mask;
+        let mut keys = vec![];
+        let mut filter = vec![];
+        let mut selectivity = 1.0;
+        for (i, pred) in self.predicates.iter().enumerate() {
+            if pred.mask & mask != pred.mask || pred.mask & left_mask == pred.mask || pred.mask & right_mask == pred.mask {
+                continue;
+            }
+            selectivity *= pred.selectivity;
+            if let Expr::Binary(BinaryOp::Eq, a, b) = &pred.expr {
+                let (a_mask, b_mask) = (self.mask(a), self.mask(b));
+                if a_mask != 0 && b_mask != 0 {
+                    if a_mask & left_mask == a_mask && b_mask & right_mask == b_mask {
+                        keys.push((a.as_ref().clone(), b.as_ref().clone()));
+                        continue;
+                    }
+                    if b_mask & left_mask == b_mask && a_mask & right_mask == a_mask {
+                        keys.push((b.as_ref().clone(), a.as_ref().clone()));
+                        continue;
+                    }
+                }
+            }
+            filter.push(i);
+        }
+        let rows = left.rows * right.rows * selectivity;
+        let width = left.width + right.width;
+        let mut relations = left.relations.clone();
+        relations.extend(&right.relations);
+        if keys.is_empty() {
+            let cost = left.cost + right.cost + left.rows * right.rows * CPU_OPERATOR_COST * filter.len().max(1) as f64 + rows * CPU_TUPLE_COST;
+            let kind = PathKind::NestedLoop {
+                left: Box::new(left),
+                right: Box::new(right),
+                filter,
+            };
+            return Path { rows, cost, width, relations, kind };
+        }
+        // 結合した行を作って残りの条件を確かめるコスト
+        let output = rows * CPU_TUPLE_COST * (1 + filter.len()) as f64;
+        // 左右を並び替えてから、両方を1度ずつ読み進める
+        let merge_cost = left.cost
+            + right.cost
+            + sort_cost(left.rows, left.width, keys.len())
+            + sort_cost(right.rows, right.width, keys.len())
+            + (left.rows + right.rows) * CPU_OPERATOR_COST * keys.len() as f64
+            + output;
+        // 右の行でハッシュ表を作り、左の行で引く。ハッシュ表はメモリに入る時だけ使える
+        let fits = right.rows * right.width <= WORK_MEMORY as f64;
+        let hash_cost = left.cost
+            + right.cost
+            + right.rows * 2.0 * CPU_TUPLE_COST
+            + left.rows * CPU_OPERATOR_COST * keys.len() as f64
+            + output;
+        let (cost, kind) = if fits && hash_cost <= merge_cost {
+            let kind = PathKind::HashJoin {
+                left: Box::new(left),
+                right: Box::new(right),
+                keys,
+                filter,
+            };
+            (hash_cost, kind)
+        } else {
+            let kind = PathKind::MergeJoin {
+                left: Box::new(left),
+                right: Box::new(right),
+                keys,
+                filter,
+            };
+            (merge_cost, kind)
+        };
+        Path { rows, cost, width, relations, kind }
+    }
+
+    // グローバルな列番号 → relationsの順に列を並べた行での列番号
+    fn layout(&self, relations: &[usize]) -> Vec<Option<usize>> {
+        let mut layout = vec![None; self.columns.len()];
+        let mut pos = 0;
+        for &relation in relations {
+            for (global, &(r, _)) in self.columns.iter().enumerate() {
+                if r == relation {
+                    layout[global] = Some(pos);
+                    pos += 1;
+                }
+            }
+        }
+        layout
+    }
+
+    fn filter_cond(&self, filter: &[usize], layout: &[Option<usize>]) -> Option<Expr> {
+        and_all(filter.iter().map(|&i| remap(&self.predicates[i].expr, layout)).collect())
+    }
+
+    fn describe_all(&self, label: &str, preds: &[usize]) -> Option<String> {
+        if preds.is_empty() {
+            return None;
+        }
+        let conds: Vec<String> = preds.iter().map(|&i| describe(&self.predicates[i].expr, &self.names)).collect();
+        Some(format!("{}: {}", label, conds.join(" AND ")))
+    }
+
+    // 計画の候補から実際の実行計画を組み立てる
+    fn build(&self, path: Path) -> Plan {
+        let layout = self.layout(&path.relations);
+        let (node, label, details, children): (Box<dyn PlanNode>, String, Vec<Option<String>>, Vec<Explain>) = match path.kind {
+            PathKind::SeqScan { relation, filter } => {
+                let rel = &self.relations[relation];
+                let scan: Box<dyn PlanNode> = Box::new(executor::SeqScan { table: rel.table.clone() });
+                let node = with_filter(scan, self.filter_cond(&filter, &layout));
+                (node, format!("Seq Scan on {}", rel.label()), vec![self.describe_all("Filter", &filter)], vec![])
+            }
+            PathKind::IndexScan {
+                relation,
+                index,
+                lower,
+                upper,
+                index_cond,
+                filter,
+            } => {
+                let rel = &self.relations[relation];
+                let scan: Box<dyn PlanNode> = match (rel.indexes[index].storage.kind(), lower) {
+                    (IndexKind::Hash, Bound::Included(key)) => Box::new(executor::IndexLookup {
+                        table: rel.table.clone(),
+                        index: rel.indexes[index].clone(),
+                        key,
+                    }),
+                    (_, lower) => Box::new(executor::IndexScan {
+                        table: rel.table.clone(),
+                        index: rel.indexes[index].clone(),
+                        lower,
+                        upper,
+                    }),
+                };
+                let node = with_filter(scan, self.filter_cond(&filter, &layout));
+                let label = format!("Index Scan using {} on {}", rel.indexes[index].name, rel.label());
+                (node, label, vec![self.describe_all("Index Cond", &index_cond), self.describe_all("Filter", &filter)], vec![])
+            }
+            PathKind::HashJoin { left, right, keys, filter } => {
+                let left_layout = self.layout(&left.relations);
+                let right_layout = self.layout(&right.relations);
+                let hash_cond = keys
+                    .iter()
+                    .map(|(l, r)| format!("({} = {})", describe(l, &self.names), describe(r, &self.names)))
+                    .collect::<Vec<_>>()
+                    .join(" AND ");
+                let (left_keys, right_keys) = keys.iter().map(|(l, r)| (remap(l, &left_layout), remap(r, &right_layout))).unzip();
+                let (left, right) = (self.build(*left), self.build(*right));
+                let join: Box<dyn PlanNode> = Box::new(executor::HashJoin {
+                    left: left.node,
+                    right: right.node,
+                    left_keys,
+                    right_keys,
+                });
+                let node = with_filter(join, self.filter_cond(&filter, &layout));
+                let details = vec![Some(format!("Hash Cond: {}", hash_cond)), self.describe_all("Filter", &filter)];
+                (node, "Hash Join".to_string(), details, vec![left.explain, right.explain])
+            }
+            PathKind::MergeJoin { left, right, keys, filter } => {
+                let left_layout = self.layout(&left.relations);
+                let right_layout = self.layout(&right.relations);
+                let merge_cond = keys
+                    .iter()
+                    .map(|(l, r)| format!("({} = {})", describe(l, &self.names), describe(r, &self.names)))
+                    .collect::<Vec<_>>()
+                    .join(" AND ");
+                let (left_keys, right_keys): (Vec<Expr>, Vec<Expr>) = keys.iter().map(|(l, r)| (remap(l, &left_layout), remap(r, &right_layout))).unzip();
+                let sort_keys = |keys: &[Expr]| keys.iter().map(|expr| SortKey { expr: expr.clone(), desc: false }).collect::<Vec<_>>();
+                let left = self.build(*left).sort(sort_keys(&left_keys));
+                let right = self.build(*right).sort(sort_keys(&right_keys));
+                let join: Box<dyn PlanNode> = Box::new(executor::MergeJoin {
+                    left: left.node,
+                    right: right.node,
+                    left_keys,
+                    right_keys,
+                });
+                let node = with_filter(join, self.filter_cond(&filter, &layout));
+                let details = vec![Some(format!("Merge Cond: {}", merge_cond)), self.describe_all("Filter", &filter)];
+                (node, "Merge Join".to_string(), details, vec![left.explain, right.explain])
+            }
+            PathKind::NestedLoop { left, right, filter } => {
+                let (left, right) = (self.build(*left), self.build(*right));
+                let node = Box::new(executor::NestedLoopJoin {
+                    left: left.node,
+                    right: right.node,
+                    cond: self.filter_cond(&filter, &layout),
+                });
+                (node, "Nested Loop".to_string(), vec![self.describe_all("Join Filter", &filter)], vec![left.explain, right.explain])
+            }
+        };
+        let mut columns = vec![String::new(); self.columns.len()];
+        let mut distinct = vec![0.0; self.columns.len()];
+        for (global, pos) in layout.iter().enumerate() {
+            if let Some(pos) = pos {
+                columns[*pos] = self.names[global].clone();
+                distinct[*pos] = self.distinct(global).min(path.rows.max(1.0));
+            }
+        }
+        let len = layout.iter().flatten().count();
+        columns.truncate(len);
+        distinct.truncate(len);
+        Plan {
+            node,
+            explain: Explain {
+                label,
+                details: details.into_iter().flatten().collect(),
+                rows: path.rows,
+                cost: path.cost,
+                children,
+            },
+            columns,
+            distinct,
+        }
+    }
+}
+
+// rows行を並び替えるコスト。メモリに入りきらなければ、ランを書き出して読み直す分も足す
+fn sort_cost(rows: f64, width: f64, keys: usize) -> f64 {
+    let compare = 2.0 * CPU_OPERATOR_COST * rows * rows.max(2.0).log2() * keys as f64;
+    let bytes = rows * width;
+    if bytes <= WORK_MEMORY as f64 {
+        return compare;
+    }
+    let pages = bytes / PAGE_SIZE as f64;
+    let runs = bytes / WORK_MEMORY as f64;
+    let fan_in = (WORK_MEMORY / PAGE_SIZE - 1) as f64;
+    let passes = runs.log(fan_in).ceil().max(1.0);
+    compare + 2.0 * pages * SEQ_PAGE_COST * passes
+}
+
+fn with_filter(node: Box<dyn PlanNode>, cond: Option<Expr>) -> Box<dyn PlanNode> {
+    match cond {
+        Some(cond) => Box::new(executor::Filter { inner: node, cond }),
+        None => node,
+    }
+}
+
+fn mask_of(relations: &[usize]) -> u32 {
+    relations.iter().fold(0, |mask, relation| mask | 1 << relation)
+}
+
+// 値 op 列 を 列 op' 値 に書き換える時の演算子
+fn flip(op: BinaryOp) -> Option<BinaryOp> {
+    use BinaryOp::*;
+    Some(match op {
+        Eq => Eq,
+        Ne => Ne,
+        Lt => Gt,
+        Le => Ge,
+        Gt => Lt,
+        Ge => Le,
+        _ => return None,
+    })
+}
+
+// 条件が「列 op 値」の形ならopと値を返す
+fn column_condition(expr: &Expr, column: usize) -> Option<(BinaryOp, Value)> {
+    match expr {
+        Expr::Binary(op, left, right) => match (left.as_ref(), right.as_ref()) {
+            (Expr::Column(i), Expr::Literal(value)) if *i == column => Some((*op, value.clone())),
+            (Expr::Literal(value), Expr::Column(i)) if *i == column => Some((flip(*op)?, value.clone())),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+// インデックスのキーはエンコードしたバイト列で比べるので、列の型に揃えられる値だけを使う
+fn coerce(value: Value, ty: ColumnType) -> Option<Value> {
+    match (value, ty) {
+        (value @ Value::Int(_), ColumnType::Int)
+        | (value @ Value::UInt(_), ColumnType::UInt)
+        | (value @ Value::Float(_), ColumnType::Float)
+        | (value @ Value::Bytes(_), ColumnType::Bytes)
+        | (value @ Value::Str(_), ColumnType::Str) => Some(value),
+        (Value::Int(n), ColumnType::UInt) if n >= 0 => Some(Value::UInt(n as u64)),
+        (Value::Int(n), ColumnType::Float) => Some(Value::Float(n as f64)),
+        (Value::UInt(n), ColumnType::Float) => Some(Value::Float(n as f64)),
+        _ => None,
+    }
+}
+
+fn number(value: &Value) -> Option<f64> {
+    match value {
+        Value::Int(n) => Some(*n as f64),
+        Value::UInt(n) => Some(*n as f64),
+        Value::Float(f) => Some(*f),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::catalog::{self, Column, Schema};
+    use crate::wal;
+
+    #[test]
+    fn test_analyze_and_estimate() {
+        let path = std::env::temp_dir().join(format!("planner_test_{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(wal::log_path(&path));
+        let (mut bufmgr, txn_mgr, catalog) = catalog::open(&path, 32).unwrap();
+        let txn = txn_mgr.begin().unwrap();
+        let schema = Schema::new(vec![Column::new("n", ColumnType::Int), Column::new("s", ColumnType::Str)]);
+        let table = catalog.create_table(&mut bufmgr, &txn, "t", schema).unwrap();
+        for i in 0..100 {
+            let s = if i % 4 == 0 { Value::Null } else { Value::Str("x".repeat(40 + i % 5)) };
+            table.heap.insert(&mut bufmgr, &txn, &executor::encode_row(&[Value::Int(i as i64), s])).unwrap();
+        }
+        let stats = analyze(&mut bufmgr, &txn, &table).unwrap();
+        assert_eq!(stats.rows, 100);
+        assert_eq!(stats.pages, table.heap.num_pages(&mut bufmgr).unwrap());
+        let n = stats.column("n").unwrap();
+        assert_eq!((n.distinct, n.nulls, n.histogram.len()), (100, 0, HISTOGRAM_BUCKETS + 1));
+        assert_eq!((n.histogram[0].clone(), n.histogram[HISTOGRAM_BUCKETS].clone()), (Value::Int(0), Value::Int(99)));
+        let s = stats.column("s").unwrap();
+        assert_eq!((s.distinct, s.nulls), (5, 25));
+        assert_eq!(s.histogram[0], Value::Str("x".repeat(HISTOGRAM_VALUE_SIZE)));
+
+        // カタログに保存して読み直せる
+        catalog.set_stats(&mut bufmgr, &txn, stats.clone()).unwrap();
+        catalog.set_stats(&mut bufmgr, &txn, stats.clone()).unwrap();
+        assert_eq!(catalog.stats(&mut bufmgr, &txn, "t").unwrap(), Some(stats.clone()));
+
+        let relations = [Relation {
+            table,
+            alias: "t".to_string(),
+            indexes: vec![],
+            stats: Some(stats),
+        }];
+        let lt = Expr::binary(BinaryOp::Lt, Expr::column(0), Expr::literal(Value::Int(25)));
+        let is_null = Expr::IsNull(Box::new(Expr::column(1)));
+        let (plan, order) = plan_joins(&relations, vec![lt, is_null]).unwrap();
+        assert_eq!(order, vec![0]);
+        // n < 25 が 1/4、s IS NULL が 1/4
+        assert!((plan.rows() - 100.0 * 0.25 * 0.25).abs() < 1.0, "{}", plan.explain);
+        txn_mgr.commit(txn).unwrap();
+    }
+
+    #[test]
+    fn test_merge_join() {
+        let path = std::env::temp_dir().join(format!("planner_test_merge_{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(wal::log_path(&path));
+        let (mut bufmgr, txn_mgr, catalog) = catalog::open(&path, 32).unwrap();
+        let txn = txn_mgr.begin().unwrap();
+        let mut relations = vec![];
+        for name in ["a", "b"] {
+            let table = catalog.create_table(&mut bufmgr, &txn, name, Schema::new(vec![Column::new("k", ColumnType::Int)])).unwrap();
+            for i in 0..30 {
+                let k = if i % 10 == 0 { Value::Null } else { Value::Int(i % 7) };
+                table.heap.insert(&mut bufmgr, &txn, &executor::encode_row(&[k])).unwrap();
+            }
+            // どちらのハッシュ表もメモリに入りきらないと思わせる
+            let stats = TableStats {
+                table: name.to_string(),
+                rows: 1_000_000,
+                pages: 100_000,
+                columns: vec![],
+            };
+            relations.push(Relation {
+                table,
+                alias: name.to_string(),
+                indexes: vec![],
+                stats: Some(stats),
+            });
+        }
+        let eq = Expr::binary(BinaryOp::Eq, Expr::column(0), Expr::column(1));
+        let (plan, _) = plan_joins(&relations, vec![eq]).unwrap();
+        let explain = plan.explain.to_string();
+        assert!(explain.starts_with("Merge Join"), "{}", explain);
+        assert!(explain.contains("Merge Cond: (a.k = b.k)") || explain.contains("Merge Cond: (b.k = a.k)"), "{}", explain);
+        assert!(explain.contains("Sort Key: a.k") && explain.contains("Sort Key: b.k"), "{}", explain);
+
+        // NULLを除いた27行ずつを、同じ値同士で組み合わせる
+        let rows = executor::collect(plan.node.as_ref(), &mut bufmgr, &txn).unwrap();
+        let counts: Vec<usize> = (0..7).map(|k| (0..30).filter(|i| i % 10 != 0 && i % 7 == k).count()).collect();
+        assert_eq!(rows.len(), counts.iter().map(|n| n * n).sum::<usize>());
+        assert!(rows.iter().all(|row| row[0] == row[1]));
+        assert!(rows.windows(2).all(|w| expr::sort_cmp(&w[0][0], &w[1][0]).is_le()));
+        txn_mgr.commit(txn).unwrap();
+    }
+}
@@ -0,0 +1,1095 @@
+// 正規表現エンジン(grepで使う)
+// パターンを構文木に解析し、NFAの命令列にコンパイルして、Pike VMで実行する
+// Pike VMはNFAの全ての状態を1文字ずつ同時に進めるので、バックトラックのように
+// パターンによって実行時間が指数的に増えることが無い(テキストの長さ × 命令数で終わる)
+//
+// 使える構文
+// - 文字・. (改行以外の任意の1文字)・\で始まるエスケープ(\. \* \t \n など)
+// - 文字クラス：[abc] [a-z] [^0-9] [[:alpha:]]、\d \w \s と否定の \D \W \S
+// - アンカー：^ (行頭) $ (行末) \b (単語の境界) \B (単語の境界以外)
+// - 選択：a|b、グループ：(ab) (?:ab)
+// - 繰り返し：* + ? {n} {n,} {n,m}、後ろに?を付けると最短一致
+//
+// 複数の一致候補がある時は、Perlなどと同じく一番左から始まるものの中で、選択や繰り返しの優先順位が高いものを選ぶ
+
+use std::fmt;
+use std::sync::Mutex;
+
+// コンパイルした命令数の上限
+// {n,m}は中身をコピーして展開するので、入れ子にすると大きくなりすぎることがある
+const MAX_PROGRAM_SIZE: usize = 100_000;
+const MAX_REPEAT: u32 = 1000;
+
+/// パターンの解析エラー
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Error {
+    /// パターンの何文字目で見つかったか
+    pub position: usize,
+    pub message: String,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "regex parse error at position {}: {}", self.position, self.message)
+    }
+}
+
+impl std::error::Error for Error {}
+
+// \d \w \s
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Perl {
+    Digit,
+    Word,
+    Space,
+}
+
+impl Perl {
+    fn matches(self, c: char) -> bool {
+        match self {
+            Perl::Digit => c.is_ascii_digit(),
+            Perl::Word => is_word_char(c),
+            Perl::Space => c.is_whitespace(),
+        }
+    }
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+#[derive(Debug, Clone)]
+enum ClassItem {
+    Range(char, char),
+    // \d \w \s (trueなら\D \W \S)
+    Perl(Perl, bool),
+    // [[:alpha:]]など
+    Posix(fn(&char) -> bool),
+}
+
+#[derive(Debug, Clone)]
+struct Class {
+    items: Vec<ClassItem>,
+    negated: bool,
+}
+
+impl Class {
+    fn contains(&self, c: char) -> bool {
+        self.items.iter().any(|item| match *item {
+            ClassItem::Range(start, end) => start <= c && c <= end,
+            ClassItem::Perl(perl, negated) => perl.matches(c) != negated,
+            ClassItem::Posix(f) => f(&c),
+        })
+    }
+
+    fn matches(&self, c: char, case_insensitive: bool) -> bool {
+        let found = self.contains(c) || (case_insensitive && case_variants(c).any(|v| self.contains(v)));
+        found != self.negated
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Assert {
+    LineStart,
+    LineEnd,
+    WordBoundary,
+    NotWordBoundary,
+    // 直前・直後が単語の文字でない(Options::whole_wordで使う)
+    NotWordBefore,
+    NotWordAfter,
+}
+
+impl Assert {
+    // prevは位置の直前の文字、nextは直後の文字
+    fn matches(self, prev: Option<char>, next: Option<char>) -> bool {
+        let word = |c: Option<char>| c.is_some_and(is_word_char);
+        match self {
+            Assert::LineStart => prev.is_none() || prev == Some('\n'),
+            Assert::LineEnd => next.is_none() || next == Some('\n'),
+            Assert::WordBoundary => word(prev) != word(next),
+            Assert::NotWordBoundary => word(prev) == word(next),
+            Assert::NotWordBefore => !word(prev),
+            Assert::NotWordAfter => !word(next),
+        }
+    }
+}
+
+// 構文木
+#[derive(Debug)]
+enum Node {
+    Empty,
+    Char(char),
+    // 改行以外の任意の1文字
+    Any,
+    Class(Class),
+    Assert(Assert),
+    Concat(Vec<Node>),
+    Alternate(Vec<Node>),
+    Repeat {
+        node: Box<Node>,
+        min: u32,
+        max: Option<u32>,
+        greedy: bool,
+    },
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Parser {
+    fn error<T>(&self, message: impl Into<String>) -> Result<T, Error> {
+        Err(Error {
+            position: self.pos,
+            message: message.into(),
+        })
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn next_char(&mut self) -> Result<char, Error> {
+        match self.peek() {
+            Some(c) => {
+                self.pos += 1;
+                Ok(c)
+            }
+            None => self.error("unexpected end of pattern"),
+        }
+    }
+
+    fn parse(mut self) -> Result<Node, Error> {
+        let node = self.parse_alternate()?;
+        if self.pos < self.chars.len() {
+            // parse_alternateが止まるのは対応する(の無い)だけ
+            return self.error("unmatched ')'");
+        }
+        Ok(node)
+    }
+
+    fn parse_alternate(&mut self) -> Result<Node, Error> {
+        let mut branches = vec![self.parse_concat()?];
+        while self.eat('|') {
+            branches.push(self.parse_concat()?);
+        }
+        if branches.len() == 1 {
+            return Ok(branches.pop().unwrap());
+        }
+        Ok(Node::Alternate(branches))
+    }
+
+    fn parse_concat(&mut self) -> Result<Node, Error> {
+        let mut nodes = vec![];
+        while let Some(c) = self.peek() {
+            if c == '|' || c == ')' {
+                break;
+            }
+            let atom = self.parse_atom()?;
+            nodes.push(self.parse_repeat(atom)?);
+        }
+        Ok(match nodes.len() {
+            0 => Node::Empty,
+            1 => nodes.pop().unwrap(),
+            _ => Node::Concat(nodes),
+        })
+    }
+
+    fn parse_repeat(&mut self, mut node: Node) -> Result<Node, Error> {
+        loop {
+            let start = self.pos;
+            let (min, max) = match self.peek() {
+                Some('*') => (0, None),
+                Some('+') => (1, None),
+                Some('?') => (0, Some(1)),
+                Some('{') => match self.parse_counted()? {
+                    Some(range) => range,
+                    // {の後ろが回数で無ければ、ただの文字として扱う
+                    None => return Ok(node),
+                },
+                _ => return Ok(node),
+            };
+            if start == self.pos {
+                self.pos += 1;
+            }
+            let greedy = !self.eat('?');
+            node = Node::Repeat {
+                node: Box::new(node),
+                min,
+                max,
+                greedy,
+            };
+        }
+    }
+
+    // {n} {n,} {n,m} を読む。回数の形になっていなければ読まずにNoneを返す
+    fn parse_counted(&mut self) -> Result<Option<(u32, Option<u32>)>, Error> {
+        let start = self.pos;
+        self.pos += 1;
+        let min = self.parse_number();
+        let max = if self.eat(',') { self.parse_number() } else { min };
+        let min = match min {
+            Some(min) if self.eat('}') => min,
+            _ => {
+                self.pos = start;
+                return Ok(None);
+            }
+        };
+        if min > MAX_REPEAT || max.is_some_and(|max| max > MAX_REPEAT) {
+            self.pos = start;
+            return self.error(format!("repetition count exceeds {}", MAX_REPEAT));
+        }
+        if max.is_some_and(|max| max < min) {
+            self.pos = start;
+            return self.error("invalid repetition range");
+        }
+        Ok(Some((min, max)))
+    }
+
+    fn parse_number(&mut self) -> Option<u32> {
+        let start = self.pos;
+        while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        if start == self.pos {
+            return None;
+        }
+        let digits: String = self.chars[start..self.pos].iter().collect();
+        // 大きすぎる数はMAX_REPEATを超えたものとして扱う
+        Some(digits.parse().unwrap_or(u32::MAX))
+    }
+
+    fn parse_atom(&mut self) -> Result<Node, Error> {
+        let c = self.next_char()?;
+        match c {
+            '(' => {
+                // (?:...)は(...)と同じ(一致した位置は取り出さないので区別しない)
+                if self.eat('?') && !self.eat(':') {
+                    return self.error("unsupported group flag");
+                }
+                let node = self.parse_alternate()?;
+                if !self.eat(')') {
+                    return self.error("unclosed group");
+                }
+                Ok(node)
+            }
+            '[' => self.parse_class(),
+            '.' => Ok(Node::Any),
+            '^' => Ok(Node::Assert(Assert::LineStart)),
+            '$' => Ok(Node::Assert(Assert::LineEnd)),
+            '*' | '+' | '?' => {
+                self.pos -= 1;
+                self.error("repetition operator has nothing to repeat")
+            }
+            '\\' => self.parse_escape(),
+            c => Ok(Node::Char(c)),
+        }
+    }
+
+    fn parse_escape(&mut self) -> Result<Node, Error> {
+        let c = self.next_char()?;
+        Ok(match c {
+            'b' => Node::Assert(Assert::WordBoundary),
+            'B' => Node::Assert(Assert::NotWordBoundary),
+            c => match self.escape_class_item(c)? {
+                ClassItem::Range(c, _) => Node::Char(c),
+                item => Node::Class(Class {
+                    items: vec![item],
+                    negated: false,
+                }),
+            },
+        })
+    }
+
+    // \の後ろの文字を、1文字か\d \w \sにする
+    fn escape_class_item(&self, c: char) -> Result<ClassItem, Error> {
+        Ok(match c {
+            'd' => ClassItem::Perl(Perl::Digit, false),
+            'D' => ClassItem::Perl(Perl::Digit, true),
+            'w' => ClassItem::Perl(Perl::Word, false),
+            'W' => ClassItem::Perl(Perl::Word, true),
+            's' => ClassItem::Perl(Perl::Space, false),
+            'S' => ClassItem::Perl(Perl::Space, true),
+            't' => ClassItem::Range('\t', '\t'),
+            'n' => ClassItem::Range('\n', '\n'),
+            'r' => ClassItem::Range('\r', '\r'),
+            // 英数字以外は、記号をそのままの文字として使うためのエスケープ
+            c if !c.is_alphanumeric() => ClassItem::Range(c, c),
+            c => return self.error(format!("unknown escape sequence \\{}", c)),
+        })
+    }
+
+    fn parse_class(&mut self) -> Result<Node, Error> {
+        let negated = self.eat('^');
+        let mut items = vec![];
+        // 最初の]は閉じ括弧ではなく文字として扱う
+        let mut first = true;
+        loop {
+            let c = match self.peek() {
+                Some(c) => c,
+                None => return self.error("unclosed character class"),
+            };
+            if c == ']' && !first {
+                self.pos += 1;
+                break;
+            }
+            first = false;
+            if c == '[' && self.chars.get(self.pos + 1) == Some(&':') {
+                items.push(self.parse_posix_class()?);
+                continue;
+            }
+            let start = match self.parse_class_char()? {
+                ClassItem::Range(c, _) => c,
+                item => {
+                    items.push(item);
+                    continue;
+                }
+            };
+            // a-zの範囲。最後の-は文字として扱う
+            if self.peek() == Some('-') && self.chars.get(self.pos + 1).is_some_and(|&c| c != ']') {
+                self.pos += 1;
+                let end = match self.parse_class_char()? {
+                    ClassItem::Range(c, _) => c,
+                    _ => return self.error("invalid range end in character class"),
+                };
+                if end < start {
+                    return self.error(format!("invalid range {}-{} in character class", start, end));
+                }
+                items.push(ClassItem::Range(start, end));
+            } else {
+                items.push(ClassItem::Range(start, start));
+            }
+        }
+        Ok(Node::Class(Class { items, negated }))
+    }
+
+    fn parse_class_char(&mut self) -> Result<ClassItem, Error> {
+        let c = self.next_char()?;
+        if c == '\\' {
+            let c = self.next_char()?;
+            return self.escape_class_item(c);
+        }
+        Ok(ClassItem::Range(c, c))
+    }
+
+    fn parse_posix_class(&mut self) -> Result<ClassItem, Error> {
+        let start = self.pos;
+        let end = match (self.pos + 2..self.chars.len().saturating_sub(1))
+            .find(|&i| self.chars[i] == ':' && self.chars[i + 1] == ']')
+        {
+            Some(end) => end,
+            None => return self.error("unclosed POSIX character class"),
+        };
+        let name: String = self.chars[start + 2..end].iter().collect();
+        let f: fn(&char) -> bool = match name.as_str() {
+            "alpha" => char::is_ascii_alphabetic,
+            "digit" => char::is_ascii_digit,
+            "alnum" => char::is_ascii_alphanumeric,
+            "upper" => char::is_ascii_uppercase,
+            "lower" => char::is_ascii_lowercase,
+            "space" => |c| c.is_ascii_whitespace() || *c == '\x0b',
+            "blank" => |c| *c == ' ' || *c == '\t',
+            "punct" => char::is_ascii_punctuation,
+            "xdigit" => char::is_ascii_hexdigit,
+            "cntrl" => char::is_ascii_control,
+            "print" => |c| c.is_ascii_graphic() || *c == ' ',
+            "graph" => char::is_ascii_graphic,
+            "word" => |c| c.is_ascii_alphanumeric() || *c == '_',
+            _ => return self.error(format!("unknown POSIX character class [:{}:]", name)),
+        };
+        self.pos = end + 2;
+        Ok(ClassItem::Posix(f))
+    }
+}
+
+// NFAの命令
+#[derive(Debug, Clone)]
+enum Inst {
+    Char(char),
+    Any,
+    Class(Class),
+    Assert(Assert),
+    // 両方に進む。1つ目の方が優先順位が高い
+    Split(usize, usize),
+    Jump(usize),
+    Match,
+}
+
+struct Compiler {
+    program: Vec<Inst>,
+    case_insensitive: bool,
+}
+
+impl Compiler {
+    fn push(&mut self, inst: Inst) -> Result<usize, Error> {
+        if self.program.len() >= MAX_PROGRAM_SIZE {
+            return Err(Error {
+                position: 0,
+                message: "pattern is too large".to_string(),
+            });
+        }
+        self.program.push(inst);
+        Ok(self.program.len() - 1)
+    }
+
+    fn compile(&mut self, node: &Node) -> Result<(), Error> {
+        match node {
+            Node::Empty => {}
+            &Node::Char(c) => {
+                let c = if self.case_insensitive { fold_case(c) } else { c };
+                self.push(Inst::Char(c))?;
+            }
+            Node::Any => {
+                self.push(Inst::Any)?;
+            }
+            Node::Class(class) => {
+                self.push(Inst::Class(class.clone()))?;
+            }
+            &Node::Assert(assert) => {
+                self.push(Inst::Assert(assert))?;
+            }
+            Node::Concat(nodes) => {
+                for node in nodes {
+                    self.compile(node)?;
+                }
+            }
+            Node::Alternate(branches) => {
+                // Split L1, next; L1: 1つ目; Jump end; next: Split L2, ... の形にする
+                let mut jumps = vec![];
+                for (i, branch) in branches.iter().enumerate() {
+                    if i + 1 < branches.len() {
+                        let split = self.push(Inst::Split(0, 0))?;
+                        self.compile(branch)?;
+                        jumps.push(self.push(Inst::Jump(0))?);
+                        let next = self.program.len();
+                        self.program[split] = Inst::Split(split + 1, next);
+                    } else {
+                        self.compile(branch)?;
+                    }
+                }
+                let end = self.program.len();
+                for jump in jumps {
+                    self.program[jump] = Inst::Jump(end);
+                }
+            }
+            Node::Repeat { node, min, max, greedy } => {
+                // 最低回数分は中身を並べる
+                for _ in 0..*min {
+                    self.compile(node)?;
+                }
+                match max {
+                    // 残りは0回以上の繰り返し
+                    // L1: Split L2, end; L2: 中身; Jump L1; end:
+                    None => {
+                        let split = self.push(Inst::Split(0, 0))?;
+                        self.compile(node)?;
+                        self.push(Inst::Jump(split))?;
+                        let end = self.program.len();
+                        self.program[split] = self.split(split + 1, end, *greedy);
+                    }
+                    // 残りは(max - min)回の省略できる中身(a{1,3}ならa(a(a)?)?)
+                    Some(max) => {
+                        let mut splits = vec![];
+                        for _ in *min..*max {
+                            splits.push(self.push(Inst::Split(0, 0))?);
+                            self.compile(node)?;
+                        }
+                        let end = self.program.len();
+                        for split in splits {
+                            self.program[split] = self.split(split + 1, end, *greedy);
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // 最長一致なら中身に進む方を優先する
+    fn split(&self, body: usize, skip: usize, greedy: bool) -> Inst {
+        if greedy {
+            Inst::Split(body, skip)
+        } else {
+            Inst::Split(skip, body)
+        }
+    }
+}
+
+// 変換結果が1文字の時だけ返す
+fn single_char(mut chars: impl Iterator<Item = char>) -> Option<char> {
+    let first = chars.next();
+    if chars.next().is_some() {
+        None
+    } else {
+        first
+    }
+}
+
+// 大文字・小文字の組の他に、同じ文字に畳み込まれる文字(UnicodeのCaseFolding.txtの単純な畳み込みから)
+// どれもfold_caseで右の文字になるが、右の文字のto_uppercase・to_lowercaseからは辿れないので、
+// 文字クラスと比べる時のためにここに並べておく
+const FOLD_EXTRAS: [(char, char); 29] = [
+    // ギリシャ文字の語末のシグマ(ς・σ・Σ)
+    ('\u{3c2}', '\u{3c3}'),
+    // 長いs(ſ・s・S)
+    ('\u{17f}', 's'),
+    // ケルビン記号・オングストローム記号・オーム記号
+    ('\u{212a}', 'k'),
+    ('\u{212b}', '\u{e5}'),
+    ('\u{2126}', '\u{3c9}'),
+    // 大文字のエスツェット(ẞ・ß)
+    ('\u{1e9e}', '\u{df}'),
+    ('\u{1e9b}', '\u{1e61}'),
+    // ギリシャ文字の異体字
+    ('\u{345}', '\u{3b9}'),
+    ('\u{1fbe}', '\u{3b9}'),
+    ('\u{3d0}', '\u{3b2}'),
+    ('\u{3d1}', '\u{3b8}'),
+    ('\u{3d5}', '\u{3c6}'),
+    ('\u{3d6}', '\u{3c0}'),
+    ('\u{3f0}', '\u{3ba}'),
+    ('\u{3f1}', '\u{3c1}'),
+    ('\u{3f5}', '\u{3b5}'),
+    // キリル文字の異体字
+    ('\u{1c80}', '\u{432}'),
+    ('\u{1c81}', '\u{434}'),
+    ('\u{1c82}', '\u{43e}'),
+    ('\u{1c83}', '\u{441}'),
+    ('\u{1c84}', '\u{442}'),
+    ('\u{1c85}', '\u{442}'),
+    ('\u{1c86}', '\u{44a}'),
+    ('\u{1c87}', '\u{463}'),
+    ('\u{1c88}', '\u{a64b}'),
+    // ǅのようなタイトルケースの文字
+    ('\u{1c5}', '\u{1c6}'),
+    ('\u{1c8}', '\u{1c9}'),
+    ('\u{1cb}', '\u{1cc}'),
+    ('\u{1f2}', '\u{1f3}'),
+];
+
+// 大文字・小文字を区別しない時に比べる形にする
+// 一度大文字にしてから小文字にするので、ς・σ・Σやſ・s・SやK(ケルビン記号)・k・Kは同じ文字になる
+// ßのように大文字が複数文字になるものは小文字にするだけ、İのように小文字も複数文字になるものはそのまま比べる
+// トルコ語の点の無いıはiと別の文字として扱う(Unicodeの畳み込みと同じ)
+fn fold_case(c: char) -> char {
+    if c == '\u{131}' {
+        return c;
+    }
+    let upper = single_char(c.to_uppercase()).unwrap_or(c);
+    single_char(upper.to_lowercase())
+        .or_else(|| single_char(c.to_lowercase()))
+        .unwrap_or(c)
+}
+
+// cと大文字・小文字だけが違う文字(畳み込むとcと同じ文字になる文字)
+fn case_variants(c: char) -> impl Iterator<Item = char> {
+    let folded = fold_case(c);
+    let upper = single_char(folded.to_uppercase());
+    let extras = FOLD_EXTRAS.iter().filter(move |&&(_, to)| to == folded).map(|&(from, _)| from);
+    Some(folded)
+        .into_iter()
+        .chain(upper)
+        .chain(extras)
+        .filter(move |&v| v != c && fold_case(v) == folded)
+}
+
+// パターンが大文字を含むか(スマートケースで使う)
+// \Wや\Sのようなエスケープは文字ではないので数えない
+fn has_uppercase(node: &Node) -> bool {
+    match node {
+        Node::Char(c) => c.is_uppercase(),
+        Node::Class(class) => class.items.iter().any(|item| match *item {
+            ClassItem::Range(start, end) => start.is_uppercase() || end.is_uppercase(),
+            _ => false,
+        }),
+        Node::Concat(nodes) | Node::Alternate(nodes) => nodes.iter().any(has_uppercase),
+        Node::Repeat { node, .. } => has_uppercase(node),
+        Node::Empty | Node::Any | Node::Assert(_) => false,
+    }
+}
+
+/// 正規表現をコンパイルする時の設定
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Options {
+    /// 大文字・小文字を区別しない
+    pub case_insensitive: bool,
+    /// 前後が単語の文字(英数字と_)でない部分だけに一致させる(grep -w)
+    pub whole_word: bool,
+    /// 行全体に一致させる(grep -x)
+    pub whole_line: bool,
+    /// パターンを正規表現ではなく、ただの文字列として扱う(grep -F)
+    pub literal: bool,
+    /// パターンに大文字が無ければ、大文字・小文字を区別しない
+    pub smart_case: bool,
+}
+
+/// コンパイルした正規表現
+#[derive(Debug, Clone)]
+pub struct Regex {
+    program: Vec<Inst>,
+    case_insensitive: bool,
+    // パターンがただの文字列なら、NFAを使わずにstr::findで探す
+    literal: Option<String>,
+    scratch: ScratchPool,
+}
+
+// Pike VMのスレッド：命令の位置と、一致し始めた位置
+#[derive(Clone, Copy)]
+struct Thread {
+    pc: usize,
+    start: usize,
+}
+
+// 1文字分のスレッドの一覧
+// 同じ命令に後から来たスレッドは優先順位が低いので捨てる
+// visitedにgenerationが入っている命令は、この一覧で既に辿っている
+struct Threads {
+    list: Vec<Thread>,
+    visited: Vec<u64>,
+    generation: u64,
+}
+
+impl Threads {
+    fn new(size: usize) -> Self {
+        Self {
+            list: Vec::with_capacity(size),
+            visited: vec![0; size],
+            generation: 1,
+        }
+    }
+
+    fn clear(&mut self) {
+        self.list.clear();
+        self.generation += 1;
+    }
+}
+
+// find_atの作業領域(今の文字と次の文字のスレッドの一覧、add_threadのスタック)
+// 1文字ごとに確保しないよう、find_atの中で使い回す
+struct Scratch {
+    current: Threads,
+    next: Threads,
+    stack: Vec<usize>,
+}
+
+// 使い終わった作業領域を置いておき、次のfind_atで使い回す
+// grepでは複数のスレッドが同じRegexを使うので、Mutexで守る
+#[derive(Default)]
+struct ScratchPool(Mutex<Vec<Scratch>>);
+
+impl ScratchPool {
+    fn take(&self, size: usize) -> Scratch {
+        match self.0.lock().unwrap().pop() {
+            Some(mut scratch) => {
+                scratch.current.clear();
+                scratch.next.clear();
+                scratch
+            }
+            None => Scratch {
+                current: Threads::new(size),
+                next: Threads::new(size),
+                stack: Vec::with_capacity(size),
+            },
+        }
+    }
+
+    fn put(&self, scratch: Scratch) {
+        self.0.lock().unwrap().push(scratch);
+    }
+}
+
+// 作業領域はコピーしなくても良い(コピーした方は必要になった時に確保する)
+impl Clone for ScratchPool {
+    fn clone(&self) -> Self {
+        Self::default()
+    }
+}
+
+impl fmt::Debug for ScratchPool {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("ScratchPool")
+    }
+}
+
+impl Regex {
+    /// パターンをコンパイルする
+    pub fn new(pattern: &str) -> Result<Self, Error> {
+        Self::with_options(&[pattern], &Options::default())
+    }
+
+    /// 大文字・小文字を区別しない正規表現にコンパイルする
+    pub fn new_case_insensitive(pattern: &str) -> Result<Self, Error> {
+        let options = Options {
+            case_insensitive: true,
+            ..Options::default()
+        };
+        Self::with_options(&[pattern], &options)
+    }
+
+    /// 複数のパターンのどれかに一致する正規表現にコンパイルする
+    /// パターンが1つも無ければ、何にも一致しない
+    pub fn with_options(patterns: &[impl AsRef<str>], options: &Options) -> Result<Self, Error> {
+        let mut branches = vec![];
+        for pattern in patterns {
+            let pattern = pattern.as_ref();
+            let node = if options.literal {
+                Node::Concat(pattern.chars().map(Node::Char).collect())
+            } else {
+                let parser = Parser {
+                    chars: pattern.chars().collect(),
+                    pos: 0,
+                };
+                parser.parse()?
+            };
+            branches.push(node);
+        }
+        let mut node = match branches.len() {
+            // 空の文字クラスは何にも一致しない
+            0 => Node::Class(Class {
+                items: vec![],
+                negated: false,
+            }),
+            1 => branches.pop().unwrap(),
+            _ => Node::Alternate(branches),
+        };
+        if options.whole_word {
+            node = Node::Concat(vec![Node::Assert(Assert::NotWordBefore), node, Node::Assert(Assert::NotWordAfter)]);
+        }
+        if options.whole_line {
+            node = Node::Concat(vec![Node::Assert(Assert::LineStart), node, Node::Assert(Assert::LineEnd)]);
+        }
+        let case_insensitive = options.case_insensitive || (options.smart_case && !has_uppercase(&node));
+        let literal = match &node {
+            _ if case_insensitive => None,
+            Node::Char(c) => Some(c.to_string()),
+            Node::Concat(nodes) => nodes
+                .iter()
+                .map(|node| match node {
+                    Node::Char(c) => Some(*c),
+                    _ => None,
+                })
+                .collect(),
+            _ => None,
+        };
+        let mut compiler = Compiler {
+            program: vec![],
+            case_insensitive,
+        };
+        compiler.compile(&node)?;
+        compiler.push(Inst::Match)?;
+        Ok(Self {
+            program: compiler.program,
+            case_insensitive,
+            literal,
+            scratch: ScratchPool::default(),
+        })
+    }
+
+    /// textのどこかに一致するか
+    pub fn is_match(&self, text: &str) -> bool {
+        self.find_at(text, 0).is_some()
+    }
+
+    /// textの最初の一致の位置(バイト単位の開始・終了位置)を返す
+    pub fn find(&self, text: &str) -> Option<(usize, usize)> {
+        self.find_at(text, 0)
+    }
+
+    /// textのstartバイト目以降で最初の一致の位置を返す
+    /// ^や\bはstartより前の文字も見て判定する
+    pub fn find_at(&self, text: &str, start: usize) -> Option<(usize, usize)> {
+        if let Some(literal) = &self.literal {
+            return text[start..].find(literal.as_str()).map(|pos| (start + pos, start + pos + literal.len()));
+        }
+        let mut scratch = self.scratch.take(self.program.len());
+        let matched = self.run(&mut scratch, text, start);
+        self.scratch.put(scratch);
+        matched
+    }
+
+    // Pike VMでtextのstartバイト目以降を探す
+    fn run(&self, scratch: &mut Scratch, text: &str, start: usize) -> Option<(usize, usize)> {
+        let Scratch { current, next, stack } = scratch;
+        let mut matched = None;
+        let mut prev = text[..start].chars().next_back();
+        let mut pos = start;
+        loop {
+            let c = text[pos..].chars().next();
+            // まだ一致が見つかっていなければ、この位置から始まるスレッドを一番低い優先順位で加える
+            if matched.is_none() {
+                let thread = Thread { pc: 0, start: pos };
+                self.add_thread(current, stack, thread, prev, c);
+            }
+            // 一致が見つかっていて、それより優先順位の高いスレッドも残っていなければ終わり
+            // (まだ見つかっていなければ、\bなどで始められなかっただけなので次の位置に進む)
+            if current.list.is_empty() && matched.is_some() {
+                break;
+            }
+            let next_pos = pos + c.map_or(0, char::len_utf8);
+            let next_char = text[next_pos..].chars().next();
+            for i in 0..current.list.len() {
+                let thread = current.list[i];
+                let advance = match &self.program[thread.pc] {
+                    Inst::Match => {
+                        // これより優先順位の低いスレッドは捨てる
+                        matched = Some((thread.start, pos));
+                        break;
+                    }
+                    &Inst::Char(expected) => c.is_some_and(|c| self.fold(c) == expected),
+                    Inst::Any => c.is_some_and(|c| c != '\n'),
+                    Inst::Class(class) => c.is_some_and(|c| class.matches(c, self.case_insensitive)),
+                    _ => unreachable!("add_thread only queues consuming instructions"),
+                };
+                if advance {
+                    let mut next_thread = thread;
+                    next_thread.pc += 1;
+                    self.add_thread(next, stack, next_thread, c, next_char);
+                }
+            }
+            if c.is_none() {
+                break;
+            }
+            std::mem::swap(current, next);
+            next.clear();
+            prev = c;
+            pos = next_pos;
+        }
+        matched
+    }
+
+    fn fold(&self, c: char) -> char {
+        if self.case_insensitive {
+            fold_case(c)
+        } else {
+            c
+        }
+    }
+
+    // Jump・Split・Assertを辿って、文字を読む命令とMatchをthreadsに加える
+    // 優先順位を保つため、Splitの1つ目の行き先を先に全て辿る
+    // prevとnextは、スレッドを置く位置の直前と直後の文字
+    fn add_thread(&self, threads: &mut Threads, stack: &mut Vec<usize>, thread: Thread, prev: Option<char>, next: Option<char>) {
+        stack.clear();
+        stack.push(thread.pc);
+        while let Some(pc) = stack.pop() {
+            if threads.visited[pc] == threads.generation {
+                continue;
+            }
+            threads.visited[pc] = threads.generation;
+            match self.program[pc] {
+                Inst::Jump(target) => stack.push(target),
+                Inst::Split(first, second) => {
+                    stack.push(second);
+                    stack.push(first);
+                }
+                Inst::Assert(assert) => {
+                    if assert.matches(prev, next) {
+                        stack.push(pc + 1);
+                    }
+                }
+                _ => threads.list.push(Thread { pc, start: thread.start }),
+            }
+        }
+    }
+
+    /// textの重ならない一致を順に返す
+    pub fn find_iter<'r, 't>(&'r self, text: &'t str) -> Matches<'r, 't> {
+        Matches {
+            regex: self,
+            text,
+            pos: 0,
+            last_end: None,
+        }
+    }
+}
+
+/// Regex::find_iterが返すイテレーター
+pub struct Matches<'r, 't> {
+    regex: &'r Regex,
+    text: &'t str,
+    pos: usize,
+    last_end: Option<usize>,
+}
+
+impl Iterator for Matches<'_, '_> {
+    type Item = (usize, usize);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.pos > self.text.len() {
+                return None;
+            }
+            let (start, end) = self.regex.find_at(self.text, self.pos)?;
+            if start == end {
+                // 空の一致の後は1文字進める(同じ位置で止まり続けないように)
+                self.pos = end + self.text[end..].chars().next().map_or(1, char::len_utf8);
+                // 直前の一致のすぐ後ろの空の一致は返さない
+                if self.last_end == Some(end) {
+                    continue;
+                }
+            } else {
+                self.pos = end;
+            }
+            self.last_end = Some(end);
+            return Some((start, end));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn find(pattern: &str, text: &str) -> Option<(usize, usize)> {
+        Regex::new(pattern).unwrap().find(text)
+    }
+
+    #[test]
+    fn test_syntax() {
+        assert_eq!(find("abc", "xxabcxx"), Some((2, 5)));
+        assert_eq!(find("a.c", "abc"), Some((0, 3)));
+        assert_eq!(find("a.c", "a\nc"), None);
+        assert_eq!(find("[0-9]+", "abc 1234 x"), Some((4, 8)));
+        assert_eq!(find("[^a-z ]+", "abc DEF"), Some((4, 7)));
+        assert_eq!(find(r"\d{2,3}", "1 12345"), Some((2, 5)));
+        assert_eq!(find(r"\w+@\w+\.com", "mail: yori@example.com!"), Some((6, 22)));
+        assert_eq!(find("[[:upper:]][[:lower:]]*", "hello World"), Some((6, 11)));
+        assert_eq!(find("colou?r", "color"), Some((0, 5)));
+        assert_eq!(find("(ab)+c", "ababab ababc"), Some((7, 12)));
+        assert_eq!(find("(?:cat|dog)s", "hotdogs"), Some((3, 7)));
+        assert_eq!(find("a{3}", "aa aaaa"), Some((3, 6)));
+        assert_eq!(find("x{2,}", "x xx xxxx"), Some((2, 4)));
+        assert_eq!(find("a{,2}", "a{,2}"), Some((0, 5)));
+        assert_eq!(find(r"\.\*", "a.*b"), Some((1, 3)));
+        assert_eq!(find("[]a]+", "x]a]"), Some((1, 4)));
+        assert_eq!(find("[a-]+", "b-a-"), Some((1, 4)));
+        // 文字の境界はバイトではなくUTF-8の文字単位
+        assert_eq!(find("な.", "こんにちは、なまえ"), Some((18, 24)));
+    }
+
+    #[test]
+    fn test_anchors_and_priority() {
+        assert_eq!(find("^abc", "abcabc"), Some((0, 3)));
+        assert_eq!(find("abc$", "abcabc"), Some((3, 6)));
+        assert_eq!(find("^b", "ab"), None);
+        assert_eq!(find(r"\bcat\b", "concat cat"), Some((7, 10)));
+        assert_eq!(find(r"\Bcat", "cat concat"), Some((7, 10)));
+        // 一番左の一致の中で、優先順位の高い選択肢を選ぶ
+        assert_eq!(find("a|ab", "ab"), Some((0, 1)));
+        assert_eq!(find("ab|a", "ab"), Some((0, 2)));
+        assert_eq!(find("a+", "baaa"), Some((1, 4)));
+        assert_eq!(find("a+?", "baaa"), Some((1, 2)));
+        assert_eq!(find("<.*>", "<a><b>"), Some((0, 6)));
+        assert_eq!(find("<.*?>", "<a><b>"), Some((0, 3)));
+        assert_eq!(find("x*", "abc"), Some((0, 0)));
+        // バックトラックでは指数的に時間がかかるパターン
+        let text = "a".repeat(30);
+        assert_eq!(find("(a*)*b", &text), None);
+        assert_eq!(find(&format!("{}{}", "a?".repeat(30), "a".repeat(30)), &text), Some((0, 30)));
+    }
+
+    #[test]
+    fn test_find_iter_and_case_insensitive() {
+        let regex = Regex::new("[a-z]+").unwrap();
+        assert_eq!(regex.find_iter("ab 12 cd").collect::<Vec<_>>(), vec![(0, 2), (6, 8)]);
+        let regex = Regex::new("x*").unwrap();
+        assert_eq!(regex.find_iter("axx").collect::<Vec<_>>(), vec![(0, 0), (1, 3)]);
+        let regex = Regex::new_case_insensitive("héllo|wörld").unwrap();
+        assert_eq!(regex.find_iter("HÉLLO, Wörld").collect::<Vec<_>>(), vec![(0, 6), (8, 14)]);
+        let regex = Regex::new_case_insensitive("[a-c]+").unwrap();
+        assert_eq!(regex.find("xxABcd"), Some((2, 5)));
+        assert!(!Regex::new("hello").unwrap().is_match("HELLO"));
+    }
+
+    #[test]
+    fn test_reuse_scratch() {
+        let regex = Regex::new("a+b|c").unwrap();
+        assert_eq!(regex.find_iter("aab c ab").count(), 3);
+        // 作業領域は1つを使い回している
+        assert_eq!(regex.scratch.0.lock().unwrap().len(), 1);
+        let regex = &regex;
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(move || assert_eq!(regex.find("xxaab"), Some((2, 5))));
+            }
+        });
+        assert!(regex.scratch.0.lock().unwrap().len() <= 4);
+    }
+
+    #[test]
+    fn test_options() {
+        let compile = |patterns: &[&str], options: Options| Regex::with_options(patterns, &options).unwrap();
+        let word = Options {
+            whole_word: true,
+            ..Options::default()
+        };
+        assert_eq!(compile(&["cat"], word).find("concat cat"), Some((7, 10)));
+        // 記号で始まるパターンでも、前後が単語の文字でなければ良い
+        assert_eq!(compile(&["@user"], word).find("a@user @user"), Some((7, 12)));
+        let line = Options {
+            whole_line: true,
+            ..Options::default()
+        };
+        assert!(compile(&["a|ab"], line).is_match("ab"));
+        assert!(!compile(&["ab"], line).is_match("abc"));
+        let literal = Options {
+            literal: true,
+            ..Options::default()
+        };
+        assert_eq!(compile(&["a.b", "(x)"], literal).find_iter("axb a.b (x)").collect::<Vec<_>>(), vec![(4, 7), (8, 11)]);
+        assert!(!compile(&[] as &[&str], Options::default()).is_match("anything"));
+    }
+
+    #[test]
+    fn test_unicode_case_folding() {
+        for &(from, to) in &FOLD_EXTRAS {
+            assert_eq!(fold_case(from), to, "{:?}", from);
+        }
+        // 畳み込んだ文字をもう一度畳み込んでも変わらない
+        for c in (0..=0x1ffff).filter_map(char::from_u32) {
+            assert_eq!(fold_case(fold_case(c)), fold_case(c), "{:?}", c);
+        }
+        let regex = Regex::new_case_insensitive("σοφος").unwrap();
+        assert_eq!(regex.find("ΣΟΦΟΣ σοφος"), Some((0, 10)));
+        assert!(regex.is_match("σοφος"));
+        assert!(Regex::new_case_insensitive("kelvin").unwrap().is_match("\u{212a}ELVIN"));
+        assert!(Regex::new_case_insensitive("stra\u{df}e").unwrap().is_match("STRA\u{1e9e}E"));
+        assert!(Regex::new_case_insensitive("mas").unwrap().is_match("ma\u{17f}"));
+        assert!(Regex::new_case_insensitive("[a-z]+").unwrap().is_match("\u{17f}\u{212a}"));
+        assert!(Regex::new_case_insensitive("[\u{3c2}]").unwrap().is_match("\u{3a3}"));
+        assert!(!Regex::new_case_insensitive("i").unwrap().is_match("\u{131}"));
+        // 一致した位置は元の文字列のバイト位置
+        assert_eq!(Regex::new_case_insensitive("k").unwrap().find("a\u{212a}b"), Some((1, 4)));
+    }
+
+    #[test]
+    fn test_smart_case() {
+        let smart = Options {
+            smart_case: true,
+            ..Options::default()
+        };
+        assert!(Regex::with_options(&["hello"], &smart).unwrap().is_match("HELLO"));
+        assert!(!Regex::with_options(&["Hello"], &smart).unwrap().is_match("HELLO"));
+        assert!(!Regex::with_options(&["[A-Z]x"], &smart).unwrap().is_match("ax"));
+        // \Wのようなエスケープは大文字として数えない
+        assert!(Regex::with_options(&[r"a\W"], &smart).unwrap().is_match("A-"));
+    }
+
+    #[test]
+    fn test_parse_error() {
+        for (pattern, position) in [("a(b", 3), ("ab)", 2), ("*a", 0), ("a|+", 2), ("[a-", 3), ("[z-a]", 4), (r"\q", 2), ("a{3,1}", 1), ("[[:foo:]]", 1)] {
+            let err = Regex::new(pattern).unwrap_err();
+            assert_eq!(err.position, position, "{}: {}", pattern, err);
+        }
+    }
+}
@@ -0,0 +1,574 @@
+// サーバーモード
+// 1つのデータベースファイルを複数のプロセスから使えるように、TCP(localhost)かUnixドメインソケットでSQLを受け付ける
+//
+// スレッドの構成
+// - エンジンスレッド：Session(BufferPoolManagerなど)を1つだけ持ち、届いた文を順に実行する
+//   BufferPoolManagerはRcを使っていてスレッド間で共有できないので、1つのスレッドに閉じ込めてチャネルで依頼を受ける
+// - 受付スレッド：接続を受け付け、接続ごとにスレッドを作る
+// - 接続スレッド：リクエストを読んでエンジンスレッドに渡し、結果をレスポンスとして返す
+// 接続ごとに別のトランザクションを持つので、BEGINした接続同士はスナップショット分離で同時に動ける
+// 接続が切れた時にトランザクションが残っていればロールバックする
+//
+// プロトコル
+// リクエスト・レスポンスとも | 長さ(u32) | 本体 | のフレームで送る
+// - リクエストの本体：| REQUEST_QUERY(u8) | SQL(UTF-8) |
+// - レスポンスの本体
+//   - | RESPONSE_MESSAGE(u8) | メッセージ(UTF-8) |
+//   - | RESPONSE_ROWS(u8) | 列の数(u32) | (長さ(u32), 列名)* | 行の数(u32) | (長さ(u32), encode_rowした行)* |
+//   - | RESPONSE_ERROR(u8) | エラーメッセージ(UTF-8) |
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::executor::{self, Result};
+use crate::sql::{Output, Session};
+
+const REQUEST_QUERY: u8 = 0;
+const RESPONSE_MESSAGE: u8 = 0;
+const RESPONSE_ROWS: u8 = 1;
+const RESPONSE_ERROR: u8 = 2;
+
+// 壊れた長さで巨大なバッファを確保しないように、フレームの大きさに上限を設ける
+pub const MAX_FRAME_SIZE: usize = 64 * 1024 * 1024;
+
+// 接続を受け付けられなかった時(ファイルディスクリプタが足りないなど)に、次に受け付けるまで待つ時間
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+/// フレームを1つ書く
+pub fn write_frame(writer: &mut impl Write, body: &[u8]) -> io::Result<()> {
+    if body.len() > MAX_FRAME_SIZE {
+        return Err(invalid_data("frame is too large"));
+    }
+    writer.write_all(&(body.len() as u32).to_le_bytes())?;
+    writer.write_all(body)?;
+    writer.flush()
+}
+
+/// フレームを1つ読む。相手が接続を閉じていたらNone
+pub fn read_frame(reader: &mut impl Read) -> io::Result<Option<Vec<u8>>> {
+    let mut len = [0u8; 4];
+    match reader.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err),
+    }
+    let len = u32::from_le_bytes(len) as usize;
+    if len > MAX_FRAME_SIZE {
+        return Err(invalid_data("frame is too large"));
+    }
+    let mut body = vec![0u8; len];
+    reader.read_exact(&mut body)?;
+    Ok(Some(body))
+}
+
+fn put_bytes(bytes: &[u8], buf: &mut Vec<u8>) {
+    buf.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    buf.extend_from_slice(bytes);
+}
+
+fn take_u32(src: &mut &[u8]) -> io::Result<u32> {
+    if src.len() < 4 {
+        return Err(invalid_data("truncated response"));
+    }
+    let (head, rest) = src.split_at(4);
+    *src = rest;
+    Ok(u32::from_le_bytes([head[0], head[1], head[2], head[3]]))
+}
+
+fn take_bytes<'a>(src: &mut &'a [u8]) -> io::Result<&'a [u8]> {
+    let len = take_u32(src)? as usize;
+    if src.len() < len {
+        return Err(invalid_data("truncated response"));
+    }
+    let (head, rest) = src.split_at(len);
+    *src = rest;
+    Ok(head)
+}
+
+fn utf8(bytes: &[u8]) -> io::Result<String> {
+    String::from_utf8(bytes.to_vec()).map_err(|_| invalid_data("invalid UTF-8"))
+}
+
+/// 文を実行した結果をレスポンスの本体にする
+pub fn encode_response(result: &std::result::Result<Output, String>) -> Vec<u8> {
+    let mut buf = vec![];
+    match result {
+        Ok(Output::Message(message)) => {
+            buf.push(RESPONSE_MESSAGE);
+            buf.extend_from_slice(message.as_bytes());
+        }
+        Ok(Output::Rows { columns, rows }) => {
+            buf.push(RESPONSE_ROWS);
+            buf.extend_from_slice(&(columns.len() as u32).to_le_bytes());
+            for column in columns {
+                put_bytes(column.as_bytes(), &mut buf);
+            }
+            buf.extend_from_slice(&(rows.len() as u32).to_le_bytes());
+            for row in rows {
+                put_bytes(&executor::encode_row(row), &mut buf);
+            }
+        }
+        Err(message) => {
+            buf.push(RESPONSE_ERROR);
+            buf.extend_from_slice(message.as_bytes());
+        }
+    }
+    buf
+}
+
+/// レスポンスの本体を読む。外側のErrは壊れたレスポンス、内側のErrはサーバーから届いたエラー
+pub fn decode_response(body: &[u8]) -> io::Result<std::result::Result<Output, String>> {
+    let (&tag, mut rest) = body.split_first().ok_or_else(|| invalid_data("empty response"))?;
+    match tag {
+        RESPONSE_MESSAGE => Ok(Ok(Output::Message(utf8(rest)?))),
+        RESPONSE_ERROR => Ok(Err(utf8(rest)?)),
+        RESPONSE_ROWS => {
+            let columns = (0..take_u32(&mut rest)?).map(|_| utf8(take_bytes(&mut rest)?)).collect::<io::Result<Vec<_>>>()?;
+            let rows = (0..take_u32(&mut rest)?)
+                .map(|_| executor::decode_row(take_bytes(&mut rest)?).map_err(|_| invalid_data("invalid row")))
+                .collect::<io::Result<Vec<_>>>()?;
+            Ok(Ok(Output::Rows { columns, rows }))
+        }
+        _ => Err(invalid_data("unknown response")),
+    }
+}
+
+/// 待ち受けるアドレス
+/// "unix:パス"ならUnixドメインソケット、それ以外("tcp:"は省略できる)はTCP
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Address {
+    Tcp(String),
+    Unix(PathBuf),
+}
+
+impl FromStr for Address {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            return Ok(Address::Unix(PathBuf::from(path)));
+        }
+        let addr = s.strip_prefix("tcp:").unwrap_or(s);
+        if addr.is_empty() {
+            return Err(format!("invalid address '{}'", s));
+        }
+        Ok(Address::Tcp(addr.to_string()))
+    }
+}
+
+/// TCPかUnixドメインソケットの接続
+pub enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Stream {
+    pub fn connect(address: &Address) -> io::Result<Self> {
+        Ok(match address {
+            Address::Tcp(addr) => {
+                let stream = TcpStream::connect(addr)?;
+                // 小さなフレームを1往復ずつ送るので、Nagleアルゴリズムで待たされないようにする
+                stream.set_nodelay(true)?;
+                Stream::Tcp(stream)
+            }
+            Address::Unix(path) => Stream::Unix(UnixStream::connect(path)?),
+        })
+    }
+
+    fn try_clone(&self) -> io::Result<Self> {
+        Ok(match self {
+            Stream::Tcp(stream) => Stream::Tcp(stream.try_clone()?),
+            Stream::Unix(stream) => Stream::Unix(stream.try_clone()?),
+        })
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.read(buf),
+            Stream::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.write(buf),
+            Stream::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.flush(),
+            Stream::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+/// 接続を待ち受けるソケット
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+impl Listener {
+    /// addressで待ち受ける。Unixドメインソケットのファイルが残っていたら消してから作る
+    pub fn bind(address: &Address) -> io::Result<Self> {
+        Ok(match address {
+            Address::Tcp(addr) => Listener::Tcp(TcpListener::bind(addr)?),
+            Address::Unix(path) => {
+                if path.exists() {
+                    fs::remove_file(path)?;
+                }
+                Listener::Unix(UnixListener::bind(path)?)
+            }
+        })
+    }
+
+    /// 実際に待ち受けているアドレス(TCPでポート0を指定した時に、割り当てられたポートを知るのに使う)
+    pub fn local_address(&self) -> io::Result<Address> {
+        Ok(match self {
+            Listener::Tcp(listener) => Address::Tcp(listener.local_addr()?.to_string()),
+            Listener::Unix(listener) => {
+                let addr = listener.local_addr()?;
+                Address::Unix(addr.as_pathname().ok_or_else(|| invalid_data("unnamed socket"))?.to_path_buf())
+            }
+        })
+    }
+
+    fn accept(&self) -> io::Result<Stream> {
+        Ok(match self {
+            Listener::Tcp(listener) => {
+                let (stream, _) = listener.accept()?;
+                stream.set_nodelay(true)?;
+                Stream::Tcp(stream)
+            }
+            Listener::Unix(listener) => Stream::Unix(listener.accept()?.0),
+        })
+    }
+}
+
+// 接続スレッドからエンジンスレッドへの依頼
+enum Command {
+    Execute {
+        session_id: u64,
+        sql: String,
+        reply: Sender<std::result::Result<Output, String>>,
+    },
+    // 接続が切れた。残っているトランザクションをロールバックする
+    Disconnect(u64),
+    Shutdown,
+}
+
+// エンジンスレッドの本体
+// 接続ごとのトランザクションを持ち、届いた順に1文ずつ実行する
+fn run_engine(mut session: Session, commands: Receiver<Command>) -> std::result::Result<(), String> {
+    let mut txns = HashMap::new();
+    for command in commands {
+        match command {
+            Command::Execute { session_id, sql, reply } => {
+                let txn = txns.entry(session_id).or_insert(None);
+                let result = session.execute_in(txn, &sql).map_err(|err| err.to_string());
+                // 返事を待たずに接続が切れていても、実行した結果はそのままにする
+                let _ = reply.send(result);
+            }
+            // ロールバックに失敗しても、他の接続の文は実行し続ける
+            Command::Disconnect(session_id) => {
+                if let Some(Some(txn)) = txns.remove(&session_id) {
+                    if let Err(err) = session.rollback(txn) {
+                        eprintln!("connection {}: rollback failed: {}", session_id, err);
+                    }
+                }
+            }
+            Command::Shutdown => break,
+        }
+    }
+    for (session_id, txn) in txns {
+        if let Some(txn) = txn {
+            if let Err(err) = session.rollback(txn) {
+                eprintln!("connection {}: rollback failed: {}", session_id, err);
+            }
+        }
+    }
+    session.close().map_err(|err| err.to_string())
+}
+
+// 1つの接続のリクエストを、接続が閉じられるまで処理する
+fn serve_connection(stream: Stream, session_id: u64, engine: Sender<Command>) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    while let Some(body) = read_frame(&mut reader)? {
+        let result = match body.split_first() {
+            Some((&REQUEST_QUERY, sql)) => match std::str::from_utf8(sql) {
+                Ok(sql) => {
+                    let (reply, response) = mpsc::channel();
+                    let command = Command::Execute {
+                        session_id,
+                        sql: sql.to_string(),
+                        reply,
+                    };
+                    if engine.send(command).is_err() {
+                        write_frame(&mut writer, &encode_response(&Err("server is shutting down".to_string())))?;
+                        return Ok(());
+                    }
+                    response.recv().unwrap_or_else(|_| Err("server is shutting down".to_string()))
+                }
+                Err(_) => Err("query is not valid UTF-8".to_string()),
+            },
+            _ => Err("unknown request".to_string()),
+        };
+        write_frame(&mut writer, &encode_response(&result))?;
+    }
+    Ok(())
+}
+
+/// 1つのデータベースファイルを持つサーバー
+pub struct Server {
+    engine: Sender<Command>,
+    engine_thread: JoinHandle<std::result::Result<(), String>>,
+    next_session_id: Arc<AtomicU64>,
+    // 受付スレッドと、そのスレッドが待ち受けているアドレス(止める時に接続して起こす)
+    acceptors: Mutex<Vec<(Address, JoinHandle<()>)>>,
+    stopping: Arc<AtomicBool>,
+}
+
+impl Server {
+    /// ヒープファイルを開いてエンジンスレッドを起動する
+    pub fn start(path: impl AsRef<Path>, pool_size: usize) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let (engine, commands) = mpsc::channel();
+        let (opened_tx, opened) = mpsc::channel();
+        // SessionはスレッドをまたいでSendできないので、エンジンスレッドの中で開く
+        let engine_thread = thread::spawn(move || {
+            let session = match Session::open(&path, pool_size) {
+                Ok(session) => session,
+                Err(err) => {
+                    let _ = opened_tx.send(Err(err.to_string()));
+                    return Ok(());
+                }
+            };
+            let _ = opened_tx.send(Ok(()));
+            run_engine(session, commands)
+        });
+        opened.recv().map_err(|_| "engine thread panicked")??;
+        Ok(Self {
+            engine,
+            engine_thread,
+            next_session_id: Arc::new(AtomicU64::new(0)),
+            acceptors: Mutex::new(vec![]),
+            stopping: Arc::new(AtomicBool::new(false)),
+        })
+    }
+
+    /// 受付スレッドを起動してlistenerで接続を待ち受ける
+    /// 接続ごとにスレッドを作り、エンジンスレッドに文の実行を依頼する
+    /// 受け付けに失敗してもエラーを表示して受け付け続け、shutdownするまで止まらない
+    pub fn listen(&self, listener: Listener) -> io::Result<()> {
+        let address = listener.local_address()?;
+        let engine = self.engine.clone();
+        let next_session_id = Arc::clone(&self.next_session_id);
+        let stopping = Arc::clone(&self.stopping);
+        let handle = thread::spawn(move || loop {
+            let accepted = listener.accept();
+            // shutdownが受付スレッドを起こすために接続してきた
+            if stopping.load(Ordering::SeqCst) {
+                break;
+            }
+            let stream = match accepted {
+                Ok(stream) => stream,
+                Err(err) => {
+                    eprintln!("accept: {}", err);
+                    thread::sleep(ACCEPT_RETRY_DELAY);
+                    continue;
+                }
+            };
+            let engine = engine.clone();
+            let session_id = next_session_id.fetch_add(1, Ordering::SeqCst);
+            thread::spawn(move || {
+                if let Err(err) = serve_connection(stream, session_id, engine.clone()) {
+                    eprintln!("connection {}: {}", session_id, err);
+                }
+                let _ = engine.send(Command::Disconnect(session_id));
+            });
+        });
+        self.acceptors.lock().unwrap().push((address, handle));
+        Ok(())
+    }
+
+    /// 接続の受け付けを止め、実行中のトランザクションをロールバックし、全てのページを書き出してエンジンスレッドを止める
+    /// 後から届いたリクエストにはエラーを返す
+    pub fn shutdown(self) -> Result<()> {
+        self.stopping.store(true, Ordering::SeqCst);
+        for (address, handle) in self.acceptors.into_inner().unwrap() {
+            // accept()で待っている受付スレッドを、自分から接続して起こす
+            let _ = Stream::connect(&address);
+            handle.join().map_err(|_| "accept thread panicked")?;
+            if let Address::Unix(path) = &address {
+                let _ = fs::remove_file(path);
+            }
+        }
+        let _ = self.engine.send(Command::Shutdown);
+        self.engine_thread.join().map_err(|_| "engine thread panicked")??;
+        Ok(())
+    }
+}
+
+/// サーバーに接続するクライアント
+pub struct Client {
+    reader: BufReader<Stream>,
+    writer: BufWriter<Stream>,
+}
+
+impl Client {
+    pub fn connect(address: &Address) -> io::Result<Self> {
+        let stream = Stream::connect(address)?;
+        Ok(Self {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: BufWriter::new(stream),
+        })
+    }
+
+    /// 1つの文をサーバーで実行する。BEGINしたトランザクションは接続ごとに続く
+    pub fn execute(&mut self, sql: &str) -> Result<Output> {
+        let mut body = vec![REQUEST_QUERY];
+        body.extend_from_slice(sql.as_bytes());
+        write_frame(&mut self.writer, &body)?;
+        let body = read_frame(&mut self.reader)?.ok_or("server closed the connection")?;
+        Ok(decode_response(&body)??)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memcmpable::Value;
+    use crate::wal;
+
+    fn start(name: &str) -> (Server, PathBuf) {
+        let path = std::env::temp_dir().join(format!("server_test_{}_{}", std::process::id(), name));
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_file(wal::log_path(&path));
+        (Server::start(&path, 32).unwrap(), path)
+    }
+
+    fn count(client: &mut Client, table: &str) -> Value {
+        match client.execute(&format!("SELECT count(*) FROM {}", table)).unwrap() {
+            Output::Rows { rows, .. } => rows[0][0].clone(),
+            output => panic!("unexpected output: {:?}", output),
+        }
+    }
+
+    #[test]
+    fn test_response() {
+        let results = vec![
+            Ok(Output::Message("INSERT 1".to_string())),
+            Ok(Output::Rows {
+                columns: vec!["id".to_string(), "name".to_string()],
+                rows: vec![vec![Value::Int(1), Value::Str("a".to_string())], vec![Value::Null, Value::Bytes(vec![0, 1])]],
+            }),
+            Err("table t does not exist".to_string()),
+        ];
+        let mut bytes = vec![];
+        for result in &results {
+            write_frame(&mut bytes, &encode_response(result)).unwrap();
+        }
+        let mut reader = &bytes[..];
+        for result in &results {
+            assert_eq!(&decode_response(&read_frame(&mut reader).unwrap().unwrap()).unwrap(), result);
+        }
+        assert_eq!(read_frame(&mut reader).unwrap(), None);
+        assert!(decode_response(&[RESPONSE_ROWS, 1, 0]).is_err());
+        assert!(read_frame(&mut &[0xff, 0xff, 0xff, 0xff][..]).is_err());
+        assert_eq!("127.0.0.1:0".parse(), Ok(Address::Tcp("127.0.0.1:0".to_string())));
+        assert_eq!("unix:/tmp/a.sock".parse(), Ok(Address::Unix(PathBuf::from("/tmp/a.sock"))));
+    }
+
+    #[test]
+    fn test_tcp_sessions() {
+        let (server, path) = start("tcp");
+        let listener = Listener::bind(&"tcp:127.0.0.1:0".parse().unwrap()).unwrap();
+        let address = listener.local_address().unwrap();
+        server.listen(listener).unwrap();
+
+        let mut a = Client::connect(&address).unwrap();
+        let mut b = Client::connect(&address).unwrap();
+        a.execute("CREATE TABLE t (id INT, name TEXT)").unwrap();
+        // BEGINは接続ごと。aがコミットするまでbからは見えない
+        a.execute("BEGIN").unwrap();
+        a.execute("INSERT INTO t VALUES (1, 'a')").unwrap();
+        assert_eq!(count(&mut a, "t"), Value::UInt(1));
+        assert_eq!(count(&mut b, "t"), Value::UInt(0));
+        assert!(b.execute("COMMIT").is_err());
+        a.execute("COMMIT").unwrap();
+        assert_eq!(count(&mut b, "t"), Value::UInt(1));
+        let err = b.execute("SELECT * FROM nothing").unwrap_err();
+        assert_eq!(err.to_string(), "table nothing does not exist");
+
+        // 途中で切断した接続のトランザクションはロールバックされる
+        let mut c = Client::connect(&address).unwrap();
+        c.execute("BEGIN").unwrap();
+        c.execute("INSERT INTO t VALUES (2, 'c')").unwrap();
+        drop(c);
+
+        // 同時に書き込む
+        let handles: Vec<_> = (0..4)
+            .map(|i| {
+                let address = address.clone();
+                thread::spawn(move || {
+                    let mut client = Client::connect(&address).unwrap();
+                    for j in 0..25 {
+                        client.execute(&format!("INSERT INTO t VALUES ({}, 'x')", 100 + i * 25 + j)).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(count(&mut b, "t"), Value::UInt(101));
+        server.shutdown().unwrap();
+        assert!(b.execute("SELECT 1").is_err());
+        // 受付スレッドも止まっているので、新しく接続できない
+        assert!(Client::connect(&address).is_err());
+
+        // 書き出した内容は開き直しても残っている
+        let mut session = Session::open(&path, 32).unwrap();
+        match session.execute("SELECT count(*) FROM t").unwrap() {
+            Output::Rows { rows, .. } => assert_eq!(rows, vec![vec![Value::UInt(101)]]),
+            output => panic!("unexpected output: {:?}", output),
+        }
+    }
+
+    #[test]
+    fn test_unix_socket() {
+        let (server, _) = start("unix");
+        let socket = std::env::temp_dir().join(format!("server_test_{}.sock", std::process::id()));
+        let address = Address::Unix(socket.clone());
+        server.listen(Listener::bind(&address).unwrap()).unwrap();
+        let mut client = Client::connect(&address).unwrap();
+        client.execute("CREATE TABLE t (x INT)").unwrap();
+        assert_eq!(client.execute("INSERT INTO t VALUES (1), (2)").unwrap(), Output::Message("INSERT 2".to_string()));
+        assert_eq!(count(&mut client, "t"), Value::UInt(2));
+        server.shutdown().unwrap();
+        // ソケットのファイルも消える
+        assert!(!socket.exists());
+        assert!(Client::connect(&address).is_err());
+    }
+}
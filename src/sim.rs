@@ -0,0 +1,938 @@
+// 決定的シミュレーションテスト
+// 複数のクライアントがストレージエンジンを同時に使う状況を、1つのスレッドの上で再現する
+// 乱数は全て1つのシードから作るので、同じシードなら操作の順番もクラッシュの起き方も毎回同じになり、
+// 失敗したシードを指定し直せば、失敗するまでの操作をそのまま再現できる
+//
+// - シード付きスケジューラー：クライアントごとに次に動く時刻を乱数で決め、一番早いクライアントに1つ操作させる
+//   操作の途中で他のクライアントの操作が割り込むことはないが、スナップショットやイテレータ、
+//   pin止めしたバッファを持ったまま次の順番を待つので、操作の組み合わせは様々な順番で起きる
+// - 仮想ディスク：ファイルの中身をメモリ上に持つ。fsyncしていない書き込みはクラッシュした時に
+//   一部だけ(どれが残るかはシードで決まる)ディスクに残る
+// - 仮想時計：ディスクの読み書きやクライアントの待ち時間で進む時刻。トレースに書き、かかった時間を測る
+//
+// 使い方
+// let report = sim::run(seed, &sim::Options::default(), sim::KvWorkload::new(16, 64))?;
+// 失敗するとFailureにシードと、そこまでの操作のトレースが入る
+
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::buffer_pool::{Buffer, BufferPool, BufferPoolManager, Error};
+use crate::disk_manager::{DiskManager, PageId, StorageFile, PAGE_SIZE};
+use crate::kv::{self, Batch};
+use crate::wal::LogManager;
+
+// ディスクの操作にかかる仮想時間
+const READ_LATENCY: u64 = 20;
+const WRITE_LATENCY: u64 = 20;
+const SYNC_LATENCY: u64 = 500;
+
+// 失敗した時に表示するトレースの行数
+const TRACE_TAIL: usize = 40;
+
+// splitmix64の仕上げ。ビットを混ぜる
+fn mix(mut x: u64) -> u64 {
+    x ^= x >> 30;
+    x = x.wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x ^= x >> 27;
+    x = x.wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+// FNV-1a
+fn hash_bytes(h: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(h, |h, &b| (h ^ b as u64).wrapping_mul(0x0000_0100_0000_01b3))
+}
+
+/// シードから決まった列を返す擬似乱数(splitmix64)
+#[derive(Debug, Clone)]
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Self(seed)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        mix(self.0)
+    }
+
+    /// 0以上n未満
+    pub fn below(&mut self, n: u64) -> u64 {
+        self.next_u64() % n
+    }
+
+    /// 1/nの確率でtrue
+    pub fn one_in(&mut self, n: u64) -> bool {
+        self.below(n) == 0
+    }
+
+    pub fn bytes(&mut self, len: usize) -> Vec<u8> {
+        (0..len).map(|_| self.next_u64() as u8).collect()
+    }
+}
+
+/// 仮想時計
+/// 複製すると同じ時計を指す
+#[derive(Debug, Clone, Default)]
+pub struct Clock(Arc<AtomicU64>);
+
+impl Clock {
+    pub fn now(&self) -> u64 {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    pub fn advance(&self, ticks: u64) {
+        self.0.fetch_add(ticks, Ordering::SeqCst);
+    }
+
+    /// timeまで進める(既に過ぎていれば何もしない)
+    pub fn advance_to(&self, time: u64) {
+        self.0.fetch_max(time, Ordering::SeqCst);
+    }
+}
+
+/// 仮想ディスクの操作の回数
+#[derive(Debug, Default, Clone, Copy)]
+pub struct DiskStats {
+    pub reads: u64,
+    pub writes: u64,
+    pub syncs: u64,
+    pub crashes: u64,
+}
+
+// fsyncしていない変更
+enum Pending {
+    Write { offset: u64, data: Vec<u8> },
+    SetLen(u64),
+}
+
+#[derive(Default)]
+struct FileState {
+    // 今の中身(OSのページキャッシュに相当)
+    data: Vec<u8>,
+    // 最後にfsyncした時の中身
+    durable: Vec<u8>,
+    // 最後のfsyncから後の変更
+    pending: Vec<Pending>,
+}
+
+fn write_at(data: &mut Vec<u8>, offset: u64, bytes: &[u8]) {
+    let end = offset as usize + bytes.len();
+    if data.len() < end {
+        data.resize(end, 0);
+    }
+    data[offset as usize..end].copy_from_slice(bytes);
+}
+
+#[derive(Default)]
+struct DiskState {
+    files: BTreeMap<String, FileState>,
+    // クラッシュするたびに増やす。クラッシュ前に開いたファイルはもう使えない
+    generation: u64,
+    stats: DiskStats,
+}
+
+/// メモリ上の仮想ディスク
+/// 複製すると同じディスクを指す
+#[derive(Clone)]
+pub struct VirtualDisk {
+    state: Arc<Mutex<DiskState>>,
+    clock: Clock,
+}
+
+impl VirtualDisk {
+    pub fn new(clock: Clock) -> Self {
+        Self {
+            state: Default::default(),
+            clock,
+        }
+    }
+
+    /// ファイルを開く(無ければ作る)
+    pub fn open(&self, name: &str) -> VirtualFile {
+        let mut state = self.state.lock().unwrap();
+        state.files.entry(name.to_string()).or_default();
+        VirtualFile {
+            disk: self.clone(),
+            name: name.to_string(),
+            generation: state.generation,
+        }
+    }
+
+    /// 電源が落ちたことにする
+    /// fsyncしていない変更は、seedで決まる一部だけが残る(書き込み1回の途中で切れることはない)
+    /// 残るかどうかは変更の順番ではなく場所で決めるので、HashMapの順番などで書き出す順番が変わっても結果は同じになる
+    pub fn crash(&self, seed: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.generation += 1;
+        state.stats.crashes += 1;
+        for (name, file) in state.files.iter_mut() {
+            let mut data = std::mem::take(&mut file.durable);
+            // 同じ場所への何回目の変更か
+            let mut occurrences: BTreeMap<(bool, u64), u64> = BTreeMap::new();
+            for pending in file.pending.drain(..) {
+                let key = match &pending {
+                    Pending::Write { offset, .. } => (false, *offset),
+                    Pending::SetLen(len) => (true, *len),
+                };
+                let occurrence = occurrences.entry(key).or_insert(0);
+                *occurrence += 1;
+                let h = mix(hash_bytes(seed, name.as_bytes()) ^ mix(key.1 ^ (key.0 as u64) << 63) ^ mix(*occurrence));
+                if h & 1 == 0 {
+                    match pending {
+                        Pending::Write { offset, data: bytes } => write_at(&mut data, offset, &bytes),
+                        Pending::SetLen(len) => data.resize(len as usize, 0),
+                    }
+                }
+            }
+            file.data = data.clone();
+            file.durable = data;
+        }
+    }
+
+    pub fn stats(&self) -> DiskStats {
+        self.state.lock().unwrap().stats
+    }
+
+    /// 全てのファイルの中身のハッシュ値(同じシードで同じ結果になるかを確かめる)
+    pub fn fingerprint(&self) -> u64 {
+        let state = self.state.lock().unwrap();
+        state.files.iter().fold(0xcbf2_9ce4_8422_2325, |h, (name, file)| hash_bytes(hash_bytes(h, name.as_bytes()), &file.data))
+    }
+}
+
+/// 仮想ディスク上のファイル
+pub struct VirtualFile {
+    disk: VirtualDisk,
+    name: String,
+    generation: u64,
+}
+
+impl VirtualFile {
+    fn with_file<R>(&self, latency: u64, f: impl FnOnce(&mut FileState, &mut DiskStats) -> io::Result<R>) -> io::Result<R> {
+        let mut state = self.disk.state.lock().unwrap();
+        if state.generation != self.generation {
+            return Err(io::Error::other("virtual disk has crashed"));
+        }
+        self.disk.clock.advance(latency);
+        let DiskState { files, stats, .. } = &mut *state;
+        f(files.get_mut(&self.name).unwrap(), stats)
+    }
+}
+
+impl StorageFile for VirtualFile {
+    fn read_exact_at(&mut self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        self.with_file(READ_LATENCY, |file, stats| {
+            stats.reads += 1;
+            let bytes = file
+                .data
+                .get(offset as usize..offset as usize + buf.len())
+                .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "failed to fill whole buffer"))?;
+            buf.copy_from_slice(bytes);
+            Ok(())
+        })
+    }
+
+    fn write_all_at(&mut self, data: &[u8], offset: u64) -> io::Result<()> {
+        self.with_file(WRITE_LATENCY, |file, stats| {
+            stats.writes += 1;
+            write_at(&mut file.data, offset, data);
+            file.pending.push(Pending::Write { offset, data: data.to_vec() });
+            Ok(())
+        })
+    }
+
+    fn file_size(&self) -> io::Result<u64> {
+        self.with_file(0, |file, _| Ok(file.data.len() as u64))
+    }
+
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        self.with_file(WRITE_LATENCY, |file, _| {
+            file.data.resize(len as usize, 0);
+            file.pending.push(Pending::SetLen(len));
+            Ok(())
+        })
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.with_file(SYNC_LATENCY, |file, stats| {
+            stats.syncs += 1;
+            file.durable = file.data.clone();
+            file.pending.clear();
+            Ok(())
+        })
+    }
+}
+
+/// シミュレーションの設定
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// 同時に動くクライアントの数
+    pub clients: usize,
+    /// 操作の回数
+    pub steps: usize,
+    /// 1回の操作の前に、1/crash_one_inの確率でクラッシュさせる(0ならクラッシュさせない)
+    pub crash_one_in: u64,
+    /// クライアントが次の操作までに待つ最大の仮想時間
+    pub max_think_time: u64,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            clients: 4,
+            steps: 500,
+            crash_one_in: 100,
+            max_think_time: 100,
+        }
+    }
+}
+
+/// ワークロードから使う乱数・仮想ディスク・仮想時計
+pub struct Env {
+    pub rng: Rng,
+    pub disk: VirtualDisk,
+    pub clock: Clock,
+    trace: Vec<String>,
+}
+
+impl Env {
+    pub fn now(&self) -> u64 {
+        self.clock.now()
+    }
+
+    /// トレースに1行書く
+    pub fn trace(&mut self, message: impl fmt::Display) {
+        let line = format!("{:>8} {}", self.now(), message);
+        self.trace.push(line);
+    }
+
+    pub fn trace_lines(&self) -> &[String] {
+        &self.trace
+    }
+}
+
+/// シミュレーションで動かすワークロード
+/// エンジンの結果が期待と違ったらErrで理由を返す
+pub trait Workload {
+    /// 仮想ディスクの上でエンジンを開く(クラッシュした後にも呼ぶ)
+    fn open(&mut self, env: &mut Env) -> Result<(), String>;
+    /// clientが1つ操作する
+    fn step(&mut self, client: usize, env: &mut Env) -> Result<(), String>;
+    /// 仮想ディスクがクラッシュした後に呼ぶ。開いていたエンジンと、クライアントの状態を捨てる
+    fn crash(&mut self, env: &mut Env);
+    /// 最後に全体を確かめる
+    fn check(&mut self, env: &mut Env) -> Result<(), String>;
+}
+
+/// 成功したシミュレーションの結果
+#[derive(Debug)]
+pub struct Report {
+    pub seed: u64,
+    pub steps: usize,
+    /// 最後の仮想時刻
+    pub time: u64,
+    pub disk: DiskStats,
+    /// 最後の仮想ディスクの中身のハッシュ値
+    pub fingerprint: u64,
+    pub trace: Vec<String>,
+}
+
+/// 失敗したシミュレーション
+/// 同じシードと設定でrunし直せば、同じ所で失敗する
+#[derive(Debug)]
+pub struct Failure {
+    pub seed: u64,
+    pub step: usize,
+    pub time: u64,
+    pub message: String,
+    pub trace: Vec<String>,
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "seed {} failed at step {} (time {}): {}", self.seed, self.step, self.time, self.message)?;
+        let start = self.trace.len().saturating_sub(TRACE_TAIL);
+        if start > 0 {
+            writeln!(f, "    ... ({} lines omitted)", start)?;
+        }
+        for line in &self.trace[start..] {
+            writeln!(f, "    {}", line)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Failure {}
+
+fn panic_message(payload: &(dyn std::any::Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        format!("panicked: {}", message)
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        format!("panicked: {}", message)
+    } else {
+        "panicked".to_string()
+    }
+}
+
+/// seedでworkloadを動かす
+/// エンジンがパニックした場合も、どこまで進んでいたかをFailureで返す
+pub fn run(seed: u64, options: &Options, mut workload: impl Workload) -> Result<Report, Failure> {
+    let clock = Clock::default();
+    let mut env = Env {
+        rng: Rng::new(seed),
+        disk: VirtualDisk::new(clock.clone()),
+        clock,
+        trace: vec![],
+    };
+    let mut step = 0;
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        workload.open(&mut env)?;
+        // クライアントが次に動く時刻
+        let mut wake: Vec<u64> = (0..options.clients).map(|_| env.rng.below(options.max_think_time + 1)).collect();
+        while step < options.steps {
+            if options.crash_one_in > 0 && env.rng.one_in(options.crash_one_in) {
+                env.trace("crash");
+                let crash_seed = env.rng.next_u64();
+                env.disk.crash(crash_seed);
+                workload.crash(&mut env);
+                workload.open(&mut env)?;
+            }
+            // 一番早く起きるクライアント(同じ時刻なら番号の小さい方)
+            let client = (0..options.clients).min_by_key(|&client| (wake[client], client)).unwrap();
+            env.clock.advance_to(wake[client]);
+            workload.step(client, &mut env)?;
+            wake[client] = env.now() + 1 + env.rng.below(options.max_think_time);
+            step += 1;
+        }
+        workload.check(&mut env)
+    }));
+    let message = match result {
+        Ok(Ok(())) => {
+            return Ok(Report {
+                seed,
+                steps: step,
+                time: env.now(),
+                disk: env.disk.stats(),
+                fingerprint: env.disk.fingerprint(),
+                trace: env.trace,
+            })
+        }
+        Ok(Err(message)) => message,
+        Err(payload) => panic_message(&*payload),
+    };
+    Err(Failure {
+        seed,
+        step,
+        time: env.now(),
+        message,
+        trace: env.trace,
+    })
+}
+
+// バッファプールに書くページの中身
+// | ページID(u64) | 書き込みの番号(u64) | ページIDと番号で決まるバイト ... |
+// 番号はワークロード全体で書くたびに増やすので、解放して再利用したページの前の中身とも区別できる
+fn stamped_page(page_id: PageId, stamp: u64) -> Vec<u8> {
+    let mut page = vec![mix(page_id.to_u64() ^ mix(stamp)) as u8; PAGE_SIZE];
+    page[..8].copy_from_slice(&page_id.to_u64().to_le_bytes());
+    page[8..16].copy_from_slice(&stamp.to_le_bytes());
+    page
+}
+
+// バッファプールのページの状態
+#[derive(Debug, Clone, Copy)]
+struct PageState {
+    // ページを作ってから最初に書いた番号
+    first: u64,
+    // 最後に書いた番号
+    stamp: u64,
+    // 最後にfsyncした時の番号(まだならNone)
+    synced: Option<u64>,
+}
+
+/// バッファプールのワークロード
+/// 小さなバッファプールで、クライアントがページをpin止めしたまま作成・読み込み・書き換え・解放を繰り返し、
+/// ページの追い出し(BufferPool::evict)と書き戻しで中身が壊れないかを確かめる
+/// - 読んだページの中身は、最後に書いた中身と同じ
+/// - pin止めしたバッファが他のページに使われない
+/// - NoFreeBufferになるのは、全てのバッファがpin止めされている時だけ
+/// - クラッシュした後、ページの中身は最後にfsyncした時から最後に書いた時までのいずれか
+pub struct BufferPoolWorkload {
+    pool_size: usize,
+    bufmgr: Option<BufferPoolManager>,
+    pages: BTreeMap<PageId, PageState>,
+    // 次に書く番号
+    next_stamp: u64,
+    // クライアントごとにpin止めしているバッファ
+    pinned: Vec<Vec<Rc<Buffer>>>,
+}
+
+impl BufferPoolWorkload {
+    pub fn new(pool_size: usize) -> Self {
+        Self {
+            pool_size,
+            bufmgr: None,
+            pages: BTreeMap::new(),
+            next_stamp: 1,
+            pinned: vec![],
+        }
+    }
+
+    // NoFreeBufferが正しいか
+    fn check_no_free_buffer(&self) -> Result<(), String> {
+        let mut buffers: Vec<*const Buffer> = self.pinned.iter().flatten().map(Rc::as_ptr).collect();
+        buffers.sort();
+        buffers.dedup();
+        if buffers.len() < self.pool_size {
+            return Err(format!("NoFreeBuffer while only {} of {} buffers are pinned", buffers.len(), self.pool_size));
+        }
+        Ok(())
+    }
+
+    fn check_page(&self, buffer: &Buffer) -> Result<(), String> {
+        let state = self.pages[&buffer.page_id];
+        if buffer.page.borrow()[..] != stamped_page(buffer.page_id, state.stamp)[..] {
+            return Err(format!("page {:?} does not contain write #{}", buffer.page_id, state.stamp));
+        }
+        Ok(())
+    }
+
+    fn write_page(&mut self, buffer: &Buffer) {
+        let state = self.pages.get_mut(&buffer.page_id).unwrap();
+        state.stamp = self.next_stamp;
+        self.next_stamp += 1;
+        buffer.page.borrow_mut().copy_from_slice(&stamped_page(buffer.page_id, state.stamp));
+        buffer.is_dirty.set(true);
+    }
+}
+
+impl Workload for BufferPoolWorkload {
+    fn open(&mut self, env: &mut Env) -> Result<(), String> {
+        let disk = DiskManager::with_storage(Box::new(env.disk.open("heap"))).map_err(|err| err.to_string())?;
+        let num_pages = disk.num_pages();
+        let mut bufmgr = BufferPoolManager::new(disk, BufferPool::new(self.pool_size));
+        // クラッシュした後なら、残っている中身を確かめて、それを期待する中身にする
+        // fsyncした後の書き込みは、追い出しで書き戻されていれば残り、そうでなければ失われる
+        let mut pages = BTreeMap::new();
+        for (&page_id, state) in &self.pages {
+            let mut found = None;
+            if page_id.to_u64() < num_pages {
+                let buffer = bufmgr.fetch_page(page_id).map_err(|err| err.to_string())?;
+                let page = buffer.page.borrow();
+                let mut bytes = [0u8; 8];
+                bytes.copy_from_slice(&page[8..16]);
+                let stamp = u64::from_le_bytes(bytes);
+                if page[..] == stamped_page(page_id, stamp)[..] && state.synced.unwrap_or(state.first) <= stamp && stamp <= state.stamp {
+                    found = Some(stamp);
+                }
+            }
+            match found {
+                Some(stamp) => {
+                    pages.insert(page_id, PageState { first: stamp, stamp, synced: Some(stamp) });
+                }
+                None if state.synced.is_some() => {
+                    return Err(format!("page {:?} lost write #{:?} after fsync", page_id, state.synced));
+                }
+                // 一度もfsyncしていないページは、0のままか前に使われていた時の中身でもよい(もう使わない)
+                None => {}
+            }
+        }
+        env.trace(format!("open: {} pages", pages.len()));
+        self.pages = pages;
+        self.bufmgr = Some(bufmgr);
+        Ok(())
+    }
+
+    fn step(&mut self, client: usize, env: &mut Env) -> Result<(), String> {
+        if self.pinned.len() <= client {
+            self.pinned.resize_with(client + 1, Vec::new);
+        }
+        let full = self.pinned[client].len() >= 2;
+        match env.rng.below(10) {
+            0..=1 if !full => {
+                let result = self.bufmgr.as_mut().unwrap().create_page();
+                match result {
+                    Ok(buffer) => {
+                        env.trace(format!("client {}: create {:?}", client, buffer.page_id));
+                        let stamp = self.next_stamp;
+                        self.pages.insert(buffer.page_id, PageState { first: stamp, stamp, synced: None });
+                        self.write_page(&buffer);
+                        self.pinned[client].push(buffer);
+                    }
+                    Err(Error::NoFreeBuffer) => {
+                        env.trace(format!("client {}: create -> no free buffer", client));
+                        self.check_no_free_buffer()?;
+                    }
+                    Err(err) => return Err(err.to_string()),
+                }
+            }
+            2..=4 if !full && !self.pages.is_empty() => {
+                let page_id = *self.pages.keys().nth(env.rng.below(self.pages.len() as u64) as usize).unwrap();
+                let result = self.bufmgr.as_mut().unwrap().fetch_page(page_id);
+                match result {
+                    Ok(buffer) => {
+                        env.trace(format!("client {}: fetch {:?}", client, page_id));
+                        if buffer.page_id != page_id {
+                            return Err(format!("fetched {:?} but got {:?}", page_id, buffer.page_id));
+                        }
+                        self.check_page(&buffer)?;
+                        self.pinned[client].push(buffer);
+                    }
+                    Err(Error::NoFreeBuffer) => {
+                        env.trace(format!("client {}: fetch {:?} -> no free buffer", client, page_id));
+                        self.check_no_free_buffer()?;
+                    }
+                    Err(err) => return Err(err.to_string()),
+                }
+            }
+            5..=6 if !self.pinned[client].is_empty() => {
+                let i = env.rng.below(self.pinned[client].len() as u64) as usize;
+                let buffer = Rc::clone(&self.pinned[client][i]);
+                env.trace(format!("client {}: write {:?}", client, buffer.page_id));
+                self.write_page(&buffer);
+            }
+            7 => {
+                // どのクライアントもpin止めしていないページを解放する
+                let page_id = self.pages.keys().copied().find(|page_id| self.pinned.iter().flatten().all(|buffer| buffer.page_id != *page_id));
+                if let Some(page_id) = page_id {
+                    env.trace(format!("client {}: free {:?}", client, page_id));
+                    self.pages.remove(&page_id);
+                    self.bufmgr.as_mut().unwrap().free_page(page_id);
+                }
+            }
+            8 => {
+                env.trace(format!("client {}: flush", client));
+                self.bufmgr.as_mut().unwrap().flush().map_err(|err| err.to_string())?;
+                for state in self.pages.values_mut() {
+                    state.synced = Some(state.stamp);
+                }
+            }
+            _ => {
+                if !self.pinned[client].is_empty() {
+                    let i = env.rng.below(self.pinned[client].len() as u64) as usize;
+                    let buffer = self.pinned[client].swap_remove(i);
+                    env.trace(format!("client {}: unpin {:?}", client, buffer.page_id));
+                }
+            }
+        }
+        // pin止めしているバッファは、他の操作の後も同じページのまま
+        for buffer in self.pinned.iter().flatten() {
+            self.check_page(buffer)?;
+        }
+        Ok(())
+    }
+
+    fn crash(&mut self, _env: &mut Env) {
+        self.pinned.clear();
+        self.bufmgr = None;
+    }
+
+    fn check(&mut self, env: &mut Env) -> Result<(), String> {
+        self.pinned.clear();
+        let page_ids: Vec<PageId> = self.pages.keys().copied().collect();
+        for page_id in page_ids {
+            let buffer = self.bufmgr.as_mut().unwrap().fetch_page(page_id).map_err(|err| err.to_string())?;
+            self.check_page(&buffer)?;
+        }
+        env.trace(format!("check: {} pages", self.pages.len()));
+        Ok(())
+    }
+}
+
+type Model = BTreeMap<Vec<u8>, Vec<u8>>;
+type Pairs = VecDeque<(Vec<u8>, Vec<u8>)>;
+
+#[derive(Default)]
+struct KvClient {
+    // 取ったスナップショットと、その時の内容
+    snapshot: Option<(kv::Snapshot, Model)>,
+    // 読みかけのイテレータと、まだ返していないキーと値
+    iter: Option<(kv::Iter, Pairs)>,
+}
+
+/// キーバリューストア(kv::Db)のワークロード
+/// クライアントが書き込み・読み込み・スナップショット・イテレータを混ぜて使い、BTreeMapで作ったモデルと比べる
+/// - 読んだ結果はモデルと同じ(スナップショットとイテレータは、取った時のモデルと同じ)
+/// - コミットした書き込みは、クラッシュした後も全て残っている
+pub struct KvWorkload {
+    pool_size: usize,
+    key_count: u64,
+    db: Option<kv::Db>,
+    model: Model,
+    clients: Vec<KvClient>,
+}
+
+impl KvWorkload {
+    /// pool_sizeページのバッファプールで、key_count個のキーを使う
+    pub fn new(pool_size: usize, key_count: u64) -> Self {
+        Self {
+            pool_size,
+            key_count,
+            db: None,
+            model: BTreeMap::new(),
+            clients: vec![],
+        }
+    }
+
+    fn random_key(&self, rng: &mut Rng) -> Vec<u8> {
+        format!("key{:04}", rng.below(self.key_count)).into_bytes()
+    }
+
+    // たまにオーバーフローページを使う大きな値にする
+    fn random_value(rng: &mut Rng) -> Vec<u8> {
+        let len = if rng.one_in(8) { 1000 + rng.below(3000) } else { rng.below(64) };
+        rng.bytes(len as usize)
+    }
+
+    fn db(&self) -> &kv::Db {
+        self.db.as_ref().unwrap()
+    }
+
+    // 全てのキーと値がモデルと同じか
+    fn check_all(&self) -> Result<(), String> {
+        let pairs = self.db().iter().collect::<Result<Vec<_>, _>>().map_err(|err| err.to_string())?;
+        let expected: Vec<_> = self.model.iter().map(|(key, value)| (key.clone(), value.clone())).collect();
+        if pairs != expected {
+            return Err(format!("store has {} keys but the model has {}", pairs.len(), expected.len()));
+        }
+        Ok(())
+    }
+}
+
+fn show(value: &Option<Vec<u8>>) -> String {
+    match value {
+        Some(value) => format!("{} bytes", value.len()),
+        None => "None".to_string(),
+    }
+}
+
+fn expect_value(what: &str, key: &[u8], actual: Option<Vec<u8>>, expected: Option<&Vec<u8>>) -> Result<(), String> {
+    if actual.as_ref() != expected {
+        return Err(format!("{} {}: got {} but expected {}", what, String::from_utf8_lossy(key), show(&actual), show(&expected.cloned())));
+    }
+    Ok(())
+}
+
+impl Workload for KvWorkload {
+    fn open(&mut self, env: &mut Env) -> Result<(), String> {
+        let disk = DiskManager::with_storage(Box::new(env.disk.open("kv.db"))).map_err(|err| err.to_string())?;
+        let wal = LogManager::with_storage(Box::new(env.disk.open("kv.db.wal"))).map_err(|err| err.to_string())?;
+        self.db = Some(kv::Db::open_with(disk, wal, self.pool_size).map_err(|err| err.to_string())?);
+        env.trace(format!("open: {} keys", self.model.len()));
+        // クラッシュする前にコミットした書き込みが全て残っている
+        self.check_all()
+    }
+
+    fn step(&mut self, client: usize, env: &mut Env) -> Result<(), String> {
+        if self.clients.len() <= client {
+            self.clients.resize_with(client + 1, KvClient::default);
+        }
+        let key = self.random_key(&mut env.rng);
+        let name = String::from_utf8_lossy(&key).into_owned();
+        match env.rng.below(10) {
+            0..=2 => {
+                let value = Self::random_value(&mut env.rng);
+                env.trace(format!("client {}: put {} ({} bytes)", client, name, value.len()));
+                let previous = self.db().put(&key, &value).map_err(|err| err.to_string())?;
+                expect_value("previous value of", &key, previous, self.model.get(&key))?;
+                self.model.insert(key, value);
+            }
+            3 => {
+                env.trace(format!("client {}: delete {}", client, name));
+                let previous = self.db().delete(&key).map_err(|err| err.to_string())?;
+                expect_value("previous value of", &key, previous, self.model.get(&key))?;
+                self.model.remove(&key);
+            }
+            4 => {
+                let mut batch = Batch::default();
+                let mut writes = vec![];
+                for _ in 0..1 + env.rng.below(4) {
+                    let key = self.random_key(&mut env.rng);
+                    if env.rng.one_in(3) {
+                        batch.delete(&key);
+                        writes.push((key, None));
+                    } else {
+                        let value = Self::random_value(&mut env.rng);
+                        batch.put(&key, &value);
+                        writes.push((key, Some(value)));
+                    }
+                }
+                env.trace(format!("client {}: batch of {} writes", client, writes.len()));
+                self.db().apply_batch(batch).map_err(|err| err.to_string())?;
+                for (key, value) in writes {
+                    match value {
+                        Some(value) => self.model.insert(key, value),
+                        None => self.model.remove(&key),
+                    };
+                }
+            }
+            5 => {
+                env.trace(format!("client {}: get {}", client, name));
+                let value = self.db().get(&key).map_err(|err| err.to_string())?;
+                expect_value("get", &key, value, self.model.get(&key))?;
+            }
+            6 => {
+                let snapshot = &mut self.clients[client].snapshot;
+                match snapshot.take() {
+                    Some(_) => env.trace(format!("client {}: release snapshot", client)),
+                    None => {
+                        env.trace(format!("client {}: take snapshot", client));
+                        *snapshot = Some((self.db.as_ref().unwrap().snapshot(), self.model.clone()));
+                    }
+                }
+            }
+            7 => {
+                if let Some((snapshot, model)) = &self.clients[client].snapshot {
+                    env.trace(format!("client {}: get {} from snapshot", client, name));
+                    let value = snapshot.get(&key).map_err(|err| err.to_string())?;
+                    expect_value("snapshot get", &key, value, model.get(&key))?;
+                }
+            }
+            8 => match self.clients[client].iter.as_mut() {
+                Some((iter, expected)) => {
+                    let count = 1 + env.rng.below(20);
+                    env.trace(format!("client {}: advance iterator by {}", client, count));
+                    for _ in 0..count {
+                        let pair = iter.next().transpose().map_err(|err| err.to_string())?;
+                        let next = expected.pop_front();
+                        if pair != next {
+                            return Err(format!("iterator returned {:?} but expected {:?}", pair.map(|pair| pair.0), next.map(|pair| pair.0)));
+                        }
+                        if pair.is_none() {
+                            self.clients[client].iter = None;
+                            break;
+                        }
+                    }
+                }
+                None => {
+                    let end = self.random_key(&mut env.rng);
+                    let (start, end) = if key <= end { (key, end) } else { (end, key) };
+                    env.trace(format!("client {}: iterate {}..{}", client, String::from_utf8_lossy(&start), String::from_utf8_lossy(&end)));
+                    let iter = self.db().range(start.clone()..end.clone());
+                    let expected = self.model.range(start..end).map(|(key, value)| (key.clone(), value.clone())).collect();
+                    self.clients[client].iter = Some((iter, expected));
+                }
+            },
+            _ => {
+                let prefix = key[..key.len() - 1].to_vec();
+                env.trace(format!("client {}: scan prefix {}", client, String::from_utf8_lossy(&prefix)));
+                let keys = self.db().scan_prefix(&prefix).map(|pair| pair.map(|(key, _)| key)).collect::<Result<Vec<_>, _>>().map_err(|err| err.to_string())?;
+                let expected: Vec<_> = self.model.keys().filter(|key| key.starts_with(&prefix)).cloned().collect();
+                if keys != expected {
+                    return Err(format!("scan prefix {}: got {} keys but expected {}", String::from_utf8_lossy(&prefix), keys.len(), expected.len()));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn crash(&mut self, _env: &mut Env) {
+        // スナップショットとイテレータがDbを参照しているので、先に捨てる
+        self.clients.clear();
+        self.db = None;
+    }
+
+    fn check(&mut self, env: &mut Env) -> Result<(), String> {
+        self.clients.clear();
+        self.check_all()?;
+        // 閉じて開き直しても同じ
+        self.db = None;
+        self.open(env)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_virtual_disk_crash() {
+        let disk = VirtualDisk::new(Clock::default());
+        let mut file = disk.open("file");
+        file.write_all_at(b"hello", 0).unwrap();
+        file.sync().unwrap();
+        for i in 0..64 {
+            file.write_all_at(&[i as u8], 5 + i).unwrap();
+        }
+        disk.crash(1);
+        // クラッシュ前に開いたファイルはもう使えない
+        assert!(file.sync().is_err());
+        let mut file = disk.open("file");
+        let mut bytes = vec![0u8; file.file_size().unwrap() as usize];
+        file.read_exact_at(&mut bytes, 0).unwrap();
+        // fsyncした内容は残り、しなかった書き込みは一部だけ残る
+        assert_eq!(&bytes[..5], b"hello");
+        let kept = (0..bytes.len() - 5).filter(|&i| bytes[5 + i] == i as u8).count();
+        assert!(0 < kept && kept < 64, "{}", kept);
+        assert_eq!(disk.stats().crashes, 1);
+    }
+
+    #[test]
+    fn test_replay_seed() {
+        // 同じシードなら、操作もディスクの中身も同じになる
+        let options = Options { steps: 200, crash_one_in: 30, ..Options::default() };
+        let first = run(7, &options, KvWorkload::new(16, 32)).unwrap();
+        let second = run(7, &options, KvWorkload::new(16, 32)).unwrap();
+        assert_eq!(first.trace, second.trace);
+        assert_eq!(first.time, second.time);
+        assert_eq!(first.fingerprint, second.fingerprint);
+        assert!(first.disk.crashes > 0);
+        let other = run(8, &options, KvWorkload::new(16, 32)).unwrap();
+        assert_ne!(first.trace, other.trace);
+    }
+
+    #[test]
+    fn test_buffer_pool_workload() {
+        let options = Options { steps: 400, crash_one_in: 50, ..Options::default() };
+        for seed in 0..20 {
+            if let Err(failure) = run(seed, &options, BufferPoolWorkload::new(6)) {
+                panic!("{}", failure);
+            }
+        }
+    }
+
+    #[test]
+    fn test_kv_workload() {
+        let options = Options { steps: 300, crash_one_in: 60, ..Options::default() };
+        for seed in 0..10 {
+            if let Err(failure) = run(seed, &options, KvWorkload::new(16, 64)) {
+                panic!("{}", failure);
+            }
+        }
+    }
+
+    #[test]
+    fn test_failure_reports_seed() {
+        // わざと間違えるワークロードで、失敗した所とトレースが返ることを確かめる
+        struct Broken;
+        impl Workload for Broken {
+            fn open(&mut self, _env: &mut Env) -> Result<(), String> {
+                Ok(())
+            }
+            fn step(&mut self, client: usize, env: &mut Env) -> Result<(), String> {
+                env.trace(format!("client {}", client));
+                if env.rng.one_in(50) {
+                    return Err("broken".to_string());
+                }
+                Ok(())
+            }
+            fn crash(&mut self, _env: &mut Env) {}
+            fn check(&mut self, _env: &mut Env) -> Result<(), String> {
+                Ok(())
+            }
+        }
+        let failure = run(3, &Options::default(), Broken).unwrap_err();
+        let again = run(3, &Options::default(), Broken).unwrap_err();
+        assert_eq!(failure.message, "broken");
+        assert_eq!((failure.step, failure.time, &failure.trace), (again.step, again.time, &again.trace));
+        assert!(failure.to_string().starts_with("seed 3 failed at step"));
+    }
+}
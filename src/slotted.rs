@@ -0,0 +1,326 @@
+// スロット付きページ(slotted page)
+// 可変長のレコードを1ページに詰め込むためのレイアウト
+//
+// | ヘッダー | スロット配列 → |    空き領域    | ← レコード |
+//
+// - ヘッダー：スロット数(u16)と、レコード領域の先頭(u16)
+// - スロット：レコード領域のオフセット(u16)と確保したサイズ(u16)
+// - レコード領域：| レコードの長さ(u16) | レコード | (確保したサイズより短いこともある)
+// - レコードはページの末尾から先頭に向かって詰めていく
+// レコードIDにはスロット番号を使うので、レコードを移動してもスロット番号は変わらない
+//
+// 削除したレコードは「削除済み」の印を付けるだけで、領域はpurgeするまで確保したままにする
+// こうしておくと、コミット前の削除をロールバックする際に必ず元に戻せる
+//
+// 中身が全て0のページは、レコードが1つも無いページとして扱える
+
+const HEADER_SIZE: usize = 4;
+const SLOT_SIZE: usize = 4;
+const LEN_SIZE: usize = 2;
+// purge済み(空き)スロットのオフセット
+// レコードはヘッダーより後ろにしか置かないので、0が本物のオフセットになることはない
+const FREE: u16 = 0;
+// オフセットの最上位ビットが立っていれば削除済み
+// ページサイズは4096なので、オフセットに最上位ビットは使わない
+const DELETED_FLAG: u16 = 0x8000;
+
+/// 中身のサイズがbody_lenのページに入る最大のレコードサイズ
+pub fn max_record_size(body_len: usize) -> usize {
+    body_len - HEADER_SIZE - SLOT_SIZE - LEN_SIZE
+}
+
+/// 長さlenのレコードを追加するのに必要な空き領域(スロットを新しく作る場合も含む)
+/// reclaimable_space()がこれ以上あれば、insertは必ず成功する
+pub fn required_space(len: usize) -> usize {
+    len + LEN_SIZE + SLOT_SIZE
+}
+
+pub struct Slotted<B> {
+    body: B,
+}
+
+// 読み取りだけならAsRef<[u8]>、書き込むならAsMut<[u8]>も実装した型で包む
+// &[u8]でも&mut [u8]でもVec<u8>でも使える
+impl<B: AsRef<[u8]>> Slotted<B> {
+    pub fn new(body: B) -> Self {
+        Self { body }
+    }
+
+    fn bytes(&self) -> &[u8] {
+        self.body.as_ref()
+    }
+
+    fn read_u16(&self, offset: usize) -> u16 {
+        let bytes = self.bytes();
+        u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+    }
+
+    pub fn num_slots(&self) -> u16 {
+        self.read_u16(0)
+    }
+
+    // レコード領域の先頭(0ならまだ何も入っていない)
+    fn free_end(&self) -> usize {
+        match self.read_u16(2) {
+            0 => self.bytes().len(),
+            n => n as usize,
+        }
+    }
+
+    // (オフセット(フラグ付き), 確保したサイズ)
+    fn slot(&self, slot: u16) -> (u16, u16) {
+        let offset = HEADER_SIZE + slot as usize * SLOT_SIZE;
+        (self.read_u16(offset), self.read_u16(offset + 2))
+    }
+
+    // 削除済みのレコードも含めて、スロットのレコードを返す
+    fn record(&self, slot: u16) -> Option<&[u8]> {
+        if slot >= self.num_slots() {
+            return None;
+        }
+        let (offset, _) = self.slot(slot);
+        if offset == FREE {
+            return None;
+        }
+        let offset = (offset & !DELETED_FLAG) as usize;
+        let len = self.read_u16(offset) as usize;
+        Some(&self.bytes()[offset + LEN_SIZE..offset + LEN_SIZE + len])
+    }
+
+    /// スロット配列とレコード領域の間の、連続した空き領域のサイズ
+    pub fn free_space(&self) -> usize {
+        self.free_end() - HEADER_SIZE - self.num_slots() as usize * SLOT_SIZE
+    }
+
+    /// 詰め直した場合に使える空き領域のサイズ
+    /// 削除済み(purgeしていない)レコードの領域は含まない
+    pub fn reclaimable_space(&self) -> usize {
+        let used: usize = (0..self.num_slots())
+            .filter(|&slot| self.slot(slot).0 != FREE)
+            .map(|slot| self.slot(slot).1 as usize)
+            .sum();
+        self.bytes().len() - HEADER_SIZE - self.num_slots() as usize * SLOT_SIZE - used
+    }
+
+    /// 1ページに入る最大のレコードサイズ
+    pub fn capacity(&self) -> usize {
+        max_record_size(self.bytes().len())
+    }
+
+    /// スロットのレコードを返す。削除済みならNone
+    pub fn get(&self, slot: u16) -> Option<&[u8]> {
+        if self.is_deleted(slot) {
+            return None;
+        }
+        self.record(slot)
+    }
+
+    /// 削除済み(purgeはしていない)かどうか
+    pub fn is_deleted(&self, slot: u16) -> bool {
+        slot < self.num_slots() && self.slot(slot).0 & DELETED_FLAG != 0
+    }
+
+    /// 生きているレコードの(スロット番号, レコード)を順番に返す
+    pub fn iter(&self) -> impl Iterator<Item = (u16, &[u8])> + '_ {
+        (0..self.num_slots()).filter_map(move |slot| self.get(slot).map(|data| (slot, data)))
+    }
+}
+
+impl<B: AsRef<[u8]> + AsMut<[u8]>> Slotted<B> {
+    fn write_u16(&mut self, offset: usize, n: u16) {
+        self.body.as_mut()[offset..offset + 2].copy_from_slice(&n.to_le_bytes());
+    }
+
+    fn set_slot(&mut self, slot: u16, offset: u16, size: u16) {
+        let pos = HEADER_SIZE + slot as usize * SLOT_SIZE;
+        self.write_u16(pos, offset);
+        self.write_u16(pos + 2, size);
+    }
+
+    // sizeバイトのレコード領域を確保してオフセットを返す
+    // 連続した空き領域が足りなければ詰め直し、それでも足りなければNone
+    fn allocate(&mut self, size: usize, extra: usize) -> Option<u16> {
+        if self.free_space() < size + extra {
+            if self.reclaimable_space() < size + extra {
+                return None;
+            }
+            self.compact();
+        }
+        let offset = self.free_end() - size;
+        self.write_u16(2, offset as u16);
+        Some(offset as u16)
+    }
+
+    fn write_record(&mut self, offset: u16, data: &[u8]) {
+        let offset = offset as usize;
+        self.write_u16(offset, data.len() as u16);
+        self.body.as_mut()[offset + LEN_SIZE..offset + LEN_SIZE + data.len()].copy_from_slice(data);
+    }
+
+    /// レコードを追加してスロット番号を返す。空きが無ければNone
+    /// purge済みのスロットがあれば再利用する
+    pub fn insert(&mut self, data: &[u8]) -> Option<u16> {
+        let size = data.len() + LEN_SIZE;
+        let reuse = (0..self.num_slots()).find(|&slot| self.slot(slot).0 == FREE);
+        let extra = if reuse.is_some() { 0 } else { SLOT_SIZE };
+        // スロット配列を伸ばす分も含めて空きがあるか確認してから確保する
+        let offset = self.allocate(size, extra)?;
+        let slot = match reuse {
+            Some(slot) => slot,
+            None => {
+                let slot = self.num_slots();
+                self.write_u16(0, slot + 1);
+                slot
+            }
+        };
+        self.set_slot(slot, offset, size as u16);
+        self.write_record(offset, data);
+        Some(slot)
+    }
+
+    /// レコードに削除済みの印を付ける。既に削除済みならfalse
+    /// 領域はpurgeするまで確保したまま
+    pub fn delete(&mut self, slot: u16) -> bool {
+        if self.get(slot).is_none() {
+            return false;
+        }
+        let (offset, size) = self.slot(slot);
+        self.set_slot(slot, offset | DELETED_FLAG, size);
+        true
+    }
+
+    /// 削除済みの印を外す。削除済みでなければfalse
+    pub fn undelete(&mut self, slot: u16) -> bool {
+        if !self.is_deleted(slot) {
+            return false;
+        }
+        let (offset, size) = self.slot(slot);
+        self.set_slot(slot, offset & !DELETED_FLAG, size);
+        true
+    }
+
+    /// 削除済みのレコードの領域を解放し、スロットを再利用できるようにする
+    pub fn purge(&mut self, slot: u16) -> bool {
+        if !self.is_deleted(slot) {
+            return false;
+        }
+        self.set_slot(slot, FREE, 0);
+        true
+    }
+
+    /// レコードを書き換える
+    /// 確保したサイズに収まらなければページ内に領域を確保し直し、それも無理なら何もせずfalse
+    /// 短く書き換えても確保したサイズはそのままなので、元の長さに戻す書き換えは必ず成功する
+    pub fn update(&mut self, slot: u16, data: &[u8]) -> bool {
+        if self.get(slot).is_none() {
+            return false;
+        }
+        let (offset, size) = self.slot(slot);
+        if data.len() + LEN_SIZE <= size as usize {
+            self.write_record(offset, data);
+            return true;
+        }
+        // 元の領域はどのスロットからも指されなくなるので、次に詰め直すときに回収される
+        let size = data.len() + LEN_SIZE;
+        match self.allocate(size, 0) {
+            Some(offset) => {
+                self.set_slot(slot, offset, size as u16);
+                self.write_record(offset, data);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// レコード(削除済みも含む)をページの末尾に詰め直し、purgeした領域をまとめる
+    /// スロット番号は変わらない
+    pub fn compact(&mut self) {
+        let records: Vec<(u16, u16, Vec<u8>)> = (0..self.num_slots())
+            .filter(|&slot| self.slot(slot).0 != FREE)
+            .map(|slot| {
+                let (offset, size) = self.slot(slot);
+                let start = (offset & !DELETED_FLAG) as usize;
+                (slot, offset & DELETED_FLAG, self.bytes()[start..start + size as usize].to_vec())
+            })
+            .collect();
+        self.write_u16(2, 0);
+        for (slot, flag, bytes) in records {
+            let offset = self.free_end() - bytes.len();
+            self.body.as_mut()[offset..offset + bytes.len()].copy_from_slice(&bytes);
+            self.write_u16(2, offset as u16);
+            self.set_slot(slot, offset as u16 | flag, bytes.len() as u16);
+        }
+    }
+
+    /// 末尾の空きスロットを取り除く
+    pub fn truncate_slots(&mut self) {
+        let mut num_slots = self.num_slots();
+        while num_slots > 0 && self.slot(num_slots - 1).0 == FREE {
+            num_slots -= 1;
+        }
+        self.write_u16(0, num_slots);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_insert_get_delete() {
+        let mut page = Slotted::new(vec![0u8; 128]);
+        let a = page.insert(b"hello").unwrap();
+        let b = page.insert(b"world!").unwrap();
+        assert_eq!(page.get(a), Some(&b"hello"[..]));
+        assert_eq!(page.get(b), Some(&b"world!"[..]));
+        assert!(page.delete(a));
+        assert_eq!(page.get(a), None);
+        assert!(page.undelete(a));
+        assert_eq!(page.get(a), Some(&b"hello"[..]));
+        // purgeしたスロットは再利用される
+        page.delete(a);
+        page.purge(a);
+        assert_eq!(page.insert(b"again").unwrap(), a);
+        assert_eq!(page.iter().count(), 2);
+    }
+
+    #[test]
+    fn test_full_page_and_compact() {
+        let mut page = Slotted::new(vec![0u8; 64]);
+        let mut slots = vec![];
+        while let Some(slot) = page.insert(&[7u8; 10]) {
+            slots.push(slot);
+        }
+        assert_eq!(slots.len(), 3);
+        // 削除しただけでは領域は空かない
+        page.delete(slots[1]);
+        assert_eq!(page.insert(&[1u8; 10]), None);
+        // purgeすれば詰め直して使える
+        page.purge(slots[1]);
+        page.delete(slots[0]);
+        assert_eq!(page.insert(&[1u8; 10]), Some(slots[1]));
+        assert!(page.undelete(slots[0]));
+        assert_eq!(page.get(slots[0]), Some(&[7u8; 10][..]));
+        assert_eq!(page.get(slots[2]), Some(&[7u8; 10][..]));
+        // 短くした後は元の長さまで戻せる
+        assert!(page.update(slots[2], &[3u8; 2]));
+        assert!(!page.update(slots[2], &[3u8; 11]));
+        assert!(page.update(slots[2], &[3u8; 10]));
+    }
+
+    #[test]
+    fn test_update_grow() {
+        let mut page = Slotted::new(vec![0u8; 64]);
+        let a = page.insert(&[1u8; 4]).unwrap();
+        let b = page.insert(&[2u8; 4]).unwrap();
+        // 確保したサイズより長くしても、ページに空きがあれば同じスロットのまま書き換えられる
+        assert!(page.update(a, &[3u8; 20]));
+        assert_eq!(page.get(a), Some(&[3u8; 20][..]));
+        assert_eq!(page.get(b), Some(&[2u8; 4][..]));
+        // 置き去りにした元の領域も詰め直せば使える
+        assert!(page.update(b, &[4u8; 22]));
+        assert_eq!(page.get(a), Some(&[3u8; 20][..]));
+        assert_eq!(page.get(b), Some(&[4u8; 22][..]));
+        assert!(!page.update(b, &[4u8; 23]));
+    }
+}
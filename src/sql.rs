@@ -0,0 +1,1550 @@
+// SQLのフロントエンド
+// 文字列 → トークン列(字句解析) → 構文木(構文解析) → 実行計画(PlanNode) → 実行
+//
+// 対応しているSQL
+// - CREATE TABLE t (列名 型, ...)    型: INT, UINT, FLOAT, TEXT, BLOB
+// - CREATE [UNIQUE] INDEX i ON t [USING BTREE|HASH] (列名, ...)    HASHは全ての列の等号での検索にだけ使える
+// - DROP TABLE t / DROP INDEX i
+// - INSERT INTO t [(列名, ...)] VALUES (式, ...), ...
+// - SELECT 式 [AS 別名], ... FROM t [別名] [[INNER] JOIN t2 [別名] ON 条件] ...
+//     [WHERE 条件] [GROUP BY 式, ...] [ORDER BY 式 [ASC|DESC], ...] [LIMIT n [OFFSET m]]
+//   条件: =, <>, <, <=, >, >=, AND, OR, NOT, IS [NOT] NULL, [NOT] BETWEEN a AND b
+//   集約関数: COUNT(*), COUNT, SUM, MIN, MAX, AVG
+// - UPDATE t SET 列名 = 式, ... [WHERE 条件]
+// - DELETE FROM t [WHERE 条件]
+// - BEGIN / COMMIT / ROLLBACK
+// - COPY t FROM 'path' / COPY t TO 'path'：CSVファイル(1行目は列名)を読み込む/書き出す
+//   読み込みはバルクロードで行い、ファイルに無い列はNULLにする
+// - ANALYZE [t]：統計情報を集めてカタログに保存する
+// - VACUUM [t]：不要になった版とインデックスのエントリを回収し、空いたページを解放する(BEGINの中では使えない)
+// - EXPLAIN SELECT ...：プランナーが選んだ実行計画と見積もったコストを表示する
+//
+// BEGINしていなければ、1文ごとにトランザクションを開始してコミットする(自動コミット)
+// BEGINした後に文がエラーになった場合は、トランザクション全体をロールバックする
+
+use std::fmt;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::Path;
+
+use crate::buffer_pool::BufferPoolManager;
+use crate::catalog::{self, Catalog, Column, ColumnType, IndexKind, Schema, TableDef};
+use crate::csv;
+use crate::executor::{self, AggregateExpr, AggregateFunc, Result, SortKey, Tuple};
+use crate::expr::{self, BinaryOp, Expr};
+use crate::memcmpable::Value;
+use crate::page_trace::Tracer;
+use crate::planner::{self, Plan};
+use crate::transaction::{Transaction, TransactionManager};
+use crate::wal;
+
+// 字句解析
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Int(i64),
+    Float(f64),
+    Str(String),
+    Symbol(&'static str),
+}
+
+// 長いものから順に試す
+const SYMBOLS: &[&str] = &["<=", ">=", "<>", "!=", "(", ")", ",", ";", "*", "=", "<", ">", "+", "-", "/", "."];
+
+fn tokenize(sql: &str) -> Result<Vec<Token>> {
+    let chars: Vec<char> = sql.chars().collect();
+    let mut tokens = vec![];
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+        } else if c == '-' && chars.get(i + 1) == Some(&'-') {
+            // 行末までコメント
+            while i < chars.len() && chars[i] != '\n' {
+                i += 1;
+            }
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            tokens.push(Token::Ident(chars[start..i].iter().collect()));
+        } else if c.is_ascii_digit() {
+            let start = i;
+            while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.') {
+                i += 1;
+            }
+            let text: String = chars[start..i].iter().collect();
+            if text.contains('.') {
+                tokens.push(Token::Float(text.parse().map_err(|_| format!("invalid number: {}", text))?));
+            } else {
+                tokens.push(Token::Int(text.parse().map_err(|_| format!("invalid number: {}", text))?));
+            }
+        } else if c == '\'' {
+            // ''は'そのものを表す
+            let mut s = String::new();
+            i += 1;
+            loop {
+                match chars.get(i) {
+                    None => return Err("unterminated string literal".into()),
+                    Some('\'') if chars.get(i + 1) == Some(&'\'') => {
+                        s.push('\'');
+                        i += 2;
+                    }
+                    Some('\'') => {
+                        i += 1;
+                        break;
+                    }
+                    Some(&c) => {
+                        s.push(c);
+                        i += 1;
+                    }
+                }
+            }
+            tokens.push(Token::Str(s));
+        } else {
+            let rest: String = chars[i..chars.len().min(i + 2)].iter().collect();
+            let symbol = SYMBOLS
+                .iter()
+                .find(|symbol| rest.starts_with(*symbol))
+                .ok_or_else(|| format!("unexpected character: {}", c))?;
+            tokens.push(Token::Symbol(symbol));
+            i += symbol.len();
+        }
+    }
+    Ok(tokens)
+}
+
+/// 文字列リテラルの外にある最初の;の位置
+/// 対話シェルで、文の終わりまで読んだかを判断するのに使う
+pub fn statement_end(sql: &str) -> Option<usize> {
+    let mut in_string = false;
+    let mut in_comment = false;
+    let mut prev = ' ';
+    for (i, c) in sql.char_indices() {
+        match c {
+            '\n' => in_comment = false,
+            '-' if !in_string && prev == '-' => in_comment = true,
+            '\'' if !in_comment => in_string = !in_string,
+            ';' if !in_string && !in_comment => return Some(i),
+            _ => {}
+        }
+        prev = c;
+    }
+    None
+}
+
+// 構文木
+
+#[derive(Debug, Clone, PartialEq)]
+enum Ast {
+    Column { table: Option<String>, name: String },
+    Literal(Value),
+    Binary(BinaryOp, Box<Ast>, Box<Ast>),
+    Not(Box<Ast>),
+    IsNull(Box<Ast>),
+    // 引数がNoneならCOUNT(*)
+    Function { name: String, arg: Option<Box<Ast>> },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum SelectItem {
+    Wildcard,
+    Expr { expr: Ast, alias: Option<String> },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct TableRef {
+    name: String,
+    alias: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Join {
+    table: TableRef,
+    on: Option<Ast>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Select {
+    items: Vec<SelectItem>,
+    from: TableRef,
+    joins: Vec<Join>,
+    filter: Option<Ast>,
+    group_by: Vec<Ast>,
+    order_by: Vec<(Ast, bool)>,
+    limit: Option<usize>,
+    offset: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Statement {
+    CreateTable { name: String, columns: Vec<Column> },
+    CreateIndex { name: String, table: String, columns: Vec<String>, unique: bool, kind: IndexKind },
+    DropTable(String),
+    DropIndex(String),
+    Insert { table: String, columns: Option<Vec<String>>, rows: Vec<Vec<Ast>> },
+    Select(Select),
+    Explain(Select),
+    // テーブル名が無ければ全てのテーブル
+    Analyze(Option<String>),
+    // テーブル名が無ければ全てのテーブル
+    Vacuum(Option<String>),
+    Update { table: String, sets: Vec<(String, Ast)>, filter: Option<Ast> },
+    Delete { table: String, filter: Option<Ast> },
+    CopyFrom { table: String, path: String },
+    CopyTo { table: String, path: String },
+    Begin,
+    Commit,
+    Rollback,
+}
+
+// 構文解析(再帰下降)
+
+// 別名として使えない単語
+const RESERVED: &[&str] = &[
+    "select", "from", "where", "join", "inner", "on", "group", "order", "by", "limit", "offset", "as", "and", "or", "not", "is", "null", "set", "values", "between",
+];
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn is_keyword(&self, keyword: &str) -> bool {
+        matches!(self.peek(), Some(Token::Ident(ident)) if ident.eq_ignore_ascii_case(keyword))
+    }
+
+    fn eat_keyword(&mut self, keyword: &str) -> bool {
+        let found = self.is_keyword(keyword);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn expect_keyword(&mut self, keyword: &str) -> Result<()> {
+        if self.eat_keyword(keyword) {
+            Ok(())
+        } else {
+            Err(self.unexpected(&keyword.to_uppercase()))
+        }
+    }
+
+    fn eat_symbol(&mut self, symbol: &str) -> bool {
+        let found = matches!(self.peek(), Some(Token::Symbol(s)) if *s == symbol);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn expect_symbol(&mut self, symbol: &str) -> Result<()> {
+        if self.eat_symbol(symbol) {
+            Ok(())
+        } else {
+            Err(self.unexpected(symbol))
+        }
+    }
+
+    fn unexpected(&self, expected: &str) -> Box<dyn std::error::Error> {
+        match self.peek() {
+            Some(token) => format!("expected {} but found {:?}", expected, token).into(),
+            None => format!("expected {} but reached the end", expected).into(),
+        }
+    }
+
+    // 識別子は大文字・小文字を区別しないので、小文字に揃える
+    fn ident(&mut self) -> Result<String> {
+        match self.peek() {
+            Some(Token::Ident(ident)) => {
+                let ident = ident.to_lowercase();
+                self.pos += 1;
+                Ok(ident)
+            }
+            _ => Err(self.unexpected("identifier")),
+        }
+    }
+
+    // 別名(AS は省略できる)
+    fn alias(&mut self) -> Result<Option<String>> {
+        if self.eat_keyword("as") {
+            return Ok(Some(self.ident()?));
+        }
+        match self.peek() {
+            Some(Token::Ident(ident)) if !RESERVED.contains(&ident.to_lowercase().as_str()) => Ok(Some(self.ident()?)),
+            _ => Ok(None),
+        }
+    }
+
+    fn comma_separated<T>(&mut self, mut f: impl FnMut(&mut Self) -> Result<T>) -> Result<Vec<T>> {
+        let mut items = vec![f(self)?];
+        while self.eat_symbol(",") {
+            items.push(f(self)?);
+        }
+        Ok(items)
+    }
+
+    fn number(&mut self) -> Result<usize> {
+        match self.next() {
+            Some(Token::Int(n)) if n >= 0 => Ok(n as usize),
+            _ => {
+                self.pos -= 1;
+                Err(self.unexpected("number"))
+            }
+        }
+    }
+
+    fn statement(&mut self) -> Result<Statement> {
+        let statement = if self.eat_keyword("create") {
+            let unique = self.eat_keyword("unique");
+            if !unique && self.eat_keyword("table") {
+                let name = self.ident()?;
+                self.expect_symbol("(")?;
+                let columns = self.comma_separated(|p| {
+                    let name = p.ident()?;
+                    let ty = p.column_type()?;
+                    Ok(Column::new(&name, ty))
+                })?;
+                self.expect_symbol(")")?;
+                Statement::CreateTable { name, columns }
+            } else {
+                self.expect_keyword("index")?;
+                let name = self.ident()?;
+                self.expect_keyword("on")?;
+                let table = self.ident()?;
+                let kind = if self.eat_keyword("using") { self.index_kind()? } else { IndexKind::BTree };
+                self.expect_symbol("(")?;
+                let columns = self.comma_separated(|p| p.ident())?;
+                self.expect_symbol(")")?;
+                Statement::CreateIndex { name, table, columns, unique, kind }
+            }
+        } else if self.eat_keyword("drop") {
+            if self.eat_keyword("table") {
+                Statement::DropTable(self.ident()?)
+            } else {
+                self.expect_keyword("index")?;
+                Statement::DropIndex(self.ident()?)
+            }
+        } else if self.eat_keyword("insert") {
+            self.expect_keyword("into")?;
+            let table = self.ident()?;
+            let columns = if self.eat_symbol("(") {
+                let columns = self.comma_separated(|p| p.ident())?;
+                self.expect_symbol(")")?;
+                Some(columns)
+            } else {
+                None
+            };
+            self.expect_keyword("values")?;
+            let rows = self.comma_separated(|p| {
+                p.expect_symbol("(")?;
+                let values = p.comma_separated(|p| p.expr())?;
+                p.expect_symbol(")")?;
+                Ok(values)
+            })?;
+            Statement::Insert { table, columns, rows }
+        } else if self.is_keyword("select") {
+            Statement::Select(self.select()?)
+        } else if self.eat_keyword("explain") {
+            Statement::Explain(self.select()?)
+        } else if self.eat_keyword("analyze") {
+            match self.peek() {
+                Some(Token::Ident(_)) => Statement::Analyze(Some(self.ident()?)),
+                _ => Statement::Analyze(None),
+            }
+        } else if self.eat_keyword("vacuum") {
+            match self.peek() {
+                Some(Token::Ident(_)) => Statement::Vacuum(Some(self.ident()?)),
+                _ => Statement::Vacuum(None),
+            }
+        } else if self.eat_keyword("update") {
+            let table = self.ident()?;
+            self.expect_keyword("set")?;
+            let sets = self.comma_separated(|p| {
+                let column = p.ident()?;
+                p.expect_symbol("=")?;
+                Ok((column, p.expr()?))
+            })?;
+            let filter = if self.eat_keyword("where") { Some(self.expr()?) } else { None };
+            Statement::Update { table, sets, filter }
+        } else if self.eat_keyword("delete") {
+            self.expect_keyword("from")?;
+            let table = self.ident()?;
+            let filter = if self.eat_keyword("where") { Some(self.expr()?) } else { None };
+            Statement::Delete { table, filter }
+        } else if self.eat_keyword("copy") {
+            let table = self.ident()?;
+            let from = self.eat_keyword("from");
+            if !from {
+                self.expect_keyword("to")?;
+            }
+            let path = match self.next() {
+                Some(Token::Str(path)) => path,
+                _ => {
+                    self.pos -= 1;
+                    return Err(self.unexpected("file path"));
+                }
+            };
+            if from {
+                Statement::CopyFrom { table, path }
+            } else {
+                Statement::CopyTo { table, path }
+            }
+        } else if self.eat_keyword("begin") {
+            self.eat_keyword("transaction");
+            Statement::Begin
+        } else if self.eat_keyword("commit") {
+            Statement::Commit
+        } else if self.eat_keyword("rollback") {
+            Statement::Rollback
+        } else {
+            return Err(self.unexpected("statement"));
+        };
+        self.eat_symbol(";");
+        if self.peek().is_some() {
+            return Err(self.unexpected("end of statement"));
+        }
+        Ok(statement)
+    }
+
+    fn column_type(&mut self) -> Result<ColumnType> {
+        let name = self.ident()?;
+        Ok(match name.as_str() {
+            "int" | "integer" | "bigint" => ColumnType::Int,
+            "uint" => ColumnType::UInt,
+            "float" | "real" | "double" => ColumnType::Float,
+            "text" | "varchar" | "string" => ColumnType::Str,
+            "blob" | "bytes" => ColumnType::Bytes,
+            _ => return Err(format!("unknown type: {}", name).into()),
+        })
+    }
+
+    fn index_kind(&mut self) -> Result<IndexKind> {
+        let name = self.ident()?;
+        Ok(match name.as_str() {
+            "btree" => IndexKind::BTree,
+            "hash" => IndexKind::Hash,
+            _ => return Err(format!("unknown index method: {}", name).into()),
+        })
+    }
+
+    fn table_ref(&mut self) -> Result<TableRef> {
+        let name = self.ident()?;
+        let alias = self.alias()?;
+        Ok(TableRef { name, alias })
+    }
+
+    fn select(&mut self) -> Result<Select> {
+        self.expect_keyword("select")?;
+        let items = self.comma_separated(|p| {
+            if p.eat_symbol("*") {
+                return Ok(SelectItem::Wildcard);
+            }
+            let expr = p.expr()?;
+            let alias = p.alias()?;
+            Ok(SelectItem::Expr { expr, alias })
+        })?;
+        self.expect_keyword("from")?;
+        let from = self.table_ref()?;
+        let mut joins = vec![];
+        loop {
+            if self.eat_symbol(",") {
+                joins.push(Join { table: self.table_ref()?, on: None });
+            } else if self.is_keyword("join") || self.is_keyword("inner") {
+                self.eat_keyword("inner");
+                self.expect_keyword("join")?;
+                let table = self.table_ref()?;
+                let on = if self.eat_keyword("on") { Some(self.expr()?) } else { None };
+                joins.push(Join { table, on });
+            } else {
+                break;
+            }
+        }
+        let filter = if self.eat_keyword("where") { Some(self.expr()?) } else { None };
+        let mut group_by = vec![];
+        if self.eat_keyword("group") {
+            self.expect_keyword("by")?;
+            group_by = self.comma_separated(|p| p.expr())?;
+        }
+        let mut order_by = vec![];
+        if self.eat_keyword("order") {
+            self.expect_keyword("by")?;
+            order_by = self.comma_separated(|p| {
+                let expr = p.expr()?;
+                let desc = if p.eat_keyword("desc") {
+                    true
+                } else {
+                    p.eat_keyword("asc");
+                    false
+                };
+                Ok((expr, desc))
+            })?;
+        }
+        let mut limit = None;
+        let mut offset = 0;
+        if self.eat_keyword("limit") {
+            limit = Some(self.number()?);
+        }
+        if self.eat_keyword("offset") {
+            offset = self.number()?;
+        }
+        Ok(Select {
+            items,
+            from,
+            joins,
+            filter,
+            group_by,
+            order_by,
+            limit,
+            offset,
+        })
+    }
+
+    // 優先順位の低い順に OR → AND → NOT → 比較 → 加減算 → 乗除算 → 単項マイナス → 項
+    fn expr(&mut self) -> Result<Ast> {
+        let mut left = self.and()?;
+        while self.eat_keyword("or") {
+            left = Ast::Binary(BinaryOp::Or, Box::new(left), Box::new(self.and()?));
+        }
+        Ok(left)
+    }
+
+    fn and(&mut self) -> Result<Ast> {
+        let mut left = self.not()?;
+        while self.eat_keyword("and") {
+            left = Ast::Binary(BinaryOp::And, Box::new(left), Box::new(self.not()?));
+        }
+        Ok(left)
+    }
+
+    fn not(&mut self) -> Result<Ast> {
+        if self.eat_keyword("not") {
+            return Ok(Ast::Not(Box::new(self.not()?)));
+        }
+        self.comparison()
+    }
+
+    fn comparison(&mut self) -> Result<Ast> {
+        let left = self.additive()?;
+        if self.eat_keyword("is") {
+            let negated = self.eat_keyword("not");
+            self.expect_keyword("null")?;
+            let is_null = Ast::IsNull(Box::new(left));
+            return Ok(if negated { Ast::Not(Box::new(is_null)) } else { is_null });
+        }
+        // x BETWEEN a AND bは x >= a AND x <= b にする(プランナーが範囲の条件として使える)
+        let negated = self.is_keyword("not")
+            && matches!(self.tokens.get(self.pos + 1), Some(Token::Ident(ident)) if ident.eq_ignore_ascii_case("between"));
+        if negated {
+            self.pos += 1;
+        }
+        if self.eat_keyword("between") {
+            let low = self.additive()?;
+            self.expect_keyword("and")?;
+            let high = self.additive()?;
+            let between = Ast::Binary(
+                BinaryOp::And,
+                Box::new(Ast::Binary(BinaryOp::Ge, Box::new(left.clone()), Box::new(low))),
+                Box::new(Ast::Binary(BinaryOp::Le, Box::new(left), Box::new(high))),
+            );
+            return Ok(if negated { Ast::Not(Box::new(between)) } else { between });
+        }
+        let op = match self.peek() {
+            Some(Token::Symbol("=")) => BinaryOp::Eq,
+            Some(Token::Symbol("<>")) | Some(Token::Symbol("!=")) => BinaryOp::Ne,
+            Some(Token::Symbol("<")) => BinaryOp::Lt,
+            Some(Token::Symbol("<=")) => BinaryOp::Le,
+            Some(Token::Symbol(">")) => BinaryOp::Gt,
+            Some(Token::Symbol(">=")) => BinaryOp::Ge,
+            _ => return Ok(left),
+        };
+        self.pos += 1;
+        Ok(Ast::Binary(op, Box::new(left), Box::new(self.additive()?)))
+    }
+
+    fn additive(&mut self) -> Result<Ast> {
+        let mut left = self.multiplicative()?;
+        loop {
+            let op = if self.eat_symbol("+") {
+                BinaryOp::Add
+            } else if self.eat_symbol("-") {
+                BinaryOp::Sub
+            } else {
+                return Ok(left);
+            };
+            left = Ast::Binary(op, Box::new(left), Box::new(self.multiplicative()?));
+        }
+    }
+
+    fn multiplicative(&mut self) -> Result<Ast> {
+        let mut left = self.unary()?;
+        loop {
+            let op = if self.eat_symbol("*") {
+                BinaryOp::Mul
+            } else if self.eat_symbol("/") {
+                BinaryOp::Div
+            } else {
+                return Ok(left);
+            };
+            left = Ast::Binary(op, Box::new(left), Box::new(self.unary()?));
+        }
+    }
+
+    fn unary(&mut self) -> Result<Ast> {
+        if self.eat_symbol("-") {
+            return Ok(match self.unary()? {
+                Ast::Literal(Value::Int(n)) => Ast::Literal(Value::Int(-n)),
+                Ast::Literal(Value::Float(f)) => Ast::Literal(Value::Float(-f)),
+                ast => Ast::Binary(BinaryOp::Sub, Box::new(Ast::Literal(Value::Int(0))), Box::new(ast)),
+            });
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<Ast> {
+        match self.next() {
+            Some(Token::Int(n)) => Ok(Ast::Literal(Value::Int(n))),
+            Some(Token::Float(f)) => Ok(Ast::Literal(Value::Float(f))),
+            Some(Token::Str(s)) => Ok(Ast::Literal(Value::Str(s))),
+            Some(Token::Symbol("(")) => {
+                let expr = self.expr()?;
+                self.expect_symbol(")")?;
+                Ok(expr)
+            }
+            Some(Token::Ident(ident)) => {
+                let ident = ident.to_lowercase();
+                match ident.as_str() {
+                    "null" => return Ok(Ast::Literal(Value::Null)),
+                    "true" => return Ok(Ast::Literal(expr::bool_value(true))),
+                    "false" => return Ok(Ast::Literal(expr::bool_value(false))),
+                    _ => {}
+                }
+                if self.eat_symbol("(") {
+                    let arg = if self.eat_symbol("*") { None } else { Some(Box::new(self.expr()?)) };
+                    self.expect_symbol(")")?;
+                    return Ok(Ast::Function { name: ident, arg });
+                }
+                if self.eat_symbol(".") {
+                    let name = self.ident()?;
+                    return Ok(Ast::Column { table: Some(ident), name });
+                }
+                Ok(Ast::Column { table: None, name: ident })
+            }
+            _ => {
+                self.pos -= 1;
+                Err(self.unexpected("expression"))
+            }
+        }
+    }
+}
+
+fn parse(sql: &str) -> Result<Statement> {
+    let mut parser = Parser { tokens: tokenize(sql)?, pos: 0 };
+    parser.statement()
+}
+
+// 構文木の列名を、行の何番目の値かに解決する
+
+// 行のi番目の値が、どのテーブル(別名)のどの列か
+#[derive(Debug, Clone, Default)]
+struct Scope {
+    columns: Vec<(String, String)>,
+}
+
+impl Scope {
+    fn table(table: &TableDef, alias: &Option<String>) -> Self {
+        let alias = alias.clone().unwrap_or_else(|| table.name.clone());
+        Self {
+            columns: table.schema.columns.iter().map(|column| (alias.clone(), column.name.clone())).collect(),
+        }
+    }
+
+    fn join(&self, right: &Scope) -> Self {
+        let mut columns = self.columns.clone();
+        columns.extend(right.columns.iter().cloned());
+        Self { columns }
+    }
+
+    fn resolve(&self, table: Option<&str>, name: &str) -> Result<usize> {
+        let mut found = self
+            .columns
+            .iter()
+            .enumerate()
+            .filter(|(_, (t, c))| c == name && (table.is_none() || table == Some(t.as_str())))
+            .map(|(i, _)| i);
+        match (found.next(), found.next()) {
+            (Some(i), None) => Ok(i),
+            (Some(_), Some(_)) => Err(format!("column reference {} is ambiguous", name).into()),
+            (None, _) => Err(format!("column {} does not exist", name).into()),
+        }
+    }
+}
+
+fn bind(ast: &Ast, scope: &Scope) -> Result<Expr> {
+    Ok(match ast {
+        Ast::Column { table, name } => Expr::Column(scope.resolve(table.as_deref(), name)?),
+        Ast::Literal(value) => Expr::Literal(value.clone()),
+        Ast::Binary(op, left, right) => Expr::binary(*op, bind(left, scope)?, bind(right, scope)?),
+        Ast::Not(ast) => Expr::Not(Box::new(bind(ast, scope)?)),
+        Ast::IsNull(ast) => Expr::IsNull(Box::new(bind(ast, scope)?)),
+        Ast::Function { name, .. } => return Err(format!("aggregate function {} is not allowed here", name).into()),
+    })
+}
+
+fn contains_aggregate(ast: &Ast) -> bool {
+    match ast {
+        Ast::Function { .. } => true,
+        Ast::Binary(_, left, right) => contains_aggregate(left) || contains_aggregate(right),
+        Ast::Not(ast) | Ast::IsNull(ast) => contains_aggregate(ast),
+        Ast::Column { .. } | Ast::Literal(_) => false,
+    }
+}
+
+fn aggregate_func(name: &str, arg: &Option<Box<Ast>>) -> Result<AggregateFunc> {
+    Ok(match (name, arg) {
+        ("count", None) => AggregateFunc::CountStar,
+        ("count", Some(_)) => AggregateFunc::Count,
+        ("sum", Some(_)) => AggregateFunc::Sum,
+        ("min", Some(_)) => AggregateFunc::Min,
+        ("max", Some(_)) => AggregateFunc::Max,
+        ("avg", Some(_)) => AggregateFunc::Avg,
+        _ => return Err(format!("unknown function: {}", name).into()),
+    })
+}
+
+// 集約した後の行(GROUP BYの値 → 集約した値の順に並ぶ)に対して式を解決する
+// 集約関数が出てきたらaggregatesに加える
+fn bind_aggregated(ast: &Ast, scope: &Scope, group_by: &[Ast], aggregates: &mut Vec<AggregateExpr>) -> Result<Expr> {
+    if let Some(i) = group_by.iter().position(|group| group == ast) {
+        return Ok(Expr::Column(i));
+    }
+    Ok(match ast {
+        Ast::Function { name, arg } => {
+            let func = aggregate_func(name, arg)?;
+            let arg = match arg {
+                Some(arg) => bind(arg, scope)?,
+                None => Expr::Literal(Value::Null),
+            };
+            let agg = AggregateExpr { func, arg };
+            let i = match aggregates.iter().position(|a| *a == agg) {
+                Some(i) => i,
+                None => {
+                    aggregates.push(agg);
+                    aggregates.len() - 1
+                }
+            };
+            Expr::Column(group_by.len() + i)
+        }
+        Ast::Column { name, .. } => return Err(format!("column {} must appear in GROUP BY or be used in an aggregate function", name).into()),
+        Ast::Literal(value) => Expr::Literal(value.clone()),
+        Ast::Binary(op, left, right) => Expr::binary(
+            *op,
+            bind_aggregated(left, scope, group_by, aggregates)?,
+            bind_aggregated(right, scope, group_by, aggregates)?,
+        ),
+        Ast::Not(ast) => Expr::Not(Box::new(bind_aggregated(ast, scope, group_by, aggregates)?)),
+        Ast::IsNull(ast) => Expr::IsNull(Box::new(bind_aggregated(ast, scope, group_by, aggregates)?)),
+    })
+}
+
+// 結果の列名
+fn column_name(ast: &Ast) -> String {
+    match ast {
+        Ast::Column { name, .. } => name.clone(),
+        Ast::Function { name, .. } => name.clone(),
+        _ => "?column?".to_string(),
+    }
+}
+
+// ANDで繋がった条件を分解する
+fn conjuncts(ast: Ast, out: &mut Vec<Ast>) {
+    match ast {
+        Ast::Binary(BinaryOp::And, left, right) => {
+            conjuncts(*left, out);
+            conjuncts(*right, out);
+        }
+        ast => out.push(ast),
+    }
+}
+
+// 値を列の型に合わせる(整数リテラルをUINTやFLOATの列に入れられるようにする)
+fn coerce(value: Value, ty: ColumnType) -> Value {
+    match (value, ty) {
+        (Value::Int(n), ColumnType::UInt) if n >= 0 => Value::UInt(n as u64),
+        (Value::Int(n), ColumnType::Float) => Value::Float(n as f64),
+        (Value::UInt(n), ColumnType::Float) => Value::Float(n as f64),
+        (Value::Str(s), ColumnType::Bytes) => Value::Bytes(s.into_bytes()),
+        (value, _) => value,
+    }
+}
+
+/// 文を実行した結果
+#[derive(Debug, Clone, PartialEq)]
+pub enum Output {
+    Rows { columns: Vec<String>, rows: Vec<Tuple> },
+    Message(String),
+}
+
+pub fn format_value(value: &Value) -> String {
+    match value {
+        Value::Null => "NULL".to_string(),
+        Value::Int(n) => n.to_string(),
+        Value::UInt(n) => n.to_string(),
+        Value::Float(f) => f.to_string(),
+        Value::Str(s) => s.clone(),
+        Value::Bytes(bytes) => format!("x'{}'", bytes.iter().map(|b| format!("{:02x}", b)).collect::<String>()),
+        Value::Tuple(values) => format!("({})", values.iter().map(format_value).collect::<Vec<_>>().join(", ")),
+    }
+}
+
+// +----+-------+
+// | id | name  |
+// +----+-------+
+// | 1  | alice |
+// +----+-------+
+// (1 row)
+impl fmt::Display for Output {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (columns, rows) = match self {
+            Output::Message(message) => return write!(f, "{}", message),
+            Output::Rows { columns, rows } => (columns, rows),
+        };
+        let cells: Vec<Vec<String>> = rows.iter().map(|row| row.iter().map(format_value).collect()).collect();
+        let widths: Vec<usize> = columns
+            .iter()
+            .enumerate()
+            .map(|(i, column)| cells.iter().map(|row| row[i].chars().count()).chain(Some(column.chars().count())).max().unwrap())
+            .collect();
+        let line: String = widths.iter().map(|w| format!("+{}", "-".repeat(w + 2))).collect::<String>() + "+";
+        let format_row = |row: &[String]| -> String {
+            let mut s: String = row
+                .iter()
+                .zip(&widths)
+                .map(|(cell, w)| format!("| {}{} ", cell, " ".repeat(w - cell.chars().count())))
+                .collect();
+            s.push('|');
+            s
+        };
+        writeln!(f, "{}", line)?;
+        writeln!(f, "{}", format_row(columns))?;
+        writeln!(f, "{}", line)?;
+        for row in &cells {
+            writeln!(f, "{}", format_row(row))?;
+        }
+        writeln!(f, "{}", line)?;
+        write!(f, "({} {})", rows.len(), if rows.len() == 1 { "row" } else { "rows" })
+    }
+}
+
+/// SQLを実行するセッション
+/// BEGINしてからCOMMIT・ROLLBACKするまでのトランザクションを持つ
+pub struct Session {
+    bufmgr: BufferPoolManager,
+    txn_mgr: TransactionManager,
+    catalog: Catalog,
+    txn: Option<Transaction>,
+}
+
+impl Session {
+    /// ヒープファイルを開く(無ければ作る)
+    pub fn open(path: impl AsRef<Path>, pool_size: usize) -> Result<Self> {
+        let (bufmgr, txn_mgr, catalog) = catalog::open(path, pool_size)?;
+        Ok(Self {
+            bufmgr,
+            txn_mgr,
+            catalog,
+            txn: None,
+        })
+    }
+
+    /// 実行中のトランザクションをロールバックし、チェックポイントを取る(全てのページを書き出す)
+    pub fn close(mut self) -> Result<()> {
+        if let Some(txn) = self.txn.take() {
+            self.txn_mgr.rollback(&mut self.bufmgr, txn)?;
+        }
+        wal::checkpoint(&mut self.bufmgr)?;
+        self.bufmgr.stop_trace()?;
+        Ok(())
+    }
+
+    /// これ以降のバッファプールのページアクセスをpathに記録する
+    /// 記録したファイルはpage-traceで再生して、バッファプールの大きさを決めるのに使う
+    pub fn trace_pages(&mut self, path: impl AsRef<Path>) -> Result<()> {
+        self.bufmgr.start_trace(Tracer::create(path)?);
+        Ok(())
+    }
+
+    pub fn in_transaction(&self) -> bool {
+        self.txn.is_some()
+    }
+
+    /// 1つの文を実行する
+    pub fn execute(&mut self, sql: &str) -> Result<Output> {
+        let mut txn = self.txn.take();
+        let result = self.execute_in(&mut txn, sql);
+        self.txn = txn;
+        result
+    }
+
+    /// BEGINしたトランザクションをtxn_slotに持たせて1つの文を実行する
+    /// 1つのSessionを複数の接続で共有するサーバーが、接続ごとのトランザクションを渡すのに使う
+    pub fn execute_in(&mut self, txn_slot: &mut Option<Transaction>, sql: &str) -> Result<Output> {
+        let statement = parse(sql)?;
+        // VACUUMの後はチェックポイントを取り、回収前のログを切り詰める
+        // 解放したページと切り詰めたファイルの大きさは、次に開いた時にチェックポイントから復元される
+        let vacuum = matches!(statement, Statement::Vacuum(_));
+        let result = self.execute_statement(txn_slot, statement);
+        if vacuum && result.is_ok() {
+            wal::checkpoint(&mut self.bufmgr)?;
+        } else {
+            self.txn_mgr.checkpoint_if_needed(&mut self.bufmgr)?;
+        }
+        result
+    }
+
+    fn execute_statement(&mut self, txn_slot: &mut Option<Transaction>, statement: Statement) -> Result<Output> {
+        match statement {
+            Statement::Begin => {
+                if txn_slot.is_some() {
+                    return Err("there is already a transaction in progress".into());
+                }
+                *txn_slot = Some(self.txn_mgr.begin()?);
+                Ok(Output::Message("BEGIN".to_string()))
+            }
+            Statement::Commit => {
+                let txn = txn_slot.take().ok_or("there is no transaction in progress")?;
+                self.txn_mgr.commit(txn)?;
+                Ok(Output::Message("COMMIT".to_string()))
+            }
+            Statement::Rollback => {
+                let txn = txn_slot.take().ok_or("there is no transaction in progress")?;
+                self.txn_mgr.rollback(&mut self.bufmgr, txn)?;
+                Ok(Output::Message("ROLLBACK".to_string()))
+            }
+            Statement::Vacuum(_) if txn_slot.is_some() => Err("VACUUM cannot run inside a transaction".into()),
+            statement => {
+                let (txn, autocommit) = match txn_slot.take() {
+                    Some(txn) => (txn, false),
+                    None => (self.txn_mgr.begin()?, true),
+                };
+                match self.run(&txn, statement) {
+                    Ok(output) => {
+                        if autocommit {
+                            self.txn_mgr.commit(txn)?;
+                        } else {
+                            *txn_slot = Some(txn);
+                        }
+                        Ok(output)
+                    }
+                    Err(err) => {
+                        self.txn_mgr.rollback(&mut self.bufmgr, txn)?;
+                        if autocommit {
+                            Err(err)
+                        } else {
+                            Err(format!("{} (transaction rolled back)", err).into())
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// execute_inでBEGINしたトランザクションをロールバックする
+    pub fn rollback(&mut self, txn: Transaction) -> Result<()> {
+        self.txn_mgr.rollback(&mut self.bufmgr, txn)?;
+        Ok(())
+    }
+
+    fn table(&mut self, txn: &Transaction, name: &str) -> Result<TableDef> {
+        self.catalog
+            .table(&mut self.bufmgr, txn, name)?
+            .ok_or_else(|| format!("table {} does not exist", name).into())
+    }
+
+    fn run(&mut self, txn: &Transaction, statement: Statement) -> Result<Output> {
+        let bufmgr = &mut self.bufmgr;
+        let catalog = self.catalog;
+        match statement {
+            Statement::CreateTable { name, columns } => {
+                match catalog.create_table(bufmgr, txn, &name, Schema::new(columns)) {
+                    Err(crate::error::Error::DuplicateKey) => return Err(format!("table {} already exists", name).into()),
+                    result => result?,
+                };
+                Ok(Output::Message("CREATE TABLE".to_string()))
+            }
+            Statement::CreateIndex { name, table, columns, unique, kind } => {
+                let table_def = self.table(txn, &table)?;
+                let bufmgr = &mut self.bufmgr;
+                let columns: Vec<&str> = columns.iter().map(|column| column.as_str()).collect();
+                let index = match catalog.create_index(bufmgr, txn, &name, &table, &columns, unique, kind) {
+                    Err(crate::error::Error::DuplicateKey) => return Err(format!("index {} already exists", name).into()),
+                    Err(crate::error::Error::NotFound) => return Err(format!("column does not exist in table {}", table).into()),
+                    result => result?,
+                };
+                executor::build_index(bufmgr, txn, &table_def, &index)?;
+                Ok(Output::Message("CREATE INDEX".to_string()))
+            }
+            Statement::DropTable(name) => {
+                if catalog.drop_table(bufmgr, txn, &name).is_err() {
+                    return Err(format!("table {} does not exist", name).into());
+                }
+                Ok(Output::Message("DROP TABLE".to_string()))
+            }
+            Statement::DropIndex(name) => {
+                if catalog.drop_index(bufmgr, txn, &name).is_err() {
+                    return Err(format!("index {} does not exist", name).into());
+                }
+                Ok(Output::Message("DROP INDEX".to_string()))
+            }
+            Statement::Insert { table, columns, rows } => {
+                let table = self.table(txn, &table)?;
+                let bufmgr = &mut self.bufmgr;
+                let indexes = catalog.indexes(bufmgr, txn, &table.name)?;
+                let positions = match columns {
+                    Some(columns) => columns
+                        .iter()
+                        .map(|column| table.schema.position(column).ok_or_else(|| format!("column {} does not exist", column).into()))
+                        .collect::<Result<Vec<_>>>()?,
+                    None => (0..table.schema.columns.len()).collect(),
+                };
+                let count = rows.len();
+                for values in rows {
+                    if values.len() != positions.len() {
+                        return Err(format!("expected {} values but got {}", positions.len(), values.len()).into());
+                    }
+                    let mut row = vec![Value::Null; table.schema.columns.len()];
+                    for (&pos, ast) in positions.iter().zip(&values) {
+                        row[pos] = coerce(bind(ast, &Scope::default())?.eval(&[])?, table.schema.columns[pos].ty);
+                    }
+                    executor::insert_row(bufmgr, txn, &table, &indexes, &row)?;
+                }
+                Ok(Output::Message(format!("INSERT {}", count)))
+            }
+            Statement::Select(select) => {
+                let (plan, columns) = self.plan_select(txn, select)?;
+                let rows = executor::collect(plan.node.as_ref(), &mut self.bufmgr, txn)?;
+                Ok(Output::Rows { columns, rows })
+            }
+            Statement::Explain(select) => {
+                let (plan, _) = self.plan_select(txn, select)?;
+                let rows = plan.explain.to_string().lines().map(|line| vec![Value::Str(line.to_string())]).collect();
+                Ok(Output::Rows {
+                    columns: vec!["QUERY PLAN".to_string()],
+                    rows,
+                })
+            }
+            Statement::Analyze(name) => {
+                let tables = match name {
+                    Some(name) => vec![self.table(txn, &name)?],
+                    None => catalog.tables(&mut self.bufmgr, txn)?,
+                };
+                for table in tables {
+                    let stats = planner::analyze(&mut self.bufmgr, txn, &table)?;
+                    catalog.set_stats(&mut self.bufmgr, txn, stats)?;
+                }
+                Ok(Output::Message("ANALYZE".to_string()))
+            }
+            Statement::Vacuum(name) => {
+                let tables = match name {
+                    Some(name) => vec![self.table(txn, &name)?],
+                    None => catalog.tables(&mut self.bufmgr, txn)?,
+                };
+                let horizon = self.txn_mgr.horizon();
+                for table in tables {
+                    let indexes = catalog.indexes(&mut self.bufmgr, txn, &table.name)?;
+                    executor::vacuum(&mut self.bufmgr, txn, &table, &indexes, horizon)?;
+                }
+                Ok(Output::Message("VACUUM".to_string()))
+            }
+            Statement::Update { table, sets, filter } => {
+                let table = self.table(txn, &table)?;
+                let bufmgr = &mut self.bufmgr;
+                let indexes = catalog.indexes(bufmgr, txn, &table.name)?;
+                let scope = Scope::table(&table, &None);
+                let sets = sets
+                    .iter()
+                    .map(|(column, ast)| {
+                        let pos = table.schema.position(column).ok_or_else(|| format!("column {} does not exist", column))?;
+                        Ok((pos, bind(ast, &scope)?))
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                let targets = matching_rows(bufmgr, txn, &table, filter, &scope)?;
+                for (rid, mut row) in targets.clone() {
+                    let new_values = sets.iter().map(|(_, expr)| expr.eval(&row)).collect::<Result<Vec<_>>>()?;
+                    for ((pos, _), value) in sets.iter().zip(new_values) {
+                        row[*pos] = coerce(value, table.schema.columns[*pos].ty);
+                    }
+                    executor::update_row(bufmgr, txn, &table, &indexes, rid, &row)?;
+                }
+                Ok(Output::Message(format!("UPDATE {}", targets.len())))
+            }
+            Statement::Delete { table, filter } => {
+                let table = self.table(txn, &table)?;
+                let bufmgr = &mut self.bufmgr;
+                let scope = Scope::table(&table, &None);
+                let targets = matching_rows(bufmgr, txn, &table, filter, &scope)?;
+                for (rid, _) in &targets {
+                    executor::delete_row(bufmgr, txn, &table, *rid)?;
+                }
+                Ok(Output::Message(format!("DELETE {}", targets.len())))
+            }
+            Statement::CopyFrom { table, path } => {
+                let table = self.table(txn, &table)?;
+                let bufmgr = &mut self.bufmgr;
+                let indexes = catalog.indexes(bufmgr, txn, &table.name)?;
+                let file = File::open(&path).map_err(|err| format!("{}: {}", path, err))?;
+                let mut reader = csv::Reader::new(BufReader::new(file));
+                // 1行目の列名から、各フィールドを入れる列の位置を決める
+                let header = reader.next_record()?.ok_or_else(|| format!("{}: no header row", path))?.1;
+                let positions = header
+                    .iter()
+                    .map(|name| {
+                        let name = name.as_deref().unwrap_or("");
+                        table.schema.position(name).ok_or_else(|| format!("line 1: column {} does not exist", name).into())
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                let schema = &table.schema;
+                let rows = std::iter::from_fn(|| {
+                    let (line, fields) = match reader.next_record() {
+                        Ok(record) => record?,
+                        Err(err) => return Some(Err(err.into())),
+                    };
+                    if fields.len() != positions.len() {
+                        return Some(Err(format!("line {}: expected {} fields but got {}", line, positions.len(), fields.len()).into()));
+                    }
+                    let mut row = vec![Value::Null; schema.columns.len()];
+                    for (&pos, field) in positions.iter().zip(&fields) {
+                        let column = &schema.columns[pos];
+                        match csv::parse_field(field, column.ty) {
+                            Ok(value) => row[pos] = value,
+                            Err(err) => return Some(Err(format!("line {}: column {}: {}", line, column.name, err).into())),
+                        }
+                    }
+                    Some(Ok(row))
+                });
+                let count = executor::load_rows(bufmgr, txn, &table, &indexes, rows)?;
+                Ok(Output::Message(format!("COPY {}", count)))
+            }
+            Statement::CopyTo { table, path } => {
+                let table = self.table(txn, &table)?;
+                let file = File::create(&path).map_err(|err| format!("{}: {}", path, err))?;
+                let mut writer = BufWriter::new(file);
+                let header: Vec<csv::Field> = table.schema.columns.iter().map(|column| Some(column.name.clone())).collect();
+                csv::write_record(&mut writer, &header)?;
+                let mut iter = table.heap.scan();
+                let mut count = 0;
+                while let Some((_, bytes)) = iter.next(&mut self.bufmgr, txn)? {
+                    let row = executor::decode_row(&bytes)?;
+                    csv::write_record(&mut writer, &row.iter().map(csv::format_field).collect::<Vec<_>>())?;
+                    count += 1;
+                }
+                writer.flush()?;
+                Ok(Output::Message(format!("COPY {}", count)))
+            }
+            Statement::Begin | Statement::Commit | Statement::Rollback => unreachable!(),
+        }
+    }
+
+    fn plan_select(&mut self, txn: &Transaction, select: Select) -> Result<(Plan, Vec<String>)> {
+        // JOINは全て内部結合なので、ONとWHEREの条件はまとめてプランナーに渡し、結合の順序は任せる
+        let mut table_refs = vec![select.from];
+        let mut conds = vec![];
+        for join in select.joins {
+            table_refs.push(join.table);
+            if let Some(on) = join.on {
+                conjuncts(on, &mut conds);
+            }
+        }
+        if let Some(filter) = select.filter {
+            conjuncts(filter, &mut conds);
+        }
+        let mut relations = vec![];
+        let mut scopes = vec![];
+        for table_ref in &table_refs {
+            let table = self.table(txn, &table_ref.name)?;
+            let alias = table_ref.alias.clone().unwrap_or_else(|| table.name.clone());
+            if relations.iter().any(|relation: &planner::Relation| relation.alias == alias) {
+                return Err(format!("table name {} specified more than once", alias).into());
+            }
+            scopes.push(Scope::table(&table, &table_ref.alias));
+            relations.push(planner::Relation {
+                alias,
+                indexes: self.catalog.indexes(&mut self.bufmgr, txn, &table.name)?,
+                stats: self.catalog.stats(&mut self.bufmgr, txn, &table.name)?,
+                table,
+            });
+        }
+        // 条件はFROMの順に列を並べた行に対して解決しておく
+        let from_scope = scopes.iter().fold(Scope::default(), |scope, s| scope.join(s));
+        let predicates = conds.iter().map(|ast| bind(ast, &from_scope)).collect::<Result<Vec<_>>>()?;
+        let (mut plan, order) = planner::plan_joins(&relations, predicates)?;
+        // 結合した結果の行には、プランナーが選んだ順にテーブルの列が並ぶ
+        let scope = order.iter().fold(Scope::default(), |scope, &i| scope.join(&scopes[i]));
+
+        // SELECTの項目(*は全ての列に展開する)
+        let mut items = vec![];
+        for item in select.items {
+            match item {
+                SelectItem::Wildcard => {
+                    for (table, column) in &from_scope.columns {
+                        let ast = Ast::Column {
+                            table: Some(table.clone()),
+                            name: column.clone(),
+                        };
+                        items.push((ast, column.clone()));
+                    }
+                }
+                SelectItem::Expr { expr, alias } => {
+                    let name = alias.unwrap_or_else(|| column_name(&expr));
+                    items.push((expr, name));
+                }
+            }
+        }
+        let group_by = select.group_by;
+        // ORDER BYではSELECTの項目の別名も使える
+        let order_by: Vec<(Ast, bool)> = select
+            .order_by
+            .into_iter()
+            .map(|(ast, desc)| match &ast {
+                Ast::Column { table: None, name } => match items.iter().find(|(_, alias)| alias == name) {
+                    Some((item, _)) if scope.resolve(None, name).is_err() => (item.clone(), desc),
+                    _ => (ast, desc),
+                },
+                _ => (ast, desc),
+            })
+            .collect();
+
+        let aggregated = !group_by.is_empty() || items.iter().any(|(ast, _)| contains_aggregate(ast)) || order_by.iter().any(|(ast, _)| contains_aggregate(ast));
+        let (exprs, sort_keys) = if aggregated {
+            let mut aggregates = vec![];
+            let exprs = items
+                .iter()
+                .map(|(ast, _)| bind_aggregated(ast, &scope, &group_by, &mut aggregates))
+                .collect::<Result<Vec<_>>>()?;
+            let sort_keys = order_by
+                .iter()
+                .map(|(ast, desc)| {
+                    Ok(SortKey {
+                        expr: bind_aggregated(ast, &scope, &group_by, &mut aggregates)?,
+                        desc: *desc,
+                    })
+                })
+                .collect::<Result<Vec<_>>>()?;
+            let group_by = group_by.iter().map(|ast| bind(ast, &scope)).collect::<Result<Vec<_>>>()?;
+            plan = plan.aggregate(group_by, aggregates);
+            (exprs, sort_keys)
+        } else {
+            let exprs = items.iter().map(|(ast, _)| bind(ast, &scope)).collect::<Result<Vec<_>>>()?;
+            let sort_keys = order_by
+                .iter()
+                .map(|(ast, desc)| Ok(SortKey { expr: bind(ast, &scope)?, desc: *desc }))
+                .collect::<Result<Vec<_>>>()?;
+            (exprs, sort_keys)
+        };
+        // 射影より前に並び替えるので、SELECTに無い列でも並び替えられる
+        if !sort_keys.is_empty() {
+            plan = plan.sort(sort_keys);
+        }
+        if select.limit.is_some() || select.offset > 0 {
+            plan = plan.limit(select.limit, select.offset);
+        }
+        let names: Vec<String> = items.into_iter().map(|(_, name)| name).collect();
+        Ok((plan.project(exprs, names.clone()), names))
+    }
+}
+
+// UPDATE・DELETEの対象の行を先に全て集める
+// 書き換えながら読むと、書き換えた行をもう一度読んでしまうことがある
+fn matching_rows(
+    bufmgr: &mut BufferPoolManager,
+    txn: &Transaction,
+    table: &TableDef,
+    filter: Option<Ast>,
+    scope: &Scope,
+) -> Result<Vec<(crate::heap::RecordId, Tuple)>> {
+    let filter = filter.map(|ast| bind(&ast, scope)).transpose()?;
+    let mut iter = table.heap.scan();
+    let mut rows = vec![];
+    while let Some((rid, bytes)) = iter.next(bufmgr, txn)? {
+        let row = executor::decode_row(&bytes)?;
+        if filter.as_ref().map_or(Ok(true), |filter| filter.matches(&row))? {
+            rows.push((rid, row));
+        }
+    }
+    Ok(rows)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session(name: &str) -> (Session, std::path::PathBuf) {
+        let path = std::env::temp_dir().join(format!("sql_test_{}_{}", std::process::id(), name));
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(wal::log_path(&path));
+        (Session::open(&path, 32).unwrap(), path)
+    }
+
+    fn rows(session: &mut Session, sql: &str) -> Vec<Vec<String>> {
+        match session.execute(sql).unwrap() {
+            Output::Rows { rows, .. } => rows.iter().map(|row| row.iter().map(format_value).collect()).collect(),
+            output => panic!("unexpected output: {:?}", output),
+        }
+    }
+
+    fn run(session: &mut Session, sqls: &[&str]) {
+        for sql in sqls {
+            session.execute(sql).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        let statement = parse("select a.x + 1 as y, count(*) from t a join u on a.id = u.id where not x is null order by y desc limit 2;").unwrap();
+        let select = match statement {
+            Statement::Select(select) => select,
+            _ => panic!(),
+        };
+        assert_eq!(select.items.len(), 2);
+        assert_eq!(select.from.alias, Some("a".to_string()));
+        assert_eq!(select.joins.len(), 1);
+        assert_eq!(select.limit, Some(2));
+        assert!(parse("select from").is_err());
+        assert!(parse("insert into t values (1) extra").is_err());
+        assert_eq!(statement_end("insert into t values ('a;b'); -- ;"), Some(28));
+    }
+
+    #[test]
+    fn test_queries() {
+        let (mut session, path) = session("queries");
+        run(
+            &mut session,
+            &[
+                "CREATE TABLE users (id INT, name TEXT, dept INT)",
+                "CREATE TABLE depts (id INT, name TEXT)",
+                "INSERT INTO users VALUES (1, 'alice', 1), (2, 'bob', 2), (3, 'carol', 1), (4, 'dave', NULL)",
+                "INSERT INTO depts (name, id) VALUES ('eng', 1), ('sales', 2)",
+                "CREATE UNIQUE INDEX users_name ON users (name)",
+            ],
+        );
+        assert_eq!(
+            rows(&mut session, "SELECT u.name, d.name FROM users u JOIN depts d ON u.dept = d.id WHERE u.id > 1 ORDER BY u.name"),
+            vec![vec!["bob", "sales"], vec!["carol", "eng"]]
+        );
+        assert_eq!(
+            rows(&mut session, "SELECT name FROM users ORDER BY id DESC LIMIT 2 OFFSET 1"),
+            vec![vec!["carol"], vec!["bob"]]
+        );
+        assert_eq!(
+            rows(&mut session, "SELECT dept, count(*) AS n, sum(id) FROM users GROUP BY dept ORDER BY n DESC, dept"),
+            vec![vec!["1", "2", "4"], vec!["NULL", "1", "4"], vec!["2", "1", "2"]]
+        );
+        assert_eq!(session.execute("UPDATE users SET dept = 2, name = name WHERE dept = 1").unwrap(), Output::Message("UPDATE 2".to_string()));
+        assert_eq!(session.execute("DELETE FROM users WHERE dept IS NULL").unwrap(), Output::Message("DELETE 1".to_string()));
+        assert_eq!(rows(&mut session, "SELECT count(*), max(dept) FROM users"), vec![vec!["3", "2"]]);
+
+        // エラー
+        assert!(session.execute("INSERT INTO users VALUES (5, 'alice', 1)").is_err());
+        assert!(session.execute("SELECT nothing FROM users").is_err());
+        assert!(session.execute("INSERT INTO users VALUES ('x', 'y', 1)").is_err());
+        assert!(session.execute("SELECT id FROM users u JOIN depts d ON u.dept = d.id").is_err());
+        // 型が合わない値はSQLのリテラルの形で表示する
+        let err = session.execute("INSERT INTO users VALUES ('x', 'y', 1)").unwrap_err();
+        assert_eq!(err.to_string(), "column id expects INT but got 'x'");
+
+        // BETWEENは両端を含む
+        assert_eq!(rows(&mut session, "SELECT id FROM users WHERE id BETWEEN 2 AND 3 AND dept = 2 ORDER BY id"), vec![vec!["2"], vec!["3"]]);
+        assert_eq!(rows(&mut session, "SELECT id FROM users WHERE id NOT BETWEEN 2 AND 3 ORDER BY id"), vec![vec!["1"]]);
+
+        // 表の形で表示する
+        let output = session.execute("SELECT id, name FROM users WHERE id = 1").unwrap();
+        assert_eq!(output.to_string(), "+----+-------+\n| id | name  |\n+----+-------+\n| 1  | alice |\n+----+-------+\n(1 row)");
+        session.close().unwrap();
+
+        // 開き直しても残っている
+        let mut session = Session::open(&path, 32).unwrap();
+        assert_eq!(rows(&mut session, "SELECT count(*) FROM users"), vec![vec!["3"]]);
+    }
+
+    #[test]
+    fn test_transactions() {
+        let (mut session, _) = session("transactions");
+        run(&mut session, &["CREATE TABLE t (x INT)", "BEGIN", "INSERT INTO t VALUES (1)", "INSERT INTO t VALUES (2)", "ROLLBACK"]);
+        assert_eq!(rows(&mut session, "SELECT count(*) FROM t"), vec![vec!["0"]]);
+        run(&mut session, &["BEGIN", "INSERT INTO t VALUES (3)", "COMMIT"]);
+        assert_eq!(rows(&mut session, "SELECT x FROM t"), vec![vec!["3"]]);
+        // トランザクションの途中でエラーになると全てロールバックされる
+        run(&mut session, &["BEGIN", "INSERT INTO t VALUES (4)"]);
+        assert!(session.execute("INSERT INTO t VALUES ('x')").is_err());
+        assert!(!session.in_transaction());
+        assert_eq!(rows(&mut session, "SELECT x FROM t"), vec![vec!["3"]]);
+        assert!(session.execute("COMMIT").is_err());
+    }
+
+    #[test]
+    fn test_explain() {
+        let (mut session, _) = session("explain");
+        let values: Vec<String> = (0..600).map(|i| format!("({}, {}, 'name{}{}')", i, i % 3, i, " ".repeat(150))).collect();
+        run(
+            &mut session,
+            &[
+                "CREATE TABLE items (id INT, kind INT, name TEXT)",
+                "CREATE TABLE kinds (id INT, label TEXT)",
+                &format!("INSERT INTO items VALUES {}", values.join(", ")),
+                "INSERT INTO items VALUES (NULL, 0, 'none')",
+                "INSERT INTO kinds VALUES (0, 'a'), (1, 'b'), (2, 'c')",
+                "CREATE INDEX items_id ON items (id)",
+                "ANALYZE",
+            ],
+        );
+        let explain = |session: &mut Session, sql: &str| -> String {
+            rows(session, sql).into_iter().map(|row| row[0].clone() + "\n").collect()
+        };
+        // 絞り込める条件ならインデックスを使い、ほとんどの行が当てはまるなら全て読む
+        let plan = explain(&mut session, "EXPLAIN SELECT name FROM items WHERE id = 42");
+        assert!(plan.contains("Index Scan using items_id on items"), "{}", plan);
+        assert!(plan.contains("Index Cond: (items.id = 42)"), "{}", plan);
+        let plan = explain(&mut session, "EXPLAIN SELECT name FROM items WHERE id > 10");
+        assert!(plan.contains("Seq Scan on items"), "{}", plan);
+        // 小さいテーブルでハッシュ表を作る
+        let plan = explain(&mut session, "EXPLAIN SELECT k.label, count(*) FROM items i JOIN kinds k ON i.kind = k.id GROUP BY k.label");
+        assert!(plan.contains("Hash Cond: (i.kind = k.id)"), "{}", plan);
+        assert!(plan.find("Seq Scan on items i").unwrap() < plan.find("Seq Scan on kinds k").unwrap(), "{}", plan);
+
+        // インデックスで読んでもNULLの行は範囲に入らない
+        assert_eq!(rows(&mut session, "SELECT id FROM items WHERE id < 2"), vec![vec!["0"], vec!["1"]]);
+        assert_eq!(rows(&mut session, "SELECT kind FROM items WHERE 42 = id"), vec![vec!["0"]]);
+        assert_eq!(
+            rows(&mut session, "SELECT k.label, count(*) FROM items i JOIN kinds k ON i.kind = k.id GROUP BY k.label"),
+            vec![vec!["a", "201"], vec!["b", "200"], vec!["c", "200"]]
+        );
+        assert!(session.execute("SELECT * FROM items JOIN items ON items.id = items.id").is_err());
+    }
+
+    #[test]
+    fn test_hash_index() {
+        let (mut session, _) = session("hash_index");
+        let values: Vec<String> = (0..600).map(|i| format!("({}, 'name{}{}')", i, i, " ".repeat(150))).collect();
+        run(
+            &mut session,
+            &[
+                "CREATE TABLE items (id INT, name TEXT)",
+                &format!("INSERT INTO items VALUES {}", values.join(", ")),
+                "CREATE UNIQUE INDEX items_id ON items USING hash (id)",
+                "ANALYZE",
+            ],
+        );
+        assert!(session.execute("INSERT INTO items VALUES (42, 'dup')").is_err());
+        assert!(session.execute("CREATE INDEX bad ON items USING bitmap (id)").is_err());
+        let explain = |session: &mut Session, sql: &str| -> String {
+            rows(session, sql).into_iter().map(|row| row[0].clone() + "\n").collect()
+        };
+        let plan = explain(&mut session, "EXPLAIN SELECT name FROM items WHERE id = 42");
+        assert!(plan.contains("Index Scan using items_id on items"), "{}", plan);
+        // 範囲の条件には使えない
+        let plan = explain(&mut session, "EXPLAIN SELECT name FROM items WHERE id < 2");
+        assert!(plan.contains("Seq Scan on items"), "{}", plan);
+        run(&mut session, &["UPDATE items SET id = 1000 WHERE id = 42"]);
+        assert!(rows(&mut session, "SELECT id FROM items WHERE id = 42").is_empty());
+        assert_eq!(rows(&mut session, "SELECT id FROM items WHERE id = 1000"), vec![vec!["1000"]]);
+    }
+
+    #[test]
+    fn test_vacuum() {
+        let (mut session, _) = session("vacuum");
+        let values: Vec<String> = (0..300).map(|i| format!("({}, '{}')", i, "x".repeat(100))).collect();
+        run(
+            &mut session,
+            &[
+                "CREATE TABLE t (id INT, name TEXT)",
+                "CREATE UNIQUE INDEX t_id ON t (id)",
+                &format!("INSERT INTO t VALUES {}", values.join(", ")),
+                "DELETE FROM t WHERE id >= 10",
+                "BEGIN",
+            ],
+        );
+        assert!(session.execute("VACUUM").is_err());
+        run(&mut session, &["COMMIT", "VACUUM t", "VACUUM"]);
+        assert_eq!(rows(&mut session, "SELECT COUNT(*) FROM t"), vec![vec!["10"]]);
+        run(&mut session, &["INSERT INTO t VALUES (200, 'again')"]);
+        assert_eq!(rows(&mut session, "SELECT name FROM t WHERE id = 200"), vec![vec!["again"]]);
+    }
+
+    #[test]
+    fn test_vacuum_survives_restart() {
+        let (mut session, path) = session("vacuum_restart");
+        let values: Vec<String> = (0..600).map(|i| format!("({}, '{}')", i, "x".repeat(100))).collect();
+        // 回収するものが無くてもチェックポイントを取るので、全てのページがファイルに書き出される
+        run(&mut session, &["CREATE TABLE t (id INT, name TEXT)", &format!("INSERT INTO t VALUES {}", values.join(", ")), "VACUUM"]);
+        let size = std::fs::metadata(&path).unwrap().len();
+        // 末尾のページは切り詰められ、途中のページは解放済みになる
+        run(&mut session, &["DELETE FROM t WHERE id >= 100 AND id < 300 OR id >= 400", "VACUUM"]);
+        let vacuumed = std::fs::metadata(&path).unwrap().len();
+        assert!(vacuumed < size);
+
+        // ページを書き出さずにクラッシュしても、REDOでファイルが伸びない
+        drop(session);
+        let mut session = Session::open(&path, 32).unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), vacuumed);
+        assert_eq!(rows(&mut session, "SELECT COUNT(*) FROM t"), vec![vec!["200"]]);
+
+        // 開き直した後も解放済みのページを再利用するので、ファイルは伸びない
+        let values: Vec<String> = (1000..1100).map(|i| format!("({}, '{}')", i, "y".repeat(100))).collect();
+        run(&mut session, &[&format!("INSERT INTO t VALUES {}", values.join(", "))]);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), vacuumed);
+        session.close().unwrap();
+        let mut session = Session::open(&path, 32).unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), vacuumed);
+        assert_eq!(rows(&mut session, "SELECT COUNT(*) FROM t"), vec![vec!["300"]]);
+    }
+
+    #[test]
+    fn test_copy() {
+        let (mut session, path) = session("copy");
+        let csv_path = path.with_extension("csv");
+        let out_path = path.with_extension("out.csv");
+        std::fs::write(&csv_path, "name,id\n\"a, \"\"b\"\"\nc\",2\n,1\n\"\",3\n").unwrap();
+        run(&mut session, &["CREATE TABLE t (id INT, name TEXT, score FLOAT)", "CREATE UNIQUE INDEX t_id ON t (id)"]);
+        let copy = |session: &mut Session, sql: String| session.execute(&sql);
+        assert_eq!(
+            copy(&mut session, format!("COPY t FROM '{}'", csv_path.display())).unwrap(),
+            Output::Message("COPY 3".to_string())
+        );
+        assert_eq!(
+            rows(&mut session, "SELECT id, name, score FROM t WHERE id >= 1 ORDER BY id"),
+            vec![vec!["1", "NULL", "NULL"], vec!["2", "a, \"b\"\nc", "NULL"], vec!["3", "", "NULL"]]
+        );
+        copy(&mut session, format!("COPY t TO '{}'", out_path.display())).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&out_path).unwrap(),
+            "id,name,score\n2,\"a, \"\"b\"\"\nc\",\n1,,\n3,\"\",\n"
+        );
+
+        // 型の合わない値は行番号付きのエラーになり、何も読み込まれない
+        std::fs::write(&csv_path, "id,score\n4,1.5\n5,abc\n").unwrap();
+        let err = copy(&mut session, format!("COPY t FROM '{}'", csv_path.display())).unwrap_err();
+        assert_eq!(err.to_string(), "line 3: column score: invalid FLOAT value 'abc'");
+        std::fs::write(&csv_path, "id,rank\n4\n").unwrap();
+        assert!(copy(&mut session, format!("COPY t FROM '{}'", csv_path.display())).is_err());
+        // 一意制約の違反もエラーになる
+        std::fs::write(&csv_path, "id\n4\n1\n").unwrap();
+        assert!(copy(&mut session, format!("COPY t FROM '{}'", csv_path.display())).is_err());
+        assert_eq!(rows(&mut session, "SELECT COUNT(*) FROM t"), vec![vec!["3"]]);
+        let _ = std::fs::remove_file(&csv_path);
+        let _ = std::fs::remove_file(&out_path);
+    }
+}
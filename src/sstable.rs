@@ -0,0 +1,495 @@
+// SSTable(Sorted String Table)
+// LSM-treeのメモリ上の表(memtable)や、コンパクションの結果を書き出す読み取り専用のファイル
+// 一度書いたら変更せず、要らなくなったらファイルごと消す
+//
+// ファイルの中身: | データブロック ... | フィルター | インデックス | フッター |
+// - データブロック：エントリを(キーの昇順, 同じキーならシーケンス番号の降順)に並べ、block_sizeを超えたら区切る
+//   エントリ: | キーの長さ(u32) | シーケンス番号(u64) | 種類(u8) | 値の長さ(u32) | キー | 値 |
+// - フィルター：全てのキーのブルームフィルター
+// - インデックス：| 最小のキーの長さ(u32) | 最小のキー | ブロックの数(u32) | (最後のキーの長さ(u32), 最後のキー, オフセット(u64), サイズ(u32)) ... |
+// - フッター(32byte)：| フィルターのオフセット(u64) | サイズ(u32) | インデックスのオフセット(u64) | サイズ(u32) | マジックナンバー(8byte) |
+// ブロック・フィルター・インデックスの後ろにはCRC-32(u32)を付け、読む時に壊れていないか確かめる
+//
+// 開く時にはインデックスとフィルターだけを読み、データブロックは必要になった時に読む
+
+use std::cmp::Ordering;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+
+use crate::bloom::{self, BloomFilter};
+use crate::error::Error;
+use crate::wal::crc32;
+
+const MAGIC: &[u8; 8] = b"PRACTSST";
+const FOOTER_SIZE: usize = 8 + 4 + 8 + 4 + 8;
+const CRC_SIZE: usize = 4;
+
+const KIND_PUT: u8 = 1;
+const KIND_DELETE: u8 = 2;
+
+fn invalid_data(msg: &str) -> Error {
+    Error::Io(io::Error::new(io::ErrorKind::InvalidData, msg.to_string()))
+}
+
+/// キーの1つの版
+/// valueがNoneなら削除した印
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub key: Vec<u8>,
+    pub seq: u64,
+    pub value: Option<Vec<u8>>,
+}
+
+impl Entry {
+    /// SSTableやmemtableに並べる順序(キーの昇順, シーケンス番号の降順)
+    pub fn order(&self, other: &Entry) -> Ordering {
+        self.key.cmp(&other.key).then(other.seq.cmp(&self.seq))
+    }
+
+    /// 書き出した時のおおよそのサイズ
+    pub fn size(&self) -> usize {
+        4 + 8 + 1 + 4 + self.key.len() + self.value.as_ref().map_or(0, |value| value.len())
+    }
+
+    pub(crate) fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&(self.key.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&self.seq.to_le_bytes());
+        buf.push(if self.value.is_some() { KIND_PUT } else { KIND_DELETE });
+        let value = self.value.as_deref().unwrap_or(&[]);
+        buf.extend_from_slice(&(value.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&self.key);
+        buf.extend_from_slice(value);
+    }
+
+    pub(crate) fn decode(src: &mut &[u8]) -> Result<Self, Error> {
+        let key_len = take_u32(src)? as usize;
+        let seq = take_u64(src)?;
+        let kind = take(src, 1)?[0];
+        let value_len = take_u32(src)? as usize;
+        let key = take(src, key_len)?.to_vec();
+        let value = take(src, value_len)?;
+        let value = match kind {
+            KIND_PUT => Some(value.to_vec()),
+            KIND_DELETE => None,
+            _ => return Err(invalid_data("invalid entry kind")),
+        };
+        Ok(Self { key, seq, value })
+    }
+}
+
+fn take<'a>(src: &mut &'a [u8], len: usize) -> Result<&'a [u8], Error> {
+    if src.len() < len {
+        return Err(invalid_data("truncated table"));
+    }
+    let (head, rest) = src.split_at(len);
+    *src = rest;
+    Ok(head)
+}
+
+fn take_u32(src: &mut &[u8]) -> Result<u32, Error> {
+    let bytes = take(src, 4)?;
+    Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+fn take_u64(src: &mut &[u8]) -> Result<u64, Error> {
+    let mut buf = [0u8; 8];
+    buf.copy_from_slice(take(src, 8)?);
+    Ok(u64::from_le_bytes(buf))
+}
+
+// put_bytesで書いた長さ付きのバイト列を読む
+fn take_bytes(src: &mut &[u8]) -> Result<Vec<u8>, Error> {
+    let len = take_u32(src)? as usize;
+    Ok(take(src, len)?.to_vec())
+}
+
+fn put_bytes(bytes: &[u8], buf: &mut Vec<u8>) {
+    buf.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    buf.extend_from_slice(bytes);
+}
+
+// データブロックの場所
+#[derive(Debug)]
+struct BlockHandle {
+    last_key: Vec<u8>,
+    offset: u64,
+    size: u32,
+}
+
+/// エントリを順に受け取ってSSTableを書き出す
+pub struct TableBuilder {
+    writer: BufWriter<File>,
+    offset: u64,
+    block_size: usize,
+    bits_per_key: usize,
+    block: Vec<u8>,
+    index: Vec<BlockHandle>,
+    smallest: Option<Vec<u8>>,
+    last: Option<(Vec<u8>, u64)>,
+    // フィルターに入れるキーのハッシュ値(キーの数が分かってからフィルターを作る)
+    hashes: Vec<u64>,
+}
+
+impl TableBuilder {
+    pub fn create(path: impl AsRef<Path>, block_size: usize, bits_per_key: usize) -> Result<Self, Error> {
+        Ok(Self {
+            writer: BufWriter::new(File::create(path)?),
+            offset: 0,
+            block_size,
+            bits_per_key,
+            block: vec![],
+            index: vec![],
+            smallest: None,
+            last: None,
+            hashes: vec![],
+        })
+    }
+
+    /// エントリを追加する。エントリはEntry::orderの順に渡す
+    pub fn add(&mut self, entry: &Entry) -> Result<(), Error> {
+        if let Some((key, seq)) = &self.last {
+            assert!(
+                (key.as_slice(), std::cmp::Reverse(*seq)) < (entry.key.as_slice(), std::cmp::Reverse(entry.seq)),
+                "entries must be added in order"
+            );
+        }
+        if self.last.as_ref().is_none_or(|(key, _)| *key != entry.key) {
+            self.hashes.push(bloom::hash(&entry.key));
+        }
+        if self.smallest.is_none() {
+            self.smallest = Some(entry.key.clone());
+        }
+        entry.encode(&mut self.block);
+        self.last = Some((entry.key.clone(), entry.seq));
+        if self.block.len() >= self.block_size {
+            self.finish_block()?;
+        }
+        Ok(())
+    }
+
+    /// ここまでに書いた(書く予定の)ファイルのサイズ
+    pub fn estimated_size(&self) -> u64 {
+        self.offset + self.block.len() as u64
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.last.is_none()
+    }
+
+    // データを書き、後ろにCRCを付ける。書いた位置とサイズを返す
+    fn write_with_crc(&mut self, data: &[u8]) -> Result<(u64, u32), Error> {
+        let offset = self.offset;
+        self.writer.write_all(data)?;
+        self.writer.write_all(&crc32(data).to_le_bytes())?;
+        self.offset += (data.len() + CRC_SIZE) as u64;
+        Ok((offset, data.len() as u32))
+    }
+
+    fn finish_block(&mut self) -> Result<(), Error> {
+        if self.block.is_empty() {
+            return Ok(());
+        }
+        let block = std::mem::take(&mut self.block);
+        let (offset, size) = self.write_with_crc(&block)?;
+        let last_key = self.last.as_ref().unwrap().0.clone();
+        self.index.push(BlockHandle { last_key, offset, size });
+        Ok(())
+    }
+
+    /// 残りを書き出してfsyncし、ファイルのサイズを返す
+    pub fn finish(mut self) -> Result<u64, Error> {
+        self.finish_block()?;
+        let mut filter = BloomFilter::new(self.hashes.len(), self.bits_per_key);
+        for &hash in &self.hashes {
+            filter.insert_hash(hash);
+        }
+        let (filter_offset, filter_size) = self.write_with_crc(&filter.encode())?;
+        let mut index = vec![];
+        put_bytes(self.smallest.as_deref().unwrap_or(&[]), &mut index);
+        index.extend_from_slice(&(self.index.len() as u32).to_le_bytes());
+        for handle in &self.index {
+            put_bytes(&handle.last_key, &mut index);
+            index.extend_from_slice(&handle.offset.to_le_bytes());
+            index.extend_from_slice(&handle.size.to_le_bytes());
+        }
+        let (index_offset, index_size) = self.write_with_crc(&index)?;
+        let mut footer = vec![];
+        footer.extend_from_slice(&filter_offset.to_le_bytes());
+        footer.extend_from_slice(&filter_size.to_le_bytes());
+        footer.extend_from_slice(&index_offset.to_le_bytes());
+        footer.extend_from_slice(&index_size.to_le_bytes());
+        footer.extend_from_slice(MAGIC);
+        self.writer.write_all(&footer)?;
+        self.offset += FOOTER_SIZE as u64;
+        let file = self.writer.into_inner().map_err(|err| err.into_error())?;
+        file.sync_all()?;
+        Ok(self.offset)
+    }
+}
+
+/// 読み取り用に開いたSSTable
+pub struct Table {
+    pub number: u64,
+    pub size: u64,
+    pub smallest: Vec<u8>,
+    pub largest: Vec<u8>,
+    file: File,
+    index: Vec<BlockHandle>,
+    filter: BloomFilter,
+}
+
+impl Table {
+    /// ファイルを開き、インデックスとフィルターを読む
+    pub fn open(path: impl AsRef<Path>, number: u64) -> Result<Self, Error> {
+        let file = File::open(path)?;
+        let size = file.metadata()?.len();
+        if size < FOOTER_SIZE as u64 {
+            return Err(invalid_data("table is too small"));
+        }
+        let mut footer = [0u8; FOOTER_SIZE];
+        file.read_exact_at(&mut footer, size - FOOTER_SIZE as u64)?;
+        if &footer[FOOTER_SIZE - 8..] != MAGIC {
+            return Err(invalid_data("not a table file"));
+        }
+        let mut src = &footer[..];
+        let filter_offset = take_u64(&mut src)?;
+        let filter_size = take_u32(&mut src)?;
+        let index_offset = take_u64(&mut src)?;
+        let index_size = take_u32(&mut src)?;
+        // フッターが壊れていても、ファイルの外を読んだり、大きすぎる領域を確保したりしない
+        let body_size = size - FOOTER_SIZE as u64;
+        for (offset, len) in [(filter_offset, filter_size), (index_offset, index_size)] {
+            if offset.checked_add(len as u64 + CRC_SIZE as u64).is_none_or(|end| end > body_size) {
+                return Err(invalid_data("table footer is out of range"));
+            }
+        }
+        let filter = BloomFilter::decode(&read_with_crc(&file, filter_offset, filter_size)?).ok_or_else(|| invalid_data("invalid filter"))?;
+
+        let index_bytes = read_with_crc(&file, index_offset, index_size)?;
+        let mut src = &index_bytes[..];
+        let smallest = take_bytes(&mut src)?;
+        let count = take_u32(&mut src)?;
+        let mut index = vec![];
+        for _ in 0..count {
+            let last_key = take_bytes(&mut src)?;
+            let offset = take_u64(&mut src)?;
+            let size = take_u32(&mut src)?;
+            index.push(BlockHandle { last_key, offset, size });
+        }
+        let largest = index.last().map(|handle| handle.last_key.clone()).unwrap_or_default();
+        Ok(Self {
+            number,
+            size,
+            smallest,
+            largest,
+            file,
+            index,
+            filter,
+        })
+    }
+
+    fn read_block(&self, i: usize) -> Result<Vec<Entry>, Error> {
+        let handle = &self.index[i];
+        let bytes = read_with_crc(&self.file, handle.offset, handle.size)?;
+        let mut src = &bytes[..];
+        let mut entries = vec![];
+        while !src.is_empty() {
+            entries.push(Entry::decode(&mut src)?);
+        }
+        Ok(entries)
+    }
+
+    /// keyの範囲が[smallest, largest]と重なるか
+    pub fn overlaps(&self, smallest: &[u8], largest: &[u8]) -> bool {
+        self.smallest.as_slice() <= largest && smallest <= self.largest.as_slice()
+    }
+
+    /// keyのシーケンス番号がseq以下の版のうち一番新しいもの。このファイルに無ければNone
+    pub fn get(&self, key: &[u8], seq: u64) -> Result<Option<Entry>, Error> {
+        if key < self.smallest.as_slice() || key > self.largest.as_slice() || !self.filter.may_contain(key) {
+            return Ok(None);
+        }
+        // 同じキーの版がブロックをまたぐこともあるので、キーが変わるまで読み進める
+        for entry in self.iter_from(Some(key)) {
+            let entry = entry?;
+            if entry.key != key {
+                break;
+            }
+            if entry.seq <= seq {
+                return Ok(Some(entry));
+            }
+        }
+        Ok(None)
+    }
+
+    /// keyの最初の版(Noneなら先頭)からエントリを順に返すイテレータ
+    pub fn iter_from(&self, key: Option<&[u8]>) -> TableIter<'_> {
+        let block = match key {
+            Some(key) => self.index.partition_point(|handle| handle.last_key.as_slice() < key),
+            None => 0,
+        };
+        TableIter {
+            table: self,
+            block,
+            start: key.map(|key| key.to_vec()),
+            entries: Vec::new().into_iter(),
+        }
+    }
+}
+
+fn read_with_crc(file: &File, offset: u64, size: u32) -> Result<Vec<u8>, Error> {
+    let mut bytes = vec![0u8; size as usize + CRC_SIZE];
+    file.read_exact_at(&mut bytes, offset)?;
+    let crc = bytes.split_off(size as usize);
+    if crc32(&bytes).to_le_bytes() != crc.as_slice() {
+        return Err(invalid_data("table checksum mismatch"));
+    }
+    Ok(bytes)
+}
+
+/// SSTableのエントリを順に返すイテレータ
+/// データブロックを1つずつ読む
+pub struct TableIter<'a> {
+    table: &'a Table,
+    // 次に読むブロック
+    block: usize,
+    // 最初に読むブロックで、これより小さいキーを読み飛ばす
+    start: Option<Vec<u8>>,
+    entries: std::vec::IntoIter<Entry>,
+}
+
+impl<'a> Iterator for TableIter<'a> {
+    type Item = Result<Entry, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(entry) = self.entries.next() {
+                return Some(Ok(entry));
+            }
+            if self.block >= self.table.index.len() {
+                return None;
+            }
+            let mut entries = match self.table.read_block(self.block) {
+                Ok(entries) => entries,
+                Err(err) => {
+                    self.block = self.table.index.len();
+                    return Some(Err(err));
+                }
+            };
+            self.block += 1;
+            if let Some(start) = self.start.take() {
+                let pos = entries.partition_point(|entry| entry.key < start);
+                entries.drain(..pos);
+            }
+            self.entries = entries.into_iter();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_table() {
+        let path = std::env::temp_dir().join(format!("sstable_test_{}", std::process::id()));
+        let mut builder = TableBuilder::create(&path, 256, 10).unwrap();
+        let mut expected = vec![];
+        for i in 0..1000u32 {
+            let key = format!("key{:05}", i * 2).into_bytes();
+            // 偶数番目のキーには2つの版を入れる
+            let versions: &[u64] = if i % 2 == 0 { &[20, 10] } else { &[5] };
+            for &seq in versions {
+                let value = if i % 7 == 0 && seq == 20 { None } else { Some(format!("value{}-{}", i, seq).into_bytes()) };
+                let entry = Entry { key: key.clone(), seq, value };
+                builder.add(&entry).unwrap();
+                expected.push(entry);
+            }
+        }
+        let size = builder.finish().unwrap();
+
+        let table = Table::open(&path, 1).unwrap();
+        assert_eq!(table.size, size);
+        assert!(table.index.len() > 10);
+        assert_eq!(table.smallest, b"key00000".to_vec());
+        assert_eq!(table.largest, b"key01998".to_vec());
+        assert_eq!(table.iter_from(None).map(|entry| entry.unwrap()).collect::<Vec<_>>(), expected);
+
+        // シーケンス番号で版を選ぶ
+        assert_eq!(table.get(b"key00008", 30).unwrap().unwrap().value, Some(b"value4-20".to_vec()));
+        assert_eq!(table.get(b"key00008", 15).unwrap().unwrap().value, Some(b"value4-10".to_vec()));
+        assert_eq!(table.get(b"key00008", 5).unwrap(), None);
+        assert_eq!(table.get(b"key00000", 30).unwrap().unwrap().value, None);
+        assert_eq!(table.get(b"key00001", 30).unwrap(), None);
+        assert_eq!(table.get(b"zzz", 30).unwrap(), None);
+
+        // 途中のキーから読む
+        let from: Vec<Vec<u8>> = table.iter_from(Some(b"key01995")).map(|entry| entry.unwrap().key).collect();
+        assert_eq!(from, vec![b"key01996".to_vec(), b"key01996".to_vec(), b"key01998".to_vec()]);
+        assert!(table.overlaps(b"a", b"key00000"));
+        assert!(!table.overlaps(b"key01999", b"z"));
+
+        // 壊れたファイルは読めない
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[10] ^= 0xff;
+        std::fs::write(&path, &bytes).unwrap();
+        let table = Table::open(&path, 1).unwrap();
+        assert!(table.iter_from(None).any(|entry| entry.is_err()));
+        let _ = std::fs::remove_file(&path);
+    }
+
+    // 小さなSSTableを書いて、その中身を返す
+    fn build(path: &Path) -> Vec<u8> {
+        let mut builder = TableBuilder::create(path, 64, 10).unwrap();
+        for i in 0..100u32 {
+            let entry = Entry {
+                key: format!("key{:03}", i).into_bytes(),
+                seq: 1,
+                value: Some(vec![i as u8; 20]),
+            };
+            builder.add(&entry).unwrap();
+        }
+        builder.finish().unwrap();
+        std::fs::read(path).unwrap()
+    }
+
+    #[test]
+    fn test_corrupt_table() {
+        let path = std::env::temp_dir().join(format!("sstable_test_corrupt_{}", std::process::id()));
+        let bytes = build(&path);
+        let open = |bytes: &[u8]| {
+            std::fs::write(&path, bytes).unwrap();
+            Table::open(&path, 1)
+        };
+        let footer = bytes.len() - FOOTER_SIZE;
+
+        // フッターより短いファイル・末尾が欠けたファイル・先頭が欠けたファイル
+        assert!(open(&bytes[..FOOTER_SIZE - 1]).is_err());
+        assert!(open(&bytes[..bytes.len() - 1]).is_err());
+        assert!(open(&bytes[100..]).is_err());
+        // フッターのサイズが壊れていて、ファイルより大きな領域を指す
+        let mut broken = bytes.clone();
+        broken[footer + 8..footer + 12].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(open(&broken).is_err());
+        // インデックスが壊れている
+        let mut broken = bytes.clone();
+        broken[footer - CRC_SIZE - 1] ^= 0xff;
+        assert!(open(&broken).is_err());
+
+        // データブロックが壊れていると、そのブロックのキーを読む時にエラーになる
+        let mut broken = bytes.clone();
+        broken[20] ^= 0xff;
+        let table = open(&broken).unwrap();
+        assert!(table.get(b"key000", 1).is_err());
+        assert!(table.get(b"key099", 1).unwrap().is_some());
+
+        // 開いた後でファイルが短くなると、ブロックを最後まで読めずにエラーになる
+        let table = open(&bytes).unwrap();
+        std::fs::OpenOptions::new().write(true).open(&path).unwrap().set_len(100).unwrap();
+        assert!(table.get(b"key099", 1).is_err());
+        assert!(table.get(b"key000", 1).unwrap().is_some());
+        let _ = std::fs::remove_file(&path);
+    }
+}
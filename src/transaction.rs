@@ -0,0 +1,397 @@
+// トランザクション
+// begin → (テーブル・インデックスの操作) → commit または rollback
+//
+// テーブルやインデックスの操作は全てTransactionを受け取り、変更をWALに書く
+// rollbackはWALを遡って変更を取り消す(クラッシュ後のリカバリと同じ仕組み)
+//
+// スナップショット分離
+// beginした時点でコミット済みのトランザクションの変更だけが見える
+// ロールバックしたトランザクションの変更はWALで取り消されて残らないので、
+// 「自分より前に始まっていて、beginした時点で実行中でなかった」トランザクションはコミット済みと分かる
+// リカバリ後は実行中のトランザクションが無いので、それまでのトランザクションは全てコミット済み
+
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::btree;
+use crate::buffer_pool::{BufferPoolManager, Error};
+use crate::disk_manager::{DiskManager, PageId};
+use crate::hash_index;
+use crate::heap::{self, RecordId};
+use crate::lock::{LockManager, LockMode, Resource};
+use crate::wal::{self, LogBody, LogManager, LogicalUndo, Lsn, RecoveryReport, TxnId, TxnLog};
+
+// WALがこの大きさを超えたら、チェックポイントを取ってログを切り詰める
+const CHECKPOINT_LOG_SIZE: u64 = 16 * 1024 * 1024;
+
+// Operationレコードの取り消し方法の先頭1byteで、どのデータ構造の操作かを区別する
+pub(crate) const UNDO_HEAP: u8 = 1;
+pub(crate) const UNDO_BTREE: u8 = 2;
+pub(crate) const UNDO_HASH: u8 = 3;
+
+/// beginした時点で、どのトランザクションの変更が見えるか
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    txn_id: TxnId,
+    // これより小さいIDのトランザクションは、beginした時点で全て終わっていた
+    xmin: TxnId,
+    // beginした時点で実行中だったトランザクション
+    active: BTreeSet<TxnId>,
+}
+
+impl Snapshot {
+    /// txn_idの変更が見えるかどうか
+    pub fn sees(&self, txn_id: TxnId) -> bool {
+        txn_id == self.txn_id || (txn_id < self.txn_id && !self.active.contains(&txn_id))
+    }
+
+    pub fn xmin(&self) -> TxnId {
+        self.xmin
+    }
+}
+
+pub struct Transaction {
+    log: TxnLog,
+    snapshot: Snapshot,
+    // ロックマネージャーを使うTransactionManagerで始めた場合だけある
+    locks: Option<Arc<LockManager>>,
+}
+
+impl Transaction {
+    pub fn id(&self) -> TxnId {
+        self.log.txn_id()
+    }
+
+    pub fn snapshot(&self) -> &Snapshot {
+        &self.snapshot
+    }
+
+    /// このトランザクションのログ
+    /// テーブルやインデックスはこれを使ってページを書き換える
+    pub fn log(&self) -> &TxnLog {
+        &self.log
+    }
+
+    /// テーブル(先頭ページのIDで区別する)にロックを掛ける
+    /// ロックマネージャーを使わない場合は何もしない
+    pub fn lock_table(&self, table: PageId, mode: LockMode) -> Result<(), Error> {
+        match &self.locks {
+            Some(locks) => locks.lock(self.id(), Resource::Table(table), mode),
+            None => Ok(()),
+        }
+    }
+
+    /// レコードにロックを掛ける。先にテーブルへインテンションロックを掛ける
+    /// ロックマネージャーを使わない場合は何もしない
+    pub fn lock_record(&self, table: PageId, rid: RecordId, mode: LockMode) -> Result<(), Error> {
+        match &self.locks {
+            Some(locks) => locks.lock_record(self.id(), table, rid, mode),
+            None => Ok(()),
+        }
+    }
+}
+
+pub struct TransactionManager {
+    wal: Arc<LogManager>,
+    next_txn_id: Cell<u64>,
+    // 実行中のトランザクションと、そのスナップショットのxmin
+    active: RefCell<BTreeMap<TxnId, TxnId>>,
+    // シリアライザブルに実行したい場合に使うロック。コミット・ロールバックの最後に解放する
+    locks: Option<Arc<LockManager>>,
+}
+
+impl TransactionManager {
+    /// ロックを掛けずに、スナップショット分離だけでトランザクションを実行する
+    /// next_txn_idは、リカバリで見つかった最大のトランザクションIDより大きくする
+    pub fn new(wal: Arc<LogManager>, next_txn_id: TxnId) -> Self {
+        Self {
+            wal,
+            next_txn_id: Cell::new(next_txn_id.0),
+            active: RefCell::new(BTreeMap::new()),
+            locks: None,
+        }
+    }
+
+    /// テーブルやレコードを読み書きする時に、locksでロックを掛ける(厳密な2相ロック)
+    /// ロックを待つ間はスレッドが止まるので、1つのスレッドで複数のトランザクションを交互に実行してはいけない
+    pub fn with_lock_manager(wal: Arc<LogManager>, next_txn_id: TxnId, locks: Arc<LockManager>) -> Self {
+        Self {
+            locks: Some(locks),
+            ..Self::new(wal, next_txn_id)
+        }
+    }
+
+    /// ロックマネージャー(with_lock_managerで作った場合だけある)
+    pub fn lock_manager(&self) -> Option<&Arc<LockManager>> {
+        self.locks.as_ref()
+    }
+
+    pub fn begin(&self) -> Result<Transaction, Error> {
+        let txn_id = TxnId(self.next_txn_id.get());
+        let log = TxnLog::new(Arc::clone(&self.wal), txn_id, Lsn::INVALID);
+        log.append(LogBody::Begin)?;
+        Ok(self.start(log))
+    }
+
+    /// 読むだけのトランザクションを開始する
+    /// WALに何も書かないのでbeginより軽いが、テーブルやインデックスに書き込んではいけない
+    /// 終わったらfinish_read_onlyを呼ぶ(実行中の間はhorizonが進まない)
+    pub fn begin_read_only(&self) -> Transaction {
+        let txn_id = TxnId(self.next_txn_id.get());
+        self.start(TxnLog::new(Arc::clone(&self.wal), txn_id, Lsn::INVALID))
+    }
+
+    /// begin_read_onlyで開始したトランザクションを終える
+    pub fn finish_read_only(&self, txn: Transaction) {
+        debug_assert_eq!(txn.log.last_lsn(), Lsn::INVALID, "read-only transaction must not write");
+        self.active.borrow_mut().remove(&txn.id());
+        self.unlock_all(&txn);
+    }
+
+    // スナップショットを取って実行中にする
+    fn start(&self, log: TxnLog) -> Transaction {
+        let txn_id = log.txn_id();
+        self.next_txn_id.set(txn_id.0 + 1);
+        let mut active = self.active.borrow_mut();
+        let snapshot = Snapshot {
+            txn_id,
+            xmin: active.keys().next().copied().unwrap_or(txn_id),
+            active: active.keys().copied().collect(),
+        };
+        active.insert(txn_id, snapshot.xmin);
+        Transaction {
+            log,
+            snapshot,
+            locks: self.locks.clone(),
+        }
+    }
+
+    fn unlock_all(&self, txn: &Transaction) {
+        if let Some(locks) = &self.locks {
+            locks.unlock_all(txn.id());
+        }
+    }
+
+    /// これより小さいIDのトランザクションの変更は、実行中の全てのスナップショットから見える
+    /// ガベージコレクションはこれより前に消された版を回収できる
+    pub fn horizon(&self) -> TxnId {
+        let active = self.active.borrow();
+        let next = TxnId(self.next_txn_id.get());
+        active.values().copied().min().unwrap_or(next)
+    }
+
+    /// 実行中のトランザクションのID
+    pub fn active_txn_ids(&self) -> Vec<TxnId> {
+        self.active.borrow().keys().copied().collect()
+    }
+
+    /// コミットする
+    /// Commitレコードが永続化されてから戻る(グループコミット)
+    pub fn commit(&self, txn: Transaction) -> Result<(), Error> {
+        let lsn = txn.log.append(LogBody::Commit)?;
+        self.wal.flush_to(lsn)?;
+        txn.log.append(LogBody::End)?;
+        self.active.borrow_mut().remove(&txn.id());
+        self.unlock_all(&txn);
+        Ok(())
+    }
+
+    /// ロールバックする
+    /// テーブル・インデックスへの変更を新しい順に取り消す
+    pub fn rollback(&self, bufmgr: &mut BufferPoolManager, txn: Transaction) -> Result<(), Error> {
+        txn.log.append(LogBody::Abort)?;
+        wal::undo(bufmgr, &[(txn.id(), txn.log.last_lsn())], &UndoDispatcher)?;
+        // 変更を全て取り消してから実行中でなくする(取り消す前に他から見えてはいけない)
+        self.active.borrow_mut().remove(&txn.id());
+        self.unlock_all(&txn);
+        Ok(())
+    }
+
+    /// WALが大きくなっていれば、チェックポイントを取ってログを切り詰める
+    /// 実行中のトランザクションがあっても取れるが、そのUNDOに要るレコードは残る
+    pub fn checkpoint_if_needed(&self, bufmgr: &mut BufferPoolManager) -> Result<(), Error> {
+        if self.wal.size() >= CHECKPOINT_LOG_SIZE {
+            wal::checkpoint(bufmgr)?;
+        }
+        Ok(())
+    }
+}
+
+/// Operationレコードの取り消し方法を、操作したデータ構造に振り分ける
+pub struct UndoDispatcher;
+
+impl LogicalUndo for UndoDispatcher {
+    fn undo(&self, bufmgr: &mut BufferPoolManager, log: &TxnLog, undo: &[u8]) -> Result<(), Error> {
+        match undo.split_first() {
+            Some((&UNDO_HEAP, rest)) => heap::undo(bufmgr, log, rest),
+            Some((&UNDO_BTREE, rest)) => btree::undo(bufmgr, log, rest),
+            Some((&UNDO_HASH, rest)) => hash_index::undo(bufmgr, log, rest),
+            _ => Err(Error::Io(std::io::Error::new(std::io::ErrorKind::InvalidData, "unknown undo record"))),
+        }
+    }
+}
+
+/// ヒープファイルを開いてクラッシュリカバリを行い、バッファプールマネージャーとトランザクションマネージャーを返す
+pub fn open(heap_file_path: impl AsRef<Path>, pool_size: usize) -> Result<(BufferPoolManager, TransactionManager, RecoveryReport), Error> {
+    let (bufmgr, report) = wal::open(heap_file_path, pool_size, &UndoDispatcher)?;
+    Ok(with_txn_manager(bufmgr, report))
+}
+
+/// 開いたヒープファイルとログファイル(仮想ディスクなど)でopenと同じことをする
+pub fn open_with(disk: DiskManager, wal: LogManager, pool_size: usize) -> Result<(BufferPoolManager, TransactionManager, RecoveryReport), Error> {
+    let (bufmgr, report) = wal::open_with(disk, wal, pool_size, &UndoDispatcher)?;
+    Ok(with_txn_manager(bufmgr, report))
+}
+
+fn with_txn_manager(bufmgr: BufferPoolManager, report: RecoveryReport) -> (BufferPoolManager, TransactionManager, RecoveryReport) {
+    let wal = Arc::clone(bufmgr.wal().unwrap());
+    let txn_mgr = TransactionManager::new(wal, TxnId(report.max_txn_id.0 + 1));
+    (bufmgr, txn_mgr, report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::btree::{BTree, SearchMode};
+    use crate::heap::HeapTable;
+    use crate::lock::LockMode::*;
+    use std::path::PathBuf;
+    use std::sync::mpsc;
+    use std::thread;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("txn_test_{}_{}", std::process::id(), name));
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(wal::log_path(&path));
+        path
+    }
+
+    fn keys(bufmgr: &mut BufferPoolManager, txn: &Transaction, index: &BTree) -> Vec<Vec<u8>> {
+        let mut iter = index.iter(bufmgr, txn, SearchMode::Start).unwrap();
+        let mut keys = vec![];
+        while let Some((key, _)) = iter.next(bufmgr).unwrap() {
+            keys.push(key);
+        }
+        keys
+    }
+
+    #[test]
+    fn test_commit_and_rollback() {
+        let path = temp_path("rollback");
+        let (mut bufmgr, txn_mgr, _) = open(&path, 16).unwrap();
+
+        let txn = txn_mgr.begin().unwrap();
+        let table = HeapTable::create(&mut bufmgr, &txn).unwrap();
+        let index = BTree::create(&mut bufmgr, &txn).unwrap();
+        let rid = table.insert(&mut bufmgr, &txn, b"alice").unwrap();
+        index.insert(&mut bufmgr, &txn, b"alice", b"1").unwrap();
+        txn_mgr.commit(txn).unwrap();
+
+        // 別のトランザクションが同じページを書き換えた後でロールバックしても、そのトランザクションの変更だけが消える
+        let txn1 = txn_mgr.begin().unwrap();
+        let txn2 = txn_mgr.begin().unwrap();
+        let rid1 = table.insert(&mut bufmgr, &txn1, b"bob").unwrap();
+        let rid2 = table.insert(&mut bufmgr, &txn2, b"carol").unwrap();
+        table.update(&mut bufmgr, &txn1, rid, b"alice2").unwrap();
+        index.insert(&mut bufmgr, &txn1, b"bob", b"2").unwrap();
+        index.insert(&mut bufmgr, &txn2, b"carol", b"3").unwrap();
+        index.delete(&mut bufmgr, &txn1, b"alice").unwrap();
+        txn_mgr.rollback(&mut bufmgr, txn1).unwrap();
+        txn_mgr.commit(txn2).unwrap();
+
+        let txn = txn_mgr.begin().unwrap();
+        assert_eq!(table.get(&mut bufmgr, &txn, rid).unwrap(), Some(b"alice".to_vec()));
+        assert_eq!(table.get(&mut bufmgr, &txn, rid1).unwrap(), None);
+        assert_eq!(table.get(&mut bufmgr, &txn, rid2).unwrap(), Some(b"carol".to_vec()));
+        assert_eq!(keys(&mut bufmgr, &txn, &index), vec![b"alice".to_vec(), b"carol".to_vec()]);
+    }
+
+    #[test]
+    fn test_rollback_index_split_and_recover() {
+        let path = temp_path("split");
+        let (table, index) = {
+            let (mut bufmgr, txn_mgr, _) = open(&path, 16).unwrap();
+            let txn = txn_mgr.begin().unwrap();
+            let table = HeapTable::create(&mut bufmgr, &txn).unwrap();
+            let index = BTree::create(&mut bufmgr, &txn).unwrap();
+            for i in 0..100u32 {
+                index.insert(&mut bufmgr, &txn, &i.to_be_bytes(), &[0u8; 100]).unwrap();
+            }
+            txn_mgr.commit(txn).unwrap();
+
+            // ページ分割を何度も起こしてからロールバックする
+            let txn = txn_mgr.begin().unwrap();
+            for i in 100..400u32 {
+                index.insert(&mut bufmgr, &txn, &i.to_be_bytes(), &[1u8; 100]).unwrap();
+                table.insert(&mut bufmgr, &txn, &[1u8; 100]).unwrap();
+            }
+            txn_mgr.rollback(&mut bufmgr, txn).unwrap();
+            let txn = txn_mgr.begin().unwrap();
+            assert_eq!(keys(&mut bufmgr, &txn, &index).len(), 100);
+
+            // コミットしないままクラッシュする
+            for i in 400..500u32 {
+                index.insert(&mut bufmgr, &txn, &i.to_be_bytes(), &[2u8; 100]).unwrap();
+            }
+            bufmgr.flush().unwrap();
+            (table, index)
+        };
+
+        let (mut bufmgr, txn_mgr, report) = open(&path, 16).unwrap();
+        assert_eq!(report.losers.len(), 1);
+        let txn = txn_mgr.begin().unwrap();
+        let keys = keys(&mut bufmgr, &txn, &index);
+        assert_eq!(keys.len(), 100);
+        assert_eq!(keys[99], 99u32.to_be_bytes().to_vec());
+        let mut iter = table.scan();
+        assert!(iter.next(&mut bufmgr, &txn).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_lock_manager() {
+        let path = temp_path("locks");
+        let (mut bufmgr, _, report) = open(&path, 16).unwrap();
+        let locks = Arc::new(LockManager::new());
+        let wal = Arc::clone(bufmgr.wal().unwrap());
+        let txn_mgr = TransactionManager::with_lock_manager(wal, TxnId(report.max_txn_id.0 + 1), Arc::clone(&locks));
+        let txn = txn_mgr.begin().unwrap();
+        let table = HeapTable::create(&mut bufmgr, &txn).unwrap();
+        let rid = table.insert(&mut bufmgr, &txn, b"alice").unwrap();
+        assert_eq!(locks.held(txn.id(), Resource::Record(rid)), Some(Exclusive));
+        let txn_id = txn.id();
+        txn_mgr.commit(txn).unwrap();
+        assert_eq!(locks.held(txn_id, Resource::Record(rid)), None);
+
+        // 読むとS、書くとXのロックをレコードに掛け、テーブルにはインテンションロックを掛ける
+        let txn = txn_mgr.begin().unwrap();
+        table.get(&mut bufmgr, &txn, rid).unwrap();
+        assert_eq!(locks.held(txn.id(), Resource::Record(rid)), Some(Shared));
+        assert_eq!(locks.held(txn.id(), Resource::Table(table.first_page_id)), Some(IntentionShared));
+        table.update(&mut bufmgr, &txn, rid, b"alice2").unwrap();
+        assert_eq!(locks.held(txn.id(), Resource::Record(rid)), Some(Exclusive));
+        assert_eq!(locks.held(txn.id(), Resource::Table(table.first_page_id)), Some(IntentionExclusive));
+
+        // 他のトランザクションは、コミットするまでそのレコードを読めない
+        thread::scope(|s| {
+            let (granted_tx, granted) = mpsc::channel();
+            let locks = &locks;
+            s.spawn(move || {
+                locks.lock_record(TxnId(100), table.first_page_id, rid, Shared).unwrap();
+                granted_tx.send(()).unwrap();
+                locks.unlock_all(TxnId(100));
+            });
+            assert!(granted.try_recv().is_err());
+            txn_mgr.commit(txn).unwrap();
+            granted.recv().unwrap();
+        });
+
+        // スキャンはテーブル全体にSロックを掛ける
+        let txn = txn_mgr.begin().unwrap();
+        let mut iter = table.scan();
+        assert_eq!(iter.next(&mut bufmgr, &txn).unwrap(), Some((rid, b"alice2".to_vec())));
+        assert_eq!(locks.held(txn.id(), Resource::Table(table.first_page_id)), Some(Shared));
+        txn_mgr.rollback(&mut bufmgr, txn).unwrap();
+    }
+}
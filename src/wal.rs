@@ -0,0 +1,1452 @@
+// Write-Ahead Log(WAL)
+// ページを書き換える前に「何をどう書き換えたか」をログファイルに追記しておき、
+// クラッシュ後はログを読み直してヒープファイルを正しい状態に戻す(ARIES方式のリカバリ)
+//
+// - ログレコードにはLSN(Log Sequence Number)を振る。LSNはログの先頭からのバイトオフセット
+// - ページの先頭にはそのページを最後に更新したレコードのLSN(page LSN)を書く
+// - ダーティページをディスクに書き戻す前に、page LSNまでのログを永続化する(WALルール)
+// - リカバリは analysis(勝者・敗者の判定) → redo(歴史の再現) → undo(敗者の取り消し) の3段階
+// - テーブルやインデックスの1操作(複数ページにまたがることもある)は、物理的なUpdateレコードの後に
+//   論理的な取り消し方法を書いたOperationレコードを書く。UNDOではOperationレコードを見つけたら
+//   論理的に取り消し、その操作のUpdateレコードは飛ばす(ARIESのnested top action)
+//   同じページを他のトランザクションが後から書き換えていても、物理的に書き戻すと壊れてしまうため
+// - チェックポイントでは全てのダーティページを書き出してから、実行中のトランザクションの一覧と
+//   解放済みのページを書く。その後のページの解放とファイルの切り詰めもログに書き、リカバリで復元する
+//   リカバリは最後のチェックポイントから始めればよいので、それより前のログは
+//   (実行中のトランザクションのUNDOに要るレコードを除いて)ファイルから切り詰める
+
+use std::cell::Cell;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs::OpenOptions;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
+
+use crate::buffer_pool::{Buffer, BufferPool, BufferPoolManager, Error, PAGE_LSN_SIZE};
+use crate::disk_manager::{DiskManager, PageId, StorageFile, PAGE_SIZE};
+
+// ログファイルの先頭に書くマジックナンバー
+const LOG_MAGIC: &[u8; 8] = b"YORIWAL2";
+
+// ヘッダー: | マジックナンバー | 先頭のレコードのLSN u64 | 先頭のレコードのオフセット u64 |
+// 切り詰めた後もLSNは増え続けるので、ファイル内のオフセットはヘッダーの2つの値から求める
+// ヘッダーの分だけ最初のレコードのLSNが0より大きくなるので、Lsn(0)を「レコード無し」として使える
+const LOG_HEADER_SIZE: u64 = 24;
+
+// ログを切り詰める途中で、レコードの終わりを表す印
+// 長さ0でチェックサムが合わないので、ここでレコードの読み込みが止まる
+const END_MARK: [u8; 8] = [0, 0, 0, 0, 0xff, 0xff, 0xff, 0xff];
+
+// ログバッファがこのサイズを超えたら、コミットを待たずにディスクに書き出す
+const LOG_BUFFER_SIZE: usize = 64 * 1024;
+
+// レコードの種類
+const KIND_BEGIN: u8 = 1;
+const KIND_UPDATE: u8 = 2;
+const KIND_COMPENSATION: u8 = 3;
+const KIND_COMMIT: u8 = 4;
+const KIND_ABORT: u8 = 5;
+const KIND_END: u8 = 6;
+const KIND_OPERATION: u8 = 7;
+const KIND_DUMMY_CLR: u8 = 8;
+const KIND_CHECKPOINT: u8 = 9;
+const KIND_FREE_PAGE: u8 = 10;
+const KIND_TRUNCATE: u8 = 11;
+
+/// ログレコードの番号(ログの先頭からのオフセット)
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Lsn(pub u64);
+
+impl Lsn {
+    // 「レコード無し」を表す
+    pub const INVALID: Lsn = Lsn(0);
+}
+
+/// トランザクションID
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TxnId(pub u64);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LogBody {
+    Begin,
+    // ページのoffsetバイト目からをbeforeからafterに書き換えた
+    Update {
+        page_id: PageId,
+        offset: u16,
+        before: Vec<u8>,
+        after: Vec<u8>,
+    },
+    // UNDOで書き戻したことを表すCLR(Compensation Log Record)
+    // REDOだけを行い、UNDOはしない
+    // undo_nextは次にUNDOすべきレコード
+    Compensation {
+        page_id: PageId,
+        offset: u16,
+        after: Vec<u8>,
+        undo_next: Lsn,
+    },
+    Commit,
+    // ロールバック開始
+    Abort,
+    // トランザクションの後始末が全て終わった
+    End,
+    // 論理的な操作が完了した
+    // undoはその操作を取り消す方法(LogicalUndoが解釈する)
+    // UNDOする際はundoを実行した後、undo_nextまで飛ぶ(操作中のUpdateレコードは物理的に取り消さない)
+    Operation {
+        undo_next: Lsn,
+        undo: Vec<u8>,
+    },
+    // 論理的な取り消しが完了した
+    // 取り消しのためのUpdateレコードはUNDOせず、undo_nextまで飛ぶ
+    DummyClr {
+        undo_next: Lsn,
+    },
+    // チェックポイント(これより前のダーティページは全てディスクに書き出した)
+    // activeは終了していないトランザクションと、その最後のレコード・コミット済みかどうか
+    // max_txn_idはそれまでに現れた最大のトランザクションID(切り詰めても再利用しないため)
+    // free_pagesはDiskManagerの解放済みのページ
+    Checkpoint {
+        max_txn_id: TxnId,
+        active: Vec<(TxnId, Lsn, bool)>,
+        free_pages: Vec<PageId>,
+    },
+    // ページを解放した(REDOだけを行い、UNDOはしない)
+    FreePage {
+        page_id: PageId,
+    },
+    // ヒープファイルをnum_pagesページに切り詰めた(REDOだけを行い、UNDOはしない)
+    // REDOでは、それより前のレコードが伸ばしたファイルを切り詰め直す
+    Truncate {
+        num_pages: u64,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogRecord {
+    pub txn_id: TxnId,
+    // 同じトランザクションの1つ前のレコード
+    pub prev_lsn: Lsn,
+    pub body: LogBody,
+}
+
+impl LogRecord {
+    pub fn new(txn_id: TxnId, prev_lsn: Lsn, body: LogBody) -> Self {
+        Self { txn_id, prev_lsn, body }
+    }
+
+    // [長さ u32][チェックサム u32][種類 u8][txn_id u64][prev_lsn u64][本体] の形式でbufに追記する
+    fn serialize(&self, buf: &mut Vec<u8>) {
+        let mut payload = vec![];
+        let kind = match &self.body {
+            LogBody::Begin => KIND_BEGIN,
+            LogBody::Update { .. } => KIND_UPDATE,
+            LogBody::Compensation { .. } => KIND_COMPENSATION,
+            LogBody::Commit => KIND_COMMIT,
+            LogBody::Abort => KIND_ABORT,
+            LogBody::End => KIND_END,
+            LogBody::Operation { .. } => KIND_OPERATION,
+            LogBody::DummyClr { .. } => KIND_DUMMY_CLR,
+            LogBody::Checkpoint { .. } => KIND_CHECKPOINT,
+            LogBody::FreePage { .. } => KIND_FREE_PAGE,
+            LogBody::Truncate { .. } => KIND_TRUNCATE,
+        };
+        payload.push(kind);
+        payload.extend_from_slice(&self.txn_id.0.to_le_bytes());
+        payload.extend_from_slice(&self.prev_lsn.0.to_le_bytes());
+        match &self.body {
+            LogBody::Update { page_id, offset, before, after } => {
+                payload.extend_from_slice(&page_id.0.to_le_bytes());
+                payload.extend_from_slice(&offset.to_le_bytes());
+                put_bytes(&mut payload, before);
+                put_bytes(&mut payload, after);
+            }
+            LogBody::Compensation { page_id, offset, after, undo_next } => {
+                payload.extend_from_slice(&page_id.0.to_le_bytes());
+                payload.extend_from_slice(&offset.to_le_bytes());
+                put_bytes(&mut payload, after);
+                payload.extend_from_slice(&undo_next.0.to_le_bytes());
+            }
+            LogBody::Operation { undo_next, undo } => {
+                payload.extend_from_slice(&undo_next.0.to_le_bytes());
+                put_bytes(&mut payload, undo);
+            }
+            LogBody::DummyClr { undo_next } => {
+                payload.extend_from_slice(&undo_next.0.to_le_bytes());
+            }
+            LogBody::Checkpoint { max_txn_id, active, free_pages } => {
+                payload.extend_from_slice(&max_txn_id.0.to_le_bytes());
+                payload.extend_from_slice(&(active.len() as u32).to_le_bytes());
+                for &(txn_id, last_lsn, committed) in active {
+                    payload.extend_from_slice(&txn_id.0.to_le_bytes());
+                    payload.extend_from_slice(&last_lsn.0.to_le_bytes());
+                    payload.push(committed as u8);
+                }
+                payload.extend_from_slice(&(free_pages.len() as u32).to_le_bytes());
+                for page_id in free_pages {
+                    payload.extend_from_slice(&page_id.0.to_le_bytes());
+                }
+            }
+            LogBody::FreePage { page_id } => {
+                payload.extend_from_slice(&page_id.0.to_le_bytes());
+            }
+            LogBody::Truncate { num_pages } => {
+                payload.extend_from_slice(&num_pages.to_le_bytes());
+            }
+            _ => {}
+        }
+        buf.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&crc32(&payload).to_le_bytes());
+        buf.extend_from_slice(&payload);
+    }
+
+    // チェックサムを検証済みのpayloadからレコードを復元する
+    fn deserialize(mut payload: &[u8]) -> io::Result<Self> {
+        let kind = get_u8(&mut payload)?;
+        let txn_id = TxnId(get_u64(&mut payload)?);
+        let prev_lsn = Lsn(get_u64(&mut payload)?);
+        let body = match kind {
+            KIND_BEGIN => LogBody::Begin,
+            KIND_UPDATE => {
+                let page_id = PageId(get_u64(&mut payload)?);
+                let offset = get_u16(&mut payload)?;
+                let before = get_bytes(&mut payload)?;
+                let after = get_bytes(&mut payload)?;
+                LogBody::Update { page_id, offset, before, after }
+            }
+            KIND_COMPENSATION => {
+                let page_id = PageId(get_u64(&mut payload)?);
+                let offset = get_u16(&mut payload)?;
+                let after = get_bytes(&mut payload)?;
+                let undo_next = Lsn(get_u64(&mut payload)?);
+                LogBody::Compensation { page_id, offset, after, undo_next }
+            }
+            KIND_COMMIT => LogBody::Commit,
+            KIND_ABORT => LogBody::Abort,
+            KIND_END => LogBody::End,
+            KIND_OPERATION => {
+                let undo_next = Lsn(get_u64(&mut payload)?);
+                let undo = get_bytes(&mut payload)?;
+                LogBody::Operation { undo_next, undo }
+            }
+            KIND_DUMMY_CLR => LogBody::DummyClr { undo_next: Lsn(get_u64(&mut payload)?) },
+            KIND_CHECKPOINT => {
+                let max_txn_id = TxnId(get_u64(&mut payload)?);
+                let count = get_u32(&mut payload)?;
+                let mut active = vec![];
+                for _ in 0..count {
+                    let txn_id = TxnId(get_u64(&mut payload)?);
+                    let last_lsn = Lsn(get_u64(&mut payload)?);
+                    let committed = get_u8(&mut payload)? != 0;
+                    active.push((txn_id, last_lsn, committed));
+                }
+                let count = get_u32(&mut payload)?;
+                let mut free_pages = vec![];
+                for _ in 0..count {
+                    free_pages.push(PageId(get_u64(&mut payload)?));
+                }
+                LogBody::Checkpoint { max_txn_id, active, free_pages }
+            }
+            KIND_FREE_PAGE => LogBody::FreePage { page_id: PageId(get_u64(&mut payload)?) },
+            KIND_TRUNCATE => LogBody::Truncate { num_pages: get_u64(&mut payload)? },
+            _ => return Err(invalid_data("unknown log record kind")),
+        };
+        Ok(Self { txn_id, prev_lsn, body })
+    }
+}
+
+struct LogState {
+    // まだファイルに書き出していないレコード
+    buffer: Vec<u8>,
+    // bufferの先頭のレコードのLSN
+    buffer_start: u64,
+    // 次に追記するレコードのLSN(= ログの末尾)
+    next_lsn: u64,
+    // このオフセットより前はディスクに永続化済み
+    flushed_lsn: u64,
+    // 誰かがfsync中かどうか
+    flushing: bool,
+    // fsyncした回数(グループコミットの効果を確認するため)
+    sync_count: u64,
+    // 終了していない(Endを書いていない)トランザクション。チェックポイントに書く
+    txns: BTreeMap<TxnId, ActiveTxn>,
+    // これまでに現れた最大のトランザクションID
+    max_txn_id: TxnId,
+}
+
+// チェックポイントの時点で終了していないトランザクション
+struct ActiveTxn {
+    // 最初のレコード。UNDOで遡るので、これより後ろは切り詰められない
+    first_lsn: Lsn,
+    last_lsn: Lsn,
+    committed: bool,
+}
+
+// ログファイルと、LSNからファイル内のオフセットへの対応
+struct LogFile {
+    storage: Box<dyn StorageFile>,
+    // ファイルに残っている先頭のレコードのLSNと、そのオフセット
+    first_lsn: u64,
+    first_offset: u64,
+}
+
+impl LogFile {
+    fn offset(&self, lsn: u64) -> io::Result<u64> {
+        if lsn < self.first_lsn {
+            return Err(invalid_data("lsn before the start of the log"));
+        }
+        Ok(lsn - self.first_lsn + self.first_offset)
+    }
+
+    // ヘッダーを書き換えて、ファイル内のfirst_offsetバイト目からをLSNがfirst_lsnのレコードとする
+    // ヘッダーは1セクターに収まるので、書きかけで壊れることはない
+    fn set_start(&mut self, first_lsn: u64, first_offset: u64) -> io::Result<()> {
+        let mut header = LOG_MAGIC.to_vec();
+        header.extend_from_slice(&first_lsn.to_le_bytes());
+        header.extend_from_slice(&first_offset.to_le_bytes());
+        self.storage.write_all_at(&header, 0)?;
+        self.storage.sync()?;
+        self.first_lsn = first_lsn;
+        self.first_offset = first_offset;
+        Ok(())
+    }
+}
+
+/// ログバッファとログファイルを管理する
+/// 複数スレッドから共有できるよう、内部の状態はMutexで守る
+/// 両方のロックを取る場合は、state → file の順に取る
+pub struct LogManager {
+    file: Mutex<LogFile>,
+    state: Mutex<LogState>,
+    // fsyncの完了を待つための条件変数
+    flushed: Condvar,
+}
+
+impl LogManager {
+    /// ログファイルを開く
+    /// 末尾に書きかけ(チェックサム不一致)のレコードがあれば切り捨てる
+    pub fn open(log_file_path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(log_file_path)?;
+        Self::with_storage(Box::new(file))
+    }
+
+    /// File以外(仮想ディスクなど)をログファイルとして使う
+    pub fn with_storage(storage: Box<dyn StorageFile>) -> io::Result<Self> {
+        let mut file = LogFile {
+            storage,
+            first_lsn: LOG_HEADER_SIZE,
+            first_offset: LOG_HEADER_SIZE,
+        };
+        if file.storage.file_size()? == 0 {
+            file.set_start(LOG_HEADER_SIZE, LOG_HEADER_SIZE)?;
+        }
+        let contents = read_all(&mut *file.storage)?;
+        if contents.len() < LOG_HEADER_SIZE as usize || &contents[..LOG_MAGIC.len()] != LOG_MAGIC {
+            return Err(invalid_data("not a log file"));
+        }
+        let mut header = &contents[LOG_MAGIC.len()..LOG_HEADER_SIZE as usize];
+        file.first_lsn = get_u64(&mut header)?;
+        file.first_offset = get_u64(&mut header)?;
+        if file.first_offset < LOG_HEADER_SIZE || file.first_offset > contents.len() as u64 {
+            return Err(invalid_data("corrupted log header"));
+        }
+        let mut end = file.first_offset as usize;
+        while let Some((_, len)) = parse_record(&contents[end..]) {
+            end += len;
+        }
+        file.storage.set_len(end as u64)?;
+        let end_lsn = file.first_lsn + (end as u64 - file.first_offset);
+        let state = LogState {
+            buffer: vec![],
+            buffer_start: end_lsn,
+            next_lsn: end_lsn,
+            flushed_lsn: end_lsn,
+            flushing: false,
+            sync_count: 0,
+            txns: BTreeMap::new(),
+            max_txn_id: TxnId(0),
+        };
+        Ok(Self {
+            file: Mutex::new(file),
+            state: Mutex::new(state),
+            flushed: Condvar::new(),
+        })
+    }
+
+    /// レコードをログバッファに追記してLSNを返す
+    /// この時点ではまだ永続化されていない
+    pub fn append(&self, record: &LogRecord) -> io::Result<Lsn> {
+        let (lsn, buffer_full) = {
+            let mut state = self.state.lock().unwrap();
+            let lsn = Self::append_locked(&mut state, record);
+            (lsn, state.buffer.len() >= LOG_BUFFER_SIZE)
+        };
+        if buffer_full {
+            self.flush_to(lsn)?;
+        }
+        Ok(lsn)
+    }
+
+    // stateのロックを取った状態で追記し、終了していないトランザクションの一覧を更新する
+    fn append_locked(state: &mut LogState, record: &LogRecord) -> Lsn {
+        let lsn = Lsn(state.next_lsn);
+        let before = state.buffer.len();
+        record.serialize(&mut state.buffer);
+        state.next_lsn += (state.buffer.len() - before) as u64;
+        match record.body {
+            LogBody::Checkpoint { .. } => {}
+            LogBody::End => {
+                state.txns.remove(&record.txn_id);
+            }
+            _ => {
+                state.max_txn_id = std::cmp::max(state.max_txn_id, record.txn_id);
+                let txn = state.txns.entry(record.txn_id).or_insert(ActiveTxn {
+                    first_lsn: lsn,
+                    last_lsn: lsn,
+                    committed: false,
+                });
+                txn.last_lsn = lsn;
+                txn.committed |= record.body == LogBody::Commit;
+            }
+        }
+        lsn
+    }
+
+    /// lsnのレコードまでを永続化する
+    ///
+    /// グループコミット：他のスレッドがfsync中なら完了を待ち、
+    /// その間に溜まったレコードは次にfsyncするスレッドがまとめて書き出す
+    pub fn flush_to(&self, lsn: Lsn) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.flushed_lsn > lsn.0 {
+                return Ok(());
+            }
+            if !state.flushing {
+                break;
+            }
+            // Condvar::waitはロックを手放して待ち、起こされたら再びロックを取る
+            state = self.flushed.wait(state).unwrap();
+        }
+        // 自分がリーダーになって、バッファに溜まっている全員分を書き出す
+        state.flushing = true;
+        let buffer = std::mem::take(&mut state.buffer);
+        let start = state.buffer_start;
+        let end = state.next_lsn;
+        state.buffer_start = end;
+        drop(state);
+
+        let result = self.write_and_sync(start, &buffer);
+
+        let mut state = self.state.lock().unwrap();
+        state.flushing = false;
+        match result {
+            Ok(()) => {
+                state.flushed_lsn = end;
+                state.sync_count += 1;
+            }
+            Err(_) => {
+                // 書けなかった分はバッファに戻しておく
+                let rest = std::mem::take(&mut state.buffer);
+                state.buffer = buffer;
+                state.buffer.extend_from_slice(&rest);
+                state.buffer_start = start;
+            }
+        }
+        self.flushed.notify_all();
+        result
+    }
+
+    /// バッファに溜まっている全てのレコードを永続化する
+    pub fn flush(&self) -> io::Result<()> {
+        let next_lsn = self.state.lock().unwrap().next_lsn;
+        if next_lsn == 0 {
+            return Ok(());
+        }
+        self.flush_to(Lsn(next_lsn - 1))
+    }
+
+    // 先頭のレコードのLSNに対応する位置に書く
+    // ファイルの末尾に追記すると、途中まで書けて失敗した時に残ったバイトの後ろに次のレコードが書かれ、
+    // LSNとオフセットがずれてしまう(書き直す時は、残ったバイトを上書きする)
+    fn write_and_sync(&self, start: u64, bytes: &[u8]) -> io::Result<()> {
+        let mut file = self.file.lock().unwrap();
+        let offset = file.offset(start)?;
+        file.storage.write_all_at(bytes, offset)?;
+        file.storage.sync()
+    }
+
+    /// 永続化済みの末尾
+    pub fn flushed_lsn(&self) -> Lsn {
+        Lsn(self.state.lock().unwrap().flushed_lsn)
+    }
+
+    /// これまでにfsyncした回数
+    pub fn sync_count(&self) -> u64 {
+        self.state.lock().unwrap().sync_count
+    }
+
+    /// ファイルに残っている先頭のレコードのLSN
+    pub fn first_lsn(&self) -> Lsn {
+        Lsn(self.file.lock().unwrap().first_lsn)
+    }
+
+    /// ログに残っているレコードのバイト数(まだバッファにある分も含む)
+    /// チェックポイントを取る目安にする
+    pub fn size(&self) -> u64 {
+        let next_lsn = self.state.lock().unwrap().next_lsn;
+        next_lsn - self.file.lock().unwrap().first_lsn
+    }
+
+    /// lsnのレコードを読み出す
+    /// まだバッファにあるレコードも読める
+    pub fn read_record(&self, lsn: Lsn) -> io::Result<LogRecord> {
+        let in_flight = {
+            let state = self.state.lock().unwrap();
+            if lsn.0 >= state.buffer_start {
+                let start = (lsn.0 - state.buffer_start) as usize;
+                let (record, _) = state.buffer.get(start..)
+                    .and_then(parse_record)
+                    .ok_or_else(|| invalid_data("invalid lsn"))?;
+                return record;
+            }
+            lsn.0 >= state.flushed_lsn
+        };
+        // 他のスレッドが書き出している途中のレコードは、書き出しが終わるのを待ってから読む
+        if in_flight {
+            self.flush_to(lsn)?;
+        }
+        let mut file = self.file.lock().unwrap();
+        let offset = file.offset(lsn.0)?;
+        let mut header = [0u8; 8];
+        file.storage.read_exact_at(&mut header, offset)?;
+        let len = u32::from_le_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        let mut bytes = header.to_vec();
+        bytes.resize(8 + len, 0);
+        file.storage.read_exact_at(&mut bytes[8..], offset + 8)?;
+        let (record, _) = parse_record(&bytes).ok_or_else(|| invalid_data("invalid lsn"))?;
+        record
+    }
+
+    /// ファイルに残っている永続化済みのレコードを先頭から順に返す
+    pub fn records(&self) -> io::Result<Vec<(Lsn, LogRecord)>> {
+        let flushed_lsn = self.flushed_lsn().0;
+        let mut file = self.file.lock().unwrap();
+        let contents = read_all(&mut *file.storage)?;
+        let end = file.offset(flushed_lsn)? as usize;
+        let mut records = vec![];
+        let mut offset = file.first_offset as usize;
+        while offset < end {
+            let (record, len) = parse_record(&contents[offset..end])
+                .ok_or_else(|| invalid_data("corrupted log"))?;
+            let lsn = file.first_lsn + (offset as u64 - file.first_offset);
+            records.push((Lsn(lsn), record?));
+            offset += len;
+        }
+        Ok(records)
+    }
+
+    // リカバリで見つかった最大のトランザクションIDを、次のチェックポイントに引き継ぐ
+    fn observe_txn_id(&self, txn_id: TxnId) {
+        let mut state = self.state.lock().unwrap();
+        state.max_txn_id = std::cmp::max(state.max_txn_id, txn_id);
+    }
+
+    // チェックポイントレコードを書いて永続化し、そのLSNと、切り詰めてよい位置を返す
+    // 一覧を作ってから書くまでの間に他のレコードが割り込まないよう、stateのロックを取ったまま書く
+    fn append_checkpoint(&self, free_pages: Vec<PageId>) -> io::Result<(Lsn, Lsn)> {
+        let (lsn, keep_from) = {
+            let mut state = self.state.lock().unwrap();
+            let active = state.txns.iter().map(|(&txn_id, txn)| (txn_id, txn.last_lsn, txn.committed)).collect();
+            let body = LogBody::Checkpoint { max_txn_id: state.max_txn_id, active, free_pages };
+            let lsn = Self::append_locked(&mut state, &LogRecord::new(TxnId(0), Lsn::INVALID, body));
+            let keep_from = state.txns.values().map(|txn| txn.first_lsn).fold(lsn, std::cmp::min);
+            (lsn, keep_from)
+        };
+        self.flush_to(lsn)?;
+        Ok((lsn, keep_from))
+    }
+
+    /// lsnより前のレコードをファイルから取り除く(LSNは変わらない)
+    ///
+    /// 残すレコードを一旦ファイルの末尾にコピーしてヘッダーを向け直し、
+    /// それから先頭に移してヘッダーを戻し、ファイルを縮める
+    /// 途中でクラッシュしても、ヘッダーは常にどちらかの完全なコピーを指している
+    pub fn truncate_before(&self, lsn: Lsn) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        // fsync中のスレッドがいなくなってから動かす(stateのロックを持っている間は新たに始まらない)
+        while state.flushing {
+            state = self.flushed.wait(state).unwrap();
+        }
+        let mut file = self.file.lock().unwrap();
+        let lsn = std::cmp::min(lsn.0, state.flushed_lsn);
+        if lsn <= file.first_lsn {
+            return Ok(());
+        }
+        let start = file.offset(lsn)?;
+        let end = file.offset(state.flushed_lsn)?;
+        let mut kept = vec![0u8; (end - start) as usize];
+        file.storage.read_exact_at(&mut kept, start)?;
+
+        // コピーの前に終端の印を置き、元のヘッダーから読んだ時にコピーをレコードの続きと見なさないようにする
+        let mut copy = END_MARK.to_vec();
+        copy.extend_from_slice(&kept);
+        file.storage.write_all_at(&copy, end)?;
+        file.storage.sync()?;
+        file.set_start(lsn, end + END_MARK.len() as u64)?;
+
+        // 先頭からkeptと終端の印を書く。start >= LOG_HEADER_SIZE なので末尾のコピーとは重ならない
+        let mut moved = kept;
+        moved.extend_from_slice(&END_MARK);
+        file.storage.write_all_at(&moved, LOG_HEADER_SIZE)?;
+        file.storage.sync()?;
+        file.set_start(lsn, LOG_HEADER_SIZE)?;
+        file.storage.set_len(LOG_HEADER_SIZE + (moved.len() - END_MARK.len()) as u64)?;
+        file.storage.sync()
+    }
+
+    /// ページのoffsetバイト目からをdataで書き換え、その変更をログに残す
+    /// 書き換えたページにはレコードのLSNをpage LSNとして書き込み、ダーティにする
+    pub fn write_page(&self, txn_id: TxnId, prev_lsn: Lsn, buffer: &Buffer, offset: usize, data: &[u8]) -> io::Result<Lsn> {
+        // 先頭のpage LSN領域はログを経由せずに書き換えてはいけない
+        assert!(offset >= PAGE_LSN_SIZE && offset + data.len() <= PAGE_SIZE);
+        let before = buffer.page.borrow()[offset..offset + data.len()].to_vec();
+        let body = LogBody::Update {
+            page_id: buffer.page_id,
+            offset: offset as u16,
+            before,
+            after: data.to_vec(),
+        };
+        let lsn = self.append(&LogRecord::new(txn_id, prev_lsn, body))?;
+        buffer.page.borrow_mut()[offset..offset + data.len()].copy_from_slice(data);
+        buffer.set_page_lsn(lsn);
+        buffer.is_dirty.set(true);
+        Ok(lsn)
+    }
+}
+
+/// 1つのトランザクションが書くログ
+/// prev_lsnを辿れるように、最後に書いたレコードのLSNを覚えておく
+pub struct TxnLog {
+    wal: Arc<LogManager>,
+    txn_id: TxnId,
+    // Cell<T>：&selfのままで値を書き換えられる
+    last_lsn: Cell<Lsn>,
+}
+
+impl TxnLog {
+    pub fn new(wal: Arc<LogManager>, txn_id: TxnId, last_lsn: Lsn) -> Self {
+        Self {
+            wal,
+            txn_id,
+            last_lsn: Cell::new(last_lsn),
+        }
+    }
+
+    pub fn txn_id(&self) -> TxnId {
+        self.txn_id
+    }
+
+    pub fn last_lsn(&self) -> Lsn {
+        self.last_lsn.get()
+    }
+
+    pub fn wal(&self) -> &Arc<LogManager> {
+        &self.wal
+    }
+
+    /// このトランザクションのレコードを追記する
+    pub fn append(&self, body: LogBody) -> io::Result<Lsn> {
+        let lsn = self.wal.append(&LogRecord::new(self.txn_id, self.last_lsn(), body))?;
+        self.last_lsn.set(lsn);
+        Ok(lsn)
+    }
+
+    /// ページのoffsetバイト目からをdataで書き換え、Updateレコードを書く
+    pub fn write_page(&self, buffer: &Buffer, offset: usize, data: &[u8]) -> io::Result<Lsn> {
+        let lsn = self.wal.write_page(self.txn_id, self.last_lsn(), buffer, offset, data)?;
+        self.last_lsn.set(lsn);
+        Ok(lsn)
+    }
+
+    /// ページの中身(page LSNより後ろ)をクロージャで書き換える
+    /// クロージャにはコピーを渡し、変更があった範囲だけをUpdateレコードに書いてからページに反映する
+    pub fn modify_page<R>(&self, buffer: &Buffer, f: impl FnOnce(&mut [u8]) -> R) -> io::Result<R> {
+        let mut body = buffer.page.borrow()[PAGE_LSN_SIZE..].to_vec();
+        let ret = f(&mut body);
+        let range = {
+            let page = buffer.page.borrow();
+            let old = &page[PAGE_LSN_SIZE..];
+            let first = old.iter().zip(&body).position(|(a, b)| a != b);
+            let last = old.iter().zip(&body).rposition(|(a, b)| a != b);
+            first.zip(last)
+        };
+        if let Some((first, last)) = range {
+            self.write_page(buffer, PAGE_LSN_SIZE + first, &body[first..=last])?;
+        }
+        Ok(ret)
+    }
+
+    /// startより後ろに書いたUpdateレコードを1つの論理的な操作としてまとめる
+    /// startは操作を始める前のlast_lsn()
+    pub fn log_operation(&self, start: Lsn, undo: Vec<u8>) -> io::Result<Lsn> {
+        self.append(LogBody::Operation { undo_next: start, undo })
+    }
+
+    /// ページを解放し、そのことをログに書く
+    /// 中身は呼び出し側でmodify_pageを使って0にしておく
+    pub fn free_page(&self, bufmgr: &mut BufferPoolManager, page_id: PageId) -> io::Result<()> {
+        self.append(LogBody::FreePage { page_id })?;
+        bufmgr.free_page(page_id);
+        Ok(())
+    }
+
+    /// ヒープファイルの末尾にある解放済みのページを取り除き、取り除いたページ数を返す
+    /// 先にTruncateレコードを永続化しておくので、クラッシュしてもREDOでファイルが伸びたままにならない
+    pub fn truncate_file(&self, bufmgr: &mut BufferPoolManager) -> Result<u64, Error> {
+        let num_pages = bufmgr.disk_mut().truncated_num_pages();
+        let removed = bufmgr.disk_mut().num_pages() - num_pages;
+        if removed > 0 {
+            let lsn = self.append(LogBody::Truncate { num_pages })?;
+            self.wal.flush_to(lsn)?;
+            bufmgr.shrink(num_pages)?;
+        }
+        Ok(removed)
+    }
+}
+
+/// Operationレコードに書いた取り消し方法を解釈して、操作を論理的に取り消す
+/// 取り消しのための変更はlogを使って書く
+pub trait LogicalUndo {
+    fn undo(&self, bufmgr: &mut BufferPoolManager, log: &TxnLog, undo: &[u8]) -> Result<(), Error>;
+}
+
+/// Operationレコードを使わない場合のLogicalUndo
+pub struct NoLogicalUndo;
+
+impl LogicalUndo for NoLogicalUndo {
+    fn undo(&self, _bufmgr: &mut BufferPoolManager, _log: &TxnLog, _undo: &[u8]) -> Result<(), Error> {
+        Err(Error::Io(invalid_data("no logical undo handler")))
+    }
+}
+
+// bytesの先頭からレコードを1つ読む
+// 読めた場合はレコードとそのバイト数を返す。書きかけやチェックサム不一致ならNone
+fn parse_record(bytes: &[u8]) -> Option<(io::Result<LogRecord>, usize)> {
+    if bytes.len() < 8 {
+        return None;
+    }
+    let len = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize;
+    let checksum = u32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]);
+    let payload = bytes.get(8..8 + len)?;
+    if crc32(payload) != checksum {
+        return None;
+    }
+    Some((LogRecord::deserialize(payload), 8 + len))
+}
+
+/// ヒープファイルに対応するログファイルのパス
+pub fn log_path(heap_file_path: &Path) -> PathBuf {
+    let mut path = heap_file_path.as_os_str().to_owned();
+    path.push(".wal");
+    PathBuf::from(path)
+}
+
+/// ヒープファイルとログファイルを開き、クラッシュリカバリを行ってからバッファプールマネージャーを返す
+pub fn open(heap_file_path: impl AsRef<Path>, pool_size: usize, handler: &dyn LogicalUndo) -> Result<(BufferPoolManager, RecoveryReport), Error> {
+    let heap_file_path = heap_file_path.as_ref();
+    let disk = DiskManager::open(heap_file_path)?;
+    let wal = LogManager::open(log_path(heap_file_path))?;
+    open_with(disk, wal, pool_size, handler)
+}
+
+/// 開いたヒープファイルとログファイルでクラッシュリカバリを行い、バッファプールマネージャーを返す
+pub fn open_with(disk: DiskManager, wal: LogManager, pool_size: usize, handler: &dyn LogicalUndo) -> Result<(BufferPoolManager, RecoveryReport), Error> {
+    let mut bufmgr = BufferPoolManager::with_wal(disk, BufferPool::new(pool_size), Arc::new(wal));
+    let report = recover(&mut bufmgr, handler)?;
+    Ok((bufmgr, report))
+}
+
+/// リカバリの結果
+#[derive(Debug, Default)]
+pub struct RecoveryReport {
+    // REDOしたレコード数
+    pub redone: usize,
+    // UNDOしたレコード数
+    pub undone: usize,
+    // ロールバックしたトランザクション
+    pub losers: Vec<TxnId>,
+    // ログに現れた最大のトランザクションID
+    pub max_txn_id: TxnId,
+}
+
+/// ARIES方式のクラッシュリカバリ
+/// 最後のチェックポイントから始め、終わったらチェックポイントを取ってログを切り詰める
+pub fn recover(bufmgr: &mut BufferPoolManager, handler: &dyn LogicalUndo) -> Result<RecoveryReport, Error> {
+    let wal = Arc::clone(bufmgr.wal().expect("recovery requires WAL"));
+    let records = wal.records()?;
+    let mut report = RecoveryReport::default();
+
+    // analysis: 終了していないトランザクション(敗者)と、その最後のレコードを求める
+    // コミット済みでもEndが無いものは勝者としてEndを書いておく
+    // チェックポイントより前のレコードは、チェックポイントの一覧にまとめられている
+    let mut active: HashMap<TxnId, (Lsn, bool)> = HashMap::new();
+    let mut free_pages = BTreeSet::new();
+    let mut start = 0;
+    for (i, (_, record)) in records.iter().enumerate() {
+        if let LogBody::Checkpoint { max_txn_id, active: txns, free_pages: pages } = &record.body {
+            report.max_txn_id = *max_txn_id;
+            active = txns.iter().map(|&(txn_id, last_lsn, committed)| (txn_id, (last_lsn, committed))).collect();
+            free_pages = pages.iter().copied().collect();
+            start = i + 1;
+        }
+    }
+    for (lsn, record) in &records[start..] {
+        report.max_txn_id = std::cmp::max(report.max_txn_id, record.txn_id);
+        match record.body {
+            LogBody::End => {
+                active.remove(&record.txn_id);
+            }
+            LogBody::Commit => {
+                active.insert(record.txn_id, (*lsn, true));
+            }
+            _ => {
+                active.insert(record.txn_id, (*lsn, false));
+            }
+        }
+    }
+
+    wal.observe_txn_id(report.max_txn_id);
+
+    // redo: 敗者も含めて、ログに残っている全ての変更を順番に再現する
+    // チェックポイントより前の変更は、ディスクに書き出し済み
+    // page LSNがレコードのLSN以上なら、その変更は既にディスクに反映されている
+    // 解放済みのページも、チェックポイントの一覧にその後の解放と再利用を反映して復元する
+    for (lsn, record) in &records[start..] {
+        let (page_id, offset, after) = match &record.body {
+            LogBody::Update { page_id, offset, after, .. } => {
+                // 解放済みのページに書くのは、再利用した時だけ
+                free_pages.remove(page_id);
+                (*page_id, *offset, after)
+            }
+            LogBody::Compensation { page_id, offset, after, .. } => (*page_id, *offset, after),
+            LogBody::FreePage { page_id } => {
+                free_pages.insert(*page_id);
+                continue;
+            }
+            LogBody::Truncate { num_pages } => {
+                bufmgr.shrink(*num_pages)?;
+                free_pages.retain(|page_id| page_id.to_u64() < *num_pages);
+                continue;
+            }
+            _ => continue,
+        };
+        bufmgr.disk_mut().ensure_page(page_id)?;
+        let buffer = bufmgr.fetch_page(page_id)?;
+        if buffer.page_lsn() < *lsn {
+            apply(&buffer, offset, after, *lsn);
+            report.redone += 1;
+        }
+    }
+
+    bufmgr.disk_mut().set_free_pages(free_pages.into_iter().collect());
+
+    // undo: 敗者の変更を新しい順に取り消す
+    let mut winners = vec![];
+    let mut losers = vec![];
+    for (txn_id, (last_lsn, committed)) in active {
+        if committed {
+            winners.push((txn_id, last_lsn));
+        } else {
+            losers.push((txn_id, last_lsn));
+        }
+    }
+    // HashMapの順番は実行ごとに変わるので、並べ替えてログに書く順番を決まったものにする
+    winners.sort();
+    losers.sort();
+    for (txn_id, last_lsn) in winners {
+        wal.append(&LogRecord::new(txn_id, last_lsn, LogBody::End))?;
+    }
+    report.losers = losers.iter().map(|&(txn_id, _)| txn_id).collect();
+    report.undone = undo(bufmgr, &losers, handler)?;
+    checkpoint(bufmgr)?;
+    Ok(report)
+}
+
+/// チェックポイントを取る
+/// ダーティページを全て書き出してから、終了していないトランザクションの一覧をログに書き、
+/// それより前のレコードを(終了していないトランザクションのUNDOに要るものを除いて)切り詰める
+/// ページの書き換えはバッファプールマネージャーを通すので、&mutで借りている間は他の変更が割り込まない
+pub fn checkpoint(bufmgr: &mut BufferPoolManager) -> Result<Lsn, Error> {
+    let wal = Arc::clone(bufmgr.wal().expect("checkpoint requires WAL"));
+    // WALルールにより、ページより先にそのページのレコードが書き出される
+    bufmgr.flush()?;
+    let free_pages = bufmgr.disk_mut().free_pages().to_vec();
+    let (lsn, keep_from) = wal.append_checkpoint(free_pages)?;
+    wal.truncate_before(keep_from)?;
+    Ok(lsn)
+}
+
+/// トランザクションの変更をログを遡りながら取り消す
+/// txnsは(トランザクションID, 最後のレコードのLSN)の組
+/// 取り消すたびにCLRを書くので、途中でクラッシュしても同じ変更を2度取り消すことはない
+/// 取り消しが終わったトランザクションにはEndを書く
+/// 戻り値は取り消したUpdateレコードとOperationレコードの数
+pub fn undo(bufmgr: &mut BufferPoolManager, txns: &[(TxnId, Lsn)], handler: &dyn LogicalUndo) -> Result<usize, Error> {
+    let wal = Arc::clone(bufmgr.wal().expect("undo requires WAL"));
+    let mut undone = 0;
+    let logs: HashMap<TxnId, TxnLog> = txns
+        .iter()
+        .map(|&(txn_id, lsn)| (txn_id, TxnLog::new(Arc::clone(&wal), txn_id, lsn)))
+        .collect();
+    // 次にUNDOするレコード(LSNの大きい順に処理する)
+    let mut to_undo: BTreeSet<(Lsn, TxnId)> = txns.iter().map(|&(txn_id, lsn)| (lsn, txn_id)).collect();
+    while let Some((lsn, txn_id)) = to_undo.iter().next_back().cloned() {
+        to_undo.remove(&(lsn, txn_id));
+        let log = &logs[&txn_id];
+        let record = wal.read_record(lsn)?;
+        let next = match record.body {
+            LogBody::Update { page_id, offset, before, .. } => {
+                let buffer = bufmgr.fetch_page(page_id)?;
+                let body = LogBody::Compensation {
+                    page_id,
+                    offset,
+                    after: before.clone(),
+                    undo_next: record.prev_lsn,
+                };
+                let clr_lsn = log.append(body)?;
+                apply(&buffer, offset, &before, clr_lsn);
+                undone += 1;
+                record.prev_lsn
+            }
+            LogBody::Operation { undo_next, undo } => {
+                handler.undo(bufmgr, log, &undo)?;
+                log.append(LogBody::DummyClr { undo_next })?;
+                undone += 1;
+                undo_next
+            }
+            // 既に取り消したところは飛ばす
+            LogBody::Compensation { undo_next, .. } | LogBody::DummyClr { undo_next } => undo_next,
+            _ => record.prev_lsn,
+        };
+        if next == Lsn::INVALID {
+            log.append(LogBody::End)?;
+        } else {
+            to_undo.insert((next, txn_id));
+        }
+    }
+    Ok(undone)
+}
+
+fn apply(buffer: &Buffer, offset: u16, data: &[u8], lsn: Lsn) {
+    let offset = offset as usize;
+    buffer.page.borrow_mut()[offset..offset + data.len()].copy_from_slice(data);
+    buffer.set_page_lsn(lsn);
+    buffer.is_dirty.set(true);
+}
+
+fn put_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    buf.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    buf.extend_from_slice(bytes);
+}
+
+fn get_u8(buf: &mut &[u8]) -> io::Result<u8> {
+    let (&n, rest) = buf.split_first().ok_or_else(|| invalid_data("truncated record"))?;
+    *buf = rest;
+    Ok(n)
+}
+
+fn get_u16(buf: &mut &[u8]) -> io::Result<u16> {
+    Ok(u16::from_le_bytes([get_u8(buf)?, get_u8(buf)?]))
+}
+
+fn get_u32(buf: &mut &[u8]) -> io::Result<u32> {
+    Ok(u32::from_le_bytes([get_u8(buf)?, get_u8(buf)?, get_u8(buf)?, get_u8(buf)?]))
+}
+
+fn get_u64(buf: &mut &[u8]) -> io::Result<u64> {
+    let mut bytes = [0u8; 8];
+    for b in bytes.iter_mut() {
+        *b = get_u8(buf)?;
+    }
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn get_bytes(buf: &mut &[u8]) -> io::Result<Vec<u8>> {
+    let len = get_u32(buf)? as usize;
+    if buf.len() < len {
+        return Err(invalid_data("truncated record"));
+    }
+    let (bytes, rest) = buf.split_at(len);
+    *buf = rest;
+    Ok(bytes.to_vec())
+}
+
+// ファイル全体を読む
+fn read_all(file: &mut dyn StorageFile) -> io::Result<Vec<u8>> {
+    let mut contents = vec![0u8; file.file_size()? as usize];
+    file.read_exact_at(&mut contents, 0)?;
+    Ok(contents)
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+// CRC-32(IEEE)
+// 書きかけのレコードを検出するためのチェックサム
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &b in bytes {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            let mask = (!(crc & 1)).wrapping_add(1);
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::io::Write;
+    use std::fs::File;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Barrier;
+    use std::thread;
+    use std::time::Duration;
+
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    // テストごとに別のファイルを使う
+    fn temp_path(name: &str) -> PathBuf {
+        let n = COUNTER.fetch_add(1, Ordering::SeqCst);
+        let path = std::env::temp_dir().join(format!("wal_test_{}_{}_{}", std::process::id(), name, n));
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_file(log_path(&path));
+        path
+    }
+
+    // クラッシュを起こす
+    // flush_pagesに指定したページだけがディスクに書き出され、残りのダーティページとログバッファは失われる
+    fn crash(mut bufmgr: BufferPoolManager, flush_pages: &[PageId]) {
+        for &page_id in flush_pages {
+            bufmgr.flush_page(page_id).unwrap();
+        }
+    }
+
+    // 書き込みの失敗やfsyncの遅さを再現するファイル
+    struct FaultyFile {
+        file: File,
+        // 0でなければ、その回数目の書き込みは半分だけ書いて失敗する
+        fail_write: Arc<AtomicUsize>,
+        sync_delay: Duration,
+    }
+
+    impl FaultyFile {
+        fn open(path: &Path, fail_write: Arc<AtomicUsize>, sync_delay: Duration) -> Box<dyn StorageFile> {
+            let file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path).unwrap();
+            Box::new(Self { file, fail_write, sync_delay })
+        }
+    }
+
+    impl StorageFile for FaultyFile {
+        fn read_exact_at(&mut self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+            self.file.read_exact_at(buf, offset)
+        }
+
+        fn write_all_at(&mut self, data: &[u8], offset: u64) -> io::Result<()> {
+            let n = self.fail_write.load(Ordering::SeqCst);
+            if n > 0 {
+                self.fail_write.store(n - 1, Ordering::SeqCst);
+            }
+            if n == 1 {
+                self.file.write_all_at(&data[..data.len() / 2], offset)?;
+                return Err(io::Error::other("injected write failure"));
+            }
+            self.file.write_all_at(data, offset)
+        }
+
+        fn file_size(&self) -> io::Result<u64> {
+            self.file.file_size()
+        }
+
+        fn set_len(&mut self, len: u64) -> io::Result<()> {
+            self.file.set_len(len)
+        }
+
+        fn sync(&mut self) -> io::Result<()> {
+            thread::sleep(self.sync_delay);
+            self.file.sync()
+        }
+    }
+
+    fn read(bufmgr: &mut BufferPoolManager, page_id: PageId, offset: usize, len: usize) -> Vec<u8> {
+        let buffer = bufmgr.fetch_page(page_id).unwrap();
+        let page = buffer.page.borrow();
+        page[offset..offset + len].to_vec()
+    }
+
+    fn begin(wal: &LogManager, txn_id: TxnId) -> Lsn {
+        wal.append(&LogRecord::new(txn_id, Lsn::INVALID, LogBody::Begin)).unwrap()
+    }
+
+    fn commit(wal: &LogManager, txn_id: TxnId, prev_lsn: Lsn) {
+        let lsn = wal.append(&LogRecord::new(txn_id, prev_lsn, LogBody::Commit)).unwrap();
+        wal.flush_to(lsn).unwrap();
+    }
+
+    #[test]
+    fn test_record_roundtrip() {
+        let record = LogRecord::new(TxnId(3), Lsn(100), LogBody::Update {
+            page_id: PageId(7),
+            offset: 16,
+            before: vec![1, 2],
+            after: vec![3, 4],
+        });
+        let mut buf = vec![];
+        record.serialize(&mut buf);
+        let (decoded, len) = parse_record(&buf).unwrap();
+        assert_eq!(decoded.unwrap(), record);
+        assert_eq!(len, buf.len());
+        // 書きかけのレコードは読めない
+        assert!(parse_record(&buf[..buf.len() - 1]).is_none());
+
+        let bodies = [
+            LogBody::Checkpoint {
+                max_txn_id: TxnId(9),
+                active: vec![(TxnId(4), Lsn(200), false), (TxnId(5), Lsn(300), true)],
+                free_pages: vec![PageId(3), PageId(8)],
+            },
+            LogBody::FreePage { page_id: PageId(5) },
+            LogBody::Truncate { num_pages: 12 },
+        ];
+        for body in bodies {
+            let record = LogRecord::new(TxnId(4), Lsn(100), body);
+            let mut buf = vec![];
+            record.serialize(&mut buf);
+            assert_eq!(parse_record(&buf).unwrap().0.unwrap(), record);
+        }
+    }
+
+    #[test]
+    fn test_redo_committed_and_undo_uncommitted() {
+        let path = temp_path("redo_undo");
+        let (page_a, page_b) = {
+            let (mut bufmgr, _) = open(&path, 4, &NoLogicalUndo).unwrap();
+            let wal = Arc::clone(bufmgr.wal().unwrap());
+            let a = bufmgr.create_page().unwrap();
+            let b = bufmgr.create_page().unwrap();
+
+            // txn1はコミットするが、ページは書き出されないままクラッシュする
+            let lsn = begin(&wal, TxnId(1));
+            let lsn = wal.write_page(TxnId(1), lsn, &a, 100, b"committed").unwrap();
+            commit(&wal, TxnId(1), lsn);
+
+            // txn2はコミットしないが、ページbだけ書き出される
+            let lsn = begin(&wal, TxnId(2));
+            let lsn = wal.write_page(TxnId(2), lsn, &a, 200, b"loser-a").unwrap();
+            wal.write_page(TxnId(2), lsn, &b, 200, b"loser-b").unwrap();
+
+            let ids = (a.page_id, b.page_id);
+            drop(a);
+            drop(b);
+            crash(bufmgr, &[ids.1]);
+            ids
+        };
+
+        let (mut bufmgr, report) = open(&path, 4, &NoLogicalUndo).unwrap();
+        assert_eq!(report.losers, vec![TxnId(2)]);
+        assert_eq!(report.max_txn_id, TxnId(2));
+        assert_eq!(read(&mut bufmgr, page_a, 100, 9), b"committed");
+        assert_eq!(read(&mut bufmgr, page_a, 200, 7), vec![0; 7]);
+        assert_eq!(read(&mut bufmgr, page_b, 200, 7), vec![0; 7]);
+        bufmgr.flush().unwrap();
+        drop(bufmgr);
+
+        // もう一度開いても同じ状態で、何もUNDOしない
+        let (mut bufmgr, report) = open(&path, 4, &NoLogicalUndo).unwrap();
+        assert!(report.losers.is_empty());
+        assert_eq!(report.undone, 0);
+        assert_eq!(read(&mut bufmgr, page_a, 100, 9), b"committed");
+        assert_eq!(read(&mut bufmgr, page_b, 200, 7), vec![0; 7]);
+    }
+
+    #[test]
+    fn test_crash_during_recovery() {
+        let path = temp_path("crash_recovery");
+        let page_id = {
+            let (mut bufmgr, _) = open(&path, 2, &NoLogicalUndo).unwrap();
+            let wal = Arc::clone(bufmgr.wal().unwrap());
+            let page = bufmgr.create_page().unwrap();
+            let lsn = begin(&wal, TxnId(1));
+            let lsn = wal.write_page(TxnId(1), lsn, &page, 8, b"one").unwrap();
+            wal.write_page(TxnId(1), lsn, &page, 8, b"two").unwrap();
+            let page_id = page.page_id;
+            drop(page);
+            crash(bufmgr, &[page_id]);
+            page_id
+        };
+        // リカバリ直後、ページを書き出さないままクラッシュする
+        {
+            let (bufmgr, report) = open(&path, 2, &NoLogicalUndo).unwrap();
+            assert_eq!(report.undone, 2);
+            crash(bufmgr, &[]);
+        }
+        let (mut bufmgr, report) = open(&path, 2, &NoLogicalUndo).unwrap();
+        // CLRのおかげで2度目はUNDOするものが無い
+        assert_eq!(report.undone, 0);
+        assert_eq!(read(&mut bufmgr, page_id, 8, 3), vec![0; 3]);
+    }
+
+    #[test]
+    fn test_torn_log_tail_is_ignored() {
+        let path = temp_path("torn");
+        let page_id = {
+            let (mut bufmgr, _) = open(&path, 2, &NoLogicalUndo).unwrap();
+            let wal = Arc::clone(bufmgr.wal().unwrap());
+            let page = bufmgr.create_page().unwrap();
+            let lsn = begin(&wal, TxnId(1));
+            let lsn = wal.write_page(TxnId(1), lsn, &page, 8, b"data").unwrap();
+            commit(&wal, TxnId(1), lsn);
+            let page_id = page.page_id;
+            drop(page);
+            crash(bufmgr, &[]);
+            page_id
+        };
+        // 書きかけのレコードを末尾に足す
+        let mut file = OpenOptions::new().append(true).open(log_path(&path)).unwrap();
+        file.write_all(&[42, 0, 0, 0, 1, 2, 3]).unwrap();
+        drop(file);
+
+        let (mut bufmgr, _) = open(&path, 2, &NoLogicalUndo).unwrap();
+        assert_eq!(read(&mut bufmgr, page_id, 8, 4), b"data");
+    }
+
+    #[test]
+    fn test_wal_rule_on_eviction() {
+        let path = temp_path("wal_rule");
+        let (mut bufmgr, _) = open(&path, 1, &NoLogicalUndo).unwrap();
+        let wal = Arc::clone(bufmgr.wal().unwrap());
+        let page = bufmgr.create_page().unwrap();
+        let lsn = begin(&wal, TxnId(1));
+        let lsn = wal.write_page(TxnId(1), lsn, &page, 8, b"x").unwrap();
+        drop(page);
+        assert!(wal.flushed_lsn() <= lsn);
+        // プールサイズが1なので、次のページを作るとダーティページが追い出される
+        bufmgr.create_page().unwrap();
+        assert!(wal.flushed_lsn() > lsn);
+    }
+
+    #[test]
+    fn test_group_commit() {
+        let path = temp_path("group_commit");
+        // fsyncが遅ければ、その間にコミットしたトランザクションは次のfsyncでまとめて永続化される
+        let file = FaultyFile::open(&log_path(&path), Arc::new(AtomicUsize::new(0)), Duration::from_millis(50));
+        let wal = Arc::new(LogManager::with_storage(file).unwrap());
+        let barrier = Arc::new(Barrier::new(16));
+        let handles: Vec<_> = (1..=16)
+            .map(|n| {
+                let wal = Arc::clone(&wal);
+                let barrier = Arc::clone(&barrier);
+                thread::spawn(move || {
+                    // 全員が揃ってから同時にコミットする
+                    barrier.wait();
+                    let lsn = begin(&wal, TxnId(n));
+                    commit(&wal, TxnId(n), lsn);
+                    lsn
+                })
+            })
+            .collect();
+        for handle in handles {
+            let lsn = handle.join().unwrap();
+            assert!(wal.flushed_lsn() > lsn);
+        }
+        // 最初のfsyncの間に残りの全員が待つので、16回より大幅に少なくなる
+        assert!(wal.sync_count() <= 4, "sync_count = {}", wal.sync_count());
+        assert_eq!(wal.records().unwrap().len(), 32);
+    }
+
+    #[test]
+    fn test_partial_write_failure() {
+        let path = log_path(&temp_path("partial_write"));
+        let fail_write = Arc::new(AtomicUsize::new(0));
+        let wal = LogManager::with_storage(FaultyFile::open(&path, Arc::clone(&fail_write), Duration::ZERO)).unwrap();
+        let lsn = begin(&wal, TxnId(1));
+        commit(&wal, TxnId(1), lsn);
+
+        // 途中まで書いて失敗しても、書き直した時にLSNの位置にレコードが書かれる
+        fail_write.store(1, Ordering::SeqCst);
+        let lsn = begin(&wal, TxnId(2));
+        assert!(wal.flush_to(lsn).is_err());
+        commit(&wal, TxnId(2), lsn);
+        let expected = wal.records().unwrap();
+        assert_eq!(expected.len(), 4);
+        drop(wal);
+
+        let wal = LogManager::open(&path).unwrap();
+        assert_eq!(wal.records().unwrap(), expected);
+    }
+
+    fn end(wal: &LogManager, txn_id: TxnId, prev_lsn: Lsn) {
+        wal.append(&LogRecord::new(txn_id, prev_lsn, LogBody::End)).unwrap();
+    }
+
+    #[test]
+    fn test_checkpoint_truncates_log() {
+        let path = temp_path("checkpoint");
+        let page_id = {
+            let (mut bufmgr, _) = open(&path, 4, &NoLogicalUndo).unwrap();
+            let wal = Arc::clone(bufmgr.wal().unwrap());
+            let page = bufmgr.create_page().unwrap();
+            for n in 1..=100u64 {
+                let lsn = begin(&wal, TxnId(n));
+                let lsn = wal.write_page(TxnId(n), lsn, &page, 8, &n.to_le_bytes()).unwrap();
+                commit(&wal, TxnId(n), lsn);
+                end(&wal, TxnId(n), lsn);
+            }
+            let size = fs::metadata(log_path(&path)).unwrap().len();
+            let lsn = checkpoint(&mut bufmgr).unwrap();
+            // 実行中のトランザクションが無いので、チェックポイントより前は全て切り詰められる
+            assert_eq!(wal.first_lsn(), lsn);
+            assert!(fs::metadata(log_path(&path)).unwrap().len() < size / 10);
+
+            // チェックポイントの後の変更は、ページを書き出さなくてもREDOされる
+            let lsn = begin(&wal, TxnId(101));
+            let lsn = wal.write_page(TxnId(101), lsn, &page, 16, b"after").unwrap();
+            commit(&wal, TxnId(101), lsn);
+            let page_id = page.page_id;
+            drop(page);
+            crash(bufmgr, &[]);
+            page_id
+        };
+
+        for _ in 0..2 {
+            let (mut bufmgr, report) = open(&path, 4, &NoLogicalUndo).unwrap();
+            // 切り詰めたトランザクションのIDも再利用しない
+            assert_eq!(report.max_txn_id, TxnId(101));
+            assert_eq!(read(&mut bufmgr, page_id, 8, 8), 100u64.to_le_bytes());
+            assert_eq!(read(&mut bufmgr, page_id, 16, 5), b"after");
+        }
+    }
+
+    #[test]
+    fn test_loser_spans_checkpoint() {
+        let path = temp_path("checkpoint_loser");
+        let (page_a, page_b) = {
+            let (mut bufmgr, _) = open(&path, 4, &NoLogicalUndo).unwrap();
+            let wal = Arc::clone(bufmgr.wal().unwrap());
+            let a = bufmgr.create_page().unwrap();
+            let b = bufmgr.create_page().unwrap();
+
+            // txn1はチェックポイントをまたいで実行中のままクラッシュする
+            let first = begin(&wal, TxnId(1));
+            let lsn = wal.write_page(TxnId(1), first, &a, 8, b"before").unwrap();
+            checkpoint(&mut bufmgr).unwrap();
+            // UNDOで遡るtxn1のレコードは切り詰めない
+            assert_eq!(wal.first_lsn(), first);
+            wal.write_page(TxnId(1), lsn, &a, 100, b"after").unwrap();
+
+            let lsn = begin(&wal, TxnId(2));
+            let lsn = wal.write_page(TxnId(2), lsn, &b, 8, b"winner").unwrap();
+            commit(&wal, TxnId(2), lsn);
+            let ids = (a.page_id, b.page_id);
+            drop(a);
+            drop(b);
+            crash(bufmgr, &[]);
+            ids
+        };
+
+        let (mut bufmgr, report) = open(&path, 4, &NoLogicalUndo).unwrap();
+        assert_eq!(report.losers, vec![TxnId(1)]);
+        assert_eq!(report.undone, 2);
+        assert_eq!(read(&mut bufmgr, page_a, 8, 6), vec![0; 6]);
+        assert_eq!(read(&mut bufmgr, page_a, 100, 5), vec![0; 5]);
+        assert_eq!(read(&mut bufmgr, page_b, 8, 6), b"winner");
+        // リカバリ後は全てのトランザクションが終わっているので、ログはチェックポイントだけになる
+        assert_eq!(bufmgr.wal().unwrap().records().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_free_and_truncate_are_redone() {
+        let path = temp_path("free_truncate");
+        let pages = {
+            let (mut bufmgr, _) = open(&path, 8, &NoLogicalUndo).unwrap();
+            let wal = Arc::clone(bufmgr.wal().unwrap());
+            let log = TxnLog::new(Arc::clone(&wal), TxnId(1), Lsn::INVALID);
+            log.append(LogBody::Begin).unwrap();
+            let pages: Vec<PageId> = (0..6)
+                .map(|_| {
+                    let buffer = bufmgr.create_page().unwrap();
+                    log.write_page(&buffer, 8, b"data").unwrap();
+                    buffer.page_id
+                })
+                .collect();
+            checkpoint(&mut bufmgr).unwrap();
+
+            // 途中のページと末尾の2ページを解放し、ファイルを切り詰める
+            for &i in &[1, 4, 5] {
+                let buffer = bufmgr.fetch_page(pages[i]).unwrap();
+                log.write_page(&buffer, 8, &[0; 4]).unwrap();
+                drop(buffer);
+                log.free_page(&mut bufmgr, pages[i]).unwrap();
+            }
+            assert_eq!(log.truncate_file(&mut bufmgr).unwrap(), 2);
+            commit(&wal, TxnId(1), log.last_lsn());
+            crash(bufmgr, &[]);
+            pages
+        };
+
+        // 末尾のページへのREDOでファイルが伸びても、Truncateレコードで切り詰め直す
+        let (mut bufmgr, _) = open(&path, 8, &NoLogicalUndo).unwrap();
+        assert_eq!(bufmgr.disk_mut().num_pages(), 4);
+        assert_eq!(fs::metadata(&path).unwrap().len(), 4 * PAGE_SIZE as u64);
+        assert_eq!(bufmgr.disk_mut().free_pages(), &[pages[1]]);
+        drop(bufmgr);
+
+        // リカバリの最後のチェックポイントから、解放済みのページが復元される
+        let (mut bufmgr, _) = open(&path, 8, &NoLogicalUndo).unwrap();
+        assert_eq!(bufmgr.disk_mut().free_pages(), &[pages[1]]);
+        assert_eq!(bufmgr.create_page().unwrap().page_id, pages[1]);
+    }
+
+    #[test]
+    fn test_crash_during_log_truncation() {
+        // 1回目は末尾へのコピー、3回目は先頭へのコピーの途中で失敗する
+        for n in [1, 3] {
+            let path = log_path(&temp_path("truncate"));
+            let fail_write = Arc::new(AtomicUsize::new(0));
+            let wal = LogManager::with_storage(FaultyFile::open(&path, Arc::clone(&fail_write), Duration::ZERO)).unwrap();
+            for txn in 1..=10u64 {
+                let lsn = begin(&wal, TxnId(txn));
+                end(&wal, TxnId(txn), lsn);
+            }
+            let (_, keep_from) = wal.append_checkpoint(vec![]).unwrap();
+            let all = wal.records().unwrap();
+            let kept: Vec<_> = all.iter().filter(|(lsn, _)| *lsn >= keep_from).cloned().collect();
+            assert!(kept.len() < all.len());
+
+            fail_write.store(n, Ordering::SeqCst);
+            assert!(wal.truncate_before(keep_from).is_err());
+            drop(wal);
+
+            // どこで失敗しても、切り詰める前か後のどちらかのレコードが読める
+            let wal = LogManager::open(&path).unwrap();
+            let records = wal.records().unwrap();
+            assert!(records == all || records == kept, "n = {}", n);
+            // 読めたレコードの後ろに続けて書ける
+            let lsn = begin(&wal, TxnId(11));
+            wal.flush_to(lsn).unwrap();
+            drop(wal);
+            let wal = LogManager::open(&path).unwrap();
+            assert_eq!(wal.records().unwrap().last().unwrap().0, lsn);
+        }
+    }
+}
@@ -0,0 +1,570 @@
+// ディレクトリを再帰的に辿って、検索するファイルを列挙する(grepで使う)
+// - 名前が.で始まる隠しファイル・ディレクトリは飛ばす
+// - 各ディレクトリの.gitignoreと.ignoreに書かれたパターンに一致するものは飛ばす
+// - --include・--excludeのglobパターンで絞り込む
+// - シンボリックリンクは辿らない(grep -rと同じ)
+//
+// 結果が毎回同じ順になるよう、ディレクトリの中身は名前順に並べて辿る
+//
+// .gitignoreのパターンの規則
+// - 空行と#で始まる行は無視する。!で始まる行は、それまでに除外したものを除外しない(否定)
+// - 末尾が/ならディレクトリにだけ一致する
+// - 途中に/を含む(先頭の/を含む)パターンは、その.gitignoreのあるディレクトリからの相対パスに一致する
+//   含まないパターンは、どの階層のファイル名にも一致する
+// - *は/以外の任意の文字列、?は/以外の任意の1文字、[a-z]は文字クラス
+// - **/は0個以上のディレクトリ、末尾の/**は中身全て、a/**/bはaとbの間の0個以上のディレクトリに一致する
+// - 同じディレクトリでは後の行が、違うディレクトリでは深いディレクトリの.gitignoreが優先される
+//   .ignoreは同じディレクトリの.gitignoreより優先される
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+// 読み込む除外ファイル(後の方が優先される)
+const IGNORE_FILES: [&str; 2] = [".gitignore", ".ignore"];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Char(char),
+    // ?
+    Any,
+    // *
+    Star,
+    // **/ (0個以上のディレクトリ)
+    AnyDirs,
+    // ** (/を含む任意の文字列)
+    AnyPath,
+    // [...]
+    Class { ranges: Vec<(char, char)>, negated: bool },
+}
+
+/// globパターン
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Glob {
+    tokens: Vec<Token>,
+    // /を含むパターンはパス全体に、含まないパターンはファイル名に一致させる
+    has_slash: bool,
+}
+
+impl Glob {
+    pub fn new(pattern: &str) -> Self {
+        let chars: Vec<char> = pattern.chars().collect();
+        let mut tokens = vec![];
+        let mut i = 0;
+        while i < chars.len() {
+            match chars[i] {
+                '*' if chars.get(i + 1) == Some(&'*') => {
+                    // **は前後が/(またはパターンの端)の時だけ特別な意味を持つ
+                    let starts_component = i == 0 || chars[i - 1] == '/';
+                    if starts_component && chars.get(i + 2) == Some(&'/') {
+                        tokens.push(Token::AnyDirs);
+                        i += 3;
+                        continue;
+                    }
+                    if starts_component && i + 2 == chars.len() {
+                        tokens.push(Token::AnyPath);
+                        i += 2;
+                        continue;
+                    }
+                    tokens.push(Token::Star);
+                    i += 2;
+                }
+                '*' => {
+                    tokens.push(Token::Star);
+                    i += 1;
+                }
+                '?' => {
+                    tokens.push(Token::Any);
+                    i += 1;
+                }
+                '[' => match parse_class(&chars[i + 1..]) {
+                    Some((token, len)) => {
+                        tokens.push(token);
+                        i += len + 1;
+                    }
+                    // 閉じていない[はただの文字
+                    None => {
+                        tokens.push(Token::Char('['));
+                        i += 1;
+                    }
+                },
+                '\\' if i + 1 < chars.len() => {
+                    tokens.push(Token::Char(chars[i + 1]));
+                    i += 2;
+                }
+                c => {
+                    tokens.push(Token::Char(c));
+                    i += 1;
+                }
+            }
+        }
+        Self {
+            tokens,
+            has_slash: pattern.contains('/'),
+        }
+    }
+
+    /// pathが一致するか
+    /// /を含まないパターンは、pathの最後の要素(ファイル名)と比べる
+    pub fn is_match(&self, path: &str) -> bool {
+        let text = if self.has_slash {
+            path
+        } else {
+            path.rsplit('/').next().unwrap_or(path)
+        };
+        self.matches(text)
+    }
+
+    // パターン全体がtext全体に一致するか
+    // matched[i]は「ここまでのトークンがtextの先頭i文字に一致する」
+    fn matches(&self, text: &str) -> bool {
+        let chars: Vec<char> = text.chars().collect();
+        let n = chars.len();
+        let mut matched = vec![false; n + 1];
+        matched[0] = true;
+        for token in &self.tokens {
+            let mut next = vec![false; n + 1];
+            for i in 0..=n {
+                if !matched[i] {
+                    continue;
+                }
+                match token {
+                    Token::Char(c) => {
+                        if chars.get(i) == Some(c) {
+                            next[i + 1] = true;
+                        }
+                    }
+                    Token::Any => {
+                        if chars.get(i).is_some_and(|&c| c != '/') {
+                            next[i + 1] = true;
+                        }
+                    }
+                    Token::Class { ranges, negated } => {
+                        if let Some(&c) = chars.get(i) {
+                            let found = ranges.iter().any(|&(start, end)| start <= c && c <= end);
+                            if c != '/' && found != *negated {
+                                next[i + 1] = true;
+                            }
+                        }
+                    }
+                    Token::Star => {
+                        next[i] = true;
+                        let mut j = i;
+                        while j < n && chars[j] != '/' {
+                            j += 1;
+                            next[j] = true;
+                        }
+                    }
+                    Token::AnyDirs => {
+                        // 空か、/で終わる任意の文字列
+                        next[i] = true;
+                        for j in i..n {
+                            if chars[j] == '/' {
+                                next[j + 1] = true;
+                            }
+                        }
+                    }
+                    Token::AnyPath => {
+                        for flag in &mut next[i..] {
+                            *flag = true;
+                        }
+                    }
+                }
+            }
+            matched = next;
+        }
+        matched[n]
+    }
+}
+
+// [の後ろを読んで文字クラスにする。読んだ文字数(]を含む)も返す
+fn parse_class(chars: &[char]) -> Option<(Token, usize)> {
+    let mut i = 0;
+    let negated = matches!(chars.first(), Some('!') | Some('^'));
+    if negated {
+        i += 1;
+    }
+    let mut ranges = vec![];
+    let start = i;
+    loop {
+        let c = *chars.get(i)?;
+        // 最初の]は文字として扱う
+        if c == ']' && i > start {
+            return Some((Token::Class { ranges, negated }, i + 1));
+        }
+        if chars.get(i + 1) == Some(&'-') && chars.get(i + 2).is_some_and(|&end| end != ']') {
+            ranges.push((c, chars[i + 2]));
+            i += 3;
+        } else {
+            ranges.push((c, c));
+            i += 1;
+        }
+    }
+}
+
+// .gitignoreの1行
+#[derive(Debug, Clone)]
+struct Rule {
+    glob: Glob,
+    negated: bool,
+    dir_only: bool,
+}
+
+impl Rule {
+    fn parse(line: &str) -> Option<Self> {
+        let line = line.trim_end_matches('\r');
+        // 末尾の空白は\でエスケープしていなければ無視する(エスケープはGlobが解釈する)
+        let mut end = line.len();
+        while line[..end].ends_with(' ') && !line[..end - 1].ends_with('\\') {
+            end -= 1;
+        }
+        let line = &line[..end];
+        // \#と\!はGlobがただの文字として解釈する
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+        let (negated, mut line) = match line.strip_prefix('!') {
+            Some(rest) => (true, rest),
+            None => (false, line),
+        };
+        let dir_only = line.ends_with('/');
+        if dir_only {
+            line = line.trim_end_matches('/');
+        }
+        if line.is_empty() {
+            return None;
+        }
+        // 途中に/があれば.gitignoreのあるディレクトリからの相対パス、無ければどの階層の名前にも一致する
+        let pattern = if line.contains('/') {
+            line.trim_start_matches('/').to_string()
+        } else {
+            format!("**/{}", line)
+        };
+        Some(Self {
+            glob: Glob::new(&pattern),
+            negated,
+            dir_only,
+        })
+    }
+}
+
+// 1つのディレクトリの除外ファイルから読んだ規則
+// 親ディレクトリの規則を辿れるようにつなげておく
+struct IgnoreDir {
+    // このディレクトリの絶対パス
+    base: PathBuf,
+    rules: Vec<Rule>,
+    parent: Option<Rc<IgnoreDir>>,
+}
+
+impl IgnoreDir {
+    // dirの除外ファイルを読む。規則が無ければparentをそのまま返す
+    fn load(dir: &Path, parent: Option<Rc<IgnoreDir>>) -> Option<Rc<IgnoreDir>> {
+        let mut rules = vec![];
+        for name in IGNORE_FILES.iter() {
+            // 読めない除外ファイルは無いものとして扱う
+            if let Ok(contents) = fs::read_to_string(dir.join(name)) {
+                rules.extend(contents.lines().filter_map(Rule::parse));
+            }
+        }
+        if rules.is_empty() {
+            return parent;
+        }
+        Some(Rc::new(IgnoreDir {
+            base: dir.to_path_buf(),
+            rules,
+            parent,
+        }))
+    }
+
+    // 絶対パスpathが除外されるか
+    // 深いディレクトリの規則から順に見て、最初に一致したディレクトリの最後に一致した規則で決める
+    fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        let mut current = Some(self);
+        while let Some(ignore) = current {
+            if let Ok(relative) = path.strip_prefix(&ignore.base) {
+                let relative = relative.to_string_lossy().replace('\\', "/");
+                let rule = ignore
+                    .rules
+                    .iter()
+                    .rev()
+                    .find(|rule| (is_dir || !rule.dir_only) && rule.glob.matches(&relative));
+                if let Some(rule) = rule {
+                    return !rule.negated;
+                }
+            }
+            current = ignore.parent.as_deref();
+        }
+        false
+    }
+}
+
+/// ディレクトリを辿る時の設定
+#[derive(Debug, Clone)]
+pub struct WalkOptions {
+    /// 隠しファイル・ディレクトリも辿る
+    pub hidden: bool,
+    /// .gitignore・.ignoreに従う
+    pub ignore_files: bool,
+    /// 空でなければ、どれかに一致するファイルだけを返す
+    pub include: Vec<Glob>,
+    /// 一致するファイル・ディレクトリは飛ばす
+    pub exclude: Vec<Glob>,
+}
+
+impl Default for WalkOptions {
+    fn default() -> Self {
+        Self {
+            hidden: false,
+            ignore_files: true,
+            include: vec![],
+            exclude: vec![],
+        }
+    }
+}
+
+// 辿る途中のパス
+struct Entry {
+    // 表示するパス(引数で指定したパスの下に名前をつなげたもの)
+    path: PathBuf,
+    // 除外の規則と比べるための絶対パス
+    absolute: PathBuf,
+    // 引数で指定したパスからの相対パス(--include・--excludeと比べる)
+    relative: String,
+    ignore: Option<Rc<IgnoreDir>>,
+}
+
+/// 引数で指定したパスを辿ってファイルを順に返すイテレーター
+/// 引数で直接指定したファイルは、隠しファイルや除外のパターンに一致していても返す
+pub struct Walk {
+    options: WalkOptions,
+    // 引数で指定したパス(逆順)
+    roots: Vec<PathBuf>,
+    // これから返す・辿るパス(逆順)
+    stack: Vec<Entry>,
+}
+
+impl Walk {
+    pub fn new(paths: &[impl AsRef<Path>], options: WalkOptions) -> Self {
+        Self {
+            options,
+            roots: paths.iter().rev().map(|path| path.as_ref().to_path_buf()).collect(),
+            stack: vec![],
+        }
+    }
+
+    // 引数で指定したディレクトリを辿り始める
+    // 親ディレクトリの除外ファイルも、Gitのリポジトリのルート(.gitのあるディレクトリ)まで遡って読む
+    fn push_root(&mut self, path: PathBuf) -> io::Result<()> {
+        let absolute = fs::canonicalize(&path)?;
+        let mut ignore = None;
+        if self.options.ignore_files {
+            let ancestors: Vec<&Path> = absolute.ancestors().collect();
+            // 指定したディレクトリ自身の除外ファイルはpush_childrenで読む
+            if let Some(repository) = ancestors.iter().position(|dir| dir.join(".git").exists()) {
+                for dir in ancestors[1..=repository].iter().rev() {
+                    ignore = IgnoreDir::load(dir, ignore);
+                }
+            }
+        }
+        self.push_children(Entry {
+            path,
+            absolute,
+            relative: String::new(),
+            ignore,
+        })
+    }
+
+    // ディレクトリの中身を、名前順に返すようstackに積む
+    fn push_children(&mut self, dir: Entry) -> io::Result<()> {
+        let ignore = if self.options.ignore_files {
+            IgnoreDir::load(&dir.absolute, dir.ignore.clone())
+        } else {
+            None
+        };
+        let mut names: Vec<_> = fs::read_dir(&dir.path)?
+            .map(|entry| entry.map(|entry| entry.file_name()))
+            .collect::<io::Result<_>>()?;
+        names.sort();
+        for name in names.into_iter().rev() {
+            let relative = if dir.relative.is_empty() {
+                name.to_string_lossy().into_owned()
+            } else {
+                format!("{}/{}", dir.relative, name.to_string_lossy())
+            };
+            self.stack.push(Entry {
+                path: dir.path.join(&name),
+                absolute: dir.absolute.join(&name),
+                relative,
+                ignore: ignore.clone(),
+            });
+        }
+        Ok(())
+    }
+
+    // 辿った先のパスを返すか・辿るかを決める
+    fn is_skipped(&self, entry: &Entry, is_dir: bool) -> bool {
+        let name = entry.relative.rsplit('/').next().unwrap_or("");
+        if !self.options.hidden && name.starts_with('.') {
+            return true;
+        }
+        if self.options.exclude.iter().any(|glob| glob.is_match(&entry.relative)) {
+            return true;
+        }
+        if !is_dir && !self.options.include.is_empty() && !self.options.include.iter().any(|glob| glob.is_match(&entry.relative)) {
+            return true;
+        }
+        match &entry.ignore {
+            Some(ignore) => ignore.is_ignored(&entry.absolute, is_dir),
+            None => false,
+        }
+    }
+}
+
+impl Iterator for Walk {
+    type Item = io::Result<PathBuf>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let entry = match self.stack.pop() {
+                Some(entry) => entry,
+                None => {
+                    let root = self.roots.pop()?;
+                    // 引数で指定したファイルはそのまま返す(存在しなければ開く時にエラーになる)
+                    if !root.is_dir() {
+                        return Some(Ok(root));
+                    }
+                    if let Err(err) = self.push_root(root.clone()) {
+                        return Some(Err(with_path(err, &root)));
+                    }
+                    continue;
+                }
+            };
+            // シンボリックリンクは辿らないので、リンクそのものの種類を見る
+            let file_type = match fs::symlink_metadata(&entry.path) {
+                Ok(metadata) => metadata.file_type(),
+                Err(err) => return Some(Err(with_path(err, &entry.path))),
+            };
+            if file_type.is_symlink() || self.is_skipped(&entry, file_type.is_dir()) {
+                continue;
+            }
+            if file_type.is_dir() {
+                let path = entry.path.clone();
+                if let Err(err) = self.push_children(entry) {
+                    return Some(Err(with_path(err, &path)));
+                }
+                continue;
+            }
+            if file_type.is_file() {
+                return Some(Ok(entry.path));
+            }
+        }
+    }
+}
+
+// どのパスのエラーか分かるように、メッセージにパスを付ける
+fn with_path(err: io::Error, path: &Path) -> io::Error {
+    io::Error::new(err.kind(), format!("{}: {}", path.display(), err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("walk_test_{}_{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn create(dir: &Path, files: &[(&str, &str)]) {
+        for (path, contents) in files {
+            let path = dir.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        }
+    }
+
+    fn walk(dir: &Path, options: WalkOptions) -> Vec<String> {
+        Walk::new(&[dir], options)
+            .map(|path| path.unwrap().strip_prefix(dir).unwrap().to_string_lossy().into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn test_glob() {
+        let cases = [
+            ("*.rs", "src/main.rs", true),
+            ("*.rs", "src/main.rsx", false),
+            ("src/*.rs", "src/a/main.rs", false),
+            ("src/**/*.rs", "src/a/b/main.rs", true),
+            ("src/**/*.rs", "src/main.rs", true),
+            ("**/target", "a/b/target", true),
+            ("**/target", "target", true),
+            ("target/**", "target/debug/x", true),
+            ("target/**", "target", false),
+            ("ma?n.[a-r]s", "main.rs", true),
+            ("ma?n.[!a-r]s", "main.rs", false),
+            ("a\\*b", "a*b", true),
+            ("a\\*b", "axb", false),
+            ("*", "a/b", true),
+        ];
+        for (pattern, path, expected) in cases.iter() {
+            assert_eq!(Glob::new(pattern).is_match(path), *expected, "{} {}", pattern, path);
+        }
+    }
+
+    #[test]
+    fn test_walk_ignore() {
+        let dir = temp_dir("ignore");
+        create(
+            &dir,
+            &[
+                (".gitignore", "# build output\n/target\n*.log\n!keep.log\nbuild/\n"),
+                ("src/.ignore", "generated.rs\n"),
+                ("src/main.rs", ""),
+                ("src/generated.rs", ""),
+                ("src/sub/target", ""),
+                ("src/build", ""),
+                ("src/build_dir/build/x.rs", ""),
+                ("target/debug/app", ""),
+                ("debug.log", ""),
+                ("keep.log", ""),
+                (".hidden/secret.rs", ""),
+                ("README.md", ""),
+            ],
+        );
+        assert_eq!(walk(&dir, WalkOptions::default()), vec!["README.md", "keep.log", "src/build", "src/main.rs", "src/sub/target"]);
+        let all = walk(
+            &dir,
+            WalkOptions {
+                hidden: true,
+                ignore_files: false,
+                ..WalkOptions::default()
+            },
+        );
+        assert_eq!(all.len(), 12);
+        // 引数で直接指定したファイルは除外しない
+        let direct: Vec<PathBuf> = Walk::new(&[dir.join("debug.log")], WalkOptions::default()).map(|path| path.unwrap()).collect();
+        assert_eq!(direct, vec![dir.join("debug.log")]);
+    }
+
+    #[test]
+    fn test_walk_include_exclude() {
+        let dir = temp_dir("include");
+        create(&dir, &[("a.rs", ""), ("b.txt", ""), ("src/c.rs", ""), ("src/vendor/d.rs", ""), ("vendor/e.rs", "")]);
+        let options = WalkOptions {
+            include: vec![Glob::new("*.rs")],
+            exclude: vec![Glob::new("vendor")],
+            ..WalkOptions::default()
+        };
+        assert_eq!(walk(&dir, options), vec!["a.rs", "src/c.rs"]);
+        let options = WalkOptions {
+            include: vec![Glob::new("src/**")],
+            ..WalkOptions::default()
+        };
+        assert_eq!(walk(&dir, options), vec!["src/c.rs", "src/vendor/d.rs"]);
+    }
+}